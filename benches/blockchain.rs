@@ -0,0 +1,212 @@
+//! Benchmarks of the hot paths: the proof of work, block validation, signing
+//! and verifying transactions, the UTXO reindex and the mempool.
+//!
+//! Run them all with `cargo bench`, or one group with `cargo bench -- utxo`.
+//! Criterion keeps the last results under target/criterion and reports the
+//! change of each benchmark against them. To judge a change, run
+//! `cargo bench -- --save-baseline before` on the old code and then
+//! `cargo bench -- --baseline before` on the new one.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+
+use blockchain_project::block::{pow_hash, PowOptions};
+use blockchain_project::config::Network;
+use blockchain_project::mempool::{Mempool, MempoolEntry};
+use blockchain_project::miner::check_block;
+use blockchain_project::storage::Compression;
+use blockchain_project::target::{POW_LIMIT_BITS, TARGET_SPACING};
+use blockchain_project::tx::{OutPoint, TXInput, TXOutput};
+use blockchain_project::wallet::{hash_to_address, Wallet};
+use blockchain_project::{Amount, Block, Blockchain, Config, Hash256, Transaction, UTXOSet, Wallets};
+
+/// CHAIN_BLOCKS is the length of the synthetic chain the UTXO reindex scans
+const CHAIN_BLOCKS: usize = 10_000;
+
+/// BLOCK_TXS is the number of signed payments in the block validated
+const BLOCK_TXS: usize = 100;
+
+/// MEMPOOL_TXS is the number of transactions inserted into the mempool
+const MEMPOOL_TXS: usize = 1_000;
+
+/// Fixture is a regtest chain in a temporary data directory with a wallet
+/// owning every output on it
+struct Fixture {
+    datadir: PathBuf,
+    bc: Blockchain,
+    address: String,
+    wallet: Wallet
+}
+
+impl Fixture {
+    /// new builds a chain of `blocks` blocks on top of the genesis, each with a
+    /// payment spending the coinbase of its parent. The blocks are linked and
+    /// indexed but not mined, their proof of work is never checked here
+    fn new(name: &str, blocks: usize) -> Fixture {
+        let datadir = std::env::temp_dir().join(format!("blockchain-bench-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&datadir);
+        let config = Config {
+            datadir: datadir.to_string_lossy().to_string(),
+            network: Network::Regtest,
+            ..Config::default()
+        };
+        let mut wallets = Wallets::new(&config).unwrap();
+        let address = wallets.create_wallet();
+        let wallet = wallets.get_wallet(&address).unwrap().clone();
+
+        let bc = Blockchain::create_blockchain(&config, address.clone(), Compression::None).unwrap();
+        let mut parent = bc.get_block(&bc.get_tip()).unwrap();
+        for height in 1..=blocks {
+            let prev_coinbase = parent.get_transactions().last().unwrap();
+            let payment = unsigned_payment(prev_coinbase.outpoint(0), &wallet, &address);
+            let coinbase = Transaction::new_coinbase(address.clone(), format!("height {}", height)).unwrap();
+            let timestamp = parent.get_timestamp() + TARGET_SPACING;
+            let block = Block::from_solution(vec![payment, coinbase], parent.get_hash(), height, POW_LIMIT_BITS, timestamp, 0).unwrap();
+            bc.connect_block(&block, Vec::new()).unwrap();
+            parent = block;
+        }
+        UTXOSet { blockchain: bc.clone() }.reindex().unwrap();
+
+        Fixture { datadir, bc, address, wallet }
+    }
+
+    /// payments signs one payment for each of the last `count` blocks, spending
+    /// the outputs their own payments created
+    fn payments(&self, count: usize) -> Vec<Transaction> {
+        let mut payments = Vec::with_capacity(count);
+        for header in self.bc.iter_headers().take(count) {
+            let block = self.bc.get_block(&header.hash).unwrap();
+            let mut tx = unsigned_payment(block.get_transactions()[0].outpoint(0), &self.wallet, &self.address);
+            self.bc.sign_transaction(&mut tx, &self.wallet.secret_key).unwrap();
+            payments.push(tx);
+        }
+        payments
+    }
+
+    fn remove(self) {
+        drop(self.bc);
+        let _ = std::fs::remove_dir_all(&self.datadir);
+    }
+}
+
+/// unsigned_payment spends the whole coin at `prev_out` to `address`
+fn unsigned_payment(prev_out: OutPoint, wallet: &Wallet, address: &str) -> Transaction {
+    let mut tx = Transaction {
+        id: Hash256::ZERO,
+        vin: vec![TXInput { prev_out, signature: Vec::new(), pub_key: wallet.public_key.clone() }],
+        vout: vec![TXOutput::new(Amount::COIN, address.to_string()).unwrap()]
+    };
+    tx.id = tx.hash().unwrap();
+    tx
+}
+
+fn pow(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pow");
+    let prefix = Block::header_prefix(&Hash256::ZERO, &[0; 32], 0, POW_LIMIT_BITS).unwrap();
+    let mut nonce = 0i32;
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("hash", |b| b.iter(|| {
+        nonce = nonce.wrapping_add(1);
+        pow_hash(&prefix, nonce)
+    }));
+
+    // at the pow limit a block takes 65536 hashes on average, the sample size
+    // evens out the luck of each search
+    let coinbase = Transaction::new_coinbase(hash_to_address(&[7; 20]), "bench".to_string()).unwrap();
+    let options = PowOptions { threads: 1, nice: false };
+    group.throughput(Throughput::Elements(1));
+    group.sample_size(30);
+    group.bench_function("seal_at_pow_limit", |b| b.iter(|| {
+        Block::seal(vec![coinbase.clone()], Hash256::ZERO, 1, POW_LIMIT_BITS, options, &AtomicBool::new(false), &AtomicU64::new(0)).unwrap()
+    }));
+    group.finish();
+}
+
+fn validation(c: &mut Criterion) {
+    let fixture = Fixture::new("validation", BLOCK_TXS);
+    let mut transactions = fixture.payments(BLOCK_TXS);
+    transactions.push(Transaction::new_coinbase(fixture.address.clone(), "bench".to_string()).unwrap());
+    let height = fixture.bc.get_best_height().unwrap() as usize + 1;
+    let block = Block::new_block(transactions, fixture.bc.get_tip(), height, fixture.bc.next_bits().unwrap()).unwrap();
+    let utxo = UTXOSet { blockchain: fixture.bc.clone() };
+    check_block(&block, &utxo).unwrap();
+
+    let mut group = c.benchmark_group("validation");
+    group.throughput(Throughput::Elements(block.get_transactions().len() as u64));
+    group.bench_function("block_validate", |b| b.iter(|| block.validate().unwrap()));
+    group.bench_function("check_block", |b| b.iter(|| check_block(&block, &utxo).unwrap()));
+    group.finish();
+    fixture.remove();
+}
+
+fn signatures(c: &mut Criterion) {
+    let fixture = Fixture::new("signatures", 1);
+    let tip = fixture.bc.get_block(&fixture.bc.get_tip()).unwrap();
+    let prev = tip.get_transactions()[0].clone();
+    let unsigned = unsigned_payment(prev.outpoint(0), &fixture.wallet, &fixture.address);
+    let prev_txs = HashMap::from([(prev.id, prev)]);
+    let mut signed = unsigned.clone();
+    signed.sign(&fixture.wallet.secret_key, prev_txs.clone()).unwrap();
+
+    let mut group = c.benchmark_group("signatures");
+    group.bench_function("sign", |b| b.iter_batched(
+        || (unsigned.clone(), prev_txs.clone()),
+        |(mut tx, prev_txs)| tx.sign(&fixture.wallet.secret_key, prev_txs).unwrap(),
+        BatchSize::SmallInput
+    ));
+    group.bench_function("verify", |b| b.iter_batched(
+        || (signed.clone(), prev_txs.clone()),
+        |(mut tx, prev_txs)| assert!(tx.verify(prev_txs).unwrap()),
+        BatchSize::SmallInput
+    ));
+    group.finish();
+    fixture.remove();
+}
+
+fn utxo(c: &mut Criterion) {
+    let fixture = Fixture::new("utxo", CHAIN_BLOCKS);
+    let utxo = UTXOSet { blockchain: fixture.bc.clone() };
+
+    let mut group = c.benchmark_group("utxo");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(CHAIN_BLOCKS as u64));
+    group.bench_function("reindex_10k_blocks", |b| b.iter(|| utxo.reindex().unwrap()));
+    group.finish();
+    fixture.remove();
+}
+
+fn mempool(c: &mut Criterion) {
+    let entries: Vec<MempoolEntry> = (0..MEMPOOL_TXS as i32)
+        .map(|i| {
+            let prev_out = OutPoint::new(Hash256::sha256(&i.to_le_bytes()), 0);
+            let mut tx = Transaction {
+                id: Hash256::ZERO,
+                vin: vec![TXInput { prev_out, signature: vec![0; 64], pub_key: vec![0; 32] }],
+                vout: vec![TXOutput { value: Amount::COIN, pub_key_hash: vec![0; 20] }]
+            };
+            tx.id = tx.hash().unwrap();
+            MempoolEntry::new(tx, Amount::from_sat(i + 1)).unwrap()
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("mempool");
+    group.throughput(Throughput::Elements(MEMPOOL_TXS as u64));
+    group.bench_function("insert_1k", |b| b.iter_batched(
+        || entries.clone(),
+        |entries| {
+            let mut pool = Mempool::new();
+            for entry in entries {
+                pool.insert(entry);
+            }
+            pool
+        },
+        BatchSize::SmallInput
+    ));
+    group.finish();
+}
+
+criterion_group!(benches, pow, validation, signatures, utxo, mempool);
+criterion_main!(benches);
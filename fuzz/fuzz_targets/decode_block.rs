@@ -0,0 +1,21 @@
+//! Decodes blocks from bincode, the encoding of block messages and of the
+//! blocks stored on disk, and checks their proof of work.
+#![no_main]
+
+use blockchain_project::block::Block;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(block) = bincode::deserialize::<Block>(data) else {
+        return;
+    };
+    let encoded = bincode::serialize(&block).expect("a decoded block encodes");
+    let again: Block = bincode::deserialize(&encoded).expect("an encoded block decodes");
+    assert_eq!(bincode::serialize(&again).expect("a decoded block encodes"), encoded);
+
+    let _ = block.validate();
+    let _ = Block::merkle_root(block.get_transactions());
+    for index in 0..block.get_transactions().len() {
+        let _ = Block::merkle_branch(block.get_transactions(), index);
+    }
+});
@@ -0,0 +1,27 @@
+//! Decodes P2P frames as a node does on every inbound connection, then runs
+//! the checks that come before a message touches the chain.
+#![no_main]
+
+use blockchain_project::codec::{Message, MessageCodec};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(message) = MessageCodec::decode(data) else {
+        return;
+    };
+    let frame = MessageCodec::encode(&message).expect("a decoded message encodes");
+    let again = MessageCodec::decode(&frame).expect("an encoded message decodes");
+    assert_eq!(MessageCodec::encode(&again).expect("a decoded message encodes"), frame);
+
+    let _ = message.command();
+    match message {
+        Message::Block(msg) => {
+            let _ = msg.block.validate();
+        },
+        Message::Tx(msg) => {
+            let _ = msg.transaction.hash();
+            let _ = msg.transaction.is_coinbase();
+        },
+        _ => {}
+    }
+});
@@ -0,0 +1,20 @@
+//! Decodes transactions from bincode, the encoding of `sendrawtransaction`
+//! and of transaction messages.
+#![no_main]
+
+use blockchain_project::transaction::Transaction;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(tx) = bincode::deserialize::<Transaction>(data) else {
+        return;
+    };
+    let encoded = bincode::serialize(&tx).expect("a decoded transaction encodes");
+    let again: Transaction = bincode::deserialize(&encoded).expect("an encoded transaction decodes");
+    assert_eq!(bincode::serialize(&again).expect("a decoded transaction encodes"), encoded);
+
+    let _ = tx.hash();
+    for out in &tx.vout {
+        let _ = out.data();
+    }
+});
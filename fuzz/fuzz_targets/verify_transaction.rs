@@ -0,0 +1,31 @@
+//! Checks the signatures of decoded transactions, the unlocking of the
+//! outputs they spend, against previous transactions the input makes up:
+//! the first byte is how many outputs each of them has.
+#![no_main]
+
+use std::collections::HashMap;
+
+use blockchain_project::transaction::Transaction;
+use blockchain_project::tx::TXOutput;
+use blockchain_project::wallet::hash_pub_key;
+use blockchain_project::Amount;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&outputs, data)) = data.split_first() else {
+        return;
+    };
+    let Ok(mut tx) = bincode::deserialize::<Transaction>(data) else {
+        return;
+    };
+
+    let mut prev_txs = HashMap::new();
+    for vin in &tx.vin {
+        let mut pub_key_hash = vin.pub_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+        let out = TXOutput { value: Amount::from_sat(1), pub_key_hash };
+        let prev = Transaction { id: vin.prev_out.txid, vin: Vec::new(), vout: vec![out; outputs as usize] };
+        prev_txs.insert(prev.id, prev);
+    }
+    let _ = tx.verify(prev_txs);
+});
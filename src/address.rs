@@ -0,0 +1,150 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::config::Network;
+use crate::error::{BlockchainError, Result};
+use crate::hash::Hash256;
+
+/// MAIN_VERSION is the version byte of main network addresses, they start with 1
+pub const MAIN_VERSION: u8 = 0x00;
+
+/// TEST_VERSION is the version byte of test and regtest addresses, they start
+/// with m or n
+pub const TEST_VERSION: u8 = 0x6f;
+
+const ALPHABET: &[u8; 58] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+const CHECKSUM_LEN: usize = 4;
+const HASH_LEN: usize = 20;
+
+/// VERSION is the version byte `encode` writes, set once the network is known
+static VERSION: AtomicU8 = AtomicU8::new(MAIN_VERSION);
+
+/// set_network makes `encode` write addresses of `network`
+pub fn set_network(network: Network) {
+    VERSION.store(version(network), Ordering::Relaxed);
+}
+
+/// version is the address version byte of `network`, like Bitcoin's P2PKH
+pub fn version(network: Network) -> u8 {
+    match network {
+        Network::Main => MAIN_VERSION,
+        Network::Test | Network::Regtest => TEST_VERSION
+    }
+}
+
+/// encode writes a public key hash as a P2PKH address of the current network
+pub fn encode(pub_key_hash: &[u8]) -> String {
+    encode_with_version(VERSION.load(Ordering::Relaxed), pub_key_hash)
+}
+
+/// encode_with_version writes Base58Check(version || pub_key_hash)
+pub fn encode_with_version(version: u8, pub_key_hash: &[u8]) -> String {
+    let mut payload = Vec::with_capacity(1 + pub_key_hash.len() + CHECKSUM_LEN);
+    payload.push(version);
+    payload.extend_from_slice(pub_key_hash);
+    let checksum = checksum(&payload);
+    payload.extend_from_slice(&checksum);
+    base58_encode(&payload)
+}
+
+/// decode reads the public key hash of a P2PKH address of any network, after
+/// checking its checksum and version byte
+pub fn decode(address: &str) -> Result<Vec<u8>> {
+    Ok(decode_with_version(address)?.1)
+}
+
+/// decode_with_version is decode also returning the version byte, which tells
+/// the network of the address
+pub fn decode_with_version(address: &str) -> Result<(u8, Vec<u8>)> {
+    let invalid = |reason: &str| BlockchainError::InvalidAddress(format!("{}: {}", address, reason));
+    let payload = base58_decode(address).ok_or_else(|| invalid("not base58"))?;
+    if payload.len() != 1 + HASH_LEN + CHECKSUM_LEN {
+        return Err(invalid("wrong length"));
+    }
+
+    let (data, sum) = payload.split_at(1 + HASH_LEN);
+    if checksum(data) != sum {
+        return Err(invalid("bad checksum"));
+    }
+    if data[0] != MAIN_VERSION && data[0] != TEST_VERSION {
+        return Err(invalid(&format!("unknown version byte {:#04x}", data[0])));
+    }
+    Ok((data[0], data[1..].to_vec()))
+}
+
+/// checksum is the first bytes of the double sha256 of `data`
+fn checksum(data: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let hash = Hash256::sha256(Hash256::sha256(data).as_bytes());
+    let mut sum = [0; CHECKSUM_LEN];
+    sum.copy_from_slice(&hash.as_bytes()[..CHECKSUM_LEN]);
+    sum
+}
+
+/// base58_encode writes `data` as a base 58 number, each leading zero byte as a 1
+fn base58_encode(data: &[u8]) -> String {
+    let zeros = data.iter().take_while(|b| **b == 0).count();
+    // little endian base 58 digits of the number
+    let mut digits: Vec<u8> = Vec::with_capacity(data.len() * 138 / 100 + 1);
+    for byte in &data[zeros..] {
+        let mut carry = *byte as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+
+    let mut encoded = "1".repeat(zeros);
+    encoded.extend(digits.iter().rev().map(|d| ALPHABET[*d as usize] as char));
+    encoded
+}
+
+/// base58_decode reads what base58_encode writes, None on a character outside
+/// the alphabet
+fn base58_decode(encoded: &str) -> Option<Vec<u8>> {
+    let zeros = encoded.bytes().take_while(|c| *c == b'1').count();
+    // little endian bytes of the number
+    let mut bytes: Vec<u8> = Vec::with_capacity(encoded.len() * 733 / 1000 + 1);
+    for c in encoded.bytes().skip(zeros) {
+        let mut carry = ALPHABET.iter().position(|a| *a == c)? as u32;
+        for byte in bytes.iter_mut() {
+            carry += (*byte as u32) * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push(carry as u8);
+            carry >>= 8;
+        }
+    }
+
+    let mut decoded = vec![0; zeros];
+    decoded.extend(bytes.iter().rev());
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_addresses_match_bitcoin() -> Result<()> {
+        // the P2PKH address of the uncompressed public key of private key 1
+        let pub_key_hash = hex::decode("91b24bf9f5288532960ac687abb035127b1d28a5").unwrap();
+        assert_eq!(encode_with_version(MAIN_VERSION, &pub_key_hash), "1EHNa6Q4Jz2uvNExL497mE43ikXhwF6kZm");
+        assert_eq!(decode("1EHNa6Q4Jz2uvNExL497mE43ikXhwF6kZm")?, pub_key_hash);
+
+        let testnet = encode_with_version(TEST_VERSION, &pub_key_hash);
+        assert!(testnet.starts_with('m') || testnet.starts_with('n'));
+        assert_eq!(decode(&testnet)?, pub_key_hash);
+        assert_eq!(encode_with_version(MAIN_VERSION, &[0; 20]), "1111111111111111111114oLvT2");
+
+        assert!(decode("1EHNa6Q4Jz2uvNExL497mE43ikXhwF6kZn").is_err());
+        assert!(decode("1EHNa6Q4Jz2uvNExL497mE43ikXhwF6kZ0").is_err());
+        assert!(decode(&encode_with_version(0x05, &pub_key_hash)).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+use serde::Serialize;
+use tracing::{error, info};
+
+use crate::block::{Block, BlockHeader};
+use crate::blockchain::Blockchain;
+use crate::error::{BlockchainError, Result};
+use crate::hash::Hash256;
+use crate::intent::IntentOp;
+use crate::progress::Progress;
+use crate::wallet::hash_pub_key;
+
+/// ADDR_INDEX_TREE maps pkh(20) || height(4, BE) || txid(32) to the change the
+/// transaction made to the balance of the address, so the activity of an
+/// address is a prefix scan in height order
+pub const ADDR_INDEX_TREE: &str = "addrindex";
+
+/// ADDR_INDEX_TIP_TREE holds the hash of the last block the index covers
+const ADDR_INDEX_TIP_TREE: &str = "addrindex_tip";
+const TIP_KEY: &[u8] = b"TIP";
+
+/// AddressActivity is a confirmed transaction touching an address
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct AddressActivity {
+    pub txid: Hash256,
+    pub height: usize,
+    #[serde(rename = "blockhash")]
+    pub block_hash: Hash256,
+    /// sats the transaction added to the balance of the address, negative when
+    /// it spent more from it than it paid back
+    pub delta: i64
+}
+
+/// AddressBalance sums the activity of an address
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct AddressBalance {
+    pub balance: i64,
+    pub received: i64,
+    pub sent: i64,
+    #[serde(rename = "txcount")]
+    pub tx_count: usize
+}
+
+/// AddressIndex records the transactions touching every address of the best
+/// chain, independent of the wallets. It catches up with the chain when it
+/// starts and then follows it from a background thread, undoing the blocks
+/// that leave the best chain
+#[derive(Debug, Clone)]
+pub struct AddressIndex {
+    bc: Blockchain,
+    /// held while the index moves, so two syncs never interleave
+    syncing: Arc<Mutex<()>>
+}
+
+impl AddressIndex {
+    /// new opens the index of `bc` as it is, call sync to bring it up to the tip
+    pub fn new(bc: &Blockchain) -> AddressIndex {
+        AddressIndex {
+            bc: bc.clone(),
+            syncing: Arc::new(Mutex::new(()))
+        }
+    }
+
+    /// start brings the index up to the chain tip and follows the chain events
+    pub fn start(bc: &Blockchain) -> Result<AddressIndex> {
+        let events = bc.events().subscribe();
+        let index = AddressIndex::new(bc);
+        index.sync()?;
+
+        let follower = index.clone();
+        thread::spawn(move || {
+            for event in events {
+                if let Err(e) = follower.sync() {
+                    error!("address index failed on {:?}: {}", event, e);
+                }
+            }
+        });
+        Ok(index)
+    }
+
+    /// sync disconnects the indexed blocks that left the best chain and then
+    /// connects the best chain blocks not indexed yet
+    pub fn sync(&self) -> Result<()> {
+        let _syncing = self.syncing.lock().unwrap_or_else(PoisonError::into_inner);
+        let (disconnected, connected) = self.bc.path_from(self.tip()?)?;
+        for header in &disconnected {
+            self.disconnect(header)?;
+        }
+
+        if connected.is_empty() {
+            return Ok(());
+        }
+        let mut progress = (connected.len() > 1).then(|| Progress::new("index addresses", connected.len() as u64));
+        for header in &connected {
+            let block = self.bc.get_block(&header.hash)?;
+            let mut ops: Vec<IntentOp> = self
+                .entries(&block)?
+                .into_iter()
+                .map(|(key, value)| IntentOp::insert(ADDR_INDEX_TREE, &key, value))
+                .collect();
+            ops.push(IntentOp::insert(ADDR_INDEX_TIP_TREE, TIP_KEY, header.hash.as_bytes().to_vec()));
+            self.bc.intents().commit(&ops)?;
+            if let Some(progress) = progress.as_mut() {
+                progress.inc(1);
+            }
+        }
+        if let Some(mut progress) = progress {
+            progress.finish();
+        }
+        Ok(())
+    }
+
+    /// rebuild drops the index and indexes the whole chain again
+    pub fn rebuild(&self) -> Result<()> {
+        {
+            let _syncing = self.syncing.lock().unwrap_or_else(PoisonError::into_inner);
+            self.bc.open_tree(ADDR_INDEX_TIP_TREE)?.clear()?;
+            self.bc.open_tree(ADDR_INDEX_TREE)?.clear()?;
+        }
+        self.sync()
+    }
+
+    /// history lists the activity of `pub_key_hash`, newest first
+    pub fn history(&self, pub_key_hash: &[u8]) -> Result<Vec<AddressActivity>> {
+        let mut history = Vec::new();
+        for item in self.bc.open_tree(ADDR_INDEX_TREE)?.scan_prefix(pub_key_hash) {
+            let (key, value) = item?;
+            if key.len() != pub_key_hash.len() + 4 + 32 {
+                continue;
+            }
+            let (height, txid) = key[pub_key_hash.len()..].split_at(4);
+            let (delta, block_hash): (i64, Hash256) = bincode::deserialize(&value)?;
+            history.push(AddressActivity {
+                txid: Hash256::from_slice(txid)?,
+                height: u32::from_be_bytes(height.try_into().unwrap_or_default()) as usize,
+                block_hash,
+                delta
+            });
+        }
+        history.reverse();
+        Ok(history)
+    }
+
+    /// balance sums the history of `pub_key_hash`
+    pub fn balance(&self, pub_key_hash: &[u8]) -> Result<AddressBalance> {
+        let mut balance = AddressBalance::default();
+        for activity in self.history(pub_key_hash)? {
+            balance.balance += activity.delta;
+            if activity.delta > 0 {
+                balance.received += activity.delta;
+            } else {
+                balance.sent -= activity.delta;
+            }
+            balance.tx_count += 1;
+        }
+        Ok(balance)
+    }
+
+    /// tip is the last indexed block, None before the genesis is
+    fn tip(&self) -> Result<Option<Hash256>> {
+        match self.bc.open_tree(ADDR_INDEX_TIP_TREE)?.get(TIP_KEY)? {
+            Some(hash) => Ok(Some(Hash256::from_slice(&hash)?)),
+            None => Ok(None)
+        }
+    }
+
+    /// disconnect removes the entries of the block of `header`, making its
+    /// parent the tip of the index
+    fn disconnect(&self, header: &BlockHeader) -> Result<()> {
+        let block = self.bc.get_block(&header.hash)?;
+        let mut ops: Vec<IntentOp> = self
+            .entries(&block)?
+            .into_keys()
+            .map(|key| IntentOp::remove(ADDR_INDEX_TREE, &key))
+            .collect();
+        ops.push(IntentOp::insert(ADDR_INDEX_TIP_TREE, TIP_KEY, header.prev_block_hash.as_bytes().to_vec()));
+        self.bc.intents().commit(&ops)?;
+        info!("address index disconnected block {}", header.hash);
+        Ok(())
+    }
+
+    /// entries computes the index entries of `block`, the net change of every
+    /// address each transaction touches
+    fn entries(&self, block: &Block) -> Result<HashMap<Vec<u8>, Vec<u8>>> {
+        let mut entries = HashMap::new();
+        for tx in block.get_transactions() {
+            let mut deltas: HashMap<Vec<u8>, i64> = HashMap::new();
+            for out in tx.vout.iter().filter(|out| !out.is_data()) {
+                *deltas.entry(out.pub_key_hash.clone()).or_default() += out.value.to_sat() as i64;
+            }
+            if !tx.is_coinbase() {
+                for vin in &tx.vin {
+                    let spent = match self.bc.get_indexed_transaction(&vin.prev_out.txid)? {
+                        Some((prev, _)) => prev.vout.get(vin.prev_out.index as usize).map(|out| out.value),
+                        None => None
+                    };
+                    let spent = spent.ok_or_else(|| BlockchainError::Corrupt(format!("{} spends unknown output {:?}", tx.id, vin.prev_out)))?;
+                    let mut pub_key_hash = vin.pub_key.clone();
+                    hash_pub_key(&mut pub_key_hash);
+                    *deltas.entry(pub_key_hash).or_default() -= spent.to_sat() as i64;
+                }
+            }
+
+            for (pub_key_hash, delta) in deltas {
+                let mut key = pub_key_hash;
+                key.extend_from_slice(&(block.get_height() as u32).to_be_bytes());
+                key.extend_from_slice(tx.id.as_bytes());
+                entries.insert(key, bincode::serialize(&(delta, block.get_hash()))?);
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{ChainFixture, MINER_SEED};
+    use crate::wallet::Wallet;
+
+    #[test]
+    fn test_index_follows_the_chain() -> Result<()> {
+        let fixture = ChainFixture::restore(3)?;
+        let bc = fixture.blockchain()?;
+        let index = AddressIndex::start(&bc)?;
+
+        let mut miner = Wallet::from_seed(&MINER_SEED).public_key;
+        hash_pub_key(&mut miner);
+        let history = index.history(&miner)?;
+        assert_eq!(history.iter().map(|a| a.height).collect::<Vec<_>>(), vec![3, 2, 1, 0]);
+        assert_eq!(history[0].block_hash, fixture.tip());
+
+        let balance = index.balance(&miner)?;
+        assert_eq!(balance.tx_count, 4);
+        assert_eq!(balance.balance, balance.received);
+        assert_eq!(balance.sent, 0);
+        assert!(index.history(&[0; 20])?.is_empty());
+
+        // a second sync with nothing new leaves the index alone
+        index.sync()?;
+        assert_eq!(index.history(&miner)?, history);
+        Ok(())
+    }
+}
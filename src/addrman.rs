@@ -0,0 +1,175 @@
+//! The table of peer addresses a node has heard of, each with the last time
+//! it was seen. Gossip fills it through bounded buckets, so a peer flooding
+//! addresses from one network block only churns the few buckets that block
+//! maps to instead of taking the whole table over.
+//!
+//! A bucket is picked from the network groups of the address and of the peer
+//! that sent it, hashed with a secret key of the node. A full bucket evicts
+//! its oldest address.
+
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+use crate::hash::Hash256;
+use crate::netaddr;
+
+/// MAX_ADDR_PER_MESSAGE is the most addresses an addr message may carry
+pub const MAX_ADDR_PER_MESSAGE: usize = 1000;
+
+/// MAX_RELAYED_MESSAGE is the most addresses an addr message may carry to be
+/// relayed, larger ones answer a handshake rather than announce new peers
+pub const MAX_RELAYED_MESSAGE: usize = 10;
+
+/// MAX_GOSSIP_PEERS is how many known nodes a node keeps before the addresses
+/// it hears of only go into the table
+pub const MAX_GOSSIP_PEERS: usize = 8;
+
+/// RELAY_FANOUT is how many peers a fresh address is relayed to
+pub const RELAY_FANOUT: usize = 2;
+
+/// RECENT_SECS is how old an address may be and still be relayed
+pub const RECENT_SECS: u64 = 10 * 60;
+
+/// HORIZON_SECS is how old an address may be and still be kept
+pub const HORIZON_SECS: u64 = 30 * 24 * 60 * 60;
+
+/// PENALTY_SECS ages the addresses of a message without timestamps and the
+/// ones stamped in the future, so they are never relayed as fresh
+pub const PENALTY_SECS: u64 = 2 * 60 * 60;
+
+/// SHARE_PERCENT is the part of the table a peer gets when it asks
+pub const SHARE_PERCENT: usize = 23;
+
+const BUCKETS: usize = 64;
+const BUCKET_SIZE: usize = 16;
+
+/// TimedAddr is a peer address and the last time, in seconds since the epoch,
+/// a node heard from it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TimedAddr {
+    pub addr: String,
+    pub time: u64
+}
+
+/// AddrMan is the bucketed address table, BUCKETS * BUCKET_SIZE addresses at most
+#[derive(Debug, Clone)]
+pub struct AddrMan {
+    key: [u8; 32],
+    buckets: Vec<Vec<TimedAddr>>,
+    /// bucket of each address of the table
+    index: HashMap<String, usize>
+}
+
+impl Default for AddrMan {
+    fn default() -> AddrMan {
+        AddrMan { key: rand::thread_rng().gen(), buckets: vec![Vec::new(); BUCKETS], index: HashMap::new() }
+    }
+}
+
+impl AddrMan {
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// get is the record of the normalized `addr`
+    pub fn get(&self, addr: &str) -> Option<&TimedAddr> {
+        let bucket = *self.index.get(addr)?;
+        self.buckets[bucket].iter().find(|record| record.addr == addr)
+    }
+
+    /// add records `record`, heard of from `source`, moving the time of a
+    /// known address forward only, and tells whether the address is new
+    pub fn add(&mut self, record: TimedAddr, source: &str) -> bool {
+        if let Some(&bucket) = self.index.get(&record.addr) {
+            if let Some(known) = self.buckets[bucket].iter_mut().find(|known| known.addr == record.addr) {
+                known.time = known.time.max(record.time);
+            }
+            return false;
+        }
+
+        let bucket = self.bucket(&record.addr, source);
+        let entries = &mut self.buckets[bucket];
+        if entries.len() >= BUCKET_SIZE {
+            let Some((oldest, _)) = entries.iter().enumerate().min_by_key(|(_, known)| known.time) else {
+                return false;
+            };
+            if entries[oldest].time > record.time {
+                return false;
+            }
+            let evicted = entries.swap_remove(oldest);
+            self.index.remove(&evicted.addr);
+        }
+        self.index.insert(record.addr.clone(), bucket);
+        self.buckets[bucket].push(record);
+        true
+    }
+
+    /// sample picks up to `count` random addresses seen within HORIZON_SECS
+    /// of `now` that `keep` accepts
+    pub fn sample(&self, count: usize, now: u64, keep: impl Fn(&TimedAddr) -> bool) -> Vec<TimedAddr> {
+        let mut records: Vec<&TimedAddr> = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter(|record| record.time + HORIZON_SECS >= now && keep(record))
+            .collect();
+        records.shuffle(&mut rand::thread_rng());
+        records.into_iter().take(count).cloned().collect()
+    }
+
+    /// share is how many addresses a peer asking for them gets
+    pub fn share(&self) -> usize {
+        (self.len() * SHARE_PERCENT / 100).clamp(MAX_RELAYED_MESSAGE, MAX_ADDR_PER_MESSAGE)
+    }
+
+    fn bucket(&self, addr: &str, source: &str) -> usize {
+        let mut bytes = self.key.to_vec();
+        bytes.extend_from_slice(netaddr::group(addr).as_bytes());
+        bytes.push(0);
+        bytes.extend_from_slice(netaddr::group(source).as_bytes());
+        let hash = Hash256::sha256(&bytes);
+        u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap_or_default()) as usize % BUCKETS
+    }
+}
+
+/// now is the time, in seconds since the epoch, addresses are stamped with
+pub fn now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_a_flooding_group_only_fills_its_buckets() {
+        let mut table = AddrMan::default();
+        let now = 1_700_000_000;
+        let honest: Vec<String> = (0..50).map(|i| format!("{}.{}.0.1:3000", 100 + i, i)).collect();
+        for addr in &honest {
+            assert!(table.add(TimedAddr { addr: addr.clone(), time: now - 100 }, addr));
+        }
+        assert!(!table.add(TimedAddr { addr: honest[0].clone(), time: now }, "9.9.9.9:3000"));
+        assert_eq!(table.get(&honest[0]).map(|r| r.time), Some(now));
+
+        // one /16 sending fresher addresses of one /16 lands in a single bucket
+        for i in 0..5000 {
+            table.add(TimedAddr { addr: format!("66.66.{}.{}:3000", i / 250, i % 250), time: now }, "66.66.0.1:3000");
+        }
+        assert!(table.len() <= honest.len() + BUCKET_SIZE);
+        assert!(honest.iter().filter(|addr| table.get(addr).is_some()).count() >= honest.len() - BUCKET_SIZE);
+
+        assert_eq!(table.sample(5, now, |_| true).len(), 5);
+        assert!(table.sample(100, now + HORIZON_SECS + 200, |_| true).is_empty());
+        assert!(table.sample(100, now, |r| r.addr.starts_with("66.")).iter().all(|r| r.addr.starts_with("66.66.")));
+    }
+}
@@ -0,0 +1,137 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{BlockchainError, Result};
+
+/// DECIMALS is how many decimal places of a coin the base unit allows
+const DECIMALS: u32 = 2;
+const SATS_PER_COIN: i32 = 10i32.pow(DECIMALS);
+
+/// Amount is a value in base units ("sats"), serialized as the bare integer so
+/// blocks, txids and the JSON APIs keep their format
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(transparent)]
+pub struct Amount(i32);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    pub const COIN: Amount = Amount(SATS_PER_COIN);
+
+    pub const fn from_sat(sats: i32) -> Amount {
+        Amount(sats)
+    }
+
+    pub const fn to_sat(self) -> i32 {
+        self.0
+    }
+
+    pub fn checked_add(self, rhs: Amount) -> Option<Amount> {
+        self.0.checked_add(rhs.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, rhs: Amount) -> Option<Amount> {
+        self.0.checked_sub(rhs.0).map(Amount)
+    }
+
+    /// try_add is `checked_add` reporting an overflow as an error
+    pub fn try_add(self, rhs: Amount) -> Result<Amount> {
+        self.checked_add(rhs).ok_or_else(|| overflow(self, "+", rhs))
+    }
+
+    /// try_sub is `checked_sub` reporting an overflow as an error
+    pub fn try_sub(self, rhs: Amount) -> Result<Amount> {
+        self.checked_sub(rhs).ok_or_else(|| overflow(self, "-", rhs))
+    }
+
+    /// sum adds up `amounts`, failing on overflow
+    pub fn sum(amounts: impl IntoIterator<Item = Amount>) -> Result<Amount> {
+        amounts.into_iter().try_fold(Amount::ZERO, Amount::try_add)
+    }
+}
+
+fn overflow(lhs: Amount, op: &str, rhs: Amount) -> BlockchainError {
+    BlockchainError::InvalidAmount(format!("{} {} {} overflows", lhs, op, rhs))
+}
+
+/// Display writes coins with all their decimals, like `1.50`, which parses back
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let sats = self.0.unsigned_abs();
+        let coin = SATS_PER_COIN as u32;
+        write!(f, "{}{}.{:0width$}", sign, sats / coin, sats % coin, width = DECIMALS as usize)
+    }
+}
+
+/// FromStr reads coins like `1.5` or `2`, or base units like `150 sats`
+impl FromStr for Amount {
+    type Err = BlockchainError;
+
+    fn from_str(s: &str) -> Result<Amount> {
+        let s = s.trim();
+        let invalid = |reason: &str| BlockchainError::InvalidAmount(format!("'{}' {}", s, reason));
+        if s.starts_with('-') {
+            return Err(invalid("is negative"));
+        }
+
+        if let Some(sats) = s.strip_suffix("sats").or_else(|| s.strip_suffix("sat")) {
+            return sats.trim_end().parse().map(Amount).map_err(|_| invalid("is not a whole number of sats"));
+        }
+
+        let (coins, decimals) = match s.split_once('.') {
+            Some((_, "")) => return Err(invalid("ends with a decimal point")),
+            Some(parts) => parts,
+            None => (s, "")
+        };
+        if decimals.len() > DECIMALS as usize {
+            return Err(invalid(&format!("has more than {} decimals", DECIMALS)));
+        }
+        let digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+        if !digits(coins) || !(decimals.is_empty() || digits(decimals)) {
+            return Err(invalid("is not an amount like 1.5 or 150 sats"));
+        }
+
+        let coins: i32 = coins.parse().map_err(|_| invalid("is too large"))?;
+        let fraction: i32 = format!("{:0<width$}", decimals, width = DECIMALS as usize).parse()?;
+        coins
+            .checked_mul(SATS_PER_COIN)
+            .and_then(|sats| sats.checked_add(fraction))
+            .map(Amount)
+            .ok_or_else(|| invalid("is too large"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_display() -> Result<()> {
+        assert_eq!("1.5".parse::<Amount>()?, Amount::from_sat(150));
+        assert_eq!("2".parse::<Amount>()?, Amount::from_sat(200));
+        assert_eq!("0.07".parse::<Amount>()?, Amount::from_sat(7));
+        assert_eq!("1500 sats".parse::<Amount>()?, Amount::from_sat(1500));
+        assert_eq!("1sat".parse::<Amount>()?, Amount::from_sat(1));
+
+        for bad in ["", "-1", "1.234", "1.", ".5", "1,5", "abc", "1.5 sats", "99999999999"] {
+            assert!(bad.parse::<Amount>().is_err(), "{} parsed", bad);
+        }
+
+        assert_eq!(Amount::from_sat(150).to_string(), "1.50");
+        assert_eq!(Amount::from_sat(-7).to_string(), "-0.07");
+        assert_eq!(Amount::from_sat(150).to_string().parse::<Amount>()?, Amount::from_sat(150));
+        Ok(())
+    }
+
+    #[test]
+    fn test_checked_arithmetic() {
+        let max = Amount::from_sat(i32::MAX);
+        assert!(max.checked_add(Amount::from_sat(1)).is_none());
+        assert!(max.try_add(Amount::from_sat(1)).is_err());
+        assert!(Amount::sum([max, max]).is_err());
+        assert_eq!(Amount::sum([Amount::COIN, Amount::from_sat(5)]).unwrap(), Amount::from_sat(105));
+        assert_eq!(bincode::serialize(&Amount::from_sat(7)).unwrap(), bincode::serialize(&7i32).unwrap());
+    }
+}
@@ -0,0 +1,150 @@
+//! Peers the operator banned with `ban`, kept in a tree of the block database
+//! so bans survive restarts. A ban covers a host on every port: an IP address
+//! stops both the connections it opens and the ones this node would open to
+//! it, a host name only the ones this node would open and the addresses gossip
+//! brings under that name.
+
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::addrman;
+use crate::blockchain::Blockchain;
+use crate::error::{BlockchainError, Result};
+use crate::netaddr;
+use crate::schedule::Every;
+
+/// BAN_TREE maps a banned host to the unix time in seconds its ban ends
+const BAN_TREE: &str = "banned";
+
+/// DEFAULT_BAN_SECS is how long a ban lasts when `ban` is given no duration
+pub const DEFAULT_BAN_SECS: u64 = 24 * 60 * 60;
+
+/// Ban is a banned host and the unix time in seconds its ban ends
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Ban {
+    pub host: String,
+    pub until: u64
+}
+
+/// BanDuration is how long a ban lasts: a number of seconds or a time like
+/// `12-hours` or `7-days`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BanDuration(pub u64);
+
+impl FromStr for BanDuration {
+    type Err = BlockchainError;
+
+    fn from_str(s: &str) -> Result<BanDuration> {
+        if let Ok(secs) = s.parse::<u64>() {
+            return Ok(BanDuration(secs));
+        }
+        match s.parse::<Every>() {
+            Ok(Every::Seconds(secs)) => Ok(BanDuration(secs)),
+            _ => Err(BlockchainError::Config(format!("'{}' is not a ban duration like 3600 or 12-hours", s)))
+        }
+    }
+}
+
+/// BanList is a handle to the banned hosts, clones share the same tree
+#[derive(Debug, Clone)]
+pub struct BanList {
+    tree: sled::Tree
+}
+
+impl BanList {
+    pub fn new(bc: &Blockchain) -> Result<BanList> {
+        Ok(BanList { tree: bc.open_tree(BAN_TREE)? })
+    }
+
+    /// ban bans the host of `addr`, a host or a host:port, for `duration`
+    pub fn ban(&self, addr: &str, duration: BanDuration) -> Result<Ban> {
+        let host = ban_host(addr)?;
+        let until = addrman::now()?.saturating_add(duration.0);
+        self.tree.insert(host.as_bytes(), &until.to_be_bytes())?;
+        self.tree.flush()?;
+        Ok(Ban { host, until })
+    }
+
+    /// unban lifts the ban of the host of `addr`, telling whether it had one
+    pub fn unban(&self, addr: &str) -> Result<bool> {
+        let removed = self.tree.remove(ban_host(addr)?.as_bytes())?.is_some();
+        self.tree.flush()?;
+        Ok(removed)
+    }
+
+    /// is_banned tells whether the host of `addr` is banned now
+    pub fn is_banned(&self, addr: &str) -> Result<bool> {
+        let Ok(host) = ban_host(addr) else {
+            return Ok(false);
+        };
+        match self.tree.get(host.as_bytes())? {
+            Some(until) => Ok(until_of(&until)? > addrman::now()?),
+            None => Ok(false)
+        }
+    }
+
+    /// list is every ban in force, by host, dropping the ones that ended
+    pub fn list(&self) -> Result<Vec<Ban>> {
+        let now = addrman::now()?;
+        let mut bans = Vec::new();
+        for entry in self.tree.iter() {
+            let (host, until) = entry?;
+            let until = until_of(&until)?;
+            if until > now {
+                bans.push(Ban { host: String::from_utf8_lossy(&host).into_owned(), until });
+            } else {
+                self.tree.remove(&host)?;
+            }
+        }
+        Ok(bans)
+    }
+}
+
+/// ban_host is the host a ban of `addr` covers, lowercase and without brackets
+fn ban_host(addr: &str) -> Result<String> {
+    let addr = addr.trim();
+    let host = match netaddr::normalize(addr) {
+        Ok(peer) => netaddr::host(&peer).to_string(),
+        Err(_) => addr.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase()
+    };
+    if host.is_empty() || host.contains(char::is_whitespace) {
+        return Err(BlockchainError::Network(format!("'{}' is not a host to ban", addr)));
+    }
+    Ok(host)
+}
+
+fn until_of(bytes: &[u8]) -> Result<u64> {
+    match <[u8; 8]>::try_from(bytes) {
+        Ok(bytes) => Ok(u64::from_be_bytes(bytes)),
+        Err(_) => Err(BlockchainError::Corrupt(format!("ban end of {} bytes", bytes.len())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ChainFixture;
+
+    #[test]
+    fn test_bans_cover_every_port_and_survive_reopening() -> Result<()> {
+        let fixture = ChainFixture::restore(1)?;
+        let bans = BanList::new(&fixture.blockchain()?)?;
+        assert_eq!(bans.ban("10.0.0.7:3000", "2-hours".parse()?)?.host, "10.0.0.7");
+        bans.ban("[::1]:3001", BanDuration(0))?;
+        bans.ban("Example.com", BanDuration(DEFAULT_BAN_SECS))?;
+        drop(bans);
+
+        let bans = BanList::new(&fixture.blockchain()?)?;
+        assert!(bans.is_banned("10.0.0.7:4000")?);
+        assert!(bans.is_banned("example.com:3000")?);
+        assert!(!bans.is_banned("::1")?);
+        assert_eq!(bans.list()?.iter().map(|ban| ban.host.as_str()).collect::<Vec<_>>(), vec!["10.0.0.7", "example.com"]);
+
+        assert!(bans.unban("10.0.0.7")?);
+        assert!(!bans.unban("10.0.0.7")?);
+        assert!(!bans.is_banned("10.0.0.7:3000")?);
+        assert!("1-blocks".parse::<BanDuration>().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,131 @@
+//! spv-wallet is a light client of the wallets of a data directory: it keeps
+//! the headers of the best chain of its peers and the transactions of its
+//! keys, never the blocks. See the `spv` module.
+
+use std::process::exit;
+
+use clap::{arg, ArgAction, ArgMatches, Command};
+use tracing_subscriber::EnvFilter;
+
+use blockchain_project::address;
+use blockchain_project::amount::Amount;
+use blockchain_project::config::Config;
+use blockchain_project::error::Result;
+use blockchain_project::spv::SpvWallet;
+use blockchain_project::wallet::Wallets;
+
+/// DEFAULT_PEER is synced with when neither `--peer` nor the config names one
+const DEFAULT_PEER: &str = "localhost:3000";
+
+fn command() -> Command {
+    Command::new("spv-wallet")
+        .version("0.1")
+        .about("light client wallet: syncs headers and its own transactions from full nodes without storing the chain")
+        .arg(arg!(--config <FILE>"'Config file, defaults to blockchain.toml when present'").global(true))
+        .arg(arg!(--datadir <DIR>"'Directory holding the wallet and header data'").global(true))
+        .arg(arg!(--network <NETWORK>"'Network to use: main, test or regtest'").global(true))
+        .arg(arg!(--peer <ADDR>"'Full node to sync from, repeat for several, the configured peers by default'").action(ArgAction::Append).global(true))
+        .subcommand(Command::new("createwallet").about("create a wallet"))
+        .subcommand(Command::new("listaddresses").about("list all addresses"))
+        .subcommand(Command::new("sync").about("fetch the new headers and the wallet transactions in them"))
+        .subcommand(
+            Command::new("getbalance")
+            .about("sync, then print the confirmed and unconfirmed balance of each wallet address")
+            .arg(arg!([ADDRESS]"'Only this address'"))
+        )
+        .subcommand(Command::new("listtransactions").about("list the wallet transactions synced, the unconfirmed ones last"))
+        .subcommand(
+            Command::new("send")
+            .about("sync, then pay out of the confirmed outputs of a wallet address and broadcast the payment")
+            .arg(arg!(<FROM>"'Wallet address paying'"))
+            .arg(arg!(<TO>"'Destination address'"))
+            .arg(arg!(<AMOUNT>"'Amount to send, in coins like 1.5 or in sats like \"150 sats\"'"))
+            .arg(arg!(--fee <FEE>"'Fee paid to the miner, like the amount, defaults to the configured fee'"))
+        )
+}
+
+fn run(matches: &ArgMatches) -> Result<()> {
+    let mut config = Config::load(matches.get_one::<String>("config").map(|s| s.as_str()))?;
+    if let Some(datadir) = matches.get_one::<String>("datadir") {
+        config.datadir = datadir.clone();
+    }
+    if let Some(network) = matches.get_one::<String>("network") {
+        config.network = network.parse()?;
+    }
+    address::set_network(config.network);
+
+    let mut peers: Vec<String> = matches.get_many::<String>("peer").map(|peers| peers.cloned().collect()).unwrap_or_default();
+    if peers.is_empty() {
+        peers = config.peers.clone();
+    }
+    if peers.is_empty() {
+        peers.push(DEFAULT_PEER.to_string());
+    }
+
+    match matches.subcommand() {
+        Some(("createwallet", _)) => {
+            let mut ws = Wallets::new(&config)?;
+            let address = ws.create_wallet();
+            ws.save_all()?;
+            println!("success: address {}", address);
+        },
+        Some(("listaddresses", _)) => {
+            for address in Wallets::new(&config)?.get_all_address() {
+                println!("{}", address);
+            }
+        },
+        Some(("sync", _)) => {
+            let wallet = SpvWallet::open(&config, peers)?;
+            let height = wallet.sync()?;
+            println!("synced to height {}, {} wallet transactions", height, wallet.transactions()?.len());
+        },
+        Some(("getbalance", matches)) => {
+            let wallet = SpvWallet::open(&config, peers)?;
+            wallet.sync()?;
+            let addresses = match matches.get_one::<String>("ADDRESS") {
+                Some(address) => vec![address.clone()],
+                None => Wallets::new(&config)?.get_all_address()
+            };
+            for address in addresses {
+                let (confirmed, unconfirmed) = wallet.balance(&address::decode(&address)?)?;
+                println!("{}: {} confirmed, {} unconfirmed", address, confirmed, unconfirmed);
+            }
+        },
+        Some(("listtransactions", _)) => {
+            let wallet = SpvWallet::open(&config, peers)?;
+            for wtx in wallet.transactions()? {
+                let height = wtx.height.map_or("unconfirmed".to_string(), |h| format!("height {}", h));
+                println!("{} {}", wtx.tx.id, height);
+            }
+        },
+        Some(("send", matches)) => {
+            let from = matches.get_one::<String>("FROM").unwrap();
+            let to = matches.get_one::<String>("TO").unwrap();
+            let amount: Amount = matches.get_one::<String>("AMOUNT").unwrap().parse()?;
+            let fee: Amount = match matches.get_one::<String>("fee") {
+                Some(fee) => fee.parse()?,
+                None => config.fee
+            };
+            let wallet = SpvWallet::open(&config, peers)?;
+            wallet.sync()?;
+            println!("{}", wallet.send(&Wallets::new(&config)?, from, to, amount, fee)?);
+        },
+        _ => {
+            command().print_help()?;
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let matches = command().get_matches();
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn")))
+        .with_writer(std::io::stderr)
+        .init();
+
+    if let Err(e) = run(&matches) {
+        eprintln!("Error: {}", e);
+        exit(1)
+    }
+}
@@ -1,119 +1,423 @@
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Instant, SystemTime};
 use crypto::{digest::Digest, sha2::Sha256};
-use log::info;
-use serde::{Deserialize, Serialize};
-use crate::{error::Result, transaction::Transaction};
+use tracing::{debug, field, info_span};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use crate::{error::{BlockchainError, Result}, transaction::Transaction};
+use crate::hash::Hash256;
+use crate::target::{Target, POW_LIMIT_BITS};
 use merkle_cbt::merkle_tree::Merge;
 use merkle_cbt::merkle_tree::CBMT;
 
 
-pub const TARGET_HEXT: usize = 4;
+/// HEADER_PREFIX_BYTES is the size of the header the proof of work hashes, before the nonce
+pub const HEADER_PREFIX_BYTES: usize = 32 + 32 + 16 + 4;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// PowOptions tunes the proof of work search of `Block::seal`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PowOptions {
+    /// worker threads, 0 for one per CPU core
+    pub threads: usize,
+    /// run the workers at the lowest scheduling priority
+    pub nice: bool
+}
+
+/// BlockHeader is the part of a block that links it to its parent and proves
+/// its work, what header-level APIs return without loading the transactions
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BlockHeader {
+    pub timestamp: u128,
+    pub prev_block_hash: Hash256,
+    pub hash: Hash256,
+    pub height: usize,
+    pub nonce: i32,
+    /// compact target the hash must meet
+    pub bits: u32
+}
+
+/// MerkleStep is one level of a merkle branch, the sibling hash and whether it
+/// is hashed on the left
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MerkleStep {
+    pub hash: Hash256,
+    pub left: bool
+}
+
+/// Block is a mined batch of transactions linked to its parent by hash
+#[derive(Debug, Clone)]
 pub struct Block {
+    header: BlockHeader,
+    transactions: Vec<Transaction>
+}
+
+/// BlockRef is how a block is encoded, its fields in the order blocks were
+/// first written so stored and relayed blocks keep their bytes
+#[derive(Serialize)]
+struct BlockRef<'a> {
+    timestamp: u128,
+    transactions: &'a [Transaction],
+    prev_block_hash: Hash256,
+    hash: Hash256,
+    height: usize,
+    nonce: i32,
+    bits: u32
+}
+
+/// BlockRepr decodes what BlockRef encodes
+#[derive(Deserialize)]
+struct BlockRepr {
     timestamp: u128,
     transactions: Vec<Transaction>,
-    prev_block_hash: String,
-    hash: String,
+    prev_block_hash: Hash256,
+    hash: Hash256,
     height: usize,
-    nonce: i32
+    nonce: i32,
+    bits: u32
+}
+
+/// BodyRepr decodes the transactions of what BlockRef encodes, leaving the
+/// header fields after them undecoded
+#[derive(Deserialize)]
+struct BodyRepr {
+    _timestamp: u128,
+    transactions: Vec<Transaction>
+}
+
+impl Serialize for Block {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        BlockRef {
+            timestamp: self.header.timestamp,
+            transactions: &self.transactions,
+            prev_block_hash: self.header.prev_block_hash,
+            hash: self.header.hash,
+            height: self.header.height,
+            nonce: self.header.nonce,
+            bits: self.header.bits
+        }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Block {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Block, D::Error> {
+        let repr = BlockRepr::deserialize(deserializer)?;
+        Ok(Block {
+            header: BlockHeader {
+                timestamp: repr.timestamp,
+                prev_block_hash: repr.prev_block_hash,
+                hash: repr.hash,
+                height: repr.height,
+                nonce: repr.nonce,
+                bits: repr.bits
+            },
+            transactions: repr.transactions
+        })
+    }
 }
 
 
 impl Block {
 
+    /// get_transactions returns the transactions of the block, coinbase last
     pub fn get_transactions(&self) -> &Vec<Transaction> {
         &self.transactions
     }
 
-    pub fn new_genesis_block(coinbase: Transaction) -> Block {
-        Block::new_block(vec![coinbase], String::new(), 0).unwrap()
+    /// new_genesis_block mines the first block of a chain around its coinbase, at the pow limit
+    pub fn new_genesis_block(coinbase: Transaction) -> Result<Block> {
+        Block::new_block(vec![coinbase], Hash256::ZERO, 0, POW_LIMIT_BITS)
     }
 
-    pub fn new_block(data: Vec<Transaction>, prev_block_hash: String, height: usize) -> Result<Block> {
+    /// new_block mines a block of `data` on top of `prev_block_hash` meeting the compact target `bits`
+    pub fn new_block(data: Vec<Transaction>, prev_block_hash: Hash256, height: usize, bits: u32) -> Result<Block> {
+        let never = AtomicBool::new(false);
+        Block::seal(data, prev_block_hash, height, bits, PowOptions::default(), &never, &AtomicU64::new(0))?
+            .ok_or_else(|| BlockchainError::Consensus(format!("mining of block at height {} was aborted", height)))
+    }
+
+    /// seal mines like `new_block` but gives up and returns None once `abort` is set,
+    /// the hashes tried are added to `hashes` as they go
+    pub fn seal(data: Vec<Transaction>, prev_block_hash: Hash256, height: usize, bits: u32, options: PowOptions, abort: &AtomicBool, hashes: &AtomicU64) -> Result<Option<Block>> {
         let timestamp: u128 = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH).unwrap()
+            .duration_since(SystemTime::UNIX_EPOCH)?
             .as_millis();
 
         let mut block = Block {
-            timestamp,
-            transactions: data,
-            prev_block_hash,
-            hash: String::new(),
-            height,
-            nonce: 0
+            header: BlockHeader {
+                timestamp,
+                prev_block_hash,
+                hash: Hash256::ZERO,
+                height,
+                nonce: 0,
+                bits
+            },
+            transactions: data
         };
 
-        block.run_proof_if_work()?;
-        Ok(block)
+        if !block.run_proof_if_work(options, abort, hashes)? {
+            return Ok(None);
+        }
+        Ok(Some(block))
 
     }
 
-    pub fn get_hash(&self) -> String {
-        self.hash.clone()
+    /// get_hash returns the proof of work hash of the block
+    pub fn get_hash(&self) -> Hash256 {
+        self.header.hash
     }
 
-    fn run_proof_if_work(&mut self) -> Result<()> {
+    /// run_proof_if_work splits the nonce space across the worker threads, each
+    /// hashing the fixed-size header, and keeps the first solution found, it tells
+    /// whether one was found before `abort` was set
+    fn run_proof_if_work(&mut self, options: PowOptions, abort: &AtomicBool, hashes: &AtomicU64) -> Result<bool> {
+        let span = info_span!("mine", height = self.header.height, hash = field::Empty, nonce = field::Empty, hashes = field::Empty, hashrate = field::Empty, duration_ms = field::Empty);
+        let _entered = span.enter();
+        let started = Instant::now();
+
+        let workers = match options.threads {
+            0 => thread::available_parallelism().map_or(1, |n| n.get()),
+            threads => threads
+        };
+        debug!("Mining the block on {} threads!", workers);
+
+        let target = Target::from_compact(self.header.bits)?;
+        let mut prefix = Sha256::new();
+        prefix.input(&self.own_header_prefix()?);
+        let found = AtomicBool::new(false);
+        let hashes_before = hashes.load(Ordering::Relaxed);
+        let solution = thread::scope(|scope| {
+            let handles: Vec<_> = (0..workers)
+                .map(|worker| {
+                    let found = &found;
+                    scope.spawn(move || {
+                        if options.nice {
+                            lower_priority();
+                        }
+                        grind(prefix, target, worker as i32, workers as i32, [found, abort], hashes)
+                    })
+                })
+                .collect();
+            handles.into_iter().filter_map(|handle| handle.join().ok().flatten()).next()
+        });
+
+        let (nonce, hash) = match solution {
+            Some(solution) => solution,
+            None if abort.load(Ordering::Relaxed) => {
+                debug!("mining aborted after {} hashes", hashes.load(Ordering::Relaxed) - hashes_before);
+                return Ok(false);
+            },
+            None => return Err(BlockchainError::Consensus(format!("no nonce solves block at height {}", self.header.height)))
+        };
+        self.header.nonce = nonce;
+        self.header.hash = Hash256::new(hash);
+
+        let hashes = hashes.load(Ordering::Relaxed) - hashes_before;
+        let elapsed = started.elapsed();
+        span.record("hash", field::display(self.header.hash));
+        span.record("nonce", self.header.nonce);
+        span.record("hashes", hashes);
+        span.record("hashrate", (hashes as f64 / elapsed.as_secs_f64().max(f64::EPSILON)) as u64);
+        span.record("duration_ms", elapsed.as_millis() as u64);
+        Ok(true)
+
+    }
 
-        info!("Mining the block!");
-        
-        while !self.validate().unwrap() {
-            self.nonce += 1;
+    /// merkle_root is the 32 byte root of the merkle tree over the ids of `transactions`
+    pub fn merkle_root(transactions: &[Transaction]) -> Result<Vec<u8>> {
+        let mut hashes = Vec::new();
+        for tx in transactions {
+            hashes.push(tx.hash()?.as_bytes().to_vec());
         }
 
-        let data: Vec<u8> = self.preapre_hash_data().unwrap();
-
-        let mut hasher = Sha256::new();
+        let tree = CBMT::<Vec<u8>, MergeTX>::build_merkle_tree(&hashes);
+        Ok(tree.root())
+    }
 
-        hasher.input(&data[..]);
-        self.hash = hasher.result_str();
-        Ok(())
+    /// merkle_branch is the path from the transaction at `index` of
+    /// `transactions` up to the merkle root, the sibling hash of every level
+    pub fn merkle_branch(transactions: &[Transaction], index: usize) -> Result<Vec<MerkleStep>> {
+        if index >= transactions.len() {
+            return Err(BlockchainError::Consensus(format!("no transaction {} in a block of {}", index, transactions.len())));
+        }
+        let mut hashes = Vec::new();
+        for tx in transactions {
+            hashes.push(tx.hash()?.as_bytes().to_vec());
+        }
+        let tree = CBMT::<Vec<u8>, MergeTX>::build_merkle_tree(&hashes);
+        let nodes = tree.nodes();
+
+        // the leaves are the last nodes, the children of node i are 2i + 1 and 2i + 2
+        let mut node = transactions.len() - 1 + index;
+        let mut branch = Vec::new();
+        while node > 0 {
+            let (sibling, left) = if node % 2 == 1 { (node + 1, false) } else { (node - 1, true) };
+            branch.push(MerkleStep { hash: Hash256::from_slice(&nodes[sibling])?, left });
+            node = (node - 1) / 2;
+        }
+        Ok(branch)
+    }
 
+    /// merkle_root_from_branch hashes `leaf` up `branch`, giving the merkle root
+    /// of the block the branch was taken from when it holds the leaf
+    pub fn merkle_root_from_branch(leaf: &Hash256, branch: &[MerkleStep]) -> Vec<u8> {
+        branch.iter().fold(leaf.as_bytes().to_vec(), |node, step| {
+            let sibling = step.hash.as_bytes().to_vec();
+            if step.left { MergeTX::merge(&sibling, &node) } else { MergeTX::merge(&node, &sibling) }
+        })
     }
 
-    fn hash_transactions(&self) -> Result<Vec<u8>> {
-        let mut transactions = Vec::new();
-        for tx in &self.transactions {
-            let mut tx_copy = tx.clone();
-            transactions.push(tx_copy.hash()?.as_bytes().to_owned());
+    /// header_prefix is the fixed-size header without the nonce: the parent hash,
+    /// the merkle root, the time and the compact target, HEADER_PREFIX_BYTES in
+    /// all, the nonce is hashed appended to it as 4 little endian bytes
+    pub fn header_prefix(prev_block_hash: &Hash256, merkle_root: &[u8], timestamp: u128, bits: u32) -> Result<Vec<u8>> {
+        if merkle_root.len() != 32 {
+            return Err(BlockchainError::Consensus(format!("merkle root {} is not 32 bytes", hex::encode(merkle_root))));
         }
-    
-        let tree = CBMT::<Vec<u8>, MergeTX>::build_merkle_tree(&*transactions);
-        
-        Ok(tree.root())
+        let mut header = Vec::with_capacity(HEADER_PREFIX_BYTES);
+        // the genesis block has no parent, its header has the zero parent hash
+        header.extend_from_slice(prev_block_hash.as_bytes());
+        header.extend_from_slice(merkle_root);
+        header.extend_from_slice(&timestamp.to_le_bytes());
+        header.extend_from_slice(&bits.to_le_bytes());
+        Ok(header)
+    }
+
+    fn own_header_prefix(&self) -> Result<Vec<u8>> {
+        let header = &self.header;
+        Block::header_prefix(&header.prev_block_hash, &Block::merkle_root(&self.transactions)?, header.timestamp, header.bits)
+    }
+
+    /// validate tells whether the header hash meets the target and is the block hash
+    pub fn validate(&self) -> Result<bool> {
+        let hash = pow_hash(&self.own_header_prefix()?, self.header.nonce);
+        Ok(Target::from_compact(self.header.bits)?.is_met_by(&hash) && Hash256::new(hash) == self.header.hash)
+    }
 
-    }   
+    /// from_solution builds a block sealed elsewhere from the header fields the
+    /// work was handed out with and the nonce found for them, the hash is
+    /// recomputed and not checked against the target
+    pub fn from_solution(transactions: Vec<Transaction>, prev_block_hash: Hash256, height: usize, bits: u32, timestamp: u128, nonce: i32) -> Result<Block> {
+        let mut block = Block {
+            header: BlockHeader {
+                timestamp,
+                prev_block_hash,
+                hash: Hash256::ZERO,
+                height,
+                nonce,
+                bits
+            },
+            transactions
+        };
+        block.header.hash = Hash256::new(pow_hash(&block.own_header_prefix()?, nonce));
+        Ok(block)
+    }
 
-    fn preapre_hash_data(&self) -> Result<Vec<u8>> {
-        let content = (
-            self.prev_block_hash.clone(),
-            self.transactions.clone(),
-            self.timestamp,
-            TARGET_HEXT,
-            self.nonce
-        );
+    /// get_prev_hash returns the hash of the parent block, zero for the genesis block
+    pub fn get_prev_hash(&self) -> Hash256 {
+        self.header.prev_block_hash
+    }
 
-        let bytes = bincode::serialize(&content)?;
-        Ok(bytes)
+    /// get_timestamp returns the mining time in milliseconds since the unix epoch
+    pub fn get_timestamp(&self) -> u128 {
+        self.header.timestamp
+    }
 
+    /// get_height returns the distance from the genesis block
+    pub fn get_height(&self) -> usize {
+        self.header.height
     }
 
-    fn validate(&self) -> Result<bool> {
-        let data = self.preapre_hash_data()?;
-        let mut hasher = Sha256::new();
+    /// get_nonce returns the nonce that satisfied the proof of work
+    pub fn get_nonce(&self) -> i32 {
+        self.header.nonce
+    }
+
+    /// get_bits returns the compact target the hash meets
+    pub fn get_bits(&self) -> u32 {
+        self.header.bits
+    }
+
+    /// header returns the header of the block
+    pub fn header(&self) -> &BlockHeader {
+        &self.header
+    }
+
+    /// from_parts rebuilds a block from its header and its transactions, read back
+    /// from storage
+    pub fn from_parts(header: BlockHeader, transactions: Vec<Transaction>) -> Block {
+        Block { header, transactions }
+    }
+
+    /// decode_transactions reads only the transactions of an encoded block
+    pub fn decode_transactions(encoded: &[u8]) -> Result<Vec<Transaction>> {
+        let body: BodyRepr = bincode::deserialize(encoded)?;
+        Ok(body.transactions)
+    }
+
+    /// into_parts splits the block into its header and its transactions
+    pub fn into_parts(self) -> (BlockHeader, Vec<Transaction>) {
+        (self.header, self.transactions)
+    }
+
+}
 
-        hasher.input(&data[..]);
+/// HASH_BATCH is how many hashes a worker tries between updates of the shared counter
+const HASH_BATCH: u64 = 1024;
+
+/// grind tries the nonces `first`, `first + step`, ... until one meets `target`
+/// or one of the `stop` flags is set, by another worker or by the caller
+fn grind(prefix: Sha256, target: Target, first: i32, step: i32, stop: [&AtomicBool; 2], hashes: &AtomicU64) -> Option<(i32, [u8; 32])> {
+    let mut hash = [0; 32];
+    let mut tried = 0;
+    let mut nonce = Some(first);
+    while let Some(n) = nonce {
+        if stop.iter().any(|flag| flag.load(Ordering::Relaxed)) {
+            break;
+        }
+        let mut hasher = prefix;
+        hasher.input(&n.to_le_bytes());
+        hasher.result(&mut hash);
+        tried += 1;
+        if tried == HASH_BATCH {
+            hashes.fetch_add(tried, Ordering::Relaxed);
+            tried = 0;
+        }
 
-        let mut vec1: Vec<u8> = vec![];
-        vec1.resize(TARGET_HEXT, '0' as u8);
-        
-        Ok(&hasher.result_str()[0..TARGET_HEXT] == String::from_utf8(vec1).unwrap())
+        if target.is_met_by(&hash) {
+            stop[0].store(true, Ordering::Relaxed);
+            hashes.fetch_add(tried, Ordering::Relaxed);
+            return Some((n, hash));
+        }
+        nonce = n.checked_add(step);
     }
+    hashes.fetch_add(tried, Ordering::Relaxed);
+    None
+}
 
-    pub fn get_prev_hash(&self) -> String {
-        self.prev_block_hash.clone()
+/// lower_priority gives the calling thread the lowest scheduling priority, on Linux
+/// the nice value is per thread so the rest of the node is not slowed down
+#[cfg(target_os = "linux")]
+fn lower_priority() {
+    // SAFETY: setpriority only reads its integer arguments
+    let res = unsafe { libc::setpriority(libc::PRIO_PROCESS, libc::gettid() as libc::id_t, 19) };
+    if res != 0 {
+        debug!("could not lower the mining thread priority: {}", std::io::Error::last_os_error());
     }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn lower_priority() {
+    debug!("lowering the mining thread priority is only supported on Linux");
+}
 
+/// pow_hash is the sha256 of a header prefix followed by `nonce` as 4 little endian bytes
+pub fn pow_hash(header_prefix: &[u8], nonce: i32) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.input(header_prefix);
+    hasher.input(&nonce.to_le_bytes());
+    let mut hash = [0; 32];
+    hasher.result(&mut hash);
+    hash
 }
 
 
@@ -137,11 +441,22 @@ impl Merge for MergeTX {
 mod tests {
     use super::*;
     use crate::blockchain::Blockchain;
+    use crate::config::Config;
+    use crate::storage::Compression;
+    use crate::wallet::Wallets;
 
     #[test]
     fn test_blockchain() {
 
-        let mut b = Blockchain::new().unwrap();
+        let datadir = std::env::temp_dir().join("blockchain_project_test_blockchain");
+        let _ = std::fs::remove_dir_all(&datadir);
+        let config = Config {
+            datadir: datadir.to_string_lossy().to_string(),
+            ..Config::default()
+        };
+        let address = Wallets::new(&config).unwrap().create_wallet();
+
+        let b = Blockchain::create_blockchain(&config, address, Compression::None).unwrap();
         // b.add_block("data".to_string());
         // b.add_block("data2".to_string());
         // b.add_block("data3".to_string());
@@ -153,4 +468,50 @@ mod tests {
 
     }
 
+    #[test]
+    fn test_proof_of_work() -> Result<()> {
+        let coinbase = Transaction::new_coinbase(crate::wallet::hash_to_address(&[7; 20]), "height 1".to_string())?;
+        let harder = Target::pow_limit().scale(2, 3);
+        let mut block = Block::new_block(vec![coinbase], Hash256::ZERO, 1, harder.to_compact())?;
+        assert!(harder.is_met_by(block.get_hash().as_bytes()));
+        assert!(block.validate()?);
+
+        block.header.nonce = block.header.nonce.wrapping_add(1);
+        assert!(!block.validate()?);
+
+        let hashes = AtomicU64::new(0);
+        let options = PowOptions { threads: 1, nice: true };
+        let niced = Block::seal(block.get_transactions().clone(), Hash256::ZERO, 1, POW_LIMIT_BITS, options, &AtomicBool::new(false), &hashes)?;
+        assert!(niced.expect("not aborted").validate()?);
+        assert!(hashes.load(Ordering::Relaxed) > 0);
+
+        let aborted = Block::seal(block.get_transactions().clone(), Hash256::ZERO, 1, POW_LIMIT_BITS, PowOptions::default(), &AtomicBool::new(true), &hashes)?;
+        assert!(aborted.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pow_hashes_only_the_header() -> Result<()> {
+        let coinbase = |n: u8| Transaction::new_coinbase(crate::wallet::hash_to_address(&[n; 20]), format!("height {}", n));
+        let small = vec![coinbase(1)?];
+        let large = (1..=50).map(coinbase).collect::<Result<Vec<_>>>()?;
+        let prefix = |txs: &[Transaction]| Block::header_prefix(&Hash256::ZERO, &Block::merkle_root(txs)?, 0, POW_LIMIT_BITS);
+        assert_eq!(prefix(&small)?.len(), HEADER_PREFIX_BYTES);
+        assert_eq!(prefix(&large)?.len(), HEADER_PREFIX_BYTES);
+
+        let block = Block::new_block(large, Hash256::ZERO, 1, POW_LIMIT_BITS)?;
+        let header = Block::header_prefix(&block.get_prev_hash(), &Block::merkle_root(block.get_transactions())?, block.get_timestamp(), block.get_bits())?;
+        assert_eq!(Hash256::new(pow_hash(&header, block.get_nonce())), block.get_hash());
+
+        let rebuilt = Block::from_solution(block.get_transactions().clone(), block.get_prev_hash(), 1, block.get_bits(), block.get_timestamp(), block.get_nonce())?;
+        assert_eq!(rebuilt.get_hash(), block.get_hash());
+        let decoded: Block = bincode::deserialize(&bincode::serialize(&block)?)?;
+        assert!(rebuilt.validate()? && decoded.validate()?);
+
+        let mut tampered = block.clone();
+        tampered.transactions.swap(0, 1);
+        assert!(!tampered.validate()?);
+        Ok(())
+    }
+
 }
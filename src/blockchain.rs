@@ -1,72 +1,171 @@
 use std::collections::HashMap;
-
-use failure::format_err;
-use log::info;
-
-use crate::block::Block;
-use crate::error::Result;
-use crate::block::TARGET_HEXT;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+use std::time::{Instant, SystemTime};
+
+use tracing::{debug, field, info, info_span, Span};
+
+use crate::block::{Block, BlockHeader};
+use crate::config::{Config, Network};
+use crate::error::{BlockchainError, Result};
+use crate::events::{Event, EventBus};
+use crate::hash::Hash256;
+use crate::intent::{IntentLog, IntentOp, DEFAULT_TREE};
+use crate::progress::Progress;
+use crate::storage::{Compression, Schema};
+use crate::supply::{self, SupplyAudit};
+use crate::target::{self, Target, Work, RETARGET_INTERVAL};
 use crate::transaction::Transaction;
-
-use crate::tx::TXOutputs;
+use crate::utxoset::{UTXOSet, LEGACY_ADDR_TREE, UTXO_TREE};
+
+const HEIGHTS_TREE: &str = "heights";
+const TXINDEX_TREE: &str = "txindex";
+const CHAINWORK_TREE: &str = "chainwork";
+/// ORPHANS_TREE lists the received blocks whose parent is not stored yet, keyed
+/// by the hash of the parent followed by their own
+const ORPHANS_TREE: &str = "orphans";
+/// INVALID_TREE lists the blocks that failed their checks when connected, and
+/// those stored after them on their branch
+const INVALID_TREE: &str = "invalid";
+const LAST_KEY: &[u8] = b"LAST";
+
+/// MEDIAN_TIME_SPAN is how many blocks, a block and those before it, the
+/// median time past is taken over
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// MAX_FUTURE_BLOCK_TIME is how far, in milliseconds, the time of a block may
+/// be ahead of the local clock
+pub const MAX_FUTURE_BLOCK_TIME: u128 = 2 * 60 * 60 * 1000;
+
+/// BULK_FLUSH_BLOCKS is how many received blocks a bulk sync stores between
+/// two flushes of the database
+const BULK_FLUSH_BLOCKS: u32 = 500;
 
 const GENESIS_COINBASE_DATA: &str = "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks";
 
+/// Blockchain is a cheaply clonable handle to the chain database, clones share
+/// the same sled instance and the same tip so they can be used across threads
 #[derive(Debug, Clone)]
 pub struct Blockchain {
 
-    current_hash: String,
-    db: sled::Db
+    current_hash: Arc<RwLock<Hash256>>,
+    db: Arc<sled::Db>,
+    schema: Schema,
+    events: EventBus,
+    /// the network the chain runs on, which decides how its difficulty adjusts
+    network: Network,
+    /// blocks received since the last flush while a bulk sync is on, None
+    /// when every block is flushed as it is stored
+    bulk_sync: Arc<Mutex<Option<u32>>>,
+    /// held while the tip moves to a received or mined block, so blocks from
+    /// several peers and the miner are connected one at a time
+    connecting: Arc<Mutex<()>>
 
 }
 
+/// BlockchainIter walks the chain from the tip back to the genesis block
 pub struct BlockchainIter<'a> {
-    current_hash: String,
+    current_hash: Hash256,
+    bc: &'a Blockchain
+}
+
+/// HeaderIter walks the chain from the tip back to the genesis block reading only
+/// the headers
+pub struct HeaderIter<'a> {
+    current_hash: Hash256,
+    bc: &'a Blockchain
+}
+
+/// RangeIter reads the headers of a range of heights through the height index,
+/// oldest first or, reversed, newest first
+pub struct RangeIter<'a> {
+    heights: sled::Iter,
     bc: &'a Blockchain
 }
 
 impl Blockchain {
-    pub fn new() -> Result<Blockchain> {
+    /// new opens the block database of the configured network, replaying any
+    /// interrupted update and upgrading an older layout
+    pub fn new(config: &Config) -> Result<Blockchain> {
         info!("open blockchain");
 
-        let db = sled::open("data/blocks")?;
-        let hash = db
-            .get("LAST")?
-            .expect("Must create a new block database first");
+        let db = Arc::new(sled::open(config.blocks_path())?);
+        if IntentLog::new(db.clone()).recover()? {
+            info!("Recovered an interrupted block update");
+        }
 
+        if !db.contains_key(LAST_KEY)? {
+            return Err(BlockchainError::NoChain(config.blocks_path().display().to_string()));
+        }
         info!("Found block database");
 
-        let lasthash = String::from_utf8(hash.to_vec())?;
-
-        Ok(
-            Blockchain {
-                current_hash: lasthash.clone(),
-                db
-            }
-        )
+        let mut schema = Schema::load(&db)?;
+        let upgrade = schema.needs_upgrade();
+        if upgrade {
+            schema.upgrade(&db, &[HEIGHTS_TREE, TXINDEX_TREE, UTXO_TREE, LEGACY_ADDR_TREE])?;
+        }
 
+        let lasthash = match db.get(LAST_KEY)? {
+            Some(hash) => Hash256::from_slice(&hash)?,
+            None => return Err(BlockchainError::NoChain(config.blocks_path().display().to_string()))
+        };
+        let bc = Blockchain {
+            current_hash: Arc::new(RwLock::new(lasthash)),
+            db,
+            schema,
+            events: EventBus::new(),
+            network: config.network,
+            bulk_sync: Arc::new(Mutex::new(None)),
+            connecting: Arc::new(Mutex::new(()))
+        };
+
+        if upgrade {
+            bc.reindex_txindex()?;
+            UTXOSet { blockchain: bc.clone() }.reindex()?;
+            bc.schema.save(&bc.db)?;
+            bc.db.flush()?;
+        }
+        if !bc.db.open_tree(CHAINWORK_TREE)?.contains_key(lasthash)? {
+            bc.reindex_chain_work()?;
+        }
+        Ok(bc)
     }
 
-    pub fn create_blockchain(address: String) -> Result<Blockchain> {
+    /// create_blockchain replaces the block database with a new chain paying the genesis reward to `address`
+    pub fn create_blockchain(config: &Config, address: String, compression: Compression) -> Result<Blockchain> {
         info!("Creating new blockchain");
+        let cbtx = Transaction::new_coinbase(address, String::from(GENESIS_COINBASE_DATA))?;
+        let genesis: Block = Block::new_genesis_block(cbtx)?;
+        Blockchain::create_with_genesis(config, genesis, compression)
+    }
 
-        if let Err(e) = std::fs::remove_dir_all("data/blocks") {
-            info!("blocks not exist to delete")
+    /// create_with_genesis replaces the chain of the configured network with one
+    /// made of `genesis` alone, a block mined elsewhere, e.g. at a fixed time
+    pub fn create_with_genesis(config: &Config, genesis: Block, compression: Compression) -> Result<Blockchain> {
+        if let Err(e) = std::fs::remove_dir_all(config.blocks_path()) {
+            info!("blocks not exist to delete: {}", e)
         }
 
-        let db = sled::open("data/blocks")?;
+        let db = sled::open(config.blocks_path())?;
+        let schema = Schema::new(compression);
+        schema.save(&db)?;
 
         info!("Creating new block database");
-        let cbtx = Transaction::new_coinbase(address, String::from(GENESIS_COINBASE_DATA))?;
-
-        let genesis: Block = Block::new_genesis_block(cbtx);
-
-        db.insert(genesis.get_hash(), bincode::serialize(&genesis)?)?;
-        db.insert("LAST", genesis.get_hash().as_bytes())?;
+        db.insert(genesis.get_hash(), schema.encode_block(&genesis)?)?;
+        db.insert(LAST_KEY, genesis.get_hash().as_bytes())?;
+        db.open_tree(HEIGHTS_TREE)?.insert(height_key(0), genesis.get_hash().as_bytes())?;
+        db.open_tree(CHAINWORK_TREE)?.insert(genesis.get_hash(), &Target::from_compact(genesis.get_bits())?.block_work().to_be_bytes())?;
+        for tx in genesis.get_transactions() {
+            db.open_tree(TXINDEX_TREE)?.insert(tx.id, genesis.get_hash().as_bytes())?;
+        }
 
         let bc = Blockchain {
-            current_hash: genesis.get_hash(),
-            db
+            current_hash: Arc::new(RwLock::new(genesis.get_hash())),
+            db: Arc::new(db),
+            schema,
+            events: EventBus::new(),
+            network: config.network,
+            bulk_sync: Arc::new(Mutex::new(None)),
+            connecting: Arc::new(Mutex::new(()))
             };
        
        bc.db.flush()?;
@@ -75,27 +174,47 @@ impl Blockchain {
     }
 
        
-    /// FindTransaction finds a transaction by its ID
-    pub fn find_transaction(&self, id: &str) -> Result<Transaction> {
+    /// FindTransaction finds a transaction by its ID, through the txindex when it knows the ID
+    pub fn find_transaction(&self, id: &Hash256) -> Result<Transaction> {
+        if let Some((tx, _)) = self.get_indexed_transaction(id)? {
+            return Ok(tx);
+        }
+
         for b in self.iter() {
             for tx in b.get_transactions() {
-                if tx.id == id {
+                if tx.id == *id {
                     return Ok(tx.clone());
                 }
             }
         }
-        Err(format_err!("Transaction not found!"))
+        Err(BlockchainError::TxNotFound(id.to_string()))
     }
 
-    fn get_prev_txs(&self, tx: &Transaction) -> Result<HashMap<String, Transaction>> {
+    /// get_indexed_transaction looks a transaction up in the txindex and returns it
+    /// with the header of its block
+    pub fn get_indexed_transaction(&self, id: &Hash256) -> Result<Option<(Transaction, BlockHeader)>> {
+        let hash = match self.db.open_tree(TXINDEX_TREE)?.get(id)? {
+            Some(hash) => Hash256::from_slice(&hash)?,
+            None => return Ok(None)
+        };
+
+        let (header, transactions) = self.get_block(&hash)?.into_parts();
+        match transactions.into_iter().find(|tx| tx.id == *id) {
+            Some(tx) => Ok(Some((tx, header))),
+            None => Err(BlockchainError::Corrupt(format!("txindex points {} at block {} which does not contain it", id, hash)))
+        }
+    }
+
+    fn get_prev_txs(&self, tx: &Transaction) -> Result<HashMap<Hash256, Transaction>> {
         let mut prev_txs = HashMap::new();
         for vin in &tx.vin {
-            let prev_tx = self.find_transaction(&vin.txid)?;
-            prev_txs.insert(prev_tx.id.clone(), prev_tx);
+            let prev_tx = self.find_transaction(&vin.prev_out.txid)?;
+            prev_txs.insert(prev_tx.id, prev_tx);
         }
         Ok(prev_txs)
     }
 
+    /// sign_transaction signs every input of `tx` with `private_key`
     pub fn sign_transaction(&self, tx: &mut Transaction, private_key: &[u8]) -> Result<()> {
         let prev_TXs = self.get_prev_txs(tx)?;
 
@@ -103,88 +222,469 @@ impl Blockchain {
         Ok(())
     }
 
+    /// verify_transaction checks the input signatures of `tx` against the outputs they spend
     pub fn verify_transaction(&self, tx: &mut Transaction) -> Result<bool> {
         let prev_txs = self.get_prev_txs(tx)?;
         tx.verify(prev_txs)
     }
 
-    pub fn add_block(&mut self, transactions: Vec<Transaction>) -> Result<Block> {
-        let lasthash = self.db.get("LAST")?.unwrap();
-        
-        let new_block = Block::new_block(transactions, String::from_utf8(lasthash.to_vec())?, TARGET_HEXT).unwrap();
-        
-        self.db.insert(new_block.get_hash(), bincode::serialize(&new_block)?)?;
-        self.db.insert("LAST", new_block.get_hash().as_bytes())?;
-        self.current_hash = new_block.get_hash();
+    /// add_block mines `transactions` into a block and makes it the new tip
+    pub fn add_block(&self, transactions: Vec<Transaction>) -> Result<Block> {
+        let new_block = self.mine_block(transactions)?;
+        self.connect_block(&new_block, Vec::new())?;
 
         Ok(new_block)
     }
 
-    pub fn iter(&self) -> BlockchainIter {
-        BlockchainIter {
-            current_hash: self.current_hash.clone(),
-            bc: &self
+    /// mine_block mines a block on top of the current tip without storing it
+    pub fn mine_block(&self, transactions: Vec<Transaction>) -> Result<Block> {
+        let lasthash = self.get_tip();
+        let height = self.get_block_header(&lasthash)?.height + 1;
+
+        let new_block = Block::new_block(transactions, lasthash, height, self.next_bits()?)?;
+        Ok(new_block)
+    }
+
+    /// receive_block stores a block received from a peer without moving the tip,
+    /// returning the header of it, or of an orphan it completes the chain of,
+    /// when that chain has more work than the current one
+    pub fn receive_block(&self, block: &Block) -> Result<Option<BlockHeader>> {
+        let span = info_span!("receive_block", hash = %block.get_hash(), height = block.get_height(), new_tip = field::Empty, duration_ms = field::Empty);
+        let _entered = span.enter();
+        let started = Instant::now();
+        let result = self.store_received_block(block);
+        span.record("duration_ms", started.elapsed().as_millis() as u64);
+        result
+    }
+
+    fn store_received_block(&self, block: &Block) -> Result<Option<BlockHeader>> {
+        if self.db.get(block.get_hash())?.is_some() {
+            debug!("block already known");
+            return Ok(None);
+        }
+        if self.is_invalid(&block.get_prev_hash())? {
+            return Err(BlockchainError::Consensus(format!("block {} builds on the invalid block {}", block.get_hash(), block.get_prev_hash())));
         }
+
+        // a block whose parent is known has to follow it to be stored
+        if let Some(parent) = self.db.get(block.get_prev_hash())? {
+            self.check_header(block.header(), &self.schema.decode_header(&parent)?)?;
+        }
+
+        let mut ops = vec![IntentOp::insert(DEFAULT_TREE, block.get_hash().as_bytes(), self.schema.encode_block(block)?)];
+        let new_tip = match self.chain_work(&block.get_prev_hash())? {
+            Some(prev_work) => self.connect_work(block.header(), prev_work, self.get_chain_work(&self.get_tip())?, &mut ops)?,
+            None => {
+                debug!("parent {} not stored yet", block.get_prev_hash());
+                let mut key = block.get_prev_hash().as_bytes().to_vec();
+                key.extend_from_slice(block.get_hash().as_bytes());
+                ops.push(IntentOp::insert(ORPHANS_TREE, &key, Vec::new()));
+                None
+            }
+        };
+        Span::current().record("new_tip", new_tip.is_some());
+
+        self.commit_received(&ops)?;
+        Ok(new_tip)
     }
 
-    pub fn find_UTXO(&self) -> HashMap<String, TXOutputs> {
-        let mut utxos: HashMap<String, TXOutputs> = HashMap::new();
-        let mut spend_txos: HashMap<String, Vec<i32>> = HashMap::new();
+    /// connect_work plans the chain work of `header`, whose parent has
+    /// `prev_work`, and of the orphans waiting on it that follow their parent,
+    /// returning the one with the most work when that is more than `best_work`
+    fn connect_work(&self, header: &BlockHeader, prev_work: Work, best_work: Work, ops: &mut Vec<IntentOp>) -> Result<Option<BlockHeader>> {
+        let orphans = self.db.open_tree(ORPHANS_TREE)?;
+        let mut best: Option<(Work, BlockHeader)> = None;
+        let mut pending = vec![(header.clone(), prev_work)];
+        while let Some((header, prev_work)) = pending.pop() {
+            let work = prev_work + Target::from_compact(header.bits)?.block_work();
+            ops.push(IntentOp::insert(CHAINWORK_TREE, header.hash.as_bytes(), work.to_be_bytes().to_vec()));
+            for entry in orphans.scan_prefix(header.hash) {
+                let (key, _) = entry?;
+                ops.push(IntentOp::remove(ORPHANS_TREE, &key));
+                let orphan = self.get_block_header(&Hash256::from_slice(&key[32..])?)?;
+                // an orphan was stored before its parent could check it
+                if let Err(e) = self.check_header(&orphan, &header) {
+                    debug!("dropping orphan: {}", e);
+                    continue;
+                }
+                pending.push((orphan, work));
+            }
+            if work > best.as_ref().map_or(best_work, |(best, _)| *best) {
+                best = Some((work, header));
+            }
+        }
+        Ok(best.map(|(_, header)| header))
+    }
 
+    /// commit_received commits the ops storing or connecting a block, flushing only
+    /// every BULK_FLUSH_BLOCKS blocks during a bulk sync
+    fn commit_received(&self, ops: &[IntentOp]) -> Result<()> {
+        let mut bulk_sync = self.bulk_sync.lock().unwrap_or_else(PoisonError::into_inner);
+        match bulk_sync.as_mut() {
+            Some(unflushed) if *unflushed + 1 < BULK_FLUSH_BLOCKS => {
+                self.intents().commit_unflushed(ops)?;
+                *unflushed += 1;
+            },
+            Some(unflushed) => {
+                self.intents().commit(ops)?;
+                *unflushed = 0;
+            },
+            None => self.intents().commit(ops)?
+        }
+        Ok(())
+    }
+
+    /// lock_connecting waits for the blocks being connected, and holds off
+    /// others until the guard is dropped
+    pub fn lock_connecting(&self) -> MutexGuard<'_, ()> {
+        self.connecting.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// set_bulk_sync turns on or off the deferred flushes of received blocks,
+    /// turning them off flushes what was deferred
+    pub fn set_bulk_sync(&self, on: bool) -> Result<()> {
+        let mut bulk_sync = self.bulk_sync.lock().unwrap_or_else(PoisonError::into_inner);
+        if on {
+            bulk_sync.get_or_insert(0);
+        } else if bulk_sync.take().is_some() {
+            self.db.flush()?;
+        }
+        Ok(())
+    }
 
+    /// path_from lists how the best chain moved since `from` was its tip: the
+    /// headers that left it, newest first, and those that joined it, oldest
+    /// first. A `from` of None stands for before the genesis
+    pub fn path_from(&self, from: Option<Hash256>) -> Result<(Vec<BlockHeader>, Vec<BlockHeader>)> {
+        self.path(from, self.get_block_header(&self.get_tip())?)
+    }
+
+    /// path lists how to move the tip from `from` to `to`: the headers to
+    /// disconnect, newest first, and those to connect, oldest first
+    pub fn path(&self, from: Option<Hash256>, to: BlockHeader) -> Result<(Vec<BlockHeader>, Vec<BlockHeader>)> {
+        let mut old = match from {
+            Some(hash) => Some(self.get_block_header(&hash)?),
+            None => None
+        };
+
+        let mut cur = to;
+        let mut connected = Vec::new();
+        while old.as_ref().is_none_or(|old| cur.height > old.height) {
+            let prev = cur.prev_block_hash;
+            let height = cur.height;
+            connected.push(cur);
+            if height == 0 {
+                connected.reverse();
+                return Ok((Vec::new(), connected));
+            }
+            cur = self.get_block_header(&prev)?;
+        }
+
+        let mut disconnected = Vec::new();
+        if let Some(mut old) = old.take() {
+            while old.height > cur.height || old.hash != cur.hash {
+                if old.height == 0 {
+                    return Err(BlockchainError::Corrupt(format!("block {} does not lead to the genesis of the chain", old.hash)));
+                }
+                let prev = self.get_block_header(&old.prev_block_hash)?;
+                disconnected.push(std::mem::replace(&mut old, prev));
+                if old.height < cur.height {
+                    let prev = self.get_block_header(&cur.prev_block_hash)?;
+                    connected.push(std::mem::replace(&mut cur, prev));
+                }
+            }
+        }
+        connected.reverse();
+        Ok((disconnected, connected))
+    }
+
+    /// get_block reads a single block by its hash
+    pub fn get_block(&self, hash: &Hash256) -> Result<Block> {
+        match self.db.get(hash)? {
+            Some(data) => self.schema.decode_block(&data),
+            None => Err(BlockchainError::BlockNotFound(hash.to_string()))
+        }
+    }
+
+    /// get_block_body reads the transactions of a block, for callers that already
+    /// hold its header
+    pub fn get_block_body(&self, hash: &Hash256) -> Result<Vec<Transaction>> {
+        match self.db.get(hash)? {
+            Some(data) => self.schema.decode_body(&data),
+            None => Err(BlockchainError::BlockNotFound(hash.to_string()))
+        }
+    }
+
+    /// get_raw_block returns the canonical encoding of a block as stored, the bytes
+    /// it is relayed as, without decoding it
+    pub fn get_raw_block(&self, hash: &Hash256) -> Result<Vec<u8>> {
+        match self.db.get(hash)? {
+            Some(data) => Ok(self.schema.raw_block(&data)?.into_owned()),
+            None => Err(BlockchainError::BlockNotFound(hash.to_string()))
+        }
+    }
+
+    /// reindex_txindex rebuilds the height index and the txindex from the blocks,
+    /// returning the number of transactions indexed
+    pub fn reindex_txindex(&self) -> Result<usize> {
+        let heights = self.db.open_tree(HEIGHTS_TREE)?;
+        let txindex = self.db.open_tree(TXINDEX_TREE)?;
+        heights.clear()?;
+        txindex.clear()?;
+
+        let mut count = 0;
+        let mut progress = Progress::new("index transactions", self.get_best_height()? as u64 + 1);
         for block in self.iter() {
+            progress.inc(1);
+            heights.insert(height_key(block.get_height()), block.get_hash().as_bytes())?;
             for tx in block.get_transactions() {
-                for index in 0..tx.vout.len() {
-                    if let Some(ids) = spend_txos.get(&tx.id) {
-                        if ids.contains(&(index as i32)) {
-                            continue;
-                        }
-                    }
+                txindex.insert(tx.id, block.get_hash().as_bytes())?;
+                count += 1;
+            }
+        }
+        progress.finish();
+        self.db.flush()?;
 
-                    match utxos.get_mut(&tx.id) {
-                        Some(v) => {
-                            v.outputs.push(tx.vout[index].clone());
-                        },
-                        None => {
-                            utxos.insert(
-                                tx.id.clone(),
-                                TXOutputs {
-                                    outputs: vec![tx.vout[index].clone()]
-                                },
-                            );
-                        }
-                    }
+        Ok(count)
+    }
 
-                    if !tx.is_coinbase() {
-                        for i in &tx.vin {
-                            match spend_txos.get_mut(&i.txid) {
-                                Some(v) => {
-                                    v.push(i.vout);
-                                },
-                                None => {
-                                    spend_txos.insert(i.txid.clone(), vec![i.vout]);
-                                }
-                            }
-                        }
-                    }
-                }
+    /// get_block_hash looks up the hash of the block at `height` in the height index
+    pub fn get_block_hash(&self, height: usize) -> Result<Hash256> {
+        match self.db.open_tree(HEIGHTS_TREE)?.get(height_key(height))? {
+            Some(hash) => Hash256::from_slice(&hash),
+            None => Err(BlockchainError::BlockNotFound(format!("at height {}", height)))
+        }
+    }
+
+    /// get_block_header reads the header of a block without decoding its transactions
+    pub fn get_block_header(&self, hash: &Hash256) -> Result<BlockHeader> {
+        match self.db.get(hash)? {
+            Some(data) => self.schema.decode_header(&data),
+            None => Err(BlockchainError::BlockNotFound(hash.to_string()))
+        }
+    }
+
+    /// get_best_height returns the height of the tip, reading only its header
+    pub fn get_best_height(&self) -> Result<i32> {
+        Ok(self.get_block_header(&self.get_tip())?.height as i32)
+    }
+
+    /// confirmations counts the blocks of the best chain from the block of
+    /// `header` to the tip, 0 for a block off the best chain
+    pub fn confirmations(&self, header: &BlockHeader) -> Result<usize> {
+        match self.get_block_hash(header.height) {
+            Ok(hash) if hash == header.hash => Ok((self.get_best_height()?.max(0) as usize + 1).saturating_sub(header.height)),
+            Ok(_) | Err(BlockchainError::BlockNotFound(_)) => Ok(0),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// get_chain_work is the total work of the chain ending at block `hash`
+    pub fn get_chain_work(&self, hash: &Hash256) -> Result<Work> {
+        self.chain_work(hash)?.ok_or_else(|| BlockchainError::BlockNotFound(format!("chain work of {}", hash)))
+    }
+
+    /// audit_supply replays the best chain from genesis to check that no block
+    /// issued more than the subsidy and the UTXO set holds what the chain allows
+    pub fn audit_supply(&self) -> Result<SupplyAudit> {
+        supply::audit(self)
+    }
+
+    /// chain_work is the total work of the chain ending at block `hash`, None
+    /// when the block or one of its ancestors is not stored
+    pub fn chain_work(&self, hash: &Hash256) -> Result<Option<Work>> {
+        match self.db.open_tree(CHAINWORK_TREE)?.get(hash)? {
+            Some(work) => Ok(Some(Work::from_slice(&work)?)),
+            None => Ok(None)
+        }
+    }
+
+    /// reindex_chain_work stores the chain work of the blocks of the best chain,
+    /// for a database written before it was tracked
+    fn reindex_chain_work(&self) -> Result<()> {
+        let chainwork = self.db.open_tree(CHAINWORK_TREE)?;
+        let mut headers: Vec<BlockHeader> = self.iter_headers().collect();
+        headers.reverse();
+        let mut work = Work::ZERO;
+        for header in headers {
+            work = work + Target::from_compact(header.bits)?.block_work();
+            chainwork.insert(header.hash, &work.to_be_bytes())?;
+        }
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// next_bits is the compact target the block after the tip must meet
+    pub fn next_bits(&self) -> Result<u32> {
+        self.bits_after(&self.get_block_header(&self.get_tip())?)
+    }
+
+    /// bits_after is the compact target the child of `parent` must meet, the
+    /// interval of a retarget is timed along the branch of `parent`
+    fn bits_after(&self, parent: &BlockHeader) -> Result<u32> {
+        target::next_bits(self.network, parent.height, parent.bits, || {
+            let mut first = parent.clone();
+            for _ in 1..RETARGET_INTERVAL {
+                first = self.get_block_header(&first.prev_block_hash)?;
             }
+            Ok(parent.timestamp.saturating_sub(first.timestamp))
+        })
+    }
+
+    /// check_header checks that `header` follows `parent`: one height above it,
+    /// with the target the retargeting gives its child and a valid time
+    fn check_header(&self, header: &BlockHeader, parent: &BlockHeader) -> Result<()> {
+        if header.height != parent.height + 1 {
+            return Err(BlockchainError::Consensus(format!("block {} has height {} but its parent {} has height {}", header.hash, header.height, parent.hash, parent.height)));
+        }
+        let bits = self.bits_after(parent)?;
+        if header.bits != bits {
+            return Err(BlockchainError::Consensus(format!("block {} has target {:08x} instead of {:08x}", header.hash, header.bits, bits)));
+        }
+        self.check_timestamp(header, parent)
+    }
+
+    /// median_time_past is the median time of `parent` and the blocks before
+    /// it, MEDIAN_TIME_SPAN at most
+    pub fn median_time_past(&self, parent: &BlockHeader) -> Result<u128> {
+        let mut times = vec![parent.timestamp];
+        let mut header = parent.clone();
+        while times.len() < MEDIAN_TIME_SPAN && header.height > 0 {
+            header = self.get_block_header(&header.prev_block_hash)?;
+            times.push(header.timestamp);
         }
+        times.sort_unstable();
+        Ok(times[times.len() / 2])
+    }
 
-        utxos
+    /// check_timestamp checks that the child `header` of `parent` is timed after
+    /// the median time past of `parent` and at most MAX_FUTURE_BLOCK_TIME ahead
+    /// of the local clock, so future times cannot ease the next retarget
+    pub fn check_timestamp(&self, header: &BlockHeader, parent: &BlockHeader) -> Result<()> {
+        let median = self.median_time_past(parent)?;
+        if header.timestamp <= median {
+            return Err(BlockchainError::Consensus(format!("block {} has time {}, not after the median time past {}", header.hash, header.timestamp, median)));
+        }
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis();
+        if header.timestamp > now + MAX_FUTURE_BLOCK_TIME {
+            return Err(BlockchainError::Consensus(format!("block {} has time {}, more than two hours ahead of {}", header.hash, header.timestamp, now)));
+        }
+        Ok(())
+    }
 
+    /// is_invalid tells whether block `hash` failed its checks or builds on one that did
+    pub fn is_invalid(&self, hash: &Hash256) -> Result<bool> {
+        Ok(self.db.open_tree(INVALID_TREE)?.contains_key(hash)?)
+    }
+
+    /// mark_invalid records that block `hash` failed its checks, so it is never
+    /// connected and no block building on it is stored
+    pub fn mark_invalid(&self, hash: &Hash256) -> Result<()> {
+        self.db.open_tree(INVALID_TREE)?.insert(hash, Vec::new())?;
+        Ok(())
+    }
+
+    /// get_difficulty is the difficulty of the tip, 1 at the pow limit
+    pub fn get_difficulty(&self) -> Result<f64> {
+        Ok(Target::from_compact(self.get_block_header(&self.get_tip())?.bits)?.difficulty())
+    }
+
+    /// get_block_hashs returns the hashes of all blocks, from the tip down to genesis
+    pub fn get_block_hashs(&self) -> Vec<Hash256> {
+        self.iter_headers().map(|header| header.hash).collect()
+    }
+
+    /// connect_block stores the block as the new tip, together with the index
+    /// updates in `ops`, as one intent so the trees never diverge
+    pub fn connect_block(&self, block: &Block, mut ops: Vec<IntentOp>) -> Result<()> {
+        let work = self.get_chain_work(&block.get_prev_hash())? + Target::from_compact(block.get_bits())?.block_work();
+        ops.push(IntentOp::insert(DEFAULT_TREE, block.get_hash().as_bytes(), self.schema.encode_block(block)?));
+        ops.append(&mut index_ops(block));
+        ops.push(IntentOp::insert(CHAINWORK_TREE, block.get_hash().as_bytes(), work.to_be_bytes().to_vec()));
+        ops.push(IntentOp::insert(DEFAULT_TREE, LAST_KEY, block.get_hash().as_bytes().to_vec()));
+
+        self.commit_received(&ops)?;
+        *self.current_hash.write().unwrap_or_else(PoisonError::into_inner) = block.get_hash();
+        self.events.publish(Event::BlockConnected { hash: block.get_hash(), height: block.get_height() });
+        Ok(())
+    }
+
+    /// disconnect_block takes the tip `block` off the best chain, dropping its
+    /// height and txindex entries, together with the index updates in `ops`
+    pub fn disconnect_block(&self, block: &Block, mut ops: Vec<IntentOp>) -> Result<()> {
+        if block.get_hash() != self.get_tip() {
+            return Err(BlockchainError::Consensus(format!("block {} is not the tip {}", block.get_hash(), self.get_tip())));
+        }
+        ops.push(IntentOp::remove(HEIGHTS_TREE, &height_key(block.get_height())));
+        ops.extend(block.get_transactions().iter().map(|tx| IntentOp::remove(TXINDEX_TREE, tx.id.as_bytes())));
+        ops.push(IntentOp::insert(DEFAULT_TREE, LAST_KEY, block.get_prev_hash().as_bytes().to_vec()));
+
+        self.commit_received(&ops)?;
+        *self.current_hash.write().unwrap_or_else(PoisonError::into_inner) = block.get_prev_hash();
+        self.events.publish(Event::BlockDisconnected { hash: block.get_hash(), height: block.get_height() });
+        Ok(())
+    }
+
+    /// events is the bus announcing blocks connected to and disconnected from this chain
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    /// intents returns the intent log guarding multi-tree updates
+    pub fn intents(&self) -> IntentLog {
+        IntentLog::new(self.db.clone())
+    }
+
+    /// get_tip returns the hash of the last block of the chain
+    pub fn get_tip(&self) -> Hash256 {
+        *self.current_hash.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// open_tree opens a named tree in the shared block database
+    pub fn open_tree(&self, name: &str) -> Result<sled::Tree> {
+        Ok(self.db.open_tree(name)?)
+    }
+
+    /// flush writes every pending change of the database to disk
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// iter walks the blocks from the tip down to genesis
+    pub fn iter(&self) -> BlockchainIter {
+        BlockchainIter {
+            current_hash: self.get_tip(),
+            bc: &self
+        }
+    }
+
+    /// iter_headers walks the headers from the tip down to genesis without decoding
+    /// the transactions
+    pub fn iter_headers(&self) -> HeaderIter<'_> {
+        HeaderIter {
+            current_hash: self.get_tip(),
+            bc: self
+        }
+    }
+
+    /// iter_range reads the headers of the blocks at heights `start..end` through
+    /// the height index without decoding the transactions
+    pub fn iter_range(&self, start: usize, end: usize) -> Result<RangeIter<'_>> {
+        let heights = self.db.open_tree(HEIGHTS_TREE)?.range(height_key(start)..height_key(end.max(start)));
+        Ok(RangeIter { heights, bc: self })
     }
 
     /// Find Unspent Transactions return a list of transactions containing unspent outputs
     fn find_unspent_transactions(&self, address: &[u8]) -> Vec<Transaction> {
-        let mut spent_TXOs: HashMap<String, Vec<i32>> = HashMap::new();
+        let mut spent_TXOs: HashMap<Hash256, Vec<u32>> = HashMap::new();
         let mut unspend_TXs: Vec<Transaction> = Vec::new();
 
         for block in self.iter() {
             for tx in block.get_transactions() {
                 for index in 0..tx.vout.len() {
                     if let Some(ids) = spent_TXOs.get(&tx.id) {
-                        if ids.contains(&(index as i32)) {
+                        if ids.contains(&(index as u32)) {
                             continue;
                         }
                     }
@@ -197,12 +697,12 @@ impl Blockchain {
                 if !tx.is_coinbase() {
                     for i in &tx.vin {
                         if i.can_unlock_output_with(address) {
-                            match spent_TXOs.get_mut(&i.txid) {
+                            match spent_TXOs.get_mut(&i.prev_out.txid) {
                                 Some(v) => {
-                                    v.push(i.vout);
+                                    v.push(i.prev_out.index);
                                 }, 
                                 None => {
-                                    spent_TXOs.insert(i.txid.clone(), vec![i.vout]);
+                                    spent_TXOs.insert(i.prev_out.txid, vec![i.prev_out.index]);
                                 }
                             }
                         }
@@ -219,15 +719,27 @@ impl Blockchain {
 }
 
 
+fn height_key(height: usize) -> [u8; 8] {
+    (height as u64).to_be_bytes()
+}
+
+/// index_ops plans the height index and txindex entries of a block of the best chain
+fn index_ops(block: &Block) -> Vec<IntentOp> {
+    let mut ops = vec![IntentOp::insert(HEIGHTS_TREE, &height_key(block.get_height()), block.get_hash().as_bytes().to_vec())];
+    ops.extend(block.get_transactions().iter().map(|tx| IntentOp::insert(TXINDEX_TREE, tx.id.as_bytes(), block.get_hash().as_bytes().to_vec())));
+    ops
+}
+
+
 impl <'a> Iterator for BlockchainIter<'a> {
     type Item = Block;
 
     fn next(&mut self) -> Option<self::Block> {
 
-        if let Ok(encoded_block) = self.bc.db.get(&self.current_hash) {
+        if let Ok(encoded_block) = self.bc.db.get(self.current_hash) {
             return match encoded_block {
                 Some(b) => {
-                    if let Ok(block) = bincode::deserialize::<Block>(&b) {
+                    if let Ok(block) = self.bc.schema.decode_block(&b) {
                         self.current_hash = block.get_prev_hash();
                         Some(block)
                     } else {
@@ -243,4 +755,166 @@ impl <'a> Iterator for BlockchainIter<'a> {
 
 }
 
+impl <'a> Iterator for HeaderIter<'a> {
+    type Item = BlockHeader;
+
+    fn next(&mut self) -> Option<BlockHeader> {
+        let header = self.bc.get_block_header(&self.current_hash).ok()?;
+        self.current_hash = header.prev_block_hash;
+        Some(header)
+    }
+}
 
+impl <'a> RangeIter<'a> {
+    fn header(&self, entry: sled::Result<(sled::IVec, sled::IVec)>) -> Result<BlockHeader> {
+        let (_, hash) = entry?;
+        self.bc.get_block_header(&Hash256::from_slice(&hash)?)
+    }
+}
+
+impl <'a> Iterator for RangeIter<'a> {
+    type Item = Result<BlockHeader>;
+
+    fn next(&mut self) -> Option<Result<BlockHeader>> {
+        let entry = self.heights.next()?;
+        Some(self.header(entry))
+    }
+}
+
+impl <'a> DoubleEndedIterator for RangeIter<'a> {
+    fn next_back(&mut self) -> Option<Result<BlockHeader>> {
+        let entry = self.heights.next_back()?;
+        Some(self.header(entry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::MAX_FUTURE_BLOCK_TIME;
+    use crate::block::{Block, BlockHeader};
+    use crate::error::Result;
+    use crate::hash::Hash256;
+    use crate::target::Target;
+    use crate::testing::{seal_at, ChainFixture};
+    use crate::transaction::Transaction;
+    use crate::utxoset::UTXOSet;
+
+    #[test]
+    fn test_header_iterators_match_the_blocks() -> Result<()> {
+        let fixture = ChainFixture::restore(3)?;
+        let bc = fixture.blockchain()?;
+
+        let hashes: Vec<Hash256> = bc.iter().map(|block| block.get_hash()).collect();
+        assert_eq!(bc.get_block_hashs(), hashes);
+        assert_eq!(hashes.len(), 4);
+
+        let heights = |headers: Vec<Result<BlockHeader>>| -> Result<Vec<usize>> {
+            headers.into_iter().map(|header| Ok(header?.height)).collect()
+        };
+        assert_eq!(heights(bc.iter_range(1, 3)?.collect())?, vec![1, 2]);
+        assert_eq!(heights(bc.iter_range(0, 10)?.rev().collect())?, vec![3, 2, 1, 0]);
+        assert!(bc.iter_range(3, 1)?.next().is_none());
+        let newest = bc.iter_range(3, 4)?.next().expect("the tip is indexed")?;
+        assert_eq!(newest.hash, hashes[0]);
+
+        let tip = bc.get_block(&hashes[0])?;
+        assert_eq!(&bc.get_block_header(&hashes[0])?, tip.header());
+        let body = bc.get_block_body(&hashes[0])?;
+        assert_eq!(body.iter().map(|tx| tx.id).collect::<Vec<_>>(), tip.get_transactions().iter().map(|tx| tx.id).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_blocks_received_in_bulk_sync_are_kept() -> Result<()> {
+        let longer = ChainFixture::restore(6)?;
+        let source = longer.blockchain()?;
+        let mut missing: Vec<_> = source.iter().take(3).collect();
+        missing.reverse();
+        drop(source);
+
+        let fixture = ChainFixture::restore(3)?;
+        let utxo = UTXOSet { blockchain: fixture.blockchain()? };
+        utxo.blockchain.set_bulk_sync(true)?;
+        for block in &missing {
+            utxo.receive_block(block)?;
+        }
+        utxo.blockchain.set_bulk_sync(false)?;
+        drop(utxo);
+
+        let bc = fixture.blockchain()?;
+        assert_eq!(bc.get_tip(), longer.tip());
+        assert_eq!(bc.get_best_height()?, 6);
+        Ok(())
+    }
+
+    #[test]
+    fn test_the_chain_with_the_most_work_wins() -> Result<()> {
+        let fixture = ChainFixture::restore(3)?;
+        let utxo = UTXOSet { blockchain: fixture.blockchain()? };
+        let bc = &utxo.blockchain;
+        let coinbase = |height: usize| Transaction::new_coinbase(fixture.miner().to_string(), format!("fork {}", height));
+        let bits = bc.next_bits()?;
+        let a2 = Block::new_block(vec![coinbase(2)?], bc.get_block_hash(1)?, 2, bits)?;
+        let a3 = Block::new_block(vec![coinbase(3)?], a2.get_hash(), 3, bits)?;
+        let a4 = Block::new_block(vec![coinbase(4)?], a3.get_hash(), 4, bits)?;
+        let a5 = Block::new_block(vec![coinbase(5)?], a4.get_hash(), 5, bits)?;
+        let harder = Target::from_compact(bits)?.scale(1, 4).to_compact();
+        let heavy = Block::new_block(vec![coinbase(6)?], a4.get_hash(), 5, harder)?;
+
+        // a fork as long as the best chain has as much work and does not win,
+        // nor do its transactions join the txindex
+        let coinbase_id = |block: &Block| block.get_transactions()[0].id;
+        let old_tip = bc.get_block(&fixture.tip())?;
+        utxo.receive_block(&a2)?;
+        utxo.receive_block(&a3)?;
+        assert_eq!(bc.get_tip(), fixture.tip());
+        assert_eq!(bc.get_chain_work(&a3.get_hash())?, bc.get_chain_work(&fixture.tip())?);
+        assert!(bc.get_indexed_transaction(&coinbase_id(&a3))?.is_none());
+        assert_eq!(bc.confirmations(&bc.get_indexed_transaction(&coinbase_id(&old_tip))?.expect("indexed").1)?, 1);
+
+        // a block arriving before its parent waits for it, and only counts
+        // once it follows the parent, so a heavier target earns nothing
+        utxo.receive_block(&a5)?;
+        utxo.receive_block(&heavy)?;
+        assert_eq!(bc.chain_work(&a5.get_hash())?, None);
+        assert_eq!(bc.get_tip(), fixture.tip());
+        utxo.receive_block(&a4)?;
+        assert_eq!(bc.get_tip(), a5.get_hash());
+        assert_eq!(bc.chain_work(&heavy.get_hash())?, None);
+        assert_eq!(bc.get_block_hash(3)?, a3.get_hash());
+        assert!(bc.get_chain_work(&a5.get_hash())? > bc.get_chain_work(&fixture.tip())?);
+        // the old tip left the best chain and has no confirmations, its
+        // transactions left the txindex and the UTXO set
+        assert_eq!(bc.confirmations(old_tip.header())?, 0);
+        assert_eq!(bc.confirmations(a2.header())?, 4);
+        assert!(bc.get_indexed_transaction(&coinbase_id(&old_tip))?.is_none());
+        assert_eq!(bc.get_indexed_transaction(&coinbase_id(&a3))?.map(|(_, header)| header.hash), Some(a3.get_hash()));
+        assert_eq!(utxo.count_transactions()?, 6);
+        Ok(())
+    }
+
+    #[test]
+    fn test_a_block_not_following_its_parent_is_refused() -> Result<()> {
+        let fixture = ChainFixture::restore(2)?;
+        let bc = fixture.blockchain()?;
+        let coinbase = |height: usize| Transaction::new_coinbase(fixture.miner().to_string(), format!("bad {}", height));
+        let harder = Target::from_compact(bc.next_bits()?)?.scale(1, 4).to_compact();
+        let wrong_bits = Block::new_block(vec![coinbase(3)?], fixture.tip(), 3, harder)?;
+        let wrong_height = Block::new_block(vec![coinbase(5)?], fixture.tip(), 5, bc.next_bits()?)?;
+        // a time not after the median of the last blocks, or far ahead, would
+        // ease the next retarget
+        let tip = bc.get_block_header(&fixture.tip())?;
+        let early = seal_at(vec![coinbase(3)?], fixture.tip(), 3, bc.median_time_past(&tip)?)?;
+        let late = seal_at(vec![coinbase(3)?], fixture.tip(), 3, SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis() + MAX_FUTURE_BLOCK_TIME + 60_000)?;
+
+        assert!(bc.receive_block(&wrong_bits).is_err());
+        assert!(bc.receive_block(&wrong_height).is_err());
+        assert!(bc.receive_block(&early).is_err());
+        assert!(bc.receive_block(&late).is_err());
+        assert_eq!(bc.get_tip(), fixture.tip());
+        assert!(bc.get_block_header(&wrong_bits.get_hash()).is_err());
+        Ok(())
+    }
+}
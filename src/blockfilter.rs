@@ -0,0 +1,364 @@
+//! Compact block filters in the manner of BIP158 and BIP157. The filter of a
+//! block is a Golomb-coded set of the public key hashes its outputs pay and of
+//! the outpoints its inputs spend. A light client downloads the filters and
+//! tests its own keys and outputs against them, so no peer learns which
+//! addresses it follows, and fetches only the blocks that match.
+//!
+//! Each filter is chained to the one of the parent block by its filter header,
+//! the sha256 of its own hash and of the parent filter header. Light clients
+//! sync the filter headers first and then check every filter against them.
+
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::block::{Block, BlockHeader};
+use crate::blockchain::Blockchain;
+use crate::error::{BlockchainError, Result};
+use crate::hash::Hash256;
+use crate::intent::IntentOp;
+use crate::progress::Progress;
+use crate::tx::OutPoint;
+
+/// FILTER_P is the number of bits of the remainder of each Golomb-Rice code
+pub const FILTER_P: u8 = 19;
+
+/// FILTER_M is the inverse of the false positive rate of a filter
+pub const FILTER_M: u64 = 784_931;
+
+/// MAX_CFILTERS is how many filters a node sends in one reply
+pub const MAX_CFILTERS: usize = 1000;
+
+/// MAX_CFHEADERS is how many filter hashes a node sends in one reply
+pub const MAX_CFHEADERS: usize = 2000;
+
+/// FILTER_TREE maps a block hash to the bincode filter of the block and its
+/// filter header
+pub const FILTER_TREE: &str = "filters";
+
+/// FILTER_TIP_TREE holds the hash of the last block the filters cover
+const FILTER_TIP_TREE: &str = "filters_tip";
+const TIP_KEY: &[u8] = b"TIP";
+
+/// BlockFilter is the Golomb-coded set of the items of a block
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BlockFilter {
+    /// number of distinct items in the set
+    pub items: u32,
+    /// sorted item hashes, delta and Golomb-Rice coded
+    pub data: Vec<u8>
+}
+
+impl BlockFilter {
+    /// build codes `items` into the filter of the block `block_hash`, which
+    /// keys the item hashes
+    pub fn build(block_hash: &Hash256, items: &[Vec<u8>]) -> Result<BlockFilter> {
+        let count = u32::try_from(items.len()).map_err(|_| BlockchainError::Consensus(format!("block {} has too many filter items", block_hash)))?;
+        let range = count as u64 * FILTER_M;
+        let mut hashes: Vec<u64> = items.iter().map(|item| hash_to_range(block_hash, item, range)).collect();
+        hashes.sort_unstable();
+
+        let mut writer = BitWriter::default();
+        let mut last = 0;
+        for hash in hashes {
+            let delta = hash - last;
+            for _ in 0..delta >> FILTER_P {
+                writer.write_bit(true);
+            }
+            writer.write_bit(false);
+            writer.write_bits(delta, FILTER_P);
+            last = hash;
+        }
+        Ok(BlockFilter { items: count, data: writer.bytes })
+    }
+
+    /// of is the filter of `block`
+    pub fn of(block: &Block) -> Result<BlockFilter> {
+        BlockFilter::build(&block.get_hash(), &filter_items(block))
+    }
+
+    /// hash identifies the filter in the filter header chain
+    pub fn hash(&self) -> Hash256 {
+        let mut bytes = self.items.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&self.data);
+        Hash256::sha256(&bytes)
+    }
+
+    /// match_any tells whether one of `items` is in the filter of the block
+    /// `block_hash`, or rarely seems to be
+    pub fn match_any(&self, block_hash: &Hash256, items: &[Vec<u8>]) -> Result<bool> {
+        if self.items == 0 || items.is_empty() {
+            return Ok(false);
+        }
+        let range = self.items as u64 * FILTER_M;
+        let mut queries: Vec<u64> = items.iter().map(|item| hash_to_range(block_hash, item, range)).collect();
+        queries.sort_unstable();
+
+        let mut reader = BitReader::new(&self.data);
+        let mut value = 0;
+        let mut queries = queries.into_iter().peekable();
+        for _ in 0..self.items {
+            let mut quotient = 0;
+            while reader.read_bit()? {
+                quotient += 1;
+            }
+            value += (quotient << FILTER_P) | reader.read_bits(FILTER_P)?;
+            while let Some(&query) = queries.peek() {
+                match query.cmp(&value) {
+                    std::cmp::Ordering::Less => {
+                        queries.next();
+                    },
+                    std::cmp::Ordering::Equal => return Ok(true),
+                    std::cmp::Ordering::Greater => break
+                }
+            }
+            if queries.peek().is_none() {
+                return Ok(false);
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// filter_items are the public key hashes of the payment outputs of `block`
+/// and the outpoints its inputs spend
+pub fn filter_items(block: &Block) -> Vec<Vec<u8>> {
+    let mut items = Vec::new();
+    for tx in block.get_transactions() {
+        items.extend(tx.vout.iter().filter(|out| !out.is_data()).map(|out| out.pub_key_hash.clone()));
+        if !tx.is_coinbase() {
+            items.extend(tx.vin.iter().map(|vin| outpoint_item(&vin.prev_out)));
+        }
+    }
+    items.sort();
+    items.dedup();
+    items
+}
+
+/// outpoint_item is the filter item of a spent outpoint, its txid and index
+pub fn outpoint_item(outpoint: &OutPoint) -> Vec<u8> {
+    let mut item = outpoint.txid.as_bytes().to_vec();
+    item.extend_from_slice(&outpoint.index.to_le_bytes());
+    item
+}
+
+/// filter_header chains the filter hash `filter_hash` to the filter header of
+/// the parent block, zero for the genesis
+pub fn filter_header(filter_hash: &Hash256, prev_header: &Hash256) -> Hash256 {
+    let mut bytes = filter_hash.as_bytes().to_vec();
+    bytes.extend_from_slice(prev_header.as_bytes());
+    Hash256::sha256(&bytes)
+}
+
+/// hash_to_range maps `item` uniformly to [0, range), keyed by the first 16
+/// bytes of the block hash so the collisions differ from block to block
+fn hash_to_range(block_hash: &Hash256, item: &[u8], range: u64) -> u64 {
+    let mut bytes = block_hash.as_bytes()[..16].to_vec();
+    bytes.extend_from_slice(item);
+    let hash = u64::from_le_bytes(Hash256::sha256(&bytes).as_bytes()[..8].try_into().unwrap_or_default());
+    ((hash as u128 * range as u128) >> 64) as u64
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    used: u8
+}
+
+impl BitWriter {
+    fn write_bit(&mut self, bit: bool) {
+        if self.used.is_multiple_of(8) {
+            self.bytes.push(0);
+            self.used = 0;
+        }
+        if let Some(byte) = self.bytes.last_mut().filter(|_| bit) {
+            *byte |= 0x80 >> self.used;
+        }
+        self.used += 1;
+    }
+
+    /// write_bits writes the `count` low bits of `value`, the highest first
+    fn write_bits(&mut self, value: u64, count: u8) {
+        for i in (0..count).rev() {
+            self.write_bit(value >> i & 1 == 1);
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> BitReader<'a> {
+        BitReader { bytes, position: 0 }
+    }
+
+    fn read_bit(&mut self) -> Result<bool> {
+        let byte = self.bytes.get(self.position / 8).ok_or_else(|| BlockchainError::Network("a block filter ends early".to_string()))?;
+        let bit = byte & (0x80 >> (self.position % 8)) != 0;
+        self.position += 1;
+        Ok(bit)
+    }
+
+    fn read_bits(&mut self, count: u8) -> Result<u64> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = value << 1 | self.read_bit()? as u64;
+        }
+        Ok(value)
+    }
+}
+
+/// FilterIndex keeps the filter and filter header of every block of the best
+/// chain. Like the address index it catches up with the chain when it starts
+/// and then follows it from a background thread
+#[derive(Debug, Clone)]
+pub struct FilterIndex {
+    bc: Blockchain,
+    /// held while the index moves, so two syncs never interleave
+    syncing: Arc<Mutex<()>>
+}
+
+impl FilterIndex {
+    /// new opens the index of `bc` as it is, call sync to bring it up to the tip
+    pub fn new(bc: &Blockchain) -> FilterIndex {
+        FilterIndex {
+            bc: bc.clone(),
+            syncing: Arc::new(Mutex::new(()))
+        }
+    }
+
+    /// start brings the index up to the chain tip and follows the chain events
+    pub fn start(bc: &Blockchain) -> Result<FilterIndex> {
+        let events = bc.events().subscribe();
+        let index = FilterIndex::new(bc);
+        index.sync()?;
+
+        let follower = index.clone();
+        thread::spawn(move || {
+            for event in events {
+                if let Err(e) = follower.sync() {
+                    error!("filter index failed on {:?}: {}", event, e);
+                }
+            }
+        });
+        Ok(index)
+    }
+
+    /// sync drops the filters of the blocks that left the best chain and then
+    /// builds those of the best chain blocks not indexed yet
+    pub fn sync(&self) -> Result<()> {
+        let _syncing = self.syncing.lock().unwrap_or_else(PoisonError::into_inner);
+        let (disconnected, connected) = self.bc.path_from(self.tip()?)?;
+        for header in &disconnected {
+            self.disconnect(header)?;
+        }
+
+        if connected.is_empty() {
+            return Ok(());
+        }
+        let mut progress = (connected.len() > 1).then(|| Progress::new("index block filters", connected.len() as u64));
+        for header in &connected {
+            let filter = BlockFilter::of(&self.bc.get_block(&header.hash)?)?;
+            let prev_header = match header.height {
+                0 => Hash256::ZERO,
+                _ => self.filter(&header.prev_block_hash)?.map(|(_, prev)| prev).ok_or_else(|| BlockchainError::Corrupt(format!("no filter for block {}", header.prev_block_hash)))?
+            };
+            let entry = (filter.clone(), filter_header(&filter.hash(), &prev_header));
+            self.bc.intents().commit(&[
+                IntentOp::insert(FILTER_TREE, header.hash.as_bytes(), bincode::serialize(&entry)?),
+                IntentOp::insert(FILTER_TIP_TREE, TIP_KEY, header.hash.as_bytes().to_vec())
+            ])?;
+            if let Some(progress) = progress.as_mut() {
+                progress.inc(1);
+            }
+        }
+        if let Some(mut progress) = progress {
+            progress.finish();
+        }
+        Ok(())
+    }
+
+    /// filter is the filter of the block `hash` and its filter header, None
+    /// for a block the index does not cover
+    pub fn filter(&self, hash: &Hash256) -> Result<Option<(BlockFilter, Hash256)>> {
+        match self.bc.open_tree(FILTER_TREE)?.get(hash.as_bytes())? {
+            Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+            None => Ok(None)
+        }
+    }
+
+    /// filters are the block hashes and filters of the best chain from height
+    /// `from` on, up to `max` of them and as far as the index reaches
+    pub fn filters(&self, from: usize, max: usize) -> Result<Vec<(Hash256, BlockFilter, Hash256)>> {
+        let best_height = self.bc.get_best_height()? as usize;
+        let mut filters = Vec::new();
+        for height in from..=best_height.min(from.saturating_add(max).saturating_sub(1)) {
+            let hash = self.bc.get_block_hash(height)?;
+            let Some((filter, header)) = self.filter(&hash)? else {
+                break;
+            };
+            filters.push((hash, filter, header));
+        }
+        Ok(filters)
+    }
+
+    /// tip is the last indexed block, None before the genesis is
+    fn tip(&self) -> Result<Option<Hash256>> {
+        match self.bc.open_tree(FILTER_TIP_TREE)?.get(TIP_KEY)? {
+            Some(hash) => Ok(Some(Hash256::from_slice(&hash)?)),
+            None => Ok(None)
+        }
+    }
+
+    /// disconnect removes the filter of the block of `header`, making its
+    /// parent the tip of the index
+    fn disconnect(&self, header: &BlockHeader) -> Result<()> {
+        self.bc.intents().commit(&[
+            IntentOp::remove(FILTER_TREE, header.hash.as_bytes()),
+            IntentOp::insert(FILTER_TIP_TREE, TIP_KEY, header.prev_block_hash.as_bytes().to_vec())
+        ])?;
+        info!("filter index disconnected block {}", header.hash);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{ChainFixture, MINER_SEED};
+    use crate::wallet::{hash_pub_key, Wallet};
+
+    #[test]
+    fn test_filters_match_their_items_and_chain_up() -> Result<()> {
+        let key = Hash256::new([7; 32]);
+        let items: Vec<Vec<u8>> = (0u32..100).map(|i| i.to_le_bytes().to_vec()).collect();
+        let filter = BlockFilter::build(&key, &items)?;
+        assert!(items.iter().all(|item| filter.match_any(&key, std::slice::from_ref(item)).unwrap_or(false)));
+        let others: Vec<Vec<u8>> = (1000u32..1100).map(|i| i.to_le_bytes().to_vec()).collect();
+        assert!(!filter.match_any(&key, &others)?);
+        assert!(!BlockFilter::build(&key, &[])?.match_any(&key, &items)?);
+        // about FILTER_P + 2 bits an item
+        assert!(filter.data.len() < 100 * (FILTER_P as usize + 3) / 8);
+
+        let fixture = ChainFixture::restore(3)?;
+        let bc = fixture.blockchain()?;
+        let index = FilterIndex::start(&bc)?;
+        let filters = index.filters(0, MAX_CFILTERS)?;
+        assert_eq!(filters.len(), 4);
+        let mut miner = Wallet::from_seed(&MINER_SEED).public_key;
+        hash_pub_key(&mut miner);
+        let mut prev_header = Hash256::ZERO;
+        for (hash, filter, header) in &filters {
+            assert!(filter.match_any(hash, std::slice::from_ref(&miner))?);
+            assert_eq!(*header, filter_header(&filter.hash(), &prev_header));
+            prev_header = *header;
+        }
+        assert_eq!(index.filters(2, 1)?.len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,95 @@
+use serde::{Deserialize, Serialize};
+
+use crate::error::{BlockchainError, Result};
+use crate::hash::Hash256;
+
+/// FALSE_POSITIVE_RATE is the share of unrelated items a filter built by
+/// `BloomFilter::new` lets through
+pub const FALSE_POSITIVE_RATE: f64 = 0.001;
+
+/// MAX_FILTER_BYTES bounds the size of a filter a peer hands over
+const MAX_FILTER_BYTES: usize = 36_000;
+
+/// MAX_FILTER_HASHES bounds the hashes a peer filter takes per item
+const MAX_FILTER_HASHES: u32 = 50;
+
+/// BloomFilter is a set of byte strings that answers `contains` with no false
+/// negatives and a small share of false positives, in a few bits per item
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32
+}
+
+impl BloomFilter {
+    /// new sizes a filter for `items` items at FALSE_POSITIVE_RATE
+    pub fn new(items: usize) -> BloomFilter {
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-(items.max(1) as f64) * FALSE_POSITIVE_RATE.ln() / (ln2 * ln2)).ceil() as usize;
+        let hashes = ((bits as f64 / items.max(1) as f64) * ln2).round().max(1.0) as u32;
+        BloomFilter {
+            bits: vec![0; bits.div_ceil(64)],
+            hashes
+        }
+    }
+
+    /// from_items builds a filter holding every item of `items`
+    pub fn from_items<'a>(items: impl ExactSizeIterator<Item = &'a [u8]>) -> BloomFilter {
+        let mut filter = BloomFilter::new(items.len());
+        for item in items {
+            filter.insert(item);
+        }
+        filter
+    }
+
+    pub fn insert(&mut self, item: &[u8]) {
+        for bit in self.positions(item) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// check refuses a filter received from a peer that is empty or larger
+    /// than a node scans blocks with
+    pub fn check(&self) -> Result<()> {
+        if self.bits.is_empty() || self.bits.len() * 8 > MAX_FILTER_BYTES || self.hashes == 0 || self.hashes > MAX_FILTER_HASHES {
+            return Err(BlockchainError::Network(format!("a bloom filter of {} bytes and {} hashes is out of bounds", self.bits.len() * 8, self.hashes)));
+        }
+        Ok(())
+    }
+
+    /// contains is true for every inserted item and, rarely, for others
+    pub fn contains(&self, item: &[u8]) -> bool {
+        self.positions(item).all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// positions derives the bits of `item` from two halves of its sha256, the
+    /// i-th being h1 + i * h2
+    fn positions(&self, item: &[u8]) -> impl Iterator<Item = usize> {
+        let digest = Hash256::sha256(item);
+        let bytes = digest.as_bytes();
+        let h1 = u64::from_le_bytes(bytes[..8].try_into().unwrap_or_default());
+        let h2 = u64::from_le_bytes(bytes[8..16].try_into().unwrap_or_default());
+        let len = self.bits.len() as u64 * 64;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_false_negatives_and_few_false_positives() {
+        let keys: Vec<[u8; 20]> = (0..1000u32).map(|i| {
+            let mut key = [0; 20];
+            key[..4].copy_from_slice(&i.to_be_bytes());
+            key
+        }).collect();
+        let filter = BloomFilter::from_items(keys.iter().map(|k| &k[..]));
+        assert!(keys.iter().all(|k| filter.contains(k)));
+
+        let false_positives = (1000..101_000u32).filter(|i| filter.contains(&i.to_be_bytes())).count();
+        assert!(false_positives < 300, "{} false positives in 100000", false_positives);
+        assert!(!BloomFilter::new(0).contains(b"anything"));
+    }
+}
@@ -0,0 +1,203 @@
+//! Statistics over a range of the best chain, for studying how the chain
+//! behaves: how far apart blocks are, what transactions pay, how long outputs
+//! wait before they are spent and how fast the UTXO set grows.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::blockchain::Blockchain;
+use crate::error::{BlockchainError, Result};
+use crate::hash::Hash256;
+
+/// Summary describes a set of values, None when the set is empty
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub count: usize,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub p10: f64,
+    pub median: f64,
+    pub p90: f64
+}
+
+impl Summary {
+    /// of summarizes `values`, picking the percentiles without interpolating
+    pub fn of(mut values: Vec<f64>) -> Option<Summary> {
+        if values.is_empty() {
+            return None;
+        }
+        values.sort_by(f64::total_cmp);
+        let percentile = |p: usize| values[(values.len() - 1) * p / 100];
+        Some(Summary {
+            count: values.len(),
+            min: values[0],
+            max: values[values.len() - 1],
+            mean: values.iter().sum::<f64>() / values.len() as f64,
+            p10: percentile(10),
+            median: percentile(50),
+            p90: percentile(90)
+        })
+    }
+}
+
+/// ChainStats covers the blocks at heights `from..=to`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ChainStats {
+    pub from: usize,
+    pub to: usize,
+    pub blocks: usize,
+    /// transactions, the coinbases included
+    pub transactions: usize,
+    pub transactions_per_block: f64,
+    /// seconds between a block and its parent
+    pub block_interval: Option<Summary>,
+    /// sats paid by each transaction but the coinbases
+    pub fee: Option<Summary>,
+    /// blocks between an output being created and spent
+    pub spent_output_age: Option<Summary>,
+    pub outputs_created: usize,
+    pub outputs_spent: usize,
+    pub outputs_created_per_block: f64,
+    pub outputs_spent_per_block: f64,
+    /// outputs created but not spent in the range
+    pub utxo_growth: i64
+}
+
+/// Created holds the value in sats and the block height of each output of a
+/// transaction
+type Created = Vec<(i32, usize)>;
+
+/// chain_stats walks the best chain of `bc` from height `from` to `to`, both
+/// included, looking up the outputs spent from before the range in the
+/// transaction index
+pub fn chain_stats(bc: &Blockchain, from: usize, to: usize) -> Result<ChainStats> {
+    let best_height = bc.get_best_height()? as usize;
+    let to = to.min(best_height);
+    if from > to {
+        return Err(BlockchainError::Config(format!("no block between heights {} and {}, the best height is {}", from, to, best_height)));
+    }
+
+    let mut intervals = Vec::new();
+    let mut fees = Vec::new();
+    let mut ages = Vec::new();
+    let (mut transactions, mut created, mut spent) = (0, 0, 0);
+    let mut outputs: HashMap<Hash256, Created> = HashMap::new();
+    let mut prev_time = match from.checked_sub(1) {
+        Some(height) => Some(bc.get_block_header(&bc.get_block_hash(height)?)?.timestamp),
+        None => None
+    };
+
+    for header in bc.iter_range(from, to + 1)? {
+        let block = bc.get_block(&header?.hash)?;
+        let height = block.get_height();
+        if let Some(prev_time) = prev_time {
+            intervals.push(block.get_timestamp().saturating_sub(prev_time) as f64 / 1000.0);
+        }
+        prev_time = Some(block.get_timestamp());
+
+        for tx in block.get_transactions() {
+            transactions += 1;
+            created += tx.vout.len();
+            if !tx.is_coinbase() {
+                let mut input_total = 0i64;
+                for vin in &tx.vin {
+                    let prev = vin.prev_out;
+                    let prev_outputs = match outputs.entry(prev.txid) {
+                        Entry::Occupied(entry) => entry.into_mut(),
+                        Entry::Vacant(entry) => {
+                            let (prev_tx, prev_header) = bc.get_indexed_transaction(&prev.txid)?.ok_or_else(|| BlockchainError::TxNotFound(prev.txid.to_string()))?;
+                            entry.insert(prev_tx.vout.iter().map(|out| (out.value.to_sat(), prev_header.height)).collect())
+                        }
+                    };
+                    let Some(&(value, created_at)) = prev_outputs.get(prev.index as usize) else {
+                        return Err(BlockchainError::Consensus(format!("transaction {} spends missing output {}", tx.id, prev)));
+                    };
+                    input_total += value as i64;
+                    ages.push(height.saturating_sub(created_at) as f64);
+                    spent += 1;
+                }
+                let output_total: i64 = tx.vout.iter().map(|out| out.value.to_sat() as i64).sum();
+                fees.push((input_total - output_total) as f64);
+            }
+            outputs.insert(tx.id, tx.vout.iter().map(|out| (out.value.to_sat(), height)).collect());
+        }
+    }
+
+    let blocks = to - from + 1;
+    Ok(ChainStats {
+        from,
+        to,
+        blocks,
+        transactions,
+        transactions_per_block: transactions as f64 / blocks as f64,
+        block_interval: Summary::of(intervals),
+        fee: Summary::of(fees),
+        spent_output_age: Summary::of(ages),
+        outputs_created: created,
+        outputs_spent: spent,
+        outputs_created_per_block: created as f64 / blocks as f64,
+        outputs_spent_per_block: spent as f64 / blocks as f64,
+        utxo_growth: created as i64 - spent as i64
+    })
+}
+
+/// Display writes the statistics as a table
+impl fmt::Display for ChainStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "heights {} to {}, {} blocks", self.from, self.to, self.blocks)?;
+        writeln!(f, "transactions      {:>10} ({:.2} per block)", self.transactions, self.transactions_per_block)?;
+        writeln!(f, "outputs created   {:>10} ({:.2} per block)", self.outputs_created, self.outputs_created_per_block)?;
+        writeln!(f, "outputs spent     {:>10} ({:.2} per block)", self.outputs_spent, self.outputs_spent_per_block)?;
+        writeln!(f, "utxo growth       {:>10}", self.utxo_growth)?;
+        writeln!(f)?;
+        writeln!(f, "{:<22}{:>8}{:>10}{:>10}{:>10}{:>10}{:>10}{:>10}", "", "count", "min", "p10", "median", "mean", "p90", "max")?;
+        for (name, summary) in [("block interval (s)", &self.block_interval), ("fee (sats)", &self.fee), ("spent output age", &self.spent_output_age)] {
+            match summary {
+                Some(s) => writeln!(f, "{:<22}{:>8}{:>10.2}{:>10.2}{:>10.2}{:>10.2}{:>10.2}{:>10.2}", name, s.count, s.min, s.p10, s.median, s.mean, s.p90, s.max)?,
+                None => writeln!(f, "{:<22}{:>8}", name, 0)?
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::testing::ChainFixture;
+    use crate::transaction::Transaction;
+    use crate::utxoset::UTXOSet;
+    use crate::wallet::Wallets;
+
+    #[test]
+    fn test_stats_count_fees_and_output_ages() -> Result<()> {
+        let fixture = ChainFixture::restore(3)?;
+        let utxo = UTXOSet { blockchain: fixture.blockchain()? };
+        let wallets = Wallets::new(&fixture.config())?;
+        let miner = fixture.miner().to_string();
+        let tx = Transaction::new_UTXO(&wallets, &miner, &miner, Amount::from_sat(5), Amount::from_sat(3), &utxo)?;
+        let inputs = tx.vin.len();
+        let coinbase = Transaction::new_coinbase(miner.clone(), "stats".to_string())?;
+        let block = utxo.blockchain.mine_block(vec![coinbase, tx])?;
+        utxo.connect_block(&block)?;
+
+        let stats = chain_stats(&utxo.blockchain, 0, usize::MAX)?;
+        assert_eq!((stats.from, stats.to, stats.blocks, stats.transactions), (0, 4, 5, 6));
+        assert_eq!(stats.block_interval.as_ref().map(|s| s.count), Some(4));
+        let fee = stats.fee.expect("one fee");
+        assert_eq!((fee.count, fee.median), (1, 3.0));
+        assert_eq!(stats.outputs_spent, inputs);
+        assert_eq!(stats.utxo_growth, stats.outputs_created as i64 - inputs as i64);
+
+        let last = chain_stats(&utxo.blockchain, 4, 4)?;
+        assert_eq!((last.blocks, last.block_interval.map(|s| s.count)), (1, Some(1)));
+        assert!(last.spent_output_age.is_some_and(|s| s.min >= 1.0));
+        assert!(chain_stats(&utxo.blockchain, 5, 9).is_err());
+        Ok(())
+    }
+}
@@ -1,67 +1,414 @@
 
+use std::collections::HashSet;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::time::{Duration, Instant};
 
-use bitcoincash_addr::Address;
-use clap::{arg, Command};
+use clap::{arg, value_parser, Arg, ArgAction, ArgMatches, Command};
+use clap_complete::{generate, Shell};
+use clap_mangen::Man;
+use serde_json::{json, Value};
+use tracing::level_filters::LevelFilter;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::EnvFilter;
 
-use crate::block::Block;
-use crate::error::Result;
+use crate::address;
+use crate::addrindex::AddressIndex;
+use crate::addrman;
+use crate::amount::Amount;
+use crate::block::BlockHeader;
+use crate::error::{BlockchainError, Result};
 use crate::blockchain::Blockchain;
+use crate::chainstats::chain_stats;
+use crate::config::{Config, Network};
+use crate::consolidate::{self, Consolidation};
+use crate::history::{address_history, apply_labels};
+use crate::invoice::PaymentRequest;
+use crate::json::{block_json, chain_block_json, header_json, name_json, tx_json};
+use crate::progress;
+use crate::projection::Projection;
+use crate::qr;
+use crate::control;
+use crate::daemon;
+use crate::eventlog;
+use crate::fixtures;
+use crate::hash::Hash256;
+use crate::server::{chain_status, Server};
+#[cfg(feature = "simulate")]
+use crate::simulate::{self, Simulation};
+use crate::storage::Compression;
+use crate::target::RETARGET_INTERVAL;
+use crate::names::{reserved_outputs, NameIndex};
+use crate::notary::{self, Proof};
+use crate::offline::{self, UnsignedTx};
+use crate::replay::{self, Recording};
+use crate::rotate::{self, MAX_SWEEP_INPUTS};
+use crate::stratum;
+use crate::token::TokenIndex;
 use crate::transaction::Transaction;
-use crate::tx::TXOutputs;
+use crate::tx::{TXOutput, TXOutputs};
 use crate::utxoset::UTXOSet;
-use crate::wallet::Wallets;
+use crate::wallet::{hash_to_address, TxLabel, WalletBackup, Wallets};
+use crate::watch::{self, Snapshot, Watcher};
+
+/// MAX_BLOCK_WAIT bounds a single waitfornewblock request, longer waits are split
+const MAX_BLOCK_WAIT: Duration = Duration::from_secs(60);
+/// LOG_FILES_KEPT is how many daily log files `--log-dir` keeps before deleting the oldest
+const LOG_FILES_KEPT: usize = 7;
+/// DEFAULT_EPOCHS is how many retarget epochs `schedule` projects at least
+const DEFAULT_EPOCHS: usize = 10;
 
 pub struct Cli {}
 
 
 impl Cli {
     
-    fn print_chain(&self) -> Result<()> {
-        let bc = Blockchain::new()?;
-        for b in bc.iter() {
-            println!("{:#?}", b);
+    /// print_chain streams the selected blocks one by one through the height index,
+    /// newest first unless `--reverse` is given
+    fn print_chain(&self, config: &Config, matches: &ArgMatches) -> Result<()> {
+        let bc = Blockchain::new(config)?;
+        let best_height = bc.get_best_height()? as usize;
+
+        let from = *matches.get_one::<usize>("from-height").unwrap_or(&0);
+        let to = *matches.get_one::<usize>("to-height").unwrap_or(&best_height);
+        let to = to.min(best_height);
+        if from > to {
+            return Ok(());
+        }
+
+        let limit = *matches.get_one::<usize>("limit").unwrap_or(&usize::MAX);
+        let txids_only = matches.get_flag("txids-only");
+        let format = matches.get_one::<String>("format").unwrap();
+
+        let headers: Box<dyn Iterator<Item = Result<BlockHeader>>> = if matches.get_flag("reverse") {
+            Box::new(bc.iter_range(from, to + 1)?)
+        } else {
+            Box::new(bc.iter_range(from, to + 1)?.rev())
+        };
+
+        for header in headers.take(limit) {
+            let block = bc.get_block(&header?.hash)?;
+
+            match format.as_str() {
+                "json" => {
+                    let mut view = block_json(&block);
+                    if !txids_only {
+                        let txs: Vec<Value> = block.get_transactions().iter().map(tx_json).collect();
+                        view["tx"] = json!(txs);
+                    }
+                    println!("{}", view);
+                },
+                "summary" => {
+                    println!("{} {} {} txs", block.get_height(), block.get_hash(), block.get_transactions().len());
+                    if txids_only {
+                        for tx in block.get_transactions() {
+                            println!("  {}", tx.id);
+                        }
+                    }
+                },
+                _ => {
+                    if txids_only {
+                        println!("Block {} (height {})", block.get_hash(), block.get_height());
+                        for tx in block.get_transactions() {
+                            println!("  {}", tx.id);
+                        }
+                    } else {
+                        println!("{:#?}", block);
+                    }
+                }
+            }
         }
         Ok(())
     }
 
+    /// simulate_attack runs the mining attack simulation the flags describe and
+    /// prints its report
+    #[cfg(feature = "simulate")]
+    fn simulate_attack(&self, matches: &ArgMatches) -> Result<()> {
+        let defaults = Simulation::default();
+        let sim = Simulation {
+            honest_nodes: *matches.get_one::<usize>("honest-nodes").unwrap_or(&defaults.honest_nodes),
+            attacker_nodes: *matches.get_one::<usize>("attacker-nodes").unwrap_or(&defaults.attacker_nodes),
+            share: *matches.get_one::<f64>("share").unwrap_or(&defaults.share),
+            blocks: *matches.get_one::<usize>("blocks").unwrap_or(&defaults.blocks),
+            strategy: match matches.get_one::<String>("strategy") {
+                Some(strategy) => strategy.parse()?,
+                None => defaults.strategy
+            },
+            seed: *matches.get_one::<u64>("seed").unwrap_or(&defaults.seed)
+        };
+        let report = simulate::simulate(&sim)?;
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        Ok(())
+    }
+
+    #[cfg(not(feature = "simulate"))]
+    fn simulate_attack(&self, _matches: &ArgMatches) -> Result<()> {
+        Err(BlockchainError::Config("this build has no attack simulator, rebuild with --features simulate".to_string()))
+    }
+
+    fn start_server(&self, config: &Config, matches: &ArgMatches) -> Result<()> {
+        let mut config = config.clone();
+        if let Some(port) = matches.get_one::<String>("port") {
+            config.port = port.clone();
+        }
+        if let Some(binds) = matches.get_many::<String>("bind") {
+            config.bind = binds.cloned().collect();
+        }
+        if let Some(address) = matches.get_one::<String>("external-address") {
+            config.external_address = address.clone();
+        }
+        if let Some(peers) = matches.get_many::<String>("connect") {
+            config.peers = peers.cloned().collect();
+        }
+        if let Some(proxy) = matches.get_one::<String>("proxy") {
+            config.proxy = proxy.clone();
+        }
+        if let Some(nets) = matches.get_many::<String>("onlynet") {
+            config.only_net = nets.map(|net| net.parse()).collect::<Result<_>>()?;
+        }
+        if let Some(rpc_port) = matches.get_one::<String>("rpcport") {
+            config.rpc_port = rpc_port.clone();
+        }
+        if let Some(rest_port) = matches.get_one::<String>("restport") {
+            config.rest_port = rest_port.clone();
+        }
+        if let Some(explorer_port) = matches.get_one::<String>("explorerport") {
+            config.explorer_port = explorer_port.clone();
+        }
+        if let Some(ws_port) = matches.get_one::<String>("wsport") {
+            config.ws_port = ws_port.clone();
+        }
+        if let Some(grpc_port) = matches.get_one::<String>("grpcport") {
+            config.grpc_port = grpc_port.clone();
+        }
+        if let Some(endpoint) = matches.get_one::<String>("zmqpubrawblock") {
+            config.zmq_pub_raw_block = endpoint.clone();
+        }
+        if let Some(endpoint) = matches.get_one::<String>("zmqpubrawtx") {
+            config.zmq_pub_raw_tx = endpoint.clone();
+        }
+        if let Ok(Some(port)) = matches.try_get_one::<String>("workport") {
+            config.work_port = port.clone();
+        }
+        if matches.get_flag("eventlog") {
+            config.event_log = true;
+        }
+        if let Some(path) = matches.get_one::<String>("record") {
+            config.p2p_record = path.clone();
+        }
+        if matches.get_flag("faucet") {
+            config.faucet = true;
+        }
+        if let Some(amount) = matches.get_one::<String>("faucet-amount") {
+            config.faucet_amount = amount.parse()?;
+        }
+        if let Some(cooldown) = matches.get_one::<u64>("faucet-cooldown") {
+            config.faucet_cooldown = *cooldown;
+        }
+        if matches.try_get_one::<bool>("public-api").ok().flatten() == Some(&true) {
+            config.public_api = true;
+        }
+        if let Ok(Some(rate)) = matches.try_get_one::<u32>("public-api-rate") {
+            config.public_api_rate = *rate;
+        }
+        if let Some(max_mempool) = matches.get_one::<usize>("maxmempool") {
+            config.max_mempool = *max_mempool;
+        }
+        if let Ok(Some(threads)) = matches.try_get_one::<usize>("mine-threads") {
+            config.mine_threads = *threads;
+        }
+        if matches.try_get_one::<bool>("mine-nice").ok().flatten() == Some(&true) {
+            config.mine_nice = true;
+        }
+        if let Ok(Some(min_txs)) = matches.try_get_one::<usize>("mine-min-txs") {
+            config.mine_min_txs = *min_txs;
+        }
+        if let Ok(Some(max_wait)) = matches.try_get_one::<u64>("mine-max-wait") {
+            config.mine_max_wait = *max_wait;
+        }
+        if let Ok(Some(msg)) = matches.try_get_one::<String>("coinbase-msg") {
+            config.coinbase_msg = msg.clone();
+        }
+
+        if matches.get_flag("daemon") {
+            drop(daemon::lock_datadir(&config)?);
+            let log_dir = match matches.get_one::<String>("log-dir") {
+                Some(dir) => PathBuf::from(dir),
+                None => config.log_dir()
+            };
+            let pid = daemon::spawn_detached(&log_dir)?;
+            println!("Started node on port {} in the background, pid {}, logs in {}", config.port, pid, log_dir.display());
+            return Ok(());
+        }
+
+        let _lock = daemon::lock_datadir(&config)?;
+        daemon::write_pid_file(&config)?;
+        println!("Start node on port {}", config.port);
+        let bc = Blockchain::new(&config)?;
+        let utxo_set = UTXOSet { blockchain: bc };
+        let server = Server::new(&config, utxo_set)?;
+        server.load_mempool()?;
+        server.start_server(!matches.get_flag("nolisten"))?;
+        server.save_mempool()
+    }
+
+    /// app_indexes opens the local chain with its token index and name registry
+    /// brought up to the tip
+    fn app_indexes(&self, config: &Config) -> Result<(UTXOSet, TokenIndex, NameIndex)> {
+        let utxo_set = UTXOSet { blockchain: Blockchain::new(config)? };
+        let tokens = TokenIndex::new(&utxo_set.blockchain);
+        tokens.sync()?;
+        let names = NameIndex::new(&utxo_set.blockchain);
+        names.sync()?;
+        Ok((utxo_set, tokens, names))
+    }
+
+    /// mine_app_tx mines a token or name transaction into a block paying the
+    /// reward to `from`, like send, and applies it to the indexes
+    fn mine_app_tx(&self, utxo_set: &UTXOSet, from: &str, tx: Transaction) -> Result<()> {
+        let cbtx = Transaction::new_coinbase(from.to_string(), format!("reward for {}", tx.id))?;
+        let new_block = utxo_set.blockchain.mine_block(vec![cbtx, tx])?;
+        utxo_set.connect_block(&new_block)?;
+        TokenIndex::new(&utxo_set.blockchain).sync()?;
+        NameIndex::new(&utxo_set.blockchain).sync()
+    }
+
+    /// send_preview describes a signed transaction: the inputs it spends,
+    /// its outputs, the change back to `from`, its size and fee
+    fn send_preview(&self, bc: &Blockchain, tx: &Transaction, from: &str) -> Result<Value> {
+        let mut inputs = Vec::new();
+        let mut input_total = Amount::ZERO;
+        for vin in &tx.vin {
+            let prev_tx = bc.find_transaction(&vin.prev_out.txid)?;
+            let prev_out = &prev_tx.vout[vin.prev_out.index as usize];
+            input_total = input_total.try_add(prev_out.value)?;
+            inputs.push(json!({
+                "txid": vin.prev_out.txid,
+                "vout": vin.prev_out.index,
+                "value": prev_out.value,
+                "address": hash_to_address(&prev_out.pub_key_hash)
+            }));
+        }
+
+        let mut outputs = Vec::new();
+        let mut output_total = Amount::ZERO;
+        let mut change = Amount::ZERO;
+        for (n, out) in tx.vout.iter().enumerate() {
+            let address = hash_to_address(&out.pub_key_hash);
+            output_total = output_total.try_add(out.value)?;
+            if address == from {
+                change = change.try_add(out.value)?;
+            }
+            outputs.push(json!({
+                "n": n,
+                "value": out.value,
+                "address": address
+            }));
+        }
+
+        Ok(json!({
+            "txid": tx.id,
+            "inputs": inputs,
+            "outputs": outputs,
+            "change": change,
+            "size": bincode::serialize(tx)?.len(),
+            "fee": input_total.try_sub(output_total)?
+        }))
+    }
+
     pub fn new() -> Result<Cli> {
         Ok(Cli {})
     }
 
     pub fn run(&mut self) -> Result<()> {
 
-        let matches = Command::new("blockchain-rust-demo")
-            .version("0.1")
-            .author("rafael.julio.dev@outlook.com")
-            .about("blockchain in rust: a simple blockchain for learning (created via tutorial)")
-            .subcommand(Command::new("printchain").about("print all the chain blocks"))
-            .subcommand(Command::new("createwallet").about("create a wallet"))
-            .subcommand(Command::new("reindex").about("reindex UTXO"))
-            .subcommand(Command::new("listaddresses").about("list all addresses"))
-            .subcommand(Command::new("getbalance")
-                .about("get balance in the blockchain")
-                .arg(arg!(<ADDRESS>"'The Address it get balance for'"))
-            )
-            .subcommand(
-                Command::new("create")
-                .about("Create new blockchain")
-                .arg(arg!(<ADDRESS>"'The address to send genesis block reqward to'"))
-            )
-            .subcommand(
-                Command::new("send")
-                .about("send in the blockchain")
-                .arg(arg!(<FROM>"'Source wallet address'"))
-                .arg(arg!(<TO>"'Destination wallet address'"))
-                .arg(arg!(<AMOUNT>"'Amount to send'"))
-            )
-            .get_matches();
-
-
-            if let Some(ref matches) = matches.subcommand_matches("create") {
+        let matches = command().get_matches();
+
+            let default_level = match matches.subcommand_name() {
+                Some("startnode") | Some("startminer") => "info",
+                _ => "warn"
+            };
+            let quiet = matches.get_flag("quiet");
+            let level = match (quiet, matches.get_count("verbosity")) {
+                (true, _) => Some(LevelFilter::ERROR),
+                (false, 0) => None,
+                (false, 1) => Some(LevelFilter::INFO),
+                (false, 2) => Some(LevelFilter::DEBUG),
+                (false, _) => Some(LevelFilter::TRACE)
+            };
+            let filter = match level {
+                Some(level) => EnvFilter::default().add_directive(level.into()),
+                None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default_level))
+            };
+            let (writer, ansi) = match matches.get_one::<String>("log-dir") {
+                Some(dir) => {
+                    let files = RollingFileAppender::builder()
+                        .rotation(Rotation::DAILY)
+                        .filename_prefix("node")
+                        .filename_suffix("log")
+                        .max_log_files(LOG_FILES_KEPT)
+                        .build(dir)
+                        .map_err(|e| BlockchainError::Config(format!("cannot log to {}: {}", dir, e)))?;
+                    (BoxMakeWriter::new(files), false)
+                },
+                None => (BoxMakeWriter::new(std::io::stderr), std::io::stderr().is_terminal())
+            };
+            let logger = tracing_subscriber::fmt()
+                .with_env_filter(filter)
+                .with_writer(writer)
+                .with_ansi(ansi)
+                .with_span_events(FmtSpan::CLOSE);
+            match matches.get_one::<String>("log-format").map(|s| s.as_str()) {
+                Some("json") => logger.json().with_current_span(true).init(),
+                _ => logger.init()
+            }
+            progress::set_quiet(quiet);
+
+            if let Some(matches) = matches.subcommand_matches("completions") {
+                let shell = *matches.get_one::<Shell>("SHELL").unwrap();
+                let mut cmd = command();
+                let name = cmd.get_name().to_string();
+                generate(shell, &mut cmd, name, &mut std::io::stdout());
+                return Ok(());
+            }
+
+            if let Some(matches) = matches.subcommand_matches("mangen") {
+                let dir = matches.get_one::<String>("out").unwrap();
+                write_man_pages(Path::new(dir))?;
+                println!("man pages written to {}", dir);
+                return Ok(());
+            }
+
+            if let Some(matches) = matches.subcommand_matches("genfixtures") {
+                let dir = matches.get_one::<String>("out").unwrap();
+                let written = fixtures::write_fixtures(Path::new(dir))?;
+                println!("{} fixtures written to {}", written, dir);
+                return Ok(());
+            }
+
+            let mut config = Config::load(matches.get_one::<String>("config").map(|s| s.as_str()))?;
+            if let Some(datadir) = matches.get_one::<String>("datadir") {
+                config.datadir = datadir.clone();
+            }
+            if let Some(network) = matches.get_one::<String>("network") {
+                config.network = network.parse()?;
+            }
+            address::set_network(config.network);
+
+
+            if let Some(matches) = matches.subcommand_matches("create") {
                 if let Some(address) = matches.get_one::<String>("ADDRESS") {
                     let address = String::from(address);
-                    let bc = Blockchain::create_blockchain(address.clone())?;
+                    let compression = if matches.get_flag("compress") {
+                        Compression::Snappy
+                    } else {
+                        Compression::None
+                    };
+                    let bc = Blockchain::create_blockchain(&config, address.clone(), compression)?;
                     let utxo_set = UTXOSet { blockchain: bc };
                     utxo_set.reindex()?;
                     println!("create blockchain!");
@@ -73,25 +420,21 @@ impl Cli {
             }*/
 
 
-            if let Some(ref matches) = matches.subcommand_matches("getbalance") {
+            if let Some(matches) = matches.subcommand_matches("getbalance") {
                 if let Some(address) = matches.get_one::<String>("ADDRESS") {
-                    let pub_key_hash = Address::decode(address).unwrap().body;
-                    let bc = Blockchain::new()?;
+                    let pub_key_hash = address::decode(address)?;
+                    let bc = Blockchain::new(&config)?;
                     //let utxos = bc.find_UTXO(&pub_key_hash);
                     let utxo_set =  UTXOSet { blockchain: bc };
                     let utxos: TXOutputs = utxo_set.find_UTXO(&pub_key_hash)?;
 
-                    let mut balance = 0;
-
-                    for out in utxos.outputs {
-                        balance += out.value;
-                    }
+                    let balance = Amount::sum(utxos.outputs.iter().map(|out| out.value))?;
                     println!("Balance of '{}'; {}", address, balance);
 
                 }
             }
 
-            if let Some(ref matches) = matches.subcommand_matches("send") {
+            if let Some(matches) = matches.subcommand_matches("send") {
                 let from = if let Some(address) = matches.get_one::<String>("FROM") {
                     address
                 } else {
@@ -99,56 +442,873 @@ impl Cli {
                     exit(1);
                 };
 
-                let to =  if let Some(address) = matches.get_one::<String>("TO") {
-                    address
+                // a payment URI fills in the destination and the amount
+                let request: Option<PaymentRequest> = match matches.get_one::<String>("uri") {
+                    Some(uri) => Some(uri.parse()?),
+                    None => None
+                };
+                let to = match (matches.get_one::<String>("TO"), &request) {
+                    (Some(to), Some(request)) if *to != request.address => {
+                        return Err(BlockchainError::Config(format!("the payment URI pays {}, not {}", request.address, to)));
+                    },
+                    (Some(to), _) => to,
+                    (None, Some(request)) => &request.address,
+                    (None, None) => {
+                        println!("to not supply!: usage");
+                        exit(1);
+                    }
+                };
+
+                let amount: Amount = match (matches.get_one::<String>("AMOUNT"), request.as_ref().and_then(|r| r.amount)) {
+                    (Some(amount), None) => amount.parse()?,
+                    (Some(amount), Some(requested)) if amount.parse::<Amount>()? != requested => {
+                        return Err(BlockchainError::Config(format!("the payment URI asks for {}, not {}", requested, amount)));
+                    },
+                    (_, Some(requested)) => requested,
+                    (None, None) => {
+                        println!("amount not supply!: usage");
+                        exit(1);
+                    }
+                };
+
+                let fee: Amount = match matches.get_one::<String>("fee") {
+                    Some(fee) => fee.parse()?,
+                    None => config.fee
+                };
+
+                let (utxo_set, _, _) = self.app_indexes(&config)?;
+                let mut wallets = Wallets::new(&config)?;
+                // spending an output that carries tokens or holds a name would lose them
+                let reserved = reserved_outputs(&utxo_set.blockchain, &address::decode(from)?)?;
+                let tx = Transaction::new_utxo_where(&wallets, from, to, amount, fee, &utxo_set, |outpoint| !reserved.contains(outpoint))?;
+                if matches.get_flag("dry-run") {
+                    let preview = self.send_preview(&utxo_set.blockchain, &tx, from)?;
+                    println!("{}", serde_json::to_string_pretty(&preview)?);
+                    return Ok(());
+                }
+
+                let txid = tx.id;
+                let cbtx = Transaction::new_coinbase(from.to_string(), String::from("reward"))?;
+                let new_block = utxo_set.blockchain.mine_block(vec![cbtx, tx])?;
+
+                utxo_set.connect_block(&new_block)?;
+                if let Some(request) = request.filter(|r| r.label.is_some() || r.message.is_some()) {
+                    wallets.set_label(txid, TxLabel {
+                        label: request.label.unwrap_or_default(),
+                        message: request.message.unwrap_or_default()
+                    });
+                    wallets.save_all()?;
+                }
+                if let Some(memo) = matches.get_one::<String>("memo") {
+                    wallets.set_memo(txid, memo.clone());
+                    wallets.save_all()?;
+                }
+                println!("sucess!");
+            }
+
+            if let Some(matches) = matches.subcommand_matches("consolidate") {
+                let from = matches.get_one::<String>("ADDRESS").unwrap();
+                let defaults = Consolidation::default();
+                let options = Consolidation {
+                    min_inputs: defaults.min_inputs,
+                    max_inputs: *matches.get_one::<usize>("max-inputs").unwrap_or(&defaults.max_inputs),
+                    fee_rate: *matches.get_one::<f64>("fee-rate").unwrap_or(&defaults.fee_rate),
+                    dust: match matches.get_one::<String>("dust") {
+                        Some(dust) => dust.parse()?,
+                        None => defaults.dust
+                    },
+                    coinbase_maturity: *matches.get_one::<usize>("maturity").unwrap_or(&defaults.coinbase_maturity)
+                };
+
+                let (utxo_set, _, _) = self.app_indexes(&config)?;
+                let mut wallets = Wallets::new(&config)?;
+                let to = wallets.create_wallet();
+                let reserved = reserved_outputs(&utxo_set.blockchain, &address::decode(from)?)?;
+                let (tx, summary) = consolidate::consolidate(&wallets, from, &to, &utxo_set, &options, |outpoint| !reserved.contains(outpoint))?;
+                println!("{}", serde_json::to_string_pretty(&summary)?);
+                if matches.get_flag("dry-run") {
+                    return Ok(());
+                }
+
+                // the fresh address is kept only once it is paid
+                wallets.save_all()?;
+                let cbtx = Transaction::new_coinbase(from.to_string(), String::from("reward"))?;
+                let new_block = utxo_set.blockchain.mine_block(vec![cbtx, tx])?;
+                utxo_set.connect_block(&new_block)?;
+            }
+
+            if let Some(matches) = matches.subcommand_matches("rotatewallet") {
+                let defaults = Consolidation::default();
+                let options = Consolidation {
+                    min_inputs: 1,
+                    max_inputs: *matches.get_one::<usize>("max-inputs").unwrap_or(&MAX_SWEEP_INPUTS),
+                    fee_rate: *matches.get_one::<f64>("fee-rate").unwrap_or(&defaults.fee_rate),
+                    dust: defaults.dust,
+                    coinbase_maturity: *matches.get_one::<usize>("maturity").unwrap_or(&defaults.coinbase_maturity)
+                };
+
+                let (utxo_set, _, _) = self.app_indexes(&config)?;
+                let mut wallets = Wallets::new(&config)?;
+                let mut reserved = HashSet::new();
+                for address in wallets.get_all_address() {
+                    reserved.extend(reserved_outputs(&utxo_set.blockchain, &address::decode(&address)?)?);
+                }
+                let rotation = rotate::rotate(&mut wallets, &utxo_set, &options, |outpoint| !reserved.contains(outpoint))?;
+                println!("{}", serde_json::to_string_pretty(&rotation)?);
+                if matches.get_flag("dry-run") {
+                    return Ok(());
+                }
+
+                // the new keychain is kept before it is paid and the old keys
+                // are retired only once the sweeps are mined
+                wallets.save_all()?;
+                if !rotation.txs.is_empty() {
+                    let mut txs = vec![Transaction::new_coinbase(rotation.addresses[0].clone(), String::from("reward"))?];
+                    txs.extend(rotation.txs.iter().cloned());
+                    let new_block = utxo_set.blockchain.mine_block(txs)?;
+                    utxo_set.connect_block(&new_block)?;
+                }
+                for address in &rotation.retired {
+                    wallets.retire(address)?;
+                }
+                wallets.save_all()?;
+            }
+
+            if let Some(matches) = matches.subcommand_matches("anchor") {
+                let file = Path::new(matches.get_one::<String>("FILE").unwrap());
+                let from = matches.get_one::<String>("FROM").unwrap();
+                let fee: Amount = match matches.get_one::<String>("fee") {
+                    Some(fee) => fee.parse()?,
+                    None => config.fee
+                };
+
+                let digest = notary::file_digest(file)?;
+                let (utxo_set, _, _) = self.app_indexes(&config)?;
+                let tx = notary::anchor_tx(&Wallets::new(&config)?, from, &digest, fee, &utxo_set)?;
+                let txid = tx.id;
+                self.mine_app_tx(&utxo_set, from, tx)?;
+                println!("anchored {} ({}) in {}", file.display(), digest, txid);
+            }
+
+            if let Some(matches) = matches.subcommand_matches("prove") {
+                let file = Path::new(matches.get_one::<String>("FILE").unwrap());
+                let digest = notary::file_digest(file)?;
+                let bc = Blockchain::new(&config)?;
+
+                let proof: Proof = match (matches.get_one::<String>("verify"), matches.get_one::<String>("TXID")) {
+                    (Some(path), _) => serde_json::from_slice(&std::fs::read(path)?)?,
+                    (None, Some(txid)) => notary::prove(&bc, &digest, &txid.parse()?)?,
+                    (None, None) => return Err(BlockchainError::Config("prove needs a TXID or a proof to --verify".to_string()))
+                };
+                let time = proof.verify_on(&bc, &digest)?;
+                if matches.contains_id("verify") {
+                    let date = chrono::DateTime::from_timestamp_millis(time as i64)
+                        .map(|d| d.to_rfc3339())
+                        .unwrap_or_default();
+                    println!("{} existed before {}, block {} at height {}", file.display(), date, proof.block.hash, proof.block.height);
                 } else {
-                    println!("to not supply!: usage");
-                    exit(1);
+                    println!("{}", serde_json::to_string_pretty(&proof)?);
+                }
+            }
+
+            if let Some(matches) = matches.subcommand_matches("tokenissue") {
+                let from = matches.get_one::<String>("FROM").unwrap();
+                let ticker = matches.get_one::<String>("TICKER").unwrap();
+                let supply = *matches.get_one::<u64>("SUPPLY").unwrap();
+                let fee: Amount = match matches.get_one::<String>("fee") {
+                    Some(fee) => fee.parse()?,
+                    None => config.fee
+                };
+
+                let (utxo_set, tokens, _) = self.app_indexes(&config)?;
+                let tx = tokens.issue_tx(&Wallets::new(&config)?, from, ticker, supply, fee)?;
+                let token = tx.id;
+                self.mine_app_tx(&utxo_set, from, tx)?;
+                println!("issued {} {} as token {}", supply, ticker, token);
+            }
+
+            if let Some(matches) = matches.subcommand_matches("tokensend") {
+                let from = matches.get_one::<String>("FROM").unwrap();
+                let to = matches.get_one::<String>("TO").unwrap();
+                let token: Hash256 = matches.get_one::<String>("TOKEN").unwrap().parse()?;
+                let amount = *matches.get_one::<u64>("AMOUNT").unwrap();
+                let fee: Amount = match matches.get_one::<String>("fee") {
+                    Some(fee) => fee.parse()?,
+                    None => config.fee
+                };
+
+                let (utxo_set, tokens, _) = self.app_indexes(&config)?;
+                let tx = tokens.send_tx(&Wallets::new(&config)?, from, to, &token, amount, fee)?;
+                let txid = tx.id;
+                self.mine_app_tx(&utxo_set, from, tx)?;
+                println!("sent {} of token {} to {} in {}", amount, token, to, txid);
+            }
+
+            if let Some(matches) = matches.subcommand_matches("tokenbalance") {
+                let address = matches.get_one::<String>("ADDRESS").unwrap();
+                let (_, tokens, _) = self.app_indexes(&config)?;
+                let balances = tokens.balances(&address::decode(address)?)?;
+                println!("{}", serde_json::to_string_pretty(&balances)?);
+            }
+
+            if let Some(matches) = matches.subcommand_matches("name_register") {
+                let from = matches.get_one::<String>("FROM").unwrap();
+                let name = matches.get_one::<String>("NAME").unwrap();
+                let value = matches.get_one::<String>("VALUE").unwrap();
+                let fee: Amount = match matches.get_one::<String>("fee") {
+                    Some(fee) => fee.parse()?,
+                    None => config.fee
+                };
+
+                let (utxo_set, _, names) = self.app_indexes(&config)?;
+                let tx = names.register_tx(&Wallets::new(&config)?, from, name, value, fee)?;
+                self.mine_app_tx(&utxo_set, from, tx)?;
+                println!("{}", serde_json::to_string_pretty(&name_json(name, names.lookup(name)?.as_ref()))?);
+            }
+
+            if let Some(matches) = matches.subcommand_matches("name_update") {
+                let from = matches.get_one::<String>("FROM").unwrap();
+                let name = matches.get_one::<String>("NAME").unwrap();
+                let value = matches.get_one::<String>("VALUE").unwrap();
+                let to = matches.get_one::<String>("to").unwrap_or(from);
+                let fee: Amount = match matches.get_one::<String>("fee") {
+                    Some(fee) => fee.parse()?,
+                    None => config.fee
+                };
+
+                let (utxo_set, _, names) = self.app_indexes(&config)?;
+                let tx = names.update_tx(&Wallets::new(&config)?, from, to, name, value, fee)?;
+                self.mine_app_tx(&utxo_set, from, tx)?;
+                println!("{}", serde_json::to_string_pretty(&name_json(name, names.lookup(name)?.as_ref()))?);
+            }
+
+            if let Some(matches) = matches.subcommand_matches("name_lookup") {
+                let name = matches.get_one::<String>("NAME").unwrap();
+                let entry = match control::request(&config, "name_lookup", &[name]) {
+                    Ok(entry) => entry,
+                    Err(_) => {
+                        let (_, _, names) = self.app_indexes(&config)?;
+                        name_json(name, names.lookup(name)?.as_ref())
+                    }
+                };
+                println!("{}", serde_json::to_string_pretty(&entry)?);
+            }
+
+            if matches.subcommand_matches("status").is_some() {
+                let status = match control::request(&config, "status", &[]) {
+                    Ok(status) => status,
+                    Err(e) => {
+                        println!("{}, showing the local chain", e);
+                        let bc = Blockchain::new(&config)?;
+                        chain_status(&config, &bc)?
+                    }
+                };
+                println!("{}", serde_json::to_string_pretty(&status)?);
+            }
+
+            if matches.subcommand_matches("getblockchaininfo").is_some() {
+                let info = control::request(&config, "getblockchaininfo", &[])?;
+                println!("{}", serde_json::to_string_pretty(&info)?);
+            }
+
+            if matches.subcommand_matches("getmempoolinfo").is_some() {
+                let info = control::request(&config, "getmempoolinfo", &[])?;
+                println!("{}", serde_json::to_string_pretty(&info)?);
+            }
+
+            if matches.subcommand_matches("getdoublespends").is_some() {
+                let alerts = control::request(&config, "getdoublespends", &[])?;
+                println!("{}", serde_json::to_string_pretty(&alerts)?);
+            }
+
+            if matches.subcommand_matches("getmininginfo").is_some() {
+                let info = control::request(&config, "getmininginfo", &[])?;
+                println!("{}", serde_json::to_string_pretty(&info)?);
+            }
+
+            if let Some(matches) = matches.subcommand_matches("estimatefee") {
+                let target = matches.get_one::<String>("TARGET").unwrap();
+                let estimate = control::request(&config, "estimatefee", &[target])?;
+                println!("{}", serde_json::to_string_pretty(&estimate)?);
+            }
+
+            if let Some(matches) = matches.subcommand_matches("setgenerate") {
+                let mut args = vec![matches.get_one::<String>("MODE").unwrap().as_str()];
+                if let Some(threads) = matches.get_one::<String>("THREADS") {
+                    args.push(threads);
+                }
+                let reply = control::request(&config, "setgenerate", &args)?;
+                println!("{}", serde_json::to_string_pretty(&reply)?);
+            }
+
+            if matches.subcommand_matches("peers").is_some() {
+                let peers = control::request(&config, "peers", &[])?;
+                println!("{}", serde_json::to_string_pretty(&peers)?);
+            }
+
+            if matches.subcommand_matches("getpeerinfo").is_some() {
+                let peers = control::request(&config, "getpeerinfo", &[])?;
+                println!("{}", serde_json::to_string_pretty(&peers)?);
+            }
+
+            if let Some(matches) = matches.subcommand_matches("ban") {
+                let mut args = vec![matches.get_one::<String>("ADDR").unwrap().as_str()];
+                if let Some(duration) = matches.get_one::<String>("DURATION") {
+                    args.push(duration);
+                }
+                let ban = control::request(&config, "ban", &args)?;
+                println!("{}", serde_json::to_string_pretty(&ban)?);
+            }
+
+            if let Some(matches) = matches.subcommand_matches("unban") {
+                let addr = matches.get_one::<String>("ADDR").unwrap();
+                let unbanned = control::request(&config, "unban", &[addr.as_str()])?;
+                if unbanned.as_bool() != Some(true) {
+                    println!("{} was not banned", addr);
+                }
+            }
+
+            if matches.subcommand_matches("listbanned").is_some() {
+                let bans = control::request(&config, "listbanned", &[])?;
+                println!("{}", serde_json::to_string_pretty(&bans)?);
+            }
+
+            if matches.subcommand_matches("stopnode").is_some() {
+                let reply = control::request(&config, "stopnode", &[])?;
+                println!("{}", reply.as_str().unwrap_or_default());
+            }
+
+            if matches.subcommand_matches("stop").is_some() {
+                let pid = daemon::stop(&config)?;
+                println!("node {} stopped", pid);
+            }
+
+            if let Some(matches) = matches.subcommand_matches("getrawmempool") {
+                let args: &[&str] = if matches.get_flag("verbose") { &["verbose"] } else { &[] };
+                let mempool = control::request(&config, "getrawmempool", args)?;
+                println!("{}", serde_json::to_string_pretty(&mempool)?);
+            }
+
+            if let Some(matches) = matches.subcommand_matches("waitfortx") {
+                let txid = matches.get_one::<String>("TXID").unwrap();
+                let target = *matches.get_one::<u64>("confirmations").unwrap();
+                let deadline = matches.get_one::<u64>("timeout").map(|s| Instant::now() + Duration::from_secs(*s));
+
+                loop {
+                    let reply = control::request(&config, "gettxconfirmations", &[txid])?;
+                    let confirmations = reply["confirmations"].as_u64().unwrap_or(0);
+                    if confirmations >= target {
+                        println!("{}", confirmations);
+                        break;
+                    }
+
+                    let wait = match deadline {
+                        Some(deadline) if Instant::now() >= deadline => {
+                            eprintln!("timed out with {} of {} confirmations", confirmations, target);
+                            exit(1)
+                        },
+                        Some(deadline) => (deadline - Instant::now()).min(MAX_BLOCK_WAIT),
+                        None => MAX_BLOCK_WAIT
+                    };
+                    let tip = reply["bestblockhash"].as_str().unwrap_or_default();
+                    control::request(&config, "waitfornewblock", &[tip, &wait.as_millis().to_string()])?;
+                }
+            }
+
+            if let Some(matches) = matches.subcommand_matches("watch") {
+                let address = matches.get_one::<String>("ADDRESS").unwrap();
+                address::decode(address)?;
+                let exec = matches.get_one::<String>("exec");
+                let confirmations = *matches.get_one::<usize>("confirmations").unwrap();
+                let depth = (confirmations + watch::DEPTH_MARGIN).to_string();
+                let wait = MAX_BLOCK_WAIT.as_millis().to_string();
+                let mut watcher = Watcher::new(confirmations);
+                let mut state = Hash256::ZERO.to_string();
+
+                loop {
+                    let reply = control::request(&config, "watchaddress", &[address, &state, &depth, &wait])?;
+                    let snapshot: Snapshot = serde_json::from_value(reply)?;
+                    for event in watcher.update(&snapshot) {
+                        match exec {
+                            Some(command) => {
+                                if let Err(e) = watch::run_hook(command, &event) {
+                                    eprintln!("{}", e);
+                                }
+                            },
+                            None => {
+                                println!("{}", serde_json::to_string(&event)?);
+                                std::io::stdout().flush()?;
+                            }
+                        }
+                    }
+                    state = snapshot.state.to_string();
+                }
+            }
+
+            if let Some(matches) = matches.subcommand_matches("tail-events") {
+                let since = *matches.get_one::<u64>("since").unwrap_or(&0);
+                eventlog::tail(&config.event_log_path(), since, matches.get_flag("follow"), &mut std::io::stdout())?;
+            }
+
+            if let Some(matches) = matches.subcommand_matches("replay") {
+                let recording = Recording::open(Path::new(matches.get_one::<String>("FILE").unwrap()))?;
+                let report = match matches.get_one::<String>("into") {
+                    Some(dir) => replay::replay(&config, &recording, Path::new(dir))?,
+                    None => {
+                        let dir = std::env::temp_dir().join(format!("blockchain-replay-{}", std::process::id()));
+                        let report = replay::replay(&config, &recording, &dir);
+                        let _ = std::fs::remove_dir_all(&dir);
+                        report?
+                    }
                 };
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            }
+
+            if let Some(matches) = matches.subcommand_matches("faucet") {
+                let address = matches.get_one::<String>("ADDRESS").unwrap();
+                let payment = control::request(&config, "faucet", &[address])?;
+                println!("{}", serde_json::to_string_pretty(&payment)?);
+            }
+
+            if let Some(matches) = matches.subcommand_matches("schedule-send") {
+                let from = matches.get_one::<String>("from").unwrap_or(&config.mining_address);
+                let mut args = vec![
+                    from.as_str(),
+                    matches.get_one::<String>("to").unwrap(),
+                    matches.get_one::<String>("amount").unwrap(),
+                    matches.get_one::<String>("every").unwrap()
+                ];
+                let times = matches.get_one::<u64>("times").map(|times| times.to_string());
+                args.extend(times.as_deref());
+                let schedule = control::request(&config, "schedulesend", &args)?;
+                println!("{}", serde_json::to_string_pretty(&schedule)?);
+            }
+
+            if matches.subcommand_matches("listschedules").is_some() {
+                let schedules = control::request(&config, "listschedules", &[])?;
+                println!("{}", serde_json::to_string_pretty(&schedules)?);
+            }
+
+            if let Some(matches) = matches.subcommand_matches("cancelschedule") {
+                let id = matches.get_one::<u64>("ID").unwrap().to_string();
+                let schedule = control::request(&config, "cancelschedule", &[id.as_str()])?;
+                println!("{}", serde_json::to_string_pretty(&schedule)?);
+            }
 
-                let amount: i32 = if let Some(amount) = matches.get_one::<String>("AMOUNT") {
-                    amount.parse()?
+            if let Some(matches) = matches.subcommand_matches("listtransactions") {
+                let address = matches.get_one::<String>("ADDRESS").unwrap();
+                let history = control::request(&config, "listtransactions", &[address])?;
+                println!("{}", serde_json::to_string_pretty(&history)?);
+            }
+
+            if let Some(matches) = matches.subcommand_matches("walletaudit") {
+                let audit = control::request(&config, "walletaudit", &[])?;
+                if matches.get_flag("json") {
+                    println!("{}", serde_json::to_string_pretty(&audit)?);
                 } else {
-                    println!("amount not supply!: usage");
+                    let warnings = audit["warnings"].as_array().cloned().unwrap_or_default();
+                    for warning in &warnings {
+                        let txid = warning["txid"].as_str().map(|txid| format!(" {}", txid)).unwrap_or_default();
+                        println!("{} {}{}: {}", warning["issue"].as_str().unwrap_or_default(), warning["address"].as_str().unwrap_or_default(), txid, warning["message"].as_str().unwrap_or_default());
+                    }
+                    println!("{} warnings about {} addresses", warnings.len(), audit["addresses"]);
+                }
+            }
+
+            if matches.subcommand_matches("getblockcount").is_some() {
+                let bc = Blockchain::new(&config)?;
+                println!("{}", bc.get_best_height()?);
+            }
+
+            if matches.subcommand_matches("getbestblockhash").is_some() {
+                let bc = Blockchain::new(&config)?;
+                println!("{}", bc.get_tip());
+            }
+
+            if matches.subcommand_matches("getdifficulty").is_some() {
+                let bc = Blockchain::new(&config)?;
+                println!("{}", bc.get_difficulty()?);
+            }
+
+            if let Some(matches) = matches.subcommand_matches("exporthistory") {
+                let address = matches.get_one::<String>("wallet").unwrap();
+                let out = matches.get_one::<String>("out").unwrap();
+                let pub_key_hash = address::decode(address)?;
+
+                let bc = Blockchain::new(&config)?;
+                let mut history = address_history(&bc, &pub_key_hash)?;
+                apply_labels(&mut history, &Wallets::new(&config)?);
+                let mut writer = csv::Writer::from_path(out)?;
+                writer.write_record(["date", "txid", "direction", "amount", "fee", "address", "confirmations", "label", "message", "memo"])?;
+                for entry in &history {
+                    let date = chrono::DateTime::from_timestamp_millis(entry.time as i64)
+                        .map(|d| d.to_rfc3339())
+                        .unwrap_or_default();
+                    let direction = serde_json::to_value(entry.direction)?;
+                    writer.write_record([
+                        date,
+                        entry.txid.to_string(),
+                        direction.as_str().unwrap_or_default().to_string(),
+                        entry.amount.to_string(),
+                        entry.fee.to_string(),
+                        entry.address.clone(),
+                        entry.confirmations.to_string(),
+                        entry.label.as_ref().map(|l| l.label.clone()).unwrap_or_default(),
+                        entry.label.as_ref().map(|l| l.message.clone()).unwrap_or_default(),
+                        entry.memo.clone().unwrap_or_default()
+                    ])?;
+                }
+                writer.flush()?;
+                println!("{} transactions written to {}", history.len(), out);
+            }
+
+            if let Some(matches) = matches.subcommand_matches("createinvoice") {
+                let request = PaymentRequest {
+                    address: matches.get_one::<String>("ADDRESS").unwrap().clone(),
+                    amount: match matches.get_one::<String>("amount") {
+                        Some(amount) => Some(amount.parse()?),
+                        None => None
+                    },
+                    label: matches.get_one::<String>("label").cloned(),
+                    message: matches.get_one::<String>("message").cloned()
+                };
+                address::decode(&request.address)?;
+                let uri = request.to_string();
+
+                println!("{}", uri);
+                if matches.get_flag("qr") {
+                    println!("{}", qr::render_terminal(&uri)?);
+                }
+                if let Some(file) = matches.get_one::<String>("png") {
+                    qr::write_png(&uri, Path::new(file), 8)?;
+                    println!("QR code written to {}", file);
+                }
+            }
+
+            if let Some(matches) = matches.subcommand_matches("showaddress") {
+                let address = matches.get_one::<String>("ADDRESS").unwrap();
+                address::decode(address)?;
+
+                println!("{}", address);
+                if matches.get_flag("qr") {
+                    println!("{}", qr::render_terminal(address)?);
+                }
+                if let Some(file) = matches.get_one::<String>("png") {
+                    qr::write_png(address, Path::new(file), 8)?;
+                    println!("QR code written to {}", file);
+                }
+            }
+
+            if let Some(matches) = matches.subcommand_matches("validateaddress") {
+                let address = matches.get_one::<String>("ADDRESS").unwrap();
+                let view = match address::decode_with_version(address) {
+                    Ok((version, pub_key_hash)) => json!({
+                        "address": address,
+                        "isvalid": true,
+                        "network": if version == address::MAIN_VERSION { "main" } else { "test" },
+                        "isnetwork": version == address::version(config.network),
+                        "version": version,
+                        "scheme": "base58check",
+                        "hashtype": "p2pkh",
+                        "pubkeyhash": hex::encode(&pub_key_hash),
+                        "ismine": Wallets::new(&config)?.get_wallet(address).is_some()
+                    }),
+                    Err(e) => json!({
+                        "address": address,
+                        "isvalid": false,
+                        "error": e.to_string()
+                    })
+                };
+                println!("{}", serde_json::to_string_pretty(&view)?);
+            }
+
+            if let Some(matches) = matches.subcommand_matches("startnode") {
+                let mut config = config.clone();
+                // a node that does not mine still runs its faucet from the mining wallet
+                if config.faucet_address.is_empty() {
+                    config.faucet_address = config.mining_address.clone();
+                }
+                config.mining_address = String::new();
+                self.start_server(&config, matches)?;
+            }
+
+            if let Some(matches) = matches.subcommand_matches("startminer") {
+                let mut config = config.clone();
+                if let Some(address) = matches.get_one::<String>("address") {
+                    config.mining_address = address.clone();
+                }
+                if address::decode(&config.mining_address).is_err() {
+                    println!("mining address is not valid: '{}'", config.mining_address);
                     exit(1);
+                }
+                self.start_server(&config, matches)?;
+            }
+
+            if let Some(matches) = matches.subcommand_matches("startworker") {
+                let addr = matches.get_one::<String>("ADDR").unwrap();
+                let name = matches.get_one::<String>("name").map_or("worker", String::as_str);
+                stratum::run_worker(addr, name)?;
+            }
+
+            if let Some(matches) = matches.subcommand_matches("getblock") {
+                let bc = Blockchain::new(&config)?;
+                let id = matches.get_one::<String>("BLOCK").unwrap();
+                let hash = match id.parse::<usize>() {
+                    Ok(height) => bc.get_block_hash(height)?,
+                    Err(_) => id.parse()?
                 };
 
-                let mut bc = Blockchain::new()?;
-                let mut utxo_set = UTXOSet { blockchain: bc };
-                let tx = Transaction::new_UTXO(from, to, amount, &utxo_set)?;
-                let cbtx = Transaction::new_coinbase(from.to_string(), String::from("reward"))?;
-                let new_block = utxo_set.blockchain.add_block(vec![cbtx, tx])?;
+                let block = bc.get_block(&hash)?;
+                if matches.get_flag("verbose") {
+                    println!("{}", serde_json::to_string_pretty(&chain_block_json(&bc, &block)?)?);
+                } else {
+                    println!("{}", hex::encode(bincode::serialize(&block)?));
+                }
+            }
 
-                utxo_set.update(&new_block)?;
-                println!("sucess!");
+            if let Some(matches) = matches.subcommand_matches("getblockheader") {
+                let bc = Blockchain::new(&config)?;
+                let hash = matches.get_one::<String>("HASH").unwrap().parse()?;
+                let header = bc.get_block_header(&hash)?;
+                println!("{}", serde_json::to_string_pretty(&header_json(&header))?);
+            }
+
+            if let Some(matches) = matches.subcommand_matches("gettransaction") {
+                let bc = Blockchain::new(&config)?;
+                let txid: Hash256 = matches.get_one::<String>("TXID").unwrap().parse()?;
+                let (tx, header) = match bc.get_indexed_transaction(&txid)? {
+                    Some(found) => found,
+                    None => {
+                        println!("transaction {} not found", txid);
+                        exit(1);
+                    }
+                };
+
+                let mut view = tx_json(&tx);
+                view["blockhash"] = json!(header.hash);
+                view["height"] = json!(header.height);
+                view["confirmations"] = json!(bc.confirmations(&header)?);
+                println!("{}", serde_json::to_string_pretty(&view)?);
+            }
+
+            if let Some(matches) = matches.subcommand_matches("listunspent") {
+                let pub_key_hash = match matches.get_one::<String>("ADDRESS") {
+                    Some(address) => match address::decode(address) {
+                        Ok(pub_key_hash) => Some(pub_key_hash),
+                        Err(_) => {
+                            println!("address is not valid: {}", address);
+                            exit(1);
+                        }
+                    },
+                    None => None
+                };
+
+                let bc = Blockchain::new(&config)?;
+                let best_height = bc.get_best_height()? as usize;
+                let utxo_set = UTXOSet { blockchain: bc };
+
+                let mut list = Vec::new();
+                for out in utxo_set.list_unspent(pub_key_hash.as_deref())? {
+                    list.push(json!({
+                        "txid": out.outpoint.txid,
+                        "vout": out.outpoint.index,
+                        "address": hash_to_address(&out.pub_key_hash),
+                        "amount": out.value,
+                        "confirmations": (best_height + 1).saturating_sub(out.height)
+                    }));
+                }
+                println!("{}", serde_json::to_string_pretty(&list)?);
+            }
+
+            if let Some(matches) = matches.subcommand_matches("printchain") {
+                self.print_chain(&config, matches)?;
+            }
+
+            if let Some(matches) = matches.subcommand_matches("simulate-attack") {
+                if config.network != Network::Regtest {
+                    return Err(BlockchainError::Config("simulate-attack runs on regtest only, pass --network regtest".to_string()));
+                }
+                self.simulate_attack(matches)?;
+            }
+
+            if let Some(matches) = matches.subcommand_matches("chainstats") {
+                let bc = Blockchain::new(&config)?;
+                let from = *matches.get_one::<usize>("from").unwrap_or(&0);
+                let to = *matches.get_one::<usize>("to").unwrap_or(&usize::MAX);
+                let stats = chain_stats(&bc, from, to)?;
+                if matches.get_flag("table") {
+                    print!("{}", stats);
+                } else {
+                    println!("{}", serde_json::to_string_pretty(&stats)?);
+                }
             }
 
-            if let Some(_) = matches.subcommand_matches("printchain") {
-                self.print_chain()?;
+            if let Some(matches) = matches.subcommand_matches("verifysupply") {
+                let audit = Blockchain::new(&config)?.audit_supply()?;
+                let mut report = serde_json::to_value(&audit)?;
+                if matches.get_flag("blocks") {
+                    report["blocks"] = serde_json::to_value(&audit.blocks)?;
+                }
+                println!("{}", serde_json::to_string_pretty(&report)?);
+                if !audit.is_sound() {
+                    return Err(BlockchainError::Consensus(format!("the supply does not add up: {} expected, {} in the UTXO set", audit.expected, audit.utxo_value)));
+                }
+            }
+
+            if let Some(matches) = matches.subcommand_matches("schedule") {
+                let bc = match Blockchain::new(&config) {
+                    Ok(bc) => Some(bc),
+                    Err(BlockchainError::NoChain(_)) => None,
+                    Err(e) => return Err(e)
+                };
+                let epochs = match (matches.get_one::<usize>("epochs"), &bc) {
+                    (Some(epochs), _) => *epochs,
+                    (None, Some(bc)) => (bc.get_best_height()? as usize / RETARGET_INTERVAL + 1).max(DEFAULT_EPOCHS),
+                    (None, None) => DEFAULT_EPOCHS
+                };
+                let mut projection = Projection::new(config.network, epochs, matches.get_one::<f64>("hashrate").copied())?;
+                if let Some(bc) = &bc {
+                    projection.compare(bc)?;
+                }
+                if matches.get_flag("table") {
+                    print!("{}", projection);
+                } else {
+                    println!("{}", serde_json::to_string_pretty(&projection)?);
+                }
             }
 
             if let Some(_) = matches.subcommand_matches("reindex") {
-                let bc = Blockchain::new()?;
+                let bc = Blockchain::new(&config)?;
                 let utxo_set = UTXOSet { blockchain: bc };
                 utxo_set.reindex()?;
                 let count = utxo_set.count_transactions()?;
                 println!("Done! There are {} transactions in the UTXO set.", count);
             }
 
-            if let Some(_) = matches.subcommand_matches("createwallet") {
-                let mut ws = Wallets::new()?;
+            if let Some(matches) = matches.subcommand_matches("reindexutxo") {
+                if confirm("Rebuild the UTXO set?", matches)? {
+                    let bc = Blockchain::new(&config)?;
+                    let utxo_set = UTXOSet { blockchain: bc };
+                    utxo_set.reindex()?;
+                    println!("Done! There are {} transactions in the UTXO set.", utxo_set.count_transactions()?);
+                }
+            }
+
+            if let Some(matches) = matches.subcommand_matches("reindex-txindex") {
+                if confirm("Rebuild the height index and the transaction index?", matches)? {
+                    let bc = Blockchain::new(&config)?;
+                    let count = bc.reindex_txindex()?;
+                    println!("Done! Indexed {} transactions.", count);
+                }
+            }
+
+            if let Some(matches) = matches.subcommand_matches("rebuild-addrindex") {
+                if confirm("Rebuild the address index?", matches)? {
+                    let bc = Blockchain::new(&config)?;
+                    AddressIndex::new(&bc).rebuild()?;
+                    println!("Done! {} blocks indexed.", bc.get_best_height()? + 1);
+                }
+            }
+
+            if let Some(matches) = matches.subcommand_matches("createwallet") {
+                let mut ws = Wallets::new(&wallet_config(&config, matches))?;
                 let address = ws.create_wallet();
                 ws.save_all()?;
                 println!("success: address {}", address);
             }
 
-            if let Some(_) = matches.subcommand_matches("listaddresses") {
-                let ws = Wallets::new()?;
+            if let Some(matches) = matches.subcommand_matches("listaddresses") {
+                let ws = Wallets::new(&wallet_config(&config, matches))?;
                 let addresses = ws.get_all_address();
                 println!("addresses: ");
                 for ad in addresses {
                     println!("{}", ad);
                 }
+                for ad in ws.get_all_watch_only() {
+                    println!("{} (watch-only)", ad);
+                }
+            }
+
+            if let Some(matches) = matches.subcommand_matches("backupwallet") {
+                let file = matches.get_one::<String>("FILE").unwrap();
+                let mut ws = Wallets::new(&wallet_config(&config, matches))?;
+                let backup = ws.backup();
+                std::fs::write(file, serde_json::to_string_pretty(&backup)? + "\n")?;
+                ws.mark_backed_up(addrman::now()?);
+                ws.save_all()?;
+                println!("{} wallets written to {}", backup.wallets.len(), file);
+            }
+
+            if let Some(matches) = matches.subcommand_matches("restorewallet") {
+                let file = matches.get_one::<String>("FILE").unwrap();
+                let backup: WalletBackup = serde_json::from_str(&std::fs::read_to_string(file)?)?;
+                let mut ws = Wallets::new(&wallet_config(&config, matches))?;
+                let added = ws.restore(backup)?;
+                ws.save_all()?;
+                println!("{} wallets restored from {}", added, file);
+            }
+
+            if let Some(matches) = matches.subcommand_matches("exportpubkey") {
+                let address = matches.get_one::<String>("ADDRESS").unwrap();
+                let ws = Wallets::new(&wallet_config(&config, matches))?;
+                match ws.get_wallet(address) {
+                    Some(wallet) => println!("{}", hex::encode(&wallet.public_key)),
+                    None => return Err(BlockchainError::Wallet(format!("{} is not a wallet of this store", address)))
+                }
+            }
+
+            if let Some(matches) = matches.subcommand_matches("importpubkey") {
+                let public_key = hex::decode(matches.get_one::<String>("PUBKEY").unwrap())
+                    .map_err(|e| BlockchainError::Wallet(format!("the public key is not hex: {}", e)))?;
+                let mut ws = Wallets::new(&config)?;
+                let address = ws.import_watch_only(public_key)?;
+                ws.save_all()?;
+                println!("watching {}", address);
+            }
+
+            if let Some(matches) = matches.subcommand_matches("createunsigned") {
+                let from = matches.get_one::<String>("FROM").unwrap();
+                let to = matches.get_one::<String>("TO").unwrap();
+                let amount: Amount = matches.get_one::<String>("AMOUNT").unwrap().parse()?;
+                let fee: Amount = match matches.get_one::<String>("fee") {
+                    Some(fee) => fee.parse()?,
+                    None => config.fee
+                };
+
+                let (utxo_set, _, _) = self.app_indexes(&config)?;
+                let reserved = reserved_outputs(&utxo_set.blockchain, &address::decode(from)?)?;
+                let payment = TXOutput::new(amount, to.to_string())?;
+                let unsigned = offline::create_unsigned(&Wallets::new(&config)?, config.network, from, payment, fee, &utxo_set, |outpoint| !reserved.contains(outpoint))?;
+                let json = serde_json::to_string_pretty(&unsigned)?;
+                match matches.get_one::<String>("out") {
+                    Some(path) => {
+                        std::fs::write(path, json + "\n")?;
+                        println!("unsigned transaction {} written to {}", unsigned.txid, path);
+                    },
+                    None => println!("{}", json)
+                }
+            }
+
+            if let Some(matches) = matches.subcommand_matches("signoffline") {
+                let unsigned: UnsignedTx = serde_json::from_str(&std::fs::read_to_string(matches.get_one::<String>("FILE").unwrap())?)?;
+                let (tx, review) = offline::sign_offline(&Wallets::new(&wallet_config(&config, matches))?, config.network, &unsigned)?;
+                eprintln!("{}", serde_json::to_string_pretty(&review)?);
+                let signed = hex::encode(bincode::serialize(&tx)?);
+                match matches.get_one::<String>("out") {
+                    Some(path) => {
+                        std::fs::write(path, signed + "\n")?;
+                        eprintln!("signed transaction written to {}", path);
+                    },
+                    None => println!("{}", signed)
+                }
+            }
+
+            if let Some(matches) = matches.subcommand_matches("broadcast") {
+                let file = matches.get_one::<String>("FILE").unwrap();
+                let tx = offline::decode_signed(&std::fs::read_to_string(file)?)?;
+                let raw = hex::encode(bincode::serialize(&tx)?);
+                let txid = control::request(&config, "sendrawtransaction", &[&raw])?;
+                println!("{}", txid.as_str().unwrap_or_default());
             }
 
 
@@ -158,4 +1318,513 @@ impl Cli {
 
 
 
-}   
+}
+
+
+/// command builds the clap definition of the whole command line, shared by
+/// the parser, the completion scripts and the man pages
+pub fn command() -> Command {
+    Command::new("blockchain-rust-demo")
+        .version("0.1")
+        .author("rafael.julio.dev@outlook.com")
+        .about("blockchain in rust: a simple blockchain for learning (created via tutorial)")
+        .arg(arg!(--config <FILE>"'Config file, defaults to blockchain.toml when present'").global(true))
+        .arg(arg!(--datadir <DIR>"'Directory holding the chain and wallet data'").global(true))
+        .arg(arg!(--network <NETWORK>"'Network to use: main, test or regtest'").global(true))
+        .arg(Arg::new("verbosity").short('v').action(ArgAction::Count).global(true).help("Log more, -v for info and -vv for debug"))
+        .arg(arg!(-q --quiet "'Only log errors and hide progress'").global(true))
+        .arg(arg!(--"log-format" <FORMAT>"'Log as human readable text or as one JSON object per line'").value_parser(["text", "json"]).default_value("text").global(true))
+        .arg(arg!(--"log-dir" <DIR>"'Log to daily rotated files in this directory instead of stderr'").global(true))
+        .subcommand(Command::new("printchain")
+            .about("print the chain blocks, newest first")
+            .arg(arg!(--"from-height" <HEIGHT>"'Lowest height to print'").value_parser(value_parser!(usize)))
+            .arg(arg!(--"to-height" <HEIGHT>"'Highest height to print'").value_parser(value_parser!(usize)))
+            .arg(arg!(--limit <COUNT>"'Print at most this many blocks'").value_parser(value_parser!(usize)))
+            .arg(arg!(--reverse "'Print oldest first'"))
+            .arg(arg!(--"txids-only" "'Print transaction ids instead of full transactions'"))
+            .arg(arg!(--format <FORMAT>"'Output format'").value_parser(["json", "summary", "full"]).default_value("full"))
+        )
+        .subcommand(
+            Command::new("simulate-attack")
+            .about("mine on two in-process regtest node groups, an attacker one following a withholding strategy, and report orphans and reorgs, needs the simulate feature")
+            .arg(arg!(--share <FRACTION>"'Part of the hashrate the attacker has, from 0 to 1'").value_parser(value_parser!(f64)))
+            .arg(arg!(--blocks <N>"'Blocks to mine'").value_parser(value_parser!(usize)))
+            .arg(arg!(--strategy <STRATEGY>"'honest, selfish or withhold:<depth>'"))
+            .arg(arg!(--"honest-nodes" <N>"'Nodes of the honest group'").value_parser(value_parser!(usize)))
+            .arg(arg!(--"attacker-nodes" <N>"'Nodes of the attacker group'").value_parser(value_parser!(usize)))
+            .arg(arg!(--seed <SEED>"'Seed of the draw of who finds each block'").value_parser(value_parser!(u64)))
+        )
+        .subcommand(
+            Command::new("verifysupply")
+            .about("replay the chain from genesis and check that no block issued more than the subsidy and the UTXO set holds the coins the chain allows")
+            .arg(arg!(--blocks "'Also list the coins each block issued and made unspendable'"))
+        )
+        .subcommand(
+            Command::new("chainstats")
+            .about("print block interval, fee, output age and UTXO statistics of a range of the chain")
+            .arg(arg!(--from <HEIGHT>"'Lowest height to cover'").value_parser(value_parser!(usize)))
+            .arg(arg!(--to <HEIGHT>"'Highest height to cover, the tip by default'").value_parser(value_parser!(usize)))
+            .arg(arg!(--table "'Print a table instead of JSON'"))
+        )
+        .subcommand(
+            Command::new("schedule")
+            .about("project the block reward, supply and difficulty of each retarget epoch and compare them with the chain")
+            .arg(arg!(--epochs <N>"'Epochs to project, 10 or up to the tip by default'").value_parser(value_parser!(usize)))
+            .arg(arg!(--hashrate <HASHES>"'Hashes per second assumed, the rate mining the genesis target on schedule by default'").value_parser(value_parser!(f64)))
+            .arg(arg!(--table "'Print a table instead of JSON'"))
+        )
+        .subcommand(
+            Command::new("createwallet")
+            .about("create a wallet")
+            .arg(arg!(--wallet <NAME>"'Wallet store to create it in, like cold'"))
+        )
+        .subcommand(Command::new("reindex").about("reindex UTXO"))
+        .subcommand(
+            Command::new("reindexutxo")
+            .about("rebuild the UTXO set from the blocks")
+            .arg(arg!(-y --yes "'Do not ask for confirmation'"))
+        )
+        .subcommand(
+            Command::new("reindex-txindex")
+            .about("rebuild the height index and the transaction index from the blocks")
+            .arg(arg!(-y --yes "'Do not ask for confirmation'"))
+        )
+        .subcommand(
+            Command::new("rebuild-addrindex")
+            .about("rebuild the index of the transactions touching each address from the blocks")
+            .arg(arg!(-y --yes "'Do not ask for confirmation'"))
+        )
+        .subcommand(
+            Command::new("listaddresses")
+            .about("list all addresses")
+            .arg(arg!(--wallet <NAME>"'Wallet store to list'"))
+        )
+        .subcommand(
+            Command::new("backupwallet")
+            .about("copy the keys, labels and memos of the wallet store to a JSON file, keep it as safe as the keys")
+            .arg(arg!(<FILE>"'Backup file to write'"))
+            .arg(arg!(--wallet <NAME>"'Wallet store to back up'"))
+        )
+        .subcommand(
+            Command::new("restorewallet")
+            .about("add the keys, labels and memos of a backupwallet file to the wallet store")
+            .arg(arg!(<FILE>"'Backup file to read'"))
+            .arg(arg!(--wallet <NAME>"'Wallet store to restore into'"))
+        )
+        .subcommand(
+            Command::new("exportpubkey")
+            .about("print the public key of a wallet, to watch it from an online node with importpubkey")
+            .arg(arg!(<ADDRESS>"'Wallet address'"))
+            .arg(arg!(--wallet <NAME>"'Wallet store holding it, like cold'"))
+        )
+        .subcommand(
+            Command::new("importpubkey")
+            .about("watch the address of a public key kept elsewhere, so createunsigned can spend from it")
+            .arg(arg!(<PUBKEY>"'Hex public key printed by exportpubkey'"))
+        )
+        .subcommand(
+            Command::new("createunsigned")
+            .about("build an unsigned transaction of a watch-only address, with what an offline signoffline needs")
+            .arg(arg!(<FROM>"'Watch-only or wallet address paying'"))
+            .arg(arg!(<TO>"'Destination address'"))
+            .arg(arg!(<AMOUNT>"'Amount to send, in coins like 1.5 or in sats like \"150 sats\"'"))
+            .arg(arg!(--fee <FEE>"'Fee paid to the miner, like the amount, defaults to the configured fee'"))
+            .arg(arg!(--out <FILE>"'Write it to this file instead of stdout'"))
+        )
+        .subcommand(
+            Command::new("signoffline")
+            .about("sign a transaction written by createunsigned without a chain, printing what it pays first")
+            .arg(arg!(<FILE>"'Unsigned transaction'"))
+            .arg(arg!(--wallet <NAME>"'Wallet store holding the key, like cold'"))
+            .arg(arg!(--out <FILE>"'Write the signed transaction to this file instead of stdout'"))
+        )
+        .subcommand(
+            Command::new("broadcast")
+            .about("submit a transaction signed by signoffline to the running node")
+            .arg(arg!(<FILE>"'Signed transaction'"))
+        )
+        .subcommand(Command::new("getbalance")
+            .about("get balance in the blockchain")
+            .arg(arg!(<ADDRESS>"'The Address it get balance for'"))
+        )
+        .subcommand(
+            Command::new("create")
+            .about("Create new blockchain")
+            .arg(arg!(<ADDRESS>"'The address to send genesis block reqward to'"))
+            .arg(arg!(--compress "'Store block bodies compressed with snappy'"))
+        )
+        .subcommand(
+            Command::new("startnode")
+            .about("start the node server")
+            .arg(arg!(--port <PORT>"'The port server bind to'"))
+            .arg(arg!(--bind <ADDR>"'Listen on this address instead of localhost, like 0.0.0.0:3000 or [::]:3000, may be repeated'").action(ArgAction::Append))
+            .arg(arg!(--"external-address" <ADDR>"'Address peers reach this node at, sent to them in version messages'"))
+            .arg(arg!(--connect <ADDR>"'Peer to connect to, replaces the default known node'").action(ArgAction::Append))
+            .arg(arg!(--proxy <URL>"'Connect to peers through this SOCKS5 proxy, like socks5://127.0.0.1:9050 for Tor'"))
+            .arg(arg!(--onlynet <NET>"'Only connect to peers on this network, ipv4, ipv6 or onion, may be repeated'").action(ArgAction::Append))
+            .arg(arg!(--nolisten "'Only announce to peers, do not accept inbound connections'"))
+            .arg(arg!(--rpcport <PORT>"'Serve JSON-RPC on this localhost port'"))
+            .arg(arg!(--restport <PORT>"'Serve the read-only REST API on this localhost port'"))
+            .arg(arg!(--explorerport <PORT>"'Serve the block explorer web UI on this localhost port, needs the explorer feature'"))
+            .arg(arg!(--wsport <PORT>"'Stream block and transaction notifications over WebSocket on this localhost port'"))
+            .arg(arg!(--grpcport <PORT>"'Serve the gRPC API on this localhost port'"))
+            .arg(arg!(--zmqpubrawblock <ENDPOINT>"'Publish raw blocks on this ZeroMQ endpoint, e.g. tcp://127.0.0.1:28332'"))
+            .arg(arg!(--zmqpubrawtx <ENDPOINT>"'Publish raw transactions on this ZeroMQ endpoint'"))
+            .arg(arg!(--maxmempool <BYTES>"'Memory the mempool may use, its lowest fee rate transactions are evicted beyond it'").value_parser(value_parser!(usize)))
+            .arg(arg!(--eventlog "'Append every block joining or leaving the best chain to events.log, see tail-events'"))
+            .arg(arg!(--record <FILE>"'Write every inbound P2P frame to this file, for replay'"))
+            .arg(arg!(--faucet "'Pay coins of the mining wallet to the addresses asking for them, on test networks only'"))
+            .arg(arg!(--"faucet-amount" <AMOUNT>"'Coins paid per faucet request, like 10 or \"500 sats\"'"))
+            .arg(arg!(--"faucet-cooldown" <SECONDS>"'Wait before paying the same address again'").value_parser(value_parser!(u64)))
+            .arg(arg!(--"public-api" "'Serve only the read-only REST and JSON-RPC endpoints, to every interface and without credentials, and load no wallet'"))
+            .arg(arg!(--"public-api-rate" <N>"'Requests per minute each IP may make to the public API, 60 by default'").value_parser(value_parser!(u32)))
+            .arg(arg!(--daemon "'Detach from the terminal and log to rotating files in the data directory'"))
+        )
+        .subcommand(
+            Command::new("startminer")
+            .about("start the minner server")
+            .arg(arg!(--port <PORT>"'The port server bind to'"))
+            .arg(arg!(--bind <ADDR>"'Listen on this address instead of localhost, like 0.0.0.0:3000 or [::]:3000, may be repeated'").action(ArgAction::Append))
+            .arg(arg!(--"external-address" <ADDR>"'Address peers reach this node at, sent to them in version messages'"))
+            .arg(arg!(--address <ADDRESS>"'The wallet address that receives the mining rewards'"))
+            .arg(arg!(--"mine-threads" <N>"'Threads grinding the proof of work, 0 for one per CPU core'").value_parser(value_parser!(usize)))
+            .arg(arg!(--"mine-nice" "'Run the mining threads at the lowest scheduling priority'"))
+            .arg(arg!(--"mine-min-txs" <N>"'Transactions to wait for before mining a block'").value_parser(value_parser!(usize)))
+            .arg(arg!(--"mine-max-wait" <SECONDS>"'Longest wait for --mine-min-txs before mining what the mempool holds'").value_parser(value_parser!(u64)))
+            .arg(arg!(--"coinbase-msg" <MSG>"'Text, like a pool tag, carried by the coinbase of the mined blocks'"))
+            .arg(arg!(--connect <ADDR>"'Peer to connect to, replaces the default known node'").action(ArgAction::Append))
+            .arg(arg!(--proxy <URL>"'Connect to peers through this SOCKS5 proxy, like socks5://127.0.0.1:9050 for Tor'"))
+            .arg(arg!(--onlynet <NET>"'Only connect to peers on this network, ipv4, ipv6 or onion, may be repeated'").action(ArgAction::Append))
+            .arg(arg!(--nolisten "'Only announce to peers, do not accept inbound connections'"))
+            .arg(arg!(--rpcport <PORT>"'Serve JSON-RPC on this localhost port'"))
+            .arg(arg!(--restport <PORT>"'Serve the read-only REST API on this localhost port'"))
+            .arg(arg!(--explorerport <PORT>"'Serve the block explorer web UI on this localhost port, needs the explorer feature'"))
+            .arg(arg!(--wsport <PORT>"'Stream block and transaction notifications over WebSocket on this localhost port'"))
+            .arg(arg!(--grpcport <PORT>"'Serve the gRPC API on this localhost port'"))
+            .arg(arg!(--zmqpubrawblock <ENDPOINT>"'Publish raw blocks on this ZeroMQ endpoint, e.g. tcp://127.0.0.1:28332'"))
+            .arg(arg!(--zmqpubrawtx <ENDPOINT>"'Publish raw transactions on this ZeroMQ endpoint'"))
+            .arg(arg!(--maxmempool <BYTES>"'Memory the mempool may use, its lowest fee rate transactions are evicted beyond it'").value_parser(value_parser!(usize)))
+            .arg(arg!(--eventlog "'Append every block joining or leaving the best chain to events.log, see tail-events'"))
+            .arg(arg!(--record <FILE>"'Write every inbound P2P frame to this file, for replay'"))
+            .arg(arg!(--faucet "'Pay coins of the mining wallet to the addresses asking for them, on test networks only'"))
+            .arg(arg!(--"faucet-amount" <AMOUNT>"'Coins paid per faucet request, like 10 or \"500 sats\"'"))
+            .arg(arg!(--"faucet-cooldown" <SECONDS>"'Wait before paying the same address again'").value_parser(value_parser!(u64)))
+            .arg(arg!(--workport <PORT>"'Hand out mining jobs to workers connecting to this port on every interface'"))
+            .arg(arg!(--daemon "'Detach from the terminal and log to rotating files in the data directory'"))
+        )
+        .subcommand(
+            Command::new("startworker")
+            .about("hash the jobs handed out by the work server of a miner, one worker uses one core")
+            .arg(arg!(<ADDR>"'Work server to connect to, like localhost:3333'"))
+            .arg(arg!(--name <NAME>"'Name the miner logs the shares of this worker under'"))
+        )
+        .subcommand(
+            Command::new("getblock")
+            .about("print a single block as raw hex or decoded JSON")
+            .arg(arg!(<BLOCK>"'Block hash or height'"))
+            .arg(arg!(--verbose "'Print the decoded block as JSON'"))
+        )
+        .subcommand(
+            Command::new("getblockheader")
+            .about("print the header of a block as JSON")
+            .arg(arg!(<HASH>"'Block hash'"))
+        )
+        .subcommand(
+            Command::new("gettransaction")
+            .about("print a confirmed transaction with its block and confirmations")
+            .arg(arg!(<TXID>"'Transaction id'"))
+        )
+        .subcommand(
+            Command::new("listunspent")
+            .about("list unspent outputs, optionally only those of one address")
+            .arg(arg!([ADDRESS]"'Only list the outputs of this address'"))
+        )
+        .subcommand(
+            Command::new("status")
+            .about("show the status of the running node, or of the local chain when no node answers")
+        )
+        .subcommand(
+            Command::new("getblockchaininfo")
+            .about("show the height of the running node against its peers and its sync progress")
+        )
+        .subcommand(
+            Command::new("getmempoolinfo")
+            .about("show the size and minimum fee rate of the running node's mempool")
+        )
+        .subcommand(
+            Command::new("getdoublespends")
+            .about("list the transactions the running node refused for spending an output already spent in its mempool or a recent block")
+        )
+        .subcommand(
+            Command::new("getmininginfo")
+            .about("show the difficulty, estimated network hashrate and mining counters of the running node")
+        )
+        .subcommand(
+            Command::new("estimatefee")
+            .about("estimate the fee rate, in sats per byte, that confirmed within TARGET blocks on the running node's recent chain")
+            .arg(arg!(<TARGET>"'Blocks to confirm within, estimates are kept for 1, 3 and 6'"))
+        )
+        .subcommand(
+            Command::new("setgenerate")
+            .about("turn mining of the running miner on or off without restarting it")
+            .arg(arg!(<MODE>"'on or off'").value_parser(["on", "off"]))
+            .arg(arg!([THREADS]"'Mining threads, 0 for one per CPU core'"))
+        )
+        .subcommand(Command::new("peers").about("list the peers known to the running node"))
+        .subcommand(Command::new("getpeerinfo").about("list the peers of the running node with the software version and best height each announced"))
+        .subcommand(
+            Command::new("ban")
+            .about("refuse connections from and to a host on the running node, across restarts")
+            .arg(arg!(<ADDR>"'Host or host:port to ban, an IP address also stops its inbound connections'"))
+            .arg(arg!([DURATION]"'How long the ban lasts, in seconds or like 12-hours, a day by default'"))
+        )
+        .subcommand(
+            Command::new("unban")
+            .about("lift the ban of a host on the running node")
+            .arg(arg!(<ADDR>"'Host or host:port to unban'"))
+        )
+        .subcommand(Command::new("listbanned").about("list the hosts banned on the running node and when each ban ends"))
+        .subcommand(Command::new("stopnode").about("stop the node running on the data directory"))
+        .subcommand(Command::new("stop").about("stop the node named by the pid file, even when its control socket does not answer"))
+        .subcommand(
+            Command::new("getrawmempool")
+            .about("list the txids in the running node's mempool")
+            .arg(arg!(--verbose "'Show fee, size, entry time and ancestors of every entry'"))
+        )
+        .subcommand(
+            Command::new("waitfortx")
+            .about("wait until a transaction has enough confirmations on the running node")
+            .arg(arg!(<TXID>"'Transaction id'"))
+            .arg(arg!(--confirmations <N>"'Confirmations to wait for'").value_parser(value_parser!(u64)).default_value("1"))
+            .arg(arg!(--timeout <SECONDS>"'Give up and exit 1 after this many seconds'").value_parser(value_parser!(u64)))
+        )
+        .subcommand(
+            Command::new("watch")
+            .about("follow an address on the running node, printing a JSON line or running a command when it receives or spends funds and when that transaction is confirmed")
+            .arg(arg!(<ADDRESS>"'Address to watch'"))
+            .arg(arg!(--exec <CMD>"'Run this through sh -c for each event, with the JSON line on stdin and WATCH_* variables set'"))
+            .arg(arg!(--confirmations <N>"'Confirmations after which a transaction is reported as confirmed'").value_parser(value_parser!(usize)).default_value("1"))
+        )
+        .subcommand(
+            Command::new("tail-events")
+            .about("print the records of the event log of a node started with --eventlog as NDJSON, for external indexers")
+            .arg(arg!(--since <SEQ>"'Print only the records after this sequence number'").value_parser(value_parser!(u64)))
+            .arg(arg!(-f --follow "'Keep printing new records as the node appends them'"))
+        )
+        .subcommand(
+            Command::new("replay")
+            .about("rebuild a node recorded with --record in a fresh data directory and feed it the recorded frames, printing where it ends")
+            .arg(arg!(<FILE>"'Recording to replay'"))
+            .arg(arg!(--into <DIR>"'Keep the replayed node in this new directory instead of a temporary one'"))
+        )
+        .subcommand(
+            Command::new("consolidate")
+            .about("merge the smallest outputs of a wallet into one output to a fresh address, mined locally like send")
+            .arg(arg!(<ADDRESS>"'Wallet address whose outputs are merged'"))
+            .arg(arg!(--"max-inputs" <N>"'Most outputs to merge, 50 by default'").value_parser(value_parser!(usize)))
+            .arg(arg!(--"fee-rate" <F>"'Fee in sats per byte of the transaction, 0 by default'").value_parser(value_parser!(f64)))
+            .arg(arg!(--dust <AMOUNT>"'Refuse to create an output smaller than this, 1 sat by default'"))
+            .arg(arg!(--maturity <N>"'Confirmations a coinbase output needs before it is merged, 100 by default'").value_parser(value_parser!(usize)))
+            .arg(arg!(--"dry-run" "'Build and sign the transaction and print it without mining'"))
+        )
+        .subcommand(
+            Command::new("rotatewallet")
+            .about("move the wallet to a fresh seed: sweep the spendable outputs of every key to new addresses, mined locally like send, and retire the emptied keys as watch-only")
+            .arg(arg!(--"max-inputs" <N>"'Most outputs one sweep spends, 500 by default'").value_parser(value_parser!(usize)))
+            .arg(arg!(--"fee-rate" <F>"'Fee in sats per byte of each sweep, 0 by default'").value_parser(value_parser!(f64)))
+            .arg(arg!(--maturity <N>"'Confirmations a coinbase output needs before it is swept, 100 by default'").value_parser(value_parser!(usize)))
+            .arg(arg!(--"dry-run" "'Sign the sweeps and print them without mining or saving'"))
+        )
+        .subcommand(
+            Command::new("anchor")
+            .about("timestamp a file by putting its sha256 in a data output, mined locally like send")
+            .arg(arg!(<FILE>"'File to timestamp'"))
+            .arg(arg!(<FROM>"'Wallet address paying the fee'"))
+            .arg(arg!(--fee <FEE>"'Fee paid to the miner, defaults to the configured fee'"))
+        )
+        .subcommand(
+            Command::new("prove")
+            .about("print the proof that the anchor transaction TXID timestamps a file, or check a saved proof with --verify")
+            .arg(arg!(<FILE>"'File the proof is about'"))
+            .arg(arg!([TXID]"'Anchor transaction of the file'"))
+            .arg(arg!(--verify <PROOF>"'Proof JSON to check against the file and the local chain'"))
+        )
+        .subcommand(
+            Command::new("tokenissue")
+            .about("issue a token on a data output, mined locally like send")
+            .arg(arg!(<FROM>"'Wallet address issuing the token and receiving its supply'"))
+            .arg(arg!(<TICKER>"'Short name of the token, up to 8 letters or digits'"))
+            .arg(arg!(<SUPPLY>"'Units of the token to create'").value_parser(value_parser!(u64)))
+            .arg(arg!(--fee <FEE>"'Fee paid to the miner, defaults to the configured fee'"))
+        )
+        .subcommand(
+            Command::new("tokensend")
+            .about("send units of a token, mined locally like send")
+            .arg(arg!(<FROM>"'Wallet address holding the token'"))
+            .arg(arg!(<TO>"'Destination address'"))
+            .arg(arg!(<TOKEN>"'Token id, the txid of its issue'"))
+            .arg(arg!(<AMOUNT>"'Units of the token to send'").value_parser(value_parser!(u64)))
+            .arg(arg!(--fee <FEE>"'Fee paid to the miner, defaults to the configured fee'"))
+        )
+        .subcommand(
+            Command::new("tokenbalance")
+            .about("print the tokens held by an address")
+            .arg(arg!(<ADDRESS>"'Address to look up'"))
+        )
+        .subcommand(
+            Command::new("name_register")
+            .about("register a free or expired name with a value, mined locally like send")
+            .arg(arg!(<FROM>"'Wallet address that will own the name'"))
+            .arg(arg!(<NAME>"'Name, up to 20 lowercase letters, digits, dots or dashes'"))
+            .arg(arg!(<VALUE>"'Value of the name, up to 32 bytes'"))
+            .arg(arg!(--fee <FEE>"'Fee paid to the miner, defaults to the configured fee'"))
+        )
+        .subcommand(
+            Command::new("name_update")
+            .about("set a new value of an owned name and renew it, or transfer it with --to")
+            .arg(arg!(<FROM>"'Wallet address owning the name'"))
+            .arg(arg!(<NAME>"'Name to update'"))
+            .arg(arg!(<VALUE>"'New value of the name'"))
+            .arg(arg!(--to <ADDRESS>"'Transfer the name to this address'"))
+            .arg(arg!(--fee <FEE>"'Fee paid to the miner, defaults to the configured fee'"))
+        )
+        .subcommand(
+            Command::new("name_lookup")
+            .about("print the value and owner of a name, from the running node or the local chain")
+            .arg(arg!(<NAME>"'Name to look up'"))
+        )
+        .subcommand(
+            Command::new("faucet")
+            .about("ask the faucet of the running node, started with --faucet, for coins")
+            .arg(arg!(<ADDRESS>"'Address to pay'"))
+        )
+        .subcommand(
+            Command::new("schedule-send")
+            .about("have the running node pay an address from one of its wallets every so many blocks or so much time")
+            .arg(arg!(--to <ADDRESS>"'Address to pay'").required(true))
+            .arg(arg!(--amount <AMOUNT>"'Amount of each payment, like 1.5 or \"150 sats\"'").required(true))
+            .arg(arg!(--every <PERIOD>"'Period like 100-blocks, 30-minutes, 12-hours or 7-days'").required(true))
+            .arg(arg!(--from <ADDRESS>"'Wallet address paying, defaults to the mining address'"))
+            .arg(arg!(--times <N>"'Stop after this many payments instead of paying forever'").value_parser(value_parser!(u64)))
+        )
+        .subcommand(Command::new("listschedules").about("list the recurring payments of the running node"))
+        .subcommand(
+            Command::new("cancelschedule")
+            .about("stop a recurring payment of the running node")
+            .arg(arg!(<ID>"'Schedule id, as listschedules prints it'").value_parser(value_parser!(u64)))
+        )
+        .subcommand(
+            Command::new("listtransactions")
+            .about("list the confirmed transactions of one of the running node's wallets")
+            .arg(arg!(<ADDRESS>"'Wallet address'"))
+        )
+        .subcommand(
+            Command::new("walletaudit")
+            .about("warn about reused addresses, dust outputs, long unconfirmed transactions and unbacked keys of the running node's wallets")
+            .arg(arg!(--json "'Print the report as JSON'"))
+        )
+        .subcommand(Command::new("getblockcount").about("print the height of the chain tip"))
+        .subcommand(Command::new("getbestblockhash").about("print the hash of the chain tip"))
+        .subcommand(Command::new("getdifficulty").about("print the current proof of work difficulty"))
+        .subcommand(
+            Command::new("exporthistory")
+            .about("export the confirmed transactions of a wallet as CSV")
+            .arg(arg!(--wallet <ADDRESS>"'Address of the wallet to export'").required(true))
+            .arg(arg!(--out <FILE>"'CSV file to write'").default_value("history.csv"))
+        )
+        .subcommand(
+            Command::new("createinvoice")
+            .about("print a payment URI asking for a payment to ADDRESS, optionally as a QR code")
+            .arg(arg!(<ADDRESS>"'The address to be paid'"))
+            .arg(arg!(--amount <AMOUNT>"'Amount asked for, in coins like 1.5 or in sats like \"150 sats\"'"))
+            .arg(arg!(--label <LABEL>"'Name of the payee'"))
+            .arg(arg!(--message <MESSAGE>"'What the payment is for'"))
+            .arg(arg!(--qr "'Render the URI as a QR code in the terminal'"))
+            .arg(arg!(--png <FILE>"'Also write the QR code to a PNG file'"))
+        )
+        .subcommand(
+            Command::new("showaddress")
+            .about("print an address for receiving payments, optionally as a QR code")
+            .arg(arg!(<ADDRESS>"'The address to show'"))
+            .arg(arg!(--qr "'Render the address as a QR code in the terminal'"))
+            .arg(arg!(--png <FILE>"'Also write the QR code to a PNG file'"))
+        )
+        .subcommand(
+            Command::new("validateaddress")
+            .about("decode an address and report its network, key hash and whether it is ours")
+            .arg(arg!(<ADDRESS>"'The address to validate'"))
+        )
+        .subcommand(
+            Command::new("completions")
+            .about("print a shell completion script")
+            .arg(arg!(<SHELL>"'Shell to generate completions for'").value_parser(value_parser!(Shell)))
+        )
+        .subcommand(
+            Command::new("mangen")
+            .about("write man pages for every command")
+            .hide(true)
+            .arg(arg!(--out <DIR>"'Directory the pages are written to'").default_value("man"))
+        )
+        .subcommand(
+            Command::new("genfixtures")
+            .about("write the encoding and JSON decoding of sample blocks, transactions and P2P messages")
+            .hide(true)
+            .arg(arg!(--out <DIR>"'Directory the fixtures are written to'").default_value("fixtures"))
+        )
+        .subcommand(
+            Command::new("send")
+            .about("send in the blockchain")
+            .arg(arg!(<FROM>"'Source wallet address'"))
+            .arg(arg!([TO]"'Destination wallet address, taken from --uri when left out'"))
+            .arg(arg!([AMOUNT]"'Amount to send, in coins like 1.5 or in sats like \"150 sats\", taken from --uri when left out'"))
+            .arg(arg!(--uri <URI>"'Payment URI like toychain:ADDRESS?amount=1.5&label=Lab, its label is kept in the wallet history'"))
+            .arg(arg!(--fee <FEE>"'Fee paid to the miner, like the amount, defaults to the configured fee'"))
+            .arg(arg!(--"dry-run" "'Build and sign the transaction and print it without mining'"))
+            .arg(arg!(--memo <TEXT>"'Private note kept in the wallet with the transaction, never on chain'"))
+        )
+}
+
+/// wallet_config is `config` with the wallet store named by the --wallet flag
+/// of `matches`, when given
+fn wallet_config(config: &Config, matches: &ArgMatches) -> Config {
+    match matches.get_one::<String>("wallet") {
+        Some(name) => Config { wallet: name.clone(), ..config.clone() },
+        None => config.clone()
+    }
+}
+
+/// write_man_pages renders a page for the binary and one per subcommand
+fn write_man_pages(dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let cmd = command();
+    let name = cmd.get_name().to_string();
+
+    let mut page = Vec::new();
+    Man::new(cmd.clone()).render(&mut page)?;
+    std::fs::write(dir.join(format!("{}.1", name)), page)?;
+
+    for sub in cmd.get_subcommands().filter(|s| !s.is_hide_set()) {
+        let sub_name = format!("{}-{}", name, sub.get_name());
+        let mut page = Vec::new();
+        Man::new(sub.clone()).title(sub_name.clone()).render(&mut page)?;
+        std::fs::write(dir.join(format!("{}.1", sub_name)), page)?;
+    }
+    Ok(())
+}
+
+/// confirm asks the operator before a destructive command, `--yes` skips the question
+fn confirm(question: &str, matches: &ArgMatches) -> Result<bool> {
+    if matches.get_flag("yes") {
+        return Ok(true);
+    }
+
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    let yes = matches!(answer.trim(), "y" | "Y" | "yes");
+    if !yes {
+        println!("Aborted.");
+    }
+    Ok(yes)
+}
@@ -0,0 +1,472 @@
+use serde::{Deserialize, Serialize};
+
+use crate::addrman::TimedAddr;
+use crate::block::Block;
+use crate::blockfilter::BlockFilter;
+use crate::bloom::BloomFilter;
+use crate::error::{BlockchainError, Result};
+use crate::hash::Hash256;
+use crate::spv::{FilteredTx, SpvHeader};
+use crate::transaction::Transaction;
+
+/// CODEC_VERSION is the first byte of every P2P frame, bumped whenever the
+/// encoding of `Message` changes
+pub const CODEC_VERSION: u8 = 5;
+
+/// COMPRESSED_VERSION starts a frame whose payload is compressed with zstd,
+/// sent only to peers whose version announced SERVICE_COMPRESSION
+pub const COMPRESSED_VERSION: u8 = CODEC_VERSION | 0x80;
+
+/// SERVICE_COMPRESSION is the service bit of a node decoding compressed frames
+pub const SERVICE_COMPRESSION: u64 = 1;
+
+/// COMPRESS_MIN_BYTES is the size from which a frame is worth compressing
+pub const COMPRESS_MIN_BYTES: usize = 1024;
+
+/// MAX_PAYLOAD_BYTES bounds the decompressed payload of a frame
+const MAX_PAYLOAD_BYTES: usize = 32 << 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Blockmsg {
+    pub addr_from: String,
+    pub block: Block
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GetBlockmsg {
+    pub addr_from: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GetDatamsg {
+    pub addr_from: String,
+    pub kind: String,
+    pub id: Hash256
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Invmsg {
+    pub addr_from: String,
+    pub kind: String,
+    pub items: Vec<Hash256>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Txmsg {
+    pub addr_from: String,
+    pub transaction: Transaction
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Versionmsg {
+    pub addr_from: String,
+    pub version: i32,
+    /// SERVICE_ bits of what the sending node supports
+    pub services: u64,
+    pub best_height: i32,
+    /// software and build of the sending node, like `/toychain:0.1.0/`
+    pub user_agent: String
+}
+
+/// GetHeadersmsg asks for the headers of the best chain from height `from`
+/// on, answered on the same connection
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GetHeadersmsg {
+    pub addr_from: String,
+    pub from: usize
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Headersmsg {
+    pub addr_from: String,
+    pub best_height: usize,
+    pub headers: Vec<SpvHeader>
+}
+
+/// GetFilteredmsg asks for the transactions of the best chain from height
+/// `from` on that match `filter`, answered on the same connection
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GetFilteredmsg {
+    pub addr_from: String,
+    pub filter: BloomFilter,
+    pub from: usize
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Filteredmsg {
+    pub addr_from: String,
+    /// height of the last block scanned
+    pub to: usize,
+    pub txs: Vec<FilteredTx>
+}
+
+/// GetCFiltersmsg asks for the compact filters of the best chain from height
+/// `from` on, answered on the same connection
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GetCFiltersmsg {
+    pub addr_from: String,
+    pub from: usize
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CFiltersmsg {
+    pub addr_from: String,
+    pub from: usize,
+    /// block hash and filter of each height from `from` on
+    pub filters: Vec<(Hash256, BlockFilter)>
+}
+
+/// GetCFHeadersmsg asks for the filter hashes of the best chain from height
+/// `from` on, answered on the same connection
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GetCFHeadersmsg {
+    pub addr_from: String,
+    pub from: usize
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CFHeadersmsg {
+    pub addr_from: String,
+    pub from: usize,
+    /// hash of the block of the last filter hash
+    pub stop: Hash256,
+    /// filter header of the block below `from`, zero from the genesis
+    pub prev_header: Hash256,
+    pub filter_hashes: Vec<Hash256>
+}
+
+/// Addrmsg announces peer addresses with the last time each was seen, unlike
+/// the `Addr` of older nodes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Addrmsg {
+    pub addr_from: String,
+    pub addrs: Vec<TimedAddr>
+}
+
+/// SendHeadersmsg asks a peer to announce new blocks with a `Headers` message
+/// instead of an `Inv`, so they can be fetched at once
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SendHeadersmsg {
+    pub addr_from: String
+}
+
+/// REJECT_INVALID is the reject code of a block or transaction breaking a
+/// consensus rule
+pub const REJECT_INVALID: u8 = 0x10;
+
+/// REJECT_INSUFFICIENT_FEE is the reject code of a transaction paying too low
+/// a fee rate to stay in the mempool
+pub const REJECT_INSUFFICIENT_FEE: u8 = 0x42;
+
+/// Rejectmsg tells a peer that its `message` carrying `hash` was refused, with
+/// a REJECT_ code and the reason in words
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Rejectmsg {
+    pub addr_from: String,
+    pub message: String,
+    pub hash: Hash256,
+    pub code: u8,
+    pub reason: String
+}
+
+/// Packagemsg relays dependent transactions together, see `package`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Packagemsg {
+    pub addr_from: String,
+    pub transactions: Vec<Transaction>
+}
+
+/// Message is a P2P protocol message, its variant index is the discriminant
+/// on the wire so new variants go at the end
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Message {
+    Addr(Vec<String>),
+    Version(Versionmsg),
+    Tx(Txmsg),
+    GetData(GetDatamsg),
+    GetBlock(GetBlockmsg),
+    Inv(Invmsg),
+    Block(Blockmsg),
+    GetHeaders(GetHeadersmsg),
+    Headers(Headersmsg),
+    GetFiltered(GetFilteredmsg),
+    Filtered(Filteredmsg),
+    GetCFilters(GetCFiltersmsg),
+    CFilters(CFiltersmsg),
+    GetCFHeaders(GetCFHeadersmsg),
+    CFHeaders(CFHeadersmsg),
+    AddrV2(Addrmsg),
+    SendHeaders(SendHeadersmsg),
+    Reject(Rejectmsg),
+    Package(Packagemsg)
+}
+
+/// BLOCK_VARIANT is the discriminant of `Message::Block` on the wire
+const BLOCK_VARIANT: u32 = 6;
+
+impl Message {
+    /// command names the message in logs and traces
+    pub fn command(&self) -> &'static str {
+        match self {
+            Message::Addr(_) => "addr",
+            Message::Version(_) => "version",
+            Message::Tx(_) => "tx",
+            Message::GetData(_) => "getdata",
+            Message::GetBlock(_) => "getblocks",
+            Message::Inv(_) => "inv",
+            Message::Block(_) => "block",
+            Message::GetHeaders(_) => "getheaders",
+            Message::Headers(_) => "headers",
+            Message::GetFiltered(_) => "getfiltered",
+            Message::Filtered(_) => "filtered",
+            Message::GetCFilters(_) => "getcfilters",
+            Message::CFilters(_) => "cfilters",
+            Message::GetCFHeaders(_) => "getcfheaders",
+            Message::CFHeaders(_) => "cfheaders",
+            Message::AddrV2(_) => "addrv2",
+            Message::SendHeaders(_) => "sendheaders",
+            Message::Reject(_) => "reject",
+            Message::Package(_) => "package"
+        }
+    }
+
+    /// addr_from is the address the sender announced, None for a bare addr
+    pub fn addr_from(&self) -> Option<&str> {
+        match self {
+            Message::Addr(_) => None,
+            Message::Version(data) => Some(&data.addr_from),
+            Message::Tx(data) => Some(&data.addr_from),
+            Message::GetData(data) => Some(&data.addr_from),
+            Message::GetBlock(data) => Some(&data.addr_from),
+            Message::Inv(data) => Some(&data.addr_from),
+            Message::Block(data) => Some(&data.addr_from),
+            Message::GetHeaders(data) => Some(&data.addr_from),
+            Message::Headers(data) => Some(&data.addr_from),
+            Message::GetFiltered(data) => Some(&data.addr_from),
+            Message::Filtered(data) => Some(&data.addr_from),
+            Message::GetCFilters(data) => Some(&data.addr_from),
+            Message::CFilters(data) => Some(&data.addr_from),
+            Message::GetCFHeaders(data) => Some(&data.addr_from),
+            Message::CFHeaders(data) => Some(&data.addr_from),
+            Message::AddrV2(data) => Some(&data.addr_from),
+            Message::SendHeaders(data) => Some(&data.addr_from),
+            Message::Reject(data) => Some(&data.addr_from),
+            Message::Package(data) => Some(&data.addr_from)
+        }
+    }
+}
+
+/// MessageCodec frames a message as the codec version followed by the bincode
+/// encoding of `Message`, a little endian u32 discriminant and the payload
+pub struct MessageCodec;
+
+impl MessageCodec {
+    pub fn encode(message: &Message) -> Result<Vec<u8>> {
+        let mut frame = vec![CODEC_VERSION];
+        bincode::serialize_into(&mut frame, message)?;
+        Ok(frame)
+    }
+
+    /// encode_raw_block frames a block message around `block`, the canonical
+    /// encoding of a stored block, giving the frame `encode` would without
+    /// decoding and encoding the block again
+    pub fn encode_raw_block(addr_from: &str, block: &[u8]) -> Result<Vec<u8>> {
+        let mut frame = vec![CODEC_VERSION];
+        bincode::serialize_into(&mut frame, &BLOCK_VARIANT)?;
+        bincode::serialize_into(&mut frame, addr_from)?;
+        frame.extend_from_slice(block);
+        Ok(frame)
+    }
+
+    /// compress turns a frame of `encode` into a compressed one, None when
+    /// that does not make it smaller
+    pub fn compress(frame: &[u8]) -> Result<Option<Vec<u8>>> {
+        match frame.split_first() {
+            Some((&CODEC_VERSION, body)) => {
+                let mut compressed = vec![COMPRESSED_VERSION];
+                compressed.extend_from_slice(&zstd::bulk::compress(body, zstd::DEFAULT_COMPRESSION_LEVEL)?);
+                Ok((compressed.len() < frame.len()).then_some(compressed))
+            },
+            _ => Err(BlockchainError::Network("only an uncompressed frame can be compressed".to_string()))
+        }
+    }
+
+    pub fn decode(frame: &[u8]) -> Result<Message> {
+        Ok(MessageCodec::decode_sized(frame)?.0)
+    }
+
+    /// decode_sized decodes a frame and gives the size it has uncompressed
+    pub fn decode_sized(frame: &[u8]) -> Result<(Message, usize)> {
+        match frame.split_first() {
+            Some((&CODEC_VERSION, body)) => Ok((bincode::deserialize(body)?, frame.len())),
+            Some((&COMPRESSED_VERSION, body)) => {
+                let payload = zstd::bulk::decompress(body, MAX_PAYLOAD_BYTES)
+                    .map_err(|e| BlockchainError::Network(format!("compressed frame not decodable within {} bytes: {}", MAX_PAYLOAD_BYTES, e)))?;
+                Ok((bincode::deserialize(&payload)?, payload.len() + 1))
+            },
+            Some((version, _)) => Err(BlockchainError::Network(format!("unsupported codec version {}", version))),
+            None => Err(BlockchainError::Network("empty message".to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use crate::tx::{OutPoint, TXInput, TXOutput};
+    use crate::Amount;
+
+    fn fixtures() -> Vec<(Message, String)> {
+        let tx = Transaction {
+            id: Hash256::new([0xab; 32]),
+            vin: vec![TXInput {
+                prev_out: OutPoint { txid: Hash256::new([0xcd; 32]), index: 1 },
+                signature: vec![1, 2],
+                pub_key: vec![3]
+            }],
+            vout: vec![TXOutput { value: Amount::from_sat(100), pub_key_hash: vec![4, 5] }]
+        };
+
+        let addr = "0300000000000000613a31";
+        vec![
+            (
+                Message::Addr(vec!["a:1".to_string()]),
+                format!("05{}{}{}", "00000000", "0100000000000000", addr)
+            ),
+            (
+                Message::Version(Versionmsg { addr_from: "a:1".to_string(), version: 1, services: SERVICE_COMPRESSION, best_height: 7, user_agent: "x".to_string() }),
+                format!("05{}{}{}{}{}{}", "01000000", addr, "01000000", "0100000000000000", "07000000", "010000000000000078")
+            ),
+            (
+                Message::Tx(Txmsg { addr_from: "a:1".to_string(), transaction: tx }),
+                format!(
+                    "05{}{}{}{}{}01000000{}{}{}{}{}",
+                    "02000000",
+                    addr,
+                    // id
+                    "ab".repeat(32),
+                    // one input spending cd..cd:1
+                    "0100000000000000",
+                    "cd".repeat(32),
+                    "02000000000000000102",
+                    "010000000000000003",
+                    // one output of 100 sats
+                    "0100000000000000",
+                    "64000000",
+                    "02000000000000000405"
+                )
+            ),
+            (
+                Message::GetData(GetDatamsg { addr_from: "a:1".to_string(), kind: "tx".to_string(), id: Hash256::new([0xff; 32]) }),
+                format!("05{}{}{}{}", "03000000", addr, "02000000000000007478", "ff".repeat(32))
+            ),
+            (
+                Message::GetBlock(GetBlockmsg { addr_from: "a:1".to_string() }),
+                format!("05{}{}", "04000000", addr)
+            ),
+            (
+                Message::Inv(Invmsg { addr_from: "a:1".to_string(), kind: "block".to_string(), items: vec![Hash256::new([0xff; 32])] }),
+                format!("05{}{}{}{}{}", "05000000", addr, "0500000000000000626c6f636b", "0100000000000000", "ff".repeat(32))
+            )
+        ]
+    }
+
+    #[test]
+    fn test_encoding_matches_the_fixtures() -> Result<()> {
+        for (message, fixture) in fixtures() {
+            let frame = MessageCodec::encode(&message)?;
+            assert_eq!(hex::encode(&frame), fixture, "{} changed its encoding", message.command());
+            assert_eq!(MessageCodec::encode(&MessageCodec::decode(&frame)?)?, frame);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_raw_blocks_frame_like_decoded_ones() -> Result<()> {
+        let coinbase = Transaction::new_coinbase(crate::wallet::hash_to_address(&[7; 20]), "raw".to_string())?;
+        let block = Block::from_solution(vec![coinbase], Hash256::ZERO, 0, crate::target::POW_LIMIT_BITS, 1, 0)?;
+        let message = Message::Block(Blockmsg { addr_from: "a:1".to_string(), block: block.clone() });
+        assert_eq!(MessageCodec::encode_raw_block("a:1", &bincode::serialize(&block)?)?, MessageCodec::encode(&message)?);
+        Ok(())
+    }
+
+    /// random_message draws a message of random content, any variant but Block
+    fn random_message(rng: &mut StdRng) -> Message {
+        let text = |rng: &mut StdRng| (0..rng.gen_range(0..12)).map(|_| rng.gen_range('a'..='z')).collect::<String>();
+        let hash = |rng: &mut StdRng| Hash256::new(rng.gen());
+        match rng.gen_range(0..6) {
+            0 => Message::Addr((0..rng.gen_range(0..4)).map(|_| text(rng)).collect()),
+            1 => Message::Version(Versionmsg { addr_from: text(rng), version: rng.gen(), services: rng.gen(), best_height: rng.gen(), user_agent: text(rng) }),
+            2 => Message::Tx(Txmsg {
+                addr_from: text(rng),
+                transaction: Transaction {
+                    id: hash(rng),
+                    vin: (0..rng.gen_range(0..3))
+                        .map(|_| TXInput {
+                            prev_out: OutPoint { txid: hash(rng), index: rng.gen() },
+                            signature: (0..rng.gen_range(0..70)).map(|_| rng.gen()).collect(),
+                            pub_key: (0..rng.gen_range(0..40)).map(|_| rng.gen()).collect()
+                        })
+                        .collect(),
+                    vout: (0..rng.gen_range(0..3))
+                        .map(|_| TXOutput { value: Amount::from_sat(rng.gen()), pub_key_hash: (0..rng.gen_range(0..25)).map(|_| rng.gen()).collect() })
+                        .collect()
+                }
+            }),
+            3 => Message::GetData(GetDatamsg { addr_from: text(rng), kind: text(rng), id: hash(rng) }),
+            4 => Message::GetBlock(GetBlockmsg { addr_from: text(rng) }),
+            _ => Message::Inv(Invmsg { addr_from: text(rng), kind: text(rng), items: (0..rng.gen_range(0..4)).map(|_| hash(rng)).collect() })
+        }
+    }
+
+    #[test]
+    fn test_random_frames_round_trip_and_never_panic() -> Result<()> {
+        let mut rng = StdRng::seed_from_u64(681);
+        for _ in 0..500 {
+            let frame = MessageCodec::encode(&random_message(&mut rng))?;
+            assert_eq!(MessageCodec::encode(&MessageCodec::decode(&frame)?)?, frame);
+
+            // cut, flipped or padded frames fail to decode or decode to a frame that round trips
+            let mut mutated = frame.clone();
+            match rng.gen_range(0..3) {
+                0 => mutated.truncate(rng.gen_range(0..frame.len())),
+                1 => mutated[rng.gen_range(1..frame.len())] = rng.gen(),
+                _ => mutated.extend((0..rng.gen_range(1..16)).map(|_| rng.gen::<u8>()))
+            }
+            if let Ok(Message::Tx(mut msg)) = MessageCodec::decode(&mutated) {
+                // inputs with keys and signatures of any length are refused, not a panic
+                let prev_txs = msg.transaction.vin.iter().map(|vin| {
+                    let prev = Transaction { id: vin.prev_out.txid, vin: Vec::new(), vout: vec![TXOutput { value: Amount::ZERO, pub_key_hash: Vec::new() }; 2] };
+                    (prev.id, prev)
+                });
+                let _ = msg.transaction.verify(prev_txs.collect());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_compressed_frames_decode_like_plain_ones() -> Result<()> {
+        let message = Message::Inv(Invmsg { addr_from: "a:1".to_string(), kind: "block".to_string(), items: vec![Hash256::new([7; 32]); 100] });
+        let frame = MessageCodec::encode(&message)?;
+        let compressed = MessageCodec::compress(&frame)?.expect("repeated hashes compress");
+        assert!(compressed.len() < frame.len());
+        let (decoded, size) = MessageCodec::decode_sized(&compressed)?;
+        assert_eq!(MessageCodec::encode(&decoded)?, frame);
+        assert_eq!(size, frame.len());
+        assert!(MessageCodec::compress(&compressed).is_err());
+        assert_eq!(MessageCodec::compress(&MessageCodec::encode(&Message::Addr(Vec::new()))?)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_versions_are_rejected() {
+        let mut frame = MessageCodec::encode(&Message::Addr(Vec::new())).unwrap();
+        frame[0] = CODEC_VERSION + 1;
+        assert!(MessageCodec::decode(&frame).is_err());
+        assert!(MessageCodec::decode(&[]).is_err());
+    }
+}
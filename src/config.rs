@@ -0,0 +1,408 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::amount::Amount;
+use crate::dialer::Net;
+use crate::error::{BlockchainError, Result};
+use crate::netaddr;
+
+/// DEFAULT_CONFIG_FILE is read from the working directory when it exists
+pub const DEFAULT_CONFIG_FILE: &str = "blockchain.toml";
+
+/// DEFAULT_MAX_MEMPOOL caps the mempool at 50 MB
+pub const DEFAULT_MAX_MEMPOOL: usize = 50_000_000;
+
+/// DEFAULT_FAUCET_AMOUNT is what the faucet pays per request, 10 coins
+pub const DEFAULT_FAUCET_AMOUNT: Amount = Amount::from_sat(10 * Amount::COIN.to_sat());
+
+/// DEFAULT_FAUCET_COOLDOWN is how long an address waits between faucet payments, in seconds
+pub const DEFAULT_FAUCET_COOLDOWN: u64 = 3600;
+
+/// DEFAULT_PUBLIC_API_RATE is how many public API requests an IP may make per minute
+pub const DEFAULT_PUBLIC_API_RATE: u32 = 60;
+
+const ENV_PREFIX: &str = "BLOCKCHAIN_";
+
+/// Network selects which chain the node runs on, each network keeps its own data directory
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    Main,
+    Test,
+    Regtest
+}
+
+impl FromStr for Network {
+    type Err = BlockchainError;
+
+    fn from_str(s: &str) -> Result<Network> {
+        match s {
+            "main" => Ok(Network::Main),
+            "test" => Ok(Network::Test),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(BlockchainError::Config(format!("unknown network '{}', expected main, test or regtest", s)))
+        }
+    }
+}
+
+/// Config holds the node settings, layered as
+/// defaults < config file < BLOCKCHAIN_* env vars < command line flags
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    pub port: String,
+    /// addresses the P2P server listens on, like `0.0.0.0:3000` or `[::]:3000`,
+    /// `localhost` on `port` when empty
+    pub bind: Vec<String>,
+    /// address peers reach this node at, sent in version messages, derived from
+    /// the first bind address when empty
+    pub external_address: String,
+    pub datadir: String,
+    pub network: Network,
+    /// name of the wallet store, the default one when empty, so a cold
+    /// instance can keep its keys apart in `wallets-<name>`
+    pub wallet: String,
+    pub peers: Vec<String>,
+    /// SOCKS5 proxy every outbound peer connection goes through, like
+    /// `socks5://127.0.0.1:9050` for Tor, empty to connect directly
+    pub proxy: String,
+    /// networks the node connects to peers on, every one when empty
+    pub only_net: Vec<Net>,
+    pub mining_address: String,
+    /// threads grinding the proof of work, 0 for one per CPU core
+    pub mine_threads: usize,
+    /// run the mining threads at the lowest scheduling priority
+    pub mine_nice: bool,
+    /// transactions the mempool miner waits for before sealing a block, so blocks
+    /// are not mined one transaction at a time, `generate` never waits
+    pub mine_min_txs: usize,
+    /// seconds the mempool miner waits for `mine_min_txs` before mining what it has
+    pub mine_max_wait: u64,
+    /// short text, like a pool tag, the miner adds to the data of its coinbases
+    pub coinbase_msg: String,
+    /// fee paid by transactions created with `send`, in sats in the config
+    /// file and as an amount like `--fee`, `1.5` or `150 sats`, in FEE
+    pub fee: Amount,
+    /// memory the mempool may use, in bytes, the lowest fee rate transactions
+    /// are evicted beyond it
+    pub max_mempool: usize,
+    /// port of the JSON-RPC server, empty to disable it
+    pub rpc_port: String,
+    /// RPC credentials, a random cookie is written to the data directory when unset
+    pub rpc_user: String,
+    pub rpc_password: String,
+    /// port of the read-only REST API, empty to disable it
+    pub rest_port: String,
+    /// port of the block explorer web UI, empty to disable it, served only by
+    /// builds with the `explorer` feature
+    pub explorer_port: String,
+    /// port of the WebSocket notification stream, empty to disable it
+    pub ws_port: String,
+    /// port of the gRPC API, empty to disable it, authenticated like the JSON-RPC server
+    pub grpc_port: String,
+    /// ZeroMQ endpoints publishing raw blocks and transactions, like
+    /// `tcp://127.0.0.1:28332`, empty to disable them
+    pub zmq_pub_raw_block: String,
+    pub zmq_pub_raw_tx: String,
+    /// port of the work server handing out jobs to external workers, empty to disable it
+    pub work_port: String,
+    /// append the blocks connected to and disconnected from the best chain to
+    /// events.log in the network directory, for external indexers
+    pub event_log: bool,
+    /// file recording the inbound P2P frames for `replay`, empty to disable it
+    pub p2p_record: String,
+    /// hand out coins on request, only on the test networks
+    pub faucet: bool,
+    /// wallet the faucet pays from, the mining address when empty
+    pub faucet_address: String,
+    /// paid per faucet request, in sats
+    pub faucet_amount: Amount,
+    /// seconds before the faucet pays the same address again
+    pub faucet_cooldown: u64,
+    /// serve only the read-only REST and JSON-RPC endpoints, on every interface
+    /// and without credentials, and never load a wallet
+    pub public_api: bool,
+    /// requests per minute each IP may make to the public API
+    pub public_api_rate: u32
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            port: String::from("3000"),
+            bind: Vec::new(),
+            external_address: String::new(),
+            datadir: String::from("data"),
+            network: Network::Main,
+            wallet: String::new(),
+            peers: Vec::new(),
+            proxy: String::new(),
+            only_net: Vec::new(),
+            mining_address: String::new(),
+            mine_threads: 0,
+            mine_nice: false,
+            mine_min_txs: 1,
+            mine_max_wait: 0,
+            coinbase_msg: String::new(),
+            fee: Amount::ZERO,
+            max_mempool: DEFAULT_MAX_MEMPOOL,
+            rpc_port: String::new(),
+            rpc_user: String::new(),
+            rpc_password: String::new(),
+            rest_port: String::new(),
+            explorer_port: String::new(),
+            ws_port: String::new(),
+            grpc_port: String::new(),
+            zmq_pub_raw_block: String::new(),
+            zmq_pub_raw_tx: String::new(),
+            work_port: String::new(),
+            event_log: false,
+            p2p_record: String::new(),
+            faucet: false,
+            faucet_address: String::new(),
+            faucet_amount: DEFAULT_FAUCET_AMOUNT,
+            faucet_cooldown: DEFAULT_FAUCET_COOLDOWN,
+            public_api: false,
+            public_api_rate: DEFAULT_PUBLIC_API_RATE
+        }
+    }
+}
+
+impl Config {
+    /// Load builds the config from the defaults, the config file and the environment,
+    /// an explicit `path` must exist while the default file is optional
+    pub fn load(path: Option<&str>) -> Result<Config> {
+        let mut config = match path {
+            Some(p) => Config::from_file(Path::new(p))?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Config::from_file(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => Config::default()
+        };
+
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    /// from_file reads a TOML config, missing keys keep their defaults
+    pub fn from_file(path: &Path) -> Result<Config> {
+        info!("loading config from {}", path.display());
+        let content = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&content)?)
+    }
+
+    fn apply_env(&mut self) -> Result<()> {
+        if let Some(v) = env_var("PORT") {
+            self.port = v;
+        }
+        if let Some(v) = env_var("BIND") {
+            self.bind = v.split(',').map(|b| b.trim().to_string()).filter(|b| !b.is_empty()).collect();
+        }
+        if let Some(v) = env_var("EXTERNAL_ADDRESS") {
+            self.external_address = v;
+        }
+        if let Some(v) = env_var("DATADIR") {
+            self.datadir = v;
+        }
+        if let Some(v) = env_var("NETWORK") {
+            self.network = v.parse()?;
+        }
+        if let Some(v) = env_var("WALLET") {
+            self.wallet = v;
+        }
+        if let Some(v) = env_var("PEERS") {
+            self.peers = v.split(',').map(|p| p.trim().to_string()).filter(|p| !p.is_empty()).collect();
+        }
+        if let Some(v) = env_var("PROXY") {
+            self.proxy = v;
+        }
+        if let Some(v) = env_var("ONLYNET") {
+            self.only_net = v.split(',').map(|n| n.trim()).filter(|n| !n.is_empty()).map(str::parse).collect::<Result<_>>()?;
+        }
+        if let Some(v) = env_var("MINING_ADDRESS") {
+            self.mining_address = v;
+        }
+        if let Some(v) = env_var("MINE_THREADS") {
+            self.mine_threads = v.parse()?;
+        }
+        if let Some(v) = env_var("MINE_NICE") {
+            self.mine_nice = matches!(v.as_str(), "1" | "true");
+        }
+        if let Some(v) = env_var("MINE_MIN_TXS") {
+            self.mine_min_txs = v.parse()?;
+        }
+        if let Some(v) = env_var("MINE_MAX_WAIT") {
+            self.mine_max_wait = v.parse()?;
+        }
+        if let Some(v) = env_var("COINBASE_MSG") {
+            self.coinbase_msg = v;
+        }
+        if let Some(v) = env_var("FEE") {
+            self.fee = v.parse()?;
+        }
+        if let Some(v) = env_var("MAX_MEMPOOL") {
+            self.max_mempool = v.parse()?;
+        }
+        if let Some(v) = env_var("RPC_PORT") {
+            self.rpc_port = v;
+        }
+        if let Some(v) = env_var("RPC_USER") {
+            self.rpc_user = v;
+        }
+        if let Some(v) = env_var("RPC_PASSWORD") {
+            self.rpc_password = v;
+        }
+        if let Some(v) = env_var("REST_PORT") {
+            self.rest_port = v;
+        }
+        if let Some(v) = env_var("EXPLORER_PORT") {
+            self.explorer_port = v;
+        }
+        if let Some(v) = env_var("WS_PORT") {
+            self.ws_port = v;
+        }
+        if let Some(v) = env_var("GRPC_PORT") {
+            self.grpc_port = v;
+        }
+        if let Some(v) = env_var("ZMQ_PUB_RAW_BLOCK") {
+            self.zmq_pub_raw_block = v;
+        }
+        if let Some(v) = env_var("ZMQ_PUB_RAW_TX") {
+            self.zmq_pub_raw_tx = v;
+        }
+        if let Some(v) = env_var("WORK_PORT") {
+            self.work_port = v;
+        }
+        if let Some(v) = env_var("EVENT_LOG") {
+            self.event_log = matches!(v.as_str(), "1" | "true");
+        }
+        if let Some(v) = env_var("P2P_RECORD") {
+            self.p2p_record = v;
+        }
+        if let Some(v) = env_var("FAUCET") {
+            self.faucet = matches!(v.as_str(), "1" | "true");
+        }
+        if let Some(v) = env_var("FAUCET_ADDRESS") {
+            self.faucet_address = v;
+        }
+        if let Some(v) = env_var("FAUCET_AMOUNT") {
+            self.faucet_amount = Amount::from_sat(v.parse()?);
+        }
+        if let Some(v) = env_var("FAUCET_COOLDOWN") {
+            self.faucet_cooldown = v.parse()?;
+        }
+        if let Some(v) = env_var("PUBLIC_API") {
+            self.public_api = matches!(v.as_str(), "1" | "true");
+        }
+        if let Some(v) = env_var("PUBLIC_API_RATE") {
+            self.public_api_rate = v.parse()?;
+        }
+        Ok(())
+    }
+
+    /// listen_addresses are the addresses the P2P server binds
+    pub fn listen_addresses(&self) -> Vec<String> {
+        if self.bind.is_empty() {
+            vec![format!("localhost:{}", self.port)]
+        } else {
+            self.bind.clone()
+        }
+    }
+
+    /// advertised_address is the address the node gives its peers: the external
+    /// address, else the first bind address, `localhost` on its port when it
+    /// names every interface
+    pub fn advertised_address(&self) -> Result<String> {
+        if !self.external_address.is_empty() {
+            return netaddr::normalize(&self.external_address);
+        }
+        let first = netaddr::normalize(&self.listen_addresses()[0])?;
+        match first.parse::<std::net::SocketAddr>() {
+            Ok(addr) if addr.ip().is_unspecified() => Ok(format!("localhost:{}", addr.port())),
+            _ => Ok(first)
+        }
+    }
+
+    /// network_dir is the data directory of the selected network
+    pub fn network_dir(&self) -> PathBuf {
+        let dir = PathBuf::from(&self.datadir);
+        match self.network {
+            Network::Main => dir,
+            Network::Test => dir.join("test"),
+            Network::Regtest => dir.join("regtest")
+        }
+    }
+
+    /// blocks_path is the block database of the selected network
+    pub fn blocks_path(&self) -> PathBuf {
+        self.network_dir().join("blocks")
+    }
+
+    /// wallets_path is the wallet store of the selected network and wallet name
+    pub fn wallets_path(&self) -> PathBuf {
+        match self.wallet.as_str() {
+            "" => self.network_dir().join("wallets"),
+            name => self.network_dir().join(format!("wallets-{}", name))
+        }
+    }
+
+    /// cookie_path holds the generated RPC credentials when none are configured
+    pub fn cookie_path(&self) -> PathBuf {
+        self.network_dir().join(".cookie")
+    }
+
+    /// control_socket_path is the Unix socket a running node answers operator commands on
+    pub fn control_socket_path(&self) -> PathBuf {
+        self.network_dir().join("control.sock")
+    }
+
+    /// pid_path holds the pid of the node running on the selected network
+    pub fn pid_path(&self) -> PathBuf {
+        self.network_dir().join("node.pid")
+    }
+
+    /// lock_path is locked by the running node so a second one refuses to start
+    pub fn lock_path(&self) -> PathBuf {
+        self.network_dir().join(".lock")
+    }
+
+    /// event_log_path is the append-only log of the changes of the best chain
+    pub fn event_log_path(&self) -> PathBuf {
+        self.network_dir().join("events.log")
+    }
+
+    /// mempool_path keeps the unconfirmed transactions of a stopped node
+    pub fn mempool_path(&self) -> PathBuf {
+        self.network_dir().join("mempool.dat")
+    }
+
+    /// spv_path is the store of the light client, headers and wallet transactions
+    pub fn spv_path(&self) -> PathBuf {
+        self.network_dir().join("spv")
+    }
+
+    /// log_dir receives the rotating log files of a node started with `--daemon`
+    pub fn log_dir(&self) -> PathBuf {
+        self.network_dir().join("logs")
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(format!("{}{}", ENV_PREFIX, name)).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partial_file_keeps_defaults() {
+        let config: Config = toml::from_str("port = \"4000\"\nnetwork = \"regtest\"\n").unwrap();
+
+        assert_eq!(config.port, "4000");
+        assert_eq!(config.network, Network::Regtest);
+        assert_eq!(config.datadir, "data");
+        assert_eq!(config.blocks_path(), PathBuf::from("data/regtest/blocks"));
+    }
+}
@@ -0,0 +1,161 @@
+//! UTXO consolidation. A wallet paid many small amounts ends up with as many
+//! outputs, which every later payment has to spend, so `consolidate` merges the
+//! smallest ones of an address into a single output to a fresh address while
+//! fees are low.
+
+use serde::Serialize;
+
+use crate::amount::Amount;
+use crate::error::{BlockchainError, Result};
+use crate::fees::fee_for;
+use crate::hash::Hash256;
+use crate::transaction::Transaction;
+use crate::tx::{OutPoint, TXInput, TXOutput};
+use crate::utxoset::{UTXOSet, UnspentOutput};
+use crate::wallet::{hash_pub_key, Wallets};
+
+/// COINBASE_MATURITY is how many confirmations a coinbase output needs before
+/// it is consolidated, a reorg would take a younger one away with its block
+pub const COINBASE_MATURITY: usize = 100;
+
+/// SIGNATURE_LEN is the size of the ed25519 signature of every input, counted
+/// in the size before the transaction is signed
+const SIGNATURE_LEN: usize = 64;
+
+/// Consolidation is how `consolidate` picks the outputs to merge
+#[derive(Debug, Clone, PartialEq)]
+pub struct Consolidation {
+    /// fewest outputs worth a transaction
+    pub min_inputs: usize,
+    /// most outputs merged by one transaction
+    pub max_inputs: usize,
+    /// fee paid per serialized byte, in sats
+    pub fee_rate: f64,
+    /// smallest output worth creating, a merge leaving less is refused
+    pub dust: Amount,
+    /// confirmations a coinbase output needs before it is merged
+    pub coinbase_maturity: usize
+}
+
+impl Default for Consolidation {
+    fn default() -> Consolidation {
+        Consolidation {
+            min_inputs: 2,
+            max_inputs: 50,
+            fee_rate: 0.0,
+            dust: Amount::from_sat(1),
+            coinbase_maturity: COINBASE_MATURITY
+        }
+    }
+}
+
+/// ConsolidationSummary describes a consolidation transaction
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ConsolidationSummary {
+    pub txid: Hash256,
+    pub inputs: usize,
+    #[serde(rename = "inputtotal")]
+    pub input_total: Amount,
+    pub fee: Amount,
+    pub output: Amount,
+    pub address: String,
+    pub size: usize
+}
+
+/// consolidate signs a transaction spending the smallest outputs of `from` that
+/// `usable` accepts to a single output paying `to`. It leaves out the coinbase
+/// outputs not mature yet and the outputs worth less than the fee of spending
+/// them, and needs at least `min_inputs` outputs to merge
+pub fn consolidate(wallets: &Wallets, from: &str, to: &str, utxo: &UTXOSet, options: &Consolidation, usable: impl Fn(&OutPoint) -> bool) -> Result<(Transaction, ConsolidationSummary)> {
+    let wallet = wallets.get_wallet(from).ok_or_else(|| BlockchainError::Wallet(format!("'from' wallet {} not found", from)))?;
+    let mut pub_key_hash = wallet.public_key.clone();
+    hash_pub_key(&mut pub_key_hash);
+
+    let input_fee = fee_for(input_size(&wallet.public_key)?, options.fee_rate);
+    let best_height = utxo.blockchain.get_best_height()? as usize;
+    let mut candidates = Vec::new();
+    for out in utxo.list_unspent(Some(&pub_key_hash))? {
+        if !usable(&out.outpoint) || out.value <= input_fee || !mature(utxo, &out, best_height, options.coinbase_maturity)? {
+            continue;
+        }
+        candidates.push(out);
+    }
+    candidates.sort_by_key(|out| (out.value, out.outpoint.txid, out.outpoint.index));
+    candidates.truncate(options.max_inputs);
+    if candidates.len() < options.min_inputs.max(1) {
+        return Err(BlockchainError::Wallet(format!("{} has {} spendable outputs worth merging, at least {} are needed", from, candidates.len(), options.min_inputs.max(1))));
+    }
+
+    let input_total = Amount::sum(candidates.iter().map(|out| out.value))?;
+    let mut tx = Transaction {
+        id: Hash256::ZERO,
+        vin: candidates
+            .iter()
+            .map(|out| TXInput { prev_out: out.outpoint, signature: vec![0; SIGNATURE_LEN], pub_key: wallet.public_key.clone() })
+            .collect(),
+        vout: vec![TXOutput::new(input_total, to.to_string())?]
+    };
+    let size = bincode::serialize(&tx)?.len();
+    let fee = fee_for(size, options.fee_rate);
+    let output = input_total.checked_sub(fee).filter(|output| *output >= options.dust).ok_or_else(|| {
+        BlockchainError::Wallet(format!("merging {} outputs worth {} for a fee of {} leaves less than the dust floor of {}", candidates.len(), input_total, fee, options.dust))
+    })?;
+
+    tx.vout[0].value = output;
+    for vin in &mut tx.vin {
+        vin.signature.clear();
+    }
+    tx.id = tx.hash()?;
+    utxo.blockchain.sign_transaction(&mut tx, &wallet.secret_key)?;
+
+    let summary = ConsolidationSummary { txid: tx.id, inputs: tx.vin.len(), input_total, fee, output, address: to.to_string(), size };
+    Ok((tx, summary))
+}
+
+/// mature tells whether `out` may be spent, a coinbase output needing
+/// `maturity` confirmations
+fn mature(utxo: &UTXOSet, out: &UnspentOutput, best_height: usize, maturity: usize) -> Result<bool> {
+    if best_height + 1 - out.height >= maturity {
+        return Ok(true);
+    }
+    match utxo.blockchain.get_indexed_transaction(&out.outpoint.txid)? {
+        Some((tx, _)) => Ok(!tx.is_coinbase()),
+        None => Err(BlockchainError::TxNotFound(out.outpoint.txid.to_string()))
+    }
+}
+
+/// input_size is the serialized size of a signed input spending from `public_key`
+pub(crate) fn input_size(public_key: &[u8]) -> Result<usize> {
+    let input = TXInput { prev_out: OutPoint::NULL, signature: vec![0; SIGNATURE_LEN], pub_key: public_key.to_vec() };
+    Ok(bincode::serialize(&input)?.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ChainFixture;
+
+    #[test]
+    fn test_merges_the_smallest_mature_outputs() -> Result<()> {
+        let fixture = ChainFixture::restore(4)?;
+        let utxo = UTXOSet { blockchain: fixture.blockchain()? };
+        let mut wallets = Wallets::new(&fixture.config())?;
+        let miner = fixture.miner().to_string();
+        let to = wallets.create_wallet();
+
+        // the five coinbases are too young until the maturity is lowered
+        assert!(consolidate(&wallets, &miner, &to, &utxo, &Consolidation::default(), |_| true).is_err());
+        let options = Consolidation { max_inputs: 3, fee_rate: 0.01, coinbase_maturity: 2, ..Consolidation::default() };
+        let (tx, summary) = consolidate(&wallets, &miner, &to, &utxo, &options, |_| true)?;
+        assert_eq!((summary.inputs, summary.input_total), (3, Amount::COIN.try_add(Amount::COIN)?.try_add(Amount::COIN)?));
+        assert_eq!(summary.fee, fee_for(bincode::serialize(&tx)?.len(), 0.01));
+        assert_eq!((tx.vout.len(), tx.vout[0].value), (1, summary.output));
+        assert!(utxo.blockchain.verify_transaction(&mut tx.clone())?);
+
+        let block = utxo.blockchain.mine_block(vec![Transaction::new_coinbase(miner.clone(), String::new())?, tx])?;
+        utxo.connect_block(&block)?;
+        let costly = Consolidation { fee_rate: 100.0, coinbase_maturity: 0, ..Consolidation::default() };
+        assert!(consolidate(&wallets, &miner, &to, &utxo, &costly, |_| true).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::process::exit;
+use std::thread;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{error, info, warn};
+
+use crate::config::Config;
+use crate::daemon;
+use crate::error::{BlockchainError, Result};
+use crate::server::Server;
+
+/// ControlRequest is an operator command sent over the control socket
+#[derive(Serialize, Deserialize, Debug)]
+struct ControlRequest {
+    command: String,
+    args: Vec<String>
+}
+
+/// start serves operator commands of `server` on a Unix socket in the data
+/// directory, readable by the node's user only
+pub fn start(server: Server) -> Result<()> {
+    let path = server.config().control_socket_path();
+    if path.exists() {
+        if UnixStream::connect(&path).is_ok() {
+            return Err(BlockchainError::Network(format!("another node is already listening on {}", path.display())));
+        }
+        warn!("removing stale control socket {}", path.display());
+        fs::remove_file(&path)?;
+    }
+    fs::create_dir_all(server.config().network_dir())?;
+
+    let listener = UnixListener::bind(&path)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+    info!("control socket at {}", path.display());
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let server = server.clone();
+            match stream {
+                Ok(stream) => {
+                    thread::spawn(move || {
+                        if let Err(e) = serve_connection(&server, stream) {
+                            error!("failed to answer control request: {}", e);
+                        }
+                    });
+                },
+                Err(e) => error!("failed to accept control connection: {}", e)
+            }
+        }
+    });
+    Ok(())
+}
+
+fn serve_connection(server: &Server, mut stream: UnixStream) -> Result<()> {
+    let mut request = Vec::new();
+    stream.read_to_end(&mut request)?;
+    let request: ControlRequest = serde_json::from_slice(&request)?;
+    info!("receive control command: {}", request.command);
+
+    if request.command == "stopnode" {
+        stream.write_all(&serde_json::to_vec(&json!({ "result": "node stopping" }))?)?;
+        stream.shutdown(Shutdown::Both)?;
+        info!("stopping on operator request");
+        if let Err(e) = server.save_mempool() {
+            error!("failed to save the mempool: {}", e);
+        }
+        server.utxo_set().blockchain.flush()?;
+        let _ = fs::remove_file(server.config().control_socket_path());
+        daemon::remove_pid_file(server.config());
+        exit(0);
+    }
+
+    let reply = match server.handle_control(&request.command, &request.args) {
+        Ok(result) => json!({ "result": result }),
+        Err(e) => json!({ "error": e.to_string() })
+    };
+    stream.write_all(&serde_json::to_vec(&reply)?)?;
+    Ok(())
+}
+
+/// request sends an operator command to the node running on the data directory
+/// of `config` and waits for its JSON reply
+pub fn request(config: &Config, command: &str, args: &[&str]) -> Result<Value> {
+    let path = config.control_socket_path();
+    let mut stream = UnixStream::connect(&path)
+        .map_err(|e| BlockchainError::Network(format!("no node is running on {} ({})", path.display(), e)))?;
+
+    let request = ControlRequest {
+        command: command.to_string(),
+        args: args.iter().map(|a| a.to_string()).collect()
+    };
+    stream.write_all(&serde_json::to_vec(&request)?)?;
+    stream.shutdown(Shutdown::Write)?;
+
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply)?;
+    let mut reply: Value = serde_json::from_slice(&reply)?;
+    match reply["error"].as_str() {
+        Some(e) => Err(BlockchainError::Network(e.to_string())),
+        None => Ok(reply["result"].take())
+    }
+}
@@ -0,0 +1,124 @@
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::control;
+use crate::error::{BlockchainError, Result};
+
+/// STARTUP_CHECK is how long `--daemon` watches the detached node for an early exit
+const STARTUP_CHECK: Duration = Duration::from_millis(500);
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// lock_datadir takes the exclusive lock of the network directory, held until
+/// the returned file is dropped, failing when another node holds it
+pub fn lock_datadir(config: &Config) -> Result<File> {
+    fs::create_dir_all(config.network_dir())?;
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(config.lock_path())?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(BlockchainError::Config(format!(
+            "another node is already running on {}",
+            config.network_dir().display()
+        ))),
+        Err(TryLockError::Error(e)) => Err(e.into())
+    }
+}
+
+/// write_pid_file records the pid of this process for `stop`
+pub fn write_pid_file(config: &Config) -> Result<()> {
+    fs::write(config.pid_path(), format!("{}\n", std::process::id()))?;
+    Ok(())
+}
+
+/// remove_pid_file deletes the pid file when it still names this process
+pub fn remove_pid_file(config: &Config) {
+    if read_pid(config).ok() == Some(std::process::id()) {
+        let _ = fs::remove_file(config.pid_path());
+    }
+}
+
+fn read_pid(config: &Config) -> Result<u32> {
+    Ok(fs::read_to_string(config.pid_path())?.trim().parse()?)
+}
+
+/// spawn_detached starts this command line again without `--daemon`, in its own
+/// process group with no terminal, logging to rotating files in `log_dir`,
+/// and returns the pid of the detached node
+pub fn spawn_detached(log_dir: &Path) -> Result<u32> {
+    let mut args = Vec::new();
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "--daemon" => {},
+            // replaced by the resolved `log_dir` below
+            "--log-dir" => {
+                argv.next();
+            },
+            _ if arg.starts_with("--log-dir=") => {},
+            _ => args.push(arg)
+        }
+    }
+    let mut child = Command::new(std::env::current_exe()?)
+        .args(&args)
+        .arg("--log-dir")
+        .arg(log_dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .process_group(0)
+        .spawn()?;
+
+    thread::sleep(STARTUP_CHECK);
+    if let Some(status) = child.try_wait()? {
+        return Err(BlockchainError::Config(format!(
+            "the node exited at startup ({}), see the logs in {}",
+            status,
+            log_dir.display()
+        )));
+    }
+    Ok(child.id())
+}
+
+/// stop asks the node named by the pid file to stop, through the control socket
+/// when it answers and with SIGTERM otherwise, and waits for it to exit
+pub fn stop(config: &Config) -> Result<u32> {
+    let pid = read_pid(config).map_err(|e| {
+        BlockchainError::Config(format!("cannot read the pid file {}: {}", config.pid_path().display(), e))
+    })?;
+    if !is_running(pid) {
+        warn!("removing stale pid file of {}", pid);
+        fs::remove_file(config.pid_path())?;
+        return Err(BlockchainError::Config(format!("no node is running with pid {}", pid)));
+    }
+
+    if let Err(e) = control::request(config, "stopnode", &[]) {
+        info!("control socket unavailable ({}), sending SIGTERM to {}", e, pid);
+        Command::new("kill").arg("-TERM").arg(pid.to_string()).status()?;
+    }
+
+    let started = Instant::now();
+    while is_running(pid) {
+        if started.elapsed() > STOP_TIMEOUT {
+            return Err(BlockchainError::Config(format!("node {} did not stop within {:?}", pid, STOP_TIMEOUT)));
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let _ = fs::remove_file(config.pid_path());
+    Ok(pid)
+}
+
+fn is_running(pid: u32) -> bool {
+    Command::new("kill")
+        .arg("-0")
+        .arg(pid.to_string())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
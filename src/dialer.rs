@@ -0,0 +1,250 @@
+//! Outbound connections to peers, made directly or through a SOCKS5 proxy like
+//! the one Tor listens on, and limited to the networks `onlynet` names:
+//!
+//! ```text
+//! blockchain_project startnode --proxy socks5://127.0.0.1:9050 --onlynet onion --external-address abcdef.onion:3000
+//! ```
+//!
+//! Through a proxy the peer address is handed over as a name, so the proxy
+//! resolves it and `.onion` peers work. Without one an onion peer cannot be
+//! reached.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::error::{BlockchainError, Result};
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_NO_AUTH: u8 = 0;
+const SOCKS_CONNECT: u8 = 1;
+const SOCKS_IPV4: u8 = 1;
+const SOCKS_DOMAIN: u8 = 3;
+const SOCKS_IPV6: u8 = 4;
+
+/// Net is a network a peer address belongs to, a host name other than an
+/// onion one counting as ipv4
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Net {
+    Ipv4,
+    Ipv6,
+    Onion
+}
+
+impl Net {
+    /// of is the network of the `host:port` or `[ipv6]:port` address `peer`
+    pub fn of(peer: &str) -> Net {
+        let host = peer.rsplit_once(':').map_or(peer, |(host, _)| host);
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(_)) => Net::Ipv6,
+            Ok(IpAddr::V4(_)) => Net::Ipv4,
+            Err(_) if host.to_ascii_lowercase().ends_with(".onion") => Net::Onion,
+            Err(_) => Net::Ipv4
+        }
+    }
+}
+
+impl fmt::Display for Net {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Net::Ipv4 => write!(f, "ipv4"),
+            Net::Ipv6 => write!(f, "ipv6"),
+            Net::Onion => write!(f, "onion")
+        }
+    }
+}
+
+impl FromStr for Net {
+    type Err = BlockchainError;
+
+    fn from_str(s: &str) -> Result<Net> {
+        match s {
+            "ipv4" => Ok(Net::Ipv4),
+            "ipv6" => Ok(Net::Ipv6),
+            "onion" => Ok(Net::Onion),
+            _ => Err(BlockchainError::Config(format!("unknown network '{}', expected ipv4, ipv6 or onion", s)))
+        }
+    }
+}
+
+/// Dialer opens the connections to peers
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Dialer {
+    /// `host:port` of the SOCKS5 proxy, None to connect directly
+    proxy: Option<String>,
+    /// networks peers may be on, every one when empty
+    only_net: Vec<Net>
+}
+
+impl Dialer {
+    /// new is the dialer of `config`, its proxy given as `socks5://host:port`
+    pub fn new(config: &Config) -> Result<Dialer> {
+        let proxy = match config.proxy.as_str() {
+            "" => None,
+            url => match url.strip_prefix("socks5://").or_else(|| url.strip_prefix("socks5h://")) {
+                Some(proxy) if !proxy.is_empty() => Some(proxy.trim_end_matches('/').to_string()),
+                _ => return Err(BlockchainError::Config(format!("proxy '{}' is not a socks5://host:port URL", url)))
+            }
+        };
+        if proxy.is_none() && config.only_net == [Net::Onion] {
+            return Err(BlockchainError::Config("onion peers are only reachable through a proxy, set --proxy".to_string()));
+        }
+        Ok(Dialer { proxy, only_net: config.only_net.clone() })
+    }
+
+    /// allows tells whether `peer` is on a network the node may connect to
+    pub fn allows(&self, peer: &str) -> bool {
+        self.only_net.is_empty() || self.only_net.contains(&Net::of(peer))
+    }
+
+    /// connect opens a connection to `peer`, waiting at most `timeout` for
+    /// each address tried and for each step of the proxy handshake
+    pub fn connect(&self, peer: &str, timeout: Duration) -> io::Result<TcpStream> {
+        if !self.allows(peer) {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("{} is on the {} network, outside onlynet", peer, Net::of(peer))));
+        }
+        match &self.proxy {
+            Some(proxy) => {
+                let mut stream = connect_direct(proxy, timeout)?;
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                socks5_connect(&mut stream, peer)?;
+                stream.set_read_timeout(None)?;
+                Ok(stream)
+            },
+            None if Net::of(peer) == Net::Onion => Err(io::Error::new(io::ErrorKind::Unsupported, format!("{} is only reachable through a proxy", peer))),
+            None => connect_direct(peer, timeout)
+        }
+    }
+}
+
+/// connect_direct tries each address `peer` resolves to, waiting at most
+/// `timeout` for each
+fn connect_direct(peer: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last = io::Error::new(io::ErrorKind::NotFound, format!("{} resolves to no address", peer));
+    for addr in peer.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last = e
+        }
+    }
+    Err(last)
+}
+
+/// socks5_connect asks the proxy on `stream` for a connection to `peer`,
+/// without authentication, as RFC 1928 describes
+fn socks5_connect(stream: &mut TcpStream, peer: &str) -> io::Result<()> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let (host, port) = peer.rsplit_once(':').ok_or_else(|| invalid(format!("{} has no port", peer)))?;
+    let port: u16 = port.parse().map_err(|_| invalid(format!("{} has no valid port", peer)))?;
+
+    stream.write_all(&[SOCKS_VERSION, 1, SOCKS_NO_AUTH])?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice)?;
+    if choice != [SOCKS_VERSION, SOCKS_NO_AUTH] {
+        return Err(invalid("the proxy wants an authentication this node does not do".to_string()));
+    }
+
+    let mut request = vec![SOCKS_VERSION, SOCKS_CONNECT, 0];
+    match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(SOCKS_IPV4);
+            request.extend(ip.octets());
+        },
+        Ok(IpAddr::V6(ip)) => {
+            request.push(SOCKS_IPV6);
+            request.extend(ip.octets());
+        },
+        Err(_) => {
+            let name = u8::try_from(host.len()).map_err(|_| invalid(format!("host name {} is too long", host)))?;
+            request.extend([SOCKS_DOMAIN, name]);
+            request.extend(host.as_bytes());
+        }
+    }
+    request.extend(port.to_be_bytes());
+    stream.write_all(&request)?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[1] != 0 {
+        return Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("the proxy could not reach {}: {}", peer, socks5_error(reply[1]))));
+    }
+    // the address the proxy bound for the connection, not needed
+    let bound = match reply[3] {
+        SOCKS_IPV4 => 4,
+        SOCKS_IPV6 => 16,
+        SOCKS_DOMAIN => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        },
+        atyp => return Err(invalid(format!("the proxy replied with address type {}", atyp)))
+    };
+    stream.read_exact(&mut vec![0u8; bound + 2])?;
+    Ok(())
+}
+
+fn socks5_error(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "not allowed by the ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_onion_peers_go_through_the_proxy() -> Result<()> {
+        let proxy = TcpListener::bind("127.0.0.1:0")?;
+        let config = Config { proxy: format!("socks5://{}", proxy.local_addr()?), only_net: vec![Net::Onion], ..Config::default() };
+        let dialer = Dialer::new(&config)?;
+        assert!(Dialer::new(&Config { proxy: String::new(), ..config.clone() }).is_err());
+        assert!(!dialer.allows("127.0.0.1:3000") && dialer.allows("abcdef.onion:3000"));
+
+        // a proxy accepting one connection, replying with a bound IPv4 address
+        let served = thread::spawn(move || -> io::Result<Vec<u8>> {
+            let (mut stream, _) = proxy.accept()?;
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting)?;
+            stream.write_all(&[SOCKS_VERSION, SOCKS_NO_AUTH])?;
+            let mut request = vec![0u8; 5 + "abcdef.onion".len() + 2];
+            stream.read_exact(&mut request)?;
+            stream.write_all(&[SOCKS_VERSION, 0, 0, SOCKS_IPV4, 127, 0, 0, 1, 0, 0])?;
+            let mut frame = Vec::new();
+            stream.read_to_end(&mut frame)?;
+            request.extend(frame);
+            Ok(request)
+        });
+
+        let mut stream = dialer.connect("abcdef.onion:3000", Duration::from_secs(5))?;
+        stream.write_all(b"hi")?;
+        drop(stream);
+        let seen = served.join().expect("the proxy thread")?;
+        let mut expected = vec![SOCKS_VERSION, SOCKS_CONNECT, 0, SOCKS_DOMAIN, 12];
+        expected.extend(b"abcdef.onion");
+        expected.extend(3000u16.to_be_bytes());
+        expected.extend(b"hi");
+        assert_eq!(seen, expected);
+        assert!(dialer.connect("127.0.0.1:3000", Duration::from_secs(1)).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,232 @@
+//! Double spend alerts. A transaction relayed while another one already spends
+//! one of its outputs, in the mempool or in a recent block, is refused like
+//! before, but the node keeps it in an alert naming both spends, logs it and
+//! publishes `Event::DoubleSpendDetected`, which shows how little an
+//! unconfirmed payment is worth.
+
+use std::collections::VecDeque;
+use std::time::SystemTime;
+
+use serde::Serialize;
+
+use crate::blockchain::Blockchain;
+use crate::error::Result;
+use crate::hash::Hash256;
+use crate::transaction::Transaction;
+use crate::tx::OutPoint;
+
+/// MAX_DOUBLE_SPENDS is how many alerts are kept, the oldest are dropped first
+pub const MAX_DOUBLE_SPENDS: usize = 100;
+
+/// RECENT_BLOCKS is how deep in the chain a mined spend is looked for
+pub const RECENT_BLOCKS: usize = 6;
+
+/// DoubleSpend is a transaction spending an output another one spent first
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DoubleSpend {
+    pub outpoint: OutPoint,
+    /// the spend the node saw first
+    pub first: Hash256,
+    /// block holding `first`, None while it is in the mempool
+    pub block: Option<Hash256>,
+    pub second: Hash256,
+    /// the second transaction as hex encoded bincode
+    pub tx: String,
+    /// when the second spend arrived, in milliseconds since the epoch
+    pub time: u128
+}
+
+impl DoubleSpend {
+    pub fn new(outpoint: OutPoint, first: Hash256, block: Option<Hash256>, second: &Transaction) -> Result<DoubleSpend> {
+        Ok(DoubleSpend {
+            outpoint,
+            first,
+            block,
+            second: second.id,
+            tx: hex::encode(bincode::serialize(second)?),
+            time: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis()
+        })
+    }
+}
+
+/// DoubleSpends holds the latest alerts, oldest first
+#[derive(Debug, Default)]
+pub struct DoubleSpends {
+    alerts: VecDeque<DoubleSpend>
+}
+
+impl DoubleSpends {
+    /// add keeps `spend` and tells whether it is new, a transaction relayed
+    /// again by another peer raises no second alert
+    pub fn add(&mut self, spend: DoubleSpend) -> bool {
+        if self.alerts.iter().any(|a| a.outpoint == spend.outpoint && a.second == spend.second) {
+            return false;
+        }
+        if self.alerts.len() == MAX_DOUBLE_SPENDS {
+            self.alerts.pop_front();
+        }
+        self.alerts.push_back(spend);
+        true
+    }
+
+    pub fn alerts(&self) -> impl Iterator<Item = &DoubleSpend> {
+        self.alerts.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.alerts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.alerts.is_empty()
+    }
+}
+
+/// mined_spender finds the transaction of the last RECENT_BLOCKS blocks of `bc`
+/// spending `outpoint`, with its block
+pub fn mined_spender(bc: &Blockchain, outpoint: &OutPoint) -> Result<Option<(Hash256, Hash256)>> {
+    for block in bc.iter().take(RECENT_BLOCKS) {
+        for tx in block.get_transactions() {
+            if tx.vin.iter().any(|vin| vin.prev_out == *outpoint) {
+                return Ok(Some((tx.id, block.get_hash())));
+            }
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::amount::Amount;
+    use crate::codec::{Message, MessageCodec, Txmsg, REJECT_INVALID};
+    use crate::events::Event;
+    use crate::testing::ChainFixture;
+    use crate::tx::{TXInput, TXOutput};
+    use crate::utxoset::UTXOSet;
+    use crate::wallet::{hash_to_address, Wallets};
+
+    #[test]
+    fn test_conflicting_relays_raise_alerts() -> Result<()> {
+        let fixture = ChainFixture::restore(2)?;
+        let miner = fixture.miner().to_string();
+        let other = hash_to_address(&[7; 20]);
+        let (first, second, third) = {
+            let utxo = UTXOSet { blockchain: fixture.blockchain()? };
+            let wallets = Wallets::new(&fixture.config())?;
+            let pay = |to: &str, sats| Transaction::new_UTXO(&wallets, &miner, to, Amount::from_sat(sats), Amount::ZERO, &utxo);
+            (pay(&miner, 5)?, pay(&other, 7)?, pay(&other, 9)?)
+        };
+        let outpoint = first.vin[0].prev_out;
+        assert_eq!((second.vin[0].prev_out, third.vin[0].prev_out), (outpoint, outpoint));
+
+        let node = fixture.node_builder().build()?;
+        let events = node.subscribe();
+        let relay = |tx: &Transaction| {
+            let frame = MessageCodec::encode(&Message::Tx(Txmsg { addr_from: "localhost:3001".to_string(), transaction: tx.clone() }))?;
+            node.server().handle_frame(&frame)
+        };
+        relay(&first)?;
+        assert!(relay(&second).is_err());
+        assert!(relay(&second).is_err());
+        assert!(events.try_iter().any(|e| e == Event::DoubleSpendDetected { outpoint, first: first.id, second: second.id }));
+
+        let mined = node.mine_blocks(1)?;
+        assert!(relay(&third).is_err());
+        let alerts = node.server().double_spends();
+        assert_eq!(alerts.iter().map(|a| (a.first, a.block, a.second)).collect::<Vec<_>>(), vec![(first.id, None, second.id), (first.id, Some(mined[0]), third.id)]);
+        node.shutdown()
+    }
+
+    #[test]
+    fn test_an_unsigned_relay_claims_no_output() -> Result<()> {
+        let fixture = ChainFixture::restore(2)?;
+        let miner = fixture.miner().to_string();
+        let real = {
+            let utxo = UTXOSet { blockchain: fixture.blockchain()? };
+            let wallets = Wallets::new(&fixture.config())?;
+            Transaction::new_UTXO(&wallets, &miner, &miner, Amount::from_sat(5), Amount::ZERO, &utxo)?
+        };
+        // a peer knowing only the public key redirects the owner's output
+        let mut forged = Transaction {
+            id: Hash256::ZERO,
+            vin: vec![TXInput { signature: Vec::new(), ..real.vin[0].clone() }],
+            vout: vec![TXOutput::new(real.vout[0].value, hash_to_address(&[7; 20]))?]
+        };
+        forged.id = forged.hash()?;
+
+        let node = fixture.node_builder().build()?;
+        let relay = |tx: &Transaction| {
+            let frame = MessageCodec::encode(&Message::Tx(Txmsg { addr_from: "localhost:3001".to_string(), transaction: tx.clone() }))?;
+            node.server().handle_frame(&frame)
+        };
+        assert!(relay(&forged).is_err());
+        assert!(!node.server().get_mempool().contains(&forged.id));
+        relay(&real)?;
+        assert!(node.server().get_mempool().contains(&real.id));
+        node.shutdown()
+    }
+
+    #[test]
+    fn test_a_forged_conflict_raises_no_alert() -> Result<()> {
+        let fixture = ChainFixture::restore(2)?;
+        let miner = fixture.miner().to_string();
+        let real = {
+            let utxo = UTXOSet { blockchain: fixture.blockchain()? };
+            let wallets = Wallets::new(&fixture.config())?;
+            Transaction::new_UTXO(&wallets, &miner, &miner, Amount::from_sat(5), Amount::ZERO, &utxo)?
+        };
+        let mut forged = Transaction {
+            id: Hash256::ZERO,
+            vin: vec![TXInput { signature: Vec::new(), ..real.vin[0].clone() }],
+            vout: vec![TXOutput::new(real.vout[0].value, hash_to_address(&[7; 20]))?]
+        };
+        forged.id = forged.hash()?;
+
+        let node = fixture.node_builder().build()?;
+        let frame = MessageCodec::encode(&Message::Tx(Txmsg { addr_from: "localhost:3001".to_string(), transaction: real }))?;
+        node.server().handle_frame(&frame)?;
+        let frame = MessageCodec::encode(&Message::Tx(Txmsg { addr_from: "localhost:3001".to_string(), transaction: forged.clone() }))?;
+        assert!(node.server().handle_frame(&frame).is_err());
+        node.mine_blocks(1)?;
+        assert!(node.server().submit_package(vec![forged]).is_err());
+        assert!(node.server().double_spends().is_empty());
+        node.shutdown()
+    }
+
+    #[test]
+    fn test_a_refused_relay_is_answered_with_a_reject() -> Result<()> {
+        let fixture = ChainFixture::restore(2)?;
+        let miner = fixture.miner().to_string();
+        let (first, second) = {
+            let utxo = UTXOSet { blockchain: fixture.blockchain()? };
+            let wallets = Wallets::new(&fixture.config())?;
+            let pay = |sats| Transaction::new_UTXO(&wallets, &miner, &miner, Amount::from_sat(sats), Amount::ZERO, &utxo);
+            (pay(5)?, pay(7)?)
+        };
+        let peer = TcpListener::bind("127.0.0.1:0")?;
+        let addr_from = peer.local_addr()?.to_string();
+
+        let node = fixture.node_builder().build()?;
+        let relay = |tx: &Transaction| {
+            let frame = MessageCodec::encode(&Message::Tx(Txmsg { addr_from: addr_from.clone(), transaction: tx.clone() }))?;
+            node.server().handle_frame(&frame)
+        };
+        relay(&first)?;
+        assert!(relay(&second).is_err());
+
+        let mut frame = Vec::new();
+        peer.accept()?.0.read_to_end(&mut frame)?;
+        match MessageCodec::decode(&frame)? {
+            Message::Reject(reject) => {
+                assert_eq!((reject.message.as_str(), reject.hash, reject.code), ("tx", second.id, REJECT_INVALID));
+                assert!(reject.reason.contains(&first.id.to_string()));
+            },
+            other => panic!("expected a reject, got {:?}", other)
+        }
+        node.shutdown()
+    }
+}
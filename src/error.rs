@@ -1 +1,112 @@
-pub type Result<T> = std::result::Result<T, failure::Error>;
+use thiserror::Error;
+
+use crate::amount::Amount;
+
+/// BlockchainError is every failure the crate reports, wrapped library errors
+/// stay reachable through `source()`
+#[derive(Error, Debug)]
+pub enum BlockchainError {
+    #[error("storage error: {0}")]
+    Storage(#[from] sled::Error),
+
+    #[error("serialization error: {0}")]
+    Serialization(#[from] bincode::Error),
+
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("compression error: {0}")]
+    Compression(#[from] snap::Error),
+
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("invalid config: {0}")]
+    Config(String),
+
+    #[error("invalid config file: {0}")]
+    ConfigFile(#[from] toml::de::Error),
+
+    #[error("invalid number: {0}")]
+    ParseInt(#[from] std::num::ParseIntError),
+
+    #[error("invalid utf-8 data: {0}")]
+    Utf8(#[from] std::string::FromUtf8Error),
+
+    #[error("clock error: {0}")]
+    Clock(#[from] std::time::SystemTimeError),
+
+    #[error("csv error: {0}")]
+    Csv(#[from] csv::Error),
+
+    #[error("qr code error: {0}")]
+    Qr(#[from] qrcode::types::QrError),
+
+    #[error("png error: {0}")]
+    Png(#[from] png::EncodingError),
+
+    /// a block or transaction breaks the chain rules
+    #[error("consensus error: {0}")]
+    Consensus(String),
+
+    /// the mempool is at its memory cap and the transaction pays too little to stay
+    #[error("mempool full: {0}")]
+    MempoolFull(String),
+
+    #[error("not enough balance: {available} available, {needed} needed")]
+    InsufficientFunds { available: Amount, needed: Amount },
+
+    #[error("invalid amount: {0}")]
+    InvalidAmount(String),
+
+    #[error("invalid hash {0}")]
+    InvalidHash(String),
+
+    #[error("block database schema {0} is no longer supported, create the chain again")]
+    UnsupportedSchema(u32),
+
+    #[error("transaction {0} not found")]
+    TxNotFound(String),
+
+    #[error("block {0} not found")]
+    BlockNotFound(String),
+
+    #[error("no blockchain in {0}, create one first")]
+    NoChain(String),
+
+    /// an index or record disagrees with the data it describes
+    #[error("corrupt database: {0}")]
+    Corrupt(String),
+
+    #[error("network error: {0}")]
+    Network(String),
+
+    #[error("wallet error: {0}")]
+    Wallet(String),
+
+    #[error("invalid address {0}")]
+    InvalidAddress(String),
+
+    /// a caller asked again before its cooldown ended
+    #[error("rate limited, retry in {0} seconds")]
+    RateLimited(u64),
+
+    #[error("invalid proof: {0}")]
+    InvalidProof(String)
+}
+
+pub type Result<T> = std::result::Result<T, BlockchainError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn test_wrapped_errors_keep_their_source() {
+        let err: BlockchainError = std::io::Error::new(std::io::ErrorKind::NotFound, "no such file").into();
+
+        assert!(matches!(err, BlockchainError::Io(_)));
+        assert_eq!(err.source().unwrap().to_string(), "no such file");
+    }
+}
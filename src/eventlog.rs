@@ -0,0 +1,226 @@
+//! An append-only log of the changes of the best chain, for external indexers.
+//!
+//! Every line of `events.log` in the network directory is a JSON record with a
+//! sequence number, one per block connected to or disconnected from the best
+//! chain, carrying the block as hex encoded bincode like `getblock` with
+//! verbosity 0. Applying the records in order rebuilds the chain, and a
+//! consumer resumes after the last sequence number it stored:
+//!
+//! ```text
+//! blockchain_project tail-events --since 41 --follow
+//! ```
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::block::BlockHeader;
+use crate::blockchain::Blockchain;
+use crate::error::Result;
+use crate::hash::Hash256;
+
+/// FOLLOW_POLL is how often `tail --follow` looks for new records
+const FOLLOW_POLL: Duration = Duration::from_millis(500);
+
+/// RecordKind tells whether a block joined or left the best chain
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordKind {
+    BlockConnected,
+    BlockDisconnected
+}
+
+/// Record is one line of the event log
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub seq: u64,
+    #[serde(rename = "type")]
+    pub kind: RecordKind,
+    pub hash: Hash256,
+    pub height: usize,
+    #[serde(rename = "previousblockhash")]
+    pub prev_block_hash: Hash256,
+    /// the block as hex encoded bincode
+    pub block: String
+}
+
+/// EventLog appends the blocks connected to and disconnected from the best
+/// chain to a file. It records what moved the chain since its last record when
+/// it starts, so nothing is missed while the node is down, and then follows
+/// the chain from a background thread
+#[derive(Debug, Clone)]
+pub struct EventLog {
+    bc: Blockchain,
+    state: Arc<Mutex<LogState>>
+}
+
+#[derive(Debug)]
+struct LogState {
+    file: File,
+    /// sequence number of the last record
+    seq: u64,
+    /// the tip of the chain once every record is applied, None when empty
+    tip: Option<Hash256>
+}
+
+impl EventLog {
+    /// start opens the log at `path`, brings it up to the chain tip and follows
+    /// the chain events
+    pub fn start(bc: &Blockchain, path: &Path) -> Result<EventLog> {
+        let events = bc.events().subscribe();
+        let log = EventLog::open(bc, path)?;
+        log.sync()?;
+
+        let follower = log.clone();
+        thread::spawn(move || {
+            for event in events {
+                if let Err(e) = follower.sync() {
+                    error!("event log failed on {:?}: {}", event, e);
+                }
+            }
+        });
+        Ok(log)
+    }
+
+    /// open reads the last record of the log at `path`, cutting off a record
+    /// left incomplete by a crash
+    fn open(bc: &Blockchain, path: &Path) -> Result<EventLog> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        let mut complete = 0;
+        let mut last = None;
+        let mut reader = BufReader::new(&mut file);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 || !line.ends_with('\n') {
+                break;
+            }
+            complete += read as u64;
+            last = Some(line.clone());
+        }
+        if complete < file.metadata()?.len() {
+            warn!("dropping an incomplete record at the end of {}", path.display());
+            file.set_len(complete)?;
+        }
+
+        let (seq, tip) = match last {
+            Some(line) => {
+                let record: Record = serde_json::from_str(&line)?;
+                let tip = match record.kind {
+                    RecordKind::BlockConnected => Some(record.hash),
+                    RecordKind::BlockDisconnected => Some(record.prev_block_hash)
+                };
+                (record.seq, tip)
+            },
+            None => (0, None)
+        };
+        info!("event log at {} holds {} records", path.display(), seq);
+
+        Ok(EventLog {
+            bc: bc.clone(),
+            state: Arc::new(Mutex::new(LogState { file, seq, tip }))
+        })
+    }
+
+    /// sync appends the blocks that left and joined the best chain since the
+    /// last record
+    pub fn sync(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let (disconnected, connected) = self.bc.path_from(state.tip)?;
+        for header in &disconnected {
+            self.append(&mut state, RecordKind::BlockDisconnected, header)?;
+        }
+        for header in &connected {
+            self.append(&mut state, RecordKind::BlockConnected, header)?;
+        }
+        Ok(())
+    }
+
+    /// append writes the record of one block and waits for it to reach the disk
+    fn append(&self, state: &mut LogState, kind: RecordKind, header: &BlockHeader) -> Result<()> {
+        let record = Record {
+            seq: state.seq + 1,
+            kind,
+            hash: header.hash,
+            height: header.height,
+            prev_block_hash: header.prev_block_hash,
+            block: hex::encode(self.bc.get_raw_block(&header.hash)?)
+        };
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+        state.file.write_all(line.as_bytes())?;
+        state.file.sync_data()?;
+
+        state.seq = record.seq;
+        state.tip = match kind {
+            RecordKind::BlockConnected => Some(header.hash),
+            RecordKind::BlockDisconnected => Some(header.prev_block_hash)
+        };
+        Ok(())
+    }
+}
+
+/// tail writes the records of the log at `path` after sequence number `since`
+/// to `out`, one per line. With `follow` it keeps waiting for new records
+pub fn tail(path: &Path, since: u64, follow: bool, out: &mut impl Write) -> Result<()> {
+    let mut file = File::open(path)?;
+    let mut offset = 0;
+    let mut pending = String::new();
+    loop {
+        file.seek(SeekFrom::Start(offset))?;
+        let mut chunk = String::new();
+        offset += file.read_to_string(&mut chunk)? as u64;
+        pending.push_str(&chunk);
+
+        // only whole lines are records, the rest is still being written
+        while let Some(end) = pending.find('\n') {
+            let line: String = pending.drain(..=end).collect();
+            let record: Record = serde_json::from_str(&line)?;
+            if record.seq > since {
+                out.write_all(line.as_bytes())?;
+            }
+        }
+        out.flush()?;
+
+        if !follow {
+            return Ok(());
+        }
+        thread::sleep(FOLLOW_POLL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ChainFixture;
+
+    #[test]
+    fn test_log_resumes_after_an_incomplete_record() -> Result<()> {
+        let fixture = ChainFixture::restore(3)?;
+        let bc = fixture.blockchain()?;
+        let path = fixture.config().event_log_path();
+        EventLog::open(&bc, &path)?.sync()?;
+
+        let mut out = Vec::new();
+        tail(&path, 2, false, &mut out)?;
+        let records: Vec<Record> = out.lines().map(|line| Ok(serde_json::from_str(&line?)?)).collect::<Result<_>>()?;
+        assert_eq!(records.iter().map(|r| (r.seq, r.height)).collect::<Vec<_>>(), vec![(3, 2), (4, 3)]);
+        assert_eq!(records[1].hash, fixture.tip());
+        assert_eq!(hex::decode(&records[1].block).unwrap(), bc.get_raw_block(&fixture.tip())?);
+
+        OpenOptions::new().append(true).open(&path)?.write_all(b"{\"seq\":5,")?;
+        let log = EventLog::open(&bc, &path)?;
+        log.sync()?;
+        let mut out = Vec::new();
+        tail(&path, 0, false, &mut out)?;
+        assert_eq!(out.lines().count(), 4);
+        Ok(())
+    }
+}
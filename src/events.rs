@@ -0,0 +1,64 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::hash::Hash256;
+use crate::tx::OutPoint;
+
+/// Event is something that happened to the chain or the node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// the block became part of the best chain
+    BlockConnected { hash: Hash256, height: usize },
+    /// the block left the best chain because a longer branch replaced it
+    BlockDisconnected { hash: Hash256, height: usize },
+    /// the transaction entered the mempool
+    TxAccepted { txid: Hash256 },
+    /// a peer was added to the known nodes
+    PeerConnected { addr: String },
+    /// a relayed transaction spends `outpoint`, which `first` spent already
+    DoubleSpendDetected { outpoint: OutPoint, first: Hash256, second: Hash256 }
+}
+
+/// EventBus fans events out to every subscriber, clones share the same subscribers
+#[derive(Debug, Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Sender<Event>>>>
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        EventBus::default()
+    }
+
+    /// subscribe returns a receiver of every event published from now on,
+    /// dropping it unsubscribes
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (tx, rx) = channel();
+        self.subscribers.lock().unwrap_or_else(PoisonError::into_inner).push(tx);
+        rx
+    }
+
+    pub fn publish(&self, event: Event) {
+        self.subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|tx| tx.send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dropped_subscribers_are_removed() {
+        let bus = EventBus::new();
+        let kept = bus.subscribe();
+        drop(bus.subscribe());
+
+        bus.publish(Event::TxAccepted { txid: Hash256::new([0xaa; 32]) });
+
+        assert_eq!(kept.try_recv().unwrap(), Event::TxAccepted { txid: Hash256::new([0xaa; 32]) });
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,52 @@
+use std::thread;
+
+use tiny_http::{Header, Method, Request, Response};
+use tracing::{error, info};
+
+use crate::error::{BlockchainError, Result};
+use crate::rest;
+use crate::server::Server;
+
+/// INDEX_HTML is the whole explorer, a page rendering the REST API answers
+/// it fetches under /api
+const INDEX_HTML: &str = include_str!("explorer.html");
+
+/// API_PREFIX is where the explorer serves the REST routes, so the page reads
+/// them from its own origin
+const API_PREFIX: &str = "/api";
+
+/// start serves the block explorer of `server` on the configured explorer port
+/// from a background thread
+pub fn start(server: Server) -> Result<()> {
+    let addr = format!("127.0.0.1:{}", server.config().explorer_port);
+    let http = tiny_http::Server::http(&addr).map_err(|e| BlockchainError::Network(format!("cannot bind explorer to {}: {}", addr, e)))?;
+    info!("block explorer listening on http://{}/", addr);
+
+    thread::spawn(move || {
+        for request in http.incoming_requests() {
+            if let Err(e) = handle_request(&server, request) {
+                error!("failed to answer explorer request: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle_request(server: &Server, request: Request) -> Result<()> {
+    if *request.method() != Method::Get {
+        return Ok(request.respond(Response::from_string("only GET is supported").with_status_code(405))?);
+    }
+
+    let url = request.url().to_string();
+    let (status, content_type, body) = match url.strip_prefix(API_PREFIX) {
+        Some(route) => {
+            let (status, body) = rest::answer(server, route);
+            (status, "application/json", body.to_string())
+        },
+        None if url == "/" || url.starts_with("/?") => (200, "text/html; charset=utf-8", INDEX_HTML.to_string()),
+        None => (404, "text/plain", format!("no page at {}", url))
+    };
+
+    let header = Header::from_bytes("Content-Type", content_type).unwrap();
+    Ok(request.respond(Response::from_string(body).with_status_code(status).with_header(header))?)
+}
@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use tracing::info;
+
+use crate::address;
+use crate::amount::Amount;
+use crate::config::{Config, Network};
+use crate::error::{BlockchainError, Result};
+use crate::hash::Hash256;
+use crate::server::Server;
+use crate::transaction::Transaction;
+use crate::wallet::Wallets;
+
+/// Faucet hands out coins of a node wallet on a test network, paying each
+/// address at most once per cooldown
+#[derive(Clone)]
+pub struct Faucet {
+    wallets: Arc<Wallets>,
+    address: String,
+    amount: Amount,
+    cooldown: Duration,
+    /// when each address was last paid, held while a payment is built so two
+    /// requests never pick the same outputs
+    paid: Arc<Mutex<HashMap<String, Instant>>>
+}
+
+impl Faucet {
+    /// new sets up the faucet of `config`, paying from the faucet address or
+    /// else the mining address, which must be a wallet of the node
+    pub fn new(config: &Config) -> Result<Faucet> {
+        if config.network == Network::Main {
+            return Err(BlockchainError::Config("the faucet only runs on the test networks".to_string()));
+        }
+        let address = if config.faucet_address.is_empty() { &config.mining_address } else { &config.faucet_address };
+        let wallets = Wallets::new(config)?;
+        if wallets.get_wallet(address).is_none() {
+            return Err(BlockchainError::Config(format!("the faucet pays from '{}', which is not a wallet of this node", address)));
+        }
+        info!("faucet pays {} per request from {}", config.faucet_amount, address);
+
+        Ok(Faucet {
+            wallets: Arc::new(wallets),
+            address: address.clone(),
+            amount: config.faucet_amount,
+            cooldown: Duration::from_secs(config.faucet_cooldown),
+            paid: Arc::new(Mutex::new(HashMap::new()))
+        })
+    }
+
+    pub fn amount(&self) -> Amount {
+        self.amount
+    }
+
+    /// pay sends the faucet amount to `to` through `server` and returns the
+    /// txid, unless `to` was paid less than a cooldown ago
+    pub fn pay(&self, server: &Server, to: &str) -> Result<Hash256> {
+        address::decode(to)?;
+        let mut paid = self.paid.lock().unwrap_or_else(PoisonError::into_inner);
+        paid.retain(|_, at| at.elapsed() < self.cooldown);
+        if let Some(at) = paid.get(to) {
+            let left = self.cooldown.saturating_sub(at.elapsed());
+            return Err(BlockchainError::RateLimited(left.as_secs().max(1)));
+        }
+
+        // the payments still in the mempool spend outputs the chain shows unspent
+        let mempool = server.get_mempool();
+        let tx = Transaction::new_utxo_where(&self.wallets, &self.address, to, self.amount, server.config().fee, server.utxo_set(), |outpoint| {
+            mempool.spender(outpoint).is_none()
+        })?;
+        let txid = tx.id;
+        server.submit_transaction(tx)?;
+
+        paid.insert(to.to_string(), Instant::now());
+        info!("faucet paid {} to {} in {}", self.amount, to, txid);
+        Ok(txid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::NodeBuilder;
+    use crate::testing::ChainFixture;
+    use crate::wallet::hash_to_address;
+
+    #[test]
+    fn test_faucet_pays_each_address_once_per_cooldown() -> Result<()> {
+        let fixture = ChainFixture::restore(3)?;
+        let mut config = fixture.config();
+        config.faucet = true;
+        config.faucet_amount = "2".parse()?;
+        let node = NodeBuilder::new().config(config).mine_to(fixture.miner()).listen(false).build()?;
+        let server = node.server();
+        let faucet = server.faucet().expect("the faucet is enabled");
+
+        let student = hash_to_address(&[1; 20]);
+        let txid = faucet.pay(server, &student)?;
+        assert_eq!(node.get_transaction(&txid)?.id, txid);
+        assert_eq!(node.balance(&student)?, "2".parse()?);
+
+        assert!(matches!(faucet.pay(server, &student), Err(BlockchainError::RateLimited(_))));
+        assert!(faucet.pay(server, "not an address").is_err());
+        faucet.pay(server, &hash_to_address(&[2; 20]))?;
+        assert_eq!(node.balance(&student)?, "2".parse()?);
+        node.shutdown()
+    }
+}
@@ -0,0 +1,195 @@
+//! Fee rate estimates from the recent chain. The node notes the fee rate and
+//! the height of every transaction entering its mempool and, once a block
+//! confirms it, how many blocks it waited. The estimate for a target is the
+//! lowest fee rate at and above which SUCCESS_PERCENT of the transactions of
+//! the last WINDOW_BLOCKS blocks confirmed within the target, counting the ones
+//! still waiting past it as misses.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+
+use serde::Serialize;
+use tracing::error;
+
+use crate::amount::Amount;
+use crate::blockchain::Blockchain;
+use crate::error::{BlockchainError, Result};
+use crate::events::Event;
+use crate::hash::Hash256;
+
+/// TARGETS are the confirmation targets, in blocks, estimates are kept for
+pub const TARGETS: [usize; 3] = [1, 3, 6];
+
+/// DEFAULT_TARGET is the target of the fee a wallet pays when given no rate
+pub const DEFAULT_TARGET: usize = 6;
+
+/// WINDOW_BLOCKS is how many recent blocks the estimates are made from
+pub const WINDOW_BLOCKS: usize = 100;
+
+/// SUCCESS_PERCENT is how many of the transactions paying a fee rate must
+/// confirm within the target for the rate to be estimated
+pub const SUCCESS_PERCENT: usize = 85;
+
+/// MIN_SAMPLES is how many transactions an estimate is made from at least
+pub const MIN_SAMPLES: usize = 3;
+
+/// FeeEstimate is the fee rate, in sats per byte, of a transaction meant to
+/// confirm within `blocks`, None while the node saw too few transactions
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct FeeEstimate {
+    pub feerate: Option<f64>,
+    pub blocks: usize
+}
+
+/// Confirmed is a transaction a block confirmed `blocks` after it entered
+/// the mempool
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Confirmed {
+    fee_rate: f64,
+    height: usize,
+    blocks: usize
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    /// fee rate and tip height at entry of the transactions not confirmed yet
+    pending: HashMap<Hash256, (f64, usize)>,
+    /// transactions confirmed within the window, oldest first
+    confirmed: VecDeque<Confirmed>,
+    tip: usize
+}
+
+impl Stats {
+    /// connect notes the transactions of the block at `height` that were
+    /// waiting and forgets what fell out of the window
+    fn connect(&mut self, height: usize, txids: &[Hash256]) {
+        for txid in txids {
+            if let Some((fee_rate, entered)) = self.pending.remove(txid) {
+                self.confirmed.push_back(Confirmed { fee_rate, height, blocks: height.saturating_sub(entered).max(1) });
+            }
+        }
+        self.tip = height;
+        let Some(start) = height.checked_sub(WINDOW_BLOCKS) else {
+            return;
+        };
+        while self.confirmed.front().is_some_and(|c| c.height <= start) {
+            self.confirmed.pop_front();
+        }
+        self.pending.retain(|_, (_, entered)| *entered > start);
+    }
+
+    fn estimate(&self, target: usize) -> Option<f64> {
+        let mut points: Vec<(f64, bool)> = self.confirmed.iter().map(|c| (c.fee_rate, c.blocks <= target)).collect();
+        points.extend(self.pending.values().filter(|(_, entered)| self.tip.saturating_sub(*entered) >= target).map(|(fee_rate, _)| (*fee_rate, false)));
+        points.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        let (mut total, mut in_time, mut estimate) = (0, 0, None);
+        for (i, (fee_rate, confirmed)) in points.iter().enumerate() {
+            total += 1;
+            in_time += usize::from(*confirmed);
+            // the transactions paying one rate count together
+            if points.get(i + 1).is_some_and(|(next, _)| next == fee_rate) {
+                continue;
+            }
+            if total >= MIN_SAMPLES && in_time * 100 >= total * SUCCESS_PERCENT {
+                estimate = Some(*fee_rate);
+            }
+        }
+        estimate
+    }
+}
+
+/// FeeEstimator follows the chain events, clones share the same statistics
+#[derive(Debug, Clone, Default)]
+pub struct FeeEstimator {
+    stats: Arc<Mutex<Stats>>
+}
+
+impl FeeEstimator {
+    /// start follows the blocks connected to `bc` on a background thread
+    pub fn start(bc: &Blockchain) -> Result<FeeEstimator> {
+        let events = bc.events().subscribe();
+        let estimator = FeeEstimator::default();
+        estimator.lock().tip = bc.get_best_height()?.max(0) as usize;
+
+        let follower = estimator.clone();
+        let bc = bc.clone();
+        thread::spawn(move || {
+            for event in events {
+                let Event::BlockConnected { hash, height } = event else {
+                    continue;
+                };
+                match bc.get_block_body(&hash) {
+                    Ok(transactions) => {
+                        let txids: Vec<Hash256> = transactions.iter().map(|tx| tx.id).collect();
+                        follower.lock().connect(height, &txids);
+                    },
+                    Err(e) => error!("fee estimator failed on block {}: {}", hash, e)
+                }
+            }
+        });
+        Ok(estimator)
+    }
+
+    /// track notes a transaction paying `fee_rate` that entered the mempool
+    /// with the tip at `height`
+    pub fn track(&self, txid: Hash256, fee_rate: f64, height: usize) {
+        self.lock().pending.insert(txid, (fee_rate, height));
+    }
+
+    /// estimate is the fee rate for the longest of TARGETS within `target`,
+    /// or for a longer one when that has too few transactions
+    pub fn estimate(&self, target: usize) -> Result<FeeEstimate> {
+        let Some(start) = TARGETS.iter().rposition(|&blocks| blocks <= target) else {
+            return Err(BlockchainError::Config(format!("a fee estimate needs a target of at least {} block", TARGETS[0])));
+        };
+        let stats = self.lock();
+        for &blocks in &TARGETS[start..] {
+            if let Some(feerate) = stats.estimate(blocks) {
+                return Ok(FeeEstimate { feerate: Some(feerate), blocks });
+            }
+        }
+        Ok(FeeEstimate { feerate: None, blocks: TARGETS[start] })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Stats> {
+        self.stats.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// fee_for is the fee of `size` bytes at `fee_rate` sats per byte, rounded up
+pub fn fee_for(size: usize, fee_rate: f64) -> Amount {
+    Amount::from_sat((size as f64 * fee_rate).ceil() as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimates_follow_how_fast_each_rate_confirmed() -> Result<()> {
+        let estimator = FeeEstimator::default();
+        let txid = |i: u8| Hash256::new([i; 32]);
+        // at 10 sats per byte the next block, at 5 three blocks later, at 1 never
+        for i in 0..4 {
+            estimator.track(txid(i), 10.0, 0);
+            estimator.track(txid(10 + i), 5.0, 0);
+            estimator.track(txid(20 + i), 1.0, 0);
+        }
+        estimator.lock().connect(1, &(0..4).map(txid).collect::<Vec<_>>());
+        assert_eq!(estimator.estimate(1)?, FeeEstimate { feerate: Some(10.0), blocks: 1 });
+        assert_eq!(estimator.estimate(3)?, FeeEstimate { feerate: Some(10.0), blocks: 3 });
+
+        estimator.lock().connect(2, &[]);
+        estimator.lock().connect(3, &(10..14).map(txid).collect::<Vec<_>>());
+        assert_eq!(estimator.estimate(1)?.feerate, Some(10.0));
+        assert_eq!(estimator.estimate(4)?, FeeEstimate { feerate: Some(5.0), blocks: 3 });
+        assert_eq!(estimator.estimate(100)?.feerate, Some(5.0));
+        assert!(estimator.estimate(0).is_err());
+
+        estimator.lock().connect(WINDOW_BLOCKS + 3, &[]);
+        assert_eq!(estimator.estimate(6)?, FeeEstimate { feerate: None, blocks: 6 });
+        Ok(())
+    }
+}
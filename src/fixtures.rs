@@ -0,0 +1,201 @@
+//! Canonical encodings of representative transactions, blocks and P2P messages
+//! for the protocol documentation. `genfixtures` writes each one as the hex of
+//! its bytes and its JSON decoding:
+//!
+//! ```text
+//! blockchain_project genfixtures --out fixtures
+//! ```
+//!
+//! The files in `fixtures/` are checked against what the current code
+//! encodes, so a change to the encoding fails the tests until they are
+//! written again on purpose.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use serde_json::{json, Value};
+
+use crate::addrman::TimedAddr;
+use crate::amount::Amount;
+use crate::block::Block;
+use crate::blockfilter::{filter_header, BlockFilter};
+use crate::bloom::BloomFilter;
+use crate::codec::{Addrmsg, Blockmsg, CFHeadersmsg, CFiltersmsg, Filteredmsg, GetBlockmsg, GetCFHeadersmsg, GetCFiltersmsg, GetDatamsg, GetFilteredmsg, GetHeadersmsg, Headersmsg, Invmsg, Message, MessageCodec, Packagemsg, Rejectmsg, SendHeadersmsg, Txmsg, Versionmsg, REJECT_INVALID, SERVICE_COMPRESSION};
+use crate::error::Result;
+use crate::hash::Hash256;
+use crate::json::{block_json, header_json, tx_json};
+use crate::spv::{FilteredTx, SpvHeader};
+use crate::target::POW_LIMIT_BITS;
+use crate::transaction::Transaction;
+use crate::tx::{TXInput, TXOutput};
+use crate::wallet::{hash_pub_key, Wallet};
+
+/// TIMESTAMP is the time, in milliseconds, of the fixture block
+const TIMESTAMP: u128 = 1_700_000_000_000;
+
+/// PEER is the sender address of the fixture messages
+const PEER: &str = "127.0.0.1:3000";
+
+/// Fixture is a value in its canonical encoding and its JSON decoding
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fixture {
+    pub name: &'static str,
+    pub bytes: Vec<u8>,
+    pub json: Value
+}
+
+impl Fixture {
+    fn new(name: &'static str, bytes: Vec<u8>, json: Value) -> Fixture {
+        Fixture { name, bytes, json }
+    }
+}
+
+/// fixtures builds every fixture from fixed keys, times and nonces, so they
+/// are the same on each run
+pub fn fixtures() -> Result<Vec<Fixture>> {
+    let alice = Wallet::from_seed(&[1; 32]);
+    let bob = Wallet::from_seed(&[2; 32]);
+    let coinbase = Transaction::new_coinbase(alice.get_address(), "fixtures".to_string())?;
+
+    let mut spend = Transaction {
+        id: Hash256::ZERO,
+        vin: vec![TXInput { prev_out: coinbase.outpoint(0), signature: Vec::new(), pub_key: alice.public_key.clone() }],
+        vout: vec![
+            TXOutput::new(Amount::from_sat(30), bob.get_address())?,
+            TXOutput::new(coinbase.vout[0].value.try_sub(Amount::from_sat(31))?, alice.get_address())?
+        ]
+    };
+    spend.id = spend.hash()?;
+    spend.sign(&alice.secret_key, HashMap::from([(coinbase.id, coinbase.clone())]))?;
+
+    let block = Block::from_solution(vec![coinbase.clone(), spend.clone()], Hash256::new([0x11; 32]), 1, POW_LIMIT_BITS, TIMESTAMP, 7)?;
+    let mut bob_hash = bob.public_key.clone();
+    hash_pub_key(&mut bob_hash);
+    let filter = BlockFilter::of(&block)?;
+    let filtered = FilteredTx { height: 1, block: block.get_hash(), tx: spend.clone(), branch: Block::merkle_branch(block.get_transactions(), 1)? };
+
+    let mut fixtures = vec![
+        Fixture::new("tx-coinbase", bincode::serialize(&coinbase)?, tx_json(&coinbase)),
+        Fixture::new("tx-signed", bincode::serialize(&spend)?, tx_json(&spend)),
+        Fixture::new("block", bincode::serialize(&block)?, block_json(&block))
+    ];
+    let messages = [
+        ("message-addr", Message::Addr(vec![PEER.to_string(), "[::1]:3001".to_string()])),
+        ("message-version", Message::Version(Versionmsg { addr_from: PEER.to_string(), version: 1, services: SERVICE_COMPRESSION, best_height: 1, user_agent: "/toychain:0.1.0/".to_string() })),
+        ("message-tx", Message::Tx(Txmsg { addr_from: PEER.to_string(), transaction: spend.clone() })),
+        ("message-getdata", Message::GetData(GetDatamsg { addr_from: PEER.to_string(), kind: "block".to_string(), id: block.get_hash() })),
+        ("message-getblocks", Message::GetBlock(GetBlockmsg { addr_from: PEER.to_string() })),
+        ("message-inv", Message::Inv(Invmsg { addr_from: PEER.to_string(), kind: "tx".to_string(), items: vec![coinbase.id, spend.id] })),
+        ("message-getheaders", Message::GetHeaders(GetHeadersmsg { addr_from: String::new(), from: 1 })),
+        ("message-headers", Message::Headers(Headersmsg { addr_from: PEER.to_string(), best_height: 1, headers: vec![SpvHeader::of(&block)?] })),
+        ("message-getfiltered", Message::GetFiltered(GetFilteredmsg { addr_from: String::new(), filter: BloomFilter::from_items([bob_hash.as_slice()].into_iter()), from: 1 })),
+        ("message-filtered", Message::Filtered(Filteredmsg { addr_from: PEER.to_string(), to: 1, txs: vec![filtered] })),
+        ("message-getcfilters", Message::GetCFilters(GetCFiltersmsg { addr_from: String::new(), from: 1 })),
+        ("message-cfilters", Message::CFilters(CFiltersmsg { addr_from: PEER.to_string(), from: 1, filters: vec![(block.get_hash(), filter.clone())] })),
+        ("message-getcfheaders", Message::GetCFHeaders(GetCFHeadersmsg { addr_from: String::new(), from: 1 })),
+        ("message-cfheaders", Message::CFHeaders(CFHeadersmsg {
+            addr_from: PEER.to_string(),
+            from: 1,
+            stop: block.get_hash(),
+            prev_header: filter_header(&Hash256::new([0x22; 32]), &Hash256::ZERO),
+            filter_hashes: vec![filter.hash()]
+        })),
+        ("message-addrv2", Message::AddrV2(Addrmsg {
+            addr_from: PEER.to_string(),
+            addrs: vec![TimedAddr { addr: "[::1]:3001".to_string(), time: 1_700_000_000 }]
+        })),
+        ("message-sendheaders", Message::SendHeaders(SendHeadersmsg { addr_from: PEER.to_string() })),
+        ("message-reject", Message::Reject(Rejectmsg {
+            addr_from: PEER.to_string(),
+            message: "tx".to_string(),
+            hash: spend.id,
+            code: REJECT_INVALID,
+            reason: "transaction spends a missing output".to_string()
+        })),
+        ("message-package", Message::Package(Packagemsg { addr_from: PEER.to_string(), transactions: vec![spend.clone()] })),
+        ("message-block", Message::Block(Blockmsg { addr_from: PEER.to_string(), block }))
+    ];
+    for (name, message) in messages {
+        fixtures.push(Fixture::new(name, MessageCodec::encode(&message)?, message_json(&message)));
+    }
+    Ok(fixtures)
+}
+
+/// message_json decodes a P2P message, its command first
+pub fn message_json(message: &Message) -> Value {
+    let payload = match message {
+        Message::Addr(addrs) => json!({ "addresses": addrs }),
+        Message::Version(msg) => json!({ "addrfrom": msg.addr_from, "version": msg.version, "services": msg.services, "bestheight": msg.best_height, "useragent": msg.user_agent }),
+        Message::Tx(msg) => json!({ "addrfrom": msg.addr_from, "transaction": tx_json(&msg.transaction) }),
+        Message::GetData(msg) => json!({ "addrfrom": msg.addr_from, "kind": msg.kind, "id": msg.id }),
+        Message::GetBlock(msg) => json!({ "addrfrom": msg.addr_from }),
+        Message::Inv(msg) => json!({ "addrfrom": msg.addr_from, "kind": msg.kind, "items": msg.items }),
+        Message::Block(msg) => json!({ "addrfrom": msg.addr_from, "block": block_json(&msg.block) }),
+        Message::GetHeaders(msg) => json!({ "addrfrom": msg.addr_from, "from": msg.from }),
+        Message::Headers(msg) => {
+            let headers: Vec<Value> = msg.headers.iter().map(|h| json!({ "header": header_json(&h.header), "merkleroot": h.merkle_root })).collect();
+            json!({ "addrfrom": msg.addr_from, "bestheight": msg.best_height, "headers": headers })
+        },
+        Message::GetFiltered(msg) => json!({ "addrfrom": msg.addr_from, "filter": msg.filter, "from": msg.from }),
+        Message::Filtered(msg) => {
+            let txs: Vec<Value> = msg.txs.iter().map(|f| json!({ "height": f.height, "blockhash": f.block, "transaction": tx_json(&f.tx), "branch": f.branch })).collect();
+            json!({ "addrfrom": msg.addr_from, "to": msg.to, "txs": txs })
+        },
+        Message::GetCFilters(msg) => json!({ "addrfrom": msg.addr_from, "from": msg.from }),
+        Message::CFilters(msg) => {
+            let filters: Vec<Value> = msg.filters.iter().map(|(hash, f)| json!({ "blockhash": hash, "items": f.items, "filter": hex::encode(&f.data) })).collect();
+            json!({ "addrfrom": msg.addr_from, "from": msg.from, "filters": filters })
+        },
+        Message::GetCFHeaders(msg) => json!({ "addrfrom": msg.addr_from, "from": msg.from }),
+        Message::CFHeaders(msg) => json!({ "addrfrom": msg.addr_from, "from": msg.from, "stophash": msg.stop, "prevheader": msg.prev_header, "filterhashes": msg.filter_hashes }),
+        Message::AddrV2(msg) => json!({ "addrfrom": msg.addr_from, "addresses": msg.addrs }),
+        Message::SendHeaders(msg) => json!({ "addrfrom": msg.addr_from }),
+        Message::Package(msg) => json!({ "addrfrom": msg.addr_from, "transactions": msg.transactions.iter().map(tx_json).collect::<Vec<_>>() }),
+        Message::Reject(msg) => json!({ "addrfrom": msg.addr_from, "message": msg.message, "hash": msg.hash, "code": msg.code, "reason": msg.reason })
+    };
+    json!({ "command": message.command(), "payload": payload })
+}
+
+/// write_fixtures writes `<name>.hex` and `<name>.json` for every fixture into
+/// `dir` and returns how many fixtures it wrote
+pub fn write_fixtures(dir: &Path) -> Result<usize> {
+    fs::create_dir_all(dir)?;
+    let fixtures = fixtures()?;
+    for fixture in &fixtures {
+        fs::write(dir.join(format!("{}.hex", fixture.name)), hex::encode(&fixture.bytes) + "\n")?;
+        fs::write(dir.join(format!("{}.json", fixture.name)), serde_json::to_string_pretty(&fixture.json)? + "\n")?;
+    }
+    Ok(fixtures.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn test_encodings_match_the_golden_fixtures() -> Result<()> {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+        let fixtures = fixtures()?;
+        let tx = |name: &str| -> Result<Transaction> { Ok(bincode::deserialize(&fixtures.iter().find(|f| f.name == name).unwrap().bytes)?) };
+        let coinbase = tx("tx-coinbase")?;
+        assert!(tx("tx-signed")?.verify(HashMap::from([(coinbase.id, coinbase)]))?);
+
+        for fixture in fixtures {
+            let hex = fs::read_to_string(dir.join(format!("{}.hex", fixture.name)))?;
+            let json: Value = serde_json::from_str(&fs::read_to_string(dir.join(format!("{}.json", fixture.name)))?)?;
+            let hint = "run `blockchain_project genfixtures` if the change is intended";
+            assert_eq!(hex.trim_end(), hex::encode(&fixture.bytes), "{} changed its encoding, {}", fixture.name, hint);
+            assert_eq!(json, fixture.json, "{} changed its decoding, {}", fixture.name, hint);
+
+            if let Some(command) = fixture.name.strip_prefix("message-") {
+                let message = MessageCodec::decode(&fixture.bytes)?;
+                assert_eq!(message.command(), command);
+                assert_eq!(MessageCodec::encode(&message)?, fixture.bytes);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,298 @@
+use std::collections::HashSet;
+use std::net::TcpListener;
+use std::thread;
+
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::service::Interceptor;
+use tonic::{Request, Response, Status};
+use tracing::{error, info};
+
+use crate::address;
+use crate::amount::Amount;
+use crate::error::{BlockchainError, Result};
+use crate::events::Event;
+use crate::rpc;
+use crate::server::Server;
+use crate::transaction::Transaction;
+use crate::wallet::{hash_pub_key, hash_to_address, Wallets};
+
+/// proto holds the messages and the service generated from proto/blockchain.proto
+pub mod proto {
+    tonic::include_proto!("blockchain");
+}
+
+use proto::node_server::{Node, NodeServer};
+
+type Reply<T> = std::result::Result<Response<T>, Status>;
+
+/// EVENT_BUFFER is how many events a slow Subscribe client may lag behind
+const EVENT_BUFFER: usize = 64;
+
+/// Authorization refuses the calls without the credentials of the node
+#[derive(Clone)]
+struct Authorization {
+    credentials: String
+}
+
+impl Interceptor for Authorization {
+    fn call(&mut self, request: Request<()>) -> std::result::Result<Request<()>, Status> {
+        let authorized = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| rpc::is_authorization(value, &self.credentials));
+        if authorized {
+            Ok(request)
+        } else {
+            Err(Status::unauthenticated("missing or wrong authorization"))
+        }
+    }
+}
+
+/// start serves the gRPC API of `server` on the configured gRPC port from a background thread,
+/// accepting calls authenticated with `credentials`
+pub fn start(server: Server, credentials: String) -> Result<()> {
+    let addr = format!("127.0.0.1:{}", server.config().grpc_port);
+    let listener = TcpListener::bind(&addr)?;
+    listener.set_nonblocking(true)?;
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+    info!("gRPC listening on {}", addr);
+
+    let service = NodeServer::with_interceptor(NodeService { server }, Authorization { credentials });
+
+    thread::spawn(move || {
+        let served = runtime.block_on(async move {
+            let incoming = TcpListenerStream::new(tokio::net::TcpListener::from_std(listener)?);
+            tonic::transport::Server::builder()
+                .add_service(service)
+                .serve_with_incoming(incoming)
+                .await
+                .map_err(|e| BlockchainError::Network(e.to_string()))
+        });
+        if let Err(e) = served {
+            error!("gRPC server stopped: {}", e);
+        }
+    });
+    Ok(())
+}
+
+impl From<BlockchainError> for Status {
+    fn from(e: BlockchainError) -> Status {
+        match e {
+            BlockchainError::TxNotFound(_) | BlockchainError::BlockNotFound(_) => Status::not_found(e.to_string()),
+            BlockchainError::InvalidAddress(_) | BlockchainError::InvalidAmount(_) | BlockchainError::InvalidHash(_) | BlockchainError::Serialization(_) => Status::invalid_argument(e.to_string()),
+            BlockchainError::InsufficientFunds { .. } | BlockchainError::Consensus(_) => Status::failed_precondition(e.to_string()),
+            BlockchainError::MempoolFull(_) => Status::resource_exhausted(e.to_string()),
+            _ => Status::internal(e.to_string())
+        }
+    }
+}
+
+struct NodeService {
+    server: Server
+}
+
+#[tonic::async_trait]
+impl Node for NodeService {
+    async fn get_block_count(&self, _: Request<proto::Empty>) -> Reply<proto::BlockCount> {
+        let count = self.server.utxo_set().blockchain.get_best_height()? as u64;
+        Ok(Response::new(proto::BlockCount { count }))
+    }
+
+    async fn get_best_block_hash(&self, _: Request<proto::Empty>) -> Reply<proto::BlockHash> {
+        let hash = self.server.utxo_set().blockchain.get_tip().to_string();
+        Ok(Response::new(proto::BlockHash { hash }))
+    }
+
+    async fn get_block(&self, request: Request<proto::GetBlockRequest>) -> Reply<proto::Block> {
+        let bc = &self.server.utxo_set().blockchain;
+        let hash = match request.into_inner().block {
+            Some(proto::get_block_request::Block::Hash(hash)) => hash.parse()?,
+            Some(proto::get_block_request::Block::Height(height)) => bc.get_block_hash(height as usize)?,
+            None => return Err(Status::invalid_argument("a block hash or height is required"))
+        };
+
+        let block = bc.get_block(&hash)?;
+        Ok(Response::new(proto::Block {
+            hash: block.get_hash().to_string(),
+            height: block.get_height() as u64,
+            time: block.get_timestamp() as u64,
+            nonce: block.get_nonce(),
+            previous_block_hash: block.get_prev_hash().to_string(),
+            txids: block.get_transactions().iter().map(|tx| tx.id.to_string()).collect()
+        }))
+    }
+
+    async fn get_transaction(&self, request: Request<proto::GetTransactionRequest>) -> Reply<proto::Transaction> {
+        let txid = request.into_inner().txid.parse()?;
+        let bc = &self.server.utxo_set().blockchain;
+
+        let reply = if let Some((tx, header)) = bc.get_indexed_transaction(&txid)? {
+            let mut reply = tx_message(&tx);
+            reply.block_hash = header.hash.to_string();
+            reply.confirmations = bc.confirmations(&header)? as u64;
+            reply
+        } else if let Some(entry) = self.server.get_mempool().get(&txid) {
+            tx_message(&entry.tx)
+        } else {
+            return Err(BlockchainError::TxNotFound(txid.to_string()).into());
+        };
+        Ok(Response::new(reply))
+    }
+
+    async fn list_unspent(&self, request: Request<proto::AddressRequest>) -> Reply<proto::UnspentOutputs> {
+        let pub_key_hash = address::decode(&request.into_inner().address)?;
+        let best_height = self.server.utxo_set().blockchain.get_best_height()? as usize;
+
+        let outputs = self
+            .server
+            .utxo_set()
+            .list_unspent(Some(&pub_key_hash))?
+            .into_iter()
+            .map(|out| proto::UnspentOutput {
+                txid: out.outpoint.txid.to_string(),
+                vout: out.outpoint.index,
+                amount: out.value.to_sat(),
+                confirmations: (best_height + 1).saturating_sub(out.height) as u64
+            })
+            .collect();
+        Ok(Response::new(proto::UnspentOutputs { outputs }))
+    }
+
+    async fn get_mempool(&self, _: Request<proto::Empty>) -> Reply<proto::Mempool> {
+        let entries = self
+            .server
+            .get_mempool()
+            .entries()
+            .map(|e| proto::MempoolEntry {
+                txid: e.tx.id.to_string(),
+                fee: e.fee.to_sat(),
+                size: e.size as u64,
+                time: e.time
+            })
+            .collect();
+        Ok(Response::new(proto::Mempool { entries }))
+    }
+
+    async fn get_peers(&self, _: Request<proto::Empty>) -> Reply<proto::Peers> {
+        let mut addresses: Vec<String> = self.server.get_known_nodes().into_iter().collect();
+        addresses.sort();
+        Ok(Response::new(proto::Peers { addresses }))
+    }
+
+    async fn list_addresses(&self, _: Request<proto::Empty>) -> Reply<proto::Addresses> {
+        let wallets = Wallets::new(self.server.config())?;
+        Ok(Response::new(proto::Addresses { addresses: wallets.get_all_address() }))
+    }
+
+    async fn get_balance(&self, request: Request<proto::AddressRequest>) -> Reply<proto::Balance> {
+        let amount = rpc::address_balance(&self.server, &request.into_inner().address)?;
+        Ok(Response::new(proto::Balance { amount: amount.to_sat() }))
+    }
+
+    async fn send_to_address(&self, request: Request<proto::SendToAddressRequest>) -> Reply<proto::TransactionId> {
+        let request = request.into_inner();
+        // a mining node mines the transaction right away
+        let txid = tokio::task::block_in_place(|| rpc::send_to_address(&self.server, &request.address, Amount::from_sat(request.amount), None))?;
+        Ok(Response::new(proto::TransactionId { txid: txid.to_string() }))
+    }
+
+    async fn send_raw_transaction(&self, request: Request<proto::RawTransaction>) -> Reply<proto::TransactionId> {
+        let tx: Transaction = bincode::deserialize(&request.into_inner().raw).map_err(BlockchainError::from)?;
+        let txid = tx.id.to_string();
+        tokio::task::block_in_place(|| self.server.submit_transaction(tx))?;
+        Ok(Response::new(proto::TransactionId { txid }))
+    }
+
+    type SubscribeStream = ReceiverStream<std::result::Result<proto::Event, Status>>;
+
+    async fn subscribe(&self, request: Request<proto::SubscribeRequest>) -> Reply<Self::SubscribeStream> {
+        let mut filter = HashSet::new();
+        for address in &request.into_inner().addresses {
+            filter.insert(address::decode(address)?);
+        }
+
+        let events = self.server.utxo_set().blockchain.events().subscribe();
+        let (tx, rx) = mpsc::channel(EVENT_BUFFER);
+        let server = self.server.clone();
+        // the bus hands out blocking receivers, so events are forwarded from a
+        // thread that stops at the first event after the client went away
+        thread::spawn(move || {
+            for event in events {
+                if let Some(message) = event_message(&server, event, &filter) {
+                    if tx.blocking_send(Ok(message)).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+/// event_message converts a bus event, None for mempool transactions outside `filter`
+fn event_message(server: &Server, event: Event, filter: &HashSet<Vec<u8>>) -> Option<proto::Event> {
+    use proto::event::Event as Kind;
+
+    let kind = match event {
+        Event::BlockConnected { hash, height } => Kind::BlockConnected(proto::BlockEvent { hash: hash.to_string(), height: height as u64 }),
+        Event::BlockDisconnected { hash, height } => Kind::BlockDisconnected(proto::BlockEvent { hash: hash.to_string(), height: height as u64 }),
+        Event::TxAccepted { txid } => {
+            let entry = server.get_mempool().get(&txid).cloned()?;
+            let mut addresses: Vec<String> = entry
+                .tx
+                .pub_key_hashes()
+                .iter()
+                .filter(|h| filter.contains(*h))
+                .map(|h| hash_to_address(h))
+                .collect();
+            if addresses.is_empty() {
+                return None;
+            }
+            addresses.sort();
+            addresses.dedup();
+            Kind::TxAccepted(proto::TxEvent { txid: txid.to_string(), fee: entry.fee.to_sat(), addresses })
+        },
+        Event::PeerConnected { addr } => Kind::PeerConnected(addr),
+        Event::DoubleSpendDetected { outpoint, first, second } => {
+            Kind::DoubleSpendDetected(proto::DoubleSpendEvent { outpoint: outpoint.to_string(), first: first.to_string(), second: second.to_string() })
+        }
+    };
+    Some(proto::Event { event: Some(kind) })
+}
+
+/// tx_message mirrors json::tx_json
+fn tx_message(tx: &Transaction) -> proto::Transaction {
+    let vin = tx
+        .vin
+        .iter()
+        .map(|input| {
+            if tx.is_coinbase() {
+                proto::TxInput { coinbase: hex::encode(&input.pub_key), ..Default::default() }
+            } else {
+                let mut pub_key_hash = input.pub_key.clone();
+                hash_pub_key(&mut pub_key_hash);
+                proto::TxInput {
+                    txid: input.prev_out.txid.to_string(),
+                    vout: input.prev_out.index,
+                    address: hash_to_address(&pub_key_hash),
+                    coinbase: String::new()
+                }
+            }
+        })
+        .collect();
+    let vout = tx
+        .vout
+        .iter()
+        .map(|out| proto::TxOutput { value: out.value.to_sat(), address: hash_to_address(&out.pub_key_hash) })
+        .collect();
+
+    proto::Transaction {
+        txid: tx.id.to_string(),
+        vin,
+        vout,
+        ..Default::default()
+    }
+}
@@ -0,0 +1,129 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crypto::{digest::Digest, sha2::Sha256};
+use serde::de::{Deserializer, Error as _};
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::error::{BlockchainError, Result};
+
+/// Hash256 is a sha256 digest, a block hash or a txid, kept as its 32 bytes: it is
+/// written as 64 hex digits for people and in JSON, and as the raw bytes in bincode
+/// and in database keys
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Hash256([u8; 32]);
+
+impl Hash256 {
+    /// ZERO stands for no hash, the parent of the genesis block and the txid
+    /// spent by a coinbase
+    pub const ZERO: Hash256 = Hash256([0; 32]);
+
+    pub const fn new(bytes: [u8; 32]) -> Hash256 {
+        Hash256(bytes)
+    }
+
+    /// sha256 hashes `data`
+    pub fn sha256(data: &[u8]) -> Hash256 {
+        let mut hasher = Sha256::new();
+        hasher.input(data);
+        let mut hash = [0; 32];
+        hasher.result(&mut hash);
+        Hash256(hash)
+    }
+
+    /// from_hex reads a hash written as 64 hex digits
+    pub fn from_hex(hex: &str) -> Result<Hash256> {
+        let mut bytes = [0; 32];
+        hex::decode_to_slice(hex, &mut bytes).map_err(|e| BlockchainError::InvalidHash(format!("{:?}: {}", hex, e)))?;
+        Ok(Hash256(bytes))
+    }
+
+    /// from_slice reads a hash stored as its raw bytes, refusing any other length
+    pub fn from_slice(bytes: &[u8]) -> Result<Hash256> {
+        match <[u8; 32]>::try_from(bytes) {
+            Ok(bytes) => Ok(Hash256(bytes)),
+            Err(_) => Err(BlockchainError::InvalidHash(format!("{} is not 32 bytes", hex::encode(bytes))))
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    pub fn is_zero(&self) -> bool {
+        *self == Hash256::ZERO
+    }
+}
+
+impl From<[u8; 32]> for Hash256 {
+    fn from(bytes: [u8; 32]) -> Hash256 {
+        Hash256(bytes)
+    }
+}
+
+impl AsRef<[u8]> for Hash256 {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl FromStr for Hash256 {
+    type Err = BlockchainError;
+
+    fn from_str(hex: &str) -> Result<Hash256> {
+        Hash256::from_hex(hex)
+    }
+}
+
+impl fmt::Display for Hash256 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for Hash256 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl Serialize for Hash256 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_string())
+        } else {
+            self.0.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Hash256 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Hash256, D::Error> {
+        if deserializer.is_human_readable() {
+            let hex = String::deserialize(deserializer)?;
+            Hash256::from_hex(&hex).map_err(D::Error::custom)
+        } else {
+            Ok(Hash256(<[u8; 32]>::deserialize(deserializer)?))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_for_people_and_bytes_for_bincode() -> Result<()> {
+        let hash = Hash256::from_hex(&"ab".repeat(32))?;
+        assert_eq!(hash.to_string(), "ab".repeat(32));
+        assert_eq!(bincode::serialize(&hash)?, vec![0xab; 32]);
+        assert_eq!(bincode::deserialize::<Hash256>(&[0xab; 32])?, hash);
+        assert_eq!(serde_json::to_value(hash)?, "ab".repeat(32));
+        assert_eq!(serde_json::from_value::<Hash256>(serde_json::to_value(hash)?)?, hash);
+
+        assert!(Hash256::from_hex("ab").is_err());
+        assert!(Hash256::from_slice(&[0; 31]).is_err());
+        assert!(Hash256::ZERO.is_zero() && !hash.is_zero());
+        Ok(())
+    }
+}
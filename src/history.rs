@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::thread;
+
+use serde::Serialize;
+use tracing::error;
+
+use crate::amount::Amount;
+use crate::block::{Block, BlockHeader};
+use crate::bloom::BloomFilter;
+use crate::blockchain::Blockchain;
+use crate::error::Result;
+use crate::events::Event;
+use crate::hash::Hash256;
+use crate::transaction::Transaction;
+use crate::wallet::{hash_pub_key, hash_to_address, TxLabel, Wallets};
+
+/// Direction of a transaction seen from one wallet
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Receive,
+    Send,
+    /// every output goes back to the wallet, only the fee leaves it
+    Internal
+}
+
+/// HistoryEntry is a confirmed transaction touching a wallet
+#[derive(Serialize, Debug, Clone)]
+pub struct HistoryEntry {
+    /// block time in milliseconds since the unix epoch
+    pub time: u128,
+    pub txid: Hash256,
+    pub direction: Direction,
+    /// value received, or sent to other addresses, excluding the fee
+    pub amount: Amount,
+    /// fee paid by the wallet, 0 for receives
+    pub fee: Amount,
+    /// counterparty address, empty for coinbase rewards
+    pub address: String,
+    pub block_hash: Hash256,
+    pub height: usize,
+    pub confirmations: usize,
+    /// the label and message the wallet noted when it paid
+    #[serde(flatten)]
+    pub label: Option<TxLabel>,
+    /// the private memo noted at send time, kept only in the wallet
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memo: Option<String>
+}
+
+/// address_history lists the confirmed transactions of `pub_key_hash`, newest first
+pub fn address_history(bc: &Blockchain, pub_key_hash: &[u8]) -> Result<Vec<HistoryEntry>> {
+    let best_height = bc.get_best_height()? as usize;
+    let mut history = Vec::new();
+
+    for block in bc.iter() {
+        history.append(&mut block_history(bc, &block, pub_key_hash, best_height)?);
+    }
+
+    Ok(history)
+}
+
+/// apply_labels attaches the labels and memos `wallets` noted to the entries
+/// of `history`
+pub fn apply_labels(history: &mut [HistoryEntry], wallets: &Wallets) {
+    for entry in history {
+        entry.label = wallets.label(&entry.txid).cloned();
+        entry.memo = wallets.memo(&entry.txid).map(str::to_string);
+    }
+}
+
+/// block_history lists the transactions of `block` touching `pub_key_hash`
+fn block_history(bc: &Blockchain, block: &Block, pub_key_hash: &[u8], best_height: usize) -> Result<Vec<HistoryEntry>> {
+    let mut history = Vec::new();
+    for tx in block.get_transactions() {
+        if let Some(entry) = tx_history(bc, block.header(), tx, pub_key_hash, best_height)? {
+            history.push(entry);
+        }
+    }
+    Ok(history)
+}
+
+fn tx_history(bc: &Blockchain, header: &BlockHeader, tx: &Transaction, pub_key_hash: &[u8], best_height: usize) -> Result<Option<HistoryEntry>> {
+    let mut input_total = Amount::ZERO;
+    let mut spent = Amount::ZERO;
+    let mut sender = String::new();
+    if !tx.is_coinbase() {
+        for vin in &tx.vin {
+            let mut input_hash = vin.pub_key.clone();
+            hash_pub_key(&mut input_hash);
+            let value = bc.find_transaction(&vin.prev_out.txid)?.vout[vin.prev_out.index as usize].value;
+            input_total = input_total.try_add(value)?;
+            if input_hash == pub_key_hash {
+                spent = spent.try_add(value)?;
+            } else if sender.is_empty() {
+                sender = hash_to_address(&input_hash);
+            }
+        }
+    }
+
+    let output_total = Amount::sum(tx.vout.iter().map(|out| out.value))?;
+    let received = Amount::sum(tx.vout.iter().filter(|out| out.pub_key_hash == pub_key_hash).map(|out| out.value))?;
+    let recipient = tx.vout.iter().find(|out| !out.is_data() && out.pub_key_hash != pub_key_hash);
+
+    let (direction, amount, fee, address) = if spent > Amount::ZERO {
+        let fee = if spent == input_total { input_total.try_sub(output_total)? } else { Amount::ZERO };
+        match recipient {
+            Some(out) => (Direction::Send, output_total.try_sub(received)?, fee, hash_to_address(&out.pub_key_hash)),
+            None => (Direction::Internal, Amount::ZERO, fee, String::new())
+        }
+    } else if received > Amount::ZERO {
+        (Direction::Receive, received, Amount::ZERO, sender)
+    } else {
+        return Ok(None);
+    };
+
+    Ok(Some(HistoryEntry {
+        time: header.timestamp,
+        txid: tx.id,
+        direction,
+        amount,
+        fee,
+        address,
+        block_hash: header.hash,
+        height: header.height,
+        confirmations: (best_height + 1).saturating_sub(header.height),
+        label: None,
+        memo: None
+    }))
+}
+
+/// HistoryIndexer keeps the history of a set of wallets in memory, following
+/// the chain events so it never rescans the chain after start. A bloom filter of
+/// the wallet keys, readable without waiting for the indexer, skips the
+/// transactions touching none of them
+#[derive(Debug, Clone)]
+pub struct HistoryIndexer {
+    bc: Blockchain,
+    entries: Arc<Mutex<HashMap<Vec<u8>, Vec<HistoryEntry>>>>,
+    /// filter of the keys of `entries`, rebuilt with the entries locked
+    filter: Arc<RwLock<BloomFilter>>
+}
+
+impl HistoryIndexer {
+    /// start scans the history of `pub_key_hashes` once and then follows new
+    /// and disconnected blocks on a background thread
+    pub fn start(bc: &Blockchain, pub_key_hashes: Vec<Vec<u8>>) -> Result<HistoryIndexer> {
+        let events = bc.events().subscribe();
+
+        let mut entries = HashMap::new();
+        for pub_key_hash in pub_key_hashes {
+            let history = address_history(bc, &pub_key_hash)?;
+            entries.insert(pub_key_hash, history);
+        }
+
+        let indexer = HistoryIndexer {
+            bc: bc.clone(),
+            filter: Arc::new(RwLock::new(BloomFilter::from_items(entries.keys().map(Vec::as_slice)))),
+            entries: Arc::new(Mutex::new(entries))
+        };
+
+        let follower = indexer.clone();
+        thread::spawn(move || {
+            for event in events {
+                if let Err(e) = follower.apply(&event) {
+                    error!("history indexer failed on {:?}: {}", event, e);
+                }
+            }
+        });
+
+        Ok(indexer)
+    }
+
+    /// watch imports a wallet key, scanning its history and rebuilding the filter,
+    /// nothing is done for a key already indexed
+    pub fn watch(&self, pub_key_hash: Vec<u8>) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if entries.contains_key(&pub_key_hash) {
+            return Ok(());
+        }
+        let history = address_history(&self.bc, &pub_key_hash)?;
+        entries.insert(pub_key_hash, history);
+        *self.filter.write().unwrap_or_else(PoisonError::into_inner) = BloomFilter::from_items(entries.keys().map(Vec::as_slice));
+        Ok(())
+    }
+
+    /// is_wallet_tx tells whether `tx` spends from or pays to an indexed wallet
+    pub fn is_wallet_tx(&self, tx: &Transaction) -> bool {
+        if !self.may_match(tx) {
+            return false;
+        }
+        let entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        tx.pub_key_hashes().iter().any(|h| entries.contains_key(h))
+    }
+
+    /// may_match asks the filter only, false means `tx` touches no indexed wallet
+    fn may_match(&self, tx: &Transaction) -> bool {
+        let filter = self.filter.read().unwrap_or_else(PoisonError::into_inner);
+        tx.pub_key_hashes().iter().any(|h| filter.contains(h))
+    }
+
+    fn apply(&self, event: &Event) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        match event {
+            Event::BlockConnected { hash, .. } => {
+                let (header, transactions) = self.bc.get_block(hash)?.into_parts();
+                let mut added: HashMap<Vec<u8>, Vec<HistoryEntry>> = HashMap::new();
+                for tx in &transactions {
+                    if !self.may_match(tx) {
+                        continue;
+                    }
+                    let mut keys = tx.pub_key_hashes();
+                    keys.sort();
+                    keys.dedup();
+                    for key in keys {
+                        match entries.get(&key) {
+                            Some(history) if !history.iter().any(|e| e.block_hash == *hash) => {},
+                            _ => continue
+                        }
+                        if let Some(entry) = tx_history(&self.bc, &header, tx, &key, header.height)? {
+                            added.entry(key).or_default().push(entry);
+                        }
+                    }
+                }
+                for (key, mut block_entries) in added {
+                    if let Some(history) = entries.get_mut(&key) {
+                        block_entries.append(history);
+                        *history = block_entries;
+                    }
+                }
+            },
+            Event::BlockDisconnected { hash, .. } => {
+                for history in entries.values_mut() {
+                    history.retain(|e| e.block_hash != *hash);
+                }
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// history returns the entries of `pub_key_hash` newest first, None when it is not indexed
+    pub fn history(&self, pub_key_hash: &[u8]) -> Result<Option<Vec<HistoryEntry>>> {
+        let best_height = self.bc.get_best_height()? as usize;
+        let entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+
+        Ok(entries.get(pub_key_hash).map(|history| {
+            history
+                .iter()
+                .cloned()
+                .map(|mut e| {
+                    e.confirmations = (best_height + 1).saturating_sub(e.height);
+                    e
+                })
+                .collect()
+        }))
+    }
+}
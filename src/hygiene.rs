@@ -0,0 +1,211 @@
+//! Wallet hygiene. `walletaudit` goes through the indexed history, the unspent
+//! outputs and the mempool transactions of every wallet of the node and warns
+//! about what costs its owner privacy or money: an address paid more than once
+//! links all its payments, an output worth less than the fee of spending it is
+//! lost, a transaction waiting for hours pays too little, and a key in no
+//! backup dies with the disk holding it.
+
+use serde::Serialize;
+
+use crate::address;
+use crate::amount::Amount;
+use crate::consolidate::input_size;
+use crate::error::Result;
+use crate::fees::fee_for;
+use crate::hash::Hash256;
+use crate::history::HistoryIndexer;
+use crate::mempool::MempoolEntry;
+use crate::utxoset::UTXOSet;
+use crate::wallet::{hash_pub_key, hash_to_address, Wallets};
+
+/// DUST_FEE_RATE is the fee rate, in sats per byte, outputs are judged at
+/// when the node has no estimate yet, about what a 5 sat payment pays
+pub const DUST_FEE_RATE: f64 = 0.02;
+
+/// STUCK_SECS is how long a wallet transaction waits in the mempool before
+/// it is reported
+pub const STUCK_SECS: u64 = 3600;
+
+/// Issue is what a warning is about
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Issue {
+    /// an address paid by more than one transaction
+    Reuse,
+    /// outputs worth less than the fee of spending them
+    Dust,
+    /// a wallet transaction waiting in the mempool for STUCK_SECS
+    Stuck,
+    /// a key pair no backup holds
+    Unbacked
+}
+
+/// Warning is an issue found with one address and what to do about it
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub issue: Issue,
+    pub address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub txid: Option<Hash256>,
+    pub message: String
+}
+
+/// WalletAudit lists the warnings about the wallets of a node
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct WalletAudit {
+    /// addresses audited, watch-only ones included
+    pub addresses: usize,
+    /// fee rate the dust was judged at
+    #[serde(rename = "dustfeerate")]
+    pub dust_fee_rate: f64,
+    pub warnings: Vec<Warning>
+}
+
+/// audit checks every wallet of `wallets` against its history in `history`,
+/// its outputs in `utxo` and the `pending` mempool transactions at `now`,
+/// judging dust at `fee_rate` sats per byte
+pub fn audit(wallets: &Wallets, utxo: &UTXOSet, history: &HistoryIndexer, pending: &[MempoolEntry], fee_rate: f64, now: u64) -> Result<WalletAudit> {
+    let mut addresses = wallets.get_all_address();
+    addresses.append(&mut wallets.get_all_watch_only());
+    addresses.sort();
+
+    let mut warnings = Vec::new();
+    for address in &addresses {
+        let pub_key_hash = address::decode(address)?;
+        if let Some(warning) = reuse(address, &pub_key_hash, utxo, history)? {
+            warnings.push(warning);
+        }
+        if let Some(public_key) = wallets.public_key(address) {
+            let threshold = fee_for(input_size(public_key)?, fee_rate);
+            if let Some(warning) = dust(address, &pub_key_hash, utxo, threshold)? {
+                warnings.push(warning);
+            }
+        }
+        if wallets.get_wallet(address).is_some() && wallets.backed_up(address).is_none() {
+            warnings.push(Warning {
+                issue: Issue::Unbacked,
+                address: address.clone(),
+                txid: None,
+                message: "no backup holds this key, run backupwallet and keep the file offline".to_string()
+            });
+        }
+    }
+    warnings.append(&mut stuck(wallets, pending, now));
+    Ok(WalletAudit { addresses: addresses.len(), dust_fee_rate: fee_rate, warnings })
+}
+
+/// reuse warns when more than one confirmed transaction paid `address`,
+/// change sent back to it included
+fn reuse(address: &str, pub_key_hash: &[u8], utxo: &UTXOSet, history: &HistoryIndexer) -> Result<Option<Warning>> {
+    // a wallet created after the node started is indexed on first use
+    history.watch(pub_key_hash.to_vec())?;
+    let Some(entries) = history.history(pub_key_hash)? else {
+        return Ok(None);
+    };
+    let mut payments = 0;
+    for entry in &entries {
+        let tx = utxo.blockchain.find_transaction(&entry.txid)?;
+        if tx.vout.iter().any(|out| out.pub_key_hash == pub_key_hash) {
+            payments += 1;
+        }
+    }
+    if payments < 2 {
+        return Ok(None);
+    }
+    Ok(Some(Warning {
+        issue: Issue::Reuse,
+        address: address.to_string(),
+        txid: None,
+        message: format!("{} transactions paid this address, which links them to one owner; hand every payer a fresh address from createwallet", payments)
+    }))
+}
+
+/// dust warns about the unspent outputs of `address` worth no more than the
+/// `threshold` fee of spending them
+fn dust(address: &str, pub_key_hash: &[u8], utxo: &UTXOSet, threshold: Amount) -> Result<Option<Warning>> {
+    let dust: Vec<Amount> = utxo.list_unspent(Some(pub_key_hash))?.iter().map(|out| out.value).filter(|value| *value <= threshold).collect();
+    if dust.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Warning {
+        issue: Issue::Dust,
+        address: address.to_string(),
+        txid: None,
+        message: format!(
+            "{} outputs worth {} in all are each worth no more than the {} fee of spending them; merge them with consolidate --fee-rate 0 while blocks have room",
+            dust.len(),
+            Amount::sum(dust.iter().copied())?,
+            threshold
+        )
+    }))
+}
+
+/// stuck warns about the `pending` transactions of `wallets` that entered the
+/// mempool STUCK_SECS or more before `now`
+fn stuck(wallets: &Wallets, pending: &[MempoolEntry], now: u64) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+    for entry in pending {
+        let waited = now.saturating_sub(entry.time);
+        if waited < STUCK_SECS {
+            continue;
+        }
+        // the spender is the one to speed it up, so inputs are looked at first
+        let mut keys: Vec<Vec<u8>> = entry.tx.vin.iter().map(|vin| vin.pub_key.clone()).collect();
+        keys.iter_mut().for_each(hash_pub_key);
+        keys.extend(entry.tx.vout.iter().map(|out| out.pub_key_hash.clone()));
+        let Some(address) = keys.iter().map(|hash| hash_to_address(hash)).find(|address| wallets.public_key(address).is_some()) else {
+            continue;
+        };
+        warnings.push(Warning {
+            issue: Issue::Stuck,
+            address,
+            txid: Some(entry.tx.id),
+            message: format!(
+                "unconfirmed for {} minutes at {:.2} sats per byte; spend one of its outputs in a package paying a higher fee rate with submitpackage",
+                waited / 60,
+                entry.fee_rate()
+            )
+        });
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ChainFixture;
+    use crate::transaction::Transaction;
+
+    #[test]
+    fn test_the_audit_reports_reuse_dust_stuck_and_unbacked_keys() -> Result<()> {
+        let fixture = ChainFixture::restore(3)?;
+        let bc = fixture.blockchain()?;
+        let utxo = UTXOSet { blockchain: bc.clone() };
+        let miner = fixture.miner().to_string();
+        let mut wallets = Wallets::new(&fixture.config())?;
+        let fresh = wallets.create_wallet();
+        wallets.save_all()?;
+
+        let tx = Transaction::new_UTXO(&wallets, &miner, &fresh, Amount::from_sat(1), Amount::from_sat(5), &utxo)?;
+        let coinbase = Transaction::new_coinbase(miner.clone(), "hygiene".to_string())?;
+        utxo.connect_block(&bc.mine_block(vec![coinbase, tx.clone()])?)?;
+        let mut keys = vec![address::decode(&miner)?];
+        let mut fresh_key = wallets.get_wallet(&fresh).map(|w| w.public_key.clone()).unwrap_or_default();
+        hash_pub_key(&mut fresh_key);
+        keys.push(fresh_key);
+        let history = HistoryIndexer::start(&bc, keys)?;
+
+        let mut waiting = MempoolEntry::new(tx, Amount::from_sat(5))?;
+        waiting.time = 0;
+        let report = audit(&wallets, &utxo, &history, &[waiting], DUST_FEE_RATE, STUCK_SECS)?;
+        let issues = |address: &str| report.warnings.iter().filter(|w| w.address == address).map(|w| w.issue).collect::<Vec<_>>();
+        assert_eq!(report.addresses, 2);
+        assert_eq!(issues(&miner), vec![Issue::Reuse, Issue::Unbacked, Issue::Stuck]);
+        assert_eq!(issues(&fresh), vec![Issue::Dust, Issue::Unbacked]);
+
+        wallets.mark_backed_up(1);
+        let report = audit(&wallets, &utxo, &history, &[], DUST_FEE_RATE, STUCK_SECS)?;
+        assert!(report.warnings.iter().all(|w| w.issue != Issue::Unbacked && w.issue != Issue::Stuck));
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::error::Result;
+
+const INTENTS_TREE: &str = "intents";
+const PENDING_KEY: &str = "PENDING";
+
+/// DEFAULT_TREE names the default sled tree, where blocks and LAST live
+pub const DEFAULT_TREE: &str = "";
+
+/// IntentOp is a single planned write against one tree of the database,
+/// `value: None` removes the key
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IntentOp {
+    pub tree: String,
+    pub key: Vec<u8>,
+    pub value: Option<Vec<u8>>
+}
+
+impl IntentOp {
+    pub fn insert(tree: &str, key: &[u8], value: Vec<u8>) -> IntentOp {
+        IntentOp {
+            tree: tree.to_string(),
+            key: key.to_vec(),
+            value: Some(value)
+        }
+    }
+
+    pub fn remove(tree: &str, key: &[u8]) -> IntentOp {
+        IntentOp {
+            tree: tree.to_string(),
+            key: key.to_vec(),
+            value: None
+        }
+    }
+}
+
+/// IntentLog records the mutations of a multi-tree update before applying them,
+/// so an update interrupted by a crash can be replayed on the next start
+#[derive(Debug, Clone)]
+pub struct IntentLog {
+    db: Arc<sled::Db>
+}
+
+impl IntentLog {
+    pub fn new(db: Arc<sled::Db>) -> IntentLog {
+        IntentLog { db }
+    }
+
+    /// Commit records the ops, applies them, then clears the record
+    pub fn commit(&self, ops: &[IntentOp]) -> Result<()> {
+        let log = self.db.open_tree(INTENTS_TREE)?;
+        log.insert(PENDING_KEY, bincode::serialize(ops)?)?;
+        log.flush()?;
+
+        self.apply(ops)?;
+        self.db.flush()?;
+
+        log.remove(PENDING_KEY)?;
+        log.flush()?;
+        Ok(())
+    }
+
+    /// commit_unflushed is commit without waiting for the disk, sled persists
+    /// writes in order so a crash loses a suffix of the commits, never half of one
+    pub fn commit_unflushed(&self, ops: &[IntentOp]) -> Result<()> {
+        let log = self.db.open_tree(INTENTS_TREE)?;
+        log.insert(PENDING_KEY, bincode::serialize(ops)?)?;
+        self.apply(ops)?;
+        log.remove(PENDING_KEY)?;
+        Ok(())
+    }
+
+    /// Recover replays an intent left behind by an interrupted commit,
+    /// every op is a full write so replaying an already applied op is harmless
+    pub fn recover(&self) -> Result<bool> {
+        let log = self.db.open_tree(INTENTS_TREE)?;
+        let pending = match log.get(PENDING_KEY)? {
+            Some(p) => p,
+            None => return Ok(false)
+        };
+
+        let ops: Vec<IntentOp> = bincode::deserialize(&pending)?;
+        info!("replaying incomplete intent with {} ops", ops.len());
+        self.apply(&ops)?;
+        self.db.flush()?;
+
+        log.remove(PENDING_KEY)?;
+        log.flush()?;
+        Ok(true)
+    }
+
+    fn apply(&self, ops: &[IntentOp]) -> Result<()> {
+        for op in ops {
+            let tree = self.tree(&op.tree)?;
+            match &op.value {
+                Some(v) => {
+                    tree.insert(&op.key, v.clone())?;
+                },
+                None => {
+                    tree.remove(&op.key)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn tree(&self, name: &str) -> Result<sled::Tree> {
+        if name == DEFAULT_TREE {
+            Ok((**self.db).clone())
+        } else {
+            Ok(self.db.open_tree(name)?)
+        }
+    }
+}
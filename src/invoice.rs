@@ -0,0 +1,137 @@
+//! Payment request URIs, like BIP 21:
+//!
+//! ```text
+//! toychain:mzZcEbAT6sr5MjgAgGREaTakSzGNT88xy9?amount=1.5&label=Lab%20fees&message=March
+//! ```
+//!
+//! The amount is in coins. Parameters starting with `req-` must be understood,
+//! so a URI with one this node does not know is refused, other unknown
+//! parameters are ignored.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::address;
+use crate::amount::Amount;
+use crate::error::{BlockchainError, Result};
+
+/// SCHEME names the payment URIs of this chain
+pub const SCHEME: &str = "toychain";
+
+/// PaymentRequest is what a payment URI asks for
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PaymentRequest {
+    pub address: String,
+    pub amount: Option<Amount>,
+    /// name of the payee, kept with the payment in the wallet history
+    pub label: Option<String>,
+    /// what the payment is for
+    pub message: Option<String>
+}
+
+impl fmt::Display for PaymentRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut params = Vec::new();
+        if let Some(amount) = self.amount {
+            params.push(format!("amount={}", amount));
+        }
+        if let Some(label) = &self.label {
+            params.push(format!("label={}", percent_encode(label)));
+        }
+        if let Some(message) = &self.message {
+            params.push(format!("message={}", percent_encode(message)));
+        }
+        write!(f, "{}:{}", SCHEME, self.address)?;
+        if !params.is_empty() {
+            write!(f, "?{}", params.join("&"))?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for PaymentRequest {
+    type Err = BlockchainError;
+
+    fn from_str(uri: &str) -> Result<PaymentRequest> {
+        let invalid = |reason: &str| BlockchainError::Config(format!("payment URI '{}' {}", uri, reason));
+        let rest = match uri.trim().split_once(':') {
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case(SCHEME) => rest,
+            _ => return Err(invalid(&format!("does not start with {}:", SCHEME)))
+        };
+        let (address, query) = rest.split_once('?').unwrap_or((rest, ""));
+        address::decode(address)?;
+
+        let mut request = PaymentRequest { address: address.to_string(), ..PaymentRequest::default() };
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            let value = percent_decode(value).ok_or_else(|| invalid(&format!("has a badly escaped {}", key)))?;
+            match key {
+                "amount" => request.amount = Some(value.parse()?),
+                "label" => request.label = Some(value),
+                "message" => request.message = Some(value),
+                key if key.starts_with("req-") => return Err(invalid(&format!("requires {}, which is not supported", key))),
+                _ => {}
+            }
+        }
+        Ok(request)
+    }
+}
+
+/// percent_encode escapes every byte of `text` but the unreserved characters
+fn percent_encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// percent_decode undoes percent_encode, reading `+` as a space like forms do,
+/// None when an escape is cut short or the text is not utf-8
+fn percent_decode(text: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(text.len());
+    let mut bytes = text.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            },
+            b'+' => decoded.push(b' '),
+            byte => decoded.push(byte)
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallet::hash_to_address;
+
+    #[test]
+    fn test_uris_round_trip() -> Result<()> {
+        let address = hash_to_address(&[3; 20]);
+        let request = PaymentRequest {
+            address: address.clone(),
+            amount: Some("1.5".parse()?),
+            label: Some("Lab fees & co".to_string()),
+            message: Some("março".to_string())
+        };
+        let uri = request.to_string();
+        assert_eq!(uri, format!("toychain:{}?amount=1.50&label=Lab%20fees%20%26%20co&message=mar%C3%A7o", address));
+        assert_eq!(uri.parse::<PaymentRequest>()?, request);
+
+        let bare: PaymentRequest = format!("TOYCHAIN:{}?label=a+b&foo=bar", address).parse()?;
+        assert_eq!((bare.amount, bare.label.as_deref()), (None, Some("a b")));
+        assert!(format!("toychain:{}?req-expires=5", address).parse::<PaymentRequest>().is_err());
+        assert!(format!("bitcoin:{}", address).parse::<PaymentRequest>().is_err());
+        assert!(format!("toychain:{}?label=%4", address).parse::<PaymentRequest>().is_err());
+        assert!("toychain:nope".parse::<PaymentRequest>().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+use serde_json::{json, Value};
+
+use crate::addrindex::AddressActivity;
+use crate::amount::Amount;
+use crate::block::{Block, BlockHeader};
+use crate::blockchain::Blockchain;
+use crate::error::Result;
+use crate::hash::Hash256;
+use crate::miner::BlockTemplate;
+use crate::names::NameEntry;
+use crate::target::Target;
+use crate::transaction::Transaction;
+use crate::wallet::{hash_pub_key, hash_to_address};
+
+/// block_json describes a block with its txids, in the bitcoind field names, and
+/// the coinbase data as text, which carries the message of the miner
+pub fn block_json(block: &Block) -> Value {
+    let txids: Vec<Hash256> = block.get_transactions().iter().map(|tx| tx.id).collect();
+    let coinbase = block
+        .get_transactions()
+        .iter()
+        .find(|tx| tx.is_coinbase())
+        .map(|tx| String::from_utf8_lossy(&tx.vin[0].pub_key).into_owned());
+    json!({
+        "hash": block.get_hash(),
+        "height": block.get_height(),
+        "time": block.get_timestamp() as u64,
+        "nonce": block.get_nonce(),
+        "bits": format!("{:08x}", block.get_bits()),
+        "difficulty": Target::from_compact(block.get_bits()).map_or(0.0, Target::difficulty),
+        "previousblockhash": block.get_prev_hash(),
+        "coinbase": coinbase,
+        "tx": txids
+    })
+}
+
+/// chain_block_json describes a block of `bc` with the total work of the chain
+/// ending at it, null while an ancestor is missing
+pub fn chain_block_json(bc: &Blockchain, block: &Block) -> Result<Value> {
+    let mut view = block_json(block);
+    view["chainwork"] = json!(bc.chain_work(&block.get_hash())?.map(|work| work.to_string()));
+    Ok(view)
+}
+
+/// template_json describes a block template for an external miner, who sets the
+/// nonce in the header, whose hash is the sha256 of `headerprefix` followed by the
+/// nonce as 4 little endian bytes, and submits the bincode encoded block
+pub fn template_json(template: &BlockTemplate, curtime: u128) -> Result<Value> {
+    let mut transactions = Vec::new();
+    for tx in &template.transactions[..template.transactions.len() - 1] {
+        transactions.push(json!({
+            "txid": tx.id,
+            "data": hex::encode(bincode::serialize(tx)?)
+        }));
+    }
+    let coinbase = &template.transactions[template.transactions.len() - 1];
+    let header_prefix = Block::header_prefix(&template.prev_block_hash, &template.merkle_root, curtime, template.bits)?;
+
+    Ok(json!({
+        "previousblockhash": template.prev_block_hash,
+        "height": template.height,
+        "curtime": curtime as u64,
+        "target": Target::from_compact(template.bits)?.to_string(),
+        "bits": format!("{:08x}", template.bits),
+        "merkleroot": hex::encode(&template.merkle_root),
+        "headerprefix": hex::encode(header_prefix),
+        "fees": template.fees,
+        "coinbasevalue": Amount::sum(coinbase.vout.iter().map(|out| out.value))?,
+        "coinbasetxn": {
+            "txid": coinbase.id,
+            "data": hex::encode(bincode::serialize(coinbase)?)
+        },
+        "transactions": transactions
+    }))
+}
+
+/// header_json describes a stored header like block_json without the txids
+pub fn header_json(header: &BlockHeader) -> Value {
+    json!({
+        "hash": header.hash,
+        "height": header.height,
+        "time": header.timestamp as u64,
+        "nonce": header.nonce,
+        "bits": format!("{:08x}", header.bits),
+        "difficulty": Target::from_compact(header.bits).map_or(0.0, Target::difficulty),
+        "previousblockhash": header.prev_block_hash
+    })
+}
+
+/// activity_json describes an address index entry with its confirmations
+/// under a tip at `best_height`
+pub fn activity_json(activity: &AddressActivity, best_height: usize) -> Value {
+    json!({
+        "txid": activity.txid,
+        "height": activity.height,
+        "blockhash": activity.block_hash,
+        "delta": activity.delta,
+        "confirmations": (best_height + 1).saturating_sub(activity.height)
+    })
+}
+
+/// name_json describes the registry entry of `name`, None when it is free
+pub fn name_json(name: &str, entry: Option<&NameEntry>) -> Value {
+    match entry {
+        Some(entry) => json!({
+            "name": name,
+            "value": entry.value,
+            "owner": hash_to_address(&entry.owner),
+            "txid": entry.outpoint.txid,
+            "height": entry.height,
+            "expiresat": entry.expires_at()
+        }),
+        None => json!({ "name": name, "registered": false })
+    }
+}
+
+/// tx_json decodes a transaction with the address of every input and output
+pub fn tx_json(tx: &Transaction) -> Value {
+    let mut vin = Vec::new();
+    for input in &tx.vin {
+        if tx.is_coinbase() {
+            vin.push(json!({ "coinbase": hex::encode(&input.pub_key) }));
+        } else {
+            let mut pub_key_hash = input.pub_key.clone();
+            hash_pub_key(&mut pub_key_hash);
+            vin.push(json!({
+                "txid": input.prev_out.txid,
+                "vout": input.prev_out.index,
+                "address": hash_to_address(&pub_key_hash)
+            }));
+        }
+    }
+
+    let mut vout = Vec::new();
+    for (n, out) in tx.vout.iter().enumerate() {
+        vout.push(match out.data() {
+            Some(data) => json!({
+                "n": n,
+                "value": out.value,
+                "data": hex::encode(data)
+            }),
+            None => json!({
+                "n": n,
+                "value": out.value,
+                "address": hash_to_address(&out.pub_key_hash)
+            })
+        });
+    }
+
+    json!({
+        "txid": tx.id,
+        "vin": vin,
+        "vout": vout
+    })
+}
@@ -0,0 +1,96 @@
+//! A small proof of work blockchain for learning: blocks, UTXO transactions,
+//! wallets and a P2P node, usable as a library or through the `blockchain_project` CLI.
+//!
+//! ```no_run
+//! use blockchain_project::{Blockchain, Config, UTXOSet};
+//!
+//! let config = Config::load(None)?;
+//! let utxo_set = UTXOSet { blockchain: Blockchain::new(&config)? };
+//! println!("{} transactions with unspent outputs", utxo_set.count_transactions()?);
+//! # Ok::<(), blockchain_project::error::BlockchainError>(())
+//! ```
+
+pub mod address;
+pub mod addrindex;
+pub mod addrman;
+pub mod amount;
+pub mod banlist;
+pub mod block;
+pub mod blockchain;
+pub mod blockfilter;
+pub mod bloom;
+pub mod chainstats;
+pub mod cli;
+pub mod codec;
+pub mod config;
+pub mod consolidate;
+pub mod control;
+pub mod daemon;
+pub mod dialer;
+pub mod doublespend;
+pub mod error;
+pub mod eventlog;
+pub mod events;
+#[cfg(feature = "explorer")]
+pub mod explorer;
+pub mod faucet;
+pub mod fees;
+pub mod fixtures;
+pub mod grpc;
+pub mod hash;
+pub mod history;
+pub mod hygiene;
+pub mod intent;
+pub mod invoice;
+pub mod json;
+pub mod mempool;
+pub mod miner;
+pub mod names;
+pub mod netaddr;
+pub mod node;
+pub mod notary;
+pub mod offline;
+pub mod outbound;
+pub mod package;
+pub mod progress;
+pub mod projection;
+pub mod qr;
+pub mod ratelimit;
+pub mod replay;
+pub mod rest;
+pub mod rotate;
+pub mod rpc;
+pub mod schedule;
+pub mod seen;
+pub mod server;
+#[cfg(any(test, feature = "simulate"))]
+pub mod simulate;
+pub mod spv;
+pub mod storage;
+pub mod stratum;
+pub mod supply;
+pub mod target;
+#[cfg(any(test, feature = "test-support"))]
+pub mod testing;
+#[cfg(any(test, feature = "test-support"))]
+pub mod testkit;
+pub mod token;
+pub mod transaction;
+pub mod tx;
+pub mod utxoset;
+pub mod wallet;
+pub mod watch;
+pub mod ws;
+pub mod zmq;
+
+pub use amount::Amount;
+pub use block::{Block, BlockHeader};
+pub use blockchain::Blockchain;
+pub use config::Config;
+pub use events::{Event, EventBus};
+pub use hash::Hash256;
+pub use node::{Node, NodeBuilder};
+pub use server::Server;
+pub use transaction::Transaction;
+pub use utxoset::UTXOSet;
+pub use wallet::Wallets;
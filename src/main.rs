@@ -1,21 +1,19 @@
+use std::process::exit;
 
-mod block;
-mod blockchain;
-mod error;
-mod cli;
-mod transaction;
-mod tx;
-mod wallet;
-mod utxoset;
-mod server;
+use blockchain_project::cli::Cli;
 
-use cli::Cli;
-use error::Result;
-fn main() -> Result<()> {
+fn main() {
 
-    let mut cli = Cli::new()?;
+    let mut cli = match Cli::new() {
+        Ok(cli) => cli,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            exit(1)
+        }
+    };
 
-    cli.run()?;
-
-    Ok(())
+    if let Err(e) = cli.run() {
+        eprintln!("Error: {}", e);
+        exit(1)
+    }
 }
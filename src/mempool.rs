@@ -0,0 +1,394 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io::ErrorKind;
+use std::mem::size_of;
+use std::path::Path;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::amount::Amount;
+use crate::error::{BlockchainError, Result};
+use crate::hash::Hash256;
+use crate::transaction::Transaction;
+use crate::tx::{OutPoint, TXInput, TXOutput};
+
+/// MEMPOOL_FILE_VERSION is written first in `mempool.dat`, a file of another
+/// version is not loaded
+const MEMPOOL_FILE_VERSION: u32 = 1;
+
+/// MempoolEntry is an unconfirmed transaction with the data needed to rank it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MempoolEntry {
+    pub tx: Transaction,
+    pub fee: Amount,
+    pub size: usize,
+    /// entry time in seconds since the unix epoch
+    pub time: u64
+}
+
+impl MempoolEntry {
+    pub fn new(tx: Transaction, fee: Amount) -> Result<MempoolEntry> {
+        let size = bincode::serialize(&tx)?.len();
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs();
+
+        Ok(MempoolEntry {
+            tx,
+            fee,
+            size,
+            time
+        })
+    }
+
+    /// fee_rate is the fee paid per serialized byte
+    pub fn fee_rate(&self) -> f64 {
+        self.fee.to_sat() as f64 / self.size as f64
+    }
+}
+
+/// FeeRateKey orders the entries by fee rate, lowest first, comparing fee/size
+/// as exact fractions and then by txid
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FeeRateKey {
+    fee: i32,
+    size: usize,
+    txid: Hash256
+}
+
+impl FeeRateKey {
+    fn new(entry: &MempoolEntry) -> FeeRateKey {
+        FeeRateKey { fee: entry.fee.to_sat(), size: entry.size, txid: entry.tx.id }
+    }
+}
+
+impl Ord for FeeRateKey {
+    fn cmp(&self, other: &FeeRateKey) -> Ordering {
+        let rate = self.fee as i128 * other.size as i128;
+        let other_rate = other.fee as i128 * self.size as i128;
+        rate.cmp(&other_rate).then_with(|| self.txid.cmp(&other.txid))
+    }
+}
+
+impl PartialOrd for FeeRateKey {
+    fn partial_cmp(&self, other: &FeeRateKey) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// memory_usage estimates the memory an entry takes in the pool: the entry, the
+/// heap data of its transaction and its slots in every index
+fn memory_usage(entry: &MempoolEntry) -> usize {
+    let tx = &entry.tx;
+    let inputs: usize = tx.vin.iter().map(|vin| size_of::<TXInput>() + vin.signature.len() + vin.pub_key.len()).sum();
+    let outputs: usize = tx.vout.iter().map(|out| size_of::<TXOutput>() + out.pub_key_hash.len()).sum();
+    let entry_slot = size_of::<Hash256>() + size_of::<MempoolEntry>();
+    let spend_slots = tx.vin.len() * (size_of::<OutPoint>() + size_of::<Hash256>());
+    // a BTreeSet element plus its share of the node
+    let fee_rate_slot = 2 * size_of::<FeeRateKey>();
+    entry_slot + inputs + outputs + spend_slots + fee_rate_slot
+}
+
+/// Mempool holds the transactions waiting to be mined, keyed by txid, within an
+/// optional cap on the memory it uses
+#[derive(Debug, Clone, Default)]
+pub struct Mempool {
+    entries: HashMap<Hash256, MempoolEntry>,
+    /// txid of the entry spending each output
+    spends: HashMap<OutPoint, Hash256>,
+    by_fee_rate: BTreeSet<FeeRateKey>,
+    /// serialized size of the transactions
+    bytes: usize,
+    /// estimated memory of the entries and indexes
+    usage: usize,
+    /// memory cap, None for an unbounded pool
+    max_usage: Option<usize>
+}
+
+impl Mempool {
+    pub fn new() -> Mempool {
+        Mempool::default()
+    }
+
+    /// with_max_usage creates a pool evicting its lowest fee rate transactions
+    /// once its memory usage passes `max_usage` bytes
+    pub fn with_max_usage(max_usage: usize) -> Mempool {
+        Mempool {
+            max_usage: Some(max_usage),
+            ..Mempool::default()
+        }
+    }
+
+    /// insert adds an entry and, when the pool is over its cap, evicts the lowest
+    /// fee rate entries with the transactions spending them until it fits again.
+    /// The evicted txids are returned, they include the new entry when it pays
+    /// the lowest fee rate
+    pub fn insert(&mut self, entry: MempoolEntry) -> Vec<Hash256> {
+        self.add(entry);
+
+        let mut evicted = Vec::new();
+        while self.max_usage.is_some_and(|max| self.usage > max) {
+            let Some(lowest) = self.by_fee_rate.first().map(|key| key.txid) else {
+                break;
+            };
+            evicted.append(&mut self.remove_with_descendants(&lowest));
+        }
+        evicted
+    }
+
+    /// insert_package adds the entries of a package and evicts like `insert`,
+    /// except that the package is ranked by its total fee over its total size,
+    /// so a child pays for its parents. The whole package is evicted when it
+    /// pays the lowest rate
+    pub fn insert_package(&mut self, entries: Vec<MempoolEntry>) -> Vec<Hash256> {
+        let members: Vec<Hash256> = entries.iter().map(|entry| entry.tx.id).collect();
+        let fee: i64 = entries.iter().map(|entry| i64::from(entry.fee.to_sat())).sum();
+        let size: usize = entries.iter().map(|entry| entry.size).sum();
+        for entry in entries {
+            self.add(entry);
+        }
+
+        let mut evicted = Vec::new();
+        while self.max_usage.is_some_and(|max| self.usage > max) {
+            let pooled = members.iter().any(|txid| self.contains(txid));
+            let lowest = self.by_fee_rate.iter().find(|key| !members.contains(&key.txid)).copied();
+            match lowest {
+                // fee / size against key.fee / key.size, compared as exact fractions
+                Some(key) if !pooled || fee * key.size as i64 >= i64::from(key.fee) * size as i64 => {
+                    evicted.append(&mut self.remove_with_descendants(&key.txid));
+                },
+                _ if pooled => {
+                    for txid in &members {
+                        evicted.append(&mut self.remove_with_descendants(txid));
+                    }
+                },
+                _ => break
+            }
+        }
+        // a package losing a parent to the eviction of its ancestors goes whole
+        if members.iter().any(|txid| evicted.contains(txid)) {
+            for txid in &members {
+                evicted.append(&mut self.remove_with_descendants(txid));
+            }
+        }
+        evicted
+    }
+
+    /// add adds an entry without evicting any
+    fn add(&mut self, entry: MempoolEntry) {
+        self.remove(&entry.tx.id);
+        if !entry.tx.is_coinbase() {
+            for vin in &entry.tx.vin {
+                self.spends.insert(vin.prev_out, entry.tx.id);
+            }
+        }
+        self.bytes += entry.size;
+        self.usage += memory_usage(&entry);
+        self.by_fee_rate.insert(FeeRateKey::new(&entry));
+        self.entries.insert(entry.tx.id, entry);
+    }
+
+    pub fn get(&self, txid: &Hash256) -> Option<&MempoolEntry> {
+        self.entries.get(txid)
+    }
+
+    pub fn contains(&self, txid: &Hash256) -> bool {
+        self.entries.contains_key(txid)
+    }
+
+    pub fn remove(&mut self, txid: &Hash256) -> Option<MempoolEntry> {
+        let entry = self.entries.remove(txid)?;
+        for vin in &entry.tx.vin {
+            if self.spends.get(&vin.prev_out) == Some(txid) {
+                self.spends.remove(&vin.prev_out);
+            }
+        }
+        self.by_fee_rate.remove(&FeeRateKey::new(&entry));
+        self.bytes -= entry.size;
+        self.usage -= memory_usage(&entry);
+        Some(entry)
+    }
+
+    /// remove_with_descendants removes `txid` and every pool transaction spending
+    /// its outputs, directly or not, and returns their txids
+    pub fn remove_with_descendants(&mut self, txid: &Hash256) -> Vec<Hash256> {
+        let mut removed = Vec::new();
+        let mut stack = vec![*txid];
+        while let Some(id) = stack.pop() {
+            if let Some(entry) = self.remove(&id) {
+                for index in 0..entry.tx.vout.len() {
+                    if let Some(child) = self.spender(&entry.tx.outpoint(index)) {
+                        stack.push(child);
+                    }
+                }
+                removed.push(id);
+            }
+        }
+        removed
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.spends.clear();
+        self.by_fee_rate.clear();
+        self.bytes = 0;
+        self.usage = 0;
+    }
+
+    /// spender is the txid of the entry spending `outpoint`
+    pub fn spender(&self, outpoint: &OutPoint) -> Option<Hash256> {
+        self.spends.get(outpoint).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &MempoolEntry> {
+        self.entries.values()
+    }
+
+    pub fn txids(&self) -> Vec<Hash256> {
+        self.entries.keys().copied().collect()
+    }
+
+    /// total_bytes is the serialized size of all transactions in the pool
+    pub fn total_bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// usage is the estimated memory of the pool, entries and indexes included
+    pub fn usage(&self) -> usize {
+        self.usage
+    }
+
+    /// max_usage is the memory cap of the pool, None when it is unbounded
+    pub fn max_usage(&self) -> Option<usize> {
+        self.max_usage
+    }
+
+    /// min_fee_rate is the lowest fee rate in the pool, 0 when it is empty
+    pub fn min_fee_rate(&self) -> f64 {
+        self.by_fee_rate
+            .first()
+            .and_then(|key| self.entries.get(&key.txid))
+            .map_or(0.0, |e| e.fee_rate())
+    }
+
+    /// ancestors returns the unconfirmed transactions `txid` depends on, directly or not
+    pub fn ancestors(&self, txid: &Hash256) -> Vec<Hash256> {
+        let mut found = HashSet::new();
+        let mut stack = vec![*txid];
+
+        while let Some(id) = stack.pop() {
+            if let Some(entry) = self.entries.get(&id) {
+                for vin in &entry.tx.vin {
+                    let parent = vin.prev_out.txid;
+                    if self.entries.contains_key(&parent) && found.insert(parent) {
+                        stack.push(parent);
+                    }
+                }
+            }
+        }
+
+        found.into_iter().collect()
+    }
+
+    /// save writes the entries to `path`, each after the pool transactions it
+    /// spends, through a temporary file so a crash leaves the old file whole
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut entries: Vec<&MempoolEntry> = self.entries.values().collect();
+        entries.sort_by_cached_key(|e| (e.time, self.ancestors(&e.tx.id).len(), e.tx.id));
+        let data = bincode::serialize(&(MEMPOOL_FILE_VERSION, entries))?;
+        let tmp = path.with_extension("dat.new");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// load reads the entries `save` wrote to `path`, none when there is no file
+    pub fn load(path: &Path) -> Result<Vec<MempoolEntry>> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into())
+        };
+        let (version, entries): (u32, Vec<MempoolEntry>) = bincode::deserialize(&data)?;
+        if version != MEMPOOL_FILE_VERSION {
+            return Err(BlockchainError::Config(format!("{} has version {}, expected {}", path.display(), version, MEMPOOL_FILE_VERSION)));
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(prev_out: OutPoint, fee: i32) -> MempoolEntry {
+        let mut tx = Transaction {
+            id: Hash256::ZERO,
+            vin: vec![TXInput { prev_out, signature: vec![0; 64], pub_key: vec![0; 32] }],
+            vout: vec![TXOutput { value: Amount::from_sat(100), pub_key_hash: vec![0; 20] }]
+        };
+        tx.id = tx.hash().unwrap();
+        MempoolEntry::new(tx, Amount::from_sat(fee)).unwrap()
+    }
+
+    #[test]
+    fn test_usage_is_capped_by_evicting_the_lowest_fee_rate() {
+        let parent = entry(OutPoint::new(Hash256::new([1; 32]), 0), 10);
+        let child = entry(parent.tx.outpoint(0), 50);
+        let rich = entry(OutPoint::new(Hash256::new([2; 32]), 0), 40);
+        let poor = entry(OutPoint::new(Hash256::new([3; 32]), 0), 1);
+        let per_entry = memory_usage(&parent);
+
+        let mut unbounded = Mempool::new();
+        assert!(unbounded.insert(parent.clone()).is_empty());
+        assert_eq!(unbounded.usage(), per_entry);
+        assert_eq!(unbounded.total_bytes(), parent.size);
+        unbounded.remove(&parent.tx.id);
+        assert_eq!((unbounded.usage(), unbounded.total_bytes()), (0, 0));
+
+        // room for two entries: the parent has the lowest rate and takes its child along
+        let mut pool = Mempool::with_max_usage(2 * per_entry);
+        pool.insert(parent.clone());
+        pool.insert(child.clone());
+        assert_eq!(pool.insert(rich.clone()), vec![parent.tx.id, child.tx.id]);
+        assert!(pool.contains(&rich.tx.id) && pool.spender(&parent.tx.outpoint(0)).is_none());
+        assert_eq!(pool.usage(), per_entry);
+
+        pool.insert(entry(OutPoint::new(Hash256::new([4; 32]), 0), 20));
+        assert_eq!(pool.insert(poor.clone()), vec![poor.tx.id]);
+        assert_eq!(pool.len(), 2);
+        assert!(pool.usage() <= 2 * per_entry);
+        assert_eq!(pool.min_fee_rate(), 20.0 / parent.size as f64);
+    }
+
+    #[test]
+    fn test_a_child_pays_for_its_parent_in_a_package() {
+        let parent = entry(OutPoint::new(Hash256::new([1; 32]), 0), 1);
+        let child = entry(parent.tx.outpoint(0), 50);
+        let middle = entry(OutPoint::new(Hash256::new([2; 32]), 0), 20);
+        let per_entry = memory_usage(&parent);
+
+        // alone the parent would go first, with the child it outbids the middle entry
+        let mut pool = Mempool::with_max_usage(2 * per_entry);
+        pool.insert(middle.clone());
+        assert_eq!(pool.insert_package(vec![parent.clone(), child.clone()]), vec![middle.tx.id]);
+        assert!(pool.contains(&parent.tx.id) && pool.contains(&child.tx.id));
+
+        let poor_child = entry(parent.tx.outpoint(0), 2);
+        let mut pool = Mempool::with_max_usage(2 * per_entry);
+        pool.insert(middle.clone());
+        let evicted = pool.insert_package(vec![parent.clone(), poor_child.clone()]);
+        assert!(evicted.contains(&parent.tx.id) && evicted.contains(&poor_child.tx.id));
+        assert_eq!(pool.txids(), vec![middle.tx.id]);
+    }
+}
@@ -0,0 +1,684 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rayon::prelude::*;
+use tracing::{debug, info};
+
+use crate::amount::Amount;
+use crate::block::{Block, PowOptions};
+use crate::blockchain::Blockchain;
+use crate::config::Config;
+use crate::error::{BlockchainError, Result};
+use crate::events::Event;
+use crate::hash::Hash256;
+use crate::mempool::{Mempool, MempoolEntry};
+use crate::server::Server;
+use crate::target::Target;
+use crate::transaction::{Transaction, SUBSIDY};
+use crate::tx::OutPoint;
+use crate::utxoset::UTXOSet;
+use crate::wallet::hash_pub_key;
+
+/// MAX_BLOCK_BYTES caps the serialized size of the transactions a template selects
+pub const MAX_BLOCK_BYTES: usize = 1_000_000;
+
+/// MAX_COINBASE_MSG caps the bytes a miner may add to its coinbase data
+pub const MAX_COINBASE_MSG: usize = 64;
+
+/// EVENT_POLL is how often sealing checks whether it finished between events
+const EVENT_POLL: Duration = Duration::from_millis(50);
+
+/// HASHRATE_WINDOW is how many recent blocks the network hashrate is estimated from
+pub const HASHRATE_WINDOW: usize = 10;
+
+/// MiningStats counts the work of the miners of a node since it started
+#[derive(Debug, Default)]
+pub struct MiningStats {
+    hashes: AtomicU64,
+    sealing_nanos: AtomicU64,
+    sealing: AtomicUsize,
+    blocks_found: AtomicU64,
+    fees_earned: AtomicI64
+}
+
+impl MiningStats {
+    /// is_mining tells whether a block is being sealed right now
+    pub fn is_mining(&self) -> bool {
+        self.sealing.load(Ordering::Relaxed) > 0
+    }
+
+    /// hashrate is the hashes per second over the time spent sealing
+    pub fn hashrate(&self) -> f64 {
+        let secs = self.sealing_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+        if secs == 0.0 {
+            return 0.0;
+        }
+        self.hashes.load(Ordering::Relaxed) as f64 / secs
+    }
+
+    pub fn hashes(&self) -> u64 {
+        self.hashes.load(Ordering::Relaxed)
+    }
+
+    pub fn blocks_found(&self) -> u64 {
+        self.blocks_found.load(Ordering::Relaxed)
+    }
+
+    /// fees_earned is the total of the fees collected by the blocks found,
+    /// an error once it no longer fits an amount
+    pub fn fees_earned(&self) -> Result<Amount> {
+        let fees = self.fees_earned.load(Ordering::Relaxed);
+        i32::try_from(fees).map(Amount::from_sat).map_err(|_| BlockchainError::InvalidAmount(format!("{} sats of fees earned overflow an amount", fees)))
+    }
+
+    pub(crate) fn record_block(&self, fees: Amount) {
+        self.blocks_found.fetch_add(1, Ordering::Relaxed);
+        self.fees_earned.fetch_add(fees.to_sat() as i64, Ordering::Relaxed);
+    }
+}
+
+/// MinerSettings are the mining options of a node, `setgenerate` changes them at runtime
+#[derive(Debug)]
+pub struct MinerSettings {
+    generate: AtomicBool,
+    threads: AtomicUsize,
+    nice: bool,
+    min_txs: usize,
+    max_wait: Duration,
+    coinbase_msg: String
+}
+
+impl MinerSettings {
+    /// new starts generating when the config has a mining address
+    pub fn new(config: &Config) -> MinerSettings {
+        MinerSettings {
+            generate: AtomicBool::new(!config.mining_address.is_empty()),
+            threads: AtomicUsize::new(config.mine_threads),
+            nice: config.mine_nice,
+            min_txs: config.mine_min_txs,
+            max_wait: Duration::from_secs(config.mine_max_wait),
+            coinbase_msg: config.coinbase_msg.clone()
+        }
+    }
+
+    /// generate tells whether the node mines the transactions it receives
+    pub fn generate(&self) -> bool {
+        self.generate.load(Ordering::Relaxed)
+    }
+
+    /// set_generate turns mining on or off, `threads` replaces the worker count when set
+    pub fn set_generate(&self, generate: bool, threads: Option<usize>) {
+        self.generate.store(generate, Ordering::Relaxed);
+        if let Some(threads) = threads {
+            self.threads.store(threads, Ordering::Relaxed);
+        }
+    }
+
+    /// pow_options are the options the next block is sealed with
+    pub fn pow_options(&self) -> PowOptions {
+        PowOptions {
+            threads: self.threads.load(Ordering::Relaxed),
+            nice: self.nice
+        }
+    }
+
+    /// mempool_wait is how many transactions mining the mempool waits for, and for
+    /// how long at most, before it seals a block
+    pub fn mempool_wait(&self) -> (usize, Duration) {
+        (self.min_txs, self.max_wait)
+    }
+
+    /// coinbase_msg is the tag the miner signs its coinbases with
+    pub fn coinbase_msg(&self) -> &str {
+        &self.coinbase_msg
+    }
+}
+
+/// network_hashrate estimates the hashes per second of the whole network from the
+/// time the last `window` blocks took and the work their targets needed on average
+pub fn network_hashrate(blockchain: &Blockchain, window: usize) -> Result<f64> {
+    let mut blocks = Vec::new();
+    for header in blockchain.iter_headers().take(window + 1) {
+        blocks.push((header.timestamp, Target::from_compact(header.bits)?.work()));
+    }
+    let (newest, oldest) = match (blocks.first(), blocks.last()) {
+        (Some(newest), Some(oldest)) if newest.0 > oldest.0 => (newest.0, oldest.0),
+        _ => return Ok(0.0)
+    };
+
+    // the oldest block only marks when the window started
+    let work: f64 = blocks[..blocks.len() - 1].iter().map(|(_, work)| work).sum();
+    Ok(work / ((newest - oldest) as f64 / 1000.0))
+}
+
+/// BlockTemplate is the next block before its proof of work: the mempool
+/// transactions it mines and, last, a coinbase collecting their fees
+#[derive(Debug, Clone)]
+pub struct BlockTemplate {
+    pub prev_block_hash: Hash256,
+    pub height: usize,
+    /// compact target the block must meet
+    pub bits: u32,
+    pub transactions: Vec<Transaction>,
+    pub fees: Amount,
+    pub merkle_root: Vec<u8>
+}
+
+impl BlockTemplate {
+    /// assemble builds the block at `height` on the chain tip, selecting mempool
+    /// transactions by fee rate, each after the unconfirmed ones it spends, and
+    /// paying the subsidy plus their fees to `mining_address` with a coinbase
+    /// carrying `coinbase_msg`
+    pub fn assemble(mempool: &Mempool, utxo: &UTXOSet, height: usize, mining_address: &str, coinbase_msg: &str) -> Result<BlockTemplate> {
+        let mut candidates: Vec<&MempoolEntry> = mempool.entries().collect();
+        candidates.sort_by(|a, b| {
+            b.fee_rate()
+                .total_cmp(&a.fee_rate())
+                .then_with(|| a.time.cmp(&b.time))
+                .then_with(|| a.tx.id.cmp(&b.tx.id))
+        });
+
+        let mut selection = Selection {
+            mempool,
+            utxo,
+            transactions: Vec::new(),
+            selected: HashMap::new(),
+            rejected: HashSet::new(),
+            spent: HashSet::new(),
+            fees: Amount::ZERO,
+            bytes: 0
+        };
+        for entry in candidates {
+            selection.select(&entry.tx.id)?;
+        }
+
+        let fees = selection.fees;
+        let mut transactions = selection.transactions;
+        // the height keeps the coinbase txids of consecutive empty blocks apart
+        let data = match coinbase_msg {
+            "" => format!("height {}", height),
+            msg => format!("height {} {}", height, msg)
+        };
+        transactions.push(Transaction::new_coinbase_with_fees(mining_address.to_string(), data, fees)?);
+        let merkle_root = Block::merkle_root(&transactions)?;
+
+        Ok(BlockTemplate {
+            prev_block_hash: utxo.blockchain.get_tip(),
+            height,
+            bits: utxo.blockchain.next_bits()?,
+            transactions,
+            fees,
+            merkle_root
+        })
+    }
+
+    /// solve builds the block of the template from the header time and nonce a
+    /// worker found
+    pub fn solve(&self, timestamp: u128, nonce: i32) -> Result<Block> {
+        Block::from_solution(self.transactions.clone(), self.prev_block_hash, self.height, self.bits, timestamp, nonce)
+    }
+
+    /// is_empty tells whether the template mines nothing but its coinbase
+    pub fn is_empty(&self) -> bool {
+        self.transactions.len() <= 1
+    }
+}
+
+/// Selection is the state of `BlockTemplate::assemble` while it walks the mempool
+struct Selection<'a> {
+    mempool: &'a Mempool,
+    utxo: &'a UTXOSet,
+    transactions: Vec<Transaction>,
+    /// the selected transactions by txid, whose outputs later ones may spend
+    selected: HashMap<Hash256, Transaction>,
+    rejected: HashSet<Hash256>,
+    spent: HashSet<OutPoint>,
+    fees: Amount,
+    bytes: usize
+}
+
+impl Selection<'_> {
+    /// select adds `txid` after its unconfirmed parents and tells whether it made it in
+    fn select(&mut self, txid: &Hash256) -> Result<bool> {
+        if self.selected.contains_key(txid) {
+            return Ok(true);
+        }
+        if self.rejected.contains(txid) {
+            return Ok(false);
+        }
+        let mempool = self.mempool;
+        let entry = match mempool.get(txid) {
+            Some(entry) => entry,
+            None => return Ok(false)
+        };
+
+        for vin in &entry.tx.vin {
+            let parent = vin.prev_out.txid;
+            if mempool.contains(&parent) && !self.select(&parent)? {
+                debug!("skip {}, its parent {} is not selected", txid, parent);
+                self.rejected.insert(*txid);
+                return Ok(false);
+            }
+        }
+
+        if self.bytes + entry.size > MAX_BLOCK_BYTES || self.input_value(&entry.tx)?.is_none() {
+            self.rejected.insert(*txid);
+            return Ok(false);
+        }
+
+        self.push(&entry.tx, entry.fee, entry.size)?;
+        Ok(true)
+    }
+
+    /// push adds a valid transaction to the selection
+    fn push(&mut self, tx: &Transaction, fee: Amount, size: usize) -> Result<()> {
+        for vin in &tx.vin {
+            self.spent.insert(vin.prev_out);
+        }
+        self.fees = self.fees.try_add(fee)?;
+        self.bytes += size;
+        self.transactions.push(tx.clone());
+        self.selected.insert(tx.id, tx.clone());
+        Ok(())
+    }
+
+    /// input_value checks that `tx` spends outputs left unspent by the chain and by
+    /// the transactions selected so far, with valid signatures, and returns their
+    /// total or None when it is not valid
+    fn input_value(&self, tx: &Transaction) -> Result<Option<Amount>> {
+        let Some((total, prev_txs)) = self.inputs(tx)? else {
+            return Ok(None);
+        };
+        if !tx.clone().verify(prev_txs)? {
+            debug!("skip {}, its signature is invalid", tx.id);
+            return Ok(None);
+        }
+        Ok(Some(total))
+    }
+
+    /// inputs checks that `tx` spends outputs left unspent by the chain and by the
+    /// transactions selected so far, without checking its signatures, and returns
+    /// their total with the transactions they belong to, or None when it does not
+    fn inputs(&self, tx: &Transaction) -> Result<Option<(Amount, HashMap<Hash256, Transaction>)>> {
+        if tx.is_coinbase() {
+            return Ok(None);
+        }
+
+        let mut prev_txs = HashMap::new();
+        let mut spends = HashSet::new();
+        let mut total = Amount::ZERO;
+        for vin in &tx.vin {
+            if self.spent.contains(&vin.prev_out) || !spends.insert(vin.prev_out) {
+                debug!("skip {}, {} is already spent", tx.id, vin.prev_out);
+                return Ok(None);
+            }
+
+            let prev_txid = vin.prev_out.txid;
+            let prev_tx = match self.selected.get(&prev_txid) {
+                Some(prev_tx) => prev_tx.clone(),
+                None => {
+                    let mut pub_key_hash = vin.pub_key.clone();
+                    hash_pub_key(&mut pub_key_hash);
+                    if !self.utxo.is_unspent(&pub_key_hash, &vin.prev_out)? {
+                        debug!("skip {}, {} is not in the utxo set", tx.id, vin.prev_out);
+                        return Ok(None);
+                    }
+                    self.utxo.blockchain.find_transaction(&prev_txid)?
+                }
+            };
+            match prev_tx.vout.get(vin.prev_out.index as usize) {
+                Some(out) => total = total.try_add(out.value)?,
+                None => return Ok(None)
+            }
+            prev_txs.insert(prev_txid, prev_tx);
+        }
+        Ok(Some((total, prev_txs)))
+    }
+}
+
+/// check_block verifies a block sealed outside this node before it is connected:
+/// its proof of work, that it extends the tip at a valid time, that its
+/// transactions spend unspent outputs with valid signatures and that its
+/// coinbase, last, claims no more than the subsidy plus their fees
+///
+/// The spends are checked in block order, since a transaction may spend one before
+/// it, then the signatures are verified in parallel, and the first invalid
+/// transaction by index is reported whichever check it fails
+pub fn check_block(block: &Block, utxo: &UTXOSet) -> Result<()> {
+    let invalid = |reason: String| Err(BlockchainError::Consensus(format!("block {} {}", block.get_hash(), reason)));
+    if !block.validate()? {
+        return invalid("does not meet the proof of work target".to_string());
+    }
+    let bits = utxo.blockchain.next_bits()?;
+    if block.get_bits() != bits {
+        return invalid(format!("has target {:08x} instead of {:08x}", block.get_bits(), bits));
+    }
+    let tip = utxo.blockchain.get_tip();
+    let height = utxo.blockchain.get_best_height()? as usize + 1;
+    if block.get_prev_hash() != tip || block.get_height() != height {
+        return invalid(format!("is not the next block after {} at height {}", tip, height));
+    }
+    utxo.blockchain.check_timestamp(block.header(), &utxo.blockchain.get_block_header(&tip)?)?;
+
+    let (coinbase, transactions) = match block.get_transactions().split_last() {
+        Some((coinbase, transactions)) if coinbase.is_coinbase() => (coinbase, transactions),
+        _ => return invalid("does not end with a coinbase".to_string())
+    };
+
+    let mempool = Mempool::new();
+    let mut selection = Selection {
+        mempool: &mempool,
+        utxo,
+        transactions: Vec::new(),
+        selected: HashMap::new(),
+        rejected: HashSet::new(),
+        spent: HashSet::new(),
+        fees: Amount::ZERO,
+        bytes: 0
+    };
+    let mut spent_txs = Vec::with_capacity(transactions.len());
+    let mut failure = None;
+    for (index, tx) in transactions.iter().enumerate() {
+        let Some((inputs, prev_txs)) = selection.inputs(tx)? else {
+            failure = Some((index, "spends an unknown or spent output"));
+            break;
+        };
+        let fee = inputs.try_sub(Amount::sum(tx.vout.iter().map(|out| out.value))?)?;
+        if fee < Amount::ZERO {
+            failure = Some((index, "pays more than it spends"));
+            break;
+        }
+        selection.push(tx, fee, 0)?;
+        spent_txs.push(prev_txs);
+    }
+
+    // only the transactions before a failed spend need their signatures checked
+    let verified: Vec<Result<bool>> = transactions
+        .par_iter()
+        .zip(spent_txs)
+        .map(|(tx, prev_txs)| tx.clone().verify(prev_txs))
+        .collect();
+    for (index, valid) in verified.into_iter().enumerate() {
+        if !valid? {
+            failure = Some((index, "has an invalid signature"));
+            break;
+        }
+    }
+    if let Some((index, reason)) = failure {
+        let tx = &transactions[index];
+        return invalid(format!("has an invalid transaction {} at index {}, it {}", tx.id, index, reason));
+    }
+
+    let reward = Amount::sum(coinbase.vout.iter().map(|out| out.value))?;
+    let allowed = SUBSIDY.try_add(selection.fees)?;
+    if reward > allowed {
+        return invalid(format!("claims {} but may claim {}", reward, allowed));
+    }
+    Ok(())
+}
+
+/// Miner seals templates on the node's chain tip, connects the blocks and
+/// announces them to the peers
+#[derive(Clone)]
+pub struct Miner {
+    server: Server,
+    mining_address: String
+}
+
+impl Miner {
+    pub fn new(server: Server, mining_address: &str) -> Miner {
+        Miner {
+            server,
+            mining_address: mining_address.to_string()
+        }
+    }
+
+    /// template assembles the next block from the node's mempool
+    pub fn template(&self) -> Result<BlockTemplate> {
+        let utxo = self.server.utxo_set();
+        let height = utxo.blockchain.get_best_height()? as usize + 1;
+        let coinbase_msg = self.server.mining_settings().coinbase_msg();
+        BlockTemplate::assemble(&self.server.get_mempool(), utxo, height, &self.mining_address, coinbase_msg)
+    }
+
+    /// wait_for_transactions waits until the mempool holds `min_txs` transactions
+    /// or `max_wait` ran out and returns how many it holds
+    pub fn wait_for_transactions(&self, min_txs: usize, max_wait: Duration) -> usize {
+        let events = self.server.utxo_set().blockchain.events().subscribe();
+        let deadline = Instant::now() + max_wait;
+        loop {
+            let pooled = self.server.get_mempool().len();
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if pooled >= min_txs || remaining.is_zero() {
+                return pooled;
+            }
+            // any event may be a transaction entering or a block emptying the mempool
+            if let Err(RecvTimeoutError::Disconnected) = events.recv_timeout(remaining) {
+                return pooled;
+            }
+        }
+    }
+
+    /// seal runs the proof of work over a template, giving up and returning None
+    /// when a block is connected or a transaction enters the mempool meanwhile,
+    /// since the template is then stale
+    pub fn seal(&self, template: BlockTemplate) -> Result<Option<Block>> {
+        let events = self.server.utxo_set().blockchain.events().subscribe();
+        let abort = AtomicBool::new(false);
+        let stats = self.server.mining_stats();
+        stats.sealing.fetch_add(1, Ordering::Relaxed);
+        let started = Instant::now();
+
+        let options = self.server.mining_settings().pow_options();
+        let sealed = thread::scope(|scope| {
+            let sealing = scope.spawn(|| Block::seal(template.transactions, template.prev_block_hash, template.height, template.bits, options, &abort, &stats.hashes));
+            while !sealing.is_finished() {
+                match events.recv_timeout(EVENT_POLL) {
+                    Ok(Event::BlockConnected { hash, .. }) => {
+                        debug!("block {} connected, abandoning the template", hash);
+                        abort.store(true, Ordering::Relaxed);
+                    },
+                    Ok(Event::TxAccepted { txid }) => {
+                        debug!("transaction {} accepted, abandoning the template", txid);
+                        abort.store(true, Ordering::Relaxed);
+                    },
+                    _ => {}
+                }
+            }
+            match sealing.join() {
+                Ok(res) => res,
+                Err(_) => Err(BlockchainError::Consensus("mining thread panicked".to_string()))
+            }
+        });
+
+        stats.sealing_nanos.fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        stats.sealing.fetch_sub(1, Ordering::Relaxed);
+        sealed
+    }
+
+    /// submit connects a sealed block, which must extend the current tip, drops
+    /// its transactions from the mempool and announces it
+    pub fn submit(&self, block: &Block) -> Result<()> {
+        let utxo = self.server.utxo_set();
+        let connecting = utxo.blockchain.lock_connecting();
+        let tip = utxo.blockchain.get_tip();
+        if block.get_prev_hash() != tip {
+            return Err(BlockchainError::Consensus(format!("block {} extends {} but the tip is now {}", block.get_hash(), block.get_prev_hash(), tip)));
+        }
+
+        utxo.connect_block(block)?;
+        drop(connecting);
+        info!("mined block {} at height {}", block.get_hash(), block.get_height());
+        self.server.announce_mined_block(block)
+    }
+
+    /// try_mine seals a template and submits the block unless the tip moved
+    /// meanwhile, returning None when the template went stale
+    pub fn try_mine(&self, template: BlockTemplate) -> Result<Option<Block>> {
+        let fees = template.fees;
+        let block = match self.seal(template)? {
+            Some(block) => block,
+            None => return Ok(None)
+        };
+        if block.get_prev_hash() != self.server.utxo_set().blockchain.get_tip() {
+            debug!("block {} was found on a stale tip", block.get_hash());
+            return Ok(None);
+        }
+        self.submit(&block)?;
+        self.server.mining_stats().record_block(fees);
+        Ok(Some(block))
+    }
+
+    /// mine_block mines and submits the next block, rebuilding its template on the
+    /// new tip or mempool whenever the previous one goes stale
+    pub fn mine_block(&self) -> Result<Block> {
+        loop {
+            if let Some(block) = self.try_mine(self.template()?)? {
+                return Ok(block);
+            }
+        }
+    }
+
+    /// mine_blocks mines `count` blocks, empty or not, and returns their hashes
+    pub fn mine_blocks(&self, count: usize) -> Result<Vec<Hash256>> {
+        let mut hashes = Vec::new();
+        for _ in 0..count {
+            hashes.push(self.mine_block()?.get_hash());
+        }
+        Ok(hashes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{Blockmsg, Message, MessageCodec};
+    use crate::config::Network;
+    use crate::mempool::MempoolEntry;
+    use crate::node::NodeBuilder;
+    use crate::wallet::Wallets;
+
+    #[test]
+    fn test_template_prefers_fee_rate_and_collects_fees() -> Result<()> {
+        let datadir = std::env::temp_dir().join(format!("blockchain-miner-test-{}", std::process::id()));
+        let builder = NodeBuilder::new().network(Network::Regtest).datadir(&datadir);
+        let miner = builder.create_wallet()?;
+        let receiver = builder.create_wallet()?;
+        let node = builder.mine_to(&miner).listen(false).build()?;
+        node.mine_blocks(1)?;
+        let stats = node.server().mining_stats();
+        assert_eq!(stats.blocks_found(), 1);
+        assert!(stats.hashes() > 0 && !stats.is_mining());
+
+        // both spend the same coin, only the one paying more can be mined
+        let wallets = Wallets::new(node.config())?;
+        let utxo = node.server().utxo_set();
+        let cheap = Transaction::new_UTXO(&wallets, &miner, &receiver, Amount::from_sat(30), Amount::from_sat(1), utxo)?;
+        let rich = Transaction::new_UTXO(&wallets, &miner, &receiver, Amount::from_sat(30), Amount::from_sat(5), utxo)?;
+        let mut mempool = Mempool::new();
+        mempool.insert(MempoolEntry::new(cheap, Amount::from_sat(1))?);
+        mempool.insert(MempoolEntry::new(rich.clone(), Amount::from_sat(5))?);
+
+        let template = BlockTemplate::assemble(&mempool, utxo, 2, &miner, "pool")?;
+        assert_eq!(template.transactions.len(), 2);
+        assert_eq!(template.transactions[0].id, rich.id);
+        assert_eq!(template.fees, Amount::from_sat(5));
+        let coinbase = &template.transactions[1];
+        assert!(coinbase.is_coinbase());
+        assert_eq!(coinbase.vin[0].pub_key, b"height 2 pool");
+        assert_eq!(coinbase.vout[0].value, SUBSIDY.try_add(template.fees)?);
+        assert_eq!(template.merkle_root, Block::merkle_root(&template.transactions)?);
+
+        let miner_handle = Miner::new(node.server().clone(), &miner);
+        let block = miner_handle.seal(template)?.expect("nothing changed while sealing");
+        miner_handle.submit(&block)?;
+        assert_eq!(node.best_height()?, 2);
+        assert_eq!(crate::json::block_json(&block)["coinbase"], "height 2 pool");
+        assert_eq!(node.balance(&receiver)?, Amount::from_sat(30));
+        assert_eq!(node.balance(&miner)?, Amount::from_sat(270));
+        assert!(miner_handle.submit(&block).is_err());
+        assert_eq!(node.server().mining_info()?["blocks"], 2);
+
+        node.shutdown()?;
+        std::fs::remove_dir_all(&datadir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_mempool_waits_for_transactions() -> Result<()> {
+        let datadir = std::env::temp_dir().join(format!("blockchain-mempool-wait-test-{}", std::process::id()));
+        let config = Config { mine_min_txs: 2, mine_max_wait: 1, ..Config::default() };
+        let builder = NodeBuilder::new().config(config).network(Network::Regtest).datadir(&datadir);
+        let miner = builder.create_wallet()?;
+        let receiver = builder.create_wallet()?;
+        let node = builder.mine_to(&miner).listen(false).build()?;
+        let events = node.subscribe();
+
+        // a lone transaction waits in the mempool until the wait runs out
+        let txid = node.send_to_address(&receiver, Amount::from_sat(10))?;
+        assert_eq!(node.best_height()?, 0);
+        assert!(node.mempool().contains(&txid));
+        loop {
+            if let Event::BlockConnected { height, .. } = events.recv_timeout(Duration::from_secs(30)).expect("the wait ran out") {
+                assert_eq!(height, 1);
+                break;
+            }
+        }
+        assert_eq!(node.balance(&receiver)?, Amount::from_sat(10));
+
+        node.shutdown()?;
+        std::fs::remove_dir_all(&datadir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_block() -> Result<()> {
+        let datadir = std::env::temp_dir().join(format!("blockchain-check-block-test-{}", std::process::id()));
+        let builder = NodeBuilder::new().network(Network::Regtest).datadir(&datadir);
+        let miner = builder.create_wallet()?;
+        let receiver = builder.create_wallet()?;
+        let node = builder.mine_to(&miner).listen(false).build()?;
+        let utxo = node.server().utxo_set();
+
+        let template = Miner::new(node.server().clone(), &miner).template()?;
+        let mut greedy = template.transactions.clone();
+        greedy[0] = Transaction::new_coinbase_with_fees(miner.clone(), "height 1".to_string(), Amount::from_sat(1))?;
+        let greedy = Block::new_block(greedy, template.prev_block_hash, template.height, template.bits)?;
+        assert!(check_block(&greedy, utxo).is_err());
+        // a peer relaying it is refused the same way
+        let frame = MessageCodec::encode(&Message::Block(Blockmsg { addr_from: "localhost:3001".to_string(), block: greedy }))?;
+        assert!(node.server().handle_frame(&frame).is_err());
+        assert_eq!(node.best_block_hash(), template.prev_block_hash);
+
+        let stale = Block::new_block(template.transactions.clone(), Hash256::ZERO, template.height, template.bits)?;
+        assert!(check_block(&stale, utxo).is_err());
+
+        let easier = Target::from_compact(template.bits)?.scale(2, 1).to_compact();
+        let cheat = Block::new_block(template.transactions.clone(), template.prev_block_hash, template.height, easier)?;
+        assert!(check_block(&cheat, utxo).is_err());
+
+        // a forged signature is reported before a later double spend of the same coin
+        let wallets = Wallets::new(node.config())?;
+        let payment = Transaction::new_UTXO(&wallets, &miner, &receiver, Amount::from_sat(30), Amount::ZERO, utxo)?;
+        let mut forged = payment.clone();
+        forged.vin[0].signature[0] ^= 1;
+        let mut transactions = vec![forged, payment];
+        transactions.push(template.transactions[0].clone());
+        let forged = Block::new_block(transactions, template.prev_block_hash, template.height, template.bits)?;
+        let error = check_block(&forged, utxo).expect_err("the signature is forged").to_string();
+        assert!(error.contains("at index 0, it has an invalid signature"), "{}", error);
+
+        let block = Block::new_block(template.transactions, template.prev_block_hash, template.height, template.bits)?;
+        check_block(&block, utxo)?;
+        node.server().submit_block(block.clone())?;
+        assert_eq!(node.best_block_hash(), block.get_hash());
+        assert!(node.server().submit_block(block).is_err());
+
+        node.shutdown()?;
+        std::fs::remove_dir_all(&datadir)?;
+        Ok(())
+    }
+}
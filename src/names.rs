@@ -0,0 +1,350 @@
+//! A name registry built on the chain, like Namecoin: transactions register a
+//! name with a value, update it, and transfer it, and a name nobody renews
+//! expires after NAME_EXPIRY_BLOCKS blocks.
+//!
+//! Name records travel in data outputs. A name belongs to output 0 of the
+//! transaction that last registered or updated it: an update spends that
+//! output and creates the next one, paid to another address for a transfer.
+//! Registering takes a name that is free or expired.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::address;
+use crate::amount::Amount;
+use crate::block::{Block, BlockHeader};
+use crate::blockchain::Blockchain;
+use crate::error::{BlockchainError, Result};
+use crate::hash::Hash256;
+use crate::intent::IntentOp;
+use crate::token::{TokenIndex, CARRIER_VALUE};
+use crate::transaction::Transaction;
+use crate::tx::{OutPoint, TXOutput};
+use crate::utxoset::UTXOSet;
+use crate::wallet::Wallets;
+
+/// NAME_TREE maps a name to its NameEntry
+pub const NAME_TREE: &str = "names";
+
+/// NAME_UNDO_TREE maps a block hash to the entries its name records replaced,
+/// so disconnecting the block puts them back
+const NAME_UNDO_TREE: &str = "names_undo";
+
+/// NAME_TIP_TREE holds the hash of the last block the registry covers
+const NAME_TIP_TREE: &str = "names_tip";
+const TIP_KEY: &[u8] = b"TIP";
+
+/// MAGIC starts the data of every name record
+const MAGIC: &[u8] = b"NAM1";
+
+/// NAME_EXPIRY_BLOCKS is how long a name lasts after its last registration or
+/// update
+pub const NAME_EXPIRY_BLOCKS: usize = 1000;
+
+/// MAX_NAME_LEN and MAX_VALUE_LEN keep a record within one data output
+pub const MAX_NAME_LEN: usize = 20;
+pub const MAX_VALUE_LEN: usize = 32;
+
+/// NameRecord is the payload of a name data output
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum NameRecord {
+    /// claims a free or expired name for output 0
+    Register { name: String, value: String },
+    /// spends the output holding the name and moves it, with a new value, to output 0
+    Update { name: String, value: String }
+}
+
+impl NameRecord {
+    /// encode is the data output carrying the record
+    pub fn encode(&self) -> Result<TXOutput> {
+        let mut data = MAGIC.to_vec();
+        data.extend(bincode::serialize(self)?);
+        TXOutput::new_data(&data)
+    }
+
+    /// decode reads the first name record among the data outputs of `tx`
+    pub fn decode(tx: &Transaction) -> Option<NameRecord> {
+        tx.vout
+            .iter()
+            .filter_map(TXOutput::data)
+            .find_map(|data| bincode::deserialize(data.strip_prefix(MAGIC)?).ok())
+    }
+}
+
+/// NameEntry is the state of a registered name
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct NameEntry {
+    pub value: String,
+    /// public key hash of the output holding the name
+    pub owner: Vec<u8>,
+    /// the output holding the name, spent by the next update
+    pub outpoint: OutPoint,
+    /// height of the last registration or update
+    pub height: usize
+}
+
+impl NameEntry {
+    /// expires_at is the first height at which the name is free again
+    pub fn expires_at(&self) -> usize {
+        self.height + NAME_EXPIRY_BLOCKS
+    }
+}
+
+/// NameIndex applies the name records of the best chain. It catches up with the
+/// chain when it starts and then follows it from a background thread, undoing
+/// the blocks that leave the best chain
+#[derive(Debug, Clone)]
+pub struct NameIndex {
+    bc: Blockchain,
+    /// held while the registry moves, so two syncs never interleave
+    syncing: Arc<Mutex<()>>
+}
+
+impl NameIndex {
+    /// new opens the registry of `bc` as it is, call sync to bring it up to the tip
+    pub fn new(bc: &Blockchain) -> NameIndex {
+        NameIndex {
+            bc: bc.clone(),
+            syncing: Arc::new(Mutex::new(()))
+        }
+    }
+
+    /// start brings the registry up to the chain tip and follows the chain events
+    pub fn start(bc: &Blockchain) -> Result<NameIndex> {
+        let events = bc.events().subscribe();
+        let index = NameIndex::new(bc);
+        index.sync()?;
+
+        let follower = index.clone();
+        thread::spawn(move || {
+            for event in events {
+                if let Err(e) = follower.sync() {
+                    error!("name registry failed on {:?}: {}", event, e);
+                }
+            }
+        });
+        Ok(index)
+    }
+
+    /// sync disconnects the applied blocks that left the best chain and then
+    /// applies the best chain blocks not applied yet
+    pub fn sync(&self) -> Result<()> {
+        let _syncing = self.syncing.lock().unwrap_or_else(PoisonError::into_inner);
+        let (disconnected, connected) = self.bc.path_from(self.tip()?)?;
+        for header in &disconnected {
+            self.disconnect(header)?;
+        }
+        for header in &connected {
+            self.connect(&self.bc.get_block(&header.hash)?)?;
+        }
+        Ok(())
+    }
+
+    /// lookup is the entry of `name` when it is registered and not expired
+    pub fn lookup(&self, name: &str) -> Result<Option<NameEntry>> {
+        let height = self.bc.get_best_height()? as usize;
+        Ok(self.entry(name)?.filter(|entry| height < entry.expires_at()))
+    }
+
+    /// owned_outputs is the set of outputs of `pub_key_hash` holding a name,
+    /// which a plain payment must not spend or the name is lost
+    pub fn owned_outputs(&self, pub_key_hash: &[u8]) -> Result<HashSet<OutPoint>> {
+        let mut outputs = HashSet::new();
+        for item in self.bc.open_tree(NAME_TREE)?.iter() {
+            let entry: NameEntry = bincode::deserialize(&item?.1)?;
+            if entry.owner == pub_key_hash {
+                outputs.insert(entry.outpoint);
+            }
+        }
+        Ok(outputs)
+    }
+
+    /// register_tx builds a transaction of `from` registering `name` with `value`
+    pub fn register_tx(&self, wallets: &Wallets, from: &str, name: &str, value: &str, fee: Amount) -> Result<Transaction> {
+        check_record(name, value)?;
+        if let Some(entry) = self.lookup(name)? {
+            return Err(BlockchainError::Wallet(format!("{} is taken until height {}", name, entry.expires_at())));
+        }
+
+        let record = NameRecord::Register { name: name.to_string(), value: value.to_string() };
+        self.build_tx(wallets, from, Vec::new(), from, record, fee)
+    }
+
+    /// update_tx builds a transaction of the owner `from` setting `name` to
+    /// `value` and handing it to `to`, which is `from` unless it transfers it
+    pub fn update_tx(&self, wallets: &Wallets, from: &str, to: &str, name: &str, value: &str, fee: Amount) -> Result<Transaction> {
+        check_record(name, value)?;
+        let entry = self.lookup(name)?.ok_or_else(|| BlockchainError::Wallet(format!("{} is not registered", name)))?;
+        if entry.owner != address::decode(from)? {
+            return Err(BlockchainError::Wallet(format!("{} does not own {}", from, name)));
+        }
+
+        let record = NameRecord::Update { name: name.to_string(), value: value.to_string() };
+        self.build_tx(wallets, from, vec![entry.outpoint], to, record, fee)
+    }
+
+    /// build_tx signs a transaction of `from` spending `held` and carrying
+    /// `record`, with output 0 paid to `owner`
+    fn build_tx(&self, wallets: &Wallets, from: &str, held: Vec<OutPoint>, owner: &str, record: NameRecord, fee: Amount) -> Result<Transaction> {
+        let reserved = reserved_outputs(&self.bc, &address::decode(from)?)?;
+        let held = held.into_iter().map(|outpoint| (outpoint, CARRIER_VALUE)).collect();
+        let vout = vec![TXOutput::new(CARRIER_VALUE, owner.to_string())?, record.encode()?];
+        let utxo = UTXOSet { blockchain: self.bc.clone() };
+        Transaction::new_with_outputs(wallets, from, held, vout, fee, &utxo, |outpoint| !reserved.contains(outpoint))
+    }
+
+    fn entry(&self, name: &str) -> Result<Option<NameEntry>> {
+        match self.bc.open_tree(NAME_TREE)?.get(name.as_bytes())? {
+            Some(entry) => Ok(Some(bincode::deserialize(&entry)?)),
+            None => Ok(None)
+        }
+    }
+
+    /// tip is the last applied block, None before the genesis is
+    fn tip(&self) -> Result<Option<Hash256>> {
+        match self.bc.open_tree(NAME_TIP_TREE)?.get(TIP_KEY)? {
+            Some(hash) => Ok(Some(Hash256::from_slice(&hash)?)),
+            None => Ok(None)
+        }
+    }
+
+    /// connect applies the name records of `block`, keeping the entries they
+    /// replace to undo it
+    fn connect(&self, block: &Block) -> Result<()> {
+        let height = block.get_height();
+        // the entries as the block leaves them, and as it found them
+        let mut entries: HashMap<String, Option<NameEntry>> = HashMap::new();
+        let mut undo: Vec<(String, Option<NameEntry>)> = Vec::new();
+
+        for tx in block.get_transactions() {
+            let (name, value, updating) = match NameRecord::decode(tx) {
+                Some(NameRecord::Register { name, value }) => (name, value, false),
+                Some(NameRecord::Update { name, value }) => (name, value, true),
+                None => continue
+            };
+            let owner = match tx.vout.first() {
+                Some(out) if !out.is_data() => out.pub_key_hash.clone(),
+                _ => continue
+            };
+            if tx.is_coinbase() || check_record(&name, &value).is_err() {
+                continue;
+            }
+
+            let current = match entries.get(&name) {
+                Some(entry) => entry.clone(),
+                None => self.entry(&name)?
+            };
+            let live = current.as_ref().filter(|entry| height < entry.expires_at());
+            let valid = match live {
+                Some(entry) => updating && tx.vin.iter().any(|vin| vin.prev_out == entry.outpoint),
+                None => !updating
+            };
+            if !valid {
+                continue;
+            }
+
+            if !entries.contains_key(&name) {
+                undo.push((name.clone(), current));
+            }
+            info!("name {} {} at height {}", name, if updating { "updated" } else { "registered" }, height);
+            entries.insert(name, Some(NameEntry { value, owner, outpoint: tx.outpoint(0), height }));
+        }
+
+        let mut ops = Vec::new();
+        for (name, entry) in entries {
+            if let Some(entry) = entry {
+                ops.push(IntentOp::insert(NAME_TREE, name.as_bytes(), bincode::serialize(&entry)?));
+            }
+        }
+        ops.push(IntentOp::insert(NAME_UNDO_TREE, block.get_hash().as_bytes(), bincode::serialize(&undo)?));
+        ops.push(IntentOp::insert(NAME_TIP_TREE, TIP_KEY, block.get_hash().as_bytes().to_vec()));
+        self.bc.intents().commit(&ops)
+    }
+
+    /// disconnect puts back the entries the block of `header` replaced, making
+    /// its parent the tip of the registry
+    fn disconnect(&self, header: &BlockHeader) -> Result<()> {
+        let undo: Vec<(String, Option<NameEntry>)> = match self.bc.open_tree(NAME_UNDO_TREE)?.get(header.hash.as_bytes())? {
+            Some(undo) => bincode::deserialize(&undo)?,
+            None => return Err(BlockchainError::Corrupt(format!("no name undo record for block {}", header.hash)))
+        };
+
+        let mut ops = Vec::new();
+        for (name, entry) in undo {
+            ops.push(match entry {
+                Some(entry) => IntentOp::insert(NAME_TREE, name.as_bytes(), bincode::serialize(&entry)?),
+                None => IntentOp::remove(NAME_TREE, name.as_bytes())
+            });
+        }
+        ops.push(IntentOp::remove(NAME_UNDO_TREE, header.hash.as_bytes()));
+        ops.push(IntentOp::insert(NAME_TIP_TREE, TIP_KEY, header.prev_block_hash.as_bytes().to_vec()));
+        self.bc.intents().commit(&ops)?;
+        info!("name registry disconnected block {}", header.hash);
+        Ok(())
+    }
+}
+
+/// reserved_outputs is the set of outputs of `pub_key_hash` carrying tokens or
+/// holding a name, which coin selection leaves alone
+pub fn reserved_outputs(bc: &Blockchain, pub_key_hash: &[u8]) -> Result<HashSet<OutPoint>> {
+    let mut reserved = TokenIndex::new(bc).carriers(pub_key_hash)?;
+    reserved.extend(NameIndex::new(bc).owned_outputs(pub_key_hash)?);
+    Ok(reserved)
+}
+
+/// check_record tells whether `name` and `value` fit a record, names are
+/// lowercase letters, digits, dots and dashes like domain names
+fn check_record(name: &str, value: &str) -> Result<()> {
+    let valid_name = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'.' || b == b'-');
+    if !valid_name {
+        return Err(BlockchainError::Wallet(format!("a name is 1 to {} lowercase letters, digits, dots or dashes, not '{}'", MAX_NAME_LEN, name)));
+    }
+    if value.len() > MAX_VALUE_LEN {
+        return Err(BlockchainError::Wallet(format!("a value holds at most {} bytes", MAX_VALUE_LEN)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ChainFixture;
+
+    #[test]
+    fn test_names_follow_the_chain() -> Result<()> {
+        let fixture = ChainFixture::restore(3)?;
+        let utxo = UTXOSet { blockchain: fixture.blockchain()? };
+        let names = NameIndex::new(&utxo.blockchain);
+        let mut wallets = Wallets::new(&fixture.config())?;
+        let miner = fixture.miner().to_string();
+        let friend = wallets.create_wallet();
+        let mine = |tx: Transaction| -> Result<Block> {
+            let coinbase = Transaction::new_coinbase(miner.clone(), format!("names {}", tx.id))?;
+            let block = utxo.blockchain.mine_block(vec![tx, coinbase])?;
+            utxo.connect_block(&block)?;
+            names.sync()?;
+            Ok(block)
+        };
+
+        mine(names.register_tx(&wallets, &miner, "alice.bit", "10.0.0.1", Amount::ZERO)?)?;
+        assert_eq!(names.lookup("alice.bit")?.map(|entry| entry.value), Some("10.0.0.1".to_string()));
+        assert!(names.register_tx(&wallets, &miner, "alice.bit", "10.0.0.2", Amount::ZERO).is_err());
+
+        let transfer = mine(names.update_tx(&wallets, &miner, &friend, "alice.bit", "10.0.0.2", Amount::ZERO)?)?;
+        let entry = names.lookup("alice.bit")?.unwrap();
+        assert_eq!((entry.value.as_str(), entry.owner.clone()), ("10.0.0.2", address::decode(&friend)?));
+        assert!(names.update_tx(&wallets, &miner, &miner, "alice.bit", "10.0.0.3", Amount::ZERO).is_err());
+
+        // undoing the transfer gives the name back with its old value
+        names.disconnect(transfer.header())?;
+        let entry = names.lookup("alice.bit")?.unwrap();
+        assert_eq!((entry.value.as_str(), entry.owner), ("10.0.0.1", address::decode(&miner)?));
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+//! Peer and listen addresses. A peer is `host:port` or `[ipv6]:port`, kept in
+//! one spelling so the known nodes hold an IPv6 peer once whichever way its
+//! address was written.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, ToSocketAddrs};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::error::{BlockchainError, Result};
+
+/// BACKLOG is how many connections wait to be accepted by a listener
+const BACKLOG: i32 = 128;
+
+/// normalize spells `peer` the way the known nodes keep it: an IP address as
+/// std prints it, compressed and bracketed for IPv6, a host name in lowercase
+pub fn normalize(peer: &str) -> Result<String> {
+    let peer = peer.trim();
+    if let Ok(addr) = peer.parse::<SocketAddr>() {
+        return Ok(addr.to_string());
+    }
+    let invalid = || BlockchainError::Network(format!("'{}' is not a host:port or [ipv6]:port address", peer));
+    let (host, port) = peer.rsplit_once(':').ok_or_else(invalid)?;
+    // an IPv6 address without brackets cannot be told apart from its port
+    if host.is_empty() || host.contains([':', '[', ']']) || port.parse::<u16>().is_err() {
+        return Err(invalid());
+    }
+    Ok(format!("{}:{}", host.to_ascii_lowercase(), port))
+}
+
+/// is_routable tells whether the normalized `peer` can be reached from the
+/// internet, not a loopback, private, link-local or unspecified address
+pub fn is_routable(peer: &str) -> bool {
+    let host = host(peer);
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast()),
+        // fc00::/7 is unique local and fe80::/10 link-local
+        Ok(IpAddr::V6(ip)) => !(ip.is_loopback() || ip.is_unspecified() || (ip.segments()[0] & 0xfe00) == 0xfc00 || (ip.segments()[0] & 0xffc0) == 0xfe80),
+        Err(_) => host != "localhost" && !host.ends_with(".localhost")
+    }
+}
+
+/// group is the network block of the normalized `peer` that one operator can
+/// easily hold many addresses in: the /16 of an IPv4 address, the /32 of an
+/// IPv6 one and the host name otherwise
+pub fn group(peer: &str) -> String {
+    let host = host(peer);
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => format!("{}.{}", ip.octets()[0], ip.octets()[1]),
+        Ok(IpAddr::V6(ip)) => format!("{:x}:{:x}", ip.segments()[0], ip.segments()[1]),
+        Err(_) => host.to_string()
+    }
+}
+
+/// host is `peer` without its port and brackets
+pub fn host(peer: &str) -> &str {
+    let host = peer.rsplit_once(':').map_or(peer, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// loopback is the address to connect to for reaching a listener on `addr`,
+/// the loopback of its family when it listens on every interface
+pub fn loopback(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port()),
+        IpAddr::V6(ip) if ip.is_unspecified() => SocketAddr::new(Ipv6Addr::LOCALHOST.into(), addr.port()),
+        _ => addr
+    }
+}
+
+/// listen binds the first address `bind` resolves to. An IPv6 listener takes
+/// only IPv6 connections, so `0.0.0.0:3000` and `[::]:3000` bind side by side
+pub fn listen(bind: &str) -> Result<TcpListener> {
+    let addr = bind
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| BlockchainError::Network(format!("{} resolves to no address", bind)))?;
+    bind_socket(addr).map_err(|e| BlockchainError::Network(format!("cannot listen on {}: {}", bind, e)))
+}
+
+fn bind_socket(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ipv6_peers_have_one_spelling() -> Result<()> {
+        assert_eq!(normalize("[0:0:0:0:0:0:0:1]:3000")?, "[::1]:3000");
+        assert_eq!(normalize(" LocalHost:3000")?, "localhost:3000");
+        assert_eq!(normalize("10.0.0.1:3000")?, "10.0.0.1:3000");
+        assert!(normalize("::1:3000").is_err());
+        assert!(normalize("localhost").is_err());
+        assert_eq!(loopback("[::]:3000".parse().unwrap()), "[::1]:3000".parse().unwrap());
+        assert!(is_routable("8.8.8.8:3000") && is_routable("[2001:db8::1]:3000") && is_routable("abcdef.onion:3000"));
+        assert!(!is_routable("127.0.0.1:3000") && !is_routable("192.168.1.2:3000") && !is_routable("[fe80::1]:3000") && !is_routable("localhost:3000"));
+        assert_eq!((group("8.8.4.4:3000"), group("[2001:db8::1]:3000")), ("8.8".to_string(), "2001:db8".to_string()));
+
+        let v4 = listen("0.0.0.0:0")?;
+        let port = v4.local_addr()?.port();
+        let v6 = listen(&format!("[::]:{}", port))?;
+        assert_eq!(v6.local_addr()?.port(), port);
+        Ok(())
+    }
+}
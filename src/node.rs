@@ -0,0 +1,287 @@
+use std::fs::File;
+use std::path::Path;
+use std::thread::{self, JoinHandle};
+
+use tracing::info;
+
+use crate::amount::Amount;
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::config::{Config, Network};
+use crate::daemon;
+use crate::error::{BlockchainError, Result};
+use crate::events::Event;
+use crate::hash::Hash256;
+use crate::mempool::Mempool;
+use crate::rpc;
+use crate::server::{Server, KNOWN_NODE1};
+use crate::storage::Compression;
+use crate::transaction::Transaction;
+use crate::utxoset::UTXOSet;
+use crate::wallet::Wallets;
+
+/// NodeBuilder configures a node embedded in another program, like an integration test
+///
+/// ```no_run
+/// use blockchain_project::config::Network;
+/// use blockchain_project::NodeBuilder;
+///
+/// let builder = NodeBuilder::new().network(Network::Regtest).datadir("/tmp/regtest-node");
+/// let address = builder.create_wallet()?;
+/// let node = builder.mine_to(&address).listen(false).build()?;
+/// node.mine_blocks(2)?;
+/// assert_eq!(node.balance(&address)?, "3".parse()?);
+/// node.shutdown()?;
+/// # Ok::<(), blockchain_project::error::BlockchainError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct NodeBuilder {
+    config: Config,
+    listen: bool
+}
+
+impl Default for NodeBuilder {
+    fn default() -> NodeBuilder {
+        NodeBuilder {
+            config: Config::default(),
+            listen: true
+        }
+    }
+}
+
+impl NodeBuilder {
+    /// new starts from the default config, ignoring the config file and the environment
+    pub fn new() -> NodeBuilder {
+        NodeBuilder::default()
+    }
+
+    /// config starts from an existing config, e.g. one from `Config::load`
+    pub fn config(mut self, config: Config) -> NodeBuilder {
+        self.config = config;
+        self
+    }
+
+    pub fn network(mut self, network: Network) -> NodeBuilder {
+        self.config.network = network;
+        self
+    }
+
+    pub fn datadir(mut self, datadir: impl AsRef<Path>) -> NodeBuilder {
+        self.config.datadir = datadir.as_ref().display().to_string();
+        self
+    }
+
+    /// port is the P2P port, also used as the node address announced to peers
+    pub fn port(mut self, port: &str) -> NodeBuilder {
+        self.config.port = port.to_string();
+        self
+    }
+
+    /// peers replaces the configured peers, a node without peers stays on its own
+    pub fn peers(mut self, peers: Vec<String>) -> NodeBuilder {
+        self.config.peers = peers;
+        self
+    }
+
+    /// mine_to makes the node a miner paying `address`, which also receives the
+    /// genesis reward when the data directory has no chain yet
+    pub fn mine_to(mut self, address: &str) -> NodeBuilder {
+        self.config.mining_address = address.to_string();
+        self
+    }
+
+    /// listen serves inbound P2P connections and the configured APIs, on by default
+    pub fn listen(mut self, listen: bool) -> NodeBuilder {
+        self.listen = listen;
+        self
+    }
+
+    /// create_wallet adds a wallet to the data directory of the node being built
+    /// and returns its address
+    pub fn create_wallet(&self) -> Result<String> {
+        let mut wallets = Wallets::new(&self.config)?;
+        let address = wallets.create_wallet();
+        wallets.save_all()?;
+        Ok(address)
+    }
+
+    /// build locks the data directory, opens the chain, creating it when a mining
+    /// address is set, and starts the node
+    pub fn build(self) -> Result<Node> {
+        let config = self.config;
+        let lock = daemon::lock_datadir(&config)?;
+
+        let utxo_set = match Blockchain::new(&config) {
+            Ok(bc) => UTXOSet { blockchain: bc },
+            Err(BlockchainError::NoChain(_)) if !config.mining_address.is_empty() => {
+                let bc = Blockchain::create_blockchain(&config, config.mining_address.clone(), Compression::None)?;
+                let utxo_set = UTXOSet { blockchain: bc };
+                utxo_set.reindex()?;
+                utxo_set
+            },
+            Err(e) => return Err(e)
+        };
+
+        let server = Server::new(&config, utxo_set)?;
+        if config.peers.is_empty() {
+            server.remove_node(KNOWN_NODE1);
+        }
+        server.load_mempool()?;
+
+        let listener = if self.listen {
+            let server = server.clone();
+            Some(thread::spawn(move || server.start_server(true)))
+        } else {
+            None
+        };
+        info!("node built on {}", config.network_dir().display());
+
+        Ok(Node {
+            server,
+            listener,
+            _lock: lock
+        })
+    }
+}
+
+/// Node is a running node built by `NodeBuilder`, it holds the data directory
+/// lock until it is shut down or dropped
+pub struct Node {
+    server: Server,
+    listener: Option<JoinHandle<Result<()>>>,
+    _lock: File
+}
+
+impl Node {
+    /// server gives access to the whole node, for what the handle does not cover
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    pub fn config(&self) -> &Config {
+        self.server.config()
+    }
+
+    /// submit_transaction adds a signed transaction to the mempool and relays it,
+    /// a miner mines it right away unless it waits for more transactions
+    pub fn submit_transaction(&self, tx: Transaction) -> Result<()> {
+        self.server.submit_transaction(tx)
+    }
+
+    /// send_to_address pays `amount` to `to` from the first local wallet that can
+    /// afford it and returns the txid
+    pub fn send_to_address(&self, to: &str, amount: Amount) -> Result<Hash256> {
+        rpc::send_to_address(&self.server, to, amount, None)
+    }
+
+    /// mine_blocks mines `count` blocks with the mempool transactions and returns their hashes
+    pub fn mine_blocks(&self, count: usize) -> Result<Vec<Hash256>> {
+        self.server.mine_blocks(count)
+    }
+
+    pub fn best_height(&self) -> Result<i32> {
+        self.server.utxo_set().blockchain.get_best_height()
+    }
+
+    pub fn best_block_hash(&self) -> Hash256 {
+        self.server.utxo_set().blockchain.get_tip()
+    }
+
+    pub fn get_block(&self, hash: &Hash256) -> Result<Block> {
+        self.server.utxo_set().blockchain.get_block(hash)
+    }
+
+    /// get_transaction finds a confirmed transaction by its ID
+    pub fn get_transaction(&self, txid: &Hash256) -> Result<Transaction> {
+        self.server.utxo_set().blockchain.find_transaction(txid)
+    }
+
+    /// balance is the confirmed balance of `address`
+    pub fn balance(&self, address: &str) -> Result<Amount> {
+        rpc::address_balance(&self.server, address)
+    }
+
+    /// mempool returns a snapshot of the unconfirmed transactions
+    pub fn mempool(&self) -> Mempool {
+        self.server.get_mempool()
+    }
+
+    /// subscribe returns a receiver of every chain and node event from now on
+    pub fn subscribe(&self) -> std::sync::mpsc::Receiver<Event> {
+        self.server.utxo_set().blockchain.events().subscribe()
+    }
+
+    /// shutdown stops the P2P listener, flushes the chain to disk, saves the
+    /// mempool and releases the data directory, the APIs enabled in the config stop with the process
+    pub fn shutdown(mut self) -> Result<()> {
+        if let Some(listener) = self.listener.take() {
+            self.server.stop_listening();
+            match listener.join() {
+                Ok(res) => res?,
+                Err(_) => return Err(BlockchainError::Network("listener thread panicked".to_string()))
+            }
+        }
+        self.server.utxo_set().blockchain.flush()?;
+        self.server.save_mempool()?;
+        info!("node shut down");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mempool::MempoolEntry;
+    use crate::testing::ChainFixture;
+    use crate::wallet::hash_to_address;
+
+    #[test]
+    fn test_mine_and_send_on_regtest() -> Result<()> {
+        let datadir = std::env::temp_dir().join(format!("blockchain-node-test-{}", std::process::id()));
+        let builder = NodeBuilder::new().network(Network::Regtest).datadir(&datadir);
+        let miner = builder.create_wallet()?;
+        let receiver = builder.create_wallet()?;
+        let node = builder.mine_to(&miner).listen(false).build()?;
+
+        assert!(NodeBuilder::new().network(Network::Regtest).datadir(&datadir).build().is_err());
+
+        node.mine_blocks(1)?;
+        assert_eq!(node.best_height()?, 1);
+        assert_eq!(node.balance(&miner)?, Amount::from_sat(200));
+
+        node.send_to_address(&receiver, Amount::from_sat(30))?;
+        assert!(node.mempool().is_empty());
+        assert_eq!(node.balance(&receiver)?, Amount::from_sat(30));
+
+        node.shutdown()?;
+        std::fs::remove_dir_all(&datadir)?;
+        Ok(())
+    }
+    #[test]
+    fn test_reloaded_mempool_drops_what_the_chain_spent() -> Result<()> {
+        let fixture = ChainFixture::restore(2)?;
+        let node = fixture.node_builder().build()?;
+        let wallets = Wallets::new(node.config())?;
+        let to = hash_to_address(&[7; 20]);
+        let pay = |fee| Transaction::new_utxo_where(&wallets, fixture.miner(), &to, Amount::from_sat(5), Amount::from_sat(fee), node.server().utxo_set(), |_| true);
+        let mined = pay(1)?;
+        node.submit_transaction(mined.clone())?;
+        node.mine_blocks(1)?;
+        let pending = pay(2)?;
+
+        // a file from before the block, its fees as the node would not compute them
+        let mut saved = Mempool::new();
+        saved.insert(MempoolEntry::new(mined, Amount::ZERO)?);
+        saved.insert(MempoolEntry { time: 42, ..MempoolEntry::new(pending.clone(), Amount::ZERO)? });
+        let path = node.config().mempool_path();
+        saved.save(&path)?;
+
+        node.server().load_mempool()?;
+        let pool = node.mempool();
+        assert_eq!(pool.txids(), vec![pending.id]);
+        assert_eq!((pool.get(&pending.id).unwrap().fee, pool.get(&pending.id).unwrap().time), (Amount::from_sat(2), 42));
+        node.shutdown()?;
+        assert_eq!(Mempool::load(&path)?.len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,174 @@
+//! Timestamping on the chain: `anchor` puts the sha256 digest of a file in a
+//! data output, and `prove` packages the transaction with its merkle branch and
+//! the header of its block. The proof shows the file existed before the block
+//! was mined, and everything but the last check needs no node:
+//!
+//! 1. the file hashes to the digest the proof names
+//! 2. the transaction carries the digest and has the txid of the proof
+//! 3. the merkle branch hashes the transaction up to a root that, with the
+//!    header fields, hashes to the block hash and meets its target
+//! 4. the block is on the best chain, checked against the local chain
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::address;
+use crate::amount::Amount;
+use crate::block::{pow_hash, Block, MerkleStep};
+use crate::blockchain::Blockchain;
+use crate::error::{BlockchainError, Result};
+use crate::hash::Hash256;
+use crate::names::reserved_outputs;
+use crate::target::Target;
+use crate::transaction::Transaction;
+use crate::tx::TXOutput;
+use crate::utxoset::UTXOSet;
+use crate::wallet::Wallets;
+
+/// MAGIC starts the data of an anchor output, ahead of the 32 byte digest
+const MAGIC: &[u8] = b"ANC1";
+
+/// Proof is the portable proof that a file was anchored in a block
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Proof {
+    /// sha256 of the file
+    pub digest: Hash256,
+    pub txid: Hash256,
+    /// the anchor transaction as hex encoded bincode
+    pub tx: String,
+    #[serde(rename = "merklebranch")]
+    pub merkle_branch: Vec<MerkleStep>,
+    pub block: ProofHeader
+}
+
+/// ProofHeader is the block header the merkle branch leads to
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ProofHeader {
+    pub hash: Hash256,
+    pub height: usize,
+    /// when the block was mined, in milliseconds since the epoch
+    pub time: u128,
+    #[serde(rename = "previousblockhash")]
+    pub prev_block_hash: Hash256,
+    pub bits: u32,
+    pub nonce: i32
+}
+
+/// file_digest is the sha256 of the file at `path`
+pub fn file_digest(path: &Path) -> Result<Hash256> {
+    Ok(Hash256::sha256(&std::fs::read(path)?))
+}
+
+/// anchor_tx builds a transaction of `from` with a data output carrying
+/// `digest`, leaving the outputs that hold tokens or names alone
+pub fn anchor_tx(wallets: &Wallets, from: &str, digest: &Hash256, fee: Amount, utxo: &UTXOSet) -> Result<Transaction> {
+    let reserved = reserved_outputs(&utxo.blockchain, &address::decode(from)?)?;
+    let mut data = MAGIC.to_vec();
+    data.extend_from_slice(digest.as_bytes());
+    Transaction::new_with_outputs(wallets, from, Vec::new(), vec![TXOutput::new_data(&data)?], fee, utxo, |outpoint| !reserved.contains(outpoint))
+}
+
+/// anchors tells whether `tx` carries `digest` in an anchor output
+fn anchors(tx: &Transaction, digest: &Hash256) -> bool {
+    tx.vout.iter().filter_map(TXOutput::data).any(|data| data.strip_prefix(MAGIC) == Some(&digest.as_bytes()[..]))
+}
+
+/// prove builds the proof that the confirmed transaction `txid` anchors `digest`
+pub fn prove(bc: &Blockchain, digest: &Hash256, txid: &Hash256) -> Result<Proof> {
+    let (tx, header) = bc.get_indexed_transaction(txid)?.ok_or_else(|| BlockchainError::TxNotFound(txid.to_string()))?;
+    if !anchors(&tx, digest) {
+        return Err(BlockchainError::InvalidProof(format!("{} does not anchor digest {}", txid, digest)));
+    }
+    let transactions = bc.get_block_body(&header.hash)?;
+    let index = transactions.iter().position(|t| t.id == *txid).unwrap_or_default();
+
+    Ok(Proof {
+        digest: *digest,
+        txid: *txid,
+        tx: hex::encode(bincode::serialize(&tx)?),
+        merkle_branch: Block::merkle_branch(&transactions, index)?,
+        block: ProofHeader {
+            hash: header.hash,
+            height: header.height,
+            time: header.timestamp,
+            prev_block_hash: header.prev_block_hash,
+            bits: header.bits,
+            nonce: header.nonce
+        }
+    })
+}
+
+impl Proof {
+    /// verify checks the proof holds for a file hashing to `digest` without
+    /// asking the chain, and returns when the block was mined
+    pub fn verify(&self, digest: &Hash256) -> Result<u128> {
+        let invalid = |reason: String| Err(BlockchainError::InvalidProof(reason));
+        if *digest != self.digest {
+            return invalid(format!("the file hashes to {}, the proof is for {}", digest, self.digest));
+        }
+        let bytes = hex::decode(&self.tx).map_err(|e| BlockchainError::InvalidProof(format!("transaction is not hex: {}", e)))?;
+        let tx: Transaction = bincode::deserialize(&bytes)?;
+        if tx.id != self.txid || !anchors(&tx, digest) {
+            return invalid(format!("transaction {} does not anchor digest {}", self.txid, digest));
+        }
+
+        let block = &self.block;
+        let merkle_root = Block::merkle_root_from_branch(&tx.hash()?, &self.merkle_branch);
+        let hash = pow_hash(&Block::header_prefix(&block.prev_block_hash, &merkle_root, block.time, block.bits)?, block.nonce);
+        if Hash256::new(hash) != block.hash {
+            return invalid(format!("the merkle branch does not lead to block {}", block.hash));
+        }
+        if !Target::from_compact(block.bits)?.is_met_by(&hash) {
+            return invalid(format!("block {} does not meet its target", block.hash));
+        }
+        Ok(block.time)
+    }
+
+    /// verify_on is verify that also checks the block is on the best chain of `bc`
+    pub fn verify_on(&self, bc: &Blockchain, digest: &Hash256) -> Result<u128> {
+        let time = self.verify(digest)?;
+        if bc.get_block_hash(self.block.height).ok() != Some(self.block.hash) {
+            return Err(BlockchainError::InvalidProof(format!("block {} is not on the best chain", self.block.hash)));
+        }
+        Ok(time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::testing::ChainFixture;
+    use crate::tx::OutPoint;
+
+    #[test]
+    fn test_proofs_check_the_file_and_the_chain() -> Result<()> {
+        let fixture = ChainFixture::restore(3)?;
+        let utxo = UTXOSet { blockchain: fixture.blockchain()? };
+        let wallets = Wallets::new(&fixture.config())?;
+        let miner = fixture.miner().to_string();
+
+        let digest = Hash256::sha256(b"my thesis");
+        let fee = Amount::from_sat(1);
+        let anchor = anchor_tx(&wallets, &miner, &digest, fee, &utxo)?;
+        let txid = anchor.id;
+        let spent: HashSet<OutPoint> = anchor.vin.iter().map(|vin| vin.prev_out).collect();
+        let other = Transaction::new_with_outputs(&wallets, &miner, Vec::new(), vec![TXOutput::new_data(b"hello")?], fee, &utxo, |outpoint| !spent.contains(outpoint))?;
+        let coinbase = Transaction::new_coinbase(miner.clone(), "notary".to_string())?;
+        let block = utxo.blockchain.mine_block(vec![coinbase, anchor, other])?;
+        utxo.connect_block(&block)?;
+
+        let proof = prove(&utxo.blockchain, &digest, &txid)?;
+        let proof: Proof = serde_json::from_str(&serde_json::to_string(&proof)?)?;
+        assert_eq!(proof.verify_on(&utxo.blockchain, &digest)?, block.get_timestamp());
+        assert!(proof.verify(&Hash256::sha256(b"my other thesis")).is_err());
+        assert!(prove(&utxo.blockchain, &Hash256::sha256(b"my other thesis"), &txid).is_err());
+
+        let mut forged = proof.clone();
+        forged.block.time += 1;
+        assert!(forged.verify(&digest).is_err());
+        Ok(())
+    }
+}
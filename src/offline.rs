@@ -0,0 +1,183 @@
+//! Hot and cold wallets. The keys stay on an offline instance, the online one
+//! only knows their public keys and builds the transactions, which cross over
+//! as files:
+//!
+//! ```text
+//! cold:   blockchain_project exportpubkey <ADDRESS> --wallet cold
+//! online: blockchain_project importpubkey <PUBKEY>
+//! online: blockchain_project createunsigned <ADDRESS> <TO> 1.5 --out unsigned.json
+//! cold:   blockchain_project signoffline unsigned.json --wallet cold --out signed.hex
+//! online: blockchain_project broadcast signed.hex
+//! ```
+//!
+//! An unsigned transaction carries every transaction its inputs spend, which
+//! the signature covers, so the cold side needs no chain. It checks they hash
+//! to the txids the inputs name before trusting their values for the review.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::amount::Amount;
+use crate::config::Network;
+use crate::error::{BlockchainError, Result};
+use crate::hash::Hash256;
+use crate::transaction::Transaction;
+use crate::tx::{OutPoint, TXOutput};
+use crate::utxoset::UTXOSet;
+use crate::wallet::{hash_pub_key, hash_to_address, Wallets};
+
+/// UnsignedTx is what the online side hands the cold one to sign
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UnsignedTx {
+    pub network: Network,
+    pub txid: Hash256,
+    /// the transaction as hex encoded bincode, its inputs without signatures
+    pub tx: String,
+    /// the transactions the inputs spend, as hex encoded bincode
+    #[serde(rename = "prevtxs")]
+    pub prev_txs: Vec<String>
+}
+
+/// Review is what a transaction does, shown before it is signed
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Review {
+    pub txid: Hash256,
+    pub from: String,
+    /// every output but the change, by address
+    pub pays: Vec<(String, Amount)>,
+    pub change: Amount,
+    pub fee: Amount
+}
+
+/// create_unsigned builds a transaction of `from`, a wallet or a watch-only
+/// address of `wallets`, paying `payment` plus `fee` from the outputs `usable`
+/// accepts, with the transactions it spends
+pub fn create_unsigned(wallets: &Wallets, network: Network, from: &str, payment: TXOutput, fee: Amount, utxo: &UTXOSet, usable: impl Fn(&OutPoint) -> bool) -> Result<UnsignedTx> {
+    let public_key = wallets
+        .public_key(from)
+        .ok_or_else(|| BlockchainError::Wallet(format!("{} is neither a wallet nor a watch-only address of this node", from)))?;
+    let tx = Transaction::new_unsigned(public_key, from, Vec::new(), vec![payment], fee, utxo, usable)?;
+
+    let mut prev_txs = Vec::new();
+    let mut seen = Vec::new();
+    for vin in &tx.vin {
+        if !seen.contains(&vin.prev_out.txid) {
+            seen.push(vin.prev_out.txid);
+            prev_txs.push(hex::encode(bincode::serialize(&utxo.blockchain.find_transaction(&vin.prev_out.txid)?)?));
+        }
+    }
+    Ok(UnsignedTx { network, txid: tx.id, tx: hex::encode(bincode::serialize(&tx)?), prev_txs })
+}
+
+/// sign_offline signs `unsigned` with the wallet of `wallets` owning its
+/// inputs, needing no chain, and returns the signed transaction with its review
+pub fn sign_offline(wallets: &Wallets, network: Network, unsigned: &UnsignedTx) -> Result<(Transaction, Review)> {
+    if unsigned.network != network {
+        return Err(BlockchainError::Config(format!("the transaction is for the {:?} network, not {:?}", unsigned.network, network)));
+    }
+    let mut tx: Transaction = decode(&unsigned.tx)?;
+    if tx.hash()? != tx.id || tx.id != unsigned.txid {
+        return Err(BlockchainError::Consensus(format!("the transaction does not hash to its txid {}", unsigned.txid)));
+    }
+    let mut prev_txs = HashMap::new();
+    for prev in &unsigned.prev_txs {
+        let prev: Transaction = decode(prev)?;
+        if prev.hash()? != prev.id {
+            return Err(BlockchainError::Consensus(format!("previous transaction {} does not hash to its txid", prev.id)));
+        }
+        prev_txs.insert(prev.id, prev);
+    }
+
+    let public_key = match tx.vin.first() {
+        Some(vin) if !tx.is_coinbase() && tx.vin.iter().all(|other| other.pub_key == vin.pub_key) => vin.pub_key.clone(),
+        _ => return Err(BlockchainError::Wallet("the inputs must all spend from one address".to_string()))
+    };
+    let mut pub_key_hash = public_key.clone();
+    hash_pub_key(&mut pub_key_hash);
+    let from = hash_to_address(&pub_key_hash);
+    let wallet = wallets.get_wallet(&from).ok_or_else(|| BlockchainError::Wallet(format!("{} is not a wallet of this store", from)))?;
+
+    let mut input_total = Amount::ZERO;
+    for vin in &tx.vin {
+        let prev_out = prev_txs
+            .get(&vin.prev_out.txid)
+            .and_then(|prev| prev.vout.get(vin.prev_out.index as usize))
+            .ok_or_else(|| BlockchainError::TxNotFound(vin.prev_out.to_string()))?;
+        if prev_out.pub_key_hash != pub_key_hash {
+            return Err(BlockchainError::Wallet(format!("input {} is not an output of {}", vin.prev_out, from)));
+        }
+        input_total = input_total.try_add(prev_out.value)?;
+    }
+    let mut pays = Vec::new();
+    let mut change = Amount::ZERO;
+    for out in tx.vout.iter().filter(|out| !out.is_data()) {
+        if out.pub_key_hash == pub_key_hash {
+            change = change.try_add(out.value)?;
+        } else {
+            pays.push((hash_to_address(&out.pub_key_hash), out.value));
+        }
+    }
+    let fee = input_total.try_sub(Amount::sum(tx.vout.iter().map(|out| out.value))?)?;
+
+    tx.sign(&wallet.secret_key, prev_txs.clone())?;
+    if !tx.verify(prev_txs)? {
+        return Err(BlockchainError::Consensus(format!("the signature of {} does not verify", tx.id)));
+    }
+    let review = Review { txid: tx.id, from, pays, change, fee };
+    Ok((tx, review))
+}
+
+fn decode<T: serde::de::DeserializeOwned>(text: &str) -> Result<T> {
+    let bytes = hex::decode(text.trim()).map_err(|e| BlockchainError::Config(format!("bad hex: {}", e)))?;
+    Ok(bincode::deserialize(&bytes)?)
+}
+
+/// decode_signed reads a signed transaction, hex encoded bincode as
+/// signoffline writes it
+pub fn decode_signed(text: &str) -> Result<Transaction> {
+    decode(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::testing::ChainFixture;
+
+    #[test]
+    fn test_watch_only_builds_and_cold_signs() -> Result<()> {
+        let fixture = ChainFixture::restore(2)?;
+        let config = fixture.config();
+        let miner = fixture.miner().to_string();
+        let cold = Wallets::new(&config)?;
+        let public_key = cold.get_wallet(&miner).expect("the fixture miner").public_key.clone();
+
+        let hot_config = Config { wallet: "hot".to_string(), ..config.clone() };
+        let mut hot = Wallets::new(&hot_config)?;
+        assert_eq!(hot.import_watch_only(public_key)?, miner);
+        hot.save_all()?;
+        let hot = Wallets::new(&hot_config)?;
+        assert!(hot.get_wallet(&miner).is_none());
+
+        let utxo = UTXOSet { blockchain: fixture.blockchain()? };
+        let to = hash_to_address(&[3; 20]);
+        let unsigned = create_unsigned(&hot, config.network, &miner, TXOutput::new(Amount::from_sat(30), to.clone())?, Amount::from_sat(2), &utxo, |_| true)?;
+        let unsigned: UnsignedTx = serde_json::from_str(&serde_json::to_string(&unsigned)?)?;
+        assert!(sign_offline(&hot, config.network, &unsigned).is_err());
+
+        let (mut tx, review) = sign_offline(&cold, config.network, &unsigned)?;
+        assert_eq!((review.pays, review.fee), (vec![(to, Amount::from_sat(30))], Amount::from_sat(2)));
+        assert_eq!(tx.id, unsigned.txid);
+        assert!(utxo.blockchain.verify_transaction(&mut tx)?);
+
+        let other = Wallets::new(&Config { wallet: "other".to_string(), ..config.clone() })?;
+        assert!(sign_offline(&other, config.network, &unsigned).is_err());
+        let mut tampered = unsigned.clone();
+        let mut prev: Transaction = decode(&tampered.prev_txs[0])?;
+        prev.vout[0].value = Amount::from_sat(1_000_000);
+        tampered.prev_txs[0] = hex::encode(bincode::serialize(&prev)?);
+        assert!(sign_offline(&cold, config.network, &tampered).is_err());
+        Ok(())
+    }
+}
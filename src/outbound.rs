@@ -0,0 +1,233 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Write;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use tracing::{debug, error, warn};
+
+use crate::dialer::Dialer;
+
+/// RELAY_WORKERS is the number of threads delivering frames to peers
+pub const RELAY_WORKERS: usize = 4;
+
+/// PEER_TIMEOUT bounds connecting to a peer and writing one frame to it
+pub const PEER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// MAX_QUEUED_FRAMES is how many frames may wait for one peer, more are dropped
+const MAX_QUEUED_FRAMES: usize = 1000;
+
+/// Outbound sends P2P frames from a pool of worker threads instead of the thread
+/// handling a message. Each peer has its own queue, drained by one worker at a
+/// time in a batch, so a slow or unreachable peer only delays its own frames.
+/// The workers stop once every clone of the handle is dropped
+#[derive(Clone)]
+pub struct Outbound {
+    shared: Arc<Shared>,
+    _close: Arc<CloseOnDrop>
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// signalled when a peer becomes ready or the pool closes
+    work: Condvar,
+    /// signalled when no frame is left to deliver
+    idle: Condvar,
+    dialer: Dialer,
+    timeout: Duration,
+    /// called with a peer that could not be connected to
+    on_unreachable: Box<dyn Fn(&str) + Send + Sync>
+}
+
+#[derive(Default)]
+struct State {
+    queues: HashMap<String, VecDeque<Vec<u8>>>,
+    /// peers with queued frames and no worker draining them
+    ready: VecDeque<String>,
+    /// peers a worker is draining
+    busy: HashSet<String>,
+    /// frames queued or being delivered
+    pending: usize,
+    closed: bool
+}
+
+struct CloseOnDrop(Arc<Shared>);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        self.0.lock().closed = true;
+        self.0.work.notify_all();
+    }
+}
+
+impl Outbound {
+    /// new starts `workers` threads connecting through `dialer` with `timeout`,
+    /// `on_unreachable` is told about the peers that refuse connections
+    pub fn new(workers: usize, dialer: Dialer, timeout: Duration, on_unreachable: impl Fn(&str) + Send + Sync + 'static) -> Outbound {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            work: Condvar::new(),
+            idle: Condvar::new(),
+            dialer,
+            timeout,
+            on_unreachable: Box::new(on_unreachable)
+        });
+        for _ in 0..workers.max(1) {
+            let shared = shared.clone();
+            thread::spawn(move || shared.run());
+        }
+
+        Outbound {
+            _close: Arc::new(CloseOnDrop(shared.clone())),
+            shared
+        }
+    }
+
+    /// discarding is an Outbound that drops every frame, for a node with no
+    /// peers to reach
+    pub fn discarding() -> Outbound {
+        let outbound = Outbound::new(1, Dialer::default(), PEER_TIMEOUT, |_| {});
+        outbound.shared.lock().closed = true;
+        outbound.shared.work.notify_all();
+        outbound
+    }
+
+    /// dialer is how the workers connect to peers
+    pub fn dialer(&self) -> &Dialer {
+        &self.shared.dialer
+    }
+
+    /// send queues `frame` for `peer` and returns at once, or drops it once
+    /// the pool is closed
+    pub fn send(&self, peer: &str, frame: Vec<u8>) {
+        let mut state = self.shared.lock();
+        if state.closed {
+            return;
+        }
+        let idle = !state.busy.contains(peer);
+        let queue = state.queues.entry(peer.to_string()).or_default();
+        if queue.len() >= MAX_QUEUED_FRAMES {
+            warn!("dropping a frame for {}, {} are already queued", peer, queue.len());
+            return;
+        }
+        queue.push_back(frame);
+        let first = queue.len() == 1;
+        state.pending += 1;
+        if idle && first {
+            state.ready.push_back(peer.to_string());
+            self.shared.work.notify_one();
+        }
+    }
+
+    /// flush waits up to `timeout` for the queued frames to be delivered and
+    /// tells whether they all were
+    pub fn flush(&self, timeout: Duration) -> bool {
+        let state = self.shared.lock();
+        let (state, _) = self
+            .shared
+            .idle
+            .wait_timeout_while(state, timeout, |state| state.pending > 0)
+            .unwrap_or_else(PoisonError::into_inner);
+        state.pending == 0
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// run drains the queues of ready peers until the pool closes with nothing
+    /// left to send
+    fn run(&self) {
+        let mut state = self.lock();
+        loop {
+            let Some(peer) = state.ready.pop_front() else {
+                if state.closed {
+                    return;
+                }
+                state = self.work.wait(state).unwrap_or_else(PoisonError::into_inner);
+                continue;
+            };
+
+            state.busy.insert(peer.clone());
+            let frames = state.queues.remove(&peer).unwrap_or_default();
+            let count = frames.len();
+            drop(state);
+
+            let reachable = self.deliver(&peer, frames);
+
+            state = self.lock();
+            state.busy.remove(&peer);
+            state.pending -= count;
+            if !reachable {
+                let dropped = state.queues.remove(&peer).map_or(0, |queue| queue.len());
+                state.pending -= dropped;
+            }
+            if state.queues.get(&peer).is_some_and(|queue| !queue.is_empty()) {
+                state.ready.push_back(peer);
+                self.work.notify_one();
+            }
+            if state.pending == 0 {
+                self.idle.notify_all();
+            }
+        }
+    }
+
+    /// deliver sends each frame on a connection of its own, as peers read a
+    /// frame until the connection closes, it gives up on the rest and tells so
+    /// when `peer` cannot be reached
+    fn deliver(&self, peer: &str, frames: VecDeque<Vec<u8>>) -> bool {
+        for frame in frames {
+            let mut stream = match self.dialer.connect(peer, self.timeout) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!("peer {} is unreachable: {}", peer, e);
+                    (self.on_unreachable)(peer);
+                    return false;
+                }
+            };
+            let written = stream.set_write_timeout(Some(self.timeout)).and_then(|_| stream.write_all(&frame));
+            match written {
+                Ok(()) => debug!("sent {} bytes to {}", frame.len(), peer),
+                Err(e) => error!("failed to send to {}: {}", peer, e)
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read};
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn test_frames_reach_live_peers_in_order() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let live = listener.local_addr()?.to_string();
+        let dead = TcpListener::bind("127.0.0.1:0")?.local_addr()?.to_string();
+
+        let unreachable = Arc::new(Mutex::new(Vec::new()));
+        let reported = unreachable.clone();
+        let outbound = Outbound::new(2, Dialer::default(), PEER_TIMEOUT, move |peer| reported.lock().unwrap().push(peer.to_string()));
+        for n in 0..5u8 {
+            outbound.send(&dead, vec![n]);
+            outbound.send(&live, vec![n]);
+        }
+
+        let mut received = Vec::new();
+        for stream in listener.incoming().take(5) {
+            let mut frame = Vec::new();
+            stream?.read_to_end(&mut frame)?;
+            received.extend(frame);
+        }
+        assert!(outbound.flush(PEER_TIMEOUT));
+        assert_eq!(received, vec![0, 1, 2, 3, 4]);
+        assert!(unreachable.lock().unwrap().iter().all(|peer| *peer == dead));
+        assert!(!unreachable.lock().unwrap().is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+//! Transaction packages. A child spending the output of a parent a node has not
+//! seen is refused on its own, and a parent paying too low a fee rate for a
+//! full mempool is evicted before its child arrives. Sent together, a small set
+//! of dependent transactions is checked at once and enters the mempool whole,
+//! ranked by the fee rate of the package, or not at all.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::error::{BlockchainError, Result};
+use crate::hash::Hash256;
+use crate::transaction::Transaction;
+
+/// MAX_PACKAGE_TXS is the most transactions a package may hold
+pub const MAX_PACKAGE_TXS: usize = 25;
+
+/// id names a package by the hash of the txids of its transactions in
+/// ascending order, whatever order they came in
+pub fn id(txs: &[Transaction]) -> Hash256 {
+    let mut txids: Vec<Hash256> = txs.iter().map(|tx| tx.id).collect();
+    txids.sort();
+    Hash256::sha256(&txids.iter().flat_map(|txid| txid.as_bytes().to_vec()).collect::<Vec<u8>>())
+}
+
+/// sort orders the transactions of a package so every parent comes before
+/// the children spending it, refusing an empty or oversized package, a
+/// coinbase, a transaction given twice and two spends of one output
+pub fn sort(txs: Vec<Transaction>) -> Result<Vec<Transaction>> {
+    if txs.is_empty() || txs.len() > MAX_PACKAGE_TXS {
+        return Err(BlockchainError::Consensus(format!("a package holds 1 to {} transactions, not {}", MAX_PACKAGE_TXS, txs.len())));
+    }
+    let mut spends = HashSet::new();
+    let mut pending: HashMap<Hash256, Transaction> = HashMap::new();
+    for tx in txs {
+        if tx.is_coinbase() {
+            return Err(BlockchainError::Consensus(format!("coinbase {} cannot be relayed in a package", tx.id)));
+        }
+        if let Some(spend) = tx.vin.iter().find(|vin| !spends.insert(vin.prev_out)) {
+            return Err(BlockchainError::Consensus(format!("two transactions of the package spend {}", spend.prev_out)));
+        }
+        if let Some(tx) = pending.insert(tx.id, tx) {
+            return Err(BlockchainError::Consensus(format!("transaction {} is twice in the package", tx.id)));
+        }
+    }
+
+    let mut sorted: Vec<Transaction> = Vec::with_capacity(pending.len());
+    while !pending.is_empty() {
+        let ready: Vec<Hash256> = pending
+            .values()
+            .filter(|tx| tx.vin.iter().all(|vin| !pending.contains_key(&vin.prev_out.txid)))
+            .map(|tx| tx.id)
+            .collect();
+        if ready.is_empty() {
+            return Err(BlockchainError::Consensus("the transactions of the package spend each other".to_string()));
+        }
+        let mut ready: Vec<Transaction> = ready.iter().filter_map(|txid| pending.remove(txid)).collect();
+        ready.sort_by_key(|tx| tx.id);
+        sorted.append(&mut ready);
+    }
+    Ok(sorted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::tx::{OutPoint, TXInput, TXOutput};
+
+    fn spend(prev_out: OutPoint, tag: i32) -> Result<Transaction> {
+        let mut tx = Transaction {
+            id: Hash256::ZERO,
+            vin: vec![TXInput { prev_out, signature: Vec::new(), pub_key: vec![1; 32] }],
+            vout: vec![TXOutput { value: Amount::from_sat(tag), pub_key_hash: vec![2; 20] }]
+        };
+        tx.id = tx.hash()?;
+        Ok(tx)
+    }
+
+    #[test]
+    fn test_parents_are_sorted_before_their_children() -> Result<()> {
+        let parent = spend(OutPoint::new(Hash256::new([9; 32]), 0), 1)?;
+        let child = spend(parent.outpoint(0), 2)?;
+        let grandchild = spend(child.outpoint(0), 3)?;
+
+        let sorted = sort(vec![grandchild.clone(), parent.clone(), child.clone()])?;
+        assert_eq!(sorted.iter().map(|tx| tx.id).collect::<Vec<_>>(), vec![parent.id, child.id, grandchild.id]);
+
+        assert!(sort(Vec::new()).is_err());
+        assert!(sort(vec![parent.clone(), parent.clone()]).is_err());
+        assert!(sort(vec![child.clone(), spend(parent.outpoint(0), 4)?]).is_err());
+        Ok(())
+    }
+}
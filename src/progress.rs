@@ -0,0 +1,113 @@
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use tracing::info;
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+const BAR_WIDTH: usize = 30;
+const TTY_REDRAW: Duration = Duration::from_millis(100);
+const LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+/// set_quiet turns off every progress report, used by `--quiet`
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Progress reports how far a long operation is, as a bar redrawn in place when
+/// stderr is a terminal and as periodic log lines otherwise
+pub struct Progress {
+    label: String,
+    total: u64,
+    done: u64,
+    tty: bool,
+    started: Instant,
+    last_report: Instant,
+    last_percent: u64
+}
+
+impl Progress {
+    pub fn new(label: &str, total: u64) -> Progress {
+        let now = Instant::now();
+        Progress {
+            label: label.to_string(),
+            total,
+            done: 0,
+            tty: std::io::stderr().is_terminal(),
+            started: now,
+            last_report: now,
+            last_percent: 0
+        }
+    }
+
+    pub fn inc(&mut self, n: u64) {
+        self.done += n;
+        if QUIET.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let percent = self.percent();
+        if self.tty {
+            if self.last_report.elapsed() >= TTY_REDRAW || self.done >= self.total {
+                self.draw();
+            }
+        } else if percent >= self.last_percent + 10 || self.last_report.elapsed() >= LOG_INTERVAL {
+            info!("{}: {}/{} ({}%){}", self.label, self.done, self.total, percent, self.eta_suffix());
+            self.last_report = Instant::now();
+            self.last_percent = percent;
+        }
+    }
+
+    /// finish draws the final state and reports how long the operation took
+    pub fn finish(&mut self) {
+        if QUIET.load(Ordering::Relaxed) {
+            return;
+        }
+
+        if self.tty {
+            self.draw();
+            eprintln!();
+        }
+        info!("{}: done {} in {:.1}s", self.label, self.done, self.started.elapsed().as_secs_f64());
+    }
+
+    /// eta extrapolates the time left from the pace so far, unknown until
+    /// something is done
+    pub fn eta(&self) -> Option<Duration> {
+        if self.done == 0 || self.done >= self.total {
+            return None;
+        }
+        let per_item = self.started.elapsed().as_secs_f64() / self.done as f64;
+        Some(Duration::from_secs_f64(per_item * (self.total - self.done) as f64))
+    }
+
+    fn eta_suffix(&self) -> String {
+        match self.eta() {
+            Some(eta) => format!(", eta {}s", eta.as_secs()),
+            None => String::new()
+        }
+    }
+
+    fn percent(&self) -> u64 {
+        (self.done * 100).checked_div(self.total).map_or(100, |percent| percent.min(100))
+    }
+
+    fn draw(&mut self) {
+        let filled = self.percent() as usize * BAR_WIDTH / 100;
+        let mut stderr = std::io::stderr();
+        let _ = write!(
+            stderr,
+            "\r{} [{}{}] {}/{} ({}%){}\x1b[K",
+            self.label,
+            "#".repeat(filled),
+            " ".repeat(BAR_WIDTH - filled),
+            self.done,
+            self.total,
+            self.percent(),
+            self.eta_suffix()
+        );
+        let _ = stderr.flush();
+        self.last_report = Instant::now();
+    }
+}
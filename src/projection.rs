@@ -0,0 +1,198 @@
+//! Dry run of the supply and difficulty schedule: the reward, cumulative
+//! supply and target of every retarget epoch a network would go through at a
+//! steady hashrate, next to what an existing chain actually did.
+//!
+//! An epoch is the `RETARGET_INTERVAL` blocks mined with one target. The
+//! projection steps the consensus retarget of `target::next_bits`, so it shows
+//! its quirks too: the interval it measures spans one block less than the
+//! epoch, which makes blocks found exactly on schedule raise the difficulty.
+
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::amount::Amount;
+use crate::blockchain::Blockchain;
+use crate::config::Network;
+use crate::error::{BlockchainError, Result};
+use crate::target::{self, Target, POW_LIMIT_BITS, RETARGET_INTERVAL, TARGET_SPACING};
+use crate::transaction::SUBSIDY;
+
+/// Epoch is a retarget epoch as projected and, when the chain reached it, as mined
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Epoch {
+    pub epoch: usize,
+    /// heights of the first and last block of the epoch
+    pub from: usize,
+    pub to: usize,
+    /// subsidy of each block of the epoch
+    pub reward: Amount,
+    /// coins in existence once the last block of the epoch is mined
+    pub supply: Amount,
+    pub bits: u32,
+    pub difficulty: f64,
+    /// seconds a block takes on average at the projected hashrate
+    pub block_time: f64,
+    /// seconds from the genesis block to the last block of the epoch
+    pub elapsed: f64,
+    pub actual: Option<Actual>
+}
+
+/// Actual is what the chain mined of an epoch
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Actual {
+    /// blocks of the epoch in the chain, fewer than the epoch has for the last one
+    pub blocks: usize,
+    /// coins issued up to the last of those blocks, the fees taken out
+    pub supply: Amount,
+    pub bits: u32,
+    pub difficulty: f64,
+    /// mean seconds between the blocks, None for a lone genesis block
+    pub block_time: Option<f64>,
+    pub elapsed: f64
+}
+
+/// Projection is the schedule of `epochs` epochs of a network
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Projection {
+    pub network: Network,
+    /// hashes per second the projection assumes
+    pub hashrate: f64,
+    pub epochs: Vec<Epoch>
+}
+
+impl Projection {
+    /// new projects `epochs` epochs from the genesis block at `hashrate`
+    /// hashes per second, by default the one that mines the genesis target on
+    /// schedule
+    pub fn new(network: Network, epochs: usize, hashrate: Option<f64>) -> Result<Projection> {
+        let mut bits = POW_LIMIT_BITS;
+        let hashrate = hashrate.unwrap_or(Target::from_compact(bits)?.work() * 1000.0 / TARGET_SPACING as f64);
+        if !(hashrate.is_finite() && hashrate > 0.0) {
+            return Err(BlockchainError::Config(format!("the hashrate must be positive, not {}", hashrate)));
+        }
+
+        let mut projected = Vec::new();
+        let (mut supply, mut elapsed) = (Amount::ZERO, 0.0);
+        for epoch in 0..epochs {
+            let (from, to) = (epoch * RETARGET_INTERVAL, (epoch + 1) * RETARGET_INTERVAL - 1);
+            let target = Target::from_compact(bits)?;
+            let block_time = target.work() / hashrate;
+            supply = Amount::sum(std::iter::once(supply).chain(vec![SUBSIDY; RETARGET_INTERVAL]))
+                .map_err(|_| BlockchainError::Config(format!("the supply overflows in epoch {}", epoch)))?;
+            // the genesis block has no parent to be timed from
+            let intervals = if epoch == 0 { RETARGET_INTERVAL - 1 } else { RETARGET_INTERVAL };
+            elapsed += intervals as f64 * block_time;
+            projected.push(Epoch {
+                epoch,
+                from,
+                to,
+                reward: SUBSIDY,
+                supply,
+                bits,
+                difficulty: target.difficulty(),
+                block_time,
+                elapsed,
+                actual: None
+            });
+            bits = target::next_bits(network, to, bits, || Ok(((RETARGET_INTERVAL - 1) as f64 * block_time * 1000.0) as u128))?;
+        }
+        Ok(Projection { network, hashrate, epochs: projected })
+    }
+
+    /// compare fills in what the best chain of `bc` mined of each epoch
+    pub fn compare(&mut self, bc: &Blockchain) -> Result<()> {
+        let best_height = bc.get_best_height()? as usize;
+        let genesis_time = bc.get_block_header(&bc.get_block_hash(0)?)?.timestamp;
+        let mut supply = Amount::ZERO;
+        for epoch in self.epochs.iter_mut().filter(|e| e.from <= best_height) {
+            let to = epoch.to.min(best_height);
+            for height in epoch.from..=to {
+                supply = supply.try_add(issued(bc, height)?)?;
+            }
+            let last = bc.get_block_header(&bc.get_block_hash(to)?)?;
+            let first = bc.get_block_header(&bc.get_block_hash(epoch.from.saturating_sub(1))?)?;
+            let intervals = to - first.height;
+            let bits = last.bits;
+            epoch.actual = Some(Actual {
+                blocks: to - epoch.from + 1,
+                supply,
+                bits,
+                difficulty: Target::from_compact(bits)?.difficulty(),
+                block_time: (intervals > 0).then(|| last.timestamp.saturating_sub(first.timestamp) as f64 / 1000.0 / intervals as f64),
+                elapsed: last.timestamp.saturating_sub(genesis_time) as f64 / 1000.0
+            });
+        }
+        Ok(())
+    }
+}
+
+/// issued is what the block at `height` adds to the supply: its coinbase
+/// outputs less the fees of its other transactions
+fn issued(bc: &Blockchain, height: usize) -> Result<Amount> {
+    let block = bc.get_block(&bc.get_block_hash(height)?)?;
+    let mut issued = Amount::ZERO;
+    for tx in block.get_transactions() {
+        let outputs = Amount::sum(tx.vout.iter().map(|out| out.value))?;
+        if tx.is_coinbase() {
+            issued = issued.try_add(outputs)?;
+            continue;
+        }
+        let mut inputs = Amount::ZERO;
+        for vin in &tx.vin {
+            let prev_tx = bc.find_transaction(&vin.prev_out.txid)?;
+            let Some(out) = prev_tx.vout.get(vin.prev_out.index as usize) else {
+                return Err(BlockchainError::Consensus(format!("transaction {} spends missing output {}", tx.id, vin.prev_out)));
+            };
+            inputs = inputs.try_add(out.value)?;
+        }
+        issued = issued.try_sub(inputs.try_sub(outputs)?)?;
+    }
+    Ok(issued)
+}
+
+/// Display writes one row per epoch, the actual values under the projected
+/// ones for the epochs the chain reached
+impl fmt::Display for Projection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} network, {:.1} hashes/s, {} blocks per epoch", format!("{:?}", self.network).to_lowercase(), self.hashrate, RETARGET_INTERVAL)?;
+        writeln!(f, "{:<9}{:>14}{:>10}{:>14}{:>12}{:>14}{:>12}{:>12}", "epoch", "heights", "reward", "supply", "bits", "difficulty", "block (s)", "elapsed (s)")?;
+        for e in &self.epochs {
+            let heights = format!("{}-{}", e.from, e.to);
+            writeln!(f, "{:<9}{:>14}{:>10}{:>14}{:>12}{:>14.4}{:>12.2}{:>12.0}", e.epoch, heights, e.reward.to_string(), e.supply.to_string(), format!("{:08x}", e.bits), e.difficulty, e.block_time, e.elapsed)?;
+            if let Some(a) = &e.actual {
+                let block_time = a.block_time.map_or("-".to_string(), |t| format!("{:.2}", t));
+                let mined = format!("{} mined", a.blocks);
+                writeln!(f, "{:<9}{:>14}{:>10}{:>14}{:>12}{:>14.4}{:>12}{:>12.0}", "  actual", mined, "", a.supply.to_string(), format!("{:08x}", a.bits), a.difficulty, block_time, a.elapsed)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ChainFixture;
+
+    #[test]
+    fn test_projection_follows_the_retarget_and_the_chain() -> Result<()> {
+        let main = Projection::new(Network::Main, 3, None)?;
+        assert_eq!(main.epochs[2].supply, Amount::sum(vec![SUBSIDY; 3 * RETARGET_INTERVAL])?);
+        assert_eq!((main.epochs[0].bits, main.epochs[0].block_time), (POW_LIMIT_BITS, TARGET_SPACING as f64 / 1000.0));
+        // on schedule the measured interval is a block short, so the target hardens
+        let ratio = main.epochs[1].difficulty / main.epochs[0].difficulty;
+        let expected = RETARGET_INTERVAL as f64 / (RETARGET_INTERVAL - 1) as f64;
+        assert!((ratio - expected).abs() < 0.001, "{}", ratio);
+        assert!(Projection::new(Network::Main, 1, Some(0.0)).is_err());
+
+        let fixture = ChainFixture::restore(3)?;
+        let mut regtest = Projection::new(Network::Regtest, 2, None)?;
+        assert!(regtest.epochs.iter().all(|e| e.bits == POW_LIMIT_BITS));
+        regtest.compare(&fixture.blockchain()?)?;
+        let actual = regtest.epochs[0].actual.clone().expect("the chain reached epoch 0");
+        assert_eq!((actual.blocks, actual.supply, actual.bits), (4, Amount::sum(vec![SUBSIDY; 4])?, POW_LIMIT_BITS));
+        assert!(regtest.epochs[1].actual.is_none());
+        Ok(())
+    }
+}
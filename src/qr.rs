@@ -0,0 +1,48 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use qrcode::render::unicode::Dense1x2;
+use qrcode::{Color, QrCode};
+
+use crate::error::Result;
+
+/// QUIET_ZONE is the light border, in modules, scanners need around the code
+const QUIET_ZONE: usize = 4;
+
+/// render_terminal draws `data` as a QR code with half block characters,
+/// inverted so it scans on dark terminal backgrounds
+pub fn render_terminal(data: &str) -> Result<String> {
+    let code = QrCode::new(data)?;
+    Ok(code
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .build())
+}
+
+/// write_png saves `data` as a grayscale PNG, `scale` pixels per module
+pub fn write_png(data: &str, path: &Path, scale: usize) -> Result<()> {
+    let code = QrCode::new(data)?;
+    let width = code.width();
+    let colors = code.to_colors();
+    let side = (width + 2 * QUIET_ZONE) * scale;
+
+    let mut pixels = vec![255u8; side * side];
+    for (i, color) in colors.iter().enumerate() {
+        if *color != Color::Dark {
+            continue;
+        }
+        let (x, y) = (i % width + QUIET_ZONE, i / width + QUIET_ZONE);
+        for dy in 0..scale {
+            let row = (y * scale + dy) * side;
+            pixels[row + x * scale..row + (x + 1) * scale].fill(0);
+        }
+    }
+
+    let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), side as u32, side as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&pixels)?;
+    Ok(())
+}
@@ -0,0 +1,69 @@
+//! Per-IP request limits of the public API, shared by the REST and JSON-RPC
+//! servers so a client gets one budget whichever it calls.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::error::{BlockchainError, Result};
+
+/// WINDOW is the period the request budget of an address covers
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// RateLimiter counts the requests of every address in fixed windows of a
+/// minute
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    per_minute: u32,
+    /// start of the current window of each address and its requests in it
+    windows: Arc<Mutex<HashMap<IpAddr, (Instant, u32)>>>
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32) -> RateLimiter {
+        RateLimiter { per_minute, windows: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// check counts a request of `ip`, failing with the seconds left in its
+    /// window once it made `per_minute` requests in it
+    pub fn check(&self, ip: IpAddr) -> Result<()> {
+        self.check_at(ip, Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, now: Instant) -> Result<()> {
+        let mut windows = self.windows.lock().unwrap_or_else(PoisonError::into_inner);
+        // forget the addresses whose window ended when a new one shows up
+        if !windows.contains_key(&ip) {
+            windows.retain(|_, (start, _)| now.duration_since(*start) < WINDOW);
+        }
+        let (start, count) = windows.entry(ip).or_insert((now, 0));
+        if now.duration_since(*start) >= WINDOW {
+            (*start, *count) = (now, 0);
+        }
+        if *count >= self.per_minute {
+            let left = WINDOW.saturating_sub(now.duration_since(*start));
+            return Err(BlockchainError::RateLimited(left.as_secs().max(1)));
+        }
+        *count += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_each_address_gets_its_budget_per_window() {
+        let limiter = RateLimiter::new(2);
+        let (a, b): (IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let start = Instant::now();
+
+        assert!(limiter.check_at(a, start).is_ok());
+        assert!(limiter.check_at(a, start + Duration::from_secs(1)).is_ok());
+        assert!(matches!(limiter.check_at(a, start + Duration::from_secs(20)), Err(BlockchainError::RateLimited(40))));
+        assert!(limiter.check_at(b, start + Duration::from_secs(20)).is_ok());
+        assert!(limiter.check_at(a, start + WINDOW).is_ok());
+    }
+}
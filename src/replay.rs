@@ -0,0 +1,233 @@
+//! Recording of the P2P traffic a node receives, to reproduce off-line what it
+//! did with it.
+//!
+//! A node started with `--record <FILE>` writes one JSON line per inbound
+//! frame, flushed before the frame is handled, so the frame that crashed the
+//! node is in the file. The first line holds the chain the node had when the
+//! recording started, so `replay <FILE>` rebuilds that node in a fresh data
+//! directory and feeds it the frames in order, with nothing sent to peers:
+//!
+//! ```text
+//! blockchain_project startnode --record p2p.ndjson
+//! blockchain_project replay p2p.ndjson
+//! ```
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::config::{Config, Network};
+use crate::error::{BlockchainError, Result};
+use crate::hash::Hash256;
+use crate::server::Server;
+use crate::storage::Compression;
+use crate::utxoset::UTXOSet;
+
+/// Header is the first line of a recording
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub network: Network,
+    /// P2P port of the recording node, which decides whether it relays blocks
+    pub port: String,
+    /// the peers the recording node knew when the recording started
+    pub peers: Vec<String>,
+    /// the best chain when the recording started, from the genesis on, each
+    /// block as hex encoded bincode
+    pub chain: Vec<String>
+}
+
+/// Frame is a line of a recording after the header
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub seq: u64,
+    /// when the frame arrived, in milliseconds since the epoch
+    pub time: u128,
+    pub peer: String,
+    /// the frame as received, hex encoded
+    pub frame: String
+}
+
+/// Recorder appends the frames a node receives to a recording
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    state: Arc<Mutex<RecorderState>>
+}
+
+#[derive(Debug)]
+struct RecorderState {
+    file: File,
+    /// sequence number of the last frame
+    seq: u64
+}
+
+impl Recorder {
+    /// create starts the recording the config names, replacing any older one,
+    /// with the current chain of `bc` in its header
+    pub fn create(config: &Config, bc: &Blockchain, peers: Vec<String>) -> Result<Recorder> {
+        let path = Path::new(&config.p2p_record);
+        let mut chain = Vec::new();
+        for block in bc.iter() {
+            chain.push(hex::encode(bincode::serialize(&block)?));
+        }
+        chain.reverse();
+        let header = Header { network: config.network, port: config.port.clone(), peers, chain };
+
+        let mut file = File::create(path)?;
+        writeln!(file, "{}", serde_json::to_string(&header)?)?;
+        file.flush()?;
+        info!("recording the inbound P2P frames to {}", path.display());
+        Ok(Recorder { state: Arc::new(Mutex::new(RecorderState { file, seq: 0 })) })
+    }
+
+    /// record appends `frame` received from `peer` and flushes it to the file
+    pub fn record(&self, peer: &str, frame: &[u8]) -> Result<()> {
+        let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis();
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let record = Frame { seq: state.seq + 1, time, peer: peer.to_string(), frame: hex::encode(frame) };
+        writeln!(state.file, "{}", serde_json::to_string(&record)?)?;
+        state.file.flush()?;
+        state.seq = record.seq;
+        Ok(())
+    }
+}
+
+/// Recording is a recording read back
+#[derive(Debug, Clone)]
+pub struct Recording {
+    pub header: Header,
+    pub frames: Vec<Frame>
+}
+
+impl Recording {
+    /// open reads the recording at `path`, ignoring a last line cut short by a crash
+    pub fn open(path: &Path) -> Result<Recording> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+        let header = match lines.next() {
+            Some(line) => serde_json::from_str(&line?)?,
+            None => return Err(BlockchainError::Config(format!("{} is not a recording, it is empty", path.display())))
+        };
+        let mut frames = Vec::new();
+        for line in lines {
+            match serde_json::from_str(&line?) {
+                Ok(frame) => frames.push(frame),
+                Err(e) => {
+                    warn!("stopping at a damaged line of {}: {}", path.display(), e);
+                    break;
+                }
+            }
+        }
+        Ok(Recording { header, frames })
+    }
+}
+
+/// FrameError is a recorded frame the node failed to handle
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct FrameError {
+    pub seq: u64,
+    pub peer: String,
+    pub error: String
+}
+
+/// ReplayReport is where a replay left the node
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReplayReport {
+    pub frames: usize,
+    pub errors: Vec<FrameError>,
+    #[serde(rename = "bestblockhash")]
+    pub best_block_hash: Hash256,
+    pub height: i32
+}
+
+/// replay rebuilds the recorded node in `datadir`, which must not hold a chain
+/// of the network yet, and feeds it the frames of `recording` in order. It
+/// neither mines nor sends anything, so the same recording always ends the same
+pub fn replay(config: &Config, recording: &Recording, datadir: &Path) -> Result<ReplayReport> {
+    let header = &recording.header;
+    if header.network != config.network {
+        return Err(BlockchainError::Config(format!("the recording is of the {:?} network, not {:?}", header.network, config.network)));
+    }
+    let config = Config {
+        datadir: datadir.to_string_lossy().into_owned(),
+        port: header.port.clone(),
+        peers: header.peers.clone(),
+        mining_address: String::new(),
+        event_log: false,
+        faucet: false,
+        p2p_record: String::new(),
+        ..config.clone()
+    };
+    if config.blocks_path().exists() {
+        return Err(BlockchainError::Config(format!("{} already holds a chain, replay needs a fresh data directory", datadir.display())));
+    }
+
+    let (genesis, chain) = header.chain.split_first().ok_or_else(|| BlockchainError::Config("the recording has no genesis block".to_string()))?;
+    let utxo = UTXOSet { blockchain: Blockchain::create_with_genesis(&config, decode_block(genesis)?, Compression::None)? };
+    utxo.reindex()?;
+    for block in chain {
+        utxo.receive_block(&decode_block(block)?)?;
+    }
+
+    let server = Server::new(&config, utxo.clone())?.replaying();
+    let mut errors = Vec::new();
+    for frame in &recording.frames {
+        info!("replaying frame {} from {}", frame.seq, frame.peer);
+        if let Err(e) = decode_hex(&frame.frame).and_then(|bytes| server.handle_frame(&bytes)) {
+            errors.push(FrameError { seq: frame.seq, peer: frame.peer.clone(), error: e.to_string() });
+        }
+    }
+
+    Ok(ReplayReport {
+        frames: recording.frames.len(),
+        errors,
+        best_block_hash: utxo.blockchain.get_tip(),
+        height: utxo.blockchain.get_best_height()?
+    })
+}
+
+fn decode_block(text: &str) -> Result<Block> {
+    Ok(bincode::deserialize(&decode_hex(text)?)?)
+}
+
+fn decode_hex(text: &str) -> Result<Vec<u8>> {
+    hex::decode(text).map_err(|e| BlockchainError::Config(format!("the recording holds bad hex: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{Blockmsg, Message, MessageCodec};
+    use crate::testing::ChainFixture;
+    use crate::transaction::Transaction;
+
+    #[test]
+    fn test_replays_end_where_the_recording_node_did() -> Result<()> {
+        let recorded = ChainFixture::restore(2)?;
+        let mut config = recorded.config();
+        config.p2p_record = recorded.datadir().join("p2p.ndjson").to_string_lossy().into_owned();
+        let recorder = Recorder::create(&config, &recorded.blockchain()?, vec!["localhost:3001".to_string()])?;
+
+        let peer = ChainFixture::restore(2)?;
+        let coinbase = Transaction::new_coinbase(peer.miner().to_string(), "replay".to_string())?;
+        let block = peer.blockchain()?.mine_block(vec![coinbase])?;
+        let frame = MessageCodec::encode(&Message::Block(Blockmsg { addr_from: "localhost:3001".to_string(), block: block.clone() }))?;
+        recorder.record("127.0.0.1:50000", &frame)?;
+        recorder.record("127.0.0.1:50001", b"not a frame")?;
+
+        let recording = Recording::open(Path::new(&config.p2p_record))?;
+        assert_eq!(recording.header.chain.len(), 3);
+        let report = replay(&config, &recording, &recorded.datadir().join("replay-1"))?;
+        assert_eq!((report.frames, report.height, report.best_block_hash), (2, 3, block.get_hash()));
+        assert_eq!(report.errors.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![2]);
+
+        assert_eq!(replay(&config, &recording, &recorded.datadir().join("replay-2"))?, report);
+        assert!(replay(&config, &recording, &recorded.datadir().join("replay-1")).is_err());
+        Ok(())
+    }
+}
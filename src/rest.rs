@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::thread;
+
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response};
+use tracing::{error, info};
+
+use crate::address;
+use crate::error::{BlockchainError, Result};
+use crate::hash::Hash256;
+use crate::json::{activity_json, chain_block_json, header_json, tx_json};
+use crate::server::Server;
+use crate::wallet::hash_to_address;
+
+/// OPENAPI_SPEC describes every route below, served at /openapi.json
+const OPENAPI_SPEC: &str = include_str!("openapi.json");
+
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 500;
+
+/// start serves the REST API of `server` on the configured REST port from a background
+/// thread, read-only but for the faucet, on every interface in public API mode
+pub fn start(server: Server) -> Result<()> {
+    let host = if server.config().public_api { "0.0.0.0" } else { "127.0.0.1" };
+    let addr = format!("{}:{}", host, server.config().rest_port);
+    let http = tiny_http::Server::http(&addr).map_err(|e| BlockchainError::Network(format!("cannot bind rest api to {}: {}", addr, e)))?;
+    info!("REST API listening on {}", addr);
+
+    thread::spawn(move || {
+        for request in http.incoming_requests() {
+            if let Err(e) = handle_request(&server, request) {
+                error!("failed to answer rest request: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle_request(server: &Server, request: Request) -> Result<()> {
+    if let (Some(limiter), Some(peer)) = (server.rate_limiter(), request.remote_addr()) {
+        if let Err(BlockchainError::RateLimited(seconds)) = limiter.check(peer.ip()) {
+            let body = json!({ "error": "too many requests", "retryafter": seconds });
+            let header = Header::from_bytes("Retry-After", seconds.to_string()).unwrap();
+            let json_header = Header::from_bytes("Content-Type", "application/json").unwrap();
+            return Ok(request.respond(Response::from_string(body.to_string()).with_status_code(429).with_header(header).with_header(json_header))?);
+        }
+    }
+    let faucet = request.url().strip_prefix("/faucet/").map(str::to_string);
+    let (status, body) = if let (Method::Post, Some(address)) = (request.method(), faucet) {
+        faucet_answer(server, &address)
+    } else if *request.method() != Method::Get {
+        (405, json!({ "error": "only GET is supported, and POST to /faucet" }))
+    } else {
+        if split_url(request.url()).0 == "/openapi.json" {
+            let header = Header::from_bytes("Content-Type", "application/json").unwrap();
+            return Ok(request.respond(Response::from_string(OPENAPI_SPEC).with_header(header))?);
+        }
+        answer(server, request.url())
+    };
+
+    let header = Header::from_bytes("Content-Type", "application/json").unwrap();
+    Ok(request.respond(Response::from_string(body.to_string()).with_status_code(status).with_header(header))?)
+}
+
+/// answer is the status and JSON body of a GET of `url`, shared with the explorer
+pub(crate) fn answer(server: &Server, url: &str) -> (u16, Value) {
+    let (path, query) = split_url(url);
+    match route(server, &path, &query) {
+        Ok(Some(body)) => (200, body),
+        Ok(None) => (404, json!({ "error": format!("no route for {}", path) })),
+        Err(BlockchainError::BlockNotFound(what)) => (404, json!({ "error": format!("block {} not found", what) })),
+        Err(BlockchainError::TxNotFound(txid)) => (404, json!({ "error": format!("transaction {} not found", txid) })),
+        Err(e @ BlockchainError::InvalidAddress(_)) | Err(e @ BlockchainError::ParseInt(_)) => (400, json!({ "error": e.to_string() })),
+        Err(e) => (500, json!({ "error": e.to_string() }))
+    }
+}
+
+/// faucet_answer is the status and JSON body of a POST to /faucet/{address}
+fn faucet_answer(server: &Server, address: &str) -> (u16, Value) {
+    match server.faucet_pay(address) {
+        Ok(body) => (200, body),
+        Err(e @ BlockchainError::Config(_)) => (404, json!({ "error": e.to_string() })),
+        Err(e @ BlockchainError::InvalidAddress(_)) => (400, json!({ "error": e.to_string() })),
+        Err(BlockchainError::RateLimited(seconds)) => (429, json!({ "error": format!("{} was paid recently", address), "retryafter": seconds })),
+        Err(e @ BlockchainError::InsufficientFunds { .. }) => (503, json!({ "error": format!("the faucet is dry: {}", e) })),
+        Err(e) => (500, json!({ "error": e.to_string() }))
+    }
+}
+
+/// route answers a GET request, None when no route matches the path
+fn route(server: &Server, path: &str, query: &HashMap<String, String>) -> Result<Option<Value>> {
+    let bc = &server.utxo_set().blockchain;
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    let body = match segments.as_slice() {
+        ["blocks"] => {
+            let (offset, limit) = page_params(query)?;
+            let headers: Vec<Value> = bc.iter_headers().skip(offset).take(limit).map(|header| header_json(&header)).collect();
+            json!({
+                "total": bc.get_best_height()? + 1,
+                "offset": offset,
+                "limit": limit,
+                "items": headers
+            })
+        },
+        ["blocks", "height", height] => {
+            let hash = bc.get_block_hash(height.parse()?)?;
+            chain_block_json(bc, &bc.get_block(&hash)?)?
+        },
+        ["blocks", hash] => chain_block_json(bc, &bc.get_block(&hash.parse()?)?)?,
+        ["tx", txid] => {
+            let txid: Hash256 = txid.parse()?;
+            if let Some((tx, header)) = bc.get_indexed_transaction(&txid)? {
+                let mut view = tx_json(&tx);
+                view["blockhash"] = json!(header.hash);
+                view["confirmations"] = json!(bc.confirmations(&header)?);
+                view
+            } else if let Some(entry) = server.get_mempool().get(&txid) {
+                let mut view = tx_json(&entry.tx);
+                view["confirmations"] = json!(0);
+                view
+            } else {
+                return Err(BlockchainError::TxNotFound(txid.to_string()));
+            }
+        },
+        ["address", address, "utxos"] => {
+            let pub_key_hash = address::decode(address)?;
+            let best_height = bc.get_best_height()? as usize;
+            let utxos: Vec<Value> = server
+                .utxo_set()
+                .list_unspent(Some(&pub_key_hash))?
+                .into_iter()
+                .map(|out| json!({
+                    "txid": out.outpoint.txid,
+                    "vout": out.outpoint.index,
+                    "address": hash_to_address(&out.pub_key_hash),
+                    "amount": out.value,
+                    "confirmations": (best_height + 1).saturating_sub(out.height)
+                }))
+                .collect();
+            paginate(utxos, query)?
+        },
+        ["address", address, "history"] => {
+            let best_height = bc.get_best_height()? as usize;
+            let history: Vec<Value> = server
+                .address_index()
+                .history(&address::decode(address)?)?
+                .iter()
+                .map(|activity| activity_json(activity, best_height))
+                .collect();
+            paginate(history, query)?
+        },
+        ["address", address, "balance"] => {
+            let mut view = json!(server.address_index().balance(&address::decode(address)?)?);
+            view["address"] = json!(address);
+            view
+        },
+        ["mempool"] => {
+            let mempool = server.get_mempool();
+            let mut entries: Vec<_> = mempool.entries().collect();
+            entries.sort_by(|a, b| b.time.cmp(&a.time).then_with(|| a.tx.id.cmp(&b.tx.id)));
+            let entries: Vec<Value> = entries
+                .into_iter()
+                .map(|e| json!({
+                    "txid": e.tx.id,
+                    "fee": e.fee,
+                    "size": e.size,
+                    "time": e.time
+                }))
+                .collect();
+            paginate(entries, query)?
+        },
+        _ => return Ok(None)
+    };
+    Ok(Some(body))
+}
+
+/// paginate slices `items` with the `offset` and `limit` query parameters
+fn paginate(items: Vec<Value>, query: &HashMap<String, String>) -> Result<Value> {
+    let (offset, limit) = page_params(query)?;
+    let total = items.len();
+    let page: Vec<Value> = items.into_iter().skip(offset).take(limit).collect();
+    Ok(json!({
+        "total": total,
+        "offset": offset,
+        "limit": limit,
+        "items": page
+    }))
+}
+
+/// page_params reads the `offset` and `limit` query parameters, the limit
+/// capped at MAX_LIMIT
+fn page_params(query: &HashMap<String, String>) -> Result<(usize, usize)> {
+    let offset: usize = match query.get("offset") {
+        Some(v) => v.parse()?,
+        None => 0
+    };
+    let limit: usize = match query.get("limit") {
+        Some(v) => v.parse::<usize>()?.min(MAX_LIMIT),
+        None => DEFAULT_LIMIT
+    };
+    Ok((offset, limit))
+}
+
+fn split_url(url: &str) -> (String, HashMap<String, String>) {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let params = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    (path.to_string(), params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paginate_clamps_to_the_items() {
+        let items: Vec<Value> = (0..5).map(|i| json!(i)).collect();
+        let (_, query) = split_url("/mempool?offset=3&limit=10");
+
+        let page = paginate(items, &query).unwrap();
+
+        assert_eq!(page["total"], 5);
+        assert_eq!(page["items"], json!([3, 4]));
+    }
+}
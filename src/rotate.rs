@@ -0,0 +1,147 @@
+//! Key rotation. `rotatewallet` replaces the keychain of a wallet store with
+//! one of a fresh seed and sweeps the spendable outputs of every key pair to a
+//! new address of it, one address per old key so the sweeps do not link the
+//! old keys together. A key the sweep emptied is retired: it no longer pays,
+//! but its history is still followed as a watch-only wallet.
+
+use serde::Serialize;
+
+use crate::consolidate::{consolidate, Consolidation, ConsolidationSummary};
+use crate::error::{BlockchainError, Result};
+use crate::transaction::Transaction;
+use crate::tx::OutPoint;
+use crate::utxoset::UTXOSet;
+use crate::wallet::{hash_pub_key, Wallets};
+
+/// MAX_SWEEP_INPUTS is the most outputs of an old key one sweep spends
+pub const MAX_SWEEP_INPUTS: usize = 500;
+
+/// Sweep is a transaction moving the outputs of an old key to the keychain
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Sweep {
+    pub from: String,
+    #[serde(flatten)]
+    pub summary: ConsolidationSummary
+}
+
+/// Kept is an old key left in use, still holding outputs the sweep could not
+/// move, like coinbase outputs not mature yet
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Kept {
+    pub address: String,
+    pub reason: String
+}
+
+/// Rotation is what `rotate` did to a wallet store
+#[derive(Serialize, Debug, Clone)]
+pub struct Rotation {
+    /// addresses of the new keychain
+    pub addresses: Vec<String>,
+    pub sweeps: Vec<Sweep>,
+    /// old keys to retire once the sweeps are mined
+    pub retired: Vec<String>,
+    pub kept: Vec<Kept>,
+    #[serde(skip)]
+    pub txs: Vec<Transaction>
+}
+
+/// rotate gives `wallets` a keychain of a fresh seed and signs the sweeps of
+/// the outputs `usable` accepts of every old key, each to a new address. The
+/// sweeps pay `options.fee_rate` and leave the coinbase outputs younger than
+/// `options.coinbase_maturity` and the ones not worth their fee behind. The
+/// keys holding nothing else are listed to retire; call save_all to keep the
+/// keychain before the sweeps are mined
+pub fn rotate(wallets: &mut Wallets, utxo: &UTXOSet, options: &Consolidation, usable: impl Fn(&OutPoint) -> bool) -> Result<Rotation> {
+    let options = Consolidation { min_inputs: 1, ..options.clone() };
+    let mut old = wallets.get_all_address();
+    old.sort();
+    wallets.new_keychain();
+
+    let mut rotation = Rotation { addresses: Vec::new(), sweeps: Vec::new(), retired: Vec::new(), kept: Vec::new(), txs: Vec::new() };
+    // a new address no sweep paid goes to the next old key
+    let mut spare = None;
+    for from in old {
+        let Some(wallet) = wallets.get_wallet(&from) else {
+            continue;
+        };
+        let mut pub_key_hash = wallet.public_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+        let unspent = utxo.list_unspent(Some(&pub_key_hash))?.len();
+        if unspent == 0 {
+            rotation.retired.push(from);
+            continue;
+        }
+
+        let to = spare.take().unwrap_or_else(|| wallets.create_wallet());
+        match consolidate(wallets, &from, &to, utxo, &options, &usable) {
+            Ok((tx, summary)) => {
+                if summary.inputs == unspent {
+                    rotation.retired.push(from.clone());
+                } else {
+                    rotation.kept.push(Kept { address: from.clone(), reason: format!("{} of its {} outputs cannot be swept yet", unspent - summary.inputs, unspent) });
+                }
+                rotation.addresses.push(to);
+                rotation.sweeps.push(Sweep { from, summary });
+                rotation.txs.push(tx);
+            },
+            Err(BlockchainError::Wallet(reason)) => {
+                rotation.kept.push(Kept { address: from, reason });
+                spare = Some(to);
+            },
+            Err(e) => return Err(e)
+        }
+    }
+    match spare {
+        Some(to) => rotation.addresses.push(to),
+        None if rotation.addresses.is_empty() => rotation.addresses.push(wallets.create_wallet()),
+        None => {}
+    }
+    Ok(rotation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address;
+    use crate::amount::Amount;
+    use crate::testing::ChainFixture;
+    use crate::transaction::SUBSIDY;
+
+    #[test]
+    fn test_rotation_sweeps_old_keys_to_a_fresh_keychain() -> Result<()> {
+        let fixture = ChainFixture::restore(3)?;
+        let utxo = UTXOSet { blockchain: fixture.blockchain()? };
+        let miner = fixture.miner().to_string();
+        let mut wallets = Wallets::new(&fixture.config())?;
+        let empty = wallets.create_wallet();
+        let balance = |address: &str| -> Result<Amount> {
+            Amount::sum(utxo.list_unspent(Some(&address::decode(address)?))?.iter().map(|out| out.value))
+        };
+        let before = balance(&miner)?;
+
+        let options = Consolidation { fee_rate: 0.01, coinbase_maturity: 0, ..Consolidation::default() };
+        let rotation = rotate(&mut wallets, &utxo, &options, |_| true)?;
+        let keychain = wallets.keychain().cloned().unwrap_or_default();
+        assert_eq!(rotation.addresses, vec![keychain.derive(0).get_address()]);
+        assert_eq!(keychain.next(), 1);
+        assert_eq!(rotation.sweeps.len(), 1);
+        assert_eq!(rotation.sweeps[0].from, miner);
+
+        let coinbase = Transaction::new_coinbase(rotation.addresses[0].clone(), "rotate".to_string())?;
+        let mut txs = vec![coinbase];
+        txs.extend(rotation.txs.iter().cloned());
+        utxo.connect_block(&utxo.blockchain.mine_block(txs)?)?;
+        for address in &rotation.retired {
+            wallets.retire(address)?;
+        }
+        wallets.save_all()?;
+
+        let wallets = Wallets::new(&fixture.config())?;
+        assert!(wallets.is_retired(&miner) && wallets.is_retired(&empty));
+        assert!(wallets.get_wallet(&miner).is_none());
+        assert!(wallets.get_all_watch_only().contains(&miner));
+        assert_eq!(balance(&miner)?, Amount::ZERO);
+        assert_eq!(balance(&rotation.addresses[0])?, before.try_add(SUBSIDY)?.try_sub(rotation.sweeps[0].summary.fee)?);
+        Ok(())
+    }
+}
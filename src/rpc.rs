@@ -0,0 +1,341 @@
+use std::thread;
+use std::time::SystemTime;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rand::{rngs::OsRng, RngCore};
+use serde_json::{json, Value};
+use tiny_http::{Header, Request, Response};
+use tracing::{error, info, warn};
+
+use crate::address;
+use crate::amount::Amount;
+use crate::block::Block;
+use crate::config::Config;
+use crate::error::{BlockchainError, Result};
+use crate::fees::{fee_for, DEFAULT_TARGET};
+use crate::hash::Hash256;
+use crate::json::{activity_json, chain_block_json, template_json, tx_json};
+use crate::miner::Miner;
+use crate::server::Server;
+use crate::transaction::Transaction;
+use crate::wallet::Wallets;
+
+const COOKIE_USER: &str = "__cookie__";
+
+// bitcoind error codes
+const RPC_MISC_ERROR: i32 = -1;
+const RPC_INVALID_ADDRESS_OR_KEY: i32 = -5;
+const RPC_WALLET_INSUFFICIENT_FUNDS: i32 = -6;
+const RPC_DESERIALIZATION_ERROR: i32 = -22;
+const RPC_VERIFY_ERROR: i32 = -25;
+const RPC_PARSE_ERROR: i32 = -32700;
+const RPC_METHOD_NOT_FOUND: i32 = -32601;
+const RPC_INVALID_PARAMS: i32 = -32602;
+
+/// PUBLIC_METHODS are the read-only methods served in public API mode
+const PUBLIC_METHODS: &[&str] = &[
+    "getblockcount",
+    "getbestblockhash",
+    "getblock",
+    "getrawtransaction",
+    "getaddressbalance",
+    "getaddresshistory",
+    "getblockchaininfo",
+    "getmempoolinfo",
+    "getdoublespends",
+    "estimatefee"
+];
+
+/// RpcError is the error object of a JSON-RPC reply
+#[derive(Debug)]
+pub struct RpcError {
+    pub code: i32,
+    pub message: String
+}
+
+impl RpcError {
+    fn new(code: i32, message: impl Into<String>) -> RpcError {
+        RpcError {
+            code,
+            message: message.into()
+        }
+    }
+}
+
+impl From<BlockchainError> for RpcError {
+    fn from(e: BlockchainError) -> RpcError {
+        let code = match e {
+            BlockchainError::InvalidAddress(_) | BlockchainError::TxNotFound(_) | BlockchainError::BlockNotFound(_) => RPC_INVALID_ADDRESS_OR_KEY,
+            BlockchainError::InsufficientFunds { .. } => RPC_WALLET_INSUFFICIENT_FUNDS,
+            BlockchainError::InvalidAmount(_) | BlockchainError::InvalidHash(_) => RPC_INVALID_PARAMS,
+            BlockchainError::Serialization(_) => RPC_DESERIALIZATION_ERROR,
+            BlockchainError::Consensus(_) | BlockchainError::MempoolFull(_) => RPC_VERIFY_ERROR,
+            _ => RPC_MISC_ERROR
+        };
+        RpcError::new(code, e.to_string())
+    }
+}
+
+/// start serves the JSON-RPC API of `server` on the configured RPC port from a background thread,
+/// accepting requests authenticated with `credentials`, or the public methods
+/// from anyone on every interface when there are none
+pub fn start(server: Server, credentials: Option<String>) -> Result<()> {
+    let host = if credentials.is_some() { "127.0.0.1" } else { "0.0.0.0" };
+    let addr = format!("{}:{}", host, server.config().rpc_port);
+    let http = tiny_http::Server::http(&addr).map_err(|e| BlockchainError::Network(format!("cannot bind rpc to {}: {}", addr, e)))?;
+    info!("RPC listening on {}", addr);
+
+    thread::spawn(move || {
+        for request in http.incoming_requests() {
+            if let Err(e) = handle_request(&server, credentials.as_deref(), request) {
+                error!("failed to answer rpc request: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// credentials returns the expected `user:password`, generating and saving
+/// a cookie when the config sets none
+pub fn credentials(config: &Config) -> Result<String> {
+    if !config.rpc_user.is_empty() {
+        return Ok(format!("{}:{}", config.rpc_user, config.rpc_password));
+    }
+
+    let mut secret = [0u8; 32];
+    OsRng.fill_bytes(&mut secret);
+    let cookie = format!("{}:{}", COOKIE_USER, hex::encode(secret));
+    std::fs::create_dir_all(config.network_dir())?;
+    std::fs::write(config.cookie_path(), &cookie)?;
+    info!("RPC cookie written to {}", config.cookie_path().display());
+    Ok(cookie)
+}
+
+fn authorized(request: &Request, credentials: &str) -> bool {
+    request
+        .headers()
+        .iter()
+        .any(|h| h.field.equiv("Authorization") && is_authorization(h.value.as_str(), credentials))
+}
+
+/// is_authorization checks a Basic `Authorization` header value against `credentials`
+pub(crate) fn is_authorization(value: &str, credentials: &str) -> bool {
+    value == format!("Basic {}", STANDARD.encode(credentials))
+}
+
+fn handle_request(server: &Server, credentials: Option<&str>, mut request: Request) -> Result<()> {
+    if credentials.is_some_and(|credentials| !authorized(&request, credentials)) {
+        warn!("rejected unauthorized rpc request from {:?}", request.remote_addr());
+        let header = Header::from_bytes("WWW-Authenticate", "Basic realm=\"jsonrpc\"").unwrap();
+        return Ok(request.respond(Response::empty(401).with_header(header))?);
+    }
+    if let (Some(limiter), Some(peer)) = (server.rate_limiter(), request.remote_addr()) {
+        if let Err(BlockchainError::RateLimited(seconds)) = limiter.check(peer.ip()) {
+            let reply = json!({ "result": null, "error": { "code": RPC_MISC_ERROR, "message": "too many requests" }, "id": null });
+            let header = Header::from_bytes("Retry-After", seconds.to_string()).unwrap();
+            let json_header = Header::from_bytes("Content-Type", "application/json").unwrap();
+            return Ok(request.respond(Response::from_string(reply.to_string()).with_status_code(429).with_header(header).with_header(json_header))?);
+        }
+    }
+
+    let mut body = String::new();
+    request.as_reader().read_to_string(&mut body)?;
+
+    let (status, reply) = match serde_json::from_str::<Value>(&body) {
+        Ok(call) => {
+            let method = call["method"].as_str().unwrap_or_default();
+            let params = call["params"].as_array().cloned().unwrap_or_default();
+            match dispatch(server, method, &params) {
+                Ok(result) => (200, json!({ "result": result, "error": null, "id": call["id"] })),
+                Err(e) => {
+                    let status = if e.code == RPC_METHOD_NOT_FOUND { 404 } else { 500 };
+                    (status, json!({ "result": null, "error": { "code": e.code, "message": e.message }, "id": call["id"] }))
+                }
+            }
+        },
+        Err(e) => (500, json!({ "result": null, "error": { "code": RPC_PARSE_ERROR, "message": e.to_string() }, "id": null }))
+    };
+
+    let header = Header::from_bytes("Content-Type", "application/json").unwrap();
+    Ok(request.respond(Response::from_string(reply.to_string()).with_status_code(status).with_header(header))?)
+}
+
+fn dispatch(server: &Server, method: &str, params: &[Value]) -> std::result::Result<Value, RpcError> {
+    if server.config().public_api && !PUBLIC_METHODS.contains(&method) {
+        return Err(RpcError::new(RPC_METHOD_NOT_FOUND, format!("Method not found: {}", method)));
+    }
+    let bc = &server.utxo_set().blockchain;
+    match method {
+        "getblockcount" => Ok(json!(bc.get_best_height()?)),
+        "getbestblockhash" => Ok(json!(bc.get_tip())),
+        "getblock" => {
+            let block = bc.get_block(&hash_param(params, 0)?)?;
+            if params.get(1).and_then(Value::as_u64) == Some(0) {
+                Ok(json!(hex::encode(bincode::serialize(&block).map_err(BlockchainError::from)?)))
+            } else {
+                Ok(chain_block_json(bc, &block)?)
+            }
+        },
+        "getrawtransaction" => {
+            let txid = hash_param(params, 0)?;
+            let (tx, header) = bc.get_indexed_transaction(&txid)?.ok_or_else(|| BlockchainError::TxNotFound(txid.to_string()))?;
+            let verbose = params.get(1).map(|v| v.as_bool() == Some(true) || v.as_u64() == Some(1)).unwrap_or(false);
+            if !verbose {
+                return Ok(json!(hex::encode(bincode::serialize(&tx).map_err(BlockchainError::from)?)));
+            }
+            let mut view = tx_json(&tx);
+            view["blockhash"] = json!(header.hash);
+            view["confirmations"] = json!(bc.confirmations(&header)?);
+            Ok(view)
+        },
+        "sendrawtransaction" => {
+            let raw = hex::decode(str_param(params, 0)?).map_err(|e| RpcError::new(RPC_DESERIALIZATION_ERROR, e.to_string()))?;
+            let tx: Transaction = bincode::deserialize(&raw).map_err(BlockchainError::from)?;
+            let txid = tx.id;
+            server.submit_transaction(tx)?;
+            Ok(json!(txid))
+        },
+        "submitpackage" => {
+            let raws = params
+                .first()
+                .and_then(Value::as_array)
+                .ok_or_else(|| RpcError::new(RPC_INVALID_PARAMS, "parameter 1 must be an array of raw transactions"))?;
+            let mut txs = Vec::with_capacity(raws.len());
+            for raw in raws {
+                let raw = raw.as_str().ok_or_else(|| RpcError::new(RPC_INVALID_PARAMS, "raw transactions must be strings"))?;
+                let raw = hex::decode(raw).map_err(|e| RpcError::new(RPC_DESERIALIZATION_ERROR, e.to_string()))?;
+                txs.push(bincode::deserialize::<Transaction>(&raw).map_err(BlockchainError::from)?);
+            }
+            Ok(server.submit_package(txs)?)
+        },
+        "getaddressbalance" => {
+            let address = str_param(params, 0)?;
+            let mut view = json!(server.address_index().balance(&address::decode(address)?)?);
+            view["address"] = json!(address);
+            Ok(view)
+        },
+        "getaddresshistory" => {
+            let history = server.address_index().history(&address::decode(str_param(params, 0)?)?)?;
+            let best_height = bc.get_best_height()? as usize;
+            let history: Vec<Value> = history.iter().map(|activity| activity_json(activity, best_height)).collect();
+            Ok(json!(history))
+        },
+        "getbalance" => {
+            let wallets = Wallets::new(server.config())?;
+            let addresses = match params.first() {
+                Some(_) => vec![str_param(params, 0)?.to_string()],
+                None => wallets.get_all_address()
+            };
+            let mut balance = Amount::ZERO;
+            for address in addresses {
+                balance = balance.try_add(address_balance(server, &address)?)?;
+            }
+            Ok(json!(balance))
+        },
+        "sendtoaddress" => {
+            let to = str_param(params, 0)?;
+            // a number is in sats, a string may use coins like "1.5"
+            let amount = match params.get(1) {
+                Some(Value::String(amount)) => amount.parse()?,
+                Some(amount) => amount
+                    .as_i64()
+                    .and_then(|sats| i32::try_from(sats).ok())
+                    .map(Amount::from_sat)
+                    .ok_or_else(|| RpcError::new(RPC_INVALID_PARAMS, "amount must be a number of sats or a string like \"1.5\""))?,
+                None => return Err(RpcError::new(RPC_INVALID_PARAMS, "amount is required"))
+            };
+            // sats per byte, estimated when left out
+            let fee_rate = match params.get(2) {
+                Some(rate) => Some(rate.as_f64().filter(|rate| rate.is_finite() && *rate >= 0.0).ok_or_else(|| RpcError::new(RPC_INVALID_PARAMS, "fee rate must be a number of sats per byte"))?),
+                None => None
+            };
+            Ok(json!(send_to_address(server, to, amount, fee_rate)?))
+        },
+        "getblocktemplate" => {
+            let address = match params.first() {
+                Some(_) => str_param(params, 0)?,
+                None => server.config().mining_address.as_str()
+            };
+            if address::decode(address).is_err() {
+                return Err(RpcError::new(RPC_INVALID_PARAMS, "getblocktemplate needs a valid mining address, as parameter or in the config"));
+            }
+            let template = Miner::new(server.clone(), address).template()?;
+            let curtime = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_err(BlockchainError::from)?.as_millis();
+            Ok(template_json(&template, curtime)?)
+        },
+        "submitblock" => {
+            let raw = hex::decode(str_param(params, 0)?).map_err(|e| RpcError::new(RPC_DESERIALIZATION_ERROR, e.to_string()))?;
+            let block: Block = bincode::deserialize(&raw).map_err(BlockchainError::from)?;
+            server.submit_block(block)?;
+            Ok(Value::Null)
+        },
+        "getpeerinfo" => Ok(json!(server.peer_info())),
+        "getblockchaininfo" => Ok(server.blockchain_info()?),
+        "getmempoolinfo" => Ok(server.mempool_info()?),
+        "getdoublespends" => Ok(json!(server.double_spends())),
+        "getmininginfo" => Ok(server.mining_info()?),
+        "estimatefee" => {
+            let target = params
+                .first()
+                .and_then(Value::as_u64)
+                .ok_or_else(|| RpcError::new(RPC_INVALID_PARAMS, "target must be a number of blocks"))?;
+            Ok(json!(server.fee_estimator().estimate(target as usize)?))
+        },
+        _ => Err(RpcError::new(RPC_METHOD_NOT_FOUND, format!("Method not found: {}", method)))
+    }
+}
+
+fn str_param(params: &[Value], index: usize) -> std::result::Result<&str, RpcError> {
+    params
+        .get(index)
+        .and_then(Value::as_str)
+        .ok_or_else(|| RpcError::new(RPC_INVALID_PARAMS, format!("parameter {} must be a string", index + 1)))
+}
+
+fn hash_param(params: &[Value], index: usize) -> std::result::Result<Hash256, RpcError> {
+    Ok(str_param(params, index)?.parse()?)
+}
+
+/// address_balance sums the unspent outputs of `address`
+pub(crate) fn address_balance(server: &Server, address: &str) -> Result<Amount> {
+    let pub_key_hash = address::decode(address)?;
+    Amount::sum(server.utxo_set().find_UTXO(&pub_key_hash)?.outputs.iter().map(|out| out.value))
+}
+
+/// send_to_address pays `amount` to `to` from the first local wallet able to
+/// cover it plus the fee and submits the transaction, returning its txid. The
+/// fee pays `fee_rate` sats per byte when given, else it is the configured fee
+/// or, when none is, the fee rate estimated for DEFAULT_TARGET blocks
+pub(crate) fn send_to_address(server: &Server, to: &str, amount: Amount, fee_rate: Option<f64>) -> Result<Hash256> {
+    let configured = server.config().fee;
+    let fee_rate = match fee_rate {
+        None if configured == Amount::ZERO => server.fee_estimator().estimate(DEFAULT_TARGET)?.feerate,
+        fee_rate => fee_rate
+    };
+
+    let wallets = Wallets::new(server.config())?;
+    let mut tx = pay(server, &wallets, to, amount, if fee_rate.is_some() { Amount::ZERO } else { configured })?;
+    if let Some(fee_rate) = fee_rate {
+        // the fee does not change the size, amounts have a fixed width
+        tx = pay(server, &wallets, to, amount, fee_for(bincode::serialize(&tx)?.len(), fee_rate))?;
+    }
+    let txid = tx.id;
+    server.submit_transaction(tx)?;
+    Ok(txid)
+}
+
+/// pay builds the transaction paying `amount` and `fee` from the first local
+/// wallet able to cover both
+fn pay(server: &Server, wallets: &Wallets, to: &str, amount: Amount, fee: Amount) -> Result<Transaction> {
+    let needed = amount.try_add(fee)?;
+    let mut from = None;
+    for address in wallets.get_all_address() {
+        if address_balance(server, &address)? >= needed {
+            from = Some(address);
+            break;
+        }
+    }
+    let from = from.ok_or(BlockchainError::InsufficientFunds { available: Amount::ZERO, needed })?;
+    Transaction::new_UTXO(wallets, &from, to, amount, fee, server.utxo_set())
+}
@@ -0,0 +1,291 @@
+//! Recurring payments made by the node. `schedule-send` stores a job in the
+//! chain database, and the node pays it from one of its wallets each time the
+//! chain reaches the next trigger height or the clock the next trigger time:
+//!
+//! ```text
+//! blockchain_project schedule-send --from <ADDRESS> --to <ADDRESS> --amount 0.5 --every 100-blocks
+//! blockchain_project listschedules
+//! blockchain_project cancelschedule 1
+//! ```
+//!
+//! A node that was down for several periods makes a single payment when it
+//! comes back and then counts the next period from there.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::address;
+use crate::amount::Amount;
+use crate::blockchain::Blockchain;
+use crate::config::Config;
+use crate::error::{BlockchainError, Result};
+use crate::hash::Hash256;
+use crate::server::Server;
+use crate::transaction::Transaction;
+use crate::wallet::Wallets;
+
+/// SCHEDULE_TREE maps the big endian id of every job to the bincode Schedule
+const SCHEDULE_TREE: &str = "schedules";
+
+/// TICK is how often the time triggers are checked without a new block
+const TICK: Duration = Duration::from_secs(1);
+
+/// Every is the period of a job
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Every {
+    Blocks(u64),
+    Seconds(u64)
+}
+
+impl fmt::Display for Every {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Every::Blocks(n) => write!(f, "{}-blocks", n),
+            Every::Seconds(n) => write!(f, "{}-seconds", n)
+        }
+    }
+}
+
+/// FromStr reads `<n>-blocks`, or a time like `<n>-seconds`, `<n>-minutes`,
+/// `<n>-hours` or `<n>-days`
+impl FromStr for Every {
+    type Err = BlockchainError;
+
+    fn from_str(s: &str) -> Result<Every> {
+        let invalid = || BlockchainError::Config(format!("'{}' is not a period like 100-blocks or 12-hours", s));
+        let (count, unit) = s.split_once('-').ok_or_else(invalid)?;
+        let count: u64 = count.parse().ok().filter(|n| *n > 0).ok_or_else(invalid)?;
+        let seconds = match unit.trim_end_matches('s') {
+            "block" => return Ok(Every::Blocks(count)),
+            "second" => 1,
+            "minute" => 60,
+            "hour" => 60 * 60,
+            "day" => 24 * 60 * 60,
+            _ => return Err(invalid())
+        };
+        Ok(Every::Seconds(count * seconds))
+    }
+}
+
+/// Schedule is a recurring payment
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    pub id: u64,
+    pub from: String,
+    pub to: String,
+    pub amount: Amount,
+    pub fee: Amount,
+    pub every: Every,
+    /// height, or unix time in seconds, of the next payment
+    pub next: u64,
+    /// payments left, None when the job never ends
+    pub remaining: Option<u64>,
+    /// txids of the payments made so far
+    pub payments: Vec<Hash256>,
+    /// why the last attempt failed, it is tried again at the next check
+    #[serde(rename = "lasterror")]
+    pub last_error: Option<String>
+}
+
+impl Schedule {
+    /// due tells whether the job pays at `height` and `now`
+    fn due(&self, height: u64, now: u64) -> bool {
+        match self.every {
+            Every::Blocks(_) => height >= self.next,
+            Every::Seconds(_) => now >= self.next
+        }
+    }
+}
+
+/// Scheduler keeps the jobs of a node in its chain database and pays them from
+/// the wallets of its config
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    config: Config,
+    bc: Blockchain,
+    /// held while jobs are added, run or cancelled, so a job never pays twice
+    running: Arc<Mutex<()>>
+}
+
+impl Scheduler {
+    pub fn new(config: &Config, bc: &Blockchain) -> Scheduler {
+        Scheduler { config: config.clone(), bc: bc.clone(), running: Arc::new(Mutex::new(())) }
+    }
+
+    /// add stores a job paying `amount` plus `fee` from `from`, a wallet of the
+    /// node, to `to` every period, `times` times or forever. The first payment
+    /// is a period from now
+    pub fn add(&self, from: &str, to: &str, amount: Amount, fee: Amount, every: Every, times: Option<u64>) -> Result<Schedule> {
+        if Wallets::new(&self.config)?.get_wallet(from).is_none() {
+            return Err(BlockchainError::Wallet(format!("{} is not a wallet of this node", from)));
+        }
+        address::decode(to)?;
+        if amount <= Amount::ZERO || times == Some(0) {
+            return Err(BlockchainError::Config("a schedule pays a positive amount at least once".to_string()));
+        }
+
+        let _running = self.running.lock().unwrap_or_else(PoisonError::into_inner);
+        let tree = self.bc.open_tree(SCHEDULE_TREE)?;
+        let id = match tree.last()? {
+            Some((key, _)) => u64::from_be_bytes(key.as_ref().try_into().unwrap_or_default()) + 1,
+            None => 1
+        };
+        let schedule = Schedule {
+            id,
+            from: from.to_string(),
+            to: to.to_string(),
+            amount,
+            fee,
+            every,
+            next: self.next_trigger(every)?,
+            remaining: times,
+            payments: Vec::new(),
+            last_error: None
+        };
+        tree.insert(id.to_be_bytes(), bincode::serialize(&schedule)?)?;
+        info!("scheduled {} from {} to {} every {}", amount, from, to, every);
+        Ok(schedule)
+    }
+
+    /// list returns every job, oldest first
+    pub fn list(&self) -> Result<Vec<Schedule>> {
+        let mut schedules = Vec::new();
+        for item in self.bc.open_tree(SCHEDULE_TREE)?.iter() {
+            schedules.push(bincode::deserialize(&item?.1)?);
+        }
+        Ok(schedules)
+    }
+
+    /// cancel removes the job `id` and returns it
+    pub fn cancel(&self, id: u64) -> Result<Schedule> {
+        let _running = self.running.lock().unwrap_or_else(PoisonError::into_inner);
+        match self.bc.open_tree(SCHEDULE_TREE)?.remove(id.to_be_bytes())? {
+            Some(schedule) => Ok(bincode::deserialize(&schedule)?),
+            None => Err(BlockchainError::Config(format!("no schedule {}", id)))
+        }
+    }
+
+    /// run_due pays the jobs that are due through `server` and returns the txids
+    /// of the payments. A failed payment is noted in its job, not returned
+    pub fn run_due(&self, server: &Server) -> Result<Vec<Hash256>> {
+        let _running = self.running.lock().unwrap_or_else(PoisonError::into_inner);
+        let (height, now) = (self.bc.get_best_height()? as u64, unix_time()?);
+        let tree = self.bc.open_tree(SCHEDULE_TREE)?;
+        let mut paid = Vec::new();
+        for mut schedule in self.list()?.into_iter().filter(|s| s.due(height, now)) {
+            match self.pay(server, &schedule) {
+                Ok(txid) => {
+                    info!("schedule {} paid {} to {} in {}", schedule.id, schedule.amount, schedule.to, txid);
+                    paid.push(txid);
+                    schedule.payments.push(txid);
+                    schedule.last_error = None;
+                    schedule.next = self.next_trigger(schedule.every)?;
+                    schedule.remaining = schedule.remaining.map(|n| n - 1);
+                },
+                Err(e) => {
+                    let e = e.to_string();
+                    if schedule.last_error.as_ref() != Some(&e) {
+                        warn!("schedule {} failed to pay {}: {}", schedule.id, schedule.to, e);
+                    }
+                    schedule.last_error = Some(e);
+                }
+            }
+            if schedule.remaining == Some(0) {
+                tree.remove(schedule.id.to_be_bytes())?;
+            } else {
+                tree.insert(schedule.id.to_be_bytes(), bincode::serialize(&schedule)?)?;
+            }
+        }
+        Ok(paid)
+    }
+
+    /// start runs the due jobs of `server` on every new block and clock tick
+    /// until the server stops
+    pub fn start(&self, server: Server) {
+        let events = self.bc.events().subscribe();
+        let scheduler = self.clone();
+        thread::spawn(move || loop {
+            match events.recv_timeout(TICK) {
+                Ok(_) | Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => return
+            }
+            if server.is_stopping() {
+                return;
+            }
+            if let Err(e) = scheduler.run_due(&server) {
+                error!("failed to run the schedules: {}", e);
+            }
+        });
+    }
+
+    /// pay builds, signs and submits the payment of `schedule`, leaving out the
+    /// outputs the mempool already spends
+    fn pay(&self, server: &Server, schedule: &Schedule) -> Result<Hash256> {
+        let wallets = Wallets::new(&self.config)?;
+        let mempool = server.get_mempool();
+        let tx = Transaction::new_utxo_where(&wallets, &schedule.from, &schedule.to, schedule.amount, schedule.fee, server.utxo_set(), |outpoint| {
+            mempool.spender(outpoint).is_none()
+        })?;
+        let txid = tx.id;
+        server.submit_transaction(tx)?;
+        Ok(txid)
+    }
+
+    /// next_trigger is a period of `every` from the current height or time
+    fn next_trigger(&self, every: Every) -> Result<u64> {
+        Ok(match every {
+            Every::Blocks(n) => self.bc.get_best_height()? as u64 + n,
+            Every::Seconds(n) => unix_time()? + n
+        })
+    }
+}
+
+fn unix_time() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::NodeBuilder;
+    use crate::testing::ChainFixture;
+    use crate::wallet::hash_to_address;
+
+    #[test]
+    fn test_block_schedules_pay_each_period_then_end() -> Result<()> {
+        assert_eq!("12-hours".parse::<Every>()?, Every::Seconds(12 * 60 * 60));
+        assert!("0-blocks".parse::<Every>().is_err());
+        assert!("weekly".parse::<Every>().is_err());
+
+        let fixture = ChainFixture::restore(3)?;
+        let node = NodeBuilder::new().config(fixture.config()).mine_to(fixture.miner()).listen(false).build()?;
+        let server = node.server();
+        let landlord = hash_to_address(&[4; 20]);
+        let scheduler = server.schedules();
+        let rent = scheduler.add(fixture.miner(), &landlord, Amount::from_sat(5), Amount::ZERO, Every::Blocks(2), Some(2))?;
+        assert!(scheduler.add(&landlord, fixture.miner(), Amount::from_sat(5), Amount::ZERO, Every::Blocks(2), None).is_err());
+        assert!(scheduler.run_due(server)?.is_empty());
+
+        node.mine_blocks(2)?;
+        let first = scheduler.run_due(server)?;
+        assert_eq!(first.len(), 1);
+        assert!(scheduler.run_due(server)?.is_empty());
+        assert_eq!(scheduler.list()?[0].payments, first);
+
+        node.mine_blocks(2)?;
+        assert_eq!(scheduler.run_due(server)?.len(), 1);
+        assert!(scheduler.list()?.is_empty());
+        assert!(scheduler.cancel(rent.id).is_err());
+        node.mine_blocks(1)?;
+        assert_eq!(node.balance(&landlord)?, Amount::from_sat(10));
+        node.shutdown()
+    }
+}
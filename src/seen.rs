@@ -0,0 +1,78 @@
+//! The blocks and transactions a node handled lately. Peers announce the same
+//! items over and over, the ones in this set are neither requested nor
+//! validated again. The set is bounded, the oldest hashes leave it first, so
+//! an item seen long ago is only checked against the chain or the mempool.
+
+use std::collections::{HashSet, VecDeque};
+
+use crate::hash::Hash256;
+
+/// SEEN_CAPACITY is how many hashes of each kind a node remembers
+pub const SEEN_CAPACITY: usize = 20_000;
+
+/// SeenSet is a set of at most `capacity` hashes, oldest first
+#[derive(Debug, Clone)]
+pub struct SeenSet {
+    capacity: usize,
+    order: VecDeque<Hash256>,
+    hashes: HashSet<Hash256>
+}
+
+impl Default for SeenSet {
+    fn default() -> SeenSet {
+        SeenSet::new(SEEN_CAPACITY)
+    }
+}
+
+impl SeenSet {
+    pub fn new(capacity: usize) -> SeenSet {
+        SeenSet { capacity: capacity.max(1), order: VecDeque::new(), hashes: HashSet::new() }
+    }
+
+    /// insert remembers `hash`, forgetting the oldest one when the set is
+    /// full, and tells whether it is new
+    pub fn insert(&mut self, hash: Hash256) -> bool {
+        if !self.hashes.insert(hash) {
+            return false;
+        }
+        if self.order.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.hashes.remove(&oldest);
+            }
+        }
+        self.order.push_back(hash);
+        true
+    }
+
+    pub fn contains(&self, hash: &Hash256) -> bool {
+        self.hashes.contains(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_the_oldest_hashes_are_forgotten_first() {
+        let mut seen = SeenSet::new(3);
+        let hashes: Vec<Hash256> = (0..4).map(|i| Hash256::new([i; 32])).collect();
+        assert!(seen.insert(hashes[0]));
+        assert!(seen.insert(hashes[1]));
+        assert!(!seen.insert(hashes[0]));
+        assert!(seen.insert(hashes[2]));
+        assert!(seen.insert(hashes[3]));
+
+        assert_eq!(seen.len(), 3);
+        assert!(!seen.contains(&hashes[0]));
+        assert!(hashes[1..].iter().all(|hash| seen.contains(hash)));
+    }
+}
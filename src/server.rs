@@ -1,128 +1,892 @@
-use std::{collections::{HashMap, HashSet}, io::{Read, Write}, net::TcpStream, sync::{Arc, Mutex}};
-use bincode::deserialize;
-use failure::format_err;
-use log::info;
-use serde::{Deserialize, Serialize};
+use std::{collections::{BTreeMap, HashMap, HashSet}, io::{Read, Write}, net::{SocketAddr, TcpListener, TcpStream}, path::Path, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex, MutexGuard, PoisonError}, thread, time::{Duration, Instant}};
+use rand::seq::SliceRandom;
+use serde_json::{json, Value};
+use tracing::{error, field, info, info_span, warn, Span};
 use crate::{block::Block, transaction::Transaction, utxoset::UTXOSet};
-use crate::error::Result;
+use crate::address;
+use crate::addrindex::AddressIndex;
+use crate::addrman::{self, AddrMan, TimedAddr, HORIZON_SECS, MAX_ADDR_PER_MESSAGE, MAX_GOSSIP_PEERS, MAX_RELAYED_MESSAGE, PENALTY_SECS, RECENT_SECS, RELAY_FANOUT};
+use crate::amount::Amount;
+use crate::banlist::{BanDuration, BanList, DEFAULT_BAN_SECS};
+use crate::blockchain::Blockchain;
+use crate::blockfilter::{FilterIndex, MAX_CFHEADERS, MAX_CFILTERS};
+use crate::codec::{Addrmsg, Blockmsg, CFHeadersmsg, CFiltersmsg, Filteredmsg, GetBlockmsg, GetCFHeadersmsg, GetCFiltersmsg, GetDatamsg, GetFilteredmsg, GetHeadersmsg, Headersmsg, Invmsg, Message, MessageCodec, Packagemsg, Rejectmsg, SendHeadersmsg, Txmsg, Versionmsg, COMPRESS_MIN_BYTES, REJECT_INSUFFICIENT_FEE, REJECT_INVALID, SERVICE_COMPRESSION};
+use crate::config::Config;
+use crate::control;
+use crate::dialer::Dialer;
+use crate::doublespend::{self, DoubleSpend, DoubleSpends};
+use crate::error::{BlockchainError, Result};
+use crate::eventlog::EventLog;
+use crate::events::Event;
+#[cfg(feature = "explorer")]
+use crate::explorer;
+use crate::faucet::Faucet;
+use crate::fees::{FeeEstimator, DEFAULT_TARGET};
+use crate::grpc;
+use crate::hash::Hash256;
+use crate::json::name_json;
+use crate::mempool::{Mempool, MempoolEntry};
+use crate::outbound::{Outbound, PEER_TIMEOUT, RELAY_WORKERS};
+use crate::miner::{self, Miner, MinerSettings, MiningStats};
+use crate::names::NameIndex;
+use crate::netaddr;
+use crate::package;
+use crate::progress::Progress;
+use crate::replay::Recorder;
+use crate::rest;
+use crate::ratelimit::RateLimiter;
+use crate::rpc;
+use crate::schedule::{Every, Scheduler};
+use crate::seen::SeenSet;
+use crate::spv::{self, SpvHeader};
+use crate::stratum;
+use crate::target::Target;
+use crate::watch;
+use crate::ws;
+use crate::zmq;
+use crate::history::{apply_labels, HistoryIndexer};
+use crate::hygiene::{self, WalletAudit, DUST_FEE_RATE, STUCK_SECS};
+use crate::wallet::{hash_pub_key, Wallets};
 
-const KNOWN_NODE1: &str = "localhost:3000";
-const CMD_LEN: usize = 12;
+pub(crate) const KNOWN_NODE1: &str = "localhost:3000";
 const VERSION: i32 = 1;
 
-pub struct Server {
-    node_address: String,
-    mining_address: String,
-    inner: Arc<Mutex<ServerInner>>
-}
-
-pub struct ServerInner {
-    known_nodes: HashSet<String>,
-    utxo: UTXOSet,
-    blocks_in_transit: Vec<String>,
-    mempool: HashMap<String, Transaction>
-}
+/// USER_AGENT names the software and build of this node in version messages
+pub const USER_AGENT: &str = concat!("/toychain:", env!("CARGO_PKG_VERSION"), "/");
 
+/// MAX_USER_AGENT_LEN is the longest user agent a peer may send
+const MAX_USER_AGENT_LEN: usize = 256;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Blockmsg {
-    addr_from: String,
-    block: Block
-}
+/// MAX_REJECT_REASON_LEN is the most characters of the reason of a reject
+const MAX_REJECT_REASON_LEN: usize = 256;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct GetBlockmsg {
-    addr_from: String,
+/// PeerVersion is what a peer said of its software in its version message
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PeerVersion {
+    version: i32,
+    services: u64,
+    user_agent: String
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct GetDatamsg {
-    addr_from: String,
-    kind: String,
-    id: String
+/// PeerTraffic counts the bytes of the frames sent to and received from a
+/// peer, before and after compression
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct PeerTraffic {
+    uncompressed: u64,
+    sent: u64,
+    received_uncompressed: u64,
+    received: u64
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Invmsg {
-    addr_from: String,
-    kind: String,
-    items: Vec<String>
+/// AcceptedPackage is a package sorted parents first, with the txids, fee and
+/// size of the transactions the mempool did not hold before
+#[derive(Debug, Clone)]
+struct AcceptedPackage {
+    txs: Vec<Transaction>,
+    new: Vec<Hash256>,
+    fee: Amount,
+    size: usize
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Txmsg {
-    addr_from: String,
-    transaction: Transaction
+impl AcceptedPackage {
+    /// fee_rate is the fee of the new transactions per byte of them
+    fn fee_rate(&self) -> f64 {
+        match self.size {
+            0 => 0.0,
+            size => self.fee.to_sat() as f64 / size as f64
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Versionmsg {
-    addr_from: String,
-    version: i32,
-    best_height: i32
+/// Server is a P2P node that relays transactions and blocks, and mines them when
+/// it has a mining address
+#[derive(Clone)]
+pub struct Server {
+    node_address: String,
+    mining_address: String,
+    config: Config,
+    started: Instant,
+    utxo: UTXOSet,
+    inner: Arc<Mutex<ServerInner>>,
+    /// history of the local wallets, served by `listtransactions`
+    history: HistoryIndexer,
+    /// activity of every address, served by the address endpoints
+    addresses: AddressIndex,
+    /// compact filter of every block, served to light clients
+    filters: FilterIndex,
+    /// names registered on the chain, served by `name_lookup`
+    names: NameIndex,
+    /// hosts the operator banned, refused both ways
+    bans: BanList,
+    /// fee rates the recent blocks confirmed, served by `estimatefee`
+    fees: FeeEstimator,
+    /// pays coins on request when the config enables it
+    faucet: Option<Faucet>,
+    /// recurring payments, made while the server listens
+    schedules: Scheduler,
+    /// limits the requests of each IP to the public API, None outside public API mode
+    rate_limiter: Option<RateLimiter>,
+    /// set by `stop_listening` to end the accept loops of `start_server`
+    stopping: Arc<AtomicBool>,
+    /// addresses the accept loops listen on, woken by `stop_listening`
+    listening: Arc<Mutex<Vec<SocketAddr>>>,
+    mining_stats: Arc<MiningStats>,
+    mining_settings: Arc<MinerSettings>,
+    /// set while a background thread waits for transactions to mine
+    mempool_miner: Arc<AtomicBool>,
+    /// delivers the frames sent to peers
+    outbound: Outbound,
+    /// writes the inbound frames to a recording when the config names one
+    recorder: Option<Recorder>
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-enum Message {
-    Addr(Vec<String>),
-    Version(Versionmsg),
-    Tx(Txmsg),
-    GetData(GetDatamsg),
-    GetBlock(GetBlockmsg),
-    Inv(Invmsg),
-    Block(Blockmsg)
+/// ServerInner is the node state shared by the connection threads
+pub struct ServerInner {
+    known_nodes: HashSet<String>,
+    /// every peer address heard of, known nodes or not
+    addrman: AddrMan,
+    blocks_in_transit: Vec<Hash256>,
+    mempool: Mempool,
+    peer_best_height: i32,
+    /// best height each peer is known to have, by normalized address
+    peer_heights: HashMap<String, i32>,
+    /// peers that asked for new blocks to be announced with headers
+    header_peers: HashSet<String>,
+    /// software of each peer that sent its version, by normalized address
+    peer_versions: HashMap<String, PeerVersion>,
+    /// bytes sent to each peer, by normalized address
+    peer_traffic: HashMap<String, PeerTraffic>,
+    /// blocks received lately, not requested or validated again
+    seen_blocks: SeenSet,
+    /// transactions accepted lately, not requested or validated again
+    seen_txs: SeenSet,
+    sync: Option<Progress>,
+    double_spends: DoubleSpends
 }
 
-struct CmdTypes {
-    addr_type: &[u8],
-}
 
 impl Server {
-    pub fn new(port: &str, miner_address: &str, utxo: UTXOSet) -> Result<Server> {
+    /// New creates a server from the config, its peers replace the default known node when set
+    pub fn new(config: &Config, utxo: UTXOSet) -> Result<Server> {
+        if config.coinbase_msg.len() > miner::MAX_COINBASE_MSG {
+            return Err(BlockchainError::Config(format!("the coinbase message is longer than {} bytes", miner::MAX_COINBASE_MSG)));
+        }
 
+        let dialer = Dialer::new(config)?;
         let mut node_set = HashSet::new();
-        node_set.insert(String::from(KNOWN_NODE1));
+        if config.peers.is_empty() {
+            node_set.insert(String::from(KNOWN_NODE1));
+        } else {
+            for peer in &config.peers {
+                let peer = netaddr::normalize(peer)?;
+                if dialer.allows(&peer) {
+                    node_set.insert(peer);
+                } else {
+                    warn!("not connecting to {}, it is outside onlynet", peer);
+                }
+            }
+        }
+        if config.public_api && config.faucet {
+            return Err(BlockchainError::Config("the faucet pays from a wallet, it cannot run in public API mode".to_string()));
+        }
+        let pub_key_hashes = if config.public_api {
+            Vec::new()
+        } else {
+            let wallets = Wallets::new(config)?;
+            wallets
+                .get_all_address()
+                .iter()
+                .filter_map(|address| wallets.get_wallet(address))
+                .map(|wallet| {
+                    let mut pub_key_hash = wallet.public_key.clone();
+                    hash_pub_key(&mut pub_key_hash);
+                    pub_key_hash
+                })
+                .collect()
+        };
+        let history = HistoryIndexer::start(&utxo.blockchain, pub_key_hashes)?;
+        let addresses = AddressIndex::start(&utxo.blockchain)?;
+        let filters = FilterIndex::start(&utxo.blockchain)?;
+        let names = NameIndex::start(&utxo.blockchain)?;
+        let bans = BanList::new(&utxo.blockchain)?;
+        let fees = FeeEstimator::start(&utxo.blockchain)?;
+        if config.event_log {
+            EventLog::start(&utxo.blockchain, &config.event_log_path())?;
+        }
+        let faucet = if config.faucet { Some(Faucet::new(config)?) } else { None };
+        let schedules = Scheduler::new(config, &utxo.blockchain);
+        let rate_limiter = config.public_api.then(|| RateLimiter::new(config.public_api_rate));
+        let recorder = if config.p2p_record.is_empty() {
+            None
+        } else {
+            let mut peers: Vec<String> = node_set.iter().cloned().collect();
+            peers.sort();
+            Some(Recorder::create(config, &utxo.blockchain, peers)?)
+        };
+        let mut addrman = AddrMan::default();
+        let now = addrman::now()?;
+        for peer in &node_set {
+            addrman.add(TimedAddr { addr: peer.clone(), time: now }, peer);
+        }
+        let inner = Arc::new(Mutex::new(ServerInner {
+            known_nodes: node_set,
+            addrman,
+            blocks_in_transit: Vec::new(),
+            mempool: Mempool::with_max_usage(config.max_mempool),
+            peer_best_height: -1,
+            peer_heights: HashMap::new(),
+            header_peers: HashSet::new(),
+            peer_versions: HashMap::new(),
+            peer_traffic: HashMap::new(),
+            seen_blocks: SeenSet::default(),
+            seen_txs: SeenSet::default(),
+            sync: None,
+            double_spends: DoubleSpends::default()
+        }));
+        let peers = inner.clone();
+        let outbound = Outbound::new(RELAY_WORKERS, dialer, PEER_TIMEOUT, move |peer| {
+            peers.lock().unwrap_or_else(PoisonError::into_inner).known_nodes.remove(peer);
+        });
+
         Ok(
             Server {
-                node_address: String::from("localhost:") + port,
-                mining_address: miner_address.to_string(),
-                inner: Arc::new(Mutex::new( ServerInner {
-                    known_nodes: node_set,
-                    utxo,
-                    blocks_in_transit: Vec::new(),
-                    mempool: HashMap::new(),
-                })),
+                node_address: config.advertised_address()?,
+                mining_address: config.mining_address.clone(),
+                config: config.clone(),
+                started: Instant::now(),
+                utxo,
+                inner,
+                history,
+                addresses,
+                filters,
+                names,
+                bans,
+                fees,
+                faucet,
+                schedules,
+                rate_limiter,
+                stopping: Arc::new(AtomicBool::new(false)),
+                listening: Arc::new(Mutex::new(Vec::new())),
+                mining_stats: Arc::new(MiningStats::default()),
+                mining_settings: Arc::new(MinerSettings::new(config)),
+                mempool_miner: Arc::new(AtomicBool::new(false)),
+                outbound,
+                recorder
             }
         )
     }
 
+    /// replaying turns the server into one fed recorded frames, it sends
+    /// nothing to its peers
+    pub(crate) fn replaying(self) -> Server {
+        Server { outbound: Outbound::discarding(), recorder: None, ..self }
+    }
+
+    /// StartServer announces the node to its peers and, when `listen` is set,
+    /// serves incoming connections until the process is interrupted
+    pub fn start_server(&self, listen: bool) -> Result<()> {
+        info!(
+            "Start server at {}, mining address: {}",
+            &self.node_address, &self.mining_address
+        );
+
+        let server1 = self.clone();
+        let announce = thread::spawn(move || -> Result<()> {
+            thread::sleep(Duration::from_millis(1000));
+            for node in server1.get_known_nodes() {
+                server1.send_version(&node)?;
+            }
+            Ok(())
+        });
+
+        if !listen {
+            info!("Not listening for inbound connections, exiting after announcing to peers");
+            let announced = match announce.join() {
+                Ok(res) => res,
+                Err(_) => Err(BlockchainError::Network("announce thread panicked".to_string()))
+            };
+            if !self.outbound.flush(PEER_TIMEOUT) {
+                warn!("exiting before every peer got the announcement");
+            }
+            return announced;
+        }
+
+        if self.config.public_api {
+            info!("Public API mode, serving the read-only REST and JSON-RPC endpoints to every interface");
+            if !self.config.rpc_port.is_empty() {
+                rpc::start(self.clone(), None)?;
+            }
+            for (port, service) in [(&self.config.grpc_port, "gRPC"), (&self.config.work_port, "work server")] {
+                if !port.is_empty() {
+                    warn!("the {} is not served in public API mode", service);
+                }
+            }
+        } else if !self.config.rpc_port.is_empty() || !self.config.grpc_port.is_empty() {
+            let credentials = rpc::credentials(&self.config)?;
+            if !self.config.rpc_port.is_empty() {
+                rpc::start(self.clone(), Some(credentials.clone()))?;
+            }
+            if !self.config.grpc_port.is_empty() {
+                grpc::start(self.clone(), credentials)?;
+            }
+        }
+        if !self.config.rest_port.is_empty() {
+            rest::start(self.clone())?;
+        }
+        if !self.config.explorer_port.is_empty() {
+            #[cfg(feature = "explorer")]
+            explorer::start(self.clone())?;
+            #[cfg(not(feature = "explorer"))]
+            warn!("explorer_port is set but this build has no explorer, rebuild with --features explorer");
+        }
+        if !self.config.ws_port.is_empty() {
+            ws::start(self.clone())?;
+        }
+        if !self.config.zmq_pub_raw_block.is_empty() || !self.config.zmq_pub_raw_tx.is_empty() {
+            zmq::start(self.clone())?;
+        }
+        if !self.config.work_port.is_empty() && !self.config.public_api {
+            stratum::start(self.clone())?;
+        }
+
+        control::start(self.clone())?;
+        if !self.config.public_api {
+            self.schedules.start(self.clone());
+        }
+
+        let mut listeners = Vec::new();
+        for bind in self.config.listen_addresses() {
+            let listener = netaddr::listen(&bind)?;
+            info!("Server listen on {}", listener.local_addr()?);
+            self.listening.lock().unwrap_or_else(PoisonError::into_inner).push(listener.local_addr()?);
+            listeners.push(listener);
+        }
+        let first = listeners.remove(0);
+        for listener in listeners {
+            let server1 = self.clone();
+            thread::spawn(move || {
+                if let Err(e) = server1.accept(listener) {
+                    error!("failed to accept connections: {}", e);
+                }
+            });
+        }
+        let accepted = self.accept(first);
+
+        info!("Server stopped listening");
+        let _ = std::fs::remove_file(self.config.control_socket_path());
+        accepted
+    }
+
+    /// accept serves the connections of `listener` until `stop_listening`
+    fn accept(&self, listener: TcpListener) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            if self.stopping.load(Ordering::SeqCst) {
+                break;
+            }
+            if stream.peer_addr().is_ok_and(|peer| self.is_banned(&peer.ip().to_string())) {
+                continue;
+            }
+            let server1 = self.clone();
+            thread::spawn(move || {
+                let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+                let span = info_span!("connection", peer = %peer, command = field::Empty, duration_ms = field::Empty);
+                let _entered = span.enter();
+                let started = Instant::now();
+                if let Err(e) = server1.handle_connection(stream) {
+                    error!("failed to handle connection: {}", e);
+                }
+                span.record("duration_ms", started.elapsed().as_millis() as u64);
+            });
+        }
+        Ok(())
+    }
+
+    /// stop_listening makes `start_server` return, waking each accept loop with a
+    /// connection of its own
+    pub fn stop_listening(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        for addr in self.listening.lock().unwrap_or_else(PoisonError::into_inner).iter() {
+            let _ = TcpStream::connect(netaddr::loopback(*addr));
+        }
+    }
+
+    /// is_stopping tells the background threads of `start_server` to exit
+    pub(crate) fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
     fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
         let mut buffer = Vec::new();
         let count = stream.read_to_end(&mut buffer)?;
         info!("Accept request: length {}", count);
+        if let Some(recorder) = &self.recorder {
+            let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+            if let Err(e) = recorder.record(&peer, &buffer) {
+                error!("failed to record a frame from {}: {}", peer, e);
+            }
+        }
+        if let Some(reply) = self.handle_frame(&buffer)? {
+            stream.write_all(&MessageCodec::encode(&reply)?)?;
+        }
+        Ok(())
+    }
 
-        let cmd = bytes_to_cmd(&buffer)?;
+    /// handle_frame acts on the message of one P2P frame, returning the reply
+    /// to write back on the connection for the requests of light clients
+    pub(crate) fn handle_frame(&self, buffer: &[u8]) -> Result<Option<Message>> {
+        let (cmd, uncompressed) = MessageCodec::decode_sized(buffer)?;
+        Span::current().record("command", cmd.command());
+        if let Some(from) = cmd.addr_from() {
+            self.count_received(from, buffer.len(), uncompressed);
+        }
 
         match cmd {
-            Message::Addr(data) => self.handle_addr(data)?,
-            Message::Block(data) => self.handle_block(data)?,
+            Message::Addr(data) => {
+                // an older node sends no times, its addresses are never fresh
+                let stale = addrman::now()?.saturating_sub(PENALTY_SECS);
+                self.handle_addr(None, data.into_iter().map(|addr| TimedAddr { addr, time: stale }).collect())?
+            },
+            Message::AddrV2(data) => self.handle_addr(Some(&data.addr_from), data.addrs)?,
+            Message::SendHeaders(data) => self.handle_send_headers(data),
+            Message::Reject(data) => self.handle_reject(data)?,
+            Message::Package(data) => {
+                let (from, id) = (data.addr_from.clone(), package::id(&data.transactions));
+                self.handle_package(data).map_err(|e| self.reject(&from, "package", id, e))?
+            },
+            // light clients get headers on the connection they asked on, a node
+            // sending them announces new blocks
+            Message::Headers(data) => self.handle_headers(data)?,
+            Message::Block(data) => {
+                let (from, hash) = (data.addr_from.clone(), data.block.get_hash());
+                self.handle_block(data).map_err(|e| self.reject(&from, "block", hash, e))?
+            },
             Message::Inv(data) => self.handle_inv(data)?,
             Message::GetBlock(data) => self.handle_get_blocks(data)?,
+            // a light client has no address to send the block to
+            Message::GetData(data) if data.addr_from.is_empty() => return self.handle_light_get_data(data).map(Some),
             Message::GetData(data) => self.handle_get_data(data)?,
-            Message::Tx(data) => self.handle_tx(data)?,
-            Message::Version(data) => self.handle_version(data)?
+            Message::Tx(data) => {
+                let (from, txid) = (data.addr_from.clone(), data.transaction.id);
+                self.handle_tx(data).map_err(|e| self.reject(&from, "tx", txid, e))?
+            },
+            Message::Version(data) => self.handle_version(data)?,
+            Message::GetHeaders(data) => return self.handle_get_headers(data).map(Some),
+            Message::GetFiltered(data) => return self.handle_get_filtered(data).map(Some),
+            Message::GetCFilters(data) => return self.handle_get_cfilters(data).map(Some),
+            Message::GetCFHeaders(data) => return self.handle_get_cfheaders(data).map(Some),
+            Message::Filtered(_) | Message::CFilters(_) | Message::CFHeaders(_) => {
+                return Err(BlockchainError::Network(format!("a node does not take {} messages", cmd.command())));
+            }
         }
 
-        Ok(())
+        Ok(None)
+
+    }
+
+    /// handle_control answers an operator command received on the control socket
+    pub fn handle_control(&self, command: &str, args: &[String]) -> Result<Value> {
+        match command {
+            "status" => self.status(),
+            "peers" => {
+                let mut peers: Vec<String> = self.get_known_nodes().into_iter().collect();
+                peers.sort();
+                Ok(json!(peers))
+            },
+            "getblockchaininfo" => self.blockchain_info(),
+            "getpeerinfo" => Ok(json!(self.peer_info())),
+            "getmempoolinfo" => self.mempool_info(),
+            "getdoublespends" => Ok(json!(self.double_spends())),
+            "getmininginfo" => self.mining_info(),
+            "estimatefee" => match args.first() {
+                Some(target) => Ok(serde_json::to_value(self.fees.estimate(target.parse()?)?)?),
+                None => Err(BlockchainError::Config("estimatefee needs a target in blocks".to_string()))
+            },
+            "setgenerate" => self.set_generate(args),
+            "getrawmempool" => self.raw_mempool(args.iter().any(|a| a == "verbose")),
+            "gettxconfirmations" => match args.first() {
+                Some(txid) => self.tx_confirmations(&txid.parse()?),
+                None => Err(BlockchainError::Network("gettxconfirmations needs a txid".to_string()))
+            },
+            "sendrawtransaction" => match args.first() {
+                Some(raw) => {
+                    let raw = hex::decode(raw).map_err(|e| BlockchainError::Network(format!("sendrawtransaction needs hex: {}", e)))?;
+                    let tx: Transaction = bincode::deserialize(&raw)?;
+                    let txid = tx.id;
+                    self.submit_transaction(tx)?;
+                    Ok(json!(txid))
+                },
+                None => Err(BlockchainError::Network("sendrawtransaction needs a transaction".to_string()))
+            },
+            "walletaudit" => Ok(serde_json::to_value(self.wallet_audit()?)?),
+            "listtransactions" => match args.first() {
+                Some(address) => self.list_transactions(address),
+                None => Err(BlockchainError::Network("listtransactions needs an address".to_string()))
+            },
+            "name_lookup" => match args.first() {
+                Some(name) => Ok(name_json(name, self.names.lookup(name)?.as_ref())),
+                None => Err(BlockchainError::Network("name_lookup needs a name".to_string()))
+            },
+            "faucet" => match args.first() {
+                Some(address) => self.faucet_pay(address),
+                None => Err(BlockchainError::Network("faucet needs an address".to_string()))
+            },
+            "schedulesend" => match args {
+                [from, to, amount, every, times @ ..] => {
+                    let times = match times.first() {
+                        Some(times) => Some(times.parse()?),
+                        None => None
+                    };
+                    let every: Every = every.parse()?;
+                    Ok(serde_json::to_value(self.schedules.add(from, to, amount.parse()?, self.config.fee, every, times)?)?)
+                },
+                _ => Err(BlockchainError::Network("schedulesend needs a from address, a to address, an amount and a period".to_string()))
+            },
+            "ban" => match args {
+                [addr, duration @ ..] => {
+                    let duration = match duration.first() {
+                        Some(duration) => duration.parse()?,
+                        None => BanDuration(DEFAULT_BAN_SECS)
+                    };
+                    let ban = self.bans.ban(addr, duration)?;
+                    for node in self.get_known_nodes() {
+                        if netaddr::host(&node) == ban.host {
+                            self.remove_node(&node);
+                        }
+                    }
+                    Ok(serde_json::to_value(ban)?)
+                },
+                [] => Err(BlockchainError::Network("ban needs an address".to_string()))
+            },
+            "unban" => match args.first() {
+                Some(addr) => Ok(json!(self.bans.unban(addr)?)),
+                None => Err(BlockchainError::Network("unban needs an address".to_string()))
+            },
+            "listbanned" => Ok(serde_json::to_value(self.bans.list()?)?),
+            "listschedules" => Ok(serde_json::to_value(self.schedules.list()?)?),
+            "cancelschedule" => match args.first() {
+                Some(id) => Ok(serde_json::to_value(self.schedules.cancel(id.parse()?)?)?),
+                None => Err(BlockchainError::Network("cancelschedule needs a schedule id".to_string()))
+            },
+            "waitfornewblock" => {
+                let known = match args.first() {
+                    Some(hash) => hash.parse()?,
+                    None => Hash256::ZERO
+                };
+                let timeout = match args.get(1) {
+                    Some(ms) => Duration::from_millis(ms.parse()?),
+                    None => Duration::from_secs(60)
+                };
+                self.wait_for_new_block(&known, timeout)
+            },
+            "watchaddress" => {
+                let Some(address) = args.first() else {
+                    return Err(BlockchainError::Network("watchaddress needs an address".to_string()));
+                };
+                let known = match args.get(1) {
+                    Some(state) => state.parse()?,
+                    None => Hash256::ZERO
+                };
+                let depth = match args.get(2) {
+                    Some(depth) => depth.parse()?,
+                    None => 1 + watch::DEPTH_MARGIN
+                };
+                let timeout = match args.get(3) {
+                    Some(ms) => Duration::from_millis(ms.parse()?),
+                    None => Duration::from_secs(60)
+                };
+                Ok(serde_json::to_value(watch::wait_for_activity(self, address, &known, depth, timeout)?)?)
+            },
+            _ => Err(BlockchainError::Network(format!("unknown control command: {}", command)))
+        }
+    }
+
+    /// Status reports the chain, mempool, peer and sync state of the running node
+    fn status(&self) -> Result<Value> {
+        let mut status = chain_status(&self.config, &self.utxo.blockchain)?;
+
+        let (mempool_size, peers, peer_best_height, syncing) = {
+            let inner = self.lock_inner();
+            (inner.mempool.len(), inner.known_nodes.len(), inner.peer_best_height, inner.sync.is_some())
+        };
+        let progress = verification_progress(self.get_best_height()?, peer_best_height);
+
+        status["mempool_size"] = json!(mempool_size);
+        status["peers"] = json!(peers);
+        status["peer_software"] = json!(self.peer_software());
+        status["uptime"] = json!(self.started.elapsed().as_secs());
+        status["sync_progress"] = json!(progress * 100.0);
+        status["verificationprogress"] = json!(progress);
+        status["initialblockdownload"] = json!(syncing);
+        Ok(status)
+    }
+
+    /// peer_info describes each known node with the software and best height it
+    /// announced, null until it sent its version, and the bytes sent to and
+    /// received from it
+    pub fn peer_info(&self) -> Vec<Value> {
+        let inner = self.lock_inner();
+        let mut peers: Vec<&String> = inner.known_nodes.iter().collect();
+        peers.sort();
+        peers
+            .into_iter()
+            .map(|addr| {
+                let version = inner.peer_versions.get(addr);
+                let traffic = inner.peer_traffic.get(addr).copied().unwrap_or_default();
+                json!({
+                    "addr": addr,
+                    "version": version.map(|v| v.version),
+                    "subver": version.map(|v| v.user_agent.as_str()),
+                    "bestheight": inner.peer_heights.get(addr),
+                    "sendheaders": inner.header_peers.contains(addr),
+                    "compression": version.is_some_and(|v| v.services & SERVICE_COMPRESSION != 0),
+                    "bytessent": traffic.sent,
+                    "bytessent_uncompressed": traffic.uncompressed,
+                    "bytesrecv": traffic.received,
+                    "bytesrecv_uncompressed": traffic.received_uncompressed
+                })
+            })
+            .collect()
+    }
+
+    /// peer_software counts the known nodes by the user agent they announced,
+    /// the ones that did not under "unknown"
+    pub fn peer_software(&self) -> BTreeMap<String, usize> {
+        let inner = self.lock_inner();
+        let mut counts = BTreeMap::new();
+        for addr in &inner.known_nodes {
+            let agent = inner.peer_versions.get(addr).map_or("unknown", |v| v.user_agent.as_str());
+            *counts.entry(agent.to_string()).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    /// blockchain_info reports the chain this node follows, the best height its
+    /// peers advertised and how far it is from reaching it
+    pub fn blockchain_info(&self) -> Result<Value> {
+        let height = self.get_best_height()?;
+        let (peer_best_height, syncing) = {
+            let inner = self.lock_inner();
+            (inner.peer_best_height, inner.sync.is_some())
+        };
+        Ok(json!({
+            "chain": self.config.network,
+            "blocks": height,
+            "headers": height.max(peer_best_height),
+            "bestblockhash": self.utxo.blockchain.get_tip(),
+            "difficulty": self.utxo.blockchain.get_difficulty()?,
+            "chainwork": self.utxo.blockchain.get_chain_work(&self.utxo.blockchain.get_tip())?.to_string(),
+            "verificationprogress": verification_progress(height, peer_best_height),
+            "initialblockdownload": syncing
+        }))
+    }
+
+    /// mining_info reports the difficulty, the estimated network hashrate and what
+    /// this node's miners did since it started
+    pub fn mining_info(&self) -> Result<Value> {
+        let stats = self.mining_stats();
+        Ok(json!({
+            "blocks": self.get_best_height()?,
+            "difficulty": self.utxo.blockchain.get_difficulty()?,
+            "networkhashps": miner::network_hashrate(&self.utxo.blockchain, miner::HASHRATE_WINDOW)?,
+            "localhashps": stats.hashrate(),
+            "generate": self.mining_settings.generate(),
+            "threads": self.mining_settings.pow_options().threads,
+            "mining": stats.is_mining(),
+            "miningaddress": self.mining_address,
+            "hashes": stats.hashes(),
+            "blocksfound": stats.blocks_found(),
+            "feesearned": stats.fees_earned()?,
+            "pooledtx": self.lock_inner().mempool.len()
+        }))
+    }
+
+    /// set_generate turns mining of received transactions on or off, `on` may be
+    /// followed by the number of mining threads, 0 for one per CPU core
+    fn set_generate(&self, args: &[String]) -> Result<Value> {
+        let generate = match args.first().map(String::as_str) {
+            Some("on") => true,
+            Some("off") => false,
+            _ => return Err(BlockchainError::Config("setgenerate needs on or off".to_string()))
+        };
+        if generate && self.mining_address.is_empty() {
+            return Err(BlockchainError::Config("mining needs a mining address, start the node with startminer".to_string()));
+        }
+        let threads = match args.get(1) {
+            Some(threads) => Some(threads.parse()?),
+            None => None
+        };
 
+        self.mining_settings.set_generate(generate, threads);
+        info!("generate {}, {} mining threads", generate, self.mining_settings.pow_options().threads);
+        Ok(json!({
+            "generate": generate,
+            "threads": self.mining_settings.pow_options().threads
+        }))
     }
 
-    fn handle_addr(&self, msg: Vec<String>) -> Result<()> {
-        info!("receive address msg: {:?}", msg);
-        for node in msg {
-            self.add_nodes(&node);
+    /// mining_settings are the mining options of this node, changed by `setgenerate`
+    pub fn mining_settings(&self) -> &MinerSettings {
+        &self.mining_settings
+    }
+
+    /// mining_stats are the counters kept by the miners of this node
+    pub fn mining_stats(&self) -> &MiningStats {
+        &self.mining_stats
+    }
+
+    /// mempool_info reports the size, bytes, memory usage against its cap and
+    /// minimum fee rate of the mempool
+    pub fn mempool_info(&self) -> Result<Value> {
+        let inner = self.lock_inner();
+        Ok(json!({
+            "size": inner.mempool.len(),
+            "bytes": inner.mempool.total_bytes(),
+            "usage": inner.mempool.usage(),
+            "maxmempool": inner.mempool.max_usage(),
+            "minfeerate": inner.mempool.min_fee_rate()
+        }))
+    }
+
+    /// TxConfirmations counts the confirmations of a transaction, 0 while it is
+    /// in the mempool or unknown to this node
+    fn tx_confirmations(&self, txid: &Hash256) -> Result<Value> {
+        let height = self.get_best_height()?;
+        let confirmations = match self.utxo.blockchain.get_indexed_transaction(txid)? {
+            Some((_, header)) => height - header.height as i32 + 1,
+            None => 0
+        };
+
+        Ok(json!({
+            "txid": txid,
+            "confirmations": confirmations,
+            "inmempool": self.lock_inner().mempool.contains(txid),
+            "bestblockhash": self.utxo.blockchain.get_tip()
+        }))
+    }
+
+    /// ListTransactions reports the indexed history of one of the node's wallets
+    fn list_transactions(&self, address: &str) -> Result<Value> {
+        let pub_key_hash = address::decode(address)?;
+
+        // a wallet created after the node started is imported on first use
+        let wallets = Wallets::new(&self.config)?;
+        if self.history.history(&pub_key_hash)?.is_none() && wallets.public_key(address).is_some() {
+            self.history.watch(pub_key_hash.clone())?;
+        }
+        match self.history.history(&pub_key_hash)? {
+            Some(mut history) => {
+                apply_labels(&mut history, &wallets);
+                Ok(serde_json::to_value(history)?)
+            },
+            None => Err(BlockchainError::Wallet(format!("{} is not a wallet of this node", address)))
+        }
+    }
+
+    /// WalletAudit warns about the address reuse, dust, stuck transactions and
+    /// unbacked keys of the node's wallets, judging dust at the fee rate
+    /// estimated for DEFAULT_TARGET blocks
+    fn wallet_audit(&self) -> Result<WalletAudit> {
+        let wallets = Wallets::new(&self.config)?;
+        let now = addrman::now()?;
+        let pending: Vec<MempoolEntry> = self.lock_inner().mempool.entries().filter(|entry| entry.time + STUCK_SECS <= now).cloned().collect();
+        let fee_rate = self.fees.estimate(DEFAULT_TARGET)?.feerate.unwrap_or(DUST_FEE_RATE);
+        hygiene::audit(&wallets, &self.utxo, &self.history, &pending, fee_rate, now)
+    }
+
+    /// WaitForNewBlock blocks until the tip differs from `known` or `timeout` passes,
+    /// then reports the current tip
+    fn wait_for_new_block(&self, known: &Hash256, timeout: Duration) -> Result<Value> {
+        let events = self.utxo.blockchain.events().subscribe();
+        let deadline = Instant::now() + timeout;
+        while self.utxo.blockchain.get_tip() == *known {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            if events.recv_timeout(deadline - now).is_err() {
+                break;
+            }
+        }
+
+        Ok(json!({
+            "hash": self.utxo.blockchain.get_tip(),
+            "height": self.get_best_height()?
+        }))
+    }
+
+    /// lock_inner locks the shared state, a thread that panicked while holding it
+    /// leaves it usable rather than taking the whole node down
+    fn lock_inner(&self) -> MutexGuard<'_, ServerInner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// RawMempool lists the mempool txids, or every entry keyed by txid when verbose
+    fn raw_mempool(&self, verbose: bool) -> Result<Value> {
+        let inner = self.lock_inner();
+        if !verbose {
+            return Ok(json!(inner.mempool.txids()));
+        }
+
+        let mut entries = serde_json::Map::new();
+        for entry in inner.mempool.entries() {
+            entries.insert(entry.tx.id.to_string(), json!({
+                "fee": entry.fee,
+                "size": entry.size,
+                "time": entry.time,
+                "ancestors": inner.mempool.ancestors(&entry.tx.id)
+            }));
         }
+        Ok(Value::Object(entries))
+    }
 
+    /// handle_addr records the addresses a peer announced, makes known nodes
+    /// of them while there are fewer than MAX_GOSSIP_PEERS, and relays the new
+    /// and fresh ones of a small announcement to RELAY_FANOUT random peers
+    fn handle_addr(&self, from: Option<&str>, addrs: Vec<TimedAddr>) -> Result<()> {
+        let from = from.and_then(|from| netaddr::normalize(from).ok());
+        info!("receive address msg from {:?}: {} addresses", from, addrs.len());
+        if addrs.len() > MAX_ADDR_PER_MESSAGE {
+            return Err(BlockchainError::Network(format!("{} addresses in one message, more than {}", addrs.len(), MAX_ADDR_PER_MESSAGE)));
+        }
+        let now = addrman::now()?;
+        let source = from.clone().unwrap_or_default();
+        let relay = addrs.len() <= MAX_RELAYED_MESSAGE;
+        let mut relays: HashMap<String, Vec<TimedAddr>> = HashMap::new();
+        for record in addrs {
+            let addr = match netaddr::normalize(&record.addr) {
+                Ok(addr) if self.accepts_peer(&addr) => addr,
+                _ => continue
+            };
+            let time = if record.time > now + RECENT_SECS { now.saturating_sub(PENALTY_SECS) } else { record.time };
+            if time + HORIZON_SECS < now {
+                continue;
+            }
+            let record = TimedAddr { addr: addr.clone(), time };
+            let (new, promote, peers) = {
+                let mut inner = self.lock_inner();
+                let new = inner.addrman.add(record.clone(), &source);
+                (new, inner.known_nodes.len() < MAX_GOSSIP_PEERS, inner.known_nodes.iter().cloned().collect::<Vec<_>>())
+            };
+            if promote {
+                self.add_nodes(&addr);
+            }
+            if !(relay && new && time + RECENT_SECS >= now) {
+                continue;
+            }
+            // a local address means nothing to a peer outside the local network
+            let peers: Vec<&String> = peers
+                .iter()
+                .filter(|peer| Some(*peer) != from.as_ref() && **peer != addr && (netaddr::is_routable(&addr) || !netaddr::is_routable(peer)))
+                .collect();
+            for peer in peers.choose_multiple(&mut rand::thread_rng(), RELAY_FANOUT) {
+                relays.entry((*peer).clone()).or_default().push(record.clone());
+            }
+        }
+
+        for (peer, addrs) in relays {
+            self.send_timed_addrs(&peer, addrs)?;
+        }
         Ok(())
     }
 
@@ -132,7 +896,25 @@ impl Server {
             msg.addr_from,
             msg.block.get_hash()
         );
-        self.add_block(msg.block)?;
+        self.note_peer_height(&msg.addr_from, msg.block.get_height() as i32);
+        if self.block_is_known(&msg.block.get_hash()) {
+            info!("block {} already seen", msg.block.get_hash());
+        } else {
+            if !msg.block.validate()? {
+                return Err(BlockchainError::Consensus(format!("block {} does not meet its proof of work", msg.block.get_hash())));
+            }
+            let hash = msg.block.get_hash();
+            let txids: Vec<Hash256> = msg.block.get_transactions().iter().map(|tx| tx.id).collect();
+            self.add_block(&msg.block)?;
+            let mut inner = self.lock_inner();
+            inner.seen_blocks.insert(hash);
+            for txid in txids {
+                inner.seen_txs.insert(txid);
+            }
+        }
+        if let Some(progress) = self.lock_inner().sync.as_mut() {
+            progress.inc(1);
+        }
 
         let mut in_transit = self.get_in_transit()?;
         if in_transit.len() > 0 {
@@ -141,18 +923,781 @@ impl Server {
             in_transit.remove(0);   
             self.replace_in_transit(in_transit);
         } else {
-            self.utxo_reindex()?;
+            if let Some(mut progress) = self.lock_inner().sync.take() {
+                progress.finish();
+            }
+            self.utxo.blockchain.set_bulk_sync(false)?;
+        }
+        Ok(())
+
+    }
+
+    fn handle_version(&self, msg: Versionmsg) -> Result<()> {
+        info!("receive version msg: {:?}", msg);
+        if msg.user_agent.len() > MAX_USER_AGENT_LEN {
+            return Err(BlockchainError::Network(format!("user agent of {} bytes, more than {}", msg.user_agent.len(), MAX_USER_AGENT_LEN)));
+        }
+        {
+            let mut inner = self.lock_inner();
+            inner.peer_best_height = inner.peer_best_height.max(msg.best_height);
+            inner.peer_heights.insert(peer_key(&msg.addr_from), msg.best_height);
+            inner.peer_versions.insert(peer_key(&msg.addr_from), PeerVersion { version: msg.version, services: msg.services, user_agent: msg.user_agent.clone() });
+        }
+
+        let my_best_height = self.get_best_height()?;
+        if my_best_height < msg.best_height {
+            self.send_get_blocks(&msg.addr_from)?;
+        } else if my_best_height > msg.best_height {
+            self.send_version(&msg.addr_from)?;
+        }
+        if my_best_height <= msg.best_height {
+            self.send_send_headers(&msg.addr_from)?;
+        }
+
+        self.send_addr(&msg.addr_from)?;
+
+        if !self.node_is_known(&msg.addr_from) {
+            self.add_nodes(&msg.addr_from);
+        }
+        Ok(())
+    }
+
+    /// handle_send_headers notes that the peer wants new blocks announced with
+    /// headers
+    fn handle_send_headers(&self, msg: SendHeadersmsg) {
+        info!("receive send headers msg from {}", msg.addr_from);
+        self.lock_inner().header_peers.insert(peer_key(&msg.addr_from));
+    }
+
+    /// handle_reject logs why a peer refused a block or transaction of ours
+    fn handle_reject(&self, msg: Rejectmsg) -> Result<()> {
+        if msg.reason.chars().count() > MAX_REJECT_REASON_LEN {
+            return Err(BlockchainError::Network(format!("reject reason of more than {} characters", MAX_REJECT_REASON_LEN)));
+        }
+        warn!("{} rejected {} {} with code {:#04x}: {}", msg.addr_from, msg.message, msg.hash, msg.code, msg.reason);
+        Ok(())
+    }
+
+    /// reject tells the peer at `addr` why its `message` carrying `hash` was
+    /// refused, when `error` is the fault of the message, and hands `error` back
+    fn reject(&self, addr: &str, message: &str, hash: Hash256, error: BlockchainError) -> BlockchainError {
+        let code = match error {
+            BlockchainError::Consensus(_) | BlockchainError::InvalidAmount(_) | BlockchainError::InvalidAddress(_) => REJECT_INVALID,
+            BlockchainError::MempoolFull(_) => REJECT_INSUFFICIENT_FEE,
+            _ => return error
+        };
+        if addr.is_empty() {
+            return error;
+        }
+        info!("send reject to: {} {} {}: {}", addr, message, hash, error);
+        let data = Rejectmsg {
+            addr_from: self.node_address.clone(),
+            message: message.to_string(),
+            hash,
+            code,
+            reason: error.to_string().chars().take(MAX_REJECT_REASON_LEN).collect()
+        };
+        if let Err(e) = MessageCodec::encode(&Message::Reject(data)).and_then(|frame| self.send_data(addr, frame)) {
+            warn!("failed to send a reject to {}: {}", addr, e);
+        }
+        error
+    }
+
+    /// handle_headers fetches the announced blocks this node lacks, or the
+    /// whole chain of the peer when they do not connect to a stored block
+    fn handle_headers(&self, msg: Headersmsg) -> Result<()> {
+        info!("receive headers msg from {}: {} headers", msg.addr_from, msg.headers.len());
+        self.note_peer_height(&msg.addr_from, msg.best_height as i32);
+        let bc = &self.utxo.blockchain;
+        for SpvHeader { header, .. } in msg.headers {
+            if !Target::from_compact(header.bits)?.is_met_by(header.hash.as_bytes()) {
+                return Err(BlockchainError::Consensus(format!("header {} does not meet its target", header.hash)));
+            }
+            if self.block_is_known(&header.hash) {
+                continue;
+            }
+            if bc.get_block_header(&header.prev_block_hash).is_err() {
+                return self.send_get_blocks(&msg.addr_from);
+            }
+            self.send_get_data(&msg.addr_from, "block", &header.hash)?;
+        }
+        Ok(())
+    }
+
+    fn handle_inv(&self, msg: Invmsg) -> Result<()> {
+        info!("receive inv msg: {:?}", msg);
+        if msg.items.is_empty() {
+            return Ok(());
+        }
+
+        if msg.kind == "block" {
+            let items: Vec<Hash256> = msg.items.into_iter().filter(|hash| !self.block_is_known(hash)).collect();
+            let Some(block_hash) = items.first() else {
+                return Ok(());
+            };
+            self.lock_inner().sync = Some(Progress::new("sync blocks", items.len() as u64));
+            self.utxo.blockchain.set_bulk_sync(true)?;
+            self.send_get_data(&msg.addr_from, "block", block_hash)?;
+
+            let mut new_in_transit = Vec::new();
+            for b in &items {
+                if b != block_hash {
+                    new_in_transit.push(*b);
+                }
+            }
+            self.replace_in_transit(new_in_transit);
+        } else if msg.kind == "tx" {
+            let txid = &msg.items[0];
+            if !self.tx_is_known(txid) {
+                self.send_get_data(&msg.addr_from, "tx", txid)?;
+            }
         }
         Ok(())
+    }
+
+    fn handle_get_blocks(&self, msg: GetBlockmsg) -> Result<()> {
+        info!("receive get blocks msg: {:?}", msg);
+        let block_hashs = self.utxo.blockchain.get_block_hashs();
+        self.send_inv(&msg.addr_from, "block", block_hashs)
+    }
+
+    fn handle_get_headers(&self, msg: GetHeadersmsg) -> Result<Message> {
+        info!("receive get headers msg: {:?}", msg);
+        let best_height = self.get_best_height()? as usize;
+        let headers = spv::headers(&self.utxo.blockchain, msg.from)?;
+        Ok(Message::Headers(Headersmsg { addr_from: self.node_address.clone(), best_height, headers }))
+    }
 
+    fn handle_get_filtered(&self, msg: GetFilteredmsg) -> Result<Message> {
+        info!("receive get filtered msg: {} from {}", msg.addr_from, msg.from);
+        let (to, txs) = spv::filtered(&self.utxo.blockchain, &msg.filter, msg.from)?;
+        Ok(Message::Filtered(Filteredmsg { addr_from: self.node_address.clone(), to, txs }))
     }
 
+    fn handle_get_cfilters(&self, msg: GetCFiltersmsg) -> Result<Message> {
+        info!("receive get cfilters msg: {:?}", msg);
+        let filters = self.filters.filters(msg.from, MAX_CFILTERS)?.into_iter().map(|(hash, filter, _)| (hash, filter)).collect();
+        Ok(Message::CFilters(CFiltersmsg { addr_from: self.node_address.clone(), from: msg.from, filters }))
+    }
+
+    fn handle_get_cfheaders(&self, msg: GetCFHeadersmsg) -> Result<Message> {
+        info!("receive get cfheaders msg: {:?}", msg);
+        let filters = self.filters.filters(msg.from, MAX_CFHEADERS)?;
+        let prev_header = match msg.from {
+            0 => Hash256::ZERO,
+            from => {
+                let prev = self.utxo.blockchain.get_block_hash(from - 1)?;
+                self.filters.filter(&prev)?.map(|(_, header)| header).ok_or_else(|| BlockchainError::Network(format!("no filter for height {} yet", from - 1)))?
+            }
+        };
+        Ok(Message::CFHeaders(CFHeadersmsg {
+            addr_from: self.node_address.clone(),
+            from: msg.from,
+            stop: filters.last().map_or(Hash256::ZERO, |(hash, _, _)| *hash),
+            prev_header,
+            filter_hashes: filters.iter().map(|(_, filter, _)| filter.hash()).collect()
+        }))
+    }
+
+    /// handle_light_get_data answers a light client with the block it asks
+    /// for on its connection
+    fn handle_light_get_data(&self, msg: GetDatamsg) -> Result<Message> {
+        info!("receive light get data msg: {:?}", msg);
+        if msg.kind != "block" {
+            return Err(BlockchainError::Network(format!("a light client can only get blocks, not {}", msg.kind)));
+        }
+        Ok(Message::Block(Blockmsg { addr_from: self.node_address.clone(), block: self.utxo.blockchain.get_block(&msg.id)? }))
+    }
+
+    fn handle_get_data(&self, msg: GetDatamsg) -> Result<()> {
+        info!("receive get data msg: {:?}", msg);
+        if msg.kind == "block" {
+            let block = self.utxo.blockchain.get_raw_block(&msg.id)?;
+            self.send_raw_block(&msg.addr_from, &msg.id, &block)?;
+        } else if msg.kind == "tx" {
+            match self.get_mempool_tx(&msg.id) {
+                Some(tx) => self.send_tx(&msg.addr_from, &tx)?,
+                None => return Err(BlockchainError::TxNotFound(msg.id.to_string()))
+            }
+        }
+        Ok(())
+    }
+
+    /// utxo_set is the chain and UTXO set served by this node
+    pub fn utxo_set(&self) -> &UTXOSet {
+        &self.utxo
+    }
+
+    /// address_index is the activity of every address of the chain
+    pub fn address_index(&self) -> &AddressIndex {
+        &self.addresses
+    }
+
+    /// name_index is the name registry of the chain
+    pub fn name_index(&self) -> &NameIndex {
+        &self.names
+    }
+
+    /// schedules are the recurring payments of the node
+    pub fn schedules(&self) -> &Scheduler {
+        &self.schedules
+    }
+
+    /// rate_limiter limits the requests of each IP, None outside public API mode
+    pub fn rate_limiter(&self) -> Option<&RateLimiter> {
+        self.rate_limiter.as_ref()
+    }
+
+    /// faucet is the coin faucet, None unless the config enables it
+    pub fn faucet(&self) -> Option<&Faucet> {
+        self.faucet.as_ref()
+    }
+
+    /// faucet_pay pays the faucet amount to `address`, for the control socket
+    /// and the REST API
+    pub fn faucet_pay(&self, address: &str) -> Result<Value> {
+        let faucet = self.faucet.as_ref().ok_or_else(|| BlockchainError::Config("the faucet is not enabled, start the node with --faucet".to_string()))?;
+        let txid = faucet.pay(self, address)?;
+        Ok(json!({
+            "txid": txid,
+            "address": address,
+            "amount": faucet.amount().to_string()
+        }))
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// submit_transaction verifies a transaction created outside the P2P network,
+    /// adds it to the mempool and relays it, a miner mines it right away
+    pub fn submit_transaction(&self, tx: Transaction) -> Result<()> {
+        info!("submit tx {}", tx.id);
+        self.insert_mempool(tx.clone())?;
+        self.lock_inner().seen_txs.insert(tx.id);
+
+        for node in self.get_known_nodes() {
+            if node != self.node_address {
+                self.send_inv(&node, "tx", vec![tx.id])?;
+            }
+        }
+        if self.mining_settings.generate() {
+            self.mine_mempool()?;
+        }
+        Ok(())
+    }
+
+    /// submit_package checks a package of dependent transactions created outside
+    /// the P2P network, adds it to the mempool whole and relays it
+    pub fn submit_package(&self, txs: Vec<Transaction>) -> Result<Value> {
+        let accepted = self.accept_package(txs)?;
+        info!("submit package of {} transactions, {} new", accepted.txs.len(), accepted.new.len());
+        for node in self.get_known_nodes() {
+            if node != self.node_address {
+                self.send_package(&node, &accepted.txs)?;
+            }
+        }
+        if self.mining_settings.generate() {
+            self.mine_mempool()?;
+        }
+        Ok(json!({
+            "package": package::id(&accepted.txs),
+            "txids": accepted.txs.iter().map(|tx| tx.id).collect::<Vec<_>>(),
+            "fee": accepted.fee,
+            "size": accepted.size,
+            "feerate": accepted.fee_rate()
+        }))
+    }
+
+    fn handle_package(&self, msg: Packagemsg) -> Result<()> {
+        info!("receive package msg: {} {} transactions", msg.addr_from, msg.transactions.len());
+        let accepted = self.accept_package(msg.transactions)?;
+        if accepted.new.is_empty() {
+            return Ok(());
+        }
+
+        let from = netaddr::normalize(&msg.addr_from).unwrap_or(msg.addr_from);
+        if self.node_address == KNOWN_NODE1 {
+            for node in self.get_known_nodes() {
+                if node != self.node_address && node != from {
+                    self.send_package(&node, &accepted.txs)?;
+                }
+            }
+        } else if self.mining_settings.generate() {
+            self.mine_mempool()?;
+        }
+        Ok(())
+    }
+
+    /// accept_package checks every transaction of a package against the chain,
+    /// the mempool and the package itself, then adds the ones the mempool does
+    /// not hold at once. They go in or stay out together: a package whose fee
+    /// rate would be the first evicted from a full mempool is refused
+    fn accept_package(&self, txs: Vec<Transaction>) -> Result<AcceptedPackage> {
+        let txs = package::sort(txs)?;
+        let mut checked: HashMap<Hash256, Transaction> = HashMap::new();
+        let mut entries = Vec::new();
+        for tx in &txs {
+            checked.insert(tx.id, tx.clone());
+            if self.tx_is_known(&tx.id) {
+                continue;
+            }
+            let fee = self.check_tx(tx, &checked)?;
+            entries.push(MempoolEntry::new(tx.clone(), fee)?);
+        }
+
+        let new: Vec<Hash256> = entries.iter().map(|entry| entry.tx.id).collect();
+        let fee = Amount::sum(entries.iter().map(|entry| entry.fee))?;
+        let size = entries.iter().map(|entry| entry.size).sum();
+        let accepted = AcceptedPackage { txs, new, fee, size };
+        let height = self.get_best_height()?.max(0) as usize;
+
+        let mut inner = self.lock_inner();
+        for entry in &entries {
+            for vin in &entry.tx.vin {
+                match inner.mempool.spender(&vin.prev_out) {
+                    Some(spender) if spender != entry.tx.id => {
+                        drop(inner);
+                        return Err(self.flag_double_spend(DoubleSpend::new(vin.prev_out, spender, None, &entry.tx)?));
+                    },
+                    _ => {}
+                }
+            }
+        }
+        let evicted = inner.mempool.insert_package(entries);
+        if evicted.iter().any(|txid| accepted.new.contains(txid)) {
+            return Err(BlockchainError::MempoolFull(format!("package {} pays too low a fee rate", package::id(&accepted.txs))));
+        }
+        for txid in &accepted.new {
+            inner.seen_txs.insert(*txid);
+        }
+        drop(inner);
+        if !evicted.is_empty() {
+            info!("mempool full, evicted {} transactions", evicted.len());
+        }
+        for txid in &accepted.new {
+            // a child pays for its parents, so all confirm at the package rate
+            self.fees.track(*txid, accepted.fee_rate(), height);
+            self.utxo.blockchain.events().publish(Event::TxAccepted { txid: *txid });
+        }
+        Ok(accepted)
+    }
+
+    fn handle_tx(&self, msg: Txmsg) -> Result<()> {
+        info!("receive tx msg: {} {}", msg.addr_from, &msg.transaction.id);
+        if self.tx_is_known(&msg.transaction.id) {
+            info!("transaction {} already seen", msg.transaction.id);
+            return Ok(());
+        }
+        self.insert_mempool(msg.transaction.clone())?;
+        self.lock_inner().seen_txs.insert(msg.transaction.id);
+
+        let known_nodes = self.get_known_nodes();
+        let from = netaddr::normalize(&msg.addr_from).unwrap_or(msg.addr_from);
+        if self.node_address == KNOWN_NODE1 {
+            for node in known_nodes {
+                if node != self.node_address && node != from {
+                    self.send_inv(&node, "tx", vec![msg.transaction.id])?;
+                }
+            }
+        } else if self.mining_settings.generate() {
+            self.mine_mempool()?;
+        }
+        Ok(())
+    }
+
+    /// MineMempool mines the mempool transactions right away or, when the node waits
+    /// for several of them, hands that to a background thread that the transactions
+    /// arriving meanwhile are left to
+    fn mine_mempool(&self) -> Result<()> {
+        let miner = Miner::new(self.clone(), &self.mining_address);
+        let (min_txs, max_wait) = self.mining_settings.mempool_wait();
+        if max_wait.is_zero() {
+            return self.mine_pooled(&miner);
+        }
+        if self.mempool_miner.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let server = self.clone();
+        thread::spawn(move || {
+            let pooled = miner.wait_for_transactions(min_txs, max_wait);
+            info!("mining {} pooled transactions", pooled);
+            let res = server.mine_pooled(&miner);
+            server.mempool_miner.store(false, Ordering::SeqCst);
+            if let Err(e) = res {
+                error!("failed to mine the mempool: {}", e);
+            }
+        });
+        Ok(())
+    }
+
+    /// mine_pooled mines the mempool transactions into new blocks until none is
+    /// left that can be mined, then drops the rest
+    fn mine_pooled(&self, miner: &Miner) -> Result<()> {
+        loop {
+            let template = miner.template()?;
+            if template.is_empty() {
+                break;
+            }
+            miner.try_mine(template)?;
+        }
+
+        self.clear_mempool();
+        Ok(())
+    }
+
+    /// mine_blocks mines `count` blocks paying the mining address, each with the
+    /// mempool transactions that can be mined, announces them and returns their hashes
+    pub fn mine_blocks(&self, count: usize) -> Result<Vec<Hash256>> {
+        if self.mining_address.is_empty() {
+            return Err(BlockchainError::Config("mining needs a mining address".to_string()));
+        }
+        Miner::new(self.clone(), &self.mining_address).mine_blocks(count)
+    }
+
+    /// submit_block checks a block sealed outside this node, connects it as the new
+    /// tip and announces it
+    pub fn submit_block(&self, block: Block) -> Result<()> {
+        miner::check_block(&block, &self.utxo)?;
+        info!("submit block {}", block.get_hash());
+        Miner::new(self.clone(), &self.mining_address).submit(&block)
+    }
+
+    /// announce_mined_block drops the transactions of a block this node mined from
+    /// the mempool and announces the block to the known nodes
+    pub(crate) fn announce_mined_block(&self, block: &Block) -> Result<()> {
+        let mut inner = self.lock_inner();
+        for tx in block.get_transactions() {
+            inner.mempool.remove(&tx.id);
+        }
+        drop(inner);
+
+        let height = block.get_height() as i32;
+        for node in self.get_known_nodes() {
+            if node == self.node_address {
+                continue;
+            }
+            // a peer behind would not connect the header, it syncs through inv
+            let by_headers = {
+                let inner = self.lock_inner();
+                let key = peer_key(&node);
+                inner.header_peers.contains(&key) && inner.peer_heights.get(&key).is_some_and(|&known| known + 1 >= height)
+            };
+            if by_headers {
+                self.send_headers(&node, block)?;
+            } else {
+                self.send_inv(&node, "block", vec![block.get_hash()])?;
+            }
+            self.note_peer_height(&node, height);
+        }
+        Ok(())
+    }
+
+    /// note_peer_height raises the best height `addr` is known to have
+    fn note_peer_height(&self, addr: &str, height: i32) {
+        let mut inner = self.lock_inner();
+        let known = inner.peer_heights.entry(peer_key(addr)).or_insert(height);
+        *known = (*known).max(height);
+    }
+
+    /// block_is_known tells whether the block was received lately or is stored
+    fn block_is_known(&self, hash: &Hash256) -> bool {
+        self.lock_inner().seen_blocks.contains(hash) || self.utxo.blockchain.get_block_header(hash).is_ok()
+    }
+
+    /// tx_is_known tells whether the transaction was accepted lately or waits
+    /// in the mempool
+    fn tx_is_known(&self, txid: &Hash256) -> bool {
+        let inner = self.lock_inner();
+        inner.seen_txs.contains(txid) || inner.mempool.contains(txid)
+    }
+
+    /// add_block stores a received block and moves the tip and the UTXO set to
+    /// the chain with the most work, checking each block it connects
+    fn add_block(&self, block: &Block) -> Result<()> {
+        self.utxo.receive_block(block)
+    }
+
+    fn get_best_height(&self) -> Result<i32> {
+        self.utxo.blockchain.get_best_height()
+    }
+
+    fn get_in_transit(&self) -> Result<Vec<Hash256>> {
+        Ok(self.lock_inner().blocks_in_transit.clone())
+    }
+
+    fn replace_in_transit(&self, hashs: Vec<Hash256>) {
+        self.lock_inner().blocks_in_transit = hashs;
+    }
+
+    fn get_mempool_tx(&self, txid: &Hash256) -> Option<Transaction> {
+        self.lock_inner().mempool.get(txid).map(|e| e.tx.clone())
+    }
+
+    /// get_mempool returns a snapshot of the unconfirmed transactions
+    pub fn get_mempool(&self) -> Mempool {
+        self.lock_inner().mempool.clone()
+    }
+
+    /// save_mempool writes the unconfirmed transactions to `mempool.dat` for
+    /// the next start of the node
+    pub fn save_mempool(&self) -> Result<()> {
+        let mempool = self.get_mempool();
+        mempool.save(&self.config.mempool_path())?;
+        info!("saved {} mempool transactions", mempool.len());
+        Ok(())
+    }
+
+    /// load_mempool puts back the transactions `save_mempool` wrote, keeping
+    /// their entry time, and drops those the chain no longer lets through
+    pub fn load_mempool(&self) -> Result<()> {
+        let entries = Mempool::load(&self.config.mempool_path())?;
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let total = entries.len();
+        for entry in entries {
+            match self.revalidate(&entry.tx) {
+                Ok(fee) => {
+                    self.lock_inner().mempool.insert(MempoolEntry { fee, ..entry });
+                },
+                Err(e) => info!("dropping mempool transaction {}: {}", entry.tx.id, e)
+            }
+        }
+        let kept = self.lock_inner().mempool.len();
+        info!("loaded {} mempool transactions, dropped {}", kept, total.saturating_sub(kept));
+        Ok(())
+    }
+
+    /// revalidate checks a reloaded transaction against the current UTXO set
+    /// and the transactions reloaded before it and returns its fee
+    fn revalidate(&self, tx: &Transaction) -> Result<Amount> {
+        if tx.is_coinbase() {
+            return Err(BlockchainError::Consensus("a coinbase is never pooled".to_string()));
+        }
+        let mut prev_txs = HashMap::new();
+        for vin in &tx.vin {
+            let (prev_tx, pooled) = match self.get_mempool_tx(&vin.prev_out.txid) {
+                Some(prev_tx) => (prev_tx, true),
+                None => (self.utxo.blockchain.find_transaction(&vin.prev_out.txid)?, false)
+            };
+            let Some(out) = prev_tx.vout.get(vin.prev_out.index as usize) else {
+                return Err(BlockchainError::Consensus(format!("it spends missing output {}", vin.prev_out)));
+            };
+            let spent = if pooled { false } else { !self.utxo.is_unspent(&out.pub_key_hash, &vin.prev_out)? };
+            if spent || self.lock_inner().mempool.spender(&vin.prev_out).is_some() {
+                return Err(BlockchainError::Consensus(format!("output {} is already spent", vin.prev_out)));
+            }
+            prev_txs.insert(prev_tx.id, prev_tx);
+        }
+        if !tx.clone().verify(prev_txs)? {
+            return Err(BlockchainError::Consensus("its signature is invalid".to_string()));
+        }
+        self.tx_fee(tx)
+    }
+
+    /// fee_estimator is the fee rates the recent blocks confirmed
+    pub fn fee_estimator(&self) -> &FeeEstimator {
+        &self.fees
+    }
+
+    /// double_spends lists the latest double spend alerts, oldest first
+    pub fn double_spends(&self) -> Vec<DoubleSpend> {
+        self.lock_inner().double_spends.alerts().cloned().collect()
+    }
+
+    /// flag_double_spend keeps the alert, logs and publishes it the first time
+    /// and returns the error refusing the second spend
+    fn flag_double_spend(&self, spend: DoubleSpend) -> BlockchainError {
+        let error = BlockchainError::Consensus(format!("transaction {} spends {} already spent by {}", spend.second, spend.outpoint, spend.first));
+        let event = Event::DoubleSpendDetected { outpoint: spend.outpoint, first: spend.first, second: spend.second };
+        if self.lock_inner().double_spends.add(spend) {
+            warn!("double spend detected: {}", error);
+            self.utxo.blockchain.events().publish(event);
+        }
+        error
+    }
+
+    /// mined_double_spend finds an input of `tx` spending an output that a
+    /// transaction of a recent block already spent
+    fn mined_double_spend(&self, tx: &Transaction) -> Result<Option<DoubleSpend>> {
+        if tx.is_coinbase() {
+            return Ok(None);
+        }
+        for vin in &tx.vin {
+            if self.get_mempool_tx(&vin.prev_out.txid).is_some() {
+                continue;
+            }
+            let prev_tx = self.utxo.blockchain.find_transaction(&vin.prev_out.txid)?;
+            let Some(out) = prev_tx.vout.get(vin.prev_out.index as usize) else {
+                continue;
+            };
+            if self.utxo.is_unspent(&out.pub_key_hash, &vin.prev_out)? {
+                continue;
+            }
+            if let Some((first, block)) = doublespend::mined_spender(&self.utxo.blockchain, &vin.prev_out)? {
+                if first != tx.id {
+                    return Ok(Some(DoubleSpend::new(vin.prev_out, first, Some(block), tx)?));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// check_tx verifies the signature of a transaction about to enter the
+    /// mempool and that it spends outputs of `package`, of the mempool or
+    /// unspent ones of the chain, and returns its fee. Only a signed spend of
+    /// an output a recent block spent is flagged as a double spend
+    fn check_tx(&self, tx: &Transaction, package: &HashMap<Hash256, Transaction>) -> Result<Amount> {
+        if tx.is_coinbase() {
+            return Err(BlockchainError::Consensus(format!("coinbase {} is never pooled", tx.id)));
+        }
+        let mut prev_txs = HashMap::new();
+        let mut input_total = Amount::ZERO;
+        let mut spent = None;
+        for vin in &tx.vin {
+            let (prev_tx, pooled) = match package.get(&vin.prev_out.txid).cloned().or_else(|| self.get_mempool_tx(&vin.prev_out.txid)) {
+                Some(prev_tx) => (prev_tx, true),
+                None => (self.utxo.blockchain.find_transaction(&vin.prev_out.txid)?, false)
+            };
+            let Some(out) = prev_tx.vout.get(vin.prev_out.index as usize) else {
+                return Err(BlockchainError::Consensus(format!("transaction {} spends missing output {}", tx.id, vin.prev_out)));
+            };
+            input_total = input_total.try_add(out.value)?;
+            if !pooled && spent.is_none() && !self.utxo.is_unspent(&out.pub_key_hash, &vin.prev_out)? {
+                spent = Some(vin.prev_out);
+            }
+            prev_txs.insert(prev_tx.id, prev_tx);
+        }
+        if !tx.clone().verify(prev_txs)? {
+            return Err(BlockchainError::Consensus(format!("transaction {} has an invalid signature", tx.id)));
+        }
+        if let Some(outpoint) = spent {
+            if let Some(spend) = self.mined_double_spend(tx)? {
+                return Err(self.flag_double_spend(spend));
+            }
+            return Err(BlockchainError::Consensus(format!("transaction {} spends {}, which is already spent", tx.id, outpoint)));
+        }
+        let fee = input_total.try_sub(Amount::sum(tx.vout.iter().map(|out| out.value))?)?;
+        if fee < Amount::ZERO {
+            return Err(BlockchainError::Consensus(format!("transaction {} pays out more than it spends", tx.id)));
+        }
+        Ok(fee)
+    }
+
+    fn insert_mempool(&self, tx: Transaction) -> Result<()> {
+        let fee = self.check_tx(&tx, &HashMap::new())?;
+        let entry = MempoolEntry::new(tx, fee)?;
+        let txid = entry.tx.id;
+        let is_wallet_tx = self.history.is_wallet_tx(&entry.tx);
+        let fee_rate = entry.fee_rate();
+        let height = self.get_best_height()?.max(0) as usize;
+
+        let mut inner = self.lock_inner();
+        for vin in &entry.tx.vin {
+            match inner.mempool.spender(&vin.prev_out) {
+                Some(spender) if spender != txid => {
+                    drop(inner);
+                    return Err(self.flag_double_spend(DoubleSpend::new(vin.prev_out, spender, None, &entry.tx)?));
+                },
+                _ => {}
+            }
+        }
+        let evicted = inner.mempool.insert(entry);
+        drop(inner);
+        if evicted.contains(&txid) {
+            return Err(BlockchainError::MempoolFull(format!("transaction {} pays too low a fee rate", txid)));
+        }
+        if !evicted.is_empty() {
+            info!("mempool full, evicted {} transactions", evicted.len());
+        }
+        if is_wallet_tx {
+            info!("wallet transaction {} entered the mempool", txid);
+        }
+        self.fees.track(txid, fee_rate, height);
+        self.utxo.blockchain.events().publish(Event::TxAccepted { txid });
+        Ok(())
+    }
+
+    /// TxFee is the value of the outputs a transaction spends, looked up in the
+    /// mempool first and then in the chain, minus the value of its own outputs
+    fn tx_fee(&self, tx: &Transaction) -> Result<Amount> {
+        if tx.is_coinbase() {
+            return Ok(Amount::ZERO);
+        }
+
+        let mut input_total = Amount::ZERO;
+        for vin in &tx.vin {
+            let prev_txid = vin.prev_out.txid;
+            let prev_tx = match self.get_mempool_tx(&prev_txid) {
+                Some(prev_tx) => prev_tx,
+                None => self.utxo.blockchain.find_transaction(&prev_txid)?
+            };
+            match prev_tx.vout.get(vin.prev_out.index as usize) {
+                Some(out) => input_total = input_total.try_add(out.value)?,
+                None => return Err(BlockchainError::Consensus(format!("transaction {} spends missing output {}", tx.id, vin.prev_out)))
+            }
+        }
+
+        let output_total = Amount::sum(tx.vout.iter().map(|out| out.value))?;
+        input_total.try_sub(output_total)
+    }
+
+    fn clear_mempool(&self) {
+        self.lock_inner().mempool.clear()
+    }
+
+    fn node_is_known(&self, addr: &str) -> bool {
+        self.lock_inner().known_nodes.contains(addr)
+    }
+
+    /// add_nodes adds a peer in its normalized spelling, leaving out this node,
+    /// the peers outside onlynet and the addresses no peer can have
     fn add_nodes(&self, addr: &str) {
-        self.inner
-            .lock()
-            .unwrap()
-            .known_nodes
-            .insert(String::from(addr));
+        let addr = match netaddr::normalize(addr) {
+            Ok(addr) if self.accepts_peer(&addr) => addr,
+            Ok(_) => return,
+            Err(e) => {
+                warn!("ignoring peer: {}", e);
+                return;
+            }
+        };
+        let now = addrman::now().unwrap_or_default();
+        let added = {
+            let mut inner = self.lock_inner();
+            inner.addrman.add(TimedAddr { addr: addr.clone(), time: now }, &addr);
+            inner.known_nodes.insert(addr.clone())
+        };
+        if added {
+            self.utxo.blockchain.events().publish(Event::PeerConnected { addr });
+        }
+    }
+
+    /// accepts_peer tells whether the normalized `addr` may become a peer: not
+    /// this node, on an allowed network and not banned
+    fn accepts_peer(&self, addr: &str) -> bool {
+        !self.is_self(addr) && self.outbound.dialer().allows(addr) && !self.is_banned(addr)
+    }
+
+    /// is_banned tells whether the operator banned the host of `addr`
+    fn is_banned(&self, addr: &str) -> bool {
+        self.bans.is_banned(addr).unwrap_or_else(|e| {
+            error!("failed to read the ban list: {}", e);
+            false
+        })
+    }
+
+    /// is_self tells whether the normalized `addr` reaches this node, its
+    /// advertised address or one it listens on
+    fn is_self(&self, addr: &str) -> bool {
+        if addr == self.node_address {
+            return true;
+        }
+        let Ok(addr) = addr.parse::<SocketAddr>() else {
+            return false;
+        };
+        self.listening.lock().unwrap_or_else(PoisonError::into_inner).iter().any(|own| *own == addr || netaddr::loopback(*own) == addr)
     }
 
     fn send_get_blocks(&self, addr: &str) -> Result<()> {
@@ -162,12 +1707,12 @@ impl Server {
             addr_from: self.node_address.clone()
         };
 
-        let data = bincode::serialize(&(cmd_to_bytes("getblocks"), data))?;
-        self.send_data(addr, &data)
+        let data = MessageCodec::encode(&Message::GetBlock(data))?;
+        self.send_data(addr, data)
 
     }
 
-    fn send_get_data(&self, addr: &str, kind: &str, id: &str) -> Result<()> {
+    fn send_get_data(&self, addr: &str, kind: &str, id: &Hash256) -> Result<()> {
         info!(
             "send get data message to: {} kind: {} id: {}",
             addr, kind, id
@@ -176,11 +1721,29 @@ impl Server {
         let data = GetDatamsg {
             addr_from: self.node_address.clone(),
             kind: String::from(kind),
-            id: String::from(id)
+            id: *id
         };
-        let data = bincode::serialize(&(cmd_to_bytes("getdata"), data))?;
-        self.send_data(addr, &data)
+        let data = MessageCodec::encode(&Message::GetData(data))?;
+        self.send_data(addr, data)
+
+    }
 
+    /// send_send_headers asks `addr` to announce new blocks with headers
+    fn send_send_headers(&self, addr: &str) -> Result<()> {
+        info!("send send headers to: {}", addr);
+        let data = SendHeadersmsg { addr_from: self.node_address.clone() };
+        self.send_data(addr, MessageCodec::encode(&Message::SendHeaders(data))?)
+    }
+
+    /// send_headers announces `block` to `addr` by its header
+    fn send_headers(&self, addr: &str, block: &Block) -> Result<()> {
+        info!("send headers to: {} block hash: {}", addr, block.get_hash());
+        let data = Headersmsg {
+            addr_from: self.node_address.clone(),
+            best_height: block.get_height(),
+            headers: vec![SpvHeader::of(block)?]
+        };
+        self.send_data(addr, MessageCodec::encode(&Message::Headers(data))?)
     }
 
     fn send_version(&self, addr: &str) -> Result<()> {
@@ -189,11 +1752,20 @@ impl Server {
         let data = Versionmsg {
             addr_from: self.node_address.clone(),
             best_height: self.get_best_height()?,
-            version: VERSION
+            version: VERSION,
+            services: SERVICE_COMPRESSION,
+            user_agent: USER_AGENT.to_string()
         };
-        let data = bincode::serialize(&(cmd_to_bytes("version"), data))?;
-        self.send_data(addr, &data)
+        let data = MessageCodec::encode(&Message::Version(data))?;
+        self.send_data(addr, data)?;
+        self.send_send_headers(addr)
+
+    }
 
+    fn send_package(&self, addr: &str, txs: &[Transaction]) -> Result<()> {
+        info!("send package to: {} {} transactions", addr, txs.len());
+        let data = Packagemsg { addr_from: self.node_address.clone(), transactions: txs.to_vec() };
+        self.send_data(addr, MessageCodec::encode(&Message::Package(data))?)
     }
 
     fn send_tx(&self, addr: &str, tx: &Transaction) -> Result<()> {
@@ -203,12 +1775,12 @@ impl Server {
             addr_from: self.node_address.clone(),
             transaction: tx.clone()
         };
-        let data = bincode::serialize(&(cmd_to_bytes("tx"), data))?;
-        self.send_data(addr, &data)
+        let data = MessageCodec::encode(&Message::Tx(data))?;
+        self.send_data(addr, data)
 
     }
 
-    fn send_inv(&self, addr: &str, kind: &str, items: Vec<String>) -> Result<()> {
+    fn send_inv(&self, addr: &str, kind: &str, items: Vec<Hash256>) -> Result<()> {
         info!(
             "Send inv message to: {} kind: {} data: {:?}",
             addr, kind, items
@@ -219,105 +1791,149 @@ impl Server {
             kind: kind.to_string(),
             items
         };
-        let data = bincode::serialize(&(cmd_to_bytes("inv"), data))?;
-        self.send_data(addr, &data)
+        let data = MessageCodec::encode(&Message::Inv(data))?;
+        self.send_data(addr, data)
     }
 
-    fn send_block(&self, addr: &str, b: &Block) -> Result<()> {
-        info!("Send block data to: {} block hash: {}", addr, b.get_hash());
-
-        let data = Blockmsg {
-            addr_from: self.node_address.clone(),
-            block: b.clone()
-        };
-        let data = bincode::serialize(&(cmd_to_bytes("block"), data))?;
-        self.send_data(addr, &data)
+    /// send_raw_block relays a stored block as it is on disk, the receiver
+    /// validates it
+    fn send_raw_block(&self, addr: &str, hash: &Hash256, block: &[u8]) -> Result<()> {
+        info!("Send block data to: {} block hash: {}", addr, hash);
+        let data = MessageCodec::encode_raw_block(&self.node_address, block)?;
+        self.send_data(addr, data)
     }
 
+    /// send_addr sends `addr` a random share of the address table, without the
+    /// local addresses when it is outside the local network
     fn send_addr(&self, addr: &str) -> Result<()> {
         info!("Send address info to: {}", addr);
+        let now = addrman::now()?;
+        let peer = netaddr::normalize(addr).unwrap_or_else(|_| addr.to_string());
+        let local = !netaddr::is_routable(&peer);
+        let addrs = {
+            let inner = self.lock_inner();
+            inner.addrman.sample(inner.addrman.share(), now, |record| record.addr != peer && (local || netaddr::is_routable(&record.addr)))
+        };
+        self.send_timed_addrs(addr, addrs)
+    }
 
-        let nodes = self.get_known_nodes();
-        let data = bincode::serialize(&(cmd_to_bytes("addr"), nodes))?;
-        self.send_data(addr, &data)
-
+    fn send_timed_addrs(&self, addr: &str, addrs: Vec<TimedAddr>) -> Result<()> {
+        let data = Addrmsg { addr_from: self.node_address.clone(), addrs };
+        self.send_data(addr, MessageCodec::encode(&Message::AddrV2(data))?)
     }
 
-    fn get_known_nodes(&self) -> HashSet<String> {
-        self.inner.lock().unwrap().known_nodes.clone()
+    /// get_known_nodes returns the peers this node announces to and relays to
+    pub fn get_known_nodes(&self) -> HashSet<String> {
+        self.lock_inner().known_nodes.clone()
     }
 
-    fn send_data(&self, addr: &str, data: &[u8]) -> Result<()> {
-        if addr == &self.node_address {
+    /// send_data queues a frame for `addr` on the outbound workers, a peer that
+    /// cannot be connected to is forgotten
+    fn send_data(&self, addr: &str, data: Vec<u8>) -> Result<()> {
+        if netaddr::normalize(addr).is_ok_and(|addr| self.is_self(&addr)) {
+            return Ok(());
+        }
+        if self.is_banned(addr) {
+            info!("not sending to banned {}", addr);
             return Ok(());
         }
 
-        let mut stream = match TcpStream::connect(addr) {
-            Ok(s) => s,
-            Err(_) => {
-                self.remove_node(addr);
-                return Ok(());
-            }
+        let key = peer_key(addr);
+        let compress = data.len() >= COMPRESS_MIN_BYTES
+            && self.lock_inner().peer_versions.get(&key).is_some_and(|v| v.services & SERVICE_COMPRESSION != 0);
+        let uncompressed = data.len() as u64;
+        let data = match compress {
+            true => MessageCodec::compress(&data)?.unwrap_or(data),
+            false => data
         };
+        {
+            let mut inner = self.lock_inner();
+            let traffic = inner.peer_traffic.entry(key).or_default();
+            traffic.uncompressed += uncompressed;
+            traffic.sent += data.len() as u64;
+        }
 
-        stream.write(data)?;
-        
-        info!("Data send successfully");
+        self.outbound.send(addr, data);
         Ok(())
 
     }
 
-    fn remove_node(&self, addr: &str) {
-        self.inner.lock().unwrap().known_nodes.remove(addr);
+    /// count_received adds a frame of `size` bytes, `uncompressed` once
+    /// decompressed, to the traffic of a known peer
+    fn count_received(&self, addr: &str, size: usize, uncompressed: usize) {
+        let key = peer_key(addr);
+        let mut inner = self.lock_inner();
+        if !inner.known_nodes.contains(&key) {
+            return;
+        }
+        let traffic = inner.peer_traffic.entry(key).or_default();
+        traffic.received_uncompressed += uncompressed as u64;
+        traffic.received += size as u64;
+    }
+
+    /// connect_peer adds a peer and sends it our version, the node with the
+    /// shorter chain then fetches the blocks it lacks
+    pub fn connect_peer(&self, addr: &str) -> Result<()> {
+        let addr = netaddr::normalize(addr)?;
+        self.add_nodes(&addr);
+        self.send_version(&addr)
+    }
+
+    /// remove_node forgets a peer, it is no longer announced or relayed to
+    pub(crate) fn remove_node(&self, addr: &str) {
+        let addr = peer_key(addr);
+        let mut inner = self.lock_inner();
+        inner.known_nodes.remove(&addr);
+        inner.header_peers.remove(&addr);
+        inner.peer_heights.remove(&addr);
+        inner.peer_versions.remove(&addr);
+        inner.peer_traffic.remove(&addr);
     }
 
 
 }
 
-fn cmd_to_bytes(cmd: &str) -> [u8; CMD_LEN] {
-    let mut data = [0; CMD_LEN];
-    for (i, d) in cmd.as_bytes().iter().enumerate() {
-        data[i] = *d;
-    }
-    data
+/// peer_key is the normalized form of a peer address, the key of the per peer
+/// state
+fn peer_key(addr: &str) -> String {
+    netaddr::normalize(addr).unwrap_or_else(|_| addr.to_string())
 }
 
-// cargo run addr data
-fn bytes_to_cmd(bytes: &[u8]) -> Result<Message> {
-    let mut cmd = Vec::new();
-    let cmd_bytes = &bytes[..CMD_LEN];
-    let data = &bytes[CMD_LEN..];
-    for b in cmd_bytes {
-        if 0 as u8 != *b {
-            cmd.push(*b);
-        }
-    }
-    info!("cmd: {}", String::from_utf8(cmd.clone())? );
-
-    if cmd == "addr".as_bytes() {
-        let data: Vec<String> = deserialize(data)?;
-        Ok(Message::Addr(data))
-    } else if cmd == "block".as_bytes() {
-        let data: Blockmsg = deserialize(data)?;
-        Ok(Message::Block(data))
-    } else if cmd == "inv".as_bytes() {
-        let data = deserialize(data)?;
-        Ok(Message::Inv(data))
-    } else if cmd == "getblocks".as_bytes() {
-        let data = deserialize(data)?;
-        Ok(Message::GetBlock(data))
-    } else if cmd == "getdata".as_bytes() {
-        let data = deserialize(data)?;
-        Ok(Message::GetData(data))
-    } else if cmd == "tx".as_bytes() {
-        let data = deserialize(data)?;
-        Ok(Message::Tx(data))
-    } else if cmd == "version".as_bytes() {
-        let data = deserialize(data)?;
-        Ok(Message::Version(data))
+/// verification_progress is the share of the best chain advertised by the peers
+/// that is stored locally, 1 when no peer is ahead
+fn verification_progress(height: i32, peer_best_height: i32) -> f64 {
+    if peer_best_height > height {
+        (height + 1) as f64 / (peer_best_height + 1) as f64
     } else {
-        Err(format_err!("Unknown command in the server"))
+        1.0
     }
-    
+}
+
+/// chain_status describes what can be read from the data directory alone,
+/// without a running node
+pub fn chain_status(config: &Config, bc: &Blockchain) -> Result<Value> {
+    Ok(json!({
+        "bestblockhash": bc.get_tip(),
+        "height": bc.get_best_height()?,
+        "difficulty": bc.get_difficulty()?,
+        "wallets": Wallets::new(config)?.get_all_address().len(),
+        "datadir_size": dir_size(&config.network_dir())?
+    }))
+}
 
-}
\ No newline at end of file
+fn dir_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+    if !path.exists() {
+        return Ok(0);
+    }
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        if meta.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            size += meta.len();
+        }
+    }
+    Ok(size)
+}
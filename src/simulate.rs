@@ -0,0 +1,311 @@
+//! Mining attack simulations on in-process regtest networks, built with the
+//! `simulate` feature.
+//!
+//! Two groups of testkit nodes mine on the same fixture chain, each block going
+//! to the attacker group with the probability of its hashrate share. The honest
+//! group publishes every block, the attacker follows its strategy:
+//!
+//! - `honest` publishes every block too, the baseline
+//! - `selfish` keeps its blocks and publishes them when the honest group comes
+//!   within a block of its private chain, the strategy of Eyal and Sirer, except
+//!   that a tie is only won by finding the next block
+//! - `withhold` keeps its blocks until it has `depth` of them and a longer chain
+//!   than the honest group, reversing that many confirmations, and gives up once
+//!   the honest group is `depth` blocks ahead
+//!
+//! Publishing connects the groups until they agree, the shorter chain reorgs to
+//! the longer one, then splits them again.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+
+use crate::error::{BlockchainError, Result};
+use crate::events::Event;
+use crate::hash::Hash256;
+use crate::testkit::TestNetwork;
+
+/// TIMEOUT bounds the wait for a group of nodes to agree on a tip
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Strategy is how the attacker group publishes its blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    Honest,
+    Selfish,
+    /// withhold blocks until this many are private and the chain is longer
+    Withhold(usize)
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Strategy::Honest => write!(f, "honest"),
+            Strategy::Selfish => write!(f, "selfish"),
+            Strategy::Withhold(depth) => write!(f, "withhold:{}", depth)
+        }
+    }
+}
+
+/// FromStr reads `honest`, `selfish` or `withhold:<depth>`
+impl FromStr for Strategy {
+    type Err = BlockchainError;
+
+    fn from_str(s: &str) -> Result<Strategy> {
+        match s.split_once(':') {
+            None if s == "honest" => Ok(Strategy::Honest),
+            None if s == "selfish" => Ok(Strategy::Selfish),
+            Some(("withhold", depth)) => match depth.parse() {
+                Ok(depth) if depth > 0 => Ok(Strategy::Withhold(depth)),
+                _ => Err(BlockchainError::Config(format!("withhold depth '{}' is not a positive number", depth)))
+            },
+            _ => Err(BlockchainError::Config(format!("unknown strategy '{}', expected honest, selfish or withhold:<depth>", s)))
+        }
+    }
+}
+
+/// Simulation describes one run
+#[derive(Debug, Clone)]
+pub struct Simulation {
+    pub honest_nodes: usize,
+    pub attacker_nodes: usize,
+    /// part of the hashrate the attacker group has, from 0 to 1
+    pub share: f64,
+    /// blocks mined in the run
+    pub blocks: usize,
+    pub strategy: Strategy,
+    /// seeds the draw of who finds each block, the same seed gives the same run
+    pub seed: u64
+}
+
+impl Default for Simulation {
+    fn default() -> Simulation {
+        Simulation {
+            honest_nodes: 2,
+            attacker_nodes: 1,
+            share: 0.3,
+            blocks: 30,
+            strategy: Strategy::Selfish,
+            seed: 0
+        }
+    }
+}
+
+/// AttackReport is the outcome of a run
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AttackReport {
+    pub strategy: String,
+    pub share: f64,
+    pub blocks_mined: usize,
+    pub attacker_mined: usize,
+    /// blocks of the final best chain mined in the run
+    pub chain_blocks: usize,
+    pub attacker_chain_blocks: usize,
+    /// attacker part of the final chain, above `share` when the strategy paid off
+    pub attacker_revenue_share: f64,
+    /// blocks mined in the run that are not on the final chain
+    pub orphaned: usize,
+    pub orphan_rate: f64,
+    /// reorgs of the honest group and the blocks each one disconnected
+    pub reorgs: Vec<usize>,
+    pub max_reorg_depth: usize
+}
+
+/// simulate runs `sim` on a new network of testkit nodes, removed afterwards
+pub fn simulate(sim: &Simulation) -> Result<AttackReport> {
+    if !(0.0..=1.0).contains(&sim.share) {
+        return Err(BlockchainError::Config(format!("hashrate share {} is not between 0 and 1", sim.share)));
+    }
+    if sim.honest_nodes == 0 || sim.attacker_nodes == 0 {
+        return Err(BlockchainError::Config("each group needs at least one node".to_string()));
+    }
+
+    let network = TestNetwork::spawn(sim.honest_nodes + sim.attacker_nodes, 1)?;
+    let honest: Vec<usize> = (0..sim.honest_nodes).collect();
+    let attacker: Vec<usize> = (sim.honest_nodes..network.len()).collect();
+    let run = Run::start(&network, honest, attacker, sim).and_then(|mut run| {
+        run.mine_all(sim)?;
+        run.report(sim)
+    });
+    network.shutdown()?;
+    run
+}
+
+/// Run is the state of a simulation in progress
+struct Run<'a> {
+    network: &'a TestNetwork,
+    honest: Vec<usize>,
+    attacker: Vec<usize>,
+    /// events of the first honest node, counting its reorgs
+    events: Receiver<Event>,
+    rng: StdRng,
+    start_height: i32,
+    attacker_blocks: HashSet<Hash256>,
+    mined: usize,
+    /// attacker blocks the honest group has not seen
+    private: usize,
+    /// both groups have chains of the same height
+    tie: bool,
+    reorgs: Vec<usize>
+}
+
+impl<'a> Run<'a> {
+    fn start(network: &'a TestNetwork, honest: Vec<usize>, attacker: Vec<usize>, sim: &Simulation) -> Result<Run<'a>> {
+        for group in [&honest, &attacker] {
+            for (i, &a) in group.iter().enumerate() {
+                for &b in &group[i + 1..] {
+                    network.connect(a, b)?;
+                }
+            }
+        }
+        Ok(Run {
+            events: network.node(honest[0]).subscribe(),
+            start_height: network.node(honest[0]).best_height()?,
+            network,
+            honest,
+            attacker,
+            rng: StdRng::seed_from_u64(sim.seed),
+            attacker_blocks: HashSet::new(),
+            mined: 0,
+            private: 0,
+            tie: false,
+            reorgs: Vec::new()
+        })
+    }
+
+    /// mine_all mines the blocks of the run, then lets the groups settle on
+    /// the longer chain, a tie staying with the honest one
+    fn mine_all(&mut self, sim: &Simulation) -> Result<()> {
+        for _ in 0..sim.blocks {
+            self.split();
+            if self.rng.gen_bool(sim.share) {
+                let hash = self.mine(true)?;
+                self.attacker_blocks.insert(hash);
+                self.private += 1;
+                let (attacker, honest) = self.heights()?;
+                let publish = match sim.strategy {
+                    Strategy::Honest => true,
+                    Strategy::Selfish => self.tie,
+                    Strategy::Withhold(depth) => self.private >= depth && attacker > honest
+                };
+                if publish {
+                    self.sync()?;
+                }
+            } else {
+                self.mine(false)?;
+                let (attacker, honest) = self.heights()?;
+                match sim.strategy {
+                    Strategy::Honest => self.sync()?,
+                    Strategy::Selfish if attacker < honest || attacker == honest + 1 => self.sync()?,
+                    Strategy::Selfish if attacker == honest => self.tie = true,
+                    Strategy::Withhold(depth) if self.private == 0 || honest > attacker + depth as i32 => self.sync()?,
+                    _ => {}
+                }
+            }
+        }
+
+        let (attacker, honest) = self.heights()?;
+        if attacker != honest {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// mine has a random node of a group mine a block and waits for the group
+    /// to agree on it
+    fn mine(&mut self, by_attacker: bool) -> Result<Hash256> {
+        let group = if by_attacker { &self.attacker } else { &self.honest };
+        let node = group[self.rng.gen_range(0..group.len())];
+        let hash = self.network.node(node).mine_blocks(1)?[0];
+        self.network.agree(group, TIMEOUT)?;
+        self.mined += 1;
+        Ok(hash)
+    }
+
+    /// heights is the best height of the attacker and of the honest group
+    fn heights(&self) -> Result<(i32, i32)> {
+        Ok((self.network.node(self.attacker[0]).best_height()?, self.network.node(self.honest[0]).best_height()?))
+    }
+
+    /// sync connects the groups until they agree on the longer chain, noting
+    /// the reorg of the honest group
+    fn sync(&mut self) -> Result<()> {
+        for &a in &self.attacker {
+            for &h in &self.honest {
+                self.network.connect(a, h)?;
+            }
+        }
+        self.network.converge(TIMEOUT)?;
+        self.split();
+
+        let depth = self.events.try_iter().filter(|e| matches!(e, Event::BlockDisconnected { .. })).count();
+        if depth > 0 {
+            self.reorgs.push(depth);
+        }
+        self.private = 0;
+        self.tie = false;
+        Ok(())
+    }
+
+    /// split makes the groups forget each other, including the addresses they
+    /// learned from addr messages
+    fn split(&self) {
+        for &a in &self.attacker {
+            for &h in &self.honest {
+                self.network.disconnect(a, h);
+            }
+        }
+    }
+
+    fn report(&self, sim: &Simulation) -> Result<AttackReport> {
+        let node = self.network.node(self.honest[0]);
+        let bc = &node.server().utxo_set().blockchain;
+        let mut chain_blocks = 0;
+        let mut attacker_chain_blocks = 0;
+        for header in bc.iter_headers().take_while(|header| header.height as i32 > self.start_height) {
+            chain_blocks += 1;
+            if self.attacker_blocks.contains(&header.hash) {
+                attacker_chain_blocks += 1;
+            }
+        }
+
+        let orphaned = self.mined - chain_blocks;
+        Ok(AttackReport {
+            strategy: sim.strategy.to_string(),
+            share: sim.share,
+            blocks_mined: self.mined,
+            attacker_mined: self.attacker_blocks.len(),
+            chain_blocks,
+            attacker_chain_blocks,
+            attacker_revenue_share: attacker_chain_blocks as f64 / chain_blocks.max(1) as f64,
+            orphaned,
+            orphan_rate: orphaned as f64 / self.mined.max(1) as f64,
+            max_reorg_depth: self.reorgs.iter().copied().max().unwrap_or(0),
+            reorgs: self.reorgs.clone()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_withholding_reorgs_the_honest_group() -> Result<()> {
+        assert_eq!("withhold:3".parse::<Strategy>()?, Strategy::Withhold(3));
+        assert!("withhold:0".parse::<Strategy>().is_err());
+
+        let sim = Simulation { honest_nodes: 1, share: 0.6, blocks: 8, strategy: Strategy::Withhold(2), seed: 685, ..Simulation::default() };
+        let report = simulate(&sim)?;
+        assert_eq!((report.blocks_mined, report.attacker_mined), (8, 4));
+        assert_eq!(report.chain_blocks + report.orphaned, 8);
+        assert_eq!((report.attacker_chain_blocks, report.reorgs.as_slice()), (4, &[1][..]));
+        Ok(())
+    }
+}
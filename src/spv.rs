@@ -0,0 +1,626 @@
+//! Light clients. A node hands out the headers of its best chain with their
+//! merkle roots, the compact filters of its blocks and, for older clients, the
+//! transactions matching a bloom filter with their merkle branches.
+//! `SpvWallet` checks the work and target of every header, tests the filters
+//! against its keys and outputs and fetches only the blocks that match, so no
+//! peer learns its addresses. The `spv-wallet` binary runs it on the wallets
+//! of the data directory:
+//!
+//! ```text
+//! spv-wallet --peer localhost:3000 sync
+//! spv-wallet --peer localhost:3000 send <FROM> <TO> 1.5
+//! ```
+//!
+//! The requests of a light client are answered on the connection they came in,
+//! so it does not listen. The genesis header is taken from the first peer
+//! synced with and kept from then on, the filter headers from the peer synced
+//! with and compared with the other peers.
+
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::amount::Amount;
+use crate::block::{pow_hash, Block, BlockHeader, MerkleStep};
+use crate::blockchain::Blockchain;
+use crate::bloom::BloomFilter;
+use crate::blockfilter::{filter_header, outpoint_item};
+use crate::codec::{GetCFHeadersmsg, GetCFiltersmsg, GetDatamsg, GetHeadersmsg, Message, MessageCodec, Txmsg};
+use crate::config::Config;
+use crate::dialer::Dialer;
+use crate::error::{BlockchainError, Result};
+use crate::hash::Hash256;
+use crate::target::{self, Target, RETARGET_INTERVAL};
+use crate::transaction::Transaction;
+use crate::tx::{OutPoint, TXInput, TXOutput};
+use crate::wallet::{hash_pub_key, Wallets};
+
+/// MAX_HEADERS is how many headers a node sends in one reply
+pub const MAX_HEADERS: usize = 500;
+
+/// MAX_FILTERED_BLOCKS is how many blocks a node scans for one filter request
+pub const MAX_FILTERED_BLOCKS: usize = 500;
+
+/// REQUEST_TIMEOUT bounds the connection to a peer and the wait for its reply
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// HEADER_TREE maps the big endian height of every header to the bincode SpvHeader
+const HEADER_TREE: &str = "headers";
+
+/// TX_TREE maps the txid of every wallet transaction to the bincode WalletTx
+const TX_TREE: &str = "txs";
+
+/// CFHEADER_TREE maps the big endian height of every block to its filter header
+const CFHEADER_TREE: &str = "cfheaders";
+
+/// SCANNED_KEY holds the big endian height the filter scan reached
+const SCANNED_KEY: &[u8] = b"scanned";
+
+/// SpvHeader is a block header with the merkle root its proof of work covers
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SpvHeader {
+    pub header: BlockHeader,
+    pub merkle_root: Hash256
+}
+
+impl SpvHeader {
+    pub fn of(block: &Block) -> Result<SpvHeader> {
+        Ok(SpvHeader { header: block.header().clone(), merkle_root: Hash256::from_slice(&Block::merkle_root(block.get_transactions())?)? })
+    }
+
+    /// check tells whether the header follows `prev`, None for the genesis,
+    /// with the target `bits` the retarget asks for and meets it
+    pub fn check(&self, prev: Option<&SpvHeader>, bits: u32) -> Result<()> {
+        let header = &self.header;
+        let invalid = |reason: &str| Err(BlockchainError::InvalidProof(format!("header {} at height {} {}", header.hash, header.height, reason)));
+        let (height, parent) = prev.map_or((0, Hash256::ZERO), |p| (p.header.height + 1, p.header.hash));
+        if header.height != height || header.prev_block_hash != parent {
+            return invalid("does not follow the previous one");
+        }
+        if header.bits != bits {
+            return invalid("has the wrong target");
+        }
+        let hash = pow_hash(&Block::header_prefix(&header.prev_block_hash, self.merkle_root.as_bytes(), header.timestamp, header.bits)?, header.nonce);
+        if Hash256::new(hash) != header.hash || !Target::from_compact(bits)?.is_met_by(&hash) {
+            return invalid("does not prove its work");
+        }
+        Ok(())
+    }
+}
+
+/// FilteredTx is a transaction matching a filter with its merkle branch
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FilteredTx {
+    pub height: usize,
+    pub block: Hash256,
+    pub tx: Transaction,
+    pub branch: Vec<MerkleStep>
+}
+
+/// headers are the headers of the best chain of `bc` from height `from` on,
+/// MAX_HEADERS at most
+pub fn headers(bc: &Blockchain, from: usize) -> Result<Vec<SpvHeader>> {
+    let best_height = bc.get_best_height()? as usize;
+    let mut headers = Vec::new();
+    for height in from..=best_height.min(from.saturating_add(MAX_HEADERS - 1)) {
+        headers.push(SpvHeader::of(&bc.get_block(&bc.get_block_hash(height)?)?)?);
+    }
+    Ok(headers)
+}
+
+/// filtered scans MAX_FILTERED_BLOCKS blocks at most of the best chain of
+/// `bc` from height `from` on, returning the last height scanned and the
+/// transactions matching `filter`
+pub fn filtered(bc: &Blockchain, filter: &BloomFilter, from: usize) -> Result<(usize, Vec<FilteredTx>)> {
+    filter.check()?;
+    let to = (bc.get_best_height()? as usize).min(from.saturating_add(MAX_FILTERED_BLOCKS - 1));
+    let mut txs = Vec::new();
+    for height in from..=to {
+        let block = bc.get_block(&bc.get_block_hash(height)?)?;
+        for (index, tx) in block.get_transactions().iter().enumerate() {
+            if pub_key_hashes(tx).iter().any(|hash| filter.contains(hash)) {
+                txs.push(FilteredTx { height, block: block.get_hash(), tx: tx.clone(), branch: Block::merkle_branch(block.get_transactions(), index)? });
+            }
+        }
+    }
+    Ok((to, txs))
+}
+
+/// pub_key_hashes are the keys a transaction pays and, but for a coinbase,
+/// the keys it spends from
+fn pub_key_hashes(tx: &Transaction) -> Vec<Vec<u8>> {
+    let mut hashes: Vec<Vec<u8>> = tx.vout.iter().map(|out| out.pub_key_hash.clone()).collect();
+    if !tx.is_coinbase() {
+        for vin in &tx.vin {
+            let mut hash = vin.pub_key.clone();
+            hash_pub_key(&mut hash);
+            hashes.push(hash);
+        }
+    }
+    hashes
+}
+
+/// WalletTx is a transaction of the wallet, without a height until it is mined
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WalletTx {
+    pub tx: Transaction,
+    pub height: Option<usize>
+}
+
+/// SpvWallet keeps the headers and the wallet transactions of a light client
+/// in `spv` of the data directory
+pub struct SpvWallet {
+    config: Config,
+    db: sled::Db,
+    dialer: Dialer,
+    peers: Vec<String>
+}
+
+impl SpvWallet {
+    /// open opens the store of `config`, syncing from `peers` in turn
+    pub fn open(config: &Config, peers: Vec<String>) -> Result<SpvWallet> {
+        if peers.is_empty() {
+            return Err(BlockchainError::Config("a light client needs at least one peer".to_string()));
+        }
+        Ok(SpvWallet { config: config.clone(), db: sled::open(config.spv_path())?, dialer: Dialer::new(config)?, peers })
+    }
+
+    /// header is the header at `height`, None above the tip
+    pub fn header(&self, height: usize) -> Result<Option<SpvHeader>> {
+        match self.db.open_tree(HEADER_TREE)?.get((height as u64).to_be_bytes())? {
+            Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+            None => Ok(None)
+        }
+    }
+
+    /// tip is the last header, None before the first sync
+    pub fn tip(&self) -> Result<Option<SpvHeader>> {
+        match self.db.open_tree(HEADER_TREE)?.last()? {
+            Some((_, data)) => Ok(Some(bincode::deserialize(&data)?)),
+            None => Ok(None)
+        }
+    }
+
+    /// sync fetches the new headers and the wallet transactions in them from
+    /// the first peer that answers and returns the tip height
+    pub fn sync(&self) -> Result<usize> {
+        let mut last = BlockchainError::Network("no peer to sync with".to_string());
+        for peer in &self.peers {
+            match self.sync_headers(peer).and_then(|_| self.sync_filter_headers(peer)).and_then(|_| self.scan(peer)) {
+                Ok(()) => {
+                    self.check_filter_headers(peer)?;
+                    return Ok(self.tip()?.map_or(0, |tip| tip.header.height));
+                },
+                Err(e) => {
+                    warn!("failed to sync with {}: {}", peer, e);
+                    last = e;
+                }
+            }
+        }
+        Err(last)
+    }
+
+    /// transactions are the wallet transactions, the unconfirmed ones last
+    pub fn transactions(&self) -> Result<Vec<WalletTx>> {
+        let mut txs = Vec::new();
+        for item in self.db.open_tree(TX_TREE)?.iter() {
+            txs.push(bincode::deserialize::<WalletTx>(&item?.1)?);
+        }
+        txs.sort_by_key(|wtx| wtx.height.unwrap_or(usize::MAX));
+        Ok(txs)
+    }
+
+    /// balance is the confirmed and the unconfirmed balance of `pub_key_hash`
+    pub fn balance(&self, pub_key_hash: &[u8]) -> Result<(Amount, Amount)> {
+        let unspent = self.unspent(pub_key_hash)?;
+        let confirmed = Amount::sum(unspent.iter().filter(|u| u.2).map(|u| u.1))?;
+        let unconfirmed = Amount::sum(unspent.iter().filter(|u| !u.2).map(|u| u.1))?;
+        Ok((confirmed, unconfirmed))
+    }
+
+    /// send pays `amount` plus `fee` from the wallet `from` to `to` out of its
+    /// confirmed outputs, broadcasts the payment and returns its txid
+    pub fn send(&self, wallets: &Wallets, from: &str, to: &str, amount: Amount, fee: Amount) -> Result<Hash256> {
+        let wallet = wallets.get_wallet(from).ok_or_else(|| BlockchainError::Wallet(format!("'from' wallet {} not found", from)))?;
+        let mut pub_key_hash = wallet.public_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+
+        let needed = amount.try_add(fee)?;
+        let (mut available, mut spent) = (Amount::ZERO, Vec::new());
+        for (outpoint, value, _) in self.unspent(&pub_key_hash)?.into_iter().filter(|u| u.2) {
+            if available >= needed {
+                break;
+            }
+            available = available.try_add(value)?;
+            spent.push(outpoint);
+        }
+        if available < needed {
+            return Err(BlockchainError::InsufficientFunds { available, needed });
+        }
+
+        let mut vout = vec![TXOutput::new(amount, to.to_string())?];
+        let change = available.try_sub(needed)?;
+        if change > Amount::ZERO {
+            vout.push(TXOutput::new(change, from.to_string())?);
+        }
+        let vin = spent.iter().map(|prev_out| TXInput { prev_out: *prev_out, signature: Vec::new(), pub_key: wallet.public_key.clone() }).collect();
+        let mut tx = Transaction { id: Hash256::ZERO, vin, vout };
+        tx.id = tx.hash()?;
+        let mut prev_txs = std::collections::HashMap::new();
+        for prev_out in &spent {
+            prev_txs.insert(prev_out.txid, self.wallet_tx(&prev_out.txid)?.tx);
+        }
+        tx.sign(&wallet.secret_key, prev_txs)?;
+
+        let frame = MessageCodec::encode(&Message::Tx(Txmsg { addr_from: String::new(), transaction: tx.clone() }))?;
+        let mut sent = false;
+        for peer in &self.peers {
+            match self.dialer.connect(peer, REQUEST_TIMEOUT).and_then(|mut stream| stream.write_all(&frame)) {
+                Ok(()) => {
+                    info!("sent {} to {}", tx.id, peer);
+                    sent = true;
+                },
+                Err(e) => warn!("failed to send {} to {}: {}", tx.id, peer, e)
+            }
+        }
+        if !sent {
+            return Err(BlockchainError::Network(format!("no peer took transaction {}", tx.id)));
+        }
+        self.db.open_tree(TX_TREE)?.insert(tx.id.as_bytes(), bincode::serialize(&WalletTx { tx: tx.clone(), height: None })?)?;
+        Ok(tx.id)
+    }
+
+    /// sync_headers appends the headers of `peer` past the tip, going back to
+    /// where the chains part when the peer has a higher branch
+    fn sync_headers(&self, peer: &str) -> Result<()> {
+        let headers = self.db.open_tree(HEADER_TREE)?;
+        let mut tip = self.tip()?;
+        loop {
+            let from = tip.as_ref().map_or(0, |tip| tip.header.height + 1);
+            let Message::Headers(reply) = self.request(peer, Message::GetHeaders(GetHeadersmsg { addr_from: String::new(), from }))? else {
+                return Err(BlockchainError::Network(format!("{} did not answer with headers", peer)));
+            };
+            let Some(first) = reply.headers.first() else {
+                return Ok(());
+            };
+            if let Some(last) = &tip {
+                if first.header.prev_block_hash != last.header.hash {
+                    if reply.best_height <= last.header.height {
+                        return Ok(());
+                    }
+                    let fork = self.find_fork(peer, last.header.height)?;
+                    info!("{} has a higher branch from height {}", peer, fork + 1);
+                    self.rollback(fork)?;
+                    tip = self.header(fork)?;
+                    continue;
+                }
+            }
+            for header in reply.headers {
+                let bits = match &tip {
+                    Some(prev) => self.next_bits(prev)?,
+                    None => header.header.bits
+                };
+                header.check(tip.as_ref(), bits)?;
+                headers.insert((header.header.height as u64).to_be_bytes(), bincode::serialize(&header)?)?;
+                tip = Some(header);
+            }
+        }
+    }
+
+    /// find_fork is the height of the last header below `height` the chain of
+    /// `peer` shares, stepping back twice as far on each miss
+    fn find_fork(&self, peer: &str, height: usize) -> Result<usize> {
+        let (mut height, mut step) = (height, 1);
+        loop {
+            height = height.saturating_sub(step);
+            let Message::Headers(reply) = self.request(peer, Message::GetHeaders(GetHeadersmsg { addr_from: String::new(), from: height }))? else {
+                return Err(BlockchainError::Network(format!("{} did not answer with headers", peer)));
+            };
+            let ours = self.header(height)?.map(|h| h.header.hash);
+            if reply.headers.first().map(|h| h.header.hash) == ours {
+                return Ok(height);
+            }
+            if height == 0 {
+                return Err(BlockchainError::Network(format!("{} is on a chain with another genesis", peer)));
+            }
+            step *= 2;
+        }
+    }
+
+    /// rollback drops the headers and filter headers above `height` and marks the transactions
+    /// mined above it unconfirmed
+    fn rollback(&self, height: usize) -> Result<()> {
+        let headers = self.db.open_tree(HEADER_TREE)?;
+        for key in headers.range(((height + 1) as u64).to_be_bytes()..).keys() {
+            headers.remove(key?)?;
+        }
+        let cfheaders = self.db.open_tree(CFHEADER_TREE)?;
+        for key in cfheaders.range(((height + 1) as u64).to_be_bytes()..).keys() {
+            cfheaders.remove(key?)?;
+        }
+        let txs = self.db.open_tree(TX_TREE)?;
+        for wtx in self.transactions()? {
+            if wtx.height.is_some_and(|h| h > height) {
+                txs.insert(wtx.tx.id.as_bytes(), bincode::serialize(&WalletTx { tx: wtx.tx.clone(), height: None })?)?;
+            }
+        }
+        if self.scanned()?.is_some_and(|scanned| scanned > height) {
+            self.db.insert(SCANNED_KEY, &(height as u64).to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// next_bits is the target of the header after `prev`
+    fn next_bits(&self, prev: &SpvHeader) -> Result<u32> {
+        let header = &prev.header;
+        target::next_bits(self.config.network, header.height, header.bits, || {
+            let first = self.header(header.height + 1 - RETARGET_INTERVAL)?.ok_or_else(|| BlockchainError::BlockNotFound(format!("at height {}", header.height + 1 - RETARGET_INTERVAL)))?;
+            Ok(header.timestamp.saturating_sub(first.header.timestamp))
+        })
+    }
+
+    /// sync_filter_headers appends the filter headers of `peer` up to the
+    /// header tip, each chained to the one before
+    fn sync_filter_headers(&self, peer: &str) -> Result<()> {
+        let cfheaders = self.db.open_tree(CFHEADER_TREE)?;
+        let Some(tip) = self.tip()?.map(|tip| tip.header.height) else {
+            return Ok(());
+        };
+        loop {
+            let from = self.filter_tip()?.map_or(0, |height| height + 1);
+            if from > tip {
+                return Ok(());
+            }
+            let Message::CFHeaders(mut reply) = self.request(peer, Message::GetCFHeaders(GetCFHeadersmsg { addr_from: String::new(), from }))? else {
+                return Err(BlockchainError::Network(format!("{} did not answer with filter headers", peer)));
+            };
+            // the filter index of the peer may lag its headers, the scan waits for it
+            if reply.filter_hashes.is_empty() {
+                return Ok(());
+            }
+            let mut prev = self.filter_header(from)?;
+            if reply.prev_header != prev {
+                return Err(BlockchainError::InvalidProof(format!("the filter headers of {} do not follow height {}", peer, from - 1)));
+            }
+            // a peer that found blocks since the headers were synced sends more
+            if from + reply.filter_hashes.len() - 1 > tip {
+                reply.filter_hashes.truncate(tip + 1 - from);
+            } else if self.header(from + reply.filter_hashes.len() - 1)?.map(|h| h.header.hash) != Some(reply.stop) {
+                return Err(BlockchainError::InvalidProof(format!("the filter headers of {} are of another chain", peer)));
+            }
+            for (height, filter_hash) in (from..).zip(&reply.filter_hashes) {
+                prev = filter_header(filter_hash, &prev);
+                cfheaders.insert((height as u64).to_be_bytes(), prev.as_bytes())?;
+            }
+        }
+    }
+
+    /// check_filter_headers compares the filter header of the tip with the one
+    /// each other peer on the same tip has, so a peer hiding transactions from
+    /// its filters is caught unless every peer does
+    fn check_filter_headers(&self, synced: &str) -> Result<()> {
+        let Some(tip) = self.tip()? else {
+            return Ok(());
+        };
+        let height = tip.header.height;
+        let ours = self.filter_header(height + 1)?;
+        for peer in self.peers.iter().filter(|peer| *peer != synced) {
+            let reply = match self.request(peer, Message::GetCFHeaders(GetCFHeadersmsg { addr_from: String::new(), from: height })) {
+                Ok(Message::CFHeaders(reply)) => reply,
+                Ok(_) | Err(_) => {
+                    warn!("could not compare the filter headers of {}", peer);
+                    continue;
+                }
+            };
+            match reply.filter_hashes.first() {
+                Some(filter_hash) if reply.stop == tip.header.hash && reply.filter_hashes.len() == 1 => {
+                    if filter_header(filter_hash, &reply.prev_header) != ours {
+                        return Err(BlockchainError::InvalidProof(format!("{} and {} disagree on the filter of block {}", synced, peer, tip.header.hash)));
+                    }
+                },
+                _ => info!("{} is not on tip {}, its filter headers are not compared", peer, tip.header.hash)
+            }
+        }
+        Ok(())
+    }
+
+    /// scan tests the compact filters of the blocks past the last scan, up to
+    /// the last filter header, against the wallet keys and outputs and fetches
+    /// the blocks that match
+    fn scan(&self, peer: &str) -> Result<()> {
+        let keys = self.wallet_keys()?;
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let mut outpoints = self.wallet_outpoints(&keys)?;
+        let txs = self.db.open_tree(TX_TREE)?;
+        let Some(tip) = self.filter_tip()? else {
+            return Ok(());
+        };
+        let mut from = self.scanned()?.map_or(0, |scanned| scanned + 1);
+        while from <= tip {
+            let Message::CFilters(reply) = self.request(peer, Message::GetCFilters(GetCFiltersmsg { addr_from: String::new(), from }))? else {
+                return Err(BlockchainError::Network(format!("{} did not answer with filters", peer)));
+            };
+            if reply.filters.is_empty() {
+                return Err(BlockchainError::Network(format!("{} has no filters from height {}", peer, from)));
+            }
+            for (height, (hash, filter)) in (from..=tip).zip(reply.filters) {
+                let header = self.header(height)?.filter(|header| header.header.hash == hash);
+                let Some(header) = header else {
+                    return Err(BlockchainError::InvalidProof(format!("{} sent the filter of block {}, not of height {}", peer, hash, height)));
+                };
+                if Some(filter_header(&filter.hash(), &self.filter_header(height)?)) != self.filter_header_at(height)? {
+                    return Err(BlockchainError::InvalidProof(format!("the filter of block {} from {} does not match its filter header", hash, peer)));
+                }
+
+                let items: Vec<Vec<u8>> = keys.iter().cloned().chain(outpoints.iter().map(outpoint_item)).collect();
+                if filter.match_any(&hash, &items)? {
+                    // the filter lets a few blocks of other keys through
+                    for tx in self.block(peer, &header)?.get_transactions() {
+                        let pays = tx.vout.iter().any(|out| keys.contains(&out.pub_key_hash));
+                        let spends = !tx.is_coinbase() && tx.vin.iter().any(|vin| outpoints.contains(&vin.prev_out));
+                        if pays || spends {
+                            outpoints.extend((0..tx.vout.len()).filter(|i| keys.contains(&tx.vout[*i].pub_key_hash)).map(|i| tx.outpoint(i)));
+                            txs.insert(tx.id.as_bytes(), bincode::serialize(&WalletTx { tx: tx.clone(), height: Some(height) })?)?;
+                        }
+                    }
+                }
+                self.db.insert(SCANNED_KEY, &(height as u64).to_be_bytes())?;
+                from = height + 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// block fetches the block of `header` from `peer` and checks it is the
+    /// one the header commits to
+    fn block(&self, peer: &str, header: &SpvHeader) -> Result<Block> {
+        let hash = header.header.hash;
+        let Message::Block(reply) = self.request(peer, Message::GetData(GetDatamsg { addr_from: String::new(), kind: "block".to_string(), id: hash }))? else {
+            return Err(BlockchainError::Network(format!("{} did not answer with block {}", peer, hash)));
+        };
+        if SpvHeader::of(&reply.block)? != *header {
+            return Err(BlockchainError::InvalidProof(format!("{} sent another block than {}", peer, hash)));
+        }
+        Ok(reply.block)
+    }
+
+    /// request sends `message` to `peer` and reads its reply off the same connection
+    fn request(&self, peer: &str, message: Message) -> Result<Message> {
+        let mut stream = self.dialer.connect(peer, REQUEST_TIMEOUT)?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.write_all(&MessageCodec::encode(&message)?)?;
+        stream.shutdown(Shutdown::Write)?;
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply)?;
+        MessageCodec::decode(&reply)
+    }
+
+    fn scanned(&self) -> Result<Option<usize>> {
+        Ok(self.db.get(SCANNED_KEY)?.map(|data| u64::from_be_bytes(data.as_ref().try_into().unwrap_or_default()) as usize))
+    }
+
+    /// filter_tip is the height of the last filter header, None before the first
+    fn filter_tip(&self) -> Result<Option<usize>> {
+        Ok(self.db.open_tree(CFHEADER_TREE)?.last()?.map(|(key, _)| u64::from_be_bytes(key.as_ref().try_into().unwrap_or_default()) as usize))
+    }
+
+    fn filter_header_at(&self, height: usize) -> Result<Option<Hash256>> {
+        match self.db.open_tree(CFHEADER_TREE)?.get((height as u64).to_be_bytes())? {
+            Some(data) => Ok(Some(Hash256::from_slice(&data)?)),
+            None => Ok(None)
+        }
+    }
+
+    /// filter_header is the filter header a filter at `height` chains to,
+    /// zero for the genesis
+    fn filter_header(&self, height: usize) -> Result<Hash256> {
+        match height {
+            0 => Ok(Hash256::ZERO),
+            height => self.filter_header_at(height - 1)?.ok_or_else(|| BlockchainError::BlockNotFound(format!("no filter header at height {}", height - 1)))
+        }
+    }
+
+    fn wallet_tx(&self, txid: &Hash256) -> Result<WalletTx> {
+        match self.db.open_tree(TX_TREE)?.get(txid.as_bytes())? {
+            Some(data) => Ok(bincode::deserialize(&data)?),
+            None => Err(BlockchainError::TxNotFound(txid.to_string()))
+        }
+    }
+
+    /// wallet_keys are the public key hashes of the wallets of the config
+    fn wallet_keys(&self) -> Result<HashSet<Vec<u8>>> {
+        let wallets = Wallets::new(&self.config)?;
+        Ok(wallets
+            .get_all_address()
+            .iter()
+            .filter_map(|address| wallets.get_wallet(address))
+            .map(|wallet| {
+                let mut pub_key_hash = wallet.public_key.clone();
+                hash_pub_key(&mut pub_key_hash);
+                pub_key_hash
+            })
+            .collect())
+    }
+
+    /// wallet_outpoints are the outputs of the wallet transactions paying `keys`
+    fn wallet_outpoints(&self, keys: &HashSet<Vec<u8>>) -> Result<HashSet<OutPoint>> {
+        let mut outpoints = HashSet::new();
+        for wtx in self.transactions()? {
+            outpoints.extend((0..wtx.tx.vout.len()).filter(|i| keys.contains(&wtx.tx.vout[*i].pub_key_hash)).map(|i| wtx.tx.outpoint(i)));
+        }
+        Ok(outpoints)
+    }
+
+    /// unspent are the outputs paying `pub_key_hash` that no wallet transaction
+    /// spends, with their value and whether they are confirmed
+    fn unspent(&self, pub_key_hash: &[u8]) -> Result<Vec<(OutPoint, Amount, bool)>> {
+        let txs = self.transactions()?;
+        let spent: HashSet<OutPoint> = txs.iter().filter(|wtx| !wtx.tx.is_coinbase()).flat_map(|wtx| wtx.tx.vin.iter().map(|vin| vin.prev_out)).collect();
+        let mut unspent = Vec::new();
+        for wtx in &txs {
+            for (index, out) in wtx.tx.vout.iter().enumerate() {
+                let outpoint = wtx.tx.outpoint(index);
+                if out.pub_key_hash == pub_key_hash && !spent.contains(&outpoint) {
+                    unspent.push((outpoint, out.value, wtx.height.is_some()));
+                }
+            }
+        }
+        Ok(unspent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::testing::ChainFixture;
+    use crate::wallet::hash_to_address;
+
+    #[test]
+    fn test_light_wallet_syncs_and_pays_through_a_node() -> Result<()> {
+        let fixture = ChainFixture::restore(3)?;
+        let port = TcpListener::bind("localhost:0")?.local_addr()?.port();
+        let node = fixture.node_builder().port(&port.to_string()).listen(true).build()?;
+        let peer = format!("localhost:{}", port);
+        wait_for(|| TcpStream::connect(&peer).is_ok());
+
+        let wallet = SpvWallet::open(&fixture.config(), vec![peer])?;
+        let wallets = Wallets::new(&fixture.config())?;
+        assert_eq!(wallet.sync()?, 3);
+        assert_eq!(wallet.tip()?.map(|tip| tip.header.hash), Some(fixture.tip()));
+        let miner = address_hash(fixture.miner());
+        assert_eq!(wallet.balance(&miner)?, (node.balance(fixture.miner())?, Amount::ZERO));
+
+        let shop = hash_to_address(&[9; 20]);
+        let txid = wallet.send(&wallets, fixture.miner(), &shop, Amount::from_sat(25), Amount::from_sat(1))?;
+        assert!(wallet.balance(&miner)?.1 > Amount::ZERO);
+        // the fixture node mines the transactions it receives
+        wait_for(|| node.best_height().is_ok_and(|height| height == 4));
+        // the filter of the new block is built a moment after it is mined
+        wait_for(|| wallet.sync().is_ok_and(|height| height == 4) && wallet.balance(&miner).is_ok_and(|(_, unconfirmed)| unconfirmed == Amount::ZERO));
+        assert_eq!(wallet.balance(&miner)?, (node.balance(fixture.miner())?, Amount::ZERO));
+        assert!(wallet.transactions()?.iter().any(|wtx| wtx.tx.id == txid && wtx.height == Some(4)));
+        node.shutdown()
+    }
+
+    fn address_hash(address: &str) -> Vec<u8> {
+        crate::address::decode(address).expect("a valid address")
+    }
+
+    fn wait_for(done: impl Fn() -> bool) {
+        for _ in 0..100 {
+            if done() {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        panic!("timed out");
+    }
+}
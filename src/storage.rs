@@ -0,0 +1,345 @@
+use std::borrow::Cow;
+
+use serde::{Deserialize, Serialize};
+
+use tracing::info;
+
+use crate::block::{Block, BlockHeader};
+use crate::error::{BlockchainError, Result};
+use crate::hash::Hash256;
+use crate::progress::Progress;
+use crate::transaction::Transaction;
+use crate::tx::{TXInput, TXOutput};
+
+const META_TREE: &str = "meta";
+const SCHEMA_KEY: &str = "SCHEMA";
+
+/// SCHEMA_VERSION is the block layout written by this build, version 1 stored
+/// the whole block as plain bincode and version 2 referenced spent outputs by
+/// hex txid and signed index, both are refused since their txids cannot be kept,
+/// version 3 headers had no target and are refused as well, version 4 kept the
+/// hashes as hex strings and version 5 stored the transactions alone as the body,
+/// both are upgraded in place, version 6 kept the UTXO set keyed by txid and only
+/// has its indexes rebuilt
+pub const SCHEMA_VERSION: u32 = 7;
+
+/// WHOLE_BLOCK_VERSION is the first layout storing the whole block as the body
+const WHOLE_BLOCK_VERSION: u32 = 6;
+
+/// UPGRADABLE_VERSION is the oldest layout `Schema::upgrade` can rewrite
+const UPGRADABLE_VERSION: u32 = 4;
+
+/// LAST_KEY holds the hash of the tip in the default tree
+const LAST_KEY: &[u8] = b"LAST";
+
+/// Compression of the block bodies at rest
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Snappy
+}
+
+/// Schema is the storage metadata recorded when the block database is created
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Schema {
+    pub version: u32,
+    pub compression: Compression
+}
+
+/// StoredBlock is a block at rest, its header comes first and uncompressed so it
+/// can be read without decoding the body, which is the canonical bincode encoding
+/// of the whole block, the bytes it is relayed to peers as
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct StoredBlock {
+    header: BlockHeader,
+    compression: Compression,
+    body: Vec<u8>
+}
+
+/// RawStoredBlock reads a StoredBlock borrowing its body
+#[derive(Deserialize)]
+struct RawStoredBlock<'a> {
+    _header: BlockHeader,
+    compression: Compression,
+    body: &'a [u8]
+}
+
+impl Schema {
+    pub fn new(compression: Compression) -> Schema {
+        Schema {
+            version: SCHEMA_VERSION,
+            compression
+        }
+    }
+
+    /// Load reads the schema of the database, refusing the layouts this build can
+    /// neither read nor upgrade
+    pub fn load(db: &sled::Db) -> Result<Schema> {
+        let meta = db.open_tree(META_TREE)?;
+        let schema: Schema = match meta.get(SCHEMA_KEY)? {
+            Some(v) => bincode::deserialize(&v)?,
+            None => return Err(BlockchainError::UnsupportedSchema(1))
+        };
+        if schema.version < UPGRADABLE_VERSION {
+            return Err(BlockchainError::UnsupportedSchema(schema.version));
+        }
+        Ok(schema)
+    }
+
+    /// needs_upgrade tells whether the database has an older layout `upgrade` rewrites
+    pub fn needs_upgrade(&self) -> bool {
+        self.version < SCHEMA_VERSION
+    }
+
+    /// upgrade rewrites the blocks of an older database in the current layout:
+    /// version 4 blocks, stored under their hex hash with hex hashes and txids,
+    /// move under their raw hash with raw hashes, and version 5 blocks get the
+    /// whole block as their body. It drops `index_trees`, the caller rebuilds them
+    /// and then saves the schema. Blocks already rewritten decode to their own
+    /// key, so an upgrade that was interrupted is simply run again
+    pub fn upgrade(&mut self, db: &sled::Db, index_trees: &[&str]) -> Result<()> {
+        info!("upgrading the block database from schema {} to {}", self.version, SCHEMA_VERSION);
+        if self.version < WHOLE_BLOCK_VERSION {
+            self.rewrite_blocks(db)?;
+        }
+        for tree in index_trees {
+            db.drop_tree(tree)?;
+        }
+        db.flush()?;
+
+        self.version = SCHEMA_VERSION;
+        Ok(())
+    }
+
+    /// rewrite_blocks stores every block of a version 4 or 5 database in the
+    /// current layout under its raw hash
+    fn rewrite_blocks(&self, db: &sled::Db) -> Result<()> {
+        let blocks: Vec<sled::IVec> = db
+            .iter()
+            .keys()
+            .filter(|key| key.as_ref().map_or(true, |key| key.len() == 64 || key.len() == 32))
+            .collect::<sled::Result<_>>()?;
+
+        let mut progress = Progress::new("upgrade blocks", blocks.len() as u64);
+        for key in blocks {
+            progress.inc(1);
+            let Some(data) = db.get(&key)? else {
+                continue;
+            };
+            let block = match key.len() {
+                64 => decode_legacy_block(&data)?,
+                _ => match self.decode_block(&data) {
+                    Ok(block) if block.get_hash().as_bytes()[..] == key[..] => continue,
+                    _ => decode_schema_5_block(&data)?
+                }
+            };
+            db.insert(block.get_hash(), self.encode_block(&block)?)?;
+            if key.len() == 64 {
+                db.remove(&key)?;
+            }
+        }
+        progress.finish();
+
+        if let Some(last) = db.get(LAST_KEY)? {
+            if last.len() == 64 {
+                db.insert(LAST_KEY, legacy_hash(&String::from_utf8(last.to_vec())?)?.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, db: &sled::Db) -> Result<()> {
+        let meta = db.open_tree(META_TREE)?;
+        meta.insert(SCHEMA_KEY, bincode::serialize(self)?)?;
+        Ok(())
+    }
+
+    pub fn encode_block(&self, block: &Block) -> Result<Vec<u8>> {
+        let body = bincode::serialize(block)?;
+        let body = match self.compression {
+            Compression::None => body,
+            Compression::Snappy => snap::raw::Encoder::new().compress_vec(&body)?
+        };
+
+        let stored = StoredBlock {
+            header: block.header().clone(),
+            compression: self.compression,
+            body
+        };
+        Ok(bincode::serialize(&stored)?)
+    }
+
+    pub fn decode_block(&self, data: &[u8]) -> Result<Block> {
+        Ok(bincode::deserialize(&self.raw_block(data)?)?)
+    }
+
+    /// decode_body reads only the transactions of a stored block
+    pub fn decode_body(&self, data: &[u8]) -> Result<Vec<Transaction>> {
+        Block::decode_transactions(&self.raw_block(data)?)
+    }
+
+    /// raw_block returns the canonical encoding of a stored block without decoding
+    /// it, borrowed from `data` unless the body is compressed
+    pub fn raw_block<'a>(&self, data: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+        let stored: RawStoredBlock = bincode::deserialize(data)?;
+        match stored.compression {
+            Compression::None => Ok(Cow::Borrowed(stored.body)),
+            Compression::Snappy => Ok(Cow::Owned(snap::raw::Decoder::new().decompress_vec(stored.body)?))
+        }
+    }
+
+    /// DecodeHeader reads only the header of a stored block
+    pub fn decode_header(&self, data: &[u8]) -> Result<BlockHeader> {
+        Ok(bincode::deserialize(data)?)
+    }
+}
+
+/// LegacyHeader is BlockHeader as schema 4 wrote it, with hex hashes
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct LegacyHeader {
+    timestamp: u128,
+    prev_block_hash: String,
+    hash: String,
+    height: usize,
+    nonce: i32,
+    bits: u32
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct LegacyStoredBlock {
+    header: LegacyHeader,
+    compression: Compression,
+    body: Vec<u8>
+}
+
+/// LegacyTransaction is Transaction as schema 4 wrote it, with a hex txid
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct LegacyTransaction {
+    id: String,
+    vin: Vec<TXInput>,
+    vout: Vec<TXOutput>
+}
+
+/// legacy_hash reads a schema 4 hex hash, the empty parent of the genesis block
+/// being the zero hash
+fn legacy_hash(hex: &str) -> Result<Hash256> {
+    match hex {
+        "" => Ok(Hash256::ZERO),
+        hex => Hash256::from_hex(hex).map_err(|e| BlockchainError::Corrupt(format!("schema 4 block: {}", e)))
+    }
+}
+
+/// decode_schema_5_block reads a block whose body is its transactions alone
+fn decode_schema_5_block(data: &[u8]) -> Result<Block> {
+    let stored: StoredBlock = bincode::deserialize(data)?;
+    let body = match stored.compression {
+        Compression::None => stored.body,
+        Compression::Snappy => snap::raw::Decoder::new().decompress_vec(&stored.body)?
+    };
+    let transactions: Vec<Transaction> = bincode::deserialize(&body)?;
+    Ok(Block::from_parts(stored.header, transactions))
+}
+
+fn decode_legacy_block(data: &[u8]) -> Result<Block> {
+    let stored: LegacyStoredBlock = bincode::deserialize(data)?;
+    let body = match stored.compression {
+        Compression::None => stored.body,
+        Compression::Snappy => snap::raw::Decoder::new().decompress_vec(&stored.body)?
+    };
+    let legacy: Vec<LegacyTransaction> = bincode::deserialize(&body)?;
+
+    let mut transactions = Vec::with_capacity(legacy.len());
+    for tx in legacy {
+        transactions.push(Transaction {
+            id: legacy_hash(&tx.id)?,
+            vin: tx.vin,
+            vout: tx.vout
+        });
+    }
+    let header = BlockHeader {
+        timestamp: stored.header.timestamp,
+        prev_block_hash: legacy_hash(&stored.header.prev_block_hash)?,
+        hash: legacy_hash(&stored.header.hash)?,
+        height: stored.header.height,
+        nonce: stored.header.nonce,
+        bits: stored.header.bits
+    };
+    Ok(Block::from_parts(header, transactions))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallet::hash_to_address;
+
+    #[test]
+    fn test_upgrade_rewrites_schema_4_blocks() -> Result<()> {
+        let coinbase = Transaction::new_coinbase(hash_to_address(&[7; 20]), "genesis".to_string())?;
+        let genesis = Block::new_genesis_block(coinbase)?;
+        let tx = &genesis.get_transactions()[0];
+        let header = genesis.header().clone();
+        let legacy_body = vec![LegacyTransaction { id: tx.id.to_string(), vin: tx.vin.clone(), vout: tx.vout.clone() }];
+        let legacy = LegacyStoredBlock {
+            header: LegacyHeader {
+                timestamp: header.timestamp,
+                prev_block_hash: String::new(),
+                hash: header.hash.to_string(),
+                height: header.height,
+                nonce: header.nonce,
+                bits: header.bits
+            },
+            compression: Compression::None,
+            body: bincode::serialize(&legacy_body)?
+        };
+
+        let db = sled::Config::new().temporary(true).open()?;
+        db.insert(header.hash.to_string(), bincode::serialize(&legacy)?)?;
+        db.insert(LAST_KEY, header.hash.to_string().as_bytes())?;
+        db.open_tree("heights")?.insert(0u32.to_be_bytes(), header.hash.to_string().as_bytes())?;
+        let schema = Schema { version: UPGRADABLE_VERSION, compression: Compression::Snappy };
+        schema.save(&db)?;
+
+        let mut schema = Schema::load(&db)?;
+        assert!(schema.needs_upgrade());
+        schema.upgrade(&db, &["heights"])?;
+        assert!(!schema.needs_upgrade());
+
+        assert_eq!(db.get(LAST_KEY)?.as_deref(), Some(&genesis.get_hash().as_bytes()[..]));
+        assert!(db.get(header.hash.to_string())?.is_none());
+        assert!(db.open_tree("heights")?.is_empty());
+        let upgraded = schema.decode_block(&db.get(genesis.get_hash())?.expect("block under its raw hash"))?;
+        assert_eq!(upgraded.get_prev_hash(), Hash256::ZERO);
+        assert_eq!(upgraded.get_transactions()[0].id, tx.id);
+        assert!(upgraded.validate()?);
+
+        schema.upgrade(&db, &[])?;
+        assert_eq!(schema.decode_block(&db.get(genesis.get_hash())?.expect("block kept"))?.get_hash(), genesis.get_hash());
+        Ok(())
+    }
+
+    #[test]
+    fn test_upgrade_stores_the_whole_block_as_the_body() -> Result<()> {
+        let coinbase = Transaction::new_coinbase(hash_to_address(&[7; 20]), "genesis".to_string())?;
+        let genesis = Block::new_genesis_block(coinbase)?;
+        let schema_5 = StoredBlock {
+            header: genesis.header().clone(),
+            compression: Compression::Snappy,
+            body: snap::raw::Encoder::new().compress_vec(&bincode::serialize(genesis.get_transactions())?)?
+        };
+
+        let db = sled::Config::new().temporary(true).open()?;
+        db.insert(genesis.get_hash(), bincode::serialize(&schema_5)?)?;
+        db.insert(LAST_KEY, genesis.get_hash().as_bytes())?;
+        Schema { version: 5, compression: Compression::Snappy }.save(&db)?;
+
+        let mut schema = Schema::load(&db)?;
+        schema.upgrade(&db, &[])?;
+        let data = db.get(genesis.get_hash())?.expect("block kept");
+        assert_eq!(schema.raw_block(&data)?, bincode::serialize(&genesis)?);
+        assert!(schema.decode_block(&data)?.validate()?);
+
+        schema.upgrade(&db, &[])?;
+        assert_eq!(db.get(genesis.get_hash())?, Some(data));
+        Ok(())
+    }
+}
@@ -0,0 +1,456 @@
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use serde_json::{json, Value};
+use tracing::{debug, error, info, warn};
+
+use crate::block::{pow_hash, Block};
+use crate::error::{BlockchainError, Result};
+use crate::events::Event;
+use crate::miner::{BlockTemplate, Miner};
+use crate::server::Server;
+use crate::target::Target;
+
+/// SHARE_FACTOR is how many times easier than the block target a share is, so
+/// that every worker keeps submitting some and shows it is hashing even when it
+/// finds no block
+pub const SHARE_FACTOR: u128 = 16;
+
+/// NONCE_RANGE is how many nonces a worker is handed at a time
+pub const NONCE_RANGE: i64 = 1 << 20;
+
+/// POLL_INTERVAL is how long a connection waits for a request before checking
+/// whether the job changed
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// WORK_BATCH is how many nonces a worker hashes between reads of its messages
+const WORK_BATCH: i64 = 4096;
+
+/// Job is a template handed out to the workers, each hashing its header with
+/// the nonces of the ranges it was given
+struct Job {
+    id: u64,
+    template: BlockTemplate,
+    timestamp: u128,
+    header_prefix: Vec<u8>,
+    target: Target,
+    share_target: Target,
+    /// first nonce not handed out yet
+    next_nonce: i64
+}
+
+impl Job {
+    fn new(miner: &Miner, id: u64) -> Result<Job> {
+        let template = miner.template()?;
+        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis();
+        let header_prefix = Block::header_prefix(&template.prev_block_hash, &template.merkle_root, timestamp, template.bits)?;
+        let target = Target::from_compact(template.bits)?;
+        Ok(Job {
+            id,
+            template,
+            timestamp,
+            header_prefix,
+            target,
+            share_target: target.scale(SHARE_FACTOR, 1),
+            next_nonce: 0
+        })
+    }
+}
+
+/// Session is what the work server knows about a connected worker
+#[derive(Debug, Default)]
+struct Session {
+    name: String,
+    subscribed: bool,
+    /// the job the ranges and the submitted nonces belong to
+    job: u64,
+    ranges: Vec<(i64, i64)>,
+    submitted: HashSet<i32>,
+    shares: u64
+}
+
+/// Pool hands out the nonce ranges of the current job and turns the solutions
+/// the workers submit into blocks
+struct Pool {
+    server: Server,
+    miner: Miner,
+    job: Mutex<Job>,
+    last_job: AtomicU64,
+    workers: AtomicU64
+}
+
+impl Pool {
+    fn new(server: Server) -> Result<Pool> {
+        let miner = Miner::new(server.clone(), &server.config().mining_address);
+        let job = Job::new(&miner, 1)?;
+        Ok(Pool {
+            server,
+            miner,
+            job: Mutex::new(job),
+            last_job: AtomicU64::new(1),
+            workers: AtomicU64::new(0)
+        })
+    }
+
+    fn lock_job(&self) -> MutexGuard<'_, Job> {
+        self.job.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn job_id(&self) -> u64 {
+        self.lock_job().id
+    }
+
+    /// refresh replaces the job with one on the current tip and mempool, the
+    /// connections hand it out as they notice
+    fn refresh(&self) -> Result<()> {
+        let job = Job::new(&self.miner, self.last_job.fetch_add(1, Ordering::SeqCst) + 1)?;
+        debug!("new mining job {} at height {}", job.id, job.template.height);
+        *self.lock_job() = job;
+        Ok(())
+    }
+
+    /// assign hands the next nonce range of the current job to `session`, the job
+    /// is rebuilt with a new timestamp once all its nonces are handed out
+    fn assign(&self, session: &mut Session) -> Result<Value> {
+        let mut job = self.lock_job();
+        if job.next_nonce > i32::MAX as i64 {
+            *job = Job::new(&self.miner, self.last_job.fetch_add(1, Ordering::SeqCst) + 1)?;
+        }
+        let start = job.next_nonce;
+        let end = (start + NONCE_RANGE).min(i32::MAX as i64 + 1);
+        job.next_nonce = end;
+
+        if session.job != job.id {
+            session.job = job.id;
+            session.ranges.clear();
+            session.submitted.clear();
+        }
+        session.ranges.push((start, end));
+
+        Ok(json!({
+            "job": job.id,
+            "height": job.template.height,
+            "headerprefix": hex::encode(&job.header_prefix),
+            "target": job.target.to_string(),
+            "sharetarget": job.share_target.to_string(),
+            "noncestart": start,
+            "nonceend": end
+        }))
+    }
+
+    /// submit checks a share of `session` and, when it also meets the block
+    /// target, connects and announces the block it solves
+    fn submit(&self, session: &mut Session, job_id: u64, nonce: i32) -> Result<Value> {
+        let reject = |reason: String| Err(BlockchainError::Consensus(format!("share rejected, {}", reason)));
+        let (block, fees) = {
+            let job = self.lock_job();
+            if job_id != job.id || session.job != job.id {
+                return reject(format!("job {} is stale", job_id));
+            }
+            if !session.ranges.iter().any(|(start, end)| (*start..*end).contains(&(nonce as i64))) {
+                return reject(format!("nonce {} was not handed to this worker", nonce));
+            }
+            if !session.submitted.insert(nonce) {
+                return reject(format!("nonce {} was already submitted", nonce));
+            }
+            let hash = pow_hash(&job.header_prefix, nonce);
+            if !job.share_target.is_met_by(&hash) {
+                return reject(format!("{} is above the share target", hex::encode(hash)));
+            }
+
+            session.shares += 1;
+            debug!("share {} from {}, {} accepted", hex::encode(hash), session.name, session.shares);
+            if !job.target.is_met_by(&hash) {
+                return Ok(json!({ "shares": session.shares, "block": null }));
+            }
+            (job.template.solve(job.timestamp, nonce)?, job.template.fees)
+        };
+
+        self.server.submit_block(block.clone())?;
+        self.server.mining_stats().record_block(fees);
+        info!("worker {} found block {} at height {}", session.name, block.get_hash(), block.get_height());
+        Ok(json!({ "shares": session.shares, "block": block.get_hash() }))
+    }
+}
+
+/// start serves the work server of `server` on the configured work port of every
+/// interface, so workers on other machines can join, from background threads
+pub fn start(server: Server) -> Result<()> {
+    if server.config().mining_address.is_empty() {
+        return Err(BlockchainError::Config("the work server needs a mining address".to_string()));
+    }
+    let addr = format!("0.0.0.0:{}", server.config().work_port);
+    let listener = TcpListener::bind(&addr)?;
+    info!("work server on {}", addr);
+
+    let events = server.utxo_set().blockchain.events().subscribe();
+    let pool = Arc::new(Pool::new(server)?);
+    let refresher = pool.clone();
+    thread::spawn(move || {
+        for event in events {
+            if let Event::BlockConnected { .. } | Event::BlockDisconnected { .. } | Event::TxAccepted { .. } = event {
+                if let Err(e) = refresher.refresh() {
+                    error!("failed to refresh the mining job: {}", e);
+                }
+            }
+        }
+    });
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("failed to accept worker connection: {}", e);
+                    continue;
+                }
+            };
+            let pool = pool.clone();
+            thread::spawn(move || {
+                if let Err(e) = serve_connection(&pool, stream) {
+                    debug!("worker connection closed: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// serve_connection answers the requests of a worker, one JSON object per line,
+/// and notifies it of every new job once it subscribed
+fn serve_connection(pool: &Pool, stream: TcpStream) -> Result<()> {
+    let requests = read_lines(stream.try_clone()?);
+    let mut writer = stream;
+    let mut session = Session::default();
+    let worker = pool.workers.fetch_add(1, Ordering::Relaxed) + 1;
+
+    loop {
+        match requests.recv_timeout(POLL_INTERVAL) {
+            Ok(line) => {
+                let reply = match serde_json::from_str(&line) {
+                    Ok(request) => handle_request(pool, &mut session, worker, &request),
+                    Err(e) => json!({ "id": null, "result": null, "error": e.to_string() })
+                };
+                send(&mut writer, &reply)?;
+            },
+            Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => {
+                info!("worker {} left after {} shares", session.name, session.shares);
+                return Ok(());
+            }
+        }
+
+        if session.subscribed && session.job != pool.job_id() {
+            let work = pool.assign(&mut session)?;
+            send(&mut writer, &json!({ "id": null, "method": "mining.notify", "params": work }))?;
+        }
+    }
+}
+
+/// handle_request runs `mining.subscribe [name]`, `mining.getwork` or
+/// `mining.submit [job, nonce]`, the first two reply with a nonce range to hash
+fn handle_request(pool: &Pool, session: &mut Session, worker: u64, request: &Value) -> Value {
+    let params = request["params"].as_array().cloned().unwrap_or_default();
+    let result = match request["method"].as_str().unwrap_or_default() {
+        "mining.subscribe" => {
+            session.name = match params.first().and_then(Value::as_str) {
+                Some(name) => format!("{}#{}", name, worker),
+                None => format!("worker#{}", worker)
+            };
+            session.subscribed = true;
+            info!("worker {} subscribed", session.name);
+            pool.assign(session)
+        },
+        "mining.getwork" if session.subscribed => pool.assign(session),
+        "mining.submit" if session.subscribed => {
+            match (params.first().and_then(Value::as_u64), params.get(1).and_then(Value::as_i64)) {
+                (Some(job), Some(nonce)) if i32::try_from(nonce).is_ok() => pool.submit(session, job, nonce as i32),
+                _ => Err(BlockchainError::Network("mining.submit expects a job id and a nonce".to_string()))
+            }
+        },
+        "mining.getwork" | "mining.submit" => Err(BlockchainError::Network("subscribe first".to_string())),
+        method => Err(BlockchainError::Network(format!("unknown method {:?}", method)))
+    };
+
+    match result {
+        Ok(result) => json!({ "id": request["id"], "result": result, "error": null }),
+        Err(e) => json!({ "id": request["id"], "result": null, "error": e.to_string() })
+    }
+}
+
+/// Work is the nonce range a worker hashes
+struct Work {
+    job: u64,
+    header_prefix: Vec<u8>,
+    share_target: Target,
+    next: i64,
+    end: i64,
+    /// a new range was asked for once this one ran out
+    requested: bool
+}
+
+impl Work {
+    /// from_message reads the work out of a `mining.notify` or of the reply to a
+    /// request handing out a range, None for other messages
+    fn from_message(message: &Value) -> Result<Option<Work>> {
+        let work = match message["method"].as_str() {
+            Some("mining.notify") => &message["params"],
+            _ => &message["result"]
+        };
+        let (Some(job), Some(prefix), Some(share_target), Some(start), Some(end)) = (
+            work["job"].as_u64(),
+            work["headerprefix"].as_str(),
+            work["sharetarget"].as_str(),
+            work["noncestart"].as_i64(),
+            work["nonceend"].as_i64()
+        ) else {
+            return Ok(None);
+        };
+
+        Ok(Some(Work {
+            job,
+            header_prefix: hex::decode(prefix).map_err(|e| BlockchainError::Network(format!("invalid header prefix: {}", e)))?,
+            share_target: Target::from_hex(share_target)?,
+            next: start,
+            end,
+            requested: false
+        }))
+    }
+
+    fn is_done(&self) -> bool {
+        self.next >= self.end
+    }
+}
+
+/// run_worker connects to the work server at `addr` as `name` and hashes the
+/// ranges it is handed, submitting every share, until the server goes away
+pub fn run_worker(addr: &str, name: &str) -> Result<()> {
+    let stream = TcpStream::connect(addr)?;
+    let messages = read_lines(stream.try_clone()?);
+    let mut writer = stream;
+    let mut request_id = 1;
+    send(&mut writer, &json!({ "id": request_id, "method": "mining.subscribe", "params": [name] }))?;
+    info!("connected to the work server at {}", addr);
+
+    let mut work: Option<Work> = None;
+    loop {
+        // block for work when there is none left, otherwise take what arrived
+        loop {
+            let line = if work.as_ref().is_none_or(Work::is_done) {
+                match messages.recv() {
+                    Ok(line) => line,
+                    Err(_) => return Ok(())
+                }
+            } else {
+                match messages.try_recv() {
+                    Ok(line) => line,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return Ok(())
+                }
+            };
+
+            let message: Value = serde_json::from_str(&line)?;
+            if let Some(next) = Work::from_message(&message)? {
+                debug!("job {}, nonces {}..{}", next.job, next.next, next.end);
+                work = Some(next);
+            } else if !message["error"].is_null() {
+                warn!("{}", message["error"]);
+            } else if let Some(hash) = message["result"]["block"].as_str() {
+                info!("found block {}", hash);
+            }
+        }
+
+        let Some(current) = work.as_mut() else {
+            continue;
+        };
+        let end = (current.next + WORK_BATCH).min(current.end);
+        for nonce in current.next..end {
+            if current.share_target.is_met_by(&pow_hash(&current.header_prefix, nonce as i32)) {
+                request_id += 1;
+                send(&mut writer, &json!({ "id": request_id, "method": "mining.submit", "params": [current.job, nonce] }))?;
+            }
+        }
+        current.next = end;
+
+        if current.is_done() && !current.requested {
+            current.requested = true;
+            request_id += 1;
+            send(&mut writer, &json!({ "id": request_id, "method": "mining.getwork", "params": [] }))?;
+        }
+    }
+}
+
+/// read_lines forwards the lines read from `stream` from a background thread
+/// until it is closed
+fn read_lines(stream: TcpStream) -> Receiver<String> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stream).lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+fn send(stream: &mut TcpStream, message: &Value) -> Result<()> {
+    writeln!(stream, "{}", message)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Network;
+    use crate::node::NodeBuilder;
+
+    #[test]
+    fn test_workers_share_and_solve_jobs() -> Result<()> {
+        let datadir = std::env::temp_dir().join(format!("blockchain-stratum-test-{}", std::process::id()));
+        let builder = NodeBuilder::new().network(Network::Regtest).datadir(&datadir);
+        let miner = builder.create_wallet()?;
+        let node = builder.mine_to(&miner).listen(false).build()?;
+        let pool = Pool::new(node.server().clone())?;
+
+        let mut session = Session::default();
+        let submit = json!({ "id": 1, "method": "mining.submit", "params": [1, 0] });
+        assert!(!handle_request(&pool, &mut session, 1, &submit)["error"].is_null());
+        let reply = handle_request(&pool, &mut session, 1, &json!({ "id": 2, "method": "mining.subscribe", "params": ["test"] }));
+        let work = Work::from_message(&reply)?.expect("subscribing hands out work");
+        assert_eq!((work.job, work.next, work.end), (1, 0, NONCE_RANGE));
+
+        let target = pool.lock_job().target;
+        let solution = (work.next..work.end)
+            .find(|nonce| target.is_met_by(&pow_hash(&work.header_prefix, *nonce as i32)))
+            .expect("a range holds a solution");
+        let share = (work.next..work.end)
+            .map(|nonce| (nonce, pow_hash(&work.header_prefix, nonce as i32)))
+            .find(|(_, hash)| work.share_target.is_met_by(hash) && !target.is_met_by(hash))
+            .map(|(nonce, _)| nonce)
+            .expect("a range holds a share");
+        let reply = pool.submit(&mut session, work.job, share as i32)?;
+        assert_eq!(reply["block"], Value::Null);
+        assert!(pool.submit(&mut session, work.job, share as i32).is_err());
+        assert!(pool.submit(&mut session, work.job, NONCE_RANGE as i32).is_err());
+
+        let reply = pool.submit(&mut session, work.job, solution as i32)?;
+        assert_eq!(reply["shares"], 2);
+        assert_eq!(reply["block"], node.best_block_hash().to_string());
+        assert_eq!(node.best_height()?, 1);
+        assert!(pool.submit(&mut session, work.job, solution as i32).is_err());
+
+        node.shutdown()?;
+        std::fs::remove_dir_all(&datadir)?;
+        Ok(())
+    }
+}
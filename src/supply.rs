@@ -0,0 +1,159 @@
+//! Supply audit. Every block may add at most SUBSIDY to the coins in
+//! circulation, so replaying the best chain from genesis tells how many coins
+//! the UTXO set must hold: the subsidies, less what coinbases left unclaimed,
+//! what data outputs locked away and what a coinbase repeating the txid of an
+//! unspent one overwrote. A block issuing more than the subsidy, or a UTXO set
+//! holding another value, is an inflation or an accounting bug.
+
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::amount::Amount;
+use crate::blockchain::Blockchain;
+use crate::error::{BlockchainError, Result};
+use crate::hash::Hash256;
+use crate::progress::Progress;
+use crate::transaction::SUBSIDY;
+use crate::tx::OutPoint;
+use crate::utxoset::UTXOSet;
+
+/// BlockSupply is what a block did to the supply: the coins it issued, the
+/// outputs it created less the ones it spent, and the coins it made unspendable
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockSupply {
+    pub height: usize,
+    pub hash: Hash256,
+    pub issued: Amount,
+    /// value of its data outputs
+    pub burned: Amount,
+    /// value of the unspent outputs its coinbase overwrote
+    pub overwritten: Amount
+}
+
+impl BlockSupply {
+    /// unclaimed is the part of the subsidy the block did not issue, negative
+    /// when it inflated the supply
+    pub fn unclaimed(&self) -> Result<Amount> {
+        SUBSIDY.try_sub(self.issued)
+    }
+}
+
+/// SupplyAudit compares the supply the chain allows with the UTXO set
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SupplyAudit {
+    pub height: usize,
+    pub tip: Hash256,
+    /// SUBSIDY for every block, genesis included
+    pub subsidies: Amount,
+    pub unclaimed: Amount,
+    pub burned: Amount,
+    pub overwritten: Amount,
+    /// the subsidies less the unclaimed, burned and overwritten coins
+    pub expected: Amount,
+    /// value of the outputs the replayed chain leaves unspent
+    pub chain_value: Amount,
+    /// value of the UTXO set
+    pub utxo_value: Amount,
+    pub utxos: usize,
+    /// blocks issuing more than the subsidy
+    pub inflation: Vec<BlockSupply>,
+    /// every block of the chain, from genesis
+    #[serde(skip)]
+    pub blocks: Vec<BlockSupply>
+}
+
+impl SupplyAudit {
+    /// is_sound tells whether no block inflated the supply and the UTXO set
+    /// holds exactly what the chain allows
+    pub fn is_sound(&self) -> bool {
+        self.inflation.is_empty() && self.chain_value == self.expected && self.utxo_value == self.expected
+    }
+}
+
+/// audit replays the best chain of `bc` from genesis and checks it against
+/// its UTXO set
+pub(crate) fn audit(bc: &Blockchain) -> Result<SupplyAudit> {
+    let tip = bc.get_tip();
+    let height = bc.get_block_header(&tip)?.height;
+    let mut unspent: HashMap<OutPoint, Amount> = HashMap::new();
+    let mut blocks = Vec::with_capacity(height + 1);
+    let mut progress = (height > 0).then(|| Progress::new("audit supply", height as u64 + 1));
+    for block_height in 0..=height {
+        let block = bc.get_block(&bc.get_block_hash(block_height)?)?;
+        let mut supply = BlockSupply { height: block_height, hash: block.get_hash(), issued: Amount::ZERO, burned: Amount::ZERO, overwritten: Amount::ZERO };
+        let mut spent = Amount::ZERO;
+        let mut created = Amount::ZERO;
+        for tx in block.get_transactions() {
+            if !tx.is_coinbase() {
+                for vin in &tx.vin {
+                    let value = unspent.remove(&vin.prev_out).ok_or_else(|| BlockchainError::Corrupt(format!("block {} spends {}, which the chain does not hold", supply.hash, vin.prev_out)))?;
+                    spent = spent.try_add(value)?;
+                }
+            }
+            for (index, out) in tx.vout.iter().enumerate() {
+                created = created.try_add(out.value)?;
+                if out.is_data() {
+                    supply.burned = supply.burned.try_add(out.value)?;
+                } else if let Some(lost) = unspent.insert(tx.outpoint(index), out.value) {
+                    supply.overwritten = supply.overwritten.try_add(lost)?;
+                }
+            }
+        }
+        supply.issued = created.try_sub(spent)?;
+        blocks.push(supply);
+        if let Some(progress) = progress.as_mut() {
+            progress.inc(1);
+        }
+    }
+    if let Some(mut progress) = progress {
+        progress.finish();
+    }
+
+    let utxos = UTXOSet { blockchain: bc.clone() }.list_unspent(None)?;
+    let subsidies = Amount::sum(blocks.iter().map(|_| SUBSIDY))?;
+    let unclaimed = Amount::sum(blocks.iter().map(BlockSupply::unclaimed).collect::<Result<Vec<_>>>()?)?;
+    let burned = Amount::sum(blocks.iter().map(|b| b.burned))?;
+    let overwritten = Amount::sum(blocks.iter().map(|b| b.overwritten))?;
+    Ok(SupplyAudit {
+        height,
+        tip,
+        subsidies,
+        unclaimed,
+        burned,
+        overwritten,
+        expected: subsidies.try_sub(unclaimed)?.try_sub(burned)?.try_sub(overwritten)?,
+        chain_value: Amount::sum(unspent.into_values())?,
+        utxo_value: Amount::sum(utxos.iter().map(|out| out.value))?,
+        utxos: utxos.len(),
+        inflation: blocks.iter().filter(|b| b.issued > SUBSIDY).copied().collect(),
+        blocks
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ChainFixture;
+    use crate::transaction::Transaction;
+    use crate::wallet::Wallets;
+
+    #[test]
+    fn test_the_supply_of_a_sound_chain_adds_up() -> Result<()> {
+        let fixture = ChainFixture::restore(3)?;
+        let bc = fixture.blockchain()?;
+        let utxo = UTXOSet { blockchain: bc.clone() };
+        let miner = fixture.miner().to_string();
+        let tx = Transaction::new_UTXO(&Wallets::new(&fixture.config())?, &miner, &miner, Amount::from_sat(30), Amount::from_sat(5), &utxo)?;
+        let coinbase = Transaction::new_coinbase(miner.clone(), "supply".to_string())?;
+        utxo.connect_block(&bc.mine_block(vec![coinbase, tx])?)?;
+
+        let audit = bc.audit_supply()?;
+        assert!(audit.is_sound(), "{:?}", audit);
+        assert_eq!(audit.blocks.len(), audit.height + 1);
+        // the coinbase did not claim the fee, which left the supply
+        assert_eq!(audit.unclaimed, Amount::from_sat(5));
+        assert_eq!(audit.expected, audit.subsidies.try_sub(Amount::from_sat(5))?);
+        Ok(())
+    }
+}
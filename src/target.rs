@@ -0,0 +1,219 @@
+use std::fmt;
+
+use ethnum::U256;
+
+use crate::config::Network;
+use crate::error::{BlockchainError, Result};
+
+/// POW_LIMIT_BITS is the easiest target, in the compact encoding, a hash must
+/// start with 4 zero hex digits to meet it
+pub const POW_LIMIT_BITS: u32 = 0x1f00ffff;
+
+/// RETARGET_INTERVAL is how many blocks are mined between difficulty adjustments
+pub const RETARGET_INTERVAL: usize = 10;
+
+/// TARGET_SPACING is the time in milliseconds a block should take on average
+pub const TARGET_SPACING: u128 = 10_000;
+
+/// MAX_ADJUSTMENT bounds how much a single adjustment moves the target either way
+const MAX_ADJUSTMENT: u128 = 4;
+
+/// Target is the highest proof of work hash a block accepts, a 256-bit number
+/// the hash is compared to in big endian, carried in headers in the compact
+/// "bits" encoding: a byte length followed by the 3 most significant bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Target(U256);
+
+impl Target {
+    /// pow_limit is the easiest target a block may have
+    pub fn pow_limit() -> Target {
+        Target::from_compact(POW_LIMIT_BITS).expect("the pow limit is a valid compact target")
+    }
+
+    /// from_compact decodes a compact target, refusing negative, zero and
+    /// overflowing ones
+    pub fn from_compact(bits: u32) -> Result<Target> {
+        let size = bits >> 24;
+        let mantissa = bits & 0x007fffff;
+        if bits & 0x00800000 != 0 {
+            return Err(BlockchainError::Consensus(format!("target {:08x} is negative", bits)));
+        }
+
+        let target = if size <= 3 {
+            U256::from(mantissa >> (8 * (3 - size)))
+        } else {
+            let shift = 8 * (size - 3);
+            let value = U256::from(mantissa);
+            if shift >= 256 || (value << shift) >> shift != value {
+                return Err(BlockchainError::Consensus(format!("target {:08x} overflows 256 bits", bits)));
+            }
+            value << shift
+        };
+        if target == U256::ZERO {
+            return Err(BlockchainError::Consensus(format!("target {:08x} is zero", bits)));
+        }
+        Ok(Target(target))
+    }
+
+    /// to_compact encodes the target, dropping all but its 3 most significant bytes
+    pub fn to_compact(self) -> u32 {
+        let mut size = (256 - self.0.leading_zeros()).div_ceil(8);
+        let mut mantissa = if size <= 3 {
+            self.0.as_u32() << (8 * (3 - size))
+        } else {
+            (self.0 >> (8 * (size - 3))).as_u32()
+        };
+        // the high bit of the mantissa is the sign, keep it clear
+        if mantissa & 0x00800000 != 0 {
+            mantissa >>= 8;
+            size += 1;
+        }
+        mantissa | (size << 24)
+    }
+
+    /// from_hex reads a target written as 64 hex digits
+    pub fn from_hex(hex: &str) -> Result<Target> {
+        match U256::from_str_radix(hex, 16) {
+            Ok(target) if target != U256::ZERO => Ok(Target(target)),
+            _ => Err(BlockchainError::Consensus(format!("invalid target {:?}", hex)))
+        }
+    }
+
+    /// is_met_by tells whether `hash` is not above the target
+    pub fn is_met_by(self, hash: &[u8; 32]) -> bool {
+        U256::from_be_bytes(*hash) <= self.0
+    }
+
+    /// scale multiplies the target by `num / den`, saturating at the highest hash
+    pub fn scale(self, num: u128, den: u128) -> Target {
+        let target = match self.0.checked_mul(U256::from(num)) {
+            Some(product) => product / U256::from(den),
+            None => (self.0 / U256::from(den)).saturating_mul(U256::from(num))
+        };
+        Target(target.max(U256::ONE))
+    }
+
+    /// difficulty is how many times harder than the pow limit the target is to meet
+    pub fn difficulty(self) -> f64 {
+        Target::pow_limit().0.as_f64() / self.0.as_f64()
+    }
+
+    /// work is the number of hashes meeting the target takes on average
+    pub fn work(self) -> f64 {
+        2f64.powi(256) / (self.0.as_f64() + 1.0)
+    }
+
+    /// block_work is the exact work of a block meeting the target, 2^256 / (target + 1)
+    pub fn block_work(self) -> Work {
+        match self.0.checked_add(U256::ONE) {
+            Some(divisor) => Work(!self.0 / divisor + U256::ONE),
+            None => Work(U256::ONE)
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:064x}", self.0)
+    }
+}
+
+/// Work is the total proof of work of a chain, the sum of the block_work of its
+/// blocks, which decides the best chain
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Work(U256);
+
+impl Work {
+    pub const ZERO: Work = Work(U256::ZERO);
+
+    /// from_slice reads a work stored as 32 big endian bytes
+    pub fn from_slice(bytes: &[u8]) -> Result<Work> {
+        match <[u8; 32]>::try_from(bytes) {
+            Ok(bytes) => Ok(Work(U256::from_be_bytes(bytes))),
+            Err(_) => Err(BlockchainError::Corrupt(format!("chain work of {} bytes", bytes.len())))
+        }
+    }
+
+    pub fn to_be_bytes(self) -> [u8; 32] {
+        self.0.to_be_bytes()
+    }
+}
+
+impl std::ops::Add for Work {
+    type Output = Work;
+
+    fn add(self, other: Work) -> Work {
+        Work(self.0.saturating_add(other.0))
+    }
+}
+
+impl fmt::Display for Work {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:064x}", self.0)
+    }
+}
+
+/// next_bits is the target of the block after one at `height` with `bits`, on
+/// `network`: every RETARGET_INTERVAL blocks it is scaled by how long the last
+/// interval took against TARGET_SPACING per block, at most MAX_ADJUSTMENT times
+/// either way and never above the pow limit, regtest keeps the pow limit
+pub fn next_bits(network: Network, height: usize, bits: u32, interval_ms: impl FnOnce() -> Result<u128>) -> Result<u32> {
+    if network == Network::Regtest || !(height + 1).is_multiple_of(RETARGET_INTERVAL) {
+        return Ok(bits);
+    }
+
+    let expected = TARGET_SPACING * RETARGET_INTERVAL as u128;
+    let actual = interval_ms()?.clamp(expected / MAX_ADJUSTMENT, expected * MAX_ADJUSTMENT);
+    let target = Target::from_compact(bits)?.scale(actual, expected).min(Target::pow_limit());
+    Ok(target.to_compact())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compact_round_trip() -> Result<()> {
+        let limit = Target::pow_limit();
+        assert_eq!(limit.to_string(), format!("0000ffff{}", "0".repeat(56)));
+        assert_eq!(limit.to_compact(), POW_LIMIT_BITS);
+        assert_eq!(limit.difficulty(), 1.0);
+        assert_eq!(Target::from_hex(&limit.to_string())?, limit);
+
+        // bitcoin's genesis target and a mantissa needing the sign byte
+        assert_eq!(Target::from_compact(0x1d00ffff)?.to_compact(), 0x1d00ffff);
+        assert_eq!(Target::from_compact(0x1f008000)?.to_compact(), 0x1f008000);
+        assert!(Target::from_compact(0x1f800000).is_err());
+        assert!(Target::from_compact(0x2200ffff).is_err());
+        assert!(Target::from_compact(0x1f000000).is_err());
+        assert_eq!(Target::from_compact(0x1d00ffff)?.block_work().to_string(), format!("{:064x}", 0x100010001u64));
+
+        let mut hash = [0; 32];
+        hash[2] = 0xff;
+        hash[3] = 0xff;
+        assert!(limit.is_met_by(&hash));
+        hash[4] = 1;
+        assert!(!limit.is_met_by(&hash));
+        Ok(())
+    }
+
+    #[test]
+    fn test_retarget_moves_smoothly() -> Result<()> {
+        let expected = TARGET_SPACING * RETARGET_INTERVAL as u128;
+        let harder = Target::pow_limit().scale(1, 2).to_compact();
+
+        // only the last block of an interval adjusts, and regtest never does
+        assert_eq!(next_bits(Network::Main, 0, harder, || Ok(1))?, harder);
+        assert_eq!(next_bits(Network::Regtest, RETARGET_INTERVAL - 1, harder, || Ok(1))?, harder);
+
+        // blocks 25% too slow ease the target by 25%, not a whole hex digit
+        let eased = Target::from_compact(next_bits(Network::Main, RETARGET_INTERVAL - 1, harder, || Ok(expected * 5 / 4))?)?;
+        let ratio = Target::from_compact(harder)?.difficulty() / eased.difficulty();
+        assert!((ratio - 1.25).abs() < 0.001, "{}", ratio);
+
+        let fastest = next_bits(Network::Main, RETARGET_INTERVAL - 1, harder, || Ok(0))?;
+        assert!((Target::from_compact(fastest)?.difficulty() / Target::from_compact(harder)?.difficulty() - 4.0).abs() < 0.001);
+        assert_eq!(next_bits(Network::Test, RETARGET_INTERVAL - 1, harder, || Ok(expected * 100))?, POW_LIMIT_BITS);
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+//! Chain fixtures for tests, built with the `test-support` feature.
+//!
+//! Mining a regtest chain block by block takes most of the time of a test that
+//! needs one. `ChainFixture::restore(n)` mines a deterministic chain of `n`
+//! blocks once per process, keeps the files of its data directory in memory
+//! and writes them into a fresh temporary directory for every test:
+//!
+//! ```no_run
+//! use blockchain_project::testing::ChainFixture;
+//!
+//! let fixture = ChainFixture::restore(20)?;
+//! let node = fixture.node_builder().build()?;
+//! assert_eq!(node.server().utxo_set().blockchain.get_best_height()?, 20);
+//! node.shutdown()?;
+//! # Ok::<(), blockchain_project::error::BlockchainError>(())
+//! ```
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+
+use rayon::prelude::*;
+
+use crate::block::{pow_hash, Block};
+use crate::blockchain::Blockchain;
+use crate::config::{Config, Network};
+use crate::error::{BlockchainError, Result};
+use crate::hash::Hash256;
+use crate::node::NodeBuilder;
+use crate::storage::Compression;
+use crate::target::{Target, POW_LIMIT_BITS, TARGET_SPACING};
+use crate::transaction::Transaction;
+use crate::utxoset::UTXOSet;
+use crate::wallet::{Wallet, Wallets};
+
+/// MINER_SEED is the seed of the wallet every fixture block pays
+pub const MINER_SEED: [u8; 32] = [7; 32];
+
+/// GENESIS_TIME is the timestamp of the fixture genesis, in milliseconds, each
+/// block after it comes TARGET_SPACING later
+pub const GENESIS_TIME: u128 = 1_700_000_000_000;
+
+/// SNAPSHOTS holds the snapshot of each chain length generated by this process
+static SNAPSHOTS: OnceLock<Mutex<HashMap<usize, Arc<Snapshot>>>> = OnceLock::new();
+
+/// RESTORES numbers the directories restored by this process
+static RESTORES: AtomicUsize = AtomicUsize::new(0);
+
+/// Snapshot is the files of a generated data directory, by relative path
+struct Snapshot {
+    files: Vec<(PathBuf, Vec<u8>)>,
+    tip: Hash256
+}
+
+/// ChainFixture is a regtest data directory holding a restored chain and the
+/// miner wallet, removed when the fixture is dropped
+pub struct ChainFixture {
+    datadir: PathBuf,
+    miner: String,
+    tip: Hash256
+}
+
+impl ChainFixture {
+    /// restore writes the chain of `blocks` blocks after the genesis into a new
+    /// temporary directory, mining it first when this process has not yet
+    pub fn restore(blocks: usize) -> Result<ChainFixture> {
+        let snapshot = snapshot(blocks)?;
+        let datadir = temp_datadir("restore");
+        for (path, data) in &snapshot.files {
+            let path = datadir.join(path);
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::write(path, data)?;
+        }
+
+        Ok(ChainFixture {
+            datadir,
+            miner: Wallet::from_seed(&MINER_SEED).get_address(),
+            tip: snapshot.tip
+        })
+    }
+
+    pub fn datadir(&self) -> &Path {
+        &self.datadir
+    }
+
+    /// config is the regtest config of the fixture directory
+    pub fn config(&self) -> Config {
+        fixture_config(&self.datadir)
+    }
+
+    /// miner is the address of the wallet paid by every block, saved in the fixture
+    pub fn miner(&self) -> &str {
+        &self.miner
+    }
+
+    /// tip is the hash of the last block, the same in every restore of a length
+    pub fn tip(&self) -> Hash256 {
+        self.tip
+    }
+
+    /// blockchain opens the restored chain, drop it before building a node
+    pub fn blockchain(&self) -> Result<Blockchain> {
+        Blockchain::new(&self.config())
+    }
+
+    /// node_builder prepares a node on the fixture that mines to the miner
+    /// wallet and does not listen
+    pub fn node_builder(&self) -> NodeBuilder {
+        NodeBuilder::new()
+            .config(self.config())
+            .mine_to(&self.miner)
+            .listen(false)
+    }
+}
+
+impl Drop for ChainFixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.datadir);
+    }
+}
+
+/// snapshot returns the snapshot of a chain of `blocks` blocks, generating it
+/// on first use
+fn snapshot(blocks: usize) -> Result<Arc<Snapshot>> {
+    let mut snapshots = SNAPSHOTS.get_or_init(Default::default).lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(snapshot) = snapshots.get(&blocks) {
+        return Ok(snapshot.clone());
+    }
+
+    let datadir = temp_datadir("generate");
+    let generated = generate(&datadir, blocks).and_then(|tip| {
+        let mut files = Vec::new();
+        read_files(&datadir, &datadir, &mut files)?;
+        Ok(Arc::new(Snapshot { files, tip }))
+    });
+    let _ = fs::remove_dir_all(&datadir);
+
+    let snapshot = generated?;
+    snapshots.insert(blocks, snapshot.clone());
+    Ok(snapshot)
+}
+
+/// generate mines the genesis and `blocks` blocks into `datadir`, all paying
+/// the MINER_SEED wallet at fixed times, and returns the tip
+fn generate(datadir: &Path, blocks: usize) -> Result<Hash256> {
+    let config = fixture_config(datadir);
+    let mut wallets = Wallets::new(&config)?;
+    let miner = wallets.import_wallet(Wallet::from_seed(&MINER_SEED));
+    wallets.save_all()?;
+
+    let coinbase = Transaction::new_coinbase(miner.clone(), "genesis".to_string())?;
+    let genesis = mine(vec![coinbase], Hash256::ZERO, 0)?;
+    let bc = Blockchain::create_with_genesis(&config, genesis, Compression::None)?;
+    let utxo = UTXOSet { blockchain: bc.clone() };
+    utxo.reindex()?;
+    for height in 1..=blocks {
+        let coinbase = Transaction::new_coinbase(miner.clone(), format!("height {}", height))?;
+        let block = mine(vec![coinbase], bc.get_tip(), height)?;
+        bc.connect_block(&block, utxo.update_ops(&block)?)?;
+    }
+
+    let tip = bc.get_tip();
+    bc.flush()?;
+    Ok(tip)
+}
+
+/// mine seals a block at the pow limit with the timestamp of its height and
+/// the lowest nonce solving it, so the same inputs always give the same block
+fn mine(transactions: Vec<Transaction>, prev_block_hash: Hash256, height: usize) -> Result<Block> {
+    seal_at(transactions, prev_block_hash, height, GENESIS_TIME + height as u128 * TARGET_SPACING)
+}
+
+/// seal_at seals a block at the pow limit timed at `timestamp`, in
+/// milliseconds, with the lowest nonce solving it
+pub fn seal_at(transactions: Vec<Transaction>, prev_block_hash: Hash256, height: usize, timestamp: u128) -> Result<Block> {
+    let target = Target::from_compact(POW_LIMIT_BITS)?;
+    let prefix = Block::header_prefix(&prev_block_hash, &Block::merkle_root(&transactions)?, timestamp, POW_LIMIT_BITS)?;
+    let nonce = (0..=i32::MAX)
+        .into_par_iter()
+        .find_first(|nonce| target.is_met_by(&pow_hash(&prefix, *nonce)))
+        .ok_or_else(|| BlockchainError::Consensus(format!("no nonce solves block at height {}", height)))?;
+    Block::from_solution(transactions, prev_block_hash, height, POW_LIMIT_BITS, timestamp, nonce)
+}
+
+fn fixture_config(datadir: &Path) -> Config {
+    Config {
+        datadir: datadir.display().to_string(),
+        network: Network::Regtest,
+        ..Config::default()
+    }
+}
+
+/// temp_datadir is a directory of the system temp dir no other fixture uses
+fn temp_datadir(kind: &str) -> PathBuf {
+    let n = RESTORES.fetch_add(1, Ordering::Relaxed);
+    let datadir = std::env::temp_dir().join(format!("blockchain-fixture-{}-{}-{}", kind, std::process::id(), n));
+    let _ = fs::remove_dir_all(&datadir);
+    datadir
+}
+
+/// read_files collects every file under `dir` with its path relative to `root`
+fn read_files(root: &Path, dir: &Path, files: &mut Vec<(PathBuf, Vec<u8>)>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            read_files(root, &path, files)?;
+        } else if let Ok(relative) = path.strip_prefix(root) {
+            files.push((relative.to_path_buf(), fs::read(&path)?));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::amount::Amount;
+
+    #[test]
+    fn test_restores_are_identical_and_fast() -> Result<()> {
+        let first = ChainFixture::restore(5)?;
+        let started = Instant::now();
+        let second = ChainFixture::restore(5)?;
+        assert!(started.elapsed().as_millis() < 1000, "restore took {:?}", started.elapsed());
+        assert_ne!(first.datadir(), second.datadir());
+        assert_eq!(first.tip(), second.tip());
+
+        let bc = second.blockchain()?;
+        assert_eq!(bc.get_tip(), second.tip());
+        assert_eq!(bc.get_best_height()?, 5);
+        drop(bc);
+
+        let node = second.node_builder().build()?;
+        assert_eq!(node.balance(second.miner())?, "6".parse::<Amount>()?);
+        node.mine_blocks(1)?;
+        assert_ne!(node.server().utxo_set().blockchain.get_tip(), first.tip());
+        node.shutdown()?;
+
+        let datadir = first.datadir().to_path_buf();
+        drop(first);
+        assert!(!datadir.exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+//! Networks of local nodes for end-to-end tests, built with the `test-support`
+//! feature.
+//!
+//! `TestNetwork::spawn(n, blocks)` starts `n` nodes in this process, each on a
+//! random port with its own restore of the same fixture chain, so they share
+//! the genesis and start in agreement. Tests then connect them, mine on some
+//! and wait for the others to follow:
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use blockchain_project::testkit::TestNetwork;
+//!
+//! let network = TestNetwork::spawn(3, 2)?;
+//! network.connect_all()?;
+//! let mined = network.node(0).mine_blocks(1)?;
+//! assert_eq!(network.converge(Duration::from_secs(10))?, mined[0]);
+//! network.shutdown()?;
+//! # Ok::<(), blockchain_project::error::BlockchainError>(())
+//! ```
+
+use std::net::TcpListener;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::{BlockchainError, Result};
+use crate::hash::Hash256;
+use crate::node::Node;
+use crate::testing::ChainFixture;
+
+/// POLL is how often `wait_for` checks its condition
+const POLL: Duration = Duration::from_millis(50);
+
+/// TestNetwork is a set of listening nodes, each removed with its data
+/// directory on shutdown
+pub struct TestNetwork {
+    /// each node with the fixture holding its data directory, which must
+    /// outlive the node
+    nodes: Vec<(Node, ChainFixture)>
+}
+
+impl TestNetwork {
+    /// spawn starts `count` unconnected nodes on the fixture chain of `blocks`
+    /// blocks, all mining to the fixture miner
+    pub fn spawn(count: usize, blocks: usize) -> Result<TestNetwork> {
+        let mut nodes = Vec::with_capacity(count);
+        for _ in 0..count {
+            let fixture = ChainFixture::restore(blocks)?;
+            let node = fixture.node_builder().port(&free_port()?.to_string()).listen(true).build()?;
+            nodes.push((node, fixture));
+        }
+        Ok(TestNetwork { nodes })
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// node is the node at `index`, in spawn order
+    pub fn node(&self, index: usize) -> &Node {
+        &self.nodes[index].0
+    }
+
+    /// address is the P2P address of the node at `index`
+    pub fn address(&self, index: usize) -> String {
+        format!("localhost:{}", self.node(index).config().port)
+    }
+
+    /// connect makes nodes `a` and `b` peers, the one behind syncs from the other
+    pub fn connect(&self, a: usize, b: usize) -> Result<()> {
+        self.node(b).server().connect_peer(&self.address(a))?;
+        self.node(a).server().connect_peer(&self.address(b))
+    }
+
+    /// connect_all connects every pair of nodes
+    pub fn connect_all(&self) -> Result<()> {
+        for a in 0..self.len() {
+            for b in a + 1..self.len() {
+                self.connect(a, b)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// disconnect makes nodes `a` and `b` forget each other, splitting the
+    /// network when they were the only link
+    pub fn disconnect(&self, a: usize, b: usize) {
+        self.node(a).server().remove_node(&self.address(b));
+        self.node(b).server().remove_node(&self.address(a));
+    }
+
+    /// tips is the best block hash of every node
+    pub fn tips(&self) -> Vec<Hash256> {
+        self.nodes.iter().map(|(node, _)| node.best_block_hash()).collect()
+    }
+
+    /// wait_for polls `done` until it holds, failing after `timeout`
+    pub fn wait_for(&self, timeout: Duration, what: &str, done: impl Fn(&TestNetwork) -> bool) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        while !done(self) {
+            if Instant::now() >= deadline {
+                return Err(BlockchainError::Network(format!("timed out after {:?} waiting for {}, tips {:?}", timeout, what, self.tips())));
+            }
+            thread::sleep(POLL);
+        }
+        Ok(())
+    }
+
+    /// converge waits until every node has the same tip and returns it
+    pub fn converge(&self, timeout: Duration) -> Result<Hash256> {
+        let all: Vec<usize> = (0..self.len()).collect();
+        self.agree(&all, timeout)
+    }
+
+    /// agree waits until the nodes at `indexes` have the same tip and returns it
+    pub fn agree(&self, indexes: &[usize], timeout: Duration) -> Result<Hash256> {
+        self.wait_for(timeout, &format!("nodes {:?} to agree on a tip", indexes), |network| {
+            let tip = network.node(indexes[0]).best_block_hash();
+            indexes.iter().all(|&i| network.node(i).best_block_hash() == tip)
+        })?;
+        Ok(self.node(indexes[0]).best_block_hash())
+    }
+
+    /// shutdown stops every node and removes the data directories
+    pub fn shutdown(self) -> Result<()> {
+        for (node, fixture) in self.nodes {
+            node.shutdown()?;
+            drop(fixture);
+        }
+        Ok(())
+    }
+}
+
+/// free_port is a TCP port nothing listens on right now
+fn free_port() -> Result<u16> {
+    Ok(TcpListener::bind("localhost:0")?.local_addr()?.port())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::USER_AGENT;
+
+    const TIMEOUT: Duration = Duration::from_secs(20);
+
+    #[test]
+    fn test_blocks_relay_and_the_longer_chain_wins() -> Result<()> {
+        let network = TestNetwork::spawn(3, 2)?;
+        network.connect_all()?;
+        network.wait_for(TIMEOUT, "the handshakes", |n| n.node(0).server().peer_software().get(USER_AGENT) == Some(&2))?;
+        let mined = network.node(0).mine_blocks(1)?;
+        assert_eq!(network.converge(TIMEOUT)?, mined[0]);
+
+        // split off node 2, which then outmines the others
+        network.disconnect(0, 2);
+        network.disconnect(1, 2);
+        network.node(0).mine_blocks(1)?;
+        network.wait_for(TIMEOUT, "node 1 to follow node 0", |n| n.tips()[1] == n.tips()[0])?;
+        let longer = network.node(2).mine_blocks(2)?;
+        assert_ne!(network.tips()[0], longer[1]);
+
+        // received blocks are not relayed, so node 2 has to reach both
+        network.connect(0, 2)?;
+        network.connect(1, 2)?;
+        assert_eq!(network.converge(TIMEOUT)?, longer[1]);
+        assert_eq!(network.node(1).best_height()?, 5);
+
+        // node 1 counted what node 2 sent it, before and after compression
+        let peers = network.node(1).server().peer_info();
+        let peer = peers.iter().find(|p| p["addr"] == network.address(2)).expect("node 2 is known");
+        let received = peer["bytesrecv"].as_u64().unwrap_or_default();
+        assert!(received > 0);
+        assert!(peer["bytesrecv_uncompressed"].as_u64().unwrap_or_default() >= received);
+        network.shutdown()
+    }
+}
@@ -0,0 +1,361 @@
+//! A colored coins style token layer, showing how an asset protocol sits on
+//! top of a UTXO chain without the chain knowing about it.
+//!
+//! Token records travel in data outputs. An issue record creates a token whose
+//! id is the txid of the issuing transaction and credits its whole supply to
+//! output 0. A send record moves the tokens riding on the inputs of its
+//! transaction to the outputs it lists, and the tokens of the inputs it does
+//! not assign, or of a send assigning more than the inputs carry, are burned.
+//! Only the token index gives these records a meaning: a node that knows
+//! nothing of tokens sees payments of a few sats and data it ignores.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::amount::Amount;
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::error::{BlockchainError, Result};
+use crate::hash::Hash256;
+use crate::intent::IntentOp;
+use crate::transaction::Transaction;
+use crate::names::reserved_outputs;
+use crate::tx::{OutPoint, TXOutput};
+use crate::utxoset::UTXOSet;
+use crate::wallet::{hash_pub_key, Wallets};
+
+/// TOKEN_TREE maps a token id to its TokenInfo
+pub const TOKEN_TREE: &str = "tokens";
+
+/// TOKEN_OUTPUTS_TREE maps pkh(20) || txid(32) || vout(4, BE) to the token and
+/// amount riding on that unspent output, so the tokens of an address are a
+/// prefix scan like its coins
+pub const TOKEN_OUTPUTS_TREE: &str = "token_outputs";
+
+/// TOKEN_TIP_TREE holds the hash of the last block the index covers
+const TOKEN_TIP_TREE: &str = "tokens_tip";
+const TIP_KEY: &[u8] = b"TIP";
+
+/// MAGIC starts the data of every token record
+const MAGIC: &[u8] = b"TKN1";
+
+/// CARRIER_VALUE is the coin value of an output carrying tokens
+pub const CARRIER_VALUE: Amount = Amount::from_sat(1);
+
+/// MAX_TICKER_LEN caps the length of a ticker
+pub const MAX_TICKER_LEN: usize = 8;
+
+/// TokenRecord is the payload of a token data output
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum TokenRecord {
+    /// creates `supply` units of a token, all on output 0
+    Issue { ticker: String, supply: u64 },
+    /// moves `token` from the inputs to the (vout, amount) outputs
+    Send { token: Hash256, outputs: Vec<(u32, u64)> }
+}
+
+impl TokenRecord {
+    /// encode is the data output carrying the record
+    pub fn encode(&self) -> Result<TXOutput> {
+        let mut data = MAGIC.to_vec();
+        data.extend(bincode::serialize(self)?);
+        TXOutput::new_data(&data)
+    }
+
+    /// decode reads the first token record among the data outputs of `tx`
+    pub fn decode(tx: &Transaction) -> Option<TokenRecord> {
+        tx.vout
+            .iter()
+            .filter_map(TXOutput::data)
+            .find_map(|data| bincode::deserialize(data.strip_prefix(MAGIC)?).ok())
+    }
+}
+
+/// TokenInfo describes an issued token
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TokenInfo {
+    pub ticker: String,
+    pub supply: u64,
+    pub height: usize
+}
+
+/// TokenBalance is what an address holds of one token
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TokenBalance {
+    pub token: Hash256,
+    pub ticker: String,
+    pub amount: u64
+}
+
+/// TokenIndex applies the token records of the best chain, block by block. It
+/// catches up with the chain when synced and starts over when blocks it
+/// applied left the best chain, since a burn cannot be undone from the records
+#[derive(Debug, Clone)]
+pub struct TokenIndex {
+    bc: Blockchain,
+    /// held while the index moves, so two syncs never interleave
+    syncing: Arc<Mutex<()>>
+}
+
+impl TokenIndex {
+    /// new opens the token index of `bc` as it is, call sync to bring it up to the tip
+    pub fn new(bc: &Blockchain) -> TokenIndex {
+        TokenIndex {
+            bc: bc.clone(),
+            syncing: Arc::new(Mutex::new(()))
+        }
+    }
+
+    /// sync applies the best chain blocks not indexed yet
+    pub fn sync(&self) -> Result<()> {
+        let _syncing = self.syncing.lock().unwrap_or_else(PoisonError::into_inner);
+        let (disconnected, mut connected) = self.bc.path_from(self.tip()?)?;
+        if !disconnected.is_empty() {
+            info!("{} indexed blocks left the best chain, indexing the tokens again", disconnected.len());
+            self.clear()?;
+            connected = self.bc.path_from(None)?.1;
+        }
+
+        for header in &connected {
+            let block = self.bc.get_block(&header.hash)?;
+            let mut ops = self.apply(&block)?;
+            ops.push(IntentOp::insert(TOKEN_TIP_TREE, TIP_KEY, header.hash.as_bytes().to_vec()));
+            self.bc.intents().commit(&ops)?;
+        }
+        Ok(())
+    }
+
+    /// token is the description of the token `id`, None when never issued
+    pub fn token(&self, id: &Hash256) -> Result<Option<TokenInfo>> {
+        match self.bc.open_tree(TOKEN_TREE)?.get(id.as_bytes())? {
+            Some(info) => Ok(Some(bincode::deserialize(&info)?)),
+            None => Ok(None)
+        }
+    }
+
+    /// outputs lists the unspent outputs of `pub_key_hash` carrying tokens, with
+    /// the token and amount on each
+    pub fn outputs(&self, pub_key_hash: &[u8]) -> Result<Vec<(OutPoint, Hash256, u64)>> {
+        let mut outputs = Vec::new();
+        for item in self.bc.open_tree(TOKEN_OUTPUTS_TREE)?.scan_prefix(pub_key_hash) {
+            let (key, value) = item?;
+            if key.len() != pub_key_hash.len() + 32 + 4 {
+                continue;
+            }
+            let (txid, vout) = key[pub_key_hash.len()..].split_at(32);
+            let outpoint = OutPoint::new(Hash256::from_slice(txid)?, u32::from_be_bytes(vout.try_into().unwrap_or_default()));
+            let (token, amount): (Hash256, u64) = bincode::deserialize(&value)?;
+            outputs.push((outpoint, token, amount));
+        }
+        Ok(outputs)
+    }
+
+    /// carriers is the set of unspent outputs of `pub_key_hash` carrying tokens,
+    /// which a plain payment must not spend or their tokens burn
+    pub fn carriers(&self, pub_key_hash: &[u8]) -> Result<HashSet<OutPoint>> {
+        Ok(self.outputs(pub_key_hash)?.into_iter().map(|(outpoint, _, _)| outpoint).collect())
+    }
+
+    /// balances sums the tokens of `pub_key_hash` by token
+    pub fn balances(&self, pub_key_hash: &[u8]) -> Result<Vec<TokenBalance>> {
+        let mut amounts: HashMap<Hash256, u64> = HashMap::new();
+        for (_, token, amount) in self.outputs(pub_key_hash)? {
+            *amounts.entry(token).or_default() += amount;
+        }
+
+        let mut balances = Vec::new();
+        for (token, amount) in amounts {
+            let ticker = self.token(&token)?.map(|info| info.ticker).unwrap_or_default();
+            balances.push(TokenBalance { token, ticker, amount });
+        }
+        balances.sort_by(|a, b| a.ticker.cmp(&b.ticker).then(a.token.cmp(&b.token)));
+        Ok(balances)
+    }
+
+    /// issue_tx builds a transaction of `from` issuing `supply` units of a token
+    /// named `ticker` to itself, its txid becomes the token id
+    pub fn issue_tx(&self, wallets: &Wallets, from: &str, ticker: &str, supply: u64, fee: Amount) -> Result<Transaction> {
+        if !valid_ticker(ticker) {
+            return Err(BlockchainError::Wallet(format!("a ticker is 1 to {} letters or digits, not '{}'", MAX_TICKER_LEN, ticker)));
+        }
+        if supply == 0 {
+            return Err(BlockchainError::Wallet("a token needs a supply".to_string()));
+        }
+
+        let record = TokenRecord::Issue { ticker: ticker.to_string(), supply };
+        self.build_tx(wallets, from, Vec::new(), vec![TXOutput::new(CARRIER_VALUE, from.to_string())?, record.encode()?], fee)
+    }
+
+    /// send_tx builds a transaction moving `amount` units of `token` from `from`
+    /// to `to`, the rest of the tokens spent goes back to `from`
+    pub fn send_tx(&self, wallets: &Wallets, from: &str, to: &str, token: &Hash256, amount: u64, fee: Amount) -> Result<Transaction> {
+        let pub_key_hash = wallet_pub_key_hash(wallets, from)?;
+        let mut carried = 0;
+        let mut spent = Vec::new();
+        for (outpoint, carried_token, carried_amount) in self.outputs(&pub_key_hash)? {
+            if carried >= amount {
+                break;
+            }
+            if carried_token == *token {
+                carried += carried_amount;
+                spent.push(outpoint);
+            }
+        }
+        if amount == 0 || carried < amount {
+            return Err(BlockchainError::Wallet(format!("{} holds {} of token {}, not {}", from, carried, token, amount)));
+        }
+
+        let mut vout = vec![TXOutput::new(CARRIER_VALUE, to.to_string())?];
+        let mut assigned = vec![(0, amount)];
+        if carried > amount {
+            vout.push(TXOutput::new(CARRIER_VALUE, from.to_string())?);
+            assigned.push((1, carried - amount));
+        }
+        vout.push(TokenRecord::Send { token: *token, outputs: assigned }.encode()?);
+        self.build_tx(wallets, from, spent, vout, fee)
+    }
+
+    /// build_tx signs a transaction of `from` spending the token outputs `carriers`
+    /// and paying `vout`, adding plain coins of `from` for the rest of the value
+    /// and the fee
+    fn build_tx(&self, wallets: &Wallets, from: &str, carriers: Vec<OutPoint>, vout: Vec<TXOutput>, fee: Amount) -> Result<Transaction> {
+        let reserved = reserved_outputs(&self.bc, &wallet_pub_key_hash(wallets, from)?)?;
+        let carriers = carriers.into_iter().map(|outpoint| (outpoint, CARRIER_VALUE)).collect();
+        let utxo = UTXOSet { blockchain: self.bc.clone() };
+        Transaction::new_with_outputs(wallets, from, carriers, vout, fee, &utxo, |outpoint| !reserved.contains(outpoint))
+    }
+
+    /// tip is the last indexed block, None before the genesis is
+    fn tip(&self) -> Result<Option<Hash256>> {
+        match self.bc.open_tree(TOKEN_TIP_TREE)?.get(TIP_KEY)? {
+            Some(hash) => Ok(Some(Hash256::from_slice(&hash)?)),
+            None => Ok(None)
+        }
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.bc.open_tree(TOKEN_TIP_TREE)?.clear()?;
+        self.bc.open_tree(TOKEN_TREE)?.clear()?;
+        self.bc.open_tree(TOKEN_OUTPUTS_TREE)?.clear()?;
+        Ok(())
+    }
+
+    /// apply plans the index writes of the token records of `block`
+    fn apply(&self, block: &Block) -> Result<Vec<IntentOp>> {
+        let outputs = self.bc.open_tree(TOKEN_OUTPUTS_TREE)?;
+        // outputs created earlier in the block, not in the tree yet
+        let mut created: HashMap<Vec<u8>, (Hash256, u64)> = HashMap::new();
+        let mut ops = Vec::new();
+
+        for tx in block.get_transactions() {
+            if tx.is_coinbase() {
+                continue;
+            }
+            let mut inputs: HashMap<Hash256, u64> = HashMap::new();
+            for vin in &tx.vin {
+                let mut pub_key_hash = vin.pub_key.clone();
+                hash_pub_key(&mut pub_key_hash);
+                let key = output_key(&pub_key_hash, &vin.prev_out);
+                let carried = match created.remove(&key) {
+                    Some(carried) => Some(carried),
+                    None => outputs.get(&key)?.map(|value| bincode::deserialize(&value)).transpose()?
+                };
+                if let Some((token, amount)) = carried {
+                    *inputs.entry(token).or_default() += amount;
+                    ops.push(IntentOp::remove(TOKEN_OUTPUTS_TREE, &key));
+                }
+            }
+
+            let credits = match TokenRecord::decode(tx) {
+                Some(TokenRecord::Issue { ticker, supply }) if valid_ticker(&ticker) && supply > 0 => {
+                    let info = TokenInfo { ticker, supply, height: block.get_height() };
+                    ops.push(IntentOp::insert(TOKEN_TREE, tx.id.as_bytes(), bincode::serialize(&info)?));
+                    vec![(0, tx.id, supply)]
+                },
+                Some(TokenRecord::Send { token, outputs }) => {
+                    let assigned = outputs.iter().try_fold(0u64, |sum, (_, amount)| sum.checked_add(*amount));
+                    if assigned.is_some_and(|assigned| assigned <= inputs.get(&token).copied().unwrap_or(0)) {
+                        outputs.into_iter().map(|(vout, amount)| (vout, token, amount)).collect()
+                    } else {
+                        Vec::new()
+                    }
+                },
+                _ => Vec::new()
+            };
+
+            for (vout, token, amount) in credits {
+                match tx.vout.get(vout as usize) {
+                    Some(out) if !out.is_data() && amount > 0 => {
+                        let key = output_key(&out.pub_key_hash, &tx.outpoint(vout as usize));
+                        ops.push(IntentOp::insert(TOKEN_OUTPUTS_TREE, &key, bincode::serialize(&(token, amount))?));
+                        created.insert(key, (token, amount));
+                    },
+                    _ => {}
+                }
+            }
+        }
+        Ok(ops)
+    }
+}
+
+fn wallet_pub_key_hash(wallets: &Wallets, address: &str) -> Result<Vec<u8>> {
+    let wallet = wallets.get_wallet(address).ok_or_else(|| BlockchainError::Wallet(format!("wallet {} not found", address)))?;
+    let mut pub_key_hash = wallet.public_key.clone();
+    hash_pub_key(&mut pub_key_hash);
+    Ok(pub_key_hash)
+}
+
+fn valid_ticker(ticker: &str) -> bool {
+    !ticker.is_empty() && ticker.len() <= MAX_TICKER_LEN && ticker.bytes().all(|b| b.is_ascii_alphanumeric())
+}
+
+/// output_key is the TOKEN_OUTPUTS_TREE key of `outpoint`
+fn output_key(pub_key_hash: &[u8], outpoint: &OutPoint) -> Vec<u8> {
+    let mut key = pub_key_hash.to_vec();
+    key.extend_from_slice(outpoint.txid.as_bytes());
+    key.extend_from_slice(&outpoint.index.to_be_bytes());
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ChainFixture;
+
+    #[test]
+    fn test_tokens_move_with_their_outputs() -> Result<()> {
+        let fixture = ChainFixture::restore(3)?;
+        let utxo = UTXOSet { blockchain: fixture.blockchain()? };
+        let tokens = TokenIndex::new(&utxo.blockchain);
+        let mut wallets = Wallets::new(&fixture.config())?;
+        let miner = fixture.miner().to_string();
+        let student = wallets.create_wallet();
+        let mine = |tx: Transaction| -> Result<()> {
+            let coinbase = Transaction::new_coinbase(miner.clone(), format!("tokens {}", tx.id))?;
+            utxo.connect_block(&utxo.blockchain.mine_block(vec![tx, coinbase])?)?;
+            tokens.sync()
+        };
+
+        let issue = tokens.issue_tx(&wallets, &miner, "GOLD", 1000, Amount::ZERO)?;
+        let token = issue.id;
+        mine(issue)?;
+        let miner_hash = wallet_pub_key_hash(&wallets, &miner)?;
+        assert_eq!(tokens.balances(&miner_hash)?, vec![TokenBalance { token, ticker: "GOLD".to_string(), amount: 1000 }]);
+
+        mine(tokens.send_tx(&wallets, &miner, &student, &token, 300, Amount::ZERO)?)?;
+        assert_eq!(tokens.balances(&miner_hash)?[0].amount, 700);
+        assert_eq!(tokens.balances(&wallet_pub_key_hash(&wallets, &student)?)?[0].amount, 300);
+        assert!(tokens.send_tx(&wallets, &miner, &student, &token, 701, Amount::ZERO).is_err());
+
+        // a plain payment leaves the token outputs alone
+        let carriers = tokens.carriers(&miner_hash)?;
+        let payment = Transaction::new_utxo_where(&wallets, &miner, &student, "1".parse()?, Amount::ZERO, &utxo, |outpoint| !carriers.contains(outpoint))?;
+        mine(payment)?;
+        assert_eq!(tokens.balances(&miner_hash)?[0].amount, 700);
+        assert_eq!(tokens.token(&token)?.map(|info| info.supply), Some(1000));
+        Ok(())
+    }
+}
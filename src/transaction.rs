@@ -2,19 +2,27 @@
 use std::collections::HashMap;
 
 use crypto::ed25519;
-use crypto::{digest::Digest, sha2::Sha256};
-use failure::format_err;
-use log::error;
+use tracing::error;
 use serde::{Deserialize, Serialize};
-use crate::tx::TXInput;
+use crate::amount::Amount;
+use crate::error::{BlockchainError, Result};
+use crate::hash::Hash256;
+use crate::tx::{OutPoint, TXInput};
 use crate::tx::TXOutput;
 use crate::utxoset::UTXOSet;
 use crate::wallet::{hash_pub_key, Wallets};
-use crate::{blockchain::Blockchain, error::Result};
 
+/// SUBSIDY is the reward of the coinbase transaction of every block
+pub const SUBSIDY: Amount = Amount::COIN;
+
+/// PUBLIC_KEY_LEN and SIGNATURE_LEN are the sizes of an ed25519 public key and signature
+const PUBLIC_KEY_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+
+/// Transaction moves value from the outputs its inputs spend to new outputs
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
-    pub id: String,
+    pub id: Hash256,
     pub vin: Vec<TXInput>,
     pub vout: Vec<TXOutput>
 }
@@ -24,95 +32,103 @@ pub struct Transaction {
 impl Transaction {
 
    
-    /// New UTXO creates a new transaction
-    pub fn new_UTXO(from: &str, to: &str, amount: i32, bc: &UTXOSet) -> Result<Transaction> {
-        let mut vin = Vec::new();
+    /// New UTXO creates a new transaction paying `amount` to `to` plus `fee` to the miner
+    pub fn new_UTXO(wallets: &Wallets, from: &str, to: &str, amount: Amount, fee: Amount, bc: &UTXOSet) -> Result<Transaction> {
+        Transaction::new_utxo_where(wallets, from, to, amount, fee, bc, |_| true)
+    }
 
-        // Buscando Wallets
-        let wallets = Wallets::new()?;
-        
+    /// new_utxo_where is new_UTXO spending only the outputs `usable` accepts, so
+    /// a sender can leave out what its unconfirmed transactions already spend
+    pub fn new_utxo_where(wallets: &Wallets, from: &str, to: &str, amount: Amount, fee: Amount, bc: &UTXOSet, usable: impl Fn(&OutPoint) -> bool) -> Result<Transaction> {
+        Transaction::new_with_outputs(wallets, from, Vec::new(), vec![TXOutput::new(amount, to.to_string())?], fee, bc, usable)
+    }
+
+    /// new_with_outputs signs a transaction of `from` paying `vout` plus `fee`. It
+    /// spends the outputs of `from` in `spent`, given with their values, and then
+    /// the outputs of `from` that `usable` accepts until the value is covered,
+    /// paying the change back to `from`
+    pub fn new_with_outputs(wallets: &Wallets, from: &str, spent: Vec<(OutPoint, Amount)>, vout: Vec<TXOutput>, fee: Amount, bc: &UTXOSet, usable: impl Fn(&OutPoint) -> bool) -> Result<Transaction> {
         // Verificando se o 'from' address existe
         let wallet = match wallets.get_wallet(from) {
             Some(w) => w,
-            None => return Err(format_err!("'from' wallet not found!")),
+            None => return Err(BlockchainError::Wallet(format!("'from' wallet {} not found", from))),
         };
 
-        // Verificando se o 'to' address existe
-        if let None = wallets.get_wallet(&to) {
-            return Err(format_err!("'to' wallet not found"));
-        };
+        let mut tx = Transaction::new_unsigned(&wallet.public_key, from, spent, vout, fee, bc, usable)?;
+        bc.blockchain.sign_transaction(&mut tx, &wallet.secret_key)?;
 
+        Ok(tx)
+    }
 
-        let mut pub_key_hash = wallet.public_key.clone();
+    /// new_unsigned builds the transaction new_with_outputs would for the key
+    /// `public_key` of `from`, leaving the inputs unsigned
+    pub fn new_unsigned(public_key: &[u8], from: &str, spent: Vec<(OutPoint, Amount)>, mut vout: Vec<TXOutput>, fee: Amount, bc: &UTXOSet, usable: impl Fn(&OutPoint) -> bool) -> Result<Transaction> {
+        let mut pub_key_hash = public_key.to_vec();
         hash_pub_key(&mut pub_key_hash);
 
-        let acc_v = bc.find_spendable_outputs(&pub_key_hash, amount)?;
-
+        let paid = Amount::sum(vout.iter().map(|out| out.value))?.try_add(fee)?;
+        let preset = Amount::sum(spent.iter().map(|(_, value)| *value))?;
+        let mut needed = paid.checked_sub(preset).unwrap_or(Amount::ZERO);
+        if spent.is_empty() && needed == Amount::ZERO {
+            // without inputs there would be nothing to sign and the txid would repeat
+            needed = Amount::from_sat(1);
+        }
+        let acc_v = bc.find_spendable_outputs_where(&pub_key_hash, needed, usable)?;
 
-        if acc_v.0 < amount {
+        if acc_v.0 < needed {
             error!("Not enough funds");
-            return Err(format_err!("Not Enough balance: current balance {}", acc_v.0));
+            return Err(BlockchainError::InsufficientFunds { available: acc_v.0, needed });
         }
 
-        for tx in acc_v.1 {
-            for out in tx.1 {
-                let input = TXInput {
-                    txid: tx.0.clone(),
-                    vout: out,
-                    signature: Vec::new(),
-                    pub_key: wallet.public_key.clone()
-                };
-                vin.push(input);
-            }
+        let change = acc_v.0.try_add(preset)?.try_sub(paid)?;
+        if change > Amount::ZERO {
+            vout.push(TXOutput::new(change, from.to_string())?);
         }
 
-        let mut vout = vec![
-            TXOutput::new(
-                amount,
-                to.to_string()
-            )?
-        ];
-
-        if acc_v.0 > amount {
-            vout.push(
-                TXOutput::new(
-                    acc_v.0 - amount,
-                    from.to_string()
-                )?
-            );
-        }
-
-
+        let vin = spent
+            .into_iter()
+            .map(|(prev_out, _)| prev_out)
+            .chain(acc_v.1)
+            .map(|prev_out| TXInput {
+                prev_out,
+                signature: Vec::new(),
+                pub_key: public_key.to_vec()
+            })
+            .collect();
 
         let mut tx = Transaction {
-            id: String::new(),
+            id: Hash256::ZERO,
             vin,
             vout
         };
 
         tx.id = tx.hash()?;
-        bc.blockchain.sign_transaction(&mut tx, &wallet.secret_key)?;
-
         Ok(tx)
     }
 
-    pub fn new_coinbase(to: String, mut data: String) -> Result<Transaction> {
+    /// new_coinbase creates the mining reward paid to `to`, `data` defaults to a reward note
+    pub fn new_coinbase(to: String, data: String) -> Result<Transaction> {
+        Transaction::new_coinbase_with_fees(to, data, Amount::ZERO)
+    }
+
+    /// new_coinbase_with_fees creates a coinbase paying the subsidy plus the `fees`
+    /// of the transactions it is mined with
+    pub fn new_coinbase_with_fees(to: String, mut data: String, fees: Amount) -> Result<Transaction> {
 
         if data == String::from("") {
             data += &format!("Reward to '{}'", to);
         }
 
         let mut tx = Transaction {
-            id: String::new(),
+            id: Hash256::ZERO,
             vin: vec![TXInput {
-                txid: String::new(),
-                vout: -1,
+                prev_out: OutPoint::NULL,
                 signature: Vec::new(),
                 pub_key: Vec::from(data.as_bytes())
             }],
             vout: vec![
                 TXOutput::new(
-                    100,
+                    SUBSIDY.try_add(fees)?,
                     to
                 )?
             ]
@@ -124,64 +140,92 @@ impl Transaction {
     }
 
     
+    /// is_coinbase tells whether the transaction is a mining reward without real inputs
     pub fn is_coinbase(&self) -> bool {
-        self.vin.len() == 1 && self.vin[0].txid.is_empty() && self.vin[0].vout == -1
+        self.vin.len() == 1 && self.vin[0].prev_out.is_null()
+    }
+
+    /// outpoint references output `index` of this transaction
+    pub fn outpoint(&self, index: usize) -> OutPoint {
+        OutPoint::new(self.id, index as u32)
+    }
+
+    /// pub_key_hashes lists the hashes the transaction spends from and pays to,
+    /// duplicates included
+    pub fn pub_key_hashes(&self) -> Vec<Vec<u8>> {
+        let mut hashes: Vec<Vec<u8>> = self.vout.iter().map(|out| out.pub_key_hash.clone()).collect();
+        if !self.is_coinbase() {
+            for vin in &self.vin {
+                let mut input_hash = vin.pub_key.clone();
+                hash_pub_key(&mut input_hash);
+                hashes.push(input_hash);
+            }
+        }
+        hashes
     }
 
 
-    pub fn sign(&mut self, private_key: &[u8], prev_TXs: HashMap<String, Transaction>) -> Result<()> {
+    /// sign signs every input with `private_key`, `prev_TXs` holds the transactions they spend
+    pub fn sign(&mut self, private_key: &[u8], prev_TXs: HashMap<Hash256, Transaction>) -> Result<()> {
         if self.is_coinbase() {
             return Ok(())
         }
         
         for vin in &self.vin {
-            if prev_TXs.get(&vin.txid).unwrap().id.is_empty() {
-                return Err(format_err!("ERROR: Previous transaction is not correct!"));
+            if prev_tx(&prev_TXs, &vin.prev_out)?.id.is_zero() {
+                return Err(BlockchainError::Consensus(format!("previous transaction {} is not correct", vin.prev_out.txid)));
             }
         }
 
         let mut tx_copy = self.trim_copy();
 
         for in_id in 0..tx_copy.vin.len() {
-            let prev_Tx = prev_TXs.get(&tx_copy.vin[in_id].txid).unwrap();
+            let prev_out = tx_copy.vin[in_id].prev_out;
             tx_copy.vin[in_id].signature.clear();
-            tx_copy.vin[in_id].pub_key = prev_Tx.vout[tx_copy.vin[in_id].vout as usize]
+            tx_copy.vin[in_id].pub_key = prev_output(&prev_TXs, &prev_out)?
                 .pub_key_hash
                 .clone();
             tx_copy.id = tx_copy.hash()?;
             tx_copy.vin[in_id].pub_key = Vec::new();
-            let signature = ed25519::signature(tx_copy.id.as_bytes(), private_key);
+            // the hex of the id is signed, as it was while ids were hex strings
+            let signature = ed25519::signature(tx_copy.id.to_string().as_bytes(), private_key);
             self.vin[in_id].signature = signature.to_vec();
         }
 
         Ok(())
     }
 
-    pub fn verify(&mut self, prev_TXs: HashMap<String, Transaction>) -> Result<bool> {
+    /// verify checks every input signature against the outputs in `prev_TXs`
+    pub fn verify(&mut self, prev_TXs: HashMap<Hash256, Transaction>) -> Result<bool> {
         if self.is_coinbase() {
             return Ok(true);
         }
 
 
         for vin in &self.vin {
-            if prev_TXs.get(&vin.txid).unwrap().id.is_empty() {
-                return Err(format_err!("ERROR: Previos transaction is not correct!"));
+            if prev_tx(&prev_TXs, &vin.prev_out)?.id.is_zero() {
+                return Err(BlockchainError::Consensus(format!("previous transaction {} is not correct", vin.prev_out.txid)));
             }
         }
 
         let mut tx_copy = self.trim_copy();
 
         for in_id in 0..tx_copy.vin.len() {
-            let prev_Tx = prev_TXs.get(&tx_copy.vin[in_id].txid).unwrap();
+            let prev_out = tx_copy.vin[in_id].prev_out;
             tx_copy.vin[in_id].signature.clear();
-            tx_copy.vin[in_id].pub_key = prev_Tx.vout[tx_copy.vin[in_id].vout as usize]
+            tx_copy.vin[in_id].pub_key = prev_output(&prev_TXs, &prev_out)?
                 .pub_key_hash
                 .clone();
             tx_copy.id = tx_copy.hash()?;
             tx_copy.vin[in_id].pub_key = Vec::new();
 
+            // ed25519::verify slices the key and the signature without checking their lengths
+            let vin = &self.vin[in_id];
+            if vin.pub_key.len() != PUBLIC_KEY_LEN || vin.signature.len() != SIGNATURE_LEN {
+                return Ok(false);
+            }
             if !ed25519::verify(
-                &tx_copy.id.as_bytes(), 
+                tx_copy.id.to_string().as_bytes(),
                 &self.vin[in_id].pub_key, 
                 &self.vin[in_id].signature
             ) {
@@ -194,12 +238,12 @@ impl Transaction {
 
     }
 
-    pub fn hash(&mut self) -> Result<String> {
-        self.id = String::new();
-        let data = bincode::serialize(self)?;
-        let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
-        Ok(hasher.result_str())
+    /// hash computes the transaction id over everything but the id itself, which
+    /// is encoded as the empty string it was while ids were hex strings so the ids
+    /// of existing chains do not change
+    pub fn hash(&self) -> Result<Hash256> {
+        let data = bincode::serialize(&("", &self.vin, &self.vout))?;
+        Ok(Hash256::sha256(&data))
     }
 
     fn trim_copy(&self) -> Transaction {
@@ -209,8 +253,7 @@ impl Transaction {
         for v in &self.vin {
             vin.push(
                 TXInput {
-                    txid: v.txid.clone(),
-                    vout: v.vout.clone(),
+                    prev_out: v.prev_out,
                     signature: Vec::new(),
                     pub_key: Vec::new(),
                 }
@@ -226,7 +269,7 @@ impl Transaction {
         }
 
         Transaction {
-            id: self.id.clone(),
+            id: self.id,
             vin,
             vout
         }
@@ -235,4 +278,15 @@ impl Transaction {
 
 }
 
+/// prev_tx looks up the transaction an input spends among those given to sign or verify
+fn prev_tx<'a>(prev_txs: &'a HashMap<Hash256, Transaction>, prev_out: &OutPoint) -> Result<&'a Transaction> {
+    prev_txs.get(&prev_out.txid).ok_or_else(|| BlockchainError::TxNotFound(prev_out.txid.to_string()))
+}
 
+/// prev_output is the output `prev_out` spends, from the transactions in `prev_txs`
+fn prev_output<'a>(prev_txs: &'a HashMap<Hash256, Transaction>, prev_out: &OutPoint) -> Result<&'a TXOutput> {
+    prev_tx(prev_txs, prev_out)?
+        .vout
+        .get(prev_out.index as usize)
+        .ok_or_else(|| BlockchainError::Consensus(format!("input spends missing output {}", prev_out)))
+}
@@ -1,10 +1,23 @@
 
-use bitcoincash_addr::Address;
-use log::debug;
+use std::fmt;
+
+use tracing::debug;
 use serde::{Deserialize, Serialize};
 
+use crate::address;
+use crate::amount::Amount;
+use crate::hash::Hash256;
 use crate::wallet::hash_pub_key;
-use crate::error::Result;
+use crate::error::{BlockchainError, Result};
+
+/// OP_RETURN starts the locking bytes of a data output, like Bitcoin's opcode
+pub const OP_RETURN: u8 = 0x6a;
+
+/// MAX_DATA_LEN caps the bytes a data output carries
+pub const MAX_DATA_LEN: usize = 80;
+
+/// PUB_KEY_HASH_LEN is the length of the locking bytes of a payment output
+const PUB_KEY_HASH_LEN: usize = 20;
 
 // TXOutputs collects TXOutput
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -12,11 +25,40 @@ pub struct TXOutputs {
     pub outputs: Vec<TXOutput>
 }
 
+/// OutPoint references an output by the txid of its transaction and its index
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct OutPoint {
+    pub txid: Hash256,
+    pub index: u32
+}
+
+impl OutPoint {
+    /// NULL is what the input of a coinbase transaction spends
+    pub const NULL: OutPoint = OutPoint {
+        txid: Hash256::ZERO,
+        index: u32::MAX
+    };
+
+    /// new references output `index` of the transaction `txid`
+    pub fn new(txid: Hash256, index: u32) -> OutPoint {
+        OutPoint { txid, index }
+    }
+
+    pub fn is_null(&self) -> bool {
+        *self == OutPoint::NULL
+    }
+}
+
+impl fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.txid, self.index)
+    }
+}
+
 // TXInput represents a transaction input
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXInput {
-    pub txid: String,
-    pub vout: i32,
+    pub prev_out: OutPoint,
     pub signature: Vec<u8>,
     pub pub_key: Vec<u8>
 }
@@ -25,7 +67,7 @@ pub struct TXInput {
 // TXOutput represents a transaction output
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXOutput {
-    pub value: i32,
+    pub value: Amount,
     pub pub_key_hash: Vec<u8>
 }
 
@@ -45,13 +87,13 @@ impl TXOutput {
     }
 
     fn lock(&mut self, address: &str) -> Result<()> {
-        let pub_key_hash = Address::decode(address).unwrap().body;
+        let pub_key_hash = address::decode(address)?;
         debug!("lock: {}", address);
         self.pub_key_hash = pub_key_hash;
         Ok(())
     }
 
-    pub fn new(value: i32, address: String) -> Result<Self> {
+    pub fn new(value: Amount, address: String) -> Result<Self> {
         let mut txo = TXOutput {
             value,
             pub_key_hash: Vec::new(),
@@ -65,5 +107,71 @@ impl TXOutput {
         self.pub_key_hash == pub_key_hash
     }
 
+    /// new_data creates an unspendable output of no value carrying `data`, its
+    /// locking bytes are OP_RETURN || data, which no public key hash matches
+    pub fn new_data(data: &[u8]) -> Result<Self> {
+        if data.len() > MAX_DATA_LEN {
+            return Err(BlockchainError::Consensus(format!("data outputs carry at most {} bytes, not {}", MAX_DATA_LEN, data.len())));
+        }
+        if data.len() + 1 == PUB_KEY_HASH_LEN {
+            return Err(BlockchainError::Consensus(format!("{} bytes of data would read as a public key hash", data.len())));
+        }
+
+        let mut pub_key_hash = Vec::with_capacity(data.len() + 1);
+        pub_key_hash.push(OP_RETURN);
+        pub_key_hash.extend_from_slice(data);
+        Ok(TXOutput { value: Amount::ZERO, pub_key_hash })
+    }
+
+    /// is_data tells a data output from a payment, it stays out of the UTXO set
+    pub fn is_data(&self) -> bool {
+        self.pub_key_hash.len() != PUB_KEY_HASH_LEN && self.pub_key_hash.first() == Some(&OP_RETURN)
+    }
+
+    /// data is what a data output carries, None for a payment
+    pub fn data(&self) -> Option<&[u8]> {
+        if self.is_data() {
+            Some(&self.pub_key_hash[1..])
+        } else {
+            None
+        }
+    }
+
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outpoint_round_trip() -> Result<()> {
+        let txid = "6c".repeat(32);
+        let outpoint = OutPoint::new(Hash256::from_hex(&txid)?, 1);
+        assert_eq!(outpoint.txid.to_string(), txid);
+        assert_eq!(outpoint.to_string(), format!("{}:1", txid));
+        assert_eq!(bincode::serialize(&outpoint)?.len(), 36);
+        assert!(!outpoint.is_null());
+
+        assert!(Hash256::from_hex("6c6c").is_err());
+        assert!(Hash256::from_hex(&"zz".repeat(32)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_data_outputs_are_not_payments() -> Result<()> {
+        let out = TXOutput::new_data(b"hello")?;
+        assert!(out.is_data());
+        assert_eq!(out.data(), Some(&b"hello"[..]));
+        assert_eq!(out.value, Amount::ZERO);
+
+        // a public key hash starting with the opcode is still a payment
+        let mut payment = TXOutput { value: Amount::COIN, pub_key_hash: vec![OP_RETURN; PUB_KEY_HASH_LEN] };
+        assert!(!payment.is_data());
+        payment.pub_key_hash.truncate(3);
+        assert!(payment.is_data());
+        assert!(TXOutput::new_data(&[0; PUB_KEY_HASH_LEN - 1]).is_err());
+        assert!(TXOutput::new_data(&[0; MAX_DATA_LEN + 1]).is_err());
+        Ok(())
+    }
+}
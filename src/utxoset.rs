@@ -1,144 +1,359 @@
-use std::collections::HashMap;
+use std::collections::HashSet;
 
-use log::info;
+use tracing::{info, warn};
 
-use crate::block::Block;
+use crate::amount::Amount;
+use crate::block::{Block, BlockHeader};
 use crate::blockchain::Blockchain;
-use crate::error::Result;
-use crate::tx::TXOutputs;
-
-
-/// UTXOSet represents UTXO set
+use crate::error::{BlockchainError, Result};
+use crate::hash::Hash256;
+use crate::intent::IntentOp;
+use crate::miner;
+use crate::progress::Progress;
+use crate::tx::{OutPoint, TXOutput, TXOutputs};
+use crate::wallet::hash_pub_key;
+
+/// UTXO_TREE holds the unspent outputs keyed by pub_key_hash || txid || vout
+pub(crate) const UTXO_TREE: &str = "utxos";
+
+/// LEGACY_ADDR_TREE is the address index kept next to a txid keyed UTXO set
+/// before schema 7, dropped by the upgrade
+pub(crate) const LEGACY_ADDR_TREE: &str = "utxo_addr";
+
+/// UTXOSet represents UTXO set, stored in the `utxos` tree of the blockchain database
+/// keyed by the hash of the key the output is locked to, so the outputs of an
+/// address are a single prefix scan
+#[derive(Debug, Clone)]
 pub struct UTXOSet {
     pub blockchain: Blockchain
 }
 
+/// UnspentOutput is an entry of the UTXO set
+#[derive(Debug, Clone)]
+pub struct UnspentOutput {
+    pub outpoint: OutPoint,
+    pub value: Amount,
+    pub pub_key_hash: Vec<u8>,
+    pub height: usize
+}
+
 
 
 impl UTXOSet {
 
-    /// Reindex rebuilds the UTXO set
+    /// Reindex rebuilds the UTXO set by walking the chain from the tip, so every
+    /// spend is seen before the output it spends
     pub fn reindex(&self) -> Result<()> {
-        if let Err(e) = std::fs::remove_dir_all("data/utxos") {
-            info!("not exist any utxos to delete")
+        let db = self.blockchain.open_tree(UTXO_TREE)?;
+        db.clear()?;
+        info!("cleared utxo set");
+
+        let mut spent: HashSet<OutPoint> = HashSet::new();
+        let mut progress = Progress::new("index addresses", self.blockchain.get_best_height()? as u64 + 1);
+        for block in self.blockchain.iter() {
+            progress.inc(1);
+            for tx in block.get_transactions().iter().rev() {
+                for (idx, out) in tx.vout.iter().enumerate() {
+                    let outpoint = tx.outpoint(idx);
+                    if !out.is_data() && !spent.contains(&outpoint) {
+                        db.insert(utxo_key(&out.pub_key_hash, &outpoint), bincode::serialize(&(out.value, block.get_height()))?)?;
+                    }
+                }
+
+                if !tx.is_coinbase() {
+                    for vin in &tx.vin {
+                        spent.insert(vin.prev_out);
+                    }
+                }
+            }
         }
-        let db = sled::open("data/utxos")?;
+        progress.finish();
 
-        let utxos = self.blockchain.find_UTXO();
+        Ok(())
+    }
 
-        for (txid, outs) in utxos {
-            db.insert(txid.as_bytes(), bincode::serialize(&outs)?)?;
+    /// ListUnspent reads the UTXO set, for one address or for all of them
+    pub fn list_unspent(&self, pub_key_hash: Option<&[u8]>) -> Result<Vec<UnspentOutput>> {
+        let db = self.blockchain.open_tree(UTXO_TREE)?;
+        let iter = match pub_key_hash {
+            Some(pkh) => db.scan_prefix(pkh),
+            None => db.iter()
+        };
+
+        let mut unspent = Vec::new();
+        for kv in iter {
+            let (k, v) = kv?;
+            let (value, height): (Amount, usize) = bincode::deserialize(&v)?;
+            let (pkh, rest) = k.split_at(PUB_KEY_HASH_LEN);
+            let (txid, vout) = rest.split_at(rest.len() - 4);
+            let mut vout_bytes = [0; 4];
+            vout_bytes.copy_from_slice(vout);
+
+            unspent.push(UnspentOutput {
+                outpoint: OutPoint::new(Hash256::from_slice(txid)?, u32::from_be_bytes(vout_bytes)),
+                value,
+                pub_key_hash: pkh.to_vec(),
+                height
+            });
         }
+        Ok(unspent)
+    }
 
-        Ok(())
+    /// is_unspent tells whether `outpoint`, locked to `pub_key_hash`, is in the UTXO set
+    pub fn is_unspent(&self, pub_key_hash: &[u8], outpoint: &OutPoint) -> Result<bool> {
+        let db = self.blockchain.open_tree(UTXO_TREE)?;
+        Ok(db.contains_key(utxo_key(pub_key_hash, outpoint))?)
     }
 
+    /// find_spendable_outputs collects outputs of `address` until they cover `amount`,
+    /// returning their total and the outputs
+    pub fn find_spendable_outputs(&self, address: &[u8], amount: Amount) -> Result<(Amount, Vec<OutPoint>)> {
+        self.find_spendable_outputs_where(address, amount, |_| true)
+    }
 
-    pub fn find_spendable_outputs(&self, address: &[u8], amount: i32) -> Result<(i32, HashMap<String, Vec<i32>>)> {
-        let mut unspent_outputs: HashMap<String, Vec<i32>> = HashMap::new();
-        let mut accumulated = 0;
+    /// find_spendable_outputs_where is find_spendable_outputs skipping the
+    /// outputs `usable` rejects
+    pub fn find_spendable_outputs_where(&self, address: &[u8], amount: Amount, usable: impl Fn(&OutPoint) -> bool) -> Result<(Amount, Vec<OutPoint>)> {
+        let mut unspent_outputs = Vec::new();
+        let mut accumulated = Amount::ZERO;
 
-        let db = sled::open("data/utxos")?;
-        for kv in db.iter() {
-            let (k, v) = kv?;
-            let txid = String::from_utf8(k.to_vec())?;
-            let outs: TXOutputs = bincode::deserialize(&v.to_vec())?;
-
-            for out_idx in 0..outs.outputs.len() {
-                if outs.outputs[out_idx].is_locked_with_key(address) && accumulated < amount {
-                    accumulated += outs.outputs[out_idx].value;
-                    match unspent_outputs.get_mut(&txid) {
-                        Some(v) => v.push(out_idx as i32),
-                        None => {
-                            unspent_outputs.insert(txid.clone(), vec![out_idx as i32]);
-                        }
-                    }
-                }
+        for out in self.list_unspent(Some(address))?.into_iter().filter(|out| usable(&out.outpoint)) {
+            if accumulated >= amount {
+                break;
             }
-
+            accumulated = accumulated.try_add(out.value)?;
+            unspent_outputs.push(out.outpoint);
         }
 
-
         Ok((accumulated, unspent_outputs))
 
     }
 
 
+    /// find_UTXO returns every unspent output locked to `pub_key_hash`
     pub fn find_UTXO(&self, pub_key_hash: &[u8]) -> Result<TXOutputs> {
         let mut utxos = TXOutputs {
             outputs: Vec::new(),
         };
 
-        let db = sled::open("data/utxos")?;
-        for kv in db.iter() {
-            let (_, v) = kv?;
+        for out in self.list_unspent(Some(pub_key_hash))? {
+            utxos.outputs.push(TXOutput { value: out.value, pub_key_hash: out.pub_key_hash });
+        }
 
-            let outs: TXOutputs = bincode::deserialize(&v.to_vec())?;
+        Ok(utxos)
+    }
+ 
+    /// Update applies the outputs spent and created by the block to the UTXO set
+    pub fn update(&self, block: &Block) -> Result<()> {
+        let ops = self.update_ops(block)?;
+        self.blockchain.intents().commit(&ops)
+    }
 
-            for out in outs.outputs {
-                if out.can_be_unlock_with(pub_key_hash) {
-                    utxos.outputs.push(out.clone());
+    /// ConnectBlock stores the block as the new tip and updates the UTXO set in one intent
+    pub fn connect_block(&self, block: &Block) -> Result<()> {
+        let ops = self.update_ops(block)?;
+        self.blockchain.connect_block(block, ops)
+    }
+
+    /// ReceiveBlock stores a block received from a peer and, when it gives a
+    /// chain with more work, reorganizes to that chain
+    pub fn receive_block(&self, block: &Block) -> Result<()> {
+        let _connecting = self.blockchain.lock_connecting();
+        match self.blockchain.receive_block(block)? {
+            Some(tip) => self.reorganize(tip),
+            None => Ok(())
+        }
+    }
+
+    /// Reorganize moves the tip to `tip`, disconnecting the blocks down to the
+    /// fork point and connecting the new ones in order, each checked against
+    /// the UTXO set it spends from. A block failing its checks is marked invalid
+    /// with those after it and the old tip is connected back
+    fn reorganize(&self, tip: BlockHeader) -> Result<()> {
+        let old_tip = self.blockchain.get_tip();
+        let (disconnected, connected) = self.blockchain.path(Some(old_tip), tip)?;
+        for (index, header) in connected.iter().enumerate() {
+            if self.blockchain.is_invalid(&header.hash)? {
+                self.mark_invalid(&connected[index..])?;
+                return Err(BlockchainError::Consensus(format!("the branch to block {} holds the invalid block {}", connected[connected.len() - 1].hash, header.hash)));
+            }
+        }
+
+        for header in &disconnected {
+            self.disconnect_block(&self.blockchain.get_block(&header.hash)?)?;
+        }
+        for (index, header) in connected.iter().enumerate() {
+            let block = self.blockchain.get_block(&header.hash)?;
+            if let Err(e) = miner::check_block(&block, self).and_then(|()| self.connect_block(&block)) {
+                warn!("keeping tip {}: {}", old_tip, e);
+                if matches!(e, BlockchainError::Consensus(_)) {
+                    self.mark_invalid(&connected[index..])?;
                 }
+                for header in connected[..index].iter().rev() {
+                    self.disconnect_block(&self.blockchain.get_block(&header.hash)?)?;
+                }
+                for header in disconnected.iter().rev() {
+                    self.connect_block(&self.blockchain.get_block(&header.hash)?)?;
+                }
+                return Err(e);
             }
+        }
+        Ok(())
+    }
 
+    fn mark_invalid(&self, headers: &[BlockHeader]) -> Result<()> {
+        for header in headers {
+            self.blockchain.mark_invalid(&header.hash)?;
         }
+        Ok(())
+    }
 
-        Ok(utxos)
+    /// DisconnectBlock takes the tip `block` off the best chain and undoes its
+    /// UTXO set updates in one intent
+    pub fn disconnect_block(&self, block: &Block) -> Result<()> {
+        let ops = self.disconnect_ops(block)?;
+        self.blockchain.disconnect_block(block, ops)
     }
- 
-    pub fn update(&self, block: &Block) -> Result<()> {
-        let db = sled::open("data/utxos")?;
 
+    /// DisconnectOps plans the UTXO set writes undoing a block of the best
+    /// chain: its outputs are removed and those it spent restored, read from
+    /// the transactions the txindex finds them in
+    pub fn disconnect_ops(&self, block: &Block) -> Result<Vec<IntentOp>> {
+        let txids: HashSet<Hash256> = block.get_transactions().iter().map(|tx| tx.id).collect();
+        let mut ops = Vec::new();
+        for tx in block.get_transactions() {
+            for (idx, out) in tx.vout.iter().enumerate().filter(|(_, out)| !out.is_data()) {
+                ops.push(IntentOp::remove(UTXO_TREE, &utxo_key(&out.pub_key_hash, &tx.outpoint(idx))));
+            }
+        }
+
+        for tx in block.get_transactions().iter().filter(|tx| !tx.is_coinbase()) {
+            // an output created in the block itself was never in the set
+            for vin in tx.vin.iter().filter(|vin| !txids.contains(&vin.prev_out.txid)) {
+                let (prev_tx, header) = self.blockchain.get_indexed_transaction(&vin.prev_out.txid)?
+                    .ok_or_else(|| BlockchainError::Corrupt(format!("{} spends {} which is not indexed", tx.id, vin.prev_out)))?;
+                let out = prev_tx.vout.get(vin.prev_out.index as usize)
+                    .ok_or_else(|| BlockchainError::Corrupt(format!("{} spends the missing output {}", tx.id, vin.prev_out)))?;
+                ops.push(IntentOp::insert(UTXO_TREE, &utxo_key(&out.pub_key_hash, &vin.prev_out), bincode::serialize(&(out.value, header.height))?));
+            }
+        }
+
+        Ok(ops)
+    }
+
+    /// UpdateOps plans the UTXO set writes for a block without applying them, the
+    /// key of a spent output is found from the public key of the input spending it
+    pub fn update_ops(&self, block: &Block) -> Result<Vec<IntentOp>> {
+        let db = self.blockchain.open_tree(UTXO_TREE)?;
+        let mut created: HashSet<Vec<u8>> = HashSet::new();
+
+        let mut ops = Vec::new();
         for tx in block.get_transactions() {
             if !tx.is_coinbase() {
                 for vin in &tx.vin {
-                    let mut update_outputs = TXOutputs {
-                        outputs: Vec::new()
-                    };
-                    let outs: TXOutputs = bincode::deserialize(&db.get(&vin.txid)?.unwrap())?;
-                    for out_idx in 0..outs.outputs.len() {
-                        if out_idx != vin.vout as usize {
-                            update_outputs.outputs.push(outs.outputs[out_idx].clone());
-                        }
-                    }
-
-                    if update_outputs.outputs.is_empty() {
-                        db.remove(&vin.txid)?;
-                    } else {
-                        db.insert(vin.txid.as_bytes(), bincode::serialize(&update_outputs)?)?;
+                    let mut pub_key_hash = vin.pub_key.clone();
+                    hash_pub_key(&mut pub_key_hash);
+                    let key = utxo_key(&pub_key_hash, &vin.prev_out);
+                    if !created.remove(&key) && !db.contains_key(&key)? {
+                        return Err(BlockchainError::Consensus(format!("{} spends {} which is not in the utxo set", tx.id, vin.prev_out)));
                     }
+                    ops.push(IntentOp::remove(UTXO_TREE, &key));
                 }
             }
 
-            let mut new_outputs = TXOutputs {
-                outputs: Vec::new()
-            };
-
-            for out in &tx.vout {
-                new_outputs.outputs.push(out.clone());
+            for (idx, out) in tx.vout.iter().enumerate().filter(|(_, out)| !out.is_data()) {
+                let key = utxo_key(&out.pub_key_hash, &tx.outpoint(idx));
+                ops.push(IntentOp::insert(UTXO_TREE, &key, bincode::serialize(&(out.value, block.get_height()))?));
+                created.insert(key);
             }
-
-            db.insert(tx.id.as_bytes(), bincode::serialize(&new_outputs)?)?;
-
         }
 
-        Ok(())
+        Ok(ops)
 
     }
 
-    /// CountTransactions returns the number of transactions in the UTXO set
+    /// CountTransactions returns the number of transactions with outputs in the UTXO set
     pub fn count_transactions(&self) -> Result<i32> {
-        let mut counter: i32 = 0;   
-        let db = sled::open("data/utxos")?;
-        
-        for kv in db.iter() {
-            kv?;
-            counter += 1;
+        let mut txids = HashSet::new();
+        for out in self.list_unspent(None)? {
+            txids.insert(out.outpoint.txid);
         }
-        Ok(counter)
-    }   
+        Ok(txids.len() as i32)
+    }
+
 
+}
+
+
+const PUB_KEY_HASH_LEN: usize = 20;
 
+fn utxo_key(pub_key_hash: &[u8], outpoint: &OutPoint) -> Vec<u8> {
+    let mut key = pub_key_hash.to_vec();
+    key.extend_from_slice(outpoint.txid.as_bytes());
+    key.extend_from_slice(&outpoint.index.to_be_bytes());
+    key
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{ChainFixture, MINER_SEED};
+    use crate::transaction::Transaction;
+    use crate::tx::TXInput;
+    use crate::wallet::Wallet;
+
+    #[test]
+    fn test_outputs_of_an_address_are_a_prefix_scan() -> Result<()> {
+        let fixture = ChainFixture::restore(2)?;
+        let utxo = UTXOSet { blockchain: fixture.blockchain()? };
+        let wallet = Wallet::from_seed(&MINER_SEED);
+        let mut miner = wallet.public_key.clone();
+        hash_pub_key(&mut miner);
+
+        let coins = utxo.list_unspent(Some(&miner))?;
+        assert_eq!(coins.len(), 3);
+        assert!(utxo.list_unspent(Some(&[0; PUB_KEY_HASH_LEN]))?.is_empty());
+        let (total, spendable) = utxo.find_spendable_outputs(&miner, "1.5".parse()?)?;
+        assert_eq!((total, spendable.len()), ("2".parse()?, 2));
+
+        let mut payment = Transaction {
+            id: Hash256::ZERO,
+            vin: vec![TXInput { prev_out: coins[0].outpoint, signature: Vec::new(), pub_key: wallet.public_key.clone() }],
+            vout: vec![TXOutput { value: Amount::COIN, pub_key_hash: vec![1; PUB_KEY_HASH_LEN] }]
+        };
+        payment.id = payment.hash()?;
+        let coinbase = Transaction::new_coinbase(fixture.miner().to_string(), "height 3".to_string())?;
+        let block = Block::from_solution(vec![payment.clone(), coinbase], utxo.blockchain.get_tip(), 3, crate::target::POW_LIMIT_BITS, 0, 0)?;
+        utxo.connect_block(&block)?;
+
+        assert!(!utxo.is_unspent(&miner, &coins[0].outpoint)?);
+        assert!(utxo.is_unspent(&[1; PUB_KEY_HASH_LEN], &payment.outpoint(0))?);
+        assert_eq!(utxo.find_UTXO(&miner)?.outputs.len(), 3);
+        assert!(utxo.update_ops(&block).is_err(), "the spent coin is gone");
+        Ok(())
+    }
 
+    #[test]
+    fn test_a_heavier_fork_with_an_inflated_coinbase_is_refused() -> Result<()> {
+        let fixture = ChainFixture::restore(2)?;
+        let utxo = UTXOSet { blockchain: fixture.blockchain()? };
+        let bc = &utxo.blockchain;
+        let coinbase = |height: usize, fees: Amount| Transaction::new_coinbase_with_fees(fixture.miner().to_string(), format!("fork {}", height), fees);
+        let bits = bc.next_bits()?;
+        let b2 = Block::new_block(vec![coinbase(2, Amount::ZERO)?], bc.get_block_hash(1)?, 2, bits)?;
+        // the coinbase claims a fee no transaction of the block pays
+        let b3 = Block::new_block(vec![coinbase(3, Amount::from_sat(1))?], b2.get_hash(), 3, bits)?;
+        let b4 = Block::new_block(vec![coinbase(4, Amount::ZERO)?], b3.get_hash(), 4, bits)?;
+        let unspent = utxo.count_transactions()?;
+
+        utxo.receive_block(&b2)?;
+        assert!(utxo.receive_block(&b3).is_err());
+        assert_eq!(bc.get_tip(), fixture.tip());
+        assert_eq!(bc.get_block_hash(2)?, fixture.tip());
+        assert!(bc.is_invalid(&b3.get_hash())? && !bc.is_invalid(&b2.get_hash())?);
+        assert_eq!(utxo.count_transactions()?, unspent);
+        assert!(bc.get_indexed_transaction(&b2.get_transactions()[0].id)?.is_none());
+        assert!(utxo.receive_block(&b4).is_err(), "a block building on an invalid one is refused");
+        Ok(())
+    }
+}
@@ -1,13 +1,44 @@
 use std::collections::HashMap;
+use std::path::PathBuf;
 
-use bitcoincash_addr::{Address, HashType, Scheme};
 use crypto::{digest::Digest, ed25519, ripemd160::Ripemd160, sha2::Sha256};
-use log::info;
 use rand::{rngs::OsRng, RngCore};
 use serde::{Deserialize, Serialize};
+use tracing::info;
 
-use crate::error::Result;
+use crate::address;
+use crate::config::Config;
+use crate::error::{BlockchainError, Result};
+use crate::hash::Hash256;
 
+/// LABEL_TREE of the wallet database maps a txid to the TxLabel of a payment
+const LABEL_TREE: &str = "labels";
+
+/// MEMO_TREE of the wallet database maps a txid to the private memo noted when
+/// it was sent, which never goes on chain
+const MEMO_TREE: &str = "memos";
+
+/// WATCH_ONLY_TREE of the wallet database maps an address to the public key of
+/// a wallet kept elsewhere, whose transactions are built here and signed there
+const WATCH_ONLY_TREE: &str = "watchonly";
+
+/// BACKUP_TREE of the wallet database maps the address of a key pair to the
+/// time, in seconds since the unix epoch, `backupwallet` last copied it
+const BACKUP_TREE: &str = "backups";
+
+/// KEYCHAIN_TREE of the wallet database holds, under KEYCHAIN_KEY, the
+/// keychain new key pairs are derived from
+const KEYCHAIN_TREE: &str = "keychain";
+const KEYCHAIN_KEY: &str = "current";
+
+/// RETIRED_TREE of the wallet database maps an address to a key pair
+/// `rotatewallet` swept and retired, kept to recover late payments
+const RETIRED_TREE: &str = "retired";
+
+/// PUBLIC_KEY_LEN is the size of an ed25519 public key
+const PUBLIC_KEY_LEN: usize = 32;
+
+/// Wallet is an ed25519 key pair
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Wallet {
     pub secret_key: Vec<u8>,
@@ -22,7 +53,13 @@ impl Wallet {
 
         OsRng.fill_bytes(&mut key);
 
-        let (secret_key, public_key) = ed25519::keypair(&key);
+        Wallet::from_seed(&key)
+    }
+
+    /// from_seed derives the key pair of `seed`, the same seed always gives the
+    /// same wallet
+    pub fn from_seed(seed: &[u8; 32]) -> Self {
+        let (secret_key, public_key) = ed25519::keypair(seed);
 
         let secret_key = secret_key.to_vec();
         let public_key = public_key.to_vec();
@@ -34,24 +71,59 @@ impl Wallet {
         }
     }
 
-    fn get_address(&self) -> String {
+    /// get_address is the address paying to this key pair
+    pub fn get_address(&self) -> String {
         let mut pub_hash = self.public_key.clone();
         hash_pub_key(&mut pub_hash);
 
-        let address = Address {
-            body: pub_hash,
-            scheme: Scheme::Base58,
-            hash_type: HashType::Script,
-            ..Default::default()
-        };
+        hash_to_address(&pub_hash)
+    }
 
-        address.encode().unwrap()
+    
+}
 
+/// Keychain derives key pairs from a seed, the `next` one being the next
+/// address handed out. A backup taken after rotatewallet holds the seed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Keychain {
+    seed: [u8; 32],
+    next: u32
+}
 
+impl Keychain {
+    /// new generates a random seed
+    pub fn new() -> Keychain {
+        let mut seed = [0; 32];
+        OsRng.fill_bytes(&mut seed);
+        Keychain { seed, next: 0 }
     }
 
-    
+    /// derive is the key pair at `index`, the same seed always gives the same
+    /// key pairs
+    pub fn derive(&self, index: u32) -> Wallet {
+        let mut data = self.seed.to_vec();
+        data.extend_from_slice(&index.to_be_bytes());
+        Wallet::from_seed(Hash256::sha256(&data).as_bytes())
+    }
+
+    /// next is how many key pairs were handed out
+    pub fn next(&self) -> u32 {
+        self.next
+    }
+}
+
+impl Default for Keychain {
+    fn default() -> Keychain {
+        Keychain::new()
+    }
 }
+
+/// hash_to_address encodes a public key hash as a P2PKH address of the current network
+pub fn hash_to_address(pub_key_hash: &[u8]) -> String {
+    address::encode(pub_key_hash)
+}
+
+/// hash_pub_key replaces a public key with its RIPEMD160(SHA256) hash in place
 pub fn hash_pub_key(pub_key: &mut Vec<u8>) {
     let mut hasher1 = Sha256::new();
     hasher1.input(pub_key);
@@ -63,38 +135,163 @@ pub fn hash_pub_key(pub_key: &mut Vec<u8>) {
 }
 
 
+/// TxLabel is what the wallet knows about one of its payments, like the label
+/// and message of the payment URI it paid
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct TxLabel {
+    pub label: String,
+    pub message: String
+}
+
+/// WalletBackup is a copy of a wallet store, written by `backupwallet` as JSON
+/// and read back by `restorewallet`
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct WalletBackup {
+    pub wallets: Vec<BackupKey>,
+    /// hex public keys of the watch-only wallets
+    #[serde(rename = "watchonly")]
+    pub watch_only: Vec<String>,
+    pub labels: Vec<(Hash256, TxLabel)>,
+    pub memos: Vec<(Hash256, String)>,
+    /// key pairs rotatewallet retired
+    #[serde(default)]
+    pub retired: Vec<BackupKey>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keychain: Option<BackupKeychain>
+}
+
+/// BackupKeychain is the keychain of a backup, its seed hex encoded
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BackupKeychain {
+    pub seed: String,
+    pub next: u32
+}
+
+/// BackupKey is a key pair of a backup, hex encoded
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BackupKey {
+    pub secret_key: String,
+    pub public_key: String
+}
+
+fn decode_key(key: &str) -> Result<Vec<u8>> {
+    hex::decode(key).map_err(|e| BlockchainError::Wallet(format!("the backup holds a bad key: {}", e)))
+}
+
+fn backup_key(wallet: &Wallet) -> BackupKey {
+    BackupKey { secret_key: hex::encode(&wallet.secret_key), public_key: hex::encode(&wallet.public_key) }
+}
+
+/// restore_key checks a key pair of a backup
+fn restore_key(key: &BackupKey) -> Result<Wallet> {
+    let wallet = Wallet { secret_key: decode_key(&key.secret_key)?, public_key: decode_key(&key.public_key)? };
+    // an ed25519 secret key ends with its public key
+    if wallet.public_key.len() != PUBLIC_KEY_LEN || !wallet.secret_key.ends_with(&wallet.public_key) {
+        return Err(BlockchainError::Wallet(format!("the backup key {} does not match its secret key", key.public_key)));
+    }
+    Ok(wallet)
+}
+
+/// Wallets is the key store of the configured network, keyed by address
 pub struct Wallets {
-    wallets: HashMap<String, Wallet>
+    wallets: HashMap<String, Wallet>,
+    watch_only: HashMap<String, Vec<u8>>,
+    labels: HashMap<Hash256, TxLabel>,
+    memos: HashMap<Hash256, String>,
+    backed_up: HashMap<String, u64>,
+    retired: HashMap<String, Wallet>,
+    keychain: Option<Keychain>,
+    path: PathBuf
 }
 
 
 impl Wallets {
-    pub fn new() -> Result<Wallets> {
+    /// new loads every wallet saved for the configured network
+    pub fn new(config: &Config) -> Result<Wallets> {
+        if config.public_api {
+            return Err(BlockchainError::Config("wallets are never loaded in public API mode".to_string()));
+        }
         let mut wlt = Wallets {
-            wallets: HashMap::<String, Wallet>::new()
+            wallets: HashMap::<String, Wallet>::new(),
+            watch_only: HashMap::new(),
+            labels: HashMap::new(),
+            memos: HashMap::new(),
+            backed_up: HashMap::new(),
+            retired: HashMap::new(),
+            keychain: None,
+            path: config.wallets_path()
         };
 
-        let db = sled::open("data/wallets")?;
+        let db = sled::open(&wlt.path)?;
 
+        for item in db.open_tree(RETIRED_TREE)?.iter() {
+            let (_, wallet) = item?;
+            let wallet: Wallet = bincode::deserialize(&wallet)?;
+            wlt.import_watch_only(wallet.public_key.clone())?;
+            wlt.retired.insert(wallet.get_address(), wallet);
+        }
+        // wallets are keyed by the address derived now, so keys saved under an
+        // older address format still load
         for item in db.into_iter() {
             let i = item?;
-            let address = String::from_utf8(i.0.to_vec())?;
-            let wallet = bincode::deserialize(&i.1.to_vec())?;
-            wlt.wallets.insert(address, wallet);
+            let wallet: Wallet = bincode::deserialize(&i.1)?;
+            let address = wallet.get_address();
+            if !wlt.retired.contains_key(&address) {
+                wlt.wallets.insert(address, wallet);
+            }
+        }
+        if let Some(keychain) = db.open_tree(KEYCHAIN_TREE)?.get(KEYCHAIN_KEY)? {
+            wlt.keychain = Some(bincode::deserialize(&keychain)?);
+        }
+        for item in db.open_tree(LABEL_TREE)?.iter() {
+            let (txid, label) = item?;
+            wlt.labels.insert(Hash256::from_slice(&txid)?, bincode::deserialize(&label)?);
+        }
+        for item in db.open_tree(MEMO_TREE)?.iter() {
+            let (txid, memo) = item?;
+            wlt.memos.insert(Hash256::from_slice(&txid)?, String::from_utf8_lossy(&memo).into_owned());
+        }
+        for item in db.open_tree(BACKUP_TREE)?.iter() {
+            let (address, time) = item?;
+            let time = time.as_ref().try_into().map_err(|_| BlockchainError::Wallet("the wallet store holds a bad backup time".to_string()))?;
+            wlt.backed_up.insert(String::from_utf8_lossy(&address).into_owned(), u64::from_be_bytes(time));
+        }
+        for item in db.open_tree(WATCH_ONLY_TREE)?.iter() {
+            let (_, public_key) = item?;
+            let mut pub_key_hash = public_key.to_vec();
+            hash_pub_key(&mut pub_key_hash);
+            wlt.watch_only.insert(hash_to_address(&pub_key_hash), public_key.to_vec());
         }
 
         drop(db);
         Ok(wlt)
     }
 
+    /// create_wallet generates a key pair, the next one of the keychain when
+    /// there is one, and returns its address, call save_all to keep it
     pub fn create_wallet(&mut self) -> String {
-        let wallet = Wallet::new();
+        let wallet = match self.keychain.as_mut() {
+            Some(keychain) => {
+                keychain.next += 1;
+                keychain.derive(keychain.next - 1)
+            },
+            None => Wallet::new()
+        };
         let address = wallet.get_address();
         self.wallets.insert(address.clone(), wallet);
         info!("Create wallet: {}", address);
         address
     }
 
+    /// import_wallet adds an existing key pair and returns its address, call
+    /// save_all to keep it
+    pub fn import_wallet(&mut self, wallet: Wallet) -> String {
+        let address = wallet.get_address();
+        self.wallets.insert(address.clone(), wallet);
+        address
+    }
+
+    /// get_all_address lists the addresses of every wallet
     pub fn get_all_address(&self) -> Vec<String> {
         let mut addresses = Vec::new();
         for (address, _) in &self.wallets {
@@ -103,17 +300,184 @@ impl Wallets {
         addresses
     }
 
+    /// get_wallet returns the key pair of `address` when it is ours
     pub fn get_wallet(&self, address: &str) -> Option<&Wallet> {
         self.wallets.get(address)
     }
 
+    /// import_watch_only adds the public key of a wallet kept elsewhere and
+    /// returns its address, call save_all to keep it
+    pub fn import_watch_only(&mut self, public_key: Vec<u8>) -> Result<String> {
+        if public_key.len() != PUBLIC_KEY_LEN {
+            return Err(BlockchainError::Wallet(format!("a public key is {} bytes, not {}", PUBLIC_KEY_LEN, public_key.len())));
+        }
+        let mut pub_key_hash = public_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+        let address = hash_to_address(&pub_key_hash);
+        self.watch_only.insert(address.clone(), public_key);
+        Ok(address)
+    }
+
+    /// get_all_watch_only lists the addresses of the watch-only wallets
+    pub fn get_all_watch_only(&self) -> Vec<String> {
+        self.watch_only.keys().cloned().collect()
+    }
+
+    /// public_key returns the public key of `address`, a wallet of ours or a
+    /// watch-only one
+    pub fn public_key(&self, address: &str) -> Option<&[u8]> {
+        match self.wallets.get(address) {
+            Some(wallet) => Some(&wallet.public_key),
+            None => self.watch_only.get(address).map(|key| key.as_slice())
+        }
+    }
+
+    /// label returns what the wallet noted about the transaction `txid`
+    pub fn label(&self, txid: &Hash256) -> Option<&TxLabel> {
+        self.labels.get(txid)
+    }
+
+    /// set_label notes `label` for the transaction `txid`, call save_all to keep it
+    pub fn set_label(&mut self, txid: Hash256, label: TxLabel) {
+        self.labels.insert(txid, label);
+    }
+
+    /// memo returns the private memo noted for the transaction `txid`
+    pub fn memo(&self, txid: &Hash256) -> Option<&str> {
+        self.memos.get(txid).map(|memo| memo.as_str())
+    }
+
+    /// set_memo notes the private `memo` for the transaction `txid`, call
+    /// save_all to keep it
+    pub fn set_memo(&mut self, txid: Hash256, memo: String) {
+        self.memos.insert(txid, memo);
+    }
+
+    /// new_keychain replaces the keychain with one of a fresh seed, the key
+    /// pairs created from now on derive from it. Call save_all to keep it
+    pub fn new_keychain(&mut self) {
+        self.keychain = Some(Keychain::new());
+    }
+
+    /// keychain returns the keychain new key pairs derive from
+    pub fn keychain(&self) -> Option<&Keychain> {
+        self.keychain.as_ref()
+    }
+
+    /// retire stops spending from `address`, whose history is still followed
+    /// as a watch-only wallet. The key pair is kept apart, in a backup too,
+    /// to recover what is paid to it later. Call save_all to keep it
+    pub fn retire(&mut self, address: &str) -> Result<()> {
+        let wallet = self.wallets.remove(address).ok_or_else(|| BlockchainError::Wallet(format!("{} is not a wallet of this store", address)))?;
+        self.import_watch_only(wallet.public_key.clone())?;
+        self.retired.insert(address.to_string(), wallet);
+        Ok(())
+    }
+
+    /// is_retired tells whether `address` is a key pair retire took out of use
+    pub fn is_retired(&self, address: &str) -> bool {
+        self.retired.contains_key(address)
+    }
+
+    /// backup copies every key, watch-only key, label and memo of the store
+    pub fn backup(&self) -> WalletBackup {
+        let mut wallets: Vec<BackupKey> = self.wallets.values().map(backup_key).collect();
+        wallets.sort_by(|a, b| a.public_key.cmp(&b.public_key));
+        let mut retired: Vec<BackupKey> = self.retired.values().map(backup_key).collect();
+        retired.sort_by(|a, b| a.public_key.cmp(&b.public_key));
+        let mut watch_only: Vec<String> = self.watch_only.values().map(hex::encode).collect();
+        watch_only.sort();
+        WalletBackup {
+            wallets,
+            watch_only,
+            labels: self.labels.iter().map(|(txid, label)| (*txid, label.clone())).collect(),
+            memos: self.memos.iter().map(|(txid, memo)| (*txid, memo.clone())).collect(),
+            retired,
+            keychain: self.keychain.as_ref().map(|keychain| BackupKeychain { seed: hex::encode(keychain.seed), next: keychain.next })
+        }
+    }
+
+    /// backed_up returns when a backup last copied the key pair of `address`
+    pub fn backed_up(&self, address: &str) -> Option<u64> {
+        self.backed_up.get(address).copied()
+    }
+
+    /// mark_backed_up notes every key pair as copied by a backup at `time`,
+    /// call save_all to keep it
+    pub fn mark_backed_up(&mut self, time: u64) {
+        for address in self.wallets.keys() {
+            self.backed_up.insert(address.clone(), time);
+        }
+    }
+
+    /// restore adds what `backup` holds to the store, keeping the labels and
+    /// memos already noted, and returns how many keys were new. Call save_all
+    /// to keep it
+    pub fn restore(&mut self, backup: WalletBackup) -> Result<usize> {
+        let mut added = 0;
+        for key in &backup.wallets {
+            let wallet = restore_key(key)?;
+            let address = wallet.get_address();
+            if !self.retired.contains_key(&address) && self.wallets.insert(address, wallet).is_none() {
+                added += 1;
+            }
+        }
+        for key in &backup.retired {
+            let wallet = restore_key(key)?;
+            let address = wallet.get_address();
+            if !self.wallets.contains_key(&address) {
+                self.import_watch_only(wallet.public_key.clone())?;
+                self.retired.insert(address, wallet);
+            }
+        }
+        if let (None, Some(keychain)) = (&self.keychain, backup.keychain) {
+            let seed = decode_key(&keychain.seed)?.try_into().map_err(|_| BlockchainError::Wallet("the backup keychain seed is not 32 bytes".to_string()))?;
+            self.keychain = Some(Keychain { seed, next: keychain.next });
+        }
+        for public_key in backup.watch_only {
+            self.import_watch_only(decode_key(&public_key)?)?;
+        }
+        for (txid, label) in backup.labels {
+            self.labels.entry(txid).or_insert(label);
+        }
+        for (txid, memo) in backup.memos {
+            self.memos.entry(txid).or_insert(memo);
+        }
+        Ok(added)
+    }
+
+    /// save_all writes every wallet and label to disk
     pub fn save_all(&self) -> Result<()> {
-        let db = sled::open("data/wallets")?;
+        let db = sled::open(&self.path)?;
 
         for (address, wallet) in &self.wallets {
             let data = bincode::serialize(wallet)?;
             db.insert(address, data)?;
         }
+        let labels = db.open_tree(LABEL_TREE)?;
+        for (txid, label) in &self.labels {
+            labels.insert(txid.as_bytes(), bincode::serialize(label)?)?;
+        }
+        let memos = db.open_tree(MEMO_TREE)?;
+        for (txid, memo) in &self.memos {
+            memos.insert(txid.as_bytes(), memo.as_bytes())?;
+        }
+        let backed_up = db.open_tree(BACKUP_TREE)?;
+        for (address, time) in &self.backed_up {
+            backed_up.insert(address, &time.to_be_bytes())?;
+        }
+        let retired = db.open_tree(RETIRED_TREE)?;
+        for (address, wallet) in &self.retired {
+            retired.insert(address, bincode::serialize(wallet)?)?;
+            db.remove(address)?;
+        }
+        if let Some(keychain) = &self.keychain {
+            db.open_tree(KEYCHAIN_TREE)?.insert(KEYCHAIN_KEY, bincode::serialize(keychain)?)?;
+        }
+        let watch_only = db.open_tree(WATCH_ONLY_TREE)?;
+        for (address, public_key) in &self.watch_only {
+            watch_only.insert(address, public_key.as_slice())?;
+        }
 
         db.flush()?;
         drop(db);
@@ -121,3 +485,38 @@ impl Wallets {
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::{address_history, apply_labels};
+    use crate::testing::ChainFixture;
+
+    #[test]
+    fn test_memos_show_in_the_history_and_survive_a_restore() -> Result<()> {
+        let fixture = ChainFixture::restore(1)?;
+        let config = fixture.config();
+        let bc = fixture.blockchain()?;
+        let tip = bc.get_block(&bc.get_tip())?;
+        let txid = tip.get_transactions()[0].id;
+
+        let mut wallets = Wallets::new(&config)?;
+        wallets.set_memo(txid, "rent".to_string());
+        wallets.save_all()?;
+        let wallets = Wallets::new(&config)?;
+        let mut history = address_history(&bc, &address::decode(fixture.miner())?)?;
+        apply_labels(&mut history, &wallets);
+        assert_eq!(history.iter().find(|e| e.txid == txid).and_then(|e| e.memo.as_deref()), Some("rent"));
+
+        let backup: WalletBackup = serde_json::from_str(&serde_json::to_string(&wallets.backup())?)?;
+        let mut restored = Wallets::new(&Config { wallet: "restored".to_string(), ..config.clone() })?;
+        assert_eq!(restored.restore(backup.clone())?, 1);
+        assert_eq!((restored.memo(&txid), restored.get_wallet(fixture.miner())), (Some("rent"), wallets.get_wallet(fixture.miner())));
+        assert_eq!(restored.restore(backup.clone())?, 0);
+
+        let mut forged = backup;
+        forged.wallets[0].public_key = hex::encode([7; PUBLIC_KEY_LEN]);
+        assert!(restored.restore(forged).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,256 @@
+//! Address watching for merchant style integrations. `watch <ADDRESS>` long
+//! polls the node for the activity of the address and prints a JSON line, or
+//! runs a command, when a transaction paying it or spending from it shows up
+//! and again when that transaction reaches the confirmations asked for:
+//!
+//! ```text
+//! blockchain_project watch mzZcEbAT6sr5MjgAgGREaTakSzGNT88xy9 --confirmations 3 --exec ./ship-order.sh
+//! ```
+//!
+//! The command runs through `sh -c` with the JSON line on its stdin and the
+//! fields in `WATCH_*` environment variables.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::address;
+use crate::error::{BlockchainError, Result};
+use crate::hash::Hash256;
+use crate::mempool::Mempool;
+use crate::server::Server;
+use crate::transaction::Transaction;
+use crate::wallet::hash_pub_key;
+
+/// DEPTH_MARGIN is how many blocks past the confirmations asked for activity is
+/// still reported, so a watcher falling that far behind still sees its
+/// transactions reach them
+pub const DEPTH_MARGIN: usize = 100;
+
+/// Activity is a transaction touching the watched address
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Activity {
+    pub txid: Hash256,
+    /// sats the transaction adds to the balance of the address, negative when
+    /// it spends more from it than it pays back
+    pub delta: i64,
+    /// 0 while the transaction is in the mempool
+    pub confirmations: usize,
+    #[serde(rename = "blockhash")]
+    pub block_hash: Option<Hash256>
+}
+
+/// Snapshot is the recent activity of an address, the mempool first and then
+/// the blocks, newest first
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub address: String,
+    #[serde(rename = "bestblockhash")]
+    pub best_block_hash: Hash256,
+    pub activity: Vec<Activity>,
+    /// hash of the tip and the activity, the node answers a watcher as soon as
+    /// it differs from the one the watcher knows
+    pub state: Hash256
+}
+
+/// snapshot reads the activity of `address` in the mempool of `server` and in
+/// the last `depth` blocks of its chain
+pub fn snapshot(server: &Server, address: &str, depth: usize) -> Result<Snapshot> {
+    let pub_key_hash = address::decode(address)?;
+    let bc = &server.utxo_set().blockchain;
+    // the index follows the chain from its own thread, catch up before reading
+    server.address_index().sync()?;
+    let best_height = bc.get_best_height()? as usize;
+    let best_block_hash = bc.get_tip();
+
+    let mempool = server.get_mempool();
+    let mut activity = Vec::new();
+    for entry in mempool.entries() {
+        if let Some(delta) = pending_delta(server, &mempool, &entry.tx, &pub_key_hash)? {
+            activity.push(Activity { txid: entry.tx.id, delta, confirmations: 0, block_hash: None });
+        }
+    }
+    activity.sort_by_key(|a| a.txid);
+    for confirmed in server.address_index().history(&pub_key_hash)? {
+        let confirmations = (best_height + 1).saturating_sub(confirmed.height);
+        if confirmations > depth {
+            break;
+        }
+        activity.push(Activity { txid: confirmed.txid, delta: confirmed.delta, confirmations, block_hash: Some(confirmed.block_hash) });
+    }
+
+    let state = Hash256::sha256(&serde_json::to_vec(&(best_block_hash, &activity))?);
+    Ok(Snapshot { address: address.to_string(), best_block_hash, activity, state })
+}
+
+/// wait_for_activity answers as soon as the snapshot of `address` differs from
+/// the `known` state, or with the unchanged one after `timeout`
+pub fn wait_for_activity(server: &Server, address: &str, known: &Hash256, depth: usize, timeout: Duration) -> Result<Snapshot> {
+    let events = server.utxo_set().blockchain.events().subscribe();
+    let deadline = Instant::now() + timeout;
+    loop {
+        let snapshot = snapshot(server, address, depth)?;
+        let now = Instant::now();
+        if snapshot.state != *known || now >= deadline || events.recv_timeout(deadline - now).is_err() {
+            return Ok(snapshot);
+        }
+    }
+}
+
+/// pending_delta is what the unconfirmed `tx` adds to the balance of
+/// `pub_key_hash`, None when it does not touch it
+fn pending_delta(server: &Server, mempool: &Mempool, tx: &Transaction, pub_key_hash: &[u8]) -> Result<Option<i64>> {
+    let mut touched = false;
+    let mut delta = 0i64;
+    for out in tx.vout.iter().filter(|out| !out.is_data() && out.pub_key_hash == pub_key_hash) {
+        touched = true;
+        delta += out.value.to_sat() as i64;
+    }
+    for vin in &tx.vin {
+        let mut input_hash = vin.pub_key.clone();
+        hash_pub_key(&mut input_hash);
+        if input_hash != pub_key_hash {
+            continue;
+        }
+        let prev = match mempool.get(&vin.prev_out.txid) {
+            Some(entry) => entry.tx.clone(),
+            None => server.utxo_set().blockchain.find_transaction(&vin.prev_out.txid)?
+        };
+        let spent = prev.vout.get(vin.prev_out.index as usize).ok_or_else(|| BlockchainError::TxNotFound(vin.prev_out.to_string()))?;
+        touched = true;
+        delta -= spent.value.to_sat() as i64;
+    }
+    Ok(touched.then_some(delta))
+}
+
+/// WatchEvent is a line `watch` prints or hands to its command
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct WatchEvent {
+    /// `received` or `spent` when the transaction shows up, `confirmed` when
+    /// it reaches the confirmations asked for
+    pub event: String,
+    pub address: String,
+    pub txid: Hash256,
+    pub delta: i64,
+    pub confirmations: usize,
+    #[serde(rename = "blockhash")]
+    pub block_hash: Option<Hash256>
+}
+
+/// Watcher turns the successive snapshots of an address into events
+#[derive(Debug, Clone)]
+pub struct Watcher {
+    /// confirmations after which a transaction is reported as confirmed
+    confirmations: usize,
+    /// confirmations of the transactions of the last snapshot, None before the
+    /// first one, which only sets the baseline
+    seen: Option<HashMap<Hash256, usize>>
+}
+
+impl Watcher {
+    pub fn new(confirmations: usize) -> Watcher {
+        Watcher { confirmations, seen: None }
+    }
+
+    /// update compares `snapshot` with the previous one, oldest transactions
+    /// first. A transaction a reorg sends back to the mempool is confirmed again
+    /// once it gets its confirmations back
+    pub fn update(&mut self, snapshot: &Snapshot) -> Vec<WatchEvent> {
+        let mut events = Vec::new();
+        let mut seen = HashMap::new();
+        for activity in snapshot.activity.iter().rev() {
+            seen.insert(activity.txid, activity.confirmations);
+            let Some(previous) = self.seen.as_ref() else {
+                continue;
+            };
+            let before = previous.get(&activity.txid).copied();
+            let event = |name: &str| WatchEvent {
+                event: name.to_string(),
+                address: snapshot.address.clone(),
+                txid: activity.txid,
+                delta: activity.delta,
+                confirmations: activity.confirmations,
+                block_hash: activity.block_hash
+            };
+            if before.is_none() {
+                events.push(event(if activity.delta < 0 { "spent" } else { "received" }));
+            }
+            if activity.confirmations >= self.confirmations && before.unwrap_or(0) < self.confirmations {
+                events.push(event("confirmed"));
+            }
+        }
+        self.seen = Some(seen);
+        events
+    }
+}
+
+/// run_hook runs `command` through `sh -c` for `event`, failing when it exits
+/// with an error
+pub fn run_hook(command: &str, event: &WatchEvent) -> Result<()> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("WATCH_EVENT", &event.event)
+        .env("WATCH_ADDRESS", &event.address)
+        .env("WATCH_TXID", event.txid.to_string())
+        .env("WATCH_DELTA", event.delta.to_string())
+        .env("WATCH_CONFIRMATIONS", event.confirmations.to_string())
+        .stdin(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // a command that does not read its stdin closes it early, that is fine
+        let _ = writeln!(stdin, "{}", serde_json::to_string(event)?);
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(BlockchainError::Config(format!("'{}' failed on {} of {}: {}", command, event.event, event.txid, status)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::codec::{Message, MessageCodec, Txmsg};
+    use crate::testing::ChainFixture;
+    use crate::utxoset::UTXOSet;
+    use crate::wallet::{hash_to_address, Wallets};
+
+    #[test]
+    fn test_payments_are_reported_then_confirmed() -> Result<()> {
+        let fixture = ChainFixture::restore(2)?;
+        let miner = fixture.miner().to_string();
+        let merchant = hash_to_address(&[9; 20]);
+        let tx = {
+            let utxo = UTXOSet { blockchain: fixture.blockchain()? };
+            let wallets = Wallets::new(&fixture.config())?;
+            Transaction::new_UTXO(&wallets, &miner, &merchant, Amount::from_sat(40), Amount::ZERO, &utxo)?
+        };
+
+        let node = fixture.node_builder().build()?;
+        let server = node.server();
+        let mut watcher = Watcher::new(2);
+        let first = snapshot(server, &merchant, 2 + DEPTH_MARGIN)?;
+        assert!(first.activity.is_empty() && watcher.update(&first).is_empty());
+        let unchanged = wait_for_activity(server, &merchant, &first.state, 2, Duration::from_millis(50))?;
+        assert_eq!(unchanged, first);
+
+        let frame = MessageCodec::encode(&Message::Tx(Txmsg { addr_from: "localhost:3001".to_string(), transaction: tx.clone() }))?;
+        server.handle_frame(&frame)?;
+        let pending = wait_for_activity(server, &merchant, &first.state, 2, Duration::from_secs(5))?;
+        let events = watcher.update(&pending);
+        assert_eq!(events.iter().map(|e| (e.event.as_str(), e.txid, e.delta, e.confirmations)).collect::<Vec<_>>(), vec![("received", tx.id, 40, 0)]);
+
+        node.mine_blocks(1)?;
+        assert!(watcher.update(&snapshot(server, &merchant, 2)?).is_empty());
+        let mined = node.mine_blocks(1)?;
+        let events = watcher.update(&snapshot(server, &merchant, 2)?);
+        assert_eq!(events.iter().map(|e| (e.event.as_str(), e.confirmations)).collect::<Vec<_>>(), vec![("confirmed", 2)]);
+        assert!(events[0].block_hash.is_some_and(|hash| hash != mined[0]));
+        node.shutdown()
+    }
+}
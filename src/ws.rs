@@ -0,0 +1,142 @@
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use tracing::{debug, error, info};
+use tungstenite::{Message, WebSocket};
+
+use crate::address;
+use crate::error::{BlockchainError, Result};
+use crate::events::Event;
+use crate::server::Server;
+use crate::transaction::Transaction;
+use crate::wallet::hash_to_address;
+
+/// POLL_INTERVAL is how long a connection waits for a client message before
+/// forwarding pending events
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// start serves WebSocket notifications of `server` on the configured WebSocket port from a background thread
+pub fn start(server: Server) -> Result<()> {
+    let addr = format!("127.0.0.1:{}", server.config().ws_port);
+    let listener = TcpListener::bind(&addr)?;
+    info!("WebSocket notifications on {}", addr);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("failed to accept websocket connection: {}", e);
+                    continue;
+                }
+            };
+            let server = server.clone();
+            thread::spawn(move || {
+                if let Err(e) = serve_connection(&server, stream) {
+                    debug!("websocket connection closed: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// serve_connection streams every block event and the mempool transactions
+/// touching the subscribed addresses until the client disconnects
+fn serve_connection(server: &Server, stream: TcpStream) -> Result<()> {
+    let events = server.utxo_set().blockchain.events().subscribe();
+    let mut socket = tungstenite::accept(stream).map_err(|e| BlockchainError::Network(format!("websocket handshake failed: {}", e)))?;
+    socket.get_ref().set_read_timeout(Some(POLL_INTERVAL))?;
+
+    let mut filter = HashSet::new();
+    loop {
+        match socket.read() {
+            Ok(Message::Text(text)) => {
+                let reply = handle_command(&mut filter, &text);
+                send(&mut socket, reply)?;
+            },
+            Ok(Message::Close(_)) => return Ok(()),
+            Ok(_) => {},
+            Err(tungstenite::Error::Io(e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {},
+            Err(e) => return Err(BlockchainError::Network(e.to_string()))
+        }
+
+        forward_events(server, &mut socket, &events, &filter)?;
+    }
+}
+
+/// handle_command applies `{"op": "subscribe"|"unsubscribe", "address": ...}` to the address filter
+fn handle_command(filter: &mut HashSet<Vec<u8>>, text: &str) -> Value {
+    let command: Value = match serde_json::from_str(text) {
+        Ok(command) => command,
+        Err(e) => return json!({ "type": "error", "error": e.to_string() })
+    };
+    let op = command["op"].as_str().unwrap_or_default();
+    if op != "subscribe" && op != "unsubscribe" {
+        return json!({ "type": "error", "error": format!("unknown op {:?}", op) });
+    }
+    let address = command["address"].as_str().unwrap_or_default();
+    let pub_key_hash = match address::decode(address) {
+        Ok(pub_key_hash) => pub_key_hash,
+        Err(e) => return json!({ "type": "error", "error": e.to_string() })
+    };
+
+    if op == "subscribe" {
+        filter.insert(pub_key_hash);
+    } else {
+        filter.remove(&pub_key_hash);
+    }
+    json!({ "type": op, "address": address })
+}
+
+fn forward_events(server: &Server, socket: &mut WebSocket<TcpStream>, events: &Receiver<Event>, filter: &HashSet<Vec<u8>>) -> Result<()> {
+    loop {
+        let event = match events.try_recv() {
+            Ok(event) => event,
+            Err(TryRecvError::Empty) => return Ok(()),
+            Err(TryRecvError::Disconnected) => return Err(BlockchainError::Network(String::from("event bus closed")))
+        };
+
+        let notification = match event {
+            Event::BlockConnected { hash, height } => json!({ "type": "block", "hash": hash, "height": height }),
+            Event::BlockDisconnected { hash, height } => json!({ "type": "blockdisconnected", "hash": hash, "height": height }),
+            Event::TxAccepted { txid } => {
+                let Some(entry) = server.get_mempool().get(&txid).cloned() else {
+                    continue;
+                };
+                let addresses = matching_addresses(&entry.tx, filter);
+                if addresses.is_empty() {
+                    continue;
+                }
+                json!({ "type": "tx", "txid": txid, "fee": entry.fee, "addresses": addresses })
+            },
+            Event::PeerConnected { .. } => continue,
+            Event::DoubleSpendDetected { outpoint, first, second } => json!({ "type": "doublespend", "outpoint": outpoint.to_string(), "first": first, "second": second })
+        };
+        send(socket, notification)?;
+    }
+}
+
+/// matching_addresses lists the subscribed addresses `tx` spends from or pays to
+fn matching_addresses(tx: &Transaction, filter: &HashSet<Vec<u8>>) -> Vec<String> {
+    let mut addresses: Vec<String> = tx
+        .pub_key_hashes()
+        .iter()
+        .filter(|h| filter.contains(*h))
+        .map(|h| hash_to_address(h))
+        .collect();
+    addresses.sort();
+    addresses.dedup();
+    addresses
+}
+
+fn send(socket: &mut WebSocket<TcpStream>, notification: Value) -> Result<()> {
+    socket
+        .send(Message::text(notification.to_string()))
+        .map_err(|e| BlockchainError::Network(e.to_string()))
+}
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::thread;
+
+use tokio::sync::mpsc;
+use tracing::{error, info};
+use zeromq::{PubSocket, Socket, SocketSend, ZmqMessage};
+
+use crate::error::{BlockchainError, Result};
+use crate::events::Event;
+use crate::server::Server;
+
+const RAW_BLOCK: &str = "rawblock";
+const RAW_TX: &str = "rawtx";
+
+/// Publisher owns one PUB socket per configured endpoint, topics configured
+/// on the same endpoint share its socket like bitcoind's -zmqpub options
+struct Publisher {
+    sockets: HashMap<String, PubSocket>,
+    /// endpoint of every enabled topic
+    topics: HashMap<&'static str, String>,
+    sequences: HashMap<&'static str, u32>
+}
+
+/// start publishes raw blocks and transactions of `server` on the configured
+/// ZeroMQ endpoints from a background thread
+pub fn start(server: Server) -> Result<()> {
+    let config = server.config();
+    let mut topics = HashMap::new();
+    if !config.zmq_pub_raw_block.is_empty() {
+        topics.insert(RAW_BLOCK, config.zmq_pub_raw_block.clone());
+    }
+    if !config.zmq_pub_raw_tx.is_empty() {
+        topics.insert(RAW_TX, config.zmq_pub_raw_tx.clone());
+    }
+
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+    let mut sockets = HashMap::new();
+    for endpoint in topics.values() {
+        if sockets.contains_key(endpoint) {
+            continue;
+        }
+        let mut socket = PubSocket::new();
+        runtime
+            .block_on(socket.bind(endpoint))
+            .map_err(|e| BlockchainError::Network(format!("cannot bind zmq socket to {}: {}", endpoint, e)))?;
+        info!("ZMQ publishing {:?} on {}", topics.iter().filter(|(_, e)| *e == endpoint).map(|(t, _)| t).collect::<Vec<_>>(), endpoint);
+        sockets.insert(endpoint.clone(), socket);
+    }
+
+    // the bus hands out blocking receivers, forward them into the runtime
+    let events = server.utxo_set().blockchain.events().subscribe();
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    thread::spawn(move || {
+        for event in events {
+            if tx.send(event).is_err() {
+                return;
+            }
+        }
+    });
+
+    let mut publisher = Publisher {
+        sockets,
+        topics,
+        sequences: HashMap::new()
+    };
+    thread::spawn(move || {
+        runtime.block_on(async move {
+            while let Some(event) = rx.recv().await {
+                if let Err(e) = publisher.publish_event(&server, event).await {
+                    error!("failed to publish zmq notification: {}", e);
+                }
+            }
+        })
+    });
+    Ok(())
+}
+
+impl Publisher {
+    /// publish_event sends a connected block on `rawblock`, and on `rawtx` its
+    /// transactions as well as the ones accepted to the mempool
+    async fn publish_event(&mut self, server: &Server, event: Event) -> Result<()> {
+        match event {
+            Event::BlockConnected { hash, .. } => {
+                let block = server.utxo_set().blockchain.get_block(&hash)?;
+                if self.topics.contains_key(RAW_BLOCK) {
+                    self.send(RAW_BLOCK, bincode::serialize(&block)?).await?;
+                }
+                if self.topics.contains_key(RAW_TX) {
+                    for tx in block.get_transactions() {
+                        self.send(RAW_TX, bincode::serialize(tx)?).await?;
+                    }
+                }
+            },
+            Event::TxAccepted { txid } if self.topics.contains_key(RAW_TX) => {
+                if let Some(entry) = server.get_mempool().get(&txid) {
+                    self.send(RAW_TX, bincode::serialize(&entry.tx)?).await?;
+                }
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// send publishes the multipart message `topic`, `body`, little endian sequence number
+    async fn send(&mut self, topic: &'static str, body: Vec<u8>) -> Result<()> {
+        let endpoint = &self.topics[topic];
+        let sequence = self.sequences.entry(topic).or_insert(0);
+
+        let mut message = ZmqMessage::from(topic);
+        message.push_back(body.into());
+        message.push_back(sequence.to_le_bytes().to_vec().into());
+        *sequence = sequence.wrapping_add(1);
+
+        let socket = self.sockets.get_mut(endpoint).ok_or_else(|| BlockchainError::Network(format!("no zmq socket for {}", endpoint)))?;
+        socket.send(message).await.map_err(|e| BlockchainError::Network(e.to_string()))
+    }
+}